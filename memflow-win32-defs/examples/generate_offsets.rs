@@ -17,6 +17,12 @@ pub fn main() {
                 .action(ArgAction::Set)
                 .required(true),
         )
+        .arg(
+            Arg::new("isf")
+                .long("isf")
+                .help("additionally generate volatility3 isf profiles")
+                .action(ArgAction::SetTrue),
+        )
         .get_matches();
 
     let log_level = match matches.get_count("verbose") {
@@ -127,6 +133,10 @@ pub fn main() {
             let mut file =
                 File::create([out_dir, &file_name].iter().collect::<PathBuf>().as_path()).unwrap();
             file.write_all(offsetstr.as_bytes()).unwrap();
+
+            if matches.get_flag("isf") {
+                generate_isf(out_dir, &file_name, &win_id.2, &offset_file.header);
+            }
         } else {
             error!(
                 "unable to find offsets for {} {:?} {:?}",
@@ -135,3 +145,22 @@ pub fn main() {
         }
     }
 }
+
+#[cfg(feature = "isf")]
+fn generate_isf(out_dir: &str, file_name: &str, guid: &Win32Guid, header: &Win32OffsetHeader) {
+    let pdb = SymbolStore::new().load(guid).unwrap();
+    match IsfProfile::from_pdb_slice(&pdb[..], header, DEFAULT_ISF_TYPES) {
+        Ok(profile) => {
+            let file_name = file_name.replace(".toml", ".json");
+            profile
+                .to_file([out_dir, &file_name].iter().collect::<PathBuf>())
+                .unwrap();
+        }
+        Err(err) => error!("unable to generate isf profile for {:?}: {}", guid, err),
+    }
+}
+
+#[cfg(not(feature = "isf"))]
+fn generate_isf(_: &str, _: &str, _: &Win32Guid, _: &Win32OffsetHeader) {
+    error!("isf profiles require the `isf` feature");
+}
@@ -3,3 +3,4 @@ extern crate no_std_compat as std;
 
 pub mod kernel;
 pub mod offsets;
+pub mod progress;
@@ -1,4 +1,7 @@
 use std::convert::TryFrom;
+#[cfg(feature = "offset_files")]
+use std::path::{Path, PathBuf};
+use std::prelude::v1::*;
 
 #[cfg(feature = "symstore")]
 use super::symstore::SymbolStore;
@@ -16,9 +19,17 @@ pub struct Win32OffsetBuilder<'a> {
 
     guid: Option<Win32Guid>,
     winver: Option<Win32Version>,
+    guid_candidates: Vec<Win32Guid>,
+    winver_candidates: Vec<Win32Version>,
     arch: Option<Win32OffsetsArchitecture>,
+    allow_nearest_build: bool,
 
     offset_list: Option<&'a [Win32OffsetFile]>,
+
+    #[cfg(feature = "offset_files")]
+    offset_db_dir: Option<PathBuf>,
+
+    heuristic: Option<Box<dyn FnOnce() -> Result<Win32Offsets> + 'a>>,
 }
 
 impl<'a> Default for Win32OffsetBuilder<'a> {
@@ -29,9 +40,17 @@ impl<'a> Default for Win32OffsetBuilder<'a> {
 
             guid: None,
             winver: None,
+            guid_candidates: vec![],
+            winver_candidates: vec![],
             arch: None,
+            allow_nearest_build: true,
 
             offset_list: None,
+
+            #[cfg(feature = "offset_files")]
+            offset_db_dir: None,
+
+            heuristic: None,
         }
     }
 }
@@ -41,8 +60,12 @@ impl<'a> Win32OffsetBuilder<'a> {
         Self::default()
     }
 
-    pub fn build(self) -> Result<Win32Offsets> {
-        if self.guid.is_none() && self.winver.is_none() {
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(name = "win32_offsets_build", skip_all)
+    )]
+    pub fn build(mut self) -> Result<Win32Offsets> {
+        if self.guids().next().is_none() && self.winvers().next().is_none() {
             return Err(Error(ErrorOrigin::OsLayer, ErrorKind::Configuration)
                 .log_error("building win32 offsets requires either a guid or winver"));
         }
@@ -52,23 +75,83 @@ impl<'a> Win32OffsetBuilder<'a> {
             return Ok(offs);
         }
 
+        // use offset files from the offset database directory
+        if let Ok(offs) = self.build_with_offset_db_dir(false) {
+            return Ok(offs);
+        }
+
         // use static offset list
-        if let Ok(offs) = self.build_with_offset_list() {
+        if let Ok(offs) = self.build_with_offset_list(false) {
             return Ok(offs);
         }
 
+        // fall back to the offsets of the nearest known build
+        if self.allow_nearest_build {
+            if let Ok(offs) = self.build_with_offset_db_dir(true) {
+                return Ok(offs);
+            }
+
+            if let Ok(offs) = self.build_with_offset_list(true) {
+                return Ok(offs);
+            }
+        }
+
+        // derive the offsets heuristically as a last resort
+        if let Some(heuristic) = self.heuristic.take() {
+            log::warn!("no offsets found for this kernel, falling back to heuristic offsets");
+            if let Ok(offs) = heuristic() {
+                return Ok(offs);
+            }
+        }
+
         Err(Error(ErrorOrigin::OsLayer, ErrorKind::Configuration)
             .log_error("no valid offset configuration found while building win32"))
     }
 
-    fn build_with_offset_list(&self) -> Result<Win32Offsets> {
+    fn build_with_offset_list(&self, nearest: bool) -> Result<Win32Offsets> {
         let offsets = self.offset_list.ok_or_else(|| {
             Error(ErrorOrigin::OsLayer, ErrorKind::Configuration)
                 .log_error("no offset list supplied")
         })?;
+        self.find_offsets(offsets, nearest)
+    }
+
+    #[cfg(feature = "offset_files")]
+    fn build_with_offset_db_dir(&self, nearest: bool) -> Result<Win32Offsets> {
+        let dir = self.offset_db_dir.as_ref().ok_or_else(|| {
+            Error(ErrorOrigin::OsLayer, ErrorKind::Configuration)
+                .log_trace("no offset database directory supplied")
+        })?;
+        let offsets = load_offset_db_dir(dir)?;
+        self.find_offsets(&offsets, nearest)
+    }
+
+    #[cfg(not(feature = "offset_files"))]
+    fn build_with_offset_db_dir(&self, _nearest: bool) -> Result<Win32Offsets> {
+        Err(
+            Error(ErrorOrigin::OsLayer, ErrorKind::UnsupportedOptionalFeature)
+                .log_trace("offset files are deactivated via a compilation feature"),
+        )
+    }
+
+    /// Returns the guid followed by the guid candidates in the order of their priority.
+    fn guids(&self) -> impl Iterator<Item = &Win32Guid> {
+        self.guid.iter().chain(self.guid_candidates.iter())
+    }
+
+    /// Returns the winver followed by the winver candidates in the order of their priority.
+    fn winvers(&self) -> impl Iterator<Item = &Win32Version> {
+        self.winver.iter().chain(self.winver_candidates.iter())
+    }
 
+    /// Selects the offsets matching the guid or the version and architecture.
+    ///
+    /// All guids are tried before falling back to the versions.
+    /// If `nearest` is set the offsets of the nearest build of the same version are selected,
+    /// otherwise the build number has to match exactly.
+    fn find_offsets(&self, offsets: &[Win32OffsetFile], nearest: bool) -> Result<Win32Offsets> {
         // Try matching exact guid
-        if let Some(target_guid) = &self.guid {
+        for target_guid in self.guids() {
             for offset in offsets.iter() {
                 if let (Ok(file), Ok(guid)) = (
                     <&str>::try_from(&offset.header.pdb_file_name),
@@ -81,48 +164,90 @@ impl<'a> Win32OffsetBuilder<'a> {
             }
         }
 
-        let mut closest_match = None;
-        let mut prev_build_number = 0;
+        if let Some(arch) = self.arch {
+            for winver in self.winvers() {
+                let offsets = if nearest {
+                    Self::find_nearest_match(offsets, winver, arch)
+                } else {
+                    Self::find_exact_match(offsets, winver, arch)
+                };
+                if let Some(offsets) = offsets {
+                    return Ok(offsets);
+                }
+            }
+        }
 
-        // Try matching the newest build from that version that is not actually newer
-        if let (Some(winver), Some(arch)) = (&self.winver, self.arch) {
-            for offset in offsets.iter() {
-                if winver.major_version() == offset.header.nt_major_version
+        Err(Error(ErrorOrigin::OsLayer, ErrorKind::Configuration)
+            .log_error("no valid offset configuration found while building win32"))
+    }
+
+    fn find_exact_match(
+        offsets: &[Win32OffsetFile],
+        winver: &Win32Version,
+        arch: Win32OffsetsArchitecture,
+    ) -> Option<Win32Offsets> {
+        offsets
+            .iter()
+            .find(|offset| {
+                winver.major_version() == offset.header.nt_major_version
                     && winver.minor_version() == offset.header.nt_minor_version
-                    && winver.build_number() >= offset.header.nt_build_number
-                    && prev_build_number <= offset.header.nt_build_number
+                    && winver.build_number() == offset.header.nt_build_number
                     && arch == offset.header.arch
-                {
-                    prev_build_number = offset.header.nt_build_number;
-                    closest_match = Some(Win32Offsets(offset.offsets));
-                }
-            }
+            })
+            .map(|offset| Win32Offsets(offset.offsets))
+    }
 
-            if prev_build_number != winver.build_number() {
-                log::warn!(
-                    "no exact build number ({}) found! Closest match: {}",
-                    winver.build_number(),
-                    prev_build_number
-                );
-            }
+    /// Selects the newest build from that version that is not actually newer.
+    ///
+    /// If the build is older than all known builds of that version (e.g. an insider build
+    /// of a new release) the oldest newer build is selected instead.
+    fn find_nearest_match(
+        offsets: &[Win32OffsetFile],
+        winver: &Win32Version,
+        arch: Win32OffsetsArchitecture,
+    ) -> Option<Win32Offsets> {
+        let candidates = offsets.iter().filter(|offset| {
+            winver.major_version() == offset.header.nt_major_version
+                && winver.minor_version() == offset.header.nt_minor_version
+                && arch == offset.header.arch
+        });
+
+        let nearest = candidates
+            .clone()
+            .filter(|offset| offset.header.nt_build_number <= winver.build_number())
+            .max_by_key(|offset| offset.header.nt_build_number)
+            .or_else(|| candidates.min_by_key(|offset| offset.header.nt_build_number))?;
+
+        if nearest.header.nt_build_number != winver.build_number() {
+            log::warn!(
+                "no exact build number ({}) found! Using offsets of the nearest build: {}",
+                winver.build_number(),
+                nearest.header.nt_build_number
+            );
         }
 
-        closest_match.ok_or_else(|| {
-            Error(ErrorOrigin::OsLayer, ErrorKind::Configuration)
-                .log_error("no valid offset configuration found while building win32")
-        })
+        Some(Win32Offsets(nearest.offsets))
     }
 
     #[cfg(feature = "symstore")]
     fn build_with_symbol_store(&self) -> Result<Win32Offsets> {
         if let Some(store) = &self.symbol_store {
-            if let Some(guid) = &self.guid {
-                let pdb = store.load(guid)?;
-                Win32Offsets::from_pdb_slice(&pdb[..])
-            } else {
-                Err(Error(ErrorOrigin::OsLayer, ErrorKind::Configuration)
-                    .log_error("symbol store can only be used with a guid"))
+            if self.guids().next().is_none() {
+                return Err(Error(ErrorOrigin::OsLayer, ErrorKind::Configuration)
+                    .log_error("symbol store can only be used with a guid"));
+            }
+
+            let mut result = Err(Error(ErrorOrigin::OsLayer, ErrorKind::Configuration));
+            for guid in self.guids() {
+                result = store
+                    .load(guid)
+                    .and_then(|pdb| Win32Offsets::from_pdb_slice(&pdb[..]));
+                if result.is_ok() {
+                    break;
+                }
+                log::debug!("unable to load offsets for guid {:?}", guid);
             }
+            result
         } else {
             Err(Error(ErrorOrigin::OsLayer, ErrorKind::Configuration)
                 .log_error("symbol store is disabled"))
@@ -154,6 +279,24 @@ impl<'a> Win32OffsetBuilder<'a> {
         self
     }
 
+    /// Sets a directory of offset files that is searched at runtime.
+    ///
+    /// All toml, json and yaml files in the directory are loaded and matched by guid,
+    /// version and architecture. Matching files take precedence over the built-in offset list.
+    #[cfg(feature = "offset_files")]
+    pub fn offset_db_dir<P: AsRef<Path>>(mut self, offset_db_dir: P) -> Self {
+        self.offset_db_dir = Some(offset_db_dir.as_ref().to_path_buf());
+        self
+    }
+
+    /// Sets a function deriving the offsets without a pdb or offset file.
+    ///
+    /// It is only invoked if all other offset sources failed.
+    pub fn heuristic<F: FnOnce() -> Result<Win32Offsets> + 'a>(mut self, heuristic: F) -> Self {
+        self.heuristic = Some(Box::new(heuristic));
+        self
+    }
+
     pub fn guid(mut self, guid: Win32Guid) -> Self {
         self.guid = Some(guid);
         self
@@ -172,6 +315,29 @@ impl<'a> Win32OffsetBuilder<'a> {
         &self.winver
     }
 
+    /// Adds guids that are tried in order if the guid does not match.
+    ///
+    /// This is useful if the codeview record of the kernel is corrupted in memory
+    /// and multiple candidate kernels of the same build exist.
+    pub fn guid_candidates<I: IntoIterator<Item = Win32Guid>>(mut self, guids: I) -> Self {
+        self.guid_candidates.extend(guids);
+        self
+    }
+
+    pub fn get_guid_candidates(&self) -> &[Win32Guid] {
+        &self.guid_candidates
+    }
+
+    /// Adds versions that are tried in order if no offsets match the winver.
+    pub fn winver_candidates<I: IntoIterator<Item = Win32Version>>(mut self, winvers: I) -> Self {
+        self.winver_candidates.extend(winvers);
+        self
+    }
+
+    pub fn get_winver_candidates(&self) -> &[Win32Version] {
+        &self.winver_candidates
+    }
+
     pub fn arch(mut self, arch: Win32OffsetsArchitecture) -> Self {
         self.arch = Some(arch);
         self
@@ -180,4 +346,43 @@ impl<'a> Win32OffsetBuilder<'a> {
     pub fn get_arch(&self) -> &Option<Win32OffsetsArchitecture> {
         &self.arch
     }
+
+    /// Allows falling back to the offsets of the nearest known build of the same version.
+    ///
+    /// The fallback is only used if neither the pdb nor an offset file of the exact build
+    /// could be found. It is enabled by default.
+    pub fn allow_nearest_build(mut self, allow_nearest_build: bool) -> Self {
+        self.allow_nearest_build = allow_nearest_build;
+        self
+    }
+
+    pub fn get_allow_nearest_build(&self) -> bool {
+        self.allow_nearest_build
+    }
+}
+
+/// Loads all offset files in the given directory.
+///
+/// Files that cannot be parsed are skipped.
+#[cfg(feature = "offset_files")]
+fn load_offset_db_dir(dir: &Path) -> Result<Vec<Win32OffsetFile>> {
+    use super::Win32OffsetFileFormat;
+
+    let entries = std::fs::read_dir(dir)
+        .map_err(|err| Error(ErrorOrigin::OsLayer, ErrorKind::UnableToReadDir).log_error(err))?;
+
+    let mut offsets = vec![];
+    for path in entries.filter_map(|e| e.ok()).map(|e| e.path()) {
+        if Win32OffsetFileFormat::from_path(&path).is_none() {
+            continue;
+        }
+
+        match Win32OffsetFile::from_file(&path) {
+            Ok(file) => offsets.push(file),
+            Err(err) => log::warn!("skipping offset file {:?}: {}", path, err),
+        }
+    }
+
+    log::debug!("loaded {} offset files from {:?}", offsets.len(), dir);
+    Ok(offsets)
 }
@@ -0,0 +1,150 @@
+use std::prelude::v1::*;
+
+use std::convert::TryFrom;
+use std::fmt::Write;
+
+use super::{Win32OffsetFile, Win32OffsetHeader, Win32Offsets};
+
+/// Layout of a generated C header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Win32CHeaderStyle {
+    /// One `#define WIN32_OFFSET_<NAME>` per offset
+    Defines,
+    /// A `Win32OffsetTable` struct and a `static const` instance of it
+    Struct,
+}
+
+impl Win32OffsetFile {
+    /// Generates a C header containing the offsets of this file.
+    ///
+    /// Fields of nested tables are flattened, e.g. `mmvad.vad_node` becomes `mmvad_vad_node`.
+    pub fn to_c_header(&self, style: Win32CHeaderStyle) -> String {
+        let mut out = String::new();
+
+        // writing into a string cannot fail
+        let _ = write_header_comment(&mut out, &self.header);
+        out.push_str("#ifndef MEMFLOW_WIN32_OFFSETS_H\n#define MEMFLOW_WIN32_OFFSETS_H\n\n");
+
+        match style {
+            Win32CHeaderStyle::Defines => {
+                for (name, value) in self.offsets.offsets() {
+                    let _ = writeln!(
+                        out,
+                        "#define WIN32_OFFSET_{} 0x{:x}",
+                        c_name(name).to_uppercase(),
+                        value
+                    );
+                }
+            }
+            Win32CHeaderStyle::Struct => {
+                out.push_str("#include <stdint.h>\n\ntypedef struct Win32OffsetTable {\n");
+                for (name, _) in self.offsets.offsets() {
+                    let _ = writeln!(out, "    uint32_t {};", c_name(name));
+                }
+                out.push_str(
+                    "} Win32OffsetTable;\n\nstatic const Win32OffsetTable WIN32_OFFSETS = {\n",
+                );
+                for (name, value) in self.offsets.offsets() {
+                    let _ = writeln!(out, "    .{} = 0x{:x},", c_name(name), value);
+                }
+                out.push_str("};\n");
+            }
+        }
+
+        out.push_str("\n#endif /* MEMFLOW_WIN32_OFFSETS_H */\n");
+        out
+    }
+}
+
+impl Win32Offsets {
+    /// Generates a C header containing the offsets together with the given header.
+    ///
+    /// See [`Win32OffsetFile::to_c_header`] for details.
+    pub fn to_c_header(&self, header: Win32OffsetHeader, style: Win32CHeaderStyle) -> String {
+        Win32OffsetFile {
+            header,
+            offsets: self.0,
+        }
+        .to_c_header(style)
+    }
+}
+
+fn write_header_comment(out: &mut String, header: &Win32OffsetHeader) -> std::fmt::Result {
+    writeln!(out, "/* generated by memflow-win32, do not edit */")?;
+    if let (Ok(file_name), Ok(guid)) = (
+        <&str>::try_from(&header.pdb_file_name),
+        <&str>::try_from(&header.pdb_guid),
+    ) {
+        if !file_name.is_empty() {
+            writeln!(out, "/* pdb: {} {} */", file_name, guid)?;
+        }
+    }
+    writeln!(
+        out,
+        "/* version: {}.{}.{} {} */\n",
+        header.nt_major_version, header.nt_minor_version, header.nt_build_number, header.arch
+    )
+}
+
+fn c_name(name: &str) -> String {
+    name.replace('.', "_")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::offsets::offset_table::{Win32OffsetsArchitecture, OFFSET_NAMES};
+
+    fn offset_file() -> Win32OffsetFile {
+        let mut file = Win32OffsetFile {
+            header: Win32OffsetHeader {
+                pdb_file_name: "ntkrnlmp.pdb".into(),
+                pdb_guid: "3844DBB920174967BE7AA4A2C20430FA2".into(),
+                nt_major_version: 10,
+                nt_minor_version: 0,
+                nt_build_number: 19041,
+                arch: Win32OffsetsArchitecture::X64,
+            },
+            offsets: Default::default(),
+        };
+        file.offsets.eproc_link = 0x448;
+        file.offsets.mmvad.vad_node = 0x10;
+        file
+    }
+
+    #[test]
+    fn defines() {
+        let header = offset_file().to_c_header(Win32CHeaderStyle::Defines);
+        assert!(header.starts_with("/* generated by memflow-win32, do not edit */\n"));
+        assert!(header.contains("/* pdb: ntkrnlmp.pdb 3844DBB920174967BE7AA4A2C20430FA2 */\n"));
+        assert!(header.contains("/* version: 10.0.19041 X64 */\n"));
+        assert!(header.contains("#define WIN32_OFFSET_EPROC_LINK 0x448\n"));
+        assert!(header.contains("#define WIN32_OFFSET_MMVAD_VAD_NODE 0x10\n"));
+        assert!(header.ends_with("#endif /* MEMFLOW_WIN32_OFFSETS_H */\n"));
+        assert_eq!(
+            header.matches("#define WIN32_OFFSET_").count(),
+            OFFSET_NAMES.len()
+        );
+    }
+
+    #[test]
+    fn struct_style() {
+        let header = offset_file().to_c_header(Win32CHeaderStyle::Struct);
+        assert!(header.contains("#include <stdint.h>\n"));
+        assert!(header.contains("    uint32_t eproc_link;\n"));
+        assert!(header.contains("    uint32_t mmvad_vad_node;\n"));
+        assert!(header.contains("    .eproc_link = 0x448,\n"));
+        assert!(header.contains("    .mmvad_vad_node = 0x10,\n"));
+        assert!(!header.contains("#define WIN32_OFFSET_"));
+    }
+
+    #[test]
+    fn without_pdb() {
+        let mut file = offset_file();
+        file.header.pdb_file_name = Default::default();
+        let header = file.to_c_header(Win32CHeaderStyle::Defines);
+        assert!(!header.contains("/* pdb:"));
+        assert!(header.contains("/* version: 10.0.19041 X64 */\n"));
+    }
+}
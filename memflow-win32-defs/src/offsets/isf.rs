@@ -0,0 +1,694 @@
+/*!
+Import and export of Volatility3 Intermediate Symbol Format (ISF) profiles.
+
+ISF files are json documents generated from the pdb of a kernel. They contain
+all user types with their field offsets as well as the rva of all public symbols.
+This allows reusing the large set of existing profiles instead of downloading the pdb.
+
+With the `symstore` feature enabled profiles can also be generated from a pdb.
+The exported profiles only contain the given user types and are intended for tools
+that need the kernel structures used by memflow. Structures that are referenced by the
+exported types but not exported themselves are emitted as opaque structures without fields
+so that every type reference in the profile can be resolved.
+*/
+use std::prelude::v1::*;
+
+use std::fs;
+use std::path::Path;
+
+use super::{MmVadOffsetTable, Win32OffsetFile, Win32OffsetHeader, Win32OffsetTable};
+use super::{Win32Offsets, Win32OffsetsArchitecture};
+
+use memflow::error::{Error, ErrorKind, ErrorOrigin, Result};
+
+use serde_json::Value;
+#[cfg(feature = "symstore")]
+use {
+    super::{pdb::PdbField, PdbStruct, PdbSymbols},
+    log::debug,
+    serde_json::{json, Map},
+    std::collections::BTreeSet,
+    std::convert::TryFrom,
+};
+
+// IMAGE_FILE_MACHINE_* values stored in the isf metadata
+const MACHINE_I386: u64 = 0x14c;
+const MACHINE_AMD64: u64 = 0x8664;
+const MACHINE_ARM64: u64 = 0xaa64;
+
+/// User types exported by default, these are all types required to build a [`Win32OffsetTable`]
+/// and to walk the user mode structures of a process
+pub const DEFAULT_ISF_TYPES: &[&str] = &[
+    "_LIST_ENTRY",
+    "_UNICODE_STRING",
+    "_KPROCESS",
+    "_EPROCESS",
+    "_KTHREAD",
+    "_ETHREAD",
+    "_KAPC_STATE",
+    "_KAPC",
+    "_KPRCB",
+    "_KPCR",
+    "_KDPC_DATA",
+    "_TEB",
+    "_TEB32",
+    "_PEB",
+    "_PEB_LDR_DATA",
+    "_LDR_DATA_TABLE_ENTRY",
+    "_RTL_USER_PROCESS_PARAMETERS",
+    "_MM_SESSION_SPACE",
+    "_MMVAD_SHORT",
+    "_MMVAD",
+    "_MMVAD_FLAGS",
+    "_SUBSECTION",
+    "_CONTROL_AREA",
+    "_FILE_OBJECT",
+];
+
+// isf name, kind, size and signedness of the primitive types emitted by the pdb parser
+#[cfg(feature = "symstore")]
+const BASE_TYPES: &[(&str, &str, &str, usize, bool)] = &[
+    ("void", "void", "void", 0, false),
+    ("char", "char", "char", 1, true),
+    ("unsigned char", "unsigned char", "char", 1, false),
+    ("int8_t", "char", "int", 1, true),
+    ("uint8_t", "unsigned char", "int", 1, false),
+    ("int16_t", "short", "int", 2, true),
+    ("uint16_t", "unsigned short", "int", 2, false),
+    ("int32_t", "long", "int", 4, true),
+    ("uint32_t", "unsigned long", "int", 4, false),
+    ("int64_t", "long long", "int", 8, true),
+    ("uint64_t", "unsigned long long", "int", 8, false),
+    ("float", "float", "float", 4, true),
+    ("double", "double", "float", 8, true),
+    ("bool", "bool", "bool", 1, false),
+];
+
+/// A parsed Volatility3 ISF profile
+#[derive(Debug, Clone)]
+pub struct IsfProfile {
+    root: Value,
+}
+
+impl IsfProfile {
+    /// Parses an isf profile from its json representation.
+    pub fn new(json: &str) -> Result<Self> {
+        let root: Value = serde_json::from_str(json).map_err(|err| {
+            Error(ErrorOrigin::OsLayer, ErrorKind::Configuration)
+                .log_error(format!("unable to parse isf profile: {}", err))
+        })?;
+
+        if !root["user_types"].is_object() || !root["symbols"].is_object() {
+            return Err(Error(ErrorOrigin::OsLayer, ErrorKind::Configuration)
+                .log_error("isf profile does not contain user types or symbols"));
+        }
+
+        Ok(Self { root })
+    }
+
+    /// Reads an uncompressed isf profile from a file.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let content = fs::read_to_string(path).map_err(|err| {
+            Error(ErrorOrigin::OsLayer, ErrorKind::UnableToReadFile).log_error(err)
+        })?;
+        Self::new(&content)
+    }
+
+    /// Returns true if the profile contains the given user type.
+    pub fn has_type(&self, name: &str) -> bool {
+        self.root["user_types"][name].is_object()
+    }
+
+    /// Returns the byte offset of a field inside of a user type.
+    pub fn find_field(&self, type_name: &str, field: &str) -> Option<u32> {
+        self.root["user_types"][type_name]["fields"][field]["offset"]
+            .as_u64()
+            .map(|o| o as u32)
+    }
+
+    /// Returns the bit position of a bitfield inside of a user type.
+    pub fn find_bit_position(&self, type_name: &str, field: &str) -> Option<u32> {
+        let ty = &self.root["user_types"][type_name]["fields"][field]["type"];
+        if ty["kind"] == "bitfield" {
+            ty["bit_position"].as_u64().map(|b| b as u32)
+        } else {
+            None
+        }
+    }
+
+    /// Returns the rva of a symbol.
+    pub fn find_symbol(&self, name: &str) -> Option<u32> {
+        self.root["symbols"][name]["address"]
+            .as_u64()
+            .map(|a| a as u32)
+    }
+
+    /// Builds an offset header from the pdb metadata of the profile.
+    ///
+    /// The nt version is only filled in if the profile contains it.
+    pub fn header(&self) -> Result<Win32OffsetHeader> {
+        let windows = &self.root["metadata"]["windows"];
+        let pdb = &windows["pdb"];
+
+        let file_name = pdb["database"].as_str().ok_or_else(|| {
+            Error(ErrorOrigin::OsLayer, ErrorKind::Configuration)
+                .log_error("isf profile does not contain a pdb name")
+        })?;
+        let guid = pdb["GUID"].as_str().ok_or_else(|| {
+            Error(ErrorOrigin::OsLayer, ErrorKind::Configuration)
+                .log_error("isf profile does not contain a pdb guid")
+        })?;
+        let age = pdb["age"].as_u64().unwrap_or_default();
+
+        let arch = match pdb["machine_type"].as_u64() {
+            Some(MACHINE_I386) => Win32OffsetsArchitecture::X86,
+            Some(MACHINE_AMD64) => Win32OffsetsArchitecture::X64,
+            Some(MACHINE_ARM64) => Win32OffsetsArchitecture::AArch64,
+            machine_type => {
+                return Err(Error(ErrorOrigin::OsLayer, ErrorKind::NotSupported)
+                    .log_error(format!("unsupported isf machine type: {:?}", machine_type)))
+            }
+        };
+
+        let version = |name: &str| windows[name].as_u64().unwrap_or_default() as u32;
+
+        Ok(Win32OffsetHeader {
+            pdb_file_name: file_name.into(),
+            // pdb guids are stored as the concatenation of the guid and the age
+            pdb_guid: format!("{}{:X}", guid.replace('-', "").to_uppercase(), age).into(),
+            nt_major_version: version("major"),
+            nt_minor_version: version("minor"),
+            nt_build_number: version("build"),
+            arch,
+        })
+    }
+
+    /// Builds the offset table from the types and symbols of the profile.
+    pub fn offset_table(&self) -> Result<Win32OffsetTable> {
+        for name in [
+            "_LIST_ENTRY",
+            "_KPROCESS",
+            "_EPROCESS",
+            "_ETHREAD",
+            "_KTHREAD",
+            "_TEB",
+        ] {
+            if !self.has_type(name) {
+                return Err(Error(ErrorOrigin::OsLayer, ErrorKind::Offset)
+                    .log_warn(format!("{} not found", name)));
+            }
+        }
+
+        let required = |type_name: &str, field: &str| -> Result<u32> {
+            self.find_field(type_name, field).ok_or_else(|| {
+                Error(ErrorOrigin::OsLayer, ErrorKind::Offset)
+                    .log_warn(format!("{}::{} not found", type_name, field))
+            })
+        };
+        let optional =
+            |type_name: &str, field: &str| self.find_field(type_name, field).unwrap_or(0);
+        let symbol = |name: &str| {
+            self.find_symbol(name)
+                .or_else(|| self.find_symbol(&format!("_{}", name)))
+                .unwrap_or(0)
+        };
+
+        Ok(Win32OffsetTable {
+            list_blink: required("_LIST_ENTRY", "Blink")?,
+            eproc_link: required("_EPROCESS", "ActiveProcessLinks")?,
+
+            phys_mem_block: symbol("MmPhysicalMemoryBlock"),
+            ki_processor_block: symbol("KiProcessorBlock"),
+
+            kproc_dtb: required("_KPROCESS", "DirectoryTableBase")?,
+            kproc_user_dtb: optional("_KPROCESS", "UserDirectoryTableBase"),
+            kproc_kernel_time: optional("_KPROCESS", "KernelTime"),
+            kproc_user_time: optional("_KPROCESS", "UserTime"),
+            kproc_cycle_time: optional("_KPROCESS", "CycleTime"),
+
+            eproc_pid: required("_EPROCESS", "UniqueProcessId")?,
+            eproc_name: required("_EPROCESS", "ImageFileName")?,
+            eproc_peb: required("_EPROCESS", "Peb")?,
+            eproc_section_base: required("_EPROCESS", "SectionBaseAddress")?,
+            eproc_exit_status: required("_EPROCESS", "ExitStatus")?,
+            eproc_thread_list: required("_EPROCESS", "ThreadListHead")?,
+            // windows 10 uses an uppercase W whereas older windows versions (windows 7) uses a lowercase w
+            eproc_wow64: self
+                .find_field("_EPROCESS", "WoW64Process")
+                .or_else(|| self.find_field("_EPROCESS", "Wow64Process"))
+                .unwrap_or(0),
+            eproc_vad_root: required("_EPROCESS", "VadRoot")?,
+            eproc_console_host_process: optional("_EPROCESS", "ConsoleHostProcess"),
+            eproc_audit_image_name: optional("_EPROCESS", "SeAuditProcessCreationInfo"),
+            eproc_create_time: optional("_EPROCESS", "CreateTime"),
+            eproc_session: optional("_EPROCESS", "Session"),
+            mm_session_space_id: optional("_MM_SESSION_SPACE", "SessionId"),
+
+            kprcb_cpu_type: optional("_KPRCB", "CpuType"),
+            kprcb_cpu_step: optional("_KPRCB", "CpuStep"),
+            kprcb_vendor_string: optional("_KPRCB", "VendorString"),
+            kprcb_current_thread: optional("_KPRCB", "CurrentThread"),
+            kprcb_idle_thread: optional("_KPRCB", "IdleThread"),
+            // the dpc queues are kept in DpcData since nt 6.0
+            kprcb_dpc_queue_depth: match self.find_field("_KPRCB", "DpcData") {
+                Some(dpc_data) => dpc_data + optional("_KDPC_DATA", "DpcQueueDepth"),
+                None => optional("_KPRCB", "DpcQueueDepth"),
+            },
+            // the prcb is embedded as PrcbData on x86 where Prcb is a pointer to it
+            kpcr_prcb: self
+                .find_field("_KPCR", "PrcbData")
+                .or_else(|| self.find_field("_KPCR", "Prcb"))
+                .unwrap_or(0),
+            kpcr_irql: optional("_KPCR", "Irql"),
+
+            kthread_teb: required("_KTHREAD", "Teb")?,
+            kthread_initial_stack: optional("_KTHREAD", "InitialStack"),
+            kthread_stack_limit: optional("_KTHREAD", "StackLimit"),
+            kthread_stack_base: optional("_KTHREAD", "StackBase"),
+            kthread_kernel_stack: optional("_KTHREAD", "KernelStack"),
+            kthread_apc_state: optional("_KTHREAD", "ApcState"),
+            kapc_state_apc_list_head: optional("_KAPC_STATE", "ApcListHead"),
+            kapc_state_process: optional("_KAPC_STATE", "Process"),
+            kapc_apc_list_entry: optional("_KAPC", "ApcListEntry"),
+            kapc_kernel_routine: optional("_KAPC", "KernelRoutine"),
+            kapc_rundown_routine: optional("_KAPC", "RundownRoutine"),
+            kapc_normal_routine: optional("_KAPC", "NormalRoutine"),
+            kapc_normal_context: optional("_KAPC", "NormalContext"),
+            kthread_kernel_time: optional("_KTHREAD", "KernelTime"),
+            kthread_user_time: optional("_KTHREAD", "UserTime"),
+            kthread_cycle_time: optional("_KTHREAD", "CycleTime"),
+            ethread_list_entry: required("_ETHREAD", "ThreadListEntry")?,
+            teb_peb: required("_TEB", "ProcessEnvironmentBlock")?,
+            teb_peb_x86: optional("_TEB32", "ProcessEnvironmentBlock"),
+
+            mmvad: MmVadOffsetTable {
+                // On older versions VadNode was inlined into the structure - LeftChild being the first
+                // field of a binary tree.
+                vad_node: self
+                    .find_field("_MMVAD_SHORT", "VadNode")
+                    .or_else(|| self.find_field("_MMVAD_SHORT", "LeftChild"))
+                    .unwrap_or(0),
+                starting_vpn: optional("_MMVAD_SHORT", "StartingVpn"),
+                ending_vpn: optional("_MMVAD_SHORT", "EndingVpn"),
+                starting_vpn_high: optional("_MMVAD_SHORT", "StartingVpnHigh"),
+                ending_vpn_high: optional("_MMVAD_SHORT", "EndingVpnHigh"),
+                u: optional("_MMVAD_SHORT", "u"),
+                protection_bit: self
+                    .find_bit_position("_MMVAD_FLAGS", "Protection")
+                    .unwrap_or(0),
+                vad_type_bit: self
+                    .find_bit_position("_MMVAD_FLAGS", "VadType")
+                    .unwrap_or(0),
+                private_memory_bit: self
+                    .find_bit_position("_MMVAD_FLAGS", "PrivateMemory")
+                    .unwrap_or(0),
+                subsection: optional("_MMVAD", "Subsection"),
+                subsection_control_area: optional("_SUBSECTION", "ControlArea"),
+                control_area_file_pointer: optional("_CONTROL_AREA", "FilePointer"),
+                file_object_file_name: optional("_FILE_OBJECT", "FileName"),
+            },
+        })
+    }
+
+    /// Converts the profile into an offset file including the header.
+    pub fn offset_file(&self) -> Result<Win32OffsetFile> {
+        Ok(Win32OffsetFile {
+            header: self.header()?,
+            offsets: self.offset_table()?,
+        })
+    }
+
+    /// Serializes the profile as json.
+    pub fn to_json(&self) -> Result<String> {
+        serde_json::to_string_pretty(&self.root).map_err(|err| {
+            Error(ErrorOrigin::OsLayer, ErrorKind::Configuration)
+                .log_error(format!("unable to serialize isf profile: {}", err))
+        })
+    }
+
+    /// Writes the profile as an uncompressed json file.
+    pub fn to_file<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        fs::write(path, self.to_json()?)
+            .map_err(|err| Error(ErrorOrigin::OsLayer, ErrorKind::UnableToWriteFile).log_error(err))
+    }
+}
+
+#[cfg(feature = "symstore")]
+impl IsfProfile {
+    /// Generates a profile from a kernel pdb.
+    ///
+    /// The profile contains all public symbols and the given user types.
+    /// Types that are not part of the pdb are skipped. The metadata is taken from `header`.
+    pub fn from_pdb_slice(
+        pdb_slice: &[u8],
+        header: &Win32OffsetHeader,
+        types: &[&str],
+    ) -> Result<Self> {
+        let symbols = PdbSymbols::new(pdb_slice).map_err(|_| {
+            Error(ErrorOrigin::OsLayer, ErrorKind::Offset).log_warn("Symbols not found")
+        })?;
+
+        let (machine_type, pointer_size) = match header.arch {
+            Win32OffsetsArchitecture::X86 => (MACHINE_I386, 4),
+            Win32OffsetsArchitecture::X64 => (MACHINE_AMD64, 8),
+            Win32OffsetsArchitecture::AArch64 => (MACHINE_ARM64, 8),
+        };
+
+        let mut user_types = Map::new();
+        for &name in types.iter() {
+            let ty = PdbStruct::new(pdb_slice, name).map_err(|_| {
+                Error(ErrorOrigin::OsLayer, ErrorKind::Offset)
+                    .log_warn(format!("unable to parse {}", name))
+            })?;
+            if ty.size() == 0 {
+                debug!("{} not found in pdb, skipping", name);
+                continue;
+            }
+
+            let fields = ty
+                .fields()
+                .map(|(field_name, field)| {
+                    (
+                        field_name.to_string(),
+                        json!({
+                            "offset": field.offset,
+                            "type": isf_field_type(field, pointer_size),
+                        }),
+                    )
+                })
+                .collect::<Map<_, _>>();
+
+            user_types.insert(
+                name.to_string(),
+                json!({
+                    "kind": "struct",
+                    "size": ty.size(),
+                    "fields": fields,
+                }),
+            );
+        }
+
+        add_opaque_types(&mut user_types, |name| {
+            PdbStruct::new(pdb_slice, name)
+                .map(|ty| ty.size())
+                .unwrap_or_default()
+        });
+
+        let symbols = symbols
+            .symbols()
+            .map(|(name, rva)| (name.to_string(), json!({ "address": rva })))
+            .collect::<Map<_, _>>();
+
+        let mut base_types = BASE_TYPES
+            .iter()
+            .map(|(_, name, kind, size, signed)| {
+                (
+                    name.to_string(),
+                    json!({
+                        "kind": kind,
+                        "size": size,
+                        "signed": signed,
+                        "endian": "little",
+                    }),
+                )
+            })
+            .collect::<Map<_, _>>();
+        base_types.insert(
+            "pointer".to_string(),
+            json!({
+                "kind": "int",
+                "size": pointer_size,
+                "signed": false,
+                "endian": "little",
+            }),
+        );
+
+        // pdb guids are stored as the concatenation of the guid and the age
+        let pdb_guid = <&str>::try_from(&header.pdb_guid).unwrap_or_default();
+        let (guid, age) = pdb_guid.split_at(pdb_guid.len().min(32));
+        let age = u32::from_str_radix(age, 16).unwrap_or_default();
+
+        Ok(Self {
+            root: json!({
+                "metadata": {
+                    "format": "6.2.0",
+                    "producer": {
+                        "name": "memflow-win32-defs",
+                        "version": env!("CARGO_PKG_VERSION"),
+                    },
+                    "windows": {
+                        "pdb": {
+                            "GUID": guid,
+                            "age": age,
+                            "database": <&str>::try_from(&header.pdb_file_name).unwrap_or_default(),
+                            "machine_type": machine_type,
+                        },
+                        "major": header.nt_major_version,
+                        "minor": header.nt_minor_version,
+                        "build": header.nt_build_number,
+                    },
+                },
+                "base_types": base_types,
+                "user_types": user_types,
+                "enums": {},
+                "symbols": symbols,
+            }),
+        })
+    }
+}
+
+/// Adds an opaque user type for every structure that is referenced but not part of `user_types`.
+#[cfg(feature = "symstore")]
+fn add_opaque_types<F: FnMut(&str) -> usize>(user_types: &mut Map<String, Value>, mut size_of: F) {
+    let mut referenced = BTreeSet::new();
+    for ty in user_types.values() {
+        struct_references(ty, &mut referenced);
+    }
+
+    for name in referenced.into_iter() {
+        if !user_types.contains_key(&name) {
+            debug!(
+                "{} is referenced but not exported, emitting it as opaque type",
+                name
+            );
+            let size = size_of(&name);
+            user_types.insert(
+                name,
+                json!({
+                    "kind": "struct",
+                    "size": size,
+                    "fields": {},
+                }),
+            );
+        }
+    }
+}
+
+/// Collects the names of all structures referenced by the type descriptors in `value`.
+#[cfg(feature = "symstore")]
+fn struct_references(value: &Value, out: &mut BTreeSet<String>) {
+    match value {
+        Value::Object(map) => {
+            if let (Some("struct"), Some(name)) = (
+                map.get("kind").and_then(Value::as_str),
+                map.get("name").and_then(Value::as_str),
+            ) {
+                out.insert(name.to_string());
+            }
+            map.values().for_each(|v| struct_references(v, out));
+        }
+        Value::Array(values) => values.iter().for_each(|v| struct_references(v, out)),
+        _ => {}
+    }
+}
+
+/// Converts the type of a pdb field into an isf type descriptor.
+#[cfg(feature = "symstore")]
+fn isf_field_type(field: &PdbField, pointer_size: usize) -> Value {
+    let (ty, _) = isf_type(&field.type_name, pointer_size);
+    if field.bit_length > 0 {
+        json!({
+            "kind": "bitfield",
+            "bit_position": field.bit_offset,
+            "bit_length": field.bit_length,
+            "type": ty,
+        })
+    } else {
+        ty
+    }
+}
+
+/// Converts a type name emitted by the pdb parser into an isf type descriptor and its size if known.
+#[cfg(feature = "symstore")]
+fn isf_type(name: &str, pointer_size: usize) -> (Value, Option<usize>) {
+    let name = name.trim();
+    if let Some(name) = name
+        .strip_prefix("const ")
+        .or_else(|| name.strip_prefix("volatile "))
+    {
+        return isf_type(name, pointer_size);
+    }
+
+    if let Some(open) = name.rfind('[').filter(|_| name.ends_with(']')) {
+        // the pdb parser emits the size of the array in bytes
+        let len = name[open + 1..name.len() - 1]
+            .parse::<usize>()
+            .unwrap_or_default();
+        let ty = match isf_type(&name[..open], pointer_size) {
+            (subtype, Some(size)) if size > 0 => json!({
+                "kind": "array",
+                "count": len / size,
+                "subtype": subtype,
+            }),
+            // arrays of unknown types are represented as byte arrays
+            _ => json!({
+                "kind": "array",
+                "count": len,
+                "subtype": { "kind": "base", "name": "unsigned char" },
+            }),
+        };
+        return (ty, Some(len));
+    }
+
+    if let Some(subtype) = name.strip_suffix('*') {
+        return (
+            json!({
+                "kind": "pointer",
+                "subtype": isf_type(subtype, pointer_size).0,
+            }),
+            Some(pointer_size),
+        );
+    }
+
+    match BASE_TYPES.iter().find(|(pdb_name, ..)| *pdb_name == name) {
+        Some((_, isf_name, _, size, _)) => {
+            (json!({ "kind": "base", "name": isf_name }), Some(*size))
+        }
+        None => (json!({ "kind": "struct", "name": name }), None),
+    }
+}
+
+impl Win32Offsets {
+    /// Constructs the offsets from a Volatility3 ISF profile.
+    pub fn from_isf_str(json: &str) -> Result<Self> {
+        Ok(Self(IsfProfile::new(json)?.offset_table()?))
+    }
+
+    /// Constructs the offsets from a Volatility3 ISF profile file.
+    ///
+    /// Compressed profiles (`.json.xz`) have to be decompressed first.
+    pub fn from_isf_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Ok(Self(IsfProfile::from_file(path)?.offset_table()?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use memflow::dataview::Pod;
+    use std::convert::TryFrom;
+
+    const PROFILE: &str = r#"{
+        "metadata": {
+            "windows": {
+                "pdb": {
+                    "GUID": "3844dbb9-2017-4967-be7a-a4a2c20430fa",
+                    "age": 2,
+                    "database": "ntkrnlmp.pdb",
+                    "machine_type": 34404
+                },
+                "major": 10,
+                "minor": 0,
+                "build": 19041
+            }
+        },
+        "base_types": {},
+        "user_types": {
+            "_LIST_ENTRY": { "kind": "struct", "size": 16, "fields": {
+                "Flink": { "offset": 0, "type": { "kind": "pointer" } },
+                "Blink": { "offset": 8, "type": { "kind": "pointer" } }
+            } },
+            "_KPROCESS": { "kind": "struct", "size": 8, "fields": {
+                "DirectoryTableBase": { "offset": 40, "type": { "kind": "base", "name": "unsigned long long" } }
+            } },
+            "_EPROCESS": { "kind": "struct", "size": 8, "fields": {
+                "ActiveProcessLinks": { "offset": 392, "type": { "kind": "struct", "name": "_LIST_ENTRY" } },
+                "UniqueProcessId": { "offset": 384, "type": { "kind": "pointer" } },
+                "ImageFileName": { "offset": 736, "type": { "kind": "array" } },
+                "Peb": { "offset": 824, "type": { "kind": "pointer" } },
+                "SectionBaseAddress": { "offset": 816, "type": { "kind": "pointer" } },
+                "ExitStatus": { "offset": 1060, "type": { "kind": "base", "name": "long" } },
+                "ThreadListHead": { "offset": 776, "type": { "kind": "struct", "name": "_LIST_ENTRY" } },
+                "WoW64Process": { "offset": 800, "type": { "kind": "pointer" } },
+                "VadRoot": { "offset": 1592, "type": { "kind": "struct", "name": "_RTL_AVL_TREE" } }
+            } },
+            "_KTHREAD": { "kind": "struct", "size": 8, "fields": {
+                "Teb": { "offset": 240, "type": { "kind": "pointer" } }
+            } },
+            "_ETHREAD": { "kind": "struct", "size": 8, "fields": {
+                "ThreadListEntry": { "offset": 1256, "type": { "kind": "struct", "name": "_LIST_ENTRY" } }
+            } },
+            "_TEB": { "kind": "struct", "size": 8, "fields": {
+                "ProcessEnvironmentBlock": { "offset": 96, "type": { "kind": "pointer" } }
+            } },
+            "_MMVAD_FLAGS": { "kind": "struct", "size": 4, "fields": {
+                "Protection": { "offset": 0, "type": { "kind": "bitfield", "bit_position": 7, "bit_length": 5 } }
+            } }
+        },
+        "enums": {},
+        "symbols": {
+            "MmPhysicalMemoryBlock": { "address": 3279072 }
+        }
+    }"#;
+
+    #[test]
+    fn import_export() {
+        let profile = IsfProfile::new(PROFILE).unwrap();
+        let file = profile.offset_file().unwrap();
+        assert_eq!(
+            <&str>::try_from(&file.header.pdb_guid).unwrap(),
+            "3844DBB920174967BE7AA4A2C20430FA2"
+        );
+        assert_eq!(file.header.nt_build_number, 19041);
+        assert_eq!(file.offsets.eproc_link, 392);
+        assert_eq!(file.offsets.eproc_wow64, 800);
+        assert_eq!(file.offsets.phys_mem_block, 3279072);
+        assert_eq!(file.offsets.mmvad.protection_bit, 7);
+
+        // exporting and importing the profile again yields the same offsets
+        let exported = IsfProfile::new(&profile.to_json().unwrap()).unwrap();
+        let roundtrip = exported.offset_file().unwrap();
+        assert_eq!(roundtrip.offsets.as_bytes(), file.offsets.as_bytes());
+        assert_eq!(
+            <&str>::try_from(&roundtrip.header.pdb_file_name).unwrap(),
+            "ntkrnlmp.pdb"
+        );
+    }
+
+    #[test]
+    fn missing_types() {
+        let profile = IsfProfile::new(&PROFILE.replace("_KTHREAD", "_KTHREAD_X")).unwrap();
+        assert!(profile.offset_table().is_err());
+        assert!(IsfProfile::new("{}").is_err());
+    }
+
+    #[cfg(feature = "symstore")]
+    #[test]
+    fn opaque_types() {
+        let profile = IsfProfile::new(PROFILE).unwrap();
+        let mut user_types = profile.root["user_types"].as_object().unwrap().clone();
+        add_opaque_types(&mut user_types, |name| match name {
+            "_RTL_AVL_TREE" => 8,
+            _ => 0,
+        });
+
+        // _LIST_ENTRY is exported, only the missing _RTL_AVL_TREE is added
+        assert_eq!(user_types.len(), 8);
+        assert_eq!(user_types["_RTL_AVL_TREE"]["size"], 8);
+        assert!(user_types["_RTL_AVL_TREE"]["fields"]
+            .as_object()
+            .unwrap()
+            .is_empty());
+    }
+}
@@ -6,16 +6,28 @@ pub mod pdb;
 #[cfg(feature = "symstore")]
 pub mod symstore;
 
+pub mod c_header;
+#[cfg(feature = "isf")]
+pub mod isf;
+#[cfg(feature = "offset_files")]
+pub mod offset_file;
 pub mod offset_table;
 #[doc(hidden)]
 pub use offset_table::{
     MmVadOffsetTable, Win32OffsetFile, Win32OffsetHeader, Win32OffsetTable,
-    Win32OffsetsArchitecture,
+    Win32OffsetsArchitecture, OFFSET_NAMES,
 };
 
+pub use c_header::Win32CHeaderStyle;
+
+#[cfg(feature = "isf")]
+pub use isf::{IsfProfile, DEFAULT_ISF_TYPES};
+#[cfg(feature = "offset_files")]
+pub use offset_file::Win32OffsetFileFormat;
+
 #[cfg(feature = "symstore")]
 pub use {
-    self::pdb::{PdbStruct, PdbSymbols},
+    self::pdb::{undecorate_symbol, PdbStruct, PdbSymbols},
     symstore::*,
 };
 
@@ -44,6 +56,7 @@ pub struct Win32ArchOffsets {
     pub ldr_data_base_name: usize,  // _LDR_DATA_TABLE_ENTRY::BaseDllName
     pub ppm_image_path_name: usize, // _RTL_USER_PROCESS_PARAMETERS::ImagePathName
     pub ppm_command_line: usize,    // _RTL_USER_PROCESS_PARAMETERS::CommandLine
+    pub ppm_console_handle: usize,  // _RTL_USER_PROCESS_PARAMETERS::ConsoleHandle
 }
 
 pub const X86: Win32ArchOffsets = Win32ArchOffsets {
@@ -56,6 +69,7 @@ pub const X86: Win32ArchOffsets = Win32ArchOffsets {
     ldr_data_base_name: 0x2c,
     ppm_image_path_name: 0x38,
     ppm_command_line: 0x40,
+    ppm_console_handle: 0x10,
 };
 
 pub const X64: Win32ArchOffsets = Win32ArchOffsets {
@@ -68,6 +82,7 @@ pub const X64: Win32ArchOffsets = Win32ArchOffsets {
     ldr_data_base_name: 0x58,
     ppm_image_path_name: 0x60,
     ppm_command_line: 0x70,
+    ppm_console_handle: 0x10,
 };
 
 pub const AARCH64: Win32ArchOffsets = Win32ArchOffsets {
@@ -80,6 +95,7 @@ pub const AARCH64: Win32ArchOffsets = Win32ArchOffsets {
     ldr_data_base_name: 0x58,
     ppm_image_path_name: 0x60,
     ppm_command_line: 0x70,
+    ppm_console_handle: 0x10,
 };
 
 impl Win32OffsetsArchitecture {
@@ -178,6 +194,12 @@ impl Win32Offsets {
             .copied()
             .unwrap_or(0);
 
+        let ki_processor_block = symbols
+            .find_symbol("KiProcessorBlock")
+            .or_else(|| symbols.find_symbol("_KiProcessorBlock"))
+            .copied()
+            .unwrap_or(0);
+
         let list_blink = list
             .find_field("Blink")
             .ok_or_else(|| {
@@ -201,6 +223,17 @@ impl Win32Offsets {
                     .log_warn("_KPROCESS::DirectoryTableBase not found")
             })?
             .offset as _;
+        // kernels with kva shadowing keep a separate dtb for user mode
+        let kproc_user_dtb = kproc
+            .find_field("UserDirectoryTableBase")
+            .map(|f| f.offset)
+            .unwrap_or(0) as _;
+        // the cpu times are optional and only used for computing the cpu usage
+        let kproc_field =
+            |name: &str| -> u32 { kproc.find_field(name).map(|f| f.offset).unwrap_or(0) as _ };
+        let kproc_kernel_time = kproc_field("KernelTime");
+        let kproc_user_time = kproc_field("UserTime");
+        let kproc_cycle_time = kproc_field("CycleTime");
         let eproc_pid = eproc
             .find_field("UniqueProcessId")
             .ok_or_else(|| {
@@ -259,6 +292,17 @@ impl Win32Offsets {
                 Error(ErrorOrigin::OsLayer, ErrorKind::Offset).log_warn("_KTHREAD::Teb not found")
             })?
             .offset as _;
+        // the stack bounds are optional and only used for walking kernel stacks
+        let kthread_field =
+            |name: &str| -> u32 { kthread.find_field(name).map(|f| f.offset).unwrap_or(0) as _ };
+        let kthread_initial_stack = kthread_field("InitialStack");
+        let kthread_stack_limit = kthread_field("StackLimit");
+        let kthread_stack_base = kthread_field("StackBase");
+        let kthread_kernel_stack = kthread_field("KernelStack");
+        let kthread_apc_state = kthread_field("ApcState");
+        let kthread_kernel_time = kthread_field("KernelTime");
+        let kthread_user_time = kthread_field("UserTime");
+        let kthread_cycle_time = kthread_field("CycleTime");
         let ethread_list_entry = ethread
             .find_field("ThreadListEntry")
             .ok_or_else(|| {
@@ -295,6 +339,65 @@ impl Win32Offsets {
             })?
             .offset as _;
 
+        // console host tracking was introduced together with conhost in windows 7
+        let eproc_console_host_process = eproc
+            .find_field("ConsoleHostProcess")
+            .map(|f| f.offset)
+            .unwrap_or(0) as _;
+
+        // _SE_AUDIT_PROCESS_CREATION_INFO only contains the pointer to the image name
+        let eproc_audit_image_name = eproc
+            .find_field("SeAuditProcessCreationInfo")
+            .map(|f| f.offset)
+            .unwrap_or(0) as _;
+
+        let eproc_create_time = eproc
+            .find_field("CreateTime")
+            .map(|f| f.offset)
+            .unwrap_or(0) as _;
+
+        // session attribution is optional, processes without a session (e.g. System) have a null pointer
+        let eproc_session = eproc.find_field("Session").map(|f| f.offset).unwrap_or(0) as _;
+        let mm_session_space_id = PdbStruct::new(pdb_slice, "_MM_SESSION_SPACE")
+            .ok()
+            .and_then(|s| s.find_field("SessionId").map(|f| f.offset))
+            .unwrap_or(0) as _;
+
+        // processor information is optional and only used for platform reporting
+        let kprcb = PdbStruct::new(pdb_slice, "_KPRCB").ok();
+        let kprcb_field = |name: &str| -> u32 {
+            kprcb
+                .as_ref()
+                .and_then(|s| s.find_field(name).map(|f| f.offset))
+                .unwrap_or(0) as _
+        };
+        let kprcb_cpu_type = kprcb_field("CpuType");
+        let kprcb_cpu_step = kprcb_field("CpuStep");
+        let kprcb_vendor_string = kprcb_field("VendorString");
+        let kprcb_current_thread = kprcb_field("CurrentThread");
+        let kprcb_idle_thread = kprcb_field("IdleThread");
+        // the dpc queues are kept in DpcData since nt 6.0
+        let kprcb_dpc_queue_depth = match kprcb.as_ref().and_then(|s| s.find_field("DpcData")) {
+            Some(dpc_data) => PdbStruct::new(pdb_slice, "_KDPC_DATA")
+                .ok()
+                .and_then(|s| s.find_field("DpcQueueDepth"))
+                .map(|f| dpc_data.offset + f.offset)
+                .unwrap_or(0) as _,
+            None => kprcb_field("DpcQueueDepth"),
+        };
+        // the prcb is embedded as PrcbData on x86 where Prcb is a pointer to it
+        let kpcr = PdbStruct::new(pdb_slice, "_KPCR").ok();
+        let kpcr_prcb = kpcr
+            .as_ref()
+            .and_then(|s| s.find_field("PrcbData").or_else(|| s.find_field("Prcb")))
+            .map(|f| f.offset)
+            .unwrap_or(0) as _;
+        let kpcr_irql = kpcr
+            .as_ref()
+            .and_then(|s| s.find_field("Irql"))
+            .map(|f| f.offset)
+            .unwrap_or(0) as _;
+
         // On older versions VadNode was inlined into the structure - LeftChild being the first
         // field of a binary tree.
         let vad_node = mm_vad
@@ -325,14 +428,48 @@ impl Win32Offsets {
             .find_field("Protection")
             .map(|f| f.bit_offset)
             .unwrap_or(0) as _;
+        let vad_type_bit = mm_vad_flags
+            .find_field("VadType")
+            .map(|f| f.bit_offset)
+            .unwrap_or(0) as _;
+        let private_memory_bit = mm_vad_flags
+            .find_field("PrivateMemory")
+            .map(|f| f.bit_offset)
+            .unwrap_or(0) as _;
+
+        // the mapped file of image and section vads is optional and only used for hollowing detection
+        let pdb_field = |type_name: &str, name: &str| -> u32 {
+            PdbStruct::new(pdb_slice, type_name)
+                .ok()
+                .and_then(|s| s.find_field(name).map(|f| f.offset))
+                .unwrap_or(0) as _
+        };
+        let subsection = pdb_field("_MMVAD", "Subsection");
+        let subsection_control_area = pdb_field("_SUBSECTION", "ControlArea");
+        let control_area_file_pointer = pdb_field("_CONTROL_AREA", "FilePointer");
+        let file_object_file_name = pdb_field("_FILE_OBJECT", "FileName");
+
+        // the apc queues are optional and only used for enumerating pending apcs
+        let kapc_state_apc_list_head = pdb_field("_KAPC_STATE", "ApcListHead");
+        let kapc_state_process = pdb_field("_KAPC_STATE", "Process");
+        let kapc_apc_list_entry = pdb_field("_KAPC", "ApcListEntry");
+        let kapc_kernel_routine = pdb_field("_KAPC", "KernelRoutine");
+        let kapc_rundown_routine = pdb_field("_KAPC", "RundownRoutine");
+        let kapc_normal_routine = pdb_field("_KAPC", "NormalRoutine");
+        let kapc_normal_context = pdb_field("_KAPC", "NormalContext");
 
         Ok(Self(Win32OffsetTable {
             list_blink,
             eproc_link,
 
             phys_mem_block,
+            ki_processor_block,
 
             kproc_dtb,
+            kproc_user_dtb,
+            kproc_kernel_time,
+            kproc_user_time,
+            kproc_cycle_time,
 
             eproc_pid,
             eproc_name,
@@ -342,8 +479,37 @@ impl Win32Offsets {
             eproc_thread_list,
             eproc_wow64,
             eproc_vad_root,
+            eproc_console_host_process,
+            eproc_audit_image_name,
+            eproc_create_time,
+            eproc_session,
+            mm_session_space_id,
+
+            kprcb_cpu_type,
+            kprcb_cpu_step,
+            kprcb_vendor_string,
+            kprcb_current_thread,
+            kprcb_idle_thread,
+            kprcb_dpc_queue_depth,
+            kpcr_prcb,
+            kpcr_irql,
 
             kthread_teb,
+            kthread_initial_stack,
+            kthread_stack_limit,
+            kthread_stack_base,
+            kthread_kernel_stack,
+            kthread_apc_state,
+            kapc_state_apc_list_head,
+            kapc_state_process,
+            kapc_apc_list_entry,
+            kapc_kernel_routine,
+            kapc_rundown_routine,
+            kapc_normal_routine,
+            kapc_normal_context,
+            kthread_kernel_time,
+            kthread_user_time,
+            kthread_cycle_time,
             ethread_list_entry,
             teb_peb,
             teb_peb_x86,
@@ -356,6 +522,12 @@ impl Win32Offsets {
                 ending_vpn_high,
                 u,
                 protection_bit,
+                vad_type_bit,
+                private_memory_bit,
+                subsection,
+                subsection_control_area,
+                control_area_file_pointer,
+                file_object_file_name,
             },
         }))
     }
@@ -373,12 +545,36 @@ impl Win32Offsets {
     pub fn phys_mem_block(&self) -> usize {
         self.0.phys_mem_block as usize
     }
+    /// KiProcessorBlock offset
+    pub fn ki_processor_block(&self) -> usize {
+        self.0.ki_processor_block as usize
+    }
 
     /// _KPROCESS::DirectoryTableBase offset
     /// Exists since version 3.10
     pub fn kproc_dtb(&self) -> usize {
         self.0.kproc_dtb as usize
     }
+    /// _KPROCESS::UserDirectoryTableBase offset
+    /// Only present on kernels with kva shadow support
+    pub fn kproc_user_dtb(&self) -> usize {
+        self.0.kproc_user_dtb as usize
+    }
+    /// _KPROCESS::KernelTime offset
+    /// Kernel mode time of the exited threads of the process in clock ticks
+    pub fn kproc_kernel_time(&self) -> usize {
+        self.0.kproc_kernel_time as usize
+    }
+    /// _KPROCESS::UserTime offset
+    /// User mode time of the exited threads of the process in clock ticks
+    pub fn kproc_user_time(&self) -> usize {
+        self.0.kproc_user_time as usize
+    }
+    /// _KPROCESS::CycleTime offset
+    /// Exists since version 6.0
+    pub fn kproc_cycle_time(&self) -> usize {
+        self.0.kproc_cycle_time as usize
+    }
     /// _EPROCESS::UniqueProcessId offset
     /// Exists since version 3.10
     pub fn eproc_pid(&self) -> usize {
@@ -419,12 +615,153 @@ impl Win32Offsets {
     pub fn eproc_vad_root(&self) -> usize {
         self.0.eproc_vad_root as usize
     }
+    /// _EPROCESS::ConsoleHostProcess offset
+    /// Exists since version 6.1
+    pub fn eproc_console_host_process(&self) -> usize {
+        self.0.eproc_console_host_process as usize
+    }
+    /// _EPROCESS::SeAuditProcessCreationInfo offset
+    /// Exists since version 5.1
+    pub fn eproc_audit_image_name(&self) -> usize {
+        self.0.eproc_audit_image_name as usize
+    }
+    /// _EPROCESS::CreateTime offset
+    /// Exists since version 5.0
+    pub fn eproc_create_time(&self) -> usize {
+        self.0.eproc_create_time as usize
+    }
+    /// _EPROCESS::Session offset
+    /// Exists since version 5.0
+    pub fn eproc_session(&self) -> usize {
+        self.0.eproc_session as usize
+    }
+    /// _MM_SESSION_SPACE::SessionId offset
+    /// Exists since version 5.0
+    pub fn mm_session_space_id(&self) -> usize {
+        self.0.mm_session_space_id as usize
+    }
+
+    /// _KPRCB::CpuType offset
+    /// Exists since version 5.0
+    pub fn kprcb_cpu_type(&self) -> usize {
+        self.0.kprcb_cpu_type as usize
+    }
+    /// _KPRCB::CpuStep offset
+    /// Exists since version 5.0
+    pub fn kprcb_cpu_step(&self) -> usize {
+        self.0.kprcb_cpu_step as usize
+    }
+    /// _KPRCB::VendorString offset
+    /// Exists since version 5.0
+    pub fn kprcb_vendor_string(&self) -> usize {
+        self.0.kprcb_vendor_string as usize
+    }
+    /// _KPRCB::CurrentThread offset
+    /// Thread that is currently running on the processor
+    pub fn kprcb_current_thread(&self) -> usize {
+        self.0.kprcb_current_thread as usize
+    }
+    /// _KPRCB::IdleThread offset
+    /// Idle thread of the processor, the processor is idle while it is the current thread
+    pub fn kprcb_idle_thread(&self) -> usize {
+        self.0.kprcb_idle_thread as usize
+    }
+    /// _KPRCB::DpcData[0].DpcQueueDepth offset, _KPRCB::DpcQueueDepth before version 6.0
+    /// Number of dpcs that are queued on the processor
+    pub fn kprcb_dpc_queue_depth(&self) -> usize {
+        self.0.kprcb_dpc_queue_depth as usize
+    }
+    /// _KPCR::Prcb offset, _KPCR::PrcbData on x86
+    /// The _KPRCB is embedded in the _KPCR, its address is the processor block entry
+    pub fn kpcr_prcb(&self) -> usize {
+        self.0.kpcr_prcb as usize
+    }
+    /// _KPCR::Irql offset
+    /// Interrupt request level the processor is currently running at, 0 on aarch64
+    pub fn kpcr_irql(&self) -> usize {
+        self.0.kpcr_irql as usize
+    }
 
     /// _KTHREAD::Teb offset
     /// Exists since version 6.2
     pub fn kthread_teb(&self) -> usize {
         self.0.kthread_teb as usize
     }
+    /// _KTHREAD::InitialStack offset
+    /// Top of the kernel stack the thread has been created with
+    pub fn kthread_initial_stack(&self) -> usize {
+        self.0.kthread_initial_stack as usize
+    }
+    /// _KTHREAD::StackLimit offset
+    /// Lowest address of the current kernel stack of the thread
+    pub fn kthread_stack_limit(&self) -> usize {
+        self.0.kthread_stack_limit as usize
+    }
+    /// _KTHREAD::StackBase offset
+    /// Exists since version 5.1
+    pub fn kthread_stack_base(&self) -> usize {
+        self.0.kthread_stack_base as usize
+    }
+    /// _KTHREAD::KernelStack offset
+    /// Saved kernel stack pointer, only valid while the thread is not running
+    pub fn kthread_kernel_stack(&self) -> usize {
+        self.0.kthread_kernel_stack as usize
+    }
+    /// _KTHREAD::ApcState offset
+    /// Apc state of the process the thread currently executes in
+    pub fn kthread_apc_state(&self) -> usize {
+        self.0.kthread_apc_state as usize
+    }
+    /// _KAPC_STATE::ApcListHead offset
+    /// Kernel and user mode apc queues, two consecutive list heads
+    pub fn kapc_state_apc_list_head(&self) -> usize {
+        self.0.kapc_state_apc_list_head as usize
+    }
+    /// _KAPC_STATE::Process offset
+    /// Process the apc state belongs to
+    pub fn kapc_state_process(&self) -> usize {
+        self.0.kapc_state_process as usize
+    }
+    /// _KAPC::ApcListEntry offset
+    /// Link in the apc queue of the target thread
+    pub fn kapc_apc_list_entry(&self) -> usize {
+        self.0.kapc_apc_list_entry as usize
+    }
+    /// _KAPC::KernelRoutine offset
+    /// Routine that is called at APC_LEVEL before the normal routine
+    pub fn kapc_kernel_routine(&self) -> usize {
+        self.0.kapc_kernel_routine as usize
+    }
+    /// _KAPC::RundownRoutine offset
+    /// Routine that is called if the thread exits with the apc still queued
+    pub fn kapc_rundown_routine(&self) -> usize {
+        self.0.kapc_rundown_routine as usize
+    }
+    /// _KAPC::NormalRoutine offset
+    /// Routine that is called at PASSIVE_LEVEL or in user mode
+    pub fn kapc_normal_routine(&self) -> usize {
+        self.0.kapc_normal_routine as usize
+    }
+    /// _KAPC::NormalContext offset
+    /// Context argument passed to the normal routine
+    pub fn kapc_normal_context(&self) -> usize {
+        self.0.kapc_normal_context as usize
+    }
+    /// _KTHREAD::KernelTime offset
+    /// Kernel mode time of the thread in clock ticks
+    pub fn kthread_kernel_time(&self) -> usize {
+        self.0.kthread_kernel_time as usize
+    }
+    /// _KTHREAD::UserTime offset
+    /// User mode time of the thread in clock ticks
+    pub fn kthread_user_time(&self) -> usize {
+        self.0.kthread_user_time as usize
+    }
+    /// _KTHREAD::CycleTime offset
+    /// Exists since version 6.0
+    pub fn kthread_cycle_time(&self) -> usize {
+        self.0.kthread_cycle_time as usize
+    }
     /// _ETHREAD::ThreadListEntry offset
     /// Exists since version 6.2
     pub fn ethread_list_entry(&self) -> usize {
@@ -441,7 +778,7 @@ impl Win32Offsets {
         self.0.teb_peb_x86 as usize
     }
 
-    /// _MMVAD_SHORT offsets
+    /// _MMVAD_SHORT offsets and the offsets required to resolve the file mapped by a vad
     pub fn mm_vad(&self) -> MmVadOffsetTable {
         self.0.mmvad
     }
@@ -0,0 +1,193 @@
+use std::prelude::v1::*;
+
+use std::fs;
+use std::path::Path;
+
+use super::{Win32OffsetFile, Win32OffsetHeader, Win32Offsets};
+
+use memflow::error::{Error, ErrorKind, ErrorOrigin, Result};
+
+/// Serialization format of an offset file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Win32OffsetFileFormat {
+    Toml,
+    Json,
+    Yaml,
+}
+
+impl Win32OffsetFileFormat {
+    /// Guesses the format from the extension of the given path.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Option<Self> {
+        let extension = path.as_ref().extension()?.to_str()?.to_lowercase();
+        match extension.as_str() {
+            "toml" => Some(Self::Toml),
+            "json" => Some(Self::Json),
+            "yaml" | "yml" => Some(Self::Yaml),
+            _ => None,
+        }
+    }
+
+    /// Guesses the format from the contents of a file.
+    ///
+    /// Files starting with a table header or containing a `key = value` line are treated as toml,
+    /// a ` = ` inside of a yaml value (e.g. `key: a = b`) is not mistaken for a toml assignment.
+    pub fn from_content(content: &str) -> Self {
+        let content = content.trim_start();
+        if content.starts_with('{') {
+            Self::Json
+        } else if content.starts_with('[') || content.lines().any(is_toml_assignment) {
+            Self::Toml
+        } else {
+            Self::Yaml
+        }
+    }
+}
+
+/// Checks if the line assigns a value to a bare or quoted toml key.
+fn is_toml_assignment(line: &str) -> bool {
+    match line.split_once(" = ") {
+        Some((key, _)) => {
+            let key = key.trim();
+            !key.is_empty() && !key.starts_with('#') && !key.starts_with('-') && !key.contains(':')
+        }
+        None => false,
+    }
+}
+
+impl Win32OffsetFile {
+    /// Parses an offset file in the given format.
+    pub fn from_str_with_format(content: &str, format: Win32OffsetFileFormat) -> Result<Self> {
+        let parsed = match format {
+            Win32OffsetFileFormat::Toml => toml::from_str(content).map_err(|err| err.to_string()),
+            Win32OffsetFileFormat::Json => {
+                serde_json::from_str(content).map_err(|err| err.to_string())
+            }
+            Win32OffsetFileFormat::Yaml => {
+                serde_yaml::from_str(content).map_err(|err| err.to_string())
+            }
+        };
+
+        parsed.map_err(|err| {
+            Error(ErrorOrigin::OsLayer, ErrorKind::Configuration)
+                .log_error(format!("unable to parse {:?} offset file: {}", format, err))
+        })
+    }
+
+    /// Reads an offset file in toml, json or yaml format.
+    ///
+    /// The format is derived from the file extension and guessed from the contents otherwise.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let content = fs::read_to_string(path.as_ref()).map_err(|err| {
+            Error(ErrorOrigin::OsLayer, ErrorKind::UnableToReadFile).log_error(err)
+        })?;
+
+        let format = Win32OffsetFileFormat::from_path(path.as_ref())
+            .unwrap_or_else(|| Win32OffsetFileFormat::from_content(&content));
+        Self::from_str_with_format(&content, format)
+    }
+
+    /// Writes the offset file in toml, json or yaml format.
+    ///
+    /// The format is derived from the file extension, toml is used if the extension is unknown.
+    pub fn to_file<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let format =
+            Win32OffsetFileFormat::from_path(path.as_ref()).unwrap_or(Win32OffsetFileFormat::Toml);
+        let content = self.to_string_with_format(format)?;
+        fs::write(path.as_ref(), content)
+            .map_err(|err| Error(ErrorOrigin::OsLayer, ErrorKind::UnableToWriteFile).log_error(err))
+    }
+
+    /// Serializes the offset file in the given format.
+    pub fn to_string_with_format(&self, format: Win32OffsetFileFormat) -> Result<String> {
+        let serialized = match format {
+            Win32OffsetFileFormat::Toml => {
+                toml::to_string_pretty(self).map_err(|err| err.to_string())
+            }
+            Win32OffsetFileFormat::Json => {
+                serde_json::to_string_pretty(self).map_err(|err| err.to_string())
+            }
+            Win32OffsetFileFormat::Yaml => {
+                serde_yaml::to_string(self).map_err(|err| err.to_string())
+            }
+        };
+
+        serialized.map_err(|err| {
+            Error(ErrorOrigin::OsLayer, ErrorKind::Configuration).log_error(format!(
+                "unable to serialize {:?} offset file: {}",
+                format, err
+            ))
+        })
+    }
+}
+
+impl Win32Offsets {
+    /// Reads the offsets from an offset file in toml, json or yaml format.
+    ///
+    /// The header of the file is not checked against the target.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Ok(Self(Win32OffsetFile::from_file(path)?.offsets))
+    }
+
+    /// Writes the offsets together with the given header as an offset file.
+    ///
+    /// See [`Win32OffsetFile::to_file`] for details.
+    pub fn to_file<P: AsRef<Path>>(&self, header: Win32OffsetHeader, path: P) -> Result<()> {
+        Win32OffsetFile {
+            header,
+            offsets: self.0,
+        }
+        .to_file(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn format_from_path() {
+        assert_eq!(
+            Win32OffsetFileFormat::from_path("a/b.TOML"),
+            Some(Win32OffsetFileFormat::Toml)
+        );
+        assert_eq!(
+            Win32OffsetFileFormat::from_path("b.json"),
+            Some(Win32OffsetFileFormat::Json)
+        );
+        assert_eq!(
+            Win32OffsetFileFormat::from_path("b.yml"),
+            Some(Win32OffsetFileFormat::Yaml)
+        );
+        assert_eq!(Win32OffsetFileFormat::from_path("b.txt"), None);
+        assert_eq!(Win32OffsetFileFormat::from_path("b"), None);
+    }
+
+    #[test]
+    fn format_from_content() {
+        assert_eq!(
+            Win32OffsetFileFormat::from_content("  {\"header\": {}}"),
+            Win32OffsetFileFormat::Json
+        );
+        assert_eq!(
+            Win32OffsetFileFormat::from_content("[header]\nnt_major_version = 10\n"),
+            Win32OffsetFileFormat::Toml
+        );
+        assert_eq!(
+            Win32OffsetFileFormat::from_content("# offsets\nheader.nt_major_version = 10\n"),
+            Win32OffsetFileFormat::Toml
+        );
+        assert_eq!(
+            Win32OffsetFileFormat::from_content("header:\n  nt_major_version: 10\n"),
+            Win32OffsetFileFormat::Yaml
+        );
+        // assignments inside of yaml values and comments are not toml
+        assert_eq!(
+            Win32OffsetFileFormat::from_content("header:\n  pdb_file_name: a = b\n# c = d\n"),
+            Win32OffsetFileFormat::Yaml
+        );
+        assert_eq!(
+            Win32OffsetFileFormat::from_content("- a = b\n"),
+            Win32OffsetFileFormat::Yaml
+        );
+    }
+}
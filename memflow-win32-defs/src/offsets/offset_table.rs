@@ -176,16 +176,30 @@ impl<'de, const N: usize> ::serde::de::Deserialize<'de> for BinaryString<N> {
 }
 
 #[repr(C, align(4))]
-#[derive(Debug, Copy, Clone, Pod)]
+#[derive(Debug, Copy, Clone, Default, Pod)]
 #[cfg_attr(feature = "serde", derive(::serde::Serialize, ::serde::Deserialize))]
 pub struct Win32OffsetTable {
     pub list_blink: u32,
     pub eproc_link: u32,
 
     pub phys_mem_block: u32,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub ki_processor_block: u32,
 
     /// Since version 3.10
     pub kproc_dtb: u32,
+    /// Only present on kernels with kva shadow support
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub kproc_user_dtb: u32,
+    /// `_KPROCESS::KernelTime`
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub kproc_kernel_time: u32,
+    /// `_KPROCESS::UserTime`
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub kproc_user_time: u32,
+    /// Since version 6.0
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub kproc_cycle_time: u32,
     /// Since version 3.10
     pub eproc_pid: u32,
     /// Since version 3.10
@@ -202,9 +216,94 @@ pub struct Win32OffsetTable {
     pub eproc_wow64: u32,
     /// Since version xxx
     pub eproc_vad_root: u32,
+    /// Since version 6.1
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub eproc_console_host_process: u32,
+    /// Since version 5.1
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub eproc_audit_image_name: u32,
+    /// Since version 5.0
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub eproc_create_time: u32,
+    /// Since version 5.0
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub eproc_session: u32,
+    /// Since version 5.0
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub mm_session_space_id: u32,
+
+    /// Since version 5.0
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub kprcb_cpu_type: u32,
+    /// Since version 5.0
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub kprcb_cpu_step: u32,
+    /// Since version 5.0
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub kprcb_vendor_string: u32,
+    /// `_KPRCB::CurrentThread`
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub kprcb_current_thread: u32,
+    /// `_KPRCB::IdleThread`
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub kprcb_idle_thread: u32,
+    /// `_KPRCB::DpcData[0].DpcQueueDepth`
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub kprcb_dpc_queue_depth: u32,
+    /// Offset of the embedded `_KPRCB` in the `_KPCR`
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub kpcr_prcb: u32,
+    /// `_KPCR::Irql`
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub kpcr_irql: u32,
 
     /// Since version 6.2
     pub kthread_teb: u32,
+    /// `_KTHREAD::InitialStack`
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub kthread_initial_stack: u32,
+    /// `_KTHREAD::StackLimit`
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub kthread_stack_limit: u32,
+    /// Since version 5.1
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub kthread_stack_base: u32,
+    /// `_KTHREAD::KernelStack`
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub kthread_kernel_stack: u32,
+    /// `_KTHREAD::ApcState`
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub kthread_apc_state: u32,
+    /// `_KAPC_STATE::ApcListHead`
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub kapc_state_apc_list_head: u32,
+    /// `_KAPC_STATE::Process`
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub kapc_state_process: u32,
+    /// `_KAPC::ApcListEntry`
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub kapc_apc_list_entry: u32,
+    /// `_KAPC::KernelRoutine`
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub kapc_kernel_routine: u32,
+    /// `_KAPC::RundownRoutine`
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub kapc_rundown_routine: u32,
+    /// `_KAPC::NormalRoutine`
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub kapc_normal_routine: u32,
+    /// `_KAPC::NormalContext`
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub kapc_normal_context: u32,
+    /// `_KTHREAD::KernelTime`
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub kthread_kernel_time: u32,
+    /// `_KTHREAD::UserTime`
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub kthread_user_time: u32,
+    /// Since version 6.0
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub kthread_cycle_time: u32,
     /// Since version 6.2
     pub ethread_list_entry: u32,
     /// Since version x.x
@@ -215,8 +314,157 @@ pub struct Win32OffsetTable {
     pub mmvad: MmVadOffsetTable,
 }
 
+/// Names of all offsets in a [`Win32OffsetTable`] in declaration order
+pub const OFFSET_NAMES: &[&str] = &[
+    "list_blink",
+    "eproc_link",
+    "phys_mem_block",
+    "ki_processor_block",
+    "kproc_dtb",
+    "kproc_user_dtb",
+    "kproc_kernel_time",
+    "kproc_user_time",
+    "kproc_cycle_time",
+    "eproc_pid",
+    "eproc_name",
+    "eproc_peb",
+    "eproc_section_base",
+    "eproc_exit_status",
+    "eproc_thread_list",
+    "eproc_wow64",
+    "eproc_vad_root",
+    "eproc_console_host_process",
+    "eproc_audit_image_name",
+    "eproc_create_time",
+    "eproc_session",
+    "mm_session_space_id",
+    "kprcb_cpu_type",
+    "kprcb_cpu_step",
+    "kprcb_vendor_string",
+    "kprcb_current_thread",
+    "kprcb_idle_thread",
+    "kprcb_dpc_queue_depth",
+    "kpcr_prcb",
+    "kpcr_irql",
+    "kthread_teb",
+    "kthread_initial_stack",
+    "kthread_stack_limit",
+    "kthread_stack_base",
+    "kthread_kernel_stack",
+    "kthread_apc_state",
+    "kapc_state_apc_list_head",
+    "kapc_state_process",
+    "kapc_apc_list_entry",
+    "kapc_kernel_routine",
+    "kapc_rundown_routine",
+    "kapc_normal_routine",
+    "kapc_normal_context",
+    "kthread_kernel_time",
+    "kthread_user_time",
+    "kthread_cycle_time",
+    "ethread_list_entry",
+    "teb_peb",
+    "teb_peb_x86",
+    "mmvad.vad_node",
+    "mmvad.starting_vpn",
+    "mmvad.ending_vpn",
+    "mmvad.starting_vpn_high",
+    "mmvad.ending_vpn_high",
+    "mmvad.u",
+    "mmvad.protection_bit",
+    "mmvad.vad_type_bit",
+    "mmvad.private_memory_bit",
+    "mmvad.subsection",
+    "mmvad.subsection_control_area",
+    "mmvad.control_area_file_pointer",
+    "mmvad.file_object_file_name",
+];
+
+impl Win32OffsetTable {
+    /// Returns the names and values of all offsets.
+    ///
+    /// See [`OFFSET_NAMES`] for the names of the offsets.
+    pub fn offsets(&self) -> impl Iterator<Item = (&'static str, u32)> {
+        let mut table = *self;
+        OFFSET_NAMES
+            .iter()
+            .map(move |name| (*name, *table.field_mut(name).unwrap()))
+    }
+
+    /// Returns a mutable reference to the offset with the given name.
+    ///
+    /// Names match the field names of this struct, fields of the vad table are prefixed with `mmvad.`.
+    pub fn field_mut(&mut self, name: &str) -> Option<&mut u32> {
+        match name {
+            "list_blink" => Some(&mut self.list_blink),
+            "eproc_link" => Some(&mut self.eproc_link),
+            "phys_mem_block" => Some(&mut self.phys_mem_block),
+            "ki_processor_block" => Some(&mut self.ki_processor_block),
+            "kproc_dtb" => Some(&mut self.kproc_dtb),
+            "kproc_user_dtb" => Some(&mut self.kproc_user_dtb),
+            "kproc_kernel_time" => Some(&mut self.kproc_kernel_time),
+            "kproc_user_time" => Some(&mut self.kproc_user_time),
+            "kproc_cycle_time" => Some(&mut self.kproc_cycle_time),
+            "eproc_pid" => Some(&mut self.eproc_pid),
+            "eproc_name" => Some(&mut self.eproc_name),
+            "eproc_peb" => Some(&mut self.eproc_peb),
+            "eproc_section_base" => Some(&mut self.eproc_section_base),
+            "eproc_exit_status" => Some(&mut self.eproc_exit_status),
+            "eproc_thread_list" => Some(&mut self.eproc_thread_list),
+            "eproc_wow64" => Some(&mut self.eproc_wow64),
+            "eproc_vad_root" => Some(&mut self.eproc_vad_root),
+            "eproc_console_host_process" => Some(&mut self.eproc_console_host_process),
+            "eproc_audit_image_name" => Some(&mut self.eproc_audit_image_name),
+            "eproc_create_time" => Some(&mut self.eproc_create_time),
+            "eproc_session" => Some(&mut self.eproc_session),
+            "mm_session_space_id" => Some(&mut self.mm_session_space_id),
+            "kprcb_cpu_type" => Some(&mut self.kprcb_cpu_type),
+            "kprcb_cpu_step" => Some(&mut self.kprcb_cpu_step),
+            "kprcb_vendor_string" => Some(&mut self.kprcb_vendor_string),
+            "kprcb_current_thread" => Some(&mut self.kprcb_current_thread),
+            "kprcb_idle_thread" => Some(&mut self.kprcb_idle_thread),
+            "kprcb_dpc_queue_depth" => Some(&mut self.kprcb_dpc_queue_depth),
+            "kpcr_prcb" => Some(&mut self.kpcr_prcb),
+            "kpcr_irql" => Some(&mut self.kpcr_irql),
+            "kthread_teb" => Some(&mut self.kthread_teb),
+            "kthread_initial_stack" => Some(&mut self.kthread_initial_stack),
+            "kthread_stack_limit" => Some(&mut self.kthread_stack_limit),
+            "kthread_stack_base" => Some(&mut self.kthread_stack_base),
+            "kthread_kernel_stack" => Some(&mut self.kthread_kernel_stack),
+            "kthread_apc_state" => Some(&mut self.kthread_apc_state),
+            "kapc_state_apc_list_head" => Some(&mut self.kapc_state_apc_list_head),
+            "kapc_state_process" => Some(&mut self.kapc_state_process),
+            "kapc_apc_list_entry" => Some(&mut self.kapc_apc_list_entry),
+            "kapc_kernel_routine" => Some(&mut self.kapc_kernel_routine),
+            "kapc_rundown_routine" => Some(&mut self.kapc_rundown_routine),
+            "kapc_normal_routine" => Some(&mut self.kapc_normal_routine),
+            "kapc_normal_context" => Some(&mut self.kapc_normal_context),
+            "kthread_kernel_time" => Some(&mut self.kthread_kernel_time),
+            "kthread_user_time" => Some(&mut self.kthread_user_time),
+            "kthread_cycle_time" => Some(&mut self.kthread_cycle_time),
+            "ethread_list_entry" => Some(&mut self.ethread_list_entry),
+            "teb_peb" => Some(&mut self.teb_peb),
+            "teb_peb_x86" => Some(&mut self.teb_peb_x86),
+            "mmvad.vad_node" => Some(&mut self.mmvad.vad_node),
+            "mmvad.starting_vpn" => Some(&mut self.mmvad.starting_vpn),
+            "mmvad.ending_vpn" => Some(&mut self.mmvad.ending_vpn),
+            "mmvad.starting_vpn_high" => Some(&mut self.mmvad.starting_vpn_high),
+            "mmvad.ending_vpn_high" => Some(&mut self.mmvad.ending_vpn_high),
+            "mmvad.u" => Some(&mut self.mmvad.u),
+            "mmvad.protection_bit" => Some(&mut self.mmvad.protection_bit),
+            "mmvad.vad_type_bit" => Some(&mut self.mmvad.vad_type_bit),
+            "mmvad.private_memory_bit" => Some(&mut self.mmvad.private_memory_bit),
+            "mmvad.subsection" => Some(&mut self.mmvad.subsection),
+            "mmvad.subsection_control_area" => Some(&mut self.mmvad.subsection_control_area),
+            "mmvad.control_area_file_pointer" => Some(&mut self.mmvad.control_area_file_pointer),
+            "mmvad.file_object_file_name" => Some(&mut self.mmvad.file_object_file_name),
+            _ => None,
+        }
+    }
+}
+
 #[repr(C, align(4))]
-#[derive(Debug, Copy, Clone, Pod)]
+#[derive(Debug, Copy, Clone, Default, Pod)]
 #[cfg_attr(feature = "serde", derive(::serde::Serialize, ::serde::Deserialize))]
 pub struct MmVadOffsetTable {
     pub vad_node: u32,
@@ -226,4 +474,22 @@ pub struct MmVadOffsetTable {
     pub ending_vpn_high: u32,
     pub u: u32,
     pub protection_bit: u32,
+    /// _MMVAD_FLAGS::VadType bit position
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub vad_type_bit: u32,
+    /// _MMVAD_FLAGS::PrivateMemory bit position
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub private_memory_bit: u32,
+    /// _MMVAD::Subsection offset
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub subsection: u32,
+    /// _SUBSECTION::ControlArea offset
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub subsection_control_area: u32,
+    /// _CONTROL_AREA::FilePointer offset
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub control_area_file_pointer: u32,
+    /// _FILE_OBJECT::FileName offset
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub file_object_file_name: u32,
 }
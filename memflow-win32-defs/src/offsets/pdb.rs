@@ -4,9 +4,11 @@ use std::convert::TryInto;
 use std::prelude::v1::*;
 
 use data::TypeSet;
+use std::borrow::Cow;
 use std::collections::HashMap;
 use std::{fmt, io, result};
 
+use msvc_demangler::DemangleFlags;
 use pdb::{FallibleIterator, Result, Source, SourceSlice, SourceView, TypeData, PDB};
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -26,9 +28,19 @@ impl PdbSymbols {
 
         let mut symbols = symbol_table.iter();
         while let Some(symbol) = symbols.next()? {
-            if let Ok(pdb::SymbolData::Public(data)) = symbol.parse() {
-                let rva = data.offset.to_rva(&address_map).unwrap_or_default();
-                symbol_map.insert(data.name.to_string().into(), rva.0);
+            match symbol.parse() {
+                Ok(pdb::SymbolData::Public(data)) => {
+                    let rva = data.offset.to_rva(&address_map).unwrap_or_default();
+                    symbol_map.insert(data.name.to_string().into(), rva.0);
+                }
+                // global variables that are not exported as public symbols
+                Ok(pdb::SymbolData::Data(data)) if data.global => {
+                    let rva = data.offset.to_rva(&address_map).unwrap_or_default();
+                    symbol_map
+                        .entry(data.name.to_string().into())
+                        .or_insert(rva.0);
+                }
+                _ => {}
             }
         }
 
@@ -38,6 +50,93 @@ impl PdbSymbols {
     pub fn find_symbol(&self, name: &str) -> Option<&u32> {
         self.symbol_map.get(name)
     }
+
+    /// Returns the names and rvas of all public and global symbols.
+    pub fn symbols(&self) -> impl Iterator<Item = (&str, u32)> {
+        self.symbol_map.iter().map(|(n, rva)| (n.as_str(), *rva))
+    }
+
+    /// Returns all symbols whose name starts with the given prefix.
+    pub fn find_symbols_with_prefix<'a>(
+        &'a self,
+        prefix: &'a str,
+    ) -> impl Iterator<Item = (&'a str, u32)> + 'a {
+        self.symbols().filter(move |(n, _)| n.starts_with(prefix))
+    }
+
+    /// Finds a symbol by its undecorated name.
+    ///
+    /// The exact name is preferred, otherwise the first symbol that undecorates
+    /// to the given name is returned (e.g. `KiSystemCall64` matches `_KiSystemCall64@0`
+    /// and `SystemDomain::m_pSystemDomain` matches `?m_pSystemDomain@SystemDomain@@0PEAV1@EA`).
+    pub fn find_symbol_undecorated(&self, name: &str) -> Option<u32> {
+        // demangling is expensive, only symbols containing the unqualified name are undecorated
+        let unqualified = name.rsplit("::").next().unwrap_or(name);
+        self.find_symbol(name).copied().or_else(|| {
+            self.symbols()
+                .filter(|(n, _)| n.contains(unqualified))
+                .find(|(n, _)| undecorate_symbol(n) == name)
+                .map(|(_, rva)| rva)
+        })
+    }
+}
+
+/// Undecorates a symbol name.
+///
+/// C++ names (starting with `?`) are demangled to their qualified name without any type
+/// information, e.g. `?m_pSystemDomain@SystemDomain@@0PEAV1@EA` becomes
+/// `SystemDomain::m_pSystemDomain`. The `_name` (cdecl), `_name@N` (stdcall) and `@name@N`
+/// (fastcall) decorations of 32 bit C symbols are stripped.
+/// Undecorated names and names that cannot be demangled are returned unchanged.
+pub fn undecorate_symbol(name: &str) -> Cow<'_, str> {
+    if name.starts_with('?') {
+        return match msvc_demangler::demangle(name, DemangleFlags::NAME_ONLY) {
+            Ok(demangled) => Cow::Owned(demangled),
+            Err(_) => Cow::Borrowed(name),
+        };
+    }
+
+    Cow::Borrowed(undecorate_c_symbol(name))
+}
+
+/// Strips the calling convention decoration of a C symbol name.
+fn undecorate_c_symbol(name: &str) -> &str {
+    let stripped = match name.strip_prefix('@') {
+        Some(fastcall) => fastcall,
+        None => name.strip_prefix('_').unwrap_or(name),
+    };
+
+    match stripped.rfind('@') {
+        Some(pos)
+            if pos > 0
+                && pos + 1 < stripped.len()
+                && stripped[pos + 1..].bytes().all(|b| b.is_ascii_digit()) =>
+        {
+            &stripped[..pos]
+        }
+        _ if name.starts_with('@') => name,
+        _ => stripped,
+    }
+}
+
+/// Returns the guid and age of a pdb in the format used by the symbol store.
+pub fn pdb_guid(pdb_slice: &[u8]) -> Result<String> {
+    let pdb_buffer = PdbSourceBuffer::new(pdb_slice);
+    let mut pdb = PDB::open(pdb_buffer)?;
+
+    let info = pdb.pdb_information()?;
+    // the codeview entry of the image references the age of the debug information stream
+    let age = pdb
+        .debug_information()
+        .ok()
+        .and_then(|dbi| dbi.age())
+        .unwrap_or(info.age);
+
+    Ok(format!(
+        "{}{:X}",
+        info.guid.as_simple().to_string().to_uppercase(),
+        age
+    ))
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -45,11 +144,15 @@ pub struct PdbField {
     pub type_name: String,
     pub offset: usize,
     pub bit_offset: usize,
+    /// Length of the bitfield, 0 if the field is not a bitfield
+    pub bit_length: usize,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct PdbStruct {
     field_map: HashMap<String, PdbField>,
+    size: usize,
+    fields: Vec<(String, PdbField)>,
 }
 
 impl PdbStruct {
@@ -94,26 +197,56 @@ impl PdbStruct {
             }
         }
 
+        let to_field = |f: &data::Field| PdbField {
+            type_name: f.type_name.clone(),
+            offset: f.offset as usize, // u16 can always be safely converted into usize
+            bit_offset: f.bit_offset as usize, // u8 can always be safely converted into usize
+            bit_length: f.bit_length as usize,
+        };
+
         let mut field_map = HashMap::new();
         for class in &data.classes {
             class.fields.iter().for_each(|f| {
-                field_map.insert(
-                    f.name.to_string().into_owned(),
-                    PdbField {
-                        type_name: f.type_name.clone(),
-                        offset: f.offset as usize, // u16 can always be safely converted into usize
-                        bit_offset: f.bit_offset as usize, // u8 can always be safely converted into usize
-                    },
-                );
+                field_map.insert(f.name.to_string().into_owned(), to_field(f));
             });
         }
 
-        Ok(Self { field_map })
+        // the lookup map also contains the fields of all nested types,
+        // keep the fields of the requested class separately
+        let class = data
+            .classes
+            .iter()
+            .find(|c| c.name.as_bytes() == class_name.as_bytes());
+        let size = class.map(|c| c.size as usize).unwrap_or_default();
+        let fields = class
+            .map(|c| {
+                c.fields
+                    .iter()
+                    .map(|f| (f.name.to_string().into_owned(), to_field(f)))
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        Ok(Self {
+            field_map,
+            size,
+            fields,
+        })
     }
 
     pub fn find_field(&self, name: &str) -> Option<&PdbField> {
         self.field_map.get(name)
     }
+
+    /// Returns the size of the struct in bytes.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Returns the fields declared directly in the struct in declaration order.
+    pub fn fields(&self) -> impl Iterator<Item = (&str, &PdbField)> {
+        self.fields.iter().map(|(n, f)| (n.as_str(), f))
+    }
 }
 
 pub struct PdbSourceBuffer<'a> {
@@ -179,3 +312,58 @@ impl Drop for PdbSourceBufferView {
         // no-op
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn undecorate_c_symbols() {
+        assert_eq!(undecorate_symbol("KiSystemCall64"), "KiSystemCall64");
+        assert_eq!(
+            undecorate_symbol("_PsInitialSystemProcess"),
+            "PsInitialSystemProcess"
+        );
+        assert_eq!(undecorate_symbol("_KeBugCheckEx@20"), "KeBugCheckEx");
+        assert_eq!(undecorate_symbol("@KfRaiseIrql@4"), "KfRaiseIrql");
+        // a trailing @ without a byte count is not a decoration
+        assert_eq!(undecorate_symbol("@name@"), "@name@");
+    }
+
+    #[test]
+    fn demangle_cpp_symbols() {
+        assert_eq!(
+            undecorate_symbol("?m_pSystemDomain@SystemDomain@@0PEAV1@EA"),
+            "SystemDomain::m_pSystemDomain"
+        );
+        assert_eq!(
+            undecorate_symbol("?m_pTheAppDomain@AppDomain@@0PEAV1@EA"),
+            "AppDomain::m_pTheAppDomain"
+        );
+    }
+
+    #[test]
+    fn find_undecorated() {
+        let symbols = PdbSymbols {
+            symbol_map: [
+                ("_KeBugCheckEx@20", 0x100),
+                ("?m_pSystemDomain@SystemDomain@@0PEAV1@EA", 0x200),
+                ("KiSystemCall64", 0x300),
+            ]
+            .iter()
+            .map(|(name, rva)| (name.to_string(), *rva))
+            .collect(),
+        };
+
+        assert_eq!(symbols.find_symbol_undecorated("KeBugCheckEx"), Some(0x100));
+        assert_eq!(
+            symbols.find_symbol_undecorated("SystemDomain::m_pSystemDomain"),
+            Some(0x200)
+        );
+        assert_eq!(
+            symbols.find_symbol_undecorated("KiSystemCall64"),
+            Some(0x300)
+        );
+        assert_eq!(symbols.find_symbol_undecorated("m_pSystemDomain"), None);
+    }
+}
@@ -80,6 +80,10 @@ pub fn type_name(
             }
         }
 
+        pdb::TypeData::Bitfield(data) => {
+            type_name(type_finder, data.underlying_type, needed_types)?
+        }
+
         pdb::TypeData::Array(data) => {
             let mut name = type_name(type_finder, data.element_type, needed_types)?;
             for size in data.dimensions {
@@ -103,6 +107,7 @@ pub fn type_name(
 pub struct Class<'p> {
     pub kind: pdb::ClassKind,
     pub name: pdb::RawString<'p>,
+    pub size: u64,
     pub base_classes: Vec<BaseClass>,
     pub fields: Vec<Field<'p>>,
     pub instance_methods: Vec<Method<'p>>,
@@ -153,9 +158,9 @@ impl<'p> Class<'p> {
             pdb::TypeData::Member(ref data) => {
                 // TODO: attributes (static, virtual, etc.)
 
-                let bit_offset = match type_finder.find(data.field_type)?.parse()? {
-                    pdb::TypeData::Bitfield(bitfield) => bitfield.position,
-                    _ => 0,
+                let (bit_offset, bit_length) = match type_finder.find(data.field_type)?.parse()? {
+                    pdb::TypeData::Bitfield(bitfield) => (bitfield.position, bitfield.length),
+                    _ => (0, 0),
                 };
 
                 self.fields.push(Field {
@@ -163,6 +168,7 @@ impl<'p> Class<'p> {
                     name: data.name,
                     offset: data.offset,
                     bit_offset,
+                    bit_length,
                 });
             }
 
@@ -249,6 +255,7 @@ pub struct Field<'p> {
     pub name: pdb::RawString<'p>,
     pub offset: u64,
     pub bit_offset: u8,
+    pub bit_length: u8,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -398,6 +405,7 @@ impl<'p> Data<'p> {
                 let mut class = Class {
                     kind: data.kind,
                     name: data.name,
+                    size: data.size,
                     fields: Vec::new(),
                     base_classes: Vec::new(),
                     instance_methods: Vec::new(),
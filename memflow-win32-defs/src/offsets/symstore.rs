@@ -1,82 +1,340 @@
 use std::prelude::v1::*;
 
+use super::pdb::pdb_guid;
 use crate::offsets::Win32Guid;
+#[cfg(feature = "download_progress")]
+use crate::progress::TerminalProgress;
+use crate::progress::{CancellationToken, Progress, ProgressCallback};
 
 use std::fs::{self, File};
-use std::io::{Read, Write};
+use std::io::{self, BufReader, Cursor, Read, Write};
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use dirs::cache_dir;
-use log::info;
+use log::{info, warn};
+use rustls::{ClientConfig, OwnedTrustAnchor, RootCertStore};
+use ureq::{Agent, AgentBuilder, Proxy};
 
 use memflow::error::{Error, ErrorKind, ErrorOrigin, Result};
 
-#[cfg(feature = "download_progress")]
-use {
-    indicatif::{ProgressBar, ProgressStyle},
-    progress_streams::ProgressReader,
-    std::sync::atomic::{AtomicBool, AtomicUsize, Ordering},
-    std::sync::Arc,
-};
+/// Size of the chunks progress is reported and cancellation is checked for
+const DOWNLOAD_CHUNK_SIZE: usize = 0x10000;
 
-#[cfg(feature = "download_progress")]
-fn read_to_end<T: Read>(reader: &mut T, len: usize) -> Result<Vec<u8>> {
-    let mut buffer = vec![];
+/// Delay before the first retry, doubled on every further attempt
+const RETRY_BASE_DELAY: Duration = Duration::from_millis(500);
+/// Upper bound for the delay between two attempts, also applies to `Retry-After`
+const RETRY_MAX_DELAY: Duration = Duration::from_secs(60);
+
+fn read_to_end<T: Read>(
+    reader: &mut T,
+    len: usize,
+    progress: Option<&Progress>,
+    cancellation: Option<&CancellationToken>,
+) -> Result<Vec<u8>> {
+    let mut buffer = Vec::with_capacity(len);
+    let mut chunk = vec![0; DOWNLOAD_CHUNK_SIZE];
+
+    if let Some(progress) = progress {
+        progress.start("downloading pdb", len as u64);
+    }
 
-    let total = Arc::new(AtomicUsize::new(0));
-    let mut reader = ProgressReader::new(reader, |progress: usize| {
-        total.fetch_add(progress, Ordering::SeqCst);
-    });
-    let pb = ProgressBar::new(len as u64);
-    pb.set_style(ProgressStyle::default_bar()
-        .template("{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} ({eta})")
-        .unwrap()
-        .progress_chars("#>-"));
-
-    let finished = Arc::new(AtomicBool::new(false));
-    let thread = {
-        let finished_thread = finished.clone();
-        let total_thread = total.clone();
-
-        std::thread::spawn(move || {
-            while !finished_thread.load(Ordering::Relaxed) {
-                pb.set_position(total_thread.load(Ordering::SeqCst) as u64);
-                std::thread::sleep(std::time::Duration::from_millis(10));
+    let result = loop {
+        if let Some(Err(err)) = cancellation.map(|c| c.check()) {
+            break Err(err);
+        }
+
+        match reader.read(&mut chunk) {
+            Ok(0) => break Ok(()),
+            Ok(read) => {
+                buffer.extend_from_slice(&chunk[..read]);
+                if let Some(progress) = progress {
+                    progress.update(buffer.len() as u64);
+                }
             }
-            pb.finish_with_message("downloaded");
-        })
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(_) => {
+                break Err(Error(ErrorOrigin::OsLayer, ErrorKind::Http)
+                    .log_error("unable to read from http request"))
+            }
+        }
     };
 
-    reader.read_to_end(&mut buffer).map_err(|_| {
-        Error(ErrorOrigin::OsLayer, ErrorKind::Http).log_error("unable to read from http request")
+    if let Some(progress) = progress {
+        progress.finish();
+    }
+    result.map(|_| buffer)
+}
+
+const CAB_SIGNATURE: &[u8] = b"MSCF";
+
+/// Extracts the pdb from a compressed (`.pd_`) cabinet file.
+fn decompress_cab(buffer: Vec<u8>) -> Result<Vec<u8>> {
+    info!("decompressing pdb cabinet");
+    let mut cabinet = cab::Cabinet::new(Cursor::new(buffer)).map_err(|_| {
+        Error(ErrorOrigin::OsLayer, ErrorKind::InvalidExeFile)
+            .log_error("unable to parse pdb cabinet")
     })?;
-    finished.store(true, Ordering::Relaxed);
-    thread.join().unwrap();
 
+    // compressed pdbs only contain a single file
+    let file_name = cabinet
+        .folder_entries()
+        .flat_map(|folder| folder.file_entries())
+        .map(|file| file.name().to_string())
+        .next()
+        .ok_or_else(|| {
+            Error(ErrorOrigin::OsLayer, ErrorKind::InvalidExeFile)
+                .log_error("pdb cabinet does not contain any files")
+        })?;
+
+    let mut buffer = vec![];
+    cabinet
+        .read_file(&file_name)
+        .and_then(|mut reader| reader.read_to_end(&mut buffer))
+        .map_err(|_| {
+            Error(ErrorOrigin::OsLayer, ErrorKind::InvalidExeFile)
+                .log_error("unable to decompress pdb cabinet")
+        })?;
     Ok(buffer)
 }
 
-#[cfg(not(feature = "download_progress"))]
-fn read_to_end<T: Read>(reader: &mut T, _len: usize) -> Result<Vec<u8>> {
-    let mut buffer = vec![];
-    reader.read_to_end(&mut buffer).map_err(|_| {
-        Error(ErrorOrigin::OsLayer, ErrorKind::Http).log_error("unable to read from http request")
+/// Creates a tls configuration that trusts the default roots and all certificates in `ca_file`.
+fn tls_config_with_ca(ca_file: &Path) -> Result<ClientConfig> {
+    let file = File::open(ca_file).map_err(|_| {
+        Error(ErrorOrigin::OsLayer, ErrorKind::UnableToReadFile)
+            .log_error("unable to open ca certificate file")
     })?;
-    Ok(buffer)
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file)).map_err(|_| {
+        Error(ErrorOrigin::OsLayer, ErrorKind::Configuration)
+            .log_error("unable to parse ca certificate file")
+    })?;
+
+    let mut root_store = RootCertStore::empty();
+    root_store.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|ta| {
+        OwnedTrustAnchor::from_subject_spki_name_constraints(
+            ta.subject,
+            ta.spki,
+            ta.name_constraints,
+        )
+    }));
+    let (added, ignored) = root_store.add_parsable_certificates(&certs);
+    info!("added {} ca certificates ({} ignored)", added, ignored);
+
+    Ok(ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(root_store)
+        .with_no_client_auth())
+}
+
+/// Checks that the guid and age embedded in the pdb match the requested guid.
+fn is_valid_pdb(buffer: &[u8], guid: &Win32Guid) -> bool {
+    match pdb_guid(buffer) {
+        Ok(pdb_guid) => pdb_guid.eq_ignore_ascii_case(&guid.guid),
+        Err(_) => false,
+    }
+}
+
+/// Checks that the timestamp and image size in the headers of the binary match its index.
+fn is_valid_binary(buffer: &[u8], time_date_stamp: u32, size_of_image: u32) -> bool {
+    let read_u32 = |offset: usize| {
+        buffer
+            .get(offset..offset + 4)
+            .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    };
+
+    if buffer.get(0..2) != Some(b"MZ") {
+        return false;
+    }
+    let nt_headers = match read_u32(0x3c) {
+        Some(e_lfanew) if read_u32(e_lfanew as usize) == Some(0x4550) => e_lfanew as usize,
+        _ => return false,
+    };
+
+    // the size of the image is at the same offset in the 32 and 64 bit optional header
+    read_u32(nt_headers + 0x8) == Some(time_date_stamp)
+        && read_u32(nt_headers + 0x18 + 0x38) == Some(size_of_image)
+}
+
+/// Returns all cached pdbs, they are stored as `<cache_path>/<name>.pdb/<guid>`.
+fn cached_pdbs(cache_path: &Path) -> Vec<PathBuf> {
+    let dirs = match fs::read_dir(cache_path) {
+        Ok(dirs) => dirs,
+        Err(_) => return vec![],
+    };
+
+    dirs.filter_map(|dir| dir.ok())
+        .map(|dir| dir.path())
+        .filter(|dir| {
+            dir.is_dir()
+                && dir
+                    .extension()
+                    .map(|ext| ext.eq_ignore_ascii_case("pdb"))
+                    .unwrap_or(false)
+        })
+        .filter_map(|dir| fs::read_dir(dir).ok())
+        .flat_map(|files| files.filter_map(|file| file.ok()).map(|file| file.path()))
+        .filter(|file| file.is_file())
+        .collect()
+}
+
+/// Removes the least recently used pdbs until the cache fits into `max_size` bytes.
+///
+/// The pdb in `keep` is never removed.
+fn evict_cache(cache_path: &Path, max_size: u64, keep: &Path) {
+    let mut files = cached_pdbs(cache_path)
+        .into_iter()
+        .filter_map(|file| {
+            let metadata = fs::metadata(&file).ok()?;
+            let used = metadata.accessed().or_else(|_| metadata.modified()).ok()?;
+            Some((file, metadata.len(), used))
+        })
+        .collect::<Vec<_>>();
+
+    let mut size = files.iter().map(|(_, len, _)| len).sum::<u64>();
+    files.sort_by_key(|(_, _, used)| *used);
+
+    for (file, len, _) in files.into_iter() {
+        if size <= max_size {
+            break;
+        }
+        if file == keep {
+            continue;
+        }
+
+        info!("evicting pdb from local cache: {}", file.to_string_lossy());
+        if fs::remove_file(&file).is_ok() {
+            size -= len;
+            if let Some(dir) = file.parent() {
+                // only succeeds if no other guid of this pdb is cached
+                fs::remove_dir(dir).ok();
+            }
+        }
+    }
+}
+
+/// Directory layout of a symbol store
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SymbolStoreLayout {
+    /// `<file_name>/<index>/<file_name>`
+    Flat,
+    /// `<first two characters of the file name>/<file_name>/<index>/<file_name>`,
+    /// used by stores that contain an `index2.txt`
+    TwoTier,
+}
+
+impl SymbolStoreLayout {
+    fn path(&self, guid: &Win32Guid) -> String {
+        match self {
+            SymbolStoreLayout::Flat => format!("{}/{}", guid.file_name, guid.guid),
+            SymbolStoreLayout::TwoTier => format!(
+                "{}/{}/{}",
+                guid.file_name.get(..2).unwrap_or(&guid.file_name),
+                guid.file_name,
+                guid.guid
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct SymbolServer {
+    url: String,
+    timeout: Option<Duration>,
+    /// Layout of the store, detected on the first download from this server
+    layout: Arc<Mutex<Option<SymbolStoreLayout>>>,
+}
+
+impl SymbolServer {
+    fn new(url: &str, timeout: Option<Duration>) -> Self {
+        Self {
+            url: url.trim_end_matches(|c| c == '/' || c == '\\').to_string(),
+            timeout,
+            layout: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Returns the root directory of the store if it is a `file://` url, a unc path or a local directory.
+    fn local_path(&self) -> Option<PathBuf> {
+        if let Some(path) = self.url.strip_prefix("file://") {
+            Some(PathBuf::from(path))
+        } else if self.url.starts_with("\\\\") || Path::new(&self.url).is_dir() {
+            Some(PathBuf::from(&self.url))
+        } else {
+            None
+        }
+    }
+}
+
+fn read_local_file(path: &Path) -> Result<Vec<u8>> {
+    info!(
+        "reading pdb from local symbol store: {}",
+        path.to_string_lossy()
+    );
+    fs::read(path).map_err(|_| Error(ErrorOrigin::OsLayer, ErrorKind::UnableToReadFile))
+}
+
+/// Returns the delay before the given retry attempt (starting at 1).
+///
+/// A `Retry-After` in seconds sent by the server takes precedence. Otherwise the delay doubles
+/// on every attempt and is randomized between half and the full delay so that concurrent
+/// clients do not retry in lockstep.
+fn retry_delay(attempt: u32, retry_after: Option<&str>, jitter_seed: u64) -> Duration {
+    if let Some(seconds) = retry_after.and_then(|r| r.trim().parse::<u64>().ok()) {
+        return Duration::from_secs(seconds).min(RETRY_MAX_DELAY);
+    }
+
+    let delay = RETRY_BASE_DELAY
+        .saturating_mul(1 << attempt.saturating_sub(1).min(16))
+        .min(RETRY_MAX_DELAY);
+    let half = delay.as_millis() as u64 / 2;
+    Duration::from_millis(half + jitter_seed % (half + 1))
+}
+
+/// Seed for the retry jitter, the sub-second part of the clock is sufficient for this purpose.
+fn jitter_seed() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.subsec_nanos() as u64)
+        .unwrap_or_default()
 }
 
 #[derive(Debug, Clone)]
 pub struct SymbolStore {
-    base_url: String,
+    servers: Vec<SymbolServer>,
+    timeout: Option<Duration>,
+    retries: u32,
+    proxy: Option<String>,
+    ca_file: Option<PathBuf>,
     cache_path: Option<PathBuf>,
+    max_cache_size: Option<u64>,
+    progress: Option<Progress>,
+    cancellation: Option<CancellationToken>,
 }
 
 impl Default for SymbolStore {
     fn default() -> Self {
-        let cache_dir = cache_dir().expect("unable to get cache directory");
+        // the temporary directory is used in environments without a user cache directory
+        let cache_dir = cache_dir().unwrap_or_else(|| {
+            warn!("unable to get cache directory, caching pdbs in the temporary directory");
+            std::env::temp_dir()
+        });
         Self {
-            base_url: "https://msdl.microsoft.com/download/symbols".to_string(),
+            servers: vec![SymbolServer::new(
+                "https://msdl.microsoft.com/download/symbols",
+                None,
+            )],
+            timeout: None,
+            retries: 0,
+            proxy: None,
+            ca_file: None,
             cache_path: Some(cache_dir.join("memflow")),
+            max_cache_size: None,
+            #[cfg(feature = "download_progress")]
+            progress: Some(Progress::new(TerminalProgress::default())),
+            #[cfg(not(feature = "download_progress"))]
+            progress: None,
+            cancellation: None,
         }
     }
 }
@@ -86,88 +344,395 @@ impl SymbolStore {
         Self::default()
     }
 
+    /// Loads the pdb with the given guid from the cache or downloads it.
+    ///
+    /// The guid can belong to any module, pdbs are cached as `<file_name>/<guid>` in the cache directory.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            name = "symstore_load",
+            skip_all,
+            fields(file_name = %guid.file_name, guid = %guid.guid, cached, bytes)
+        )
+    )]
     pub fn load(&self, guid: &Win32Guid) -> Result<Vec<u8>> {
+        self.load_indexed(guid, |buffer| is_valid_pdb(buffer, guid))
+    }
+
+    /// Loads the binary of a module from the cache or downloads it.
+    ///
+    /// Binaries are indexed by the `TimeDateStamp` of their file header and the `SizeOfImage`
+    /// of their optional header. They are cached next to the pdbs as `<file_name>/<index>`.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            name = "symstore_load_binary",
+            skip_all,
+            fields(file_name = %file_name, cached, bytes)
+        )
+    )]
+    pub fn load_binary(
+        &self,
+        file_name: &str,
+        time_date_stamp: u32,
+        size_of_image: u32,
+    ) -> Result<Vec<u8>> {
+        let index = Win32Guid::new(
+            file_name,
+            &format!("{:08X}{:x}", time_date_stamp, size_of_image),
+        );
+        self.load_indexed(&index, |buffer| {
+            is_valid_binary(buffer, time_date_stamp, size_of_image)
+        })
+    }
+
+    /// Loads the file with the given index from the cache or downloads it.
+    ///
+    /// Cached and downloaded files are only used if `is_valid` accepts them.
+    fn load_indexed(&self, guid: &Win32Guid, is_valid: impl Fn(&[u8]) -> bool) -> Result<Vec<u8>> {
         if let Some(cache_path) = &self.cache_path {
             let cache_dir = cache_path.join(guid.file_name.clone());
             let cache_file = cache_dir.join(guid.guid.clone());
 
-            let buffer = if cache_file.exists() {
+            #[cfg(feature = "tracing")]
+            tracing::Span::current().record("cached", cache_file.exists());
+
+            let cached = if cache_file.exists() {
                 info!(
-                    "reading pdb from local cache: {}",
+                    "reading file from local cache: {}",
                     cache_file.to_string_lossy()
                 );
-                let mut file = File::open(cache_file).map_err(|_| {
+                let mut file = File::open(&cache_file).map_err(|_| {
                     Error(ErrorOrigin::OsLayer, ErrorKind::UnableToReadFile)
-                        .log_error("unable to open pdb in local cache")
+                        .log_error("unable to open file in local cache")
                 })?;
                 let mut buffer = Vec::new();
                 file.read_to_end(&mut buffer).map_err(|_| {
                     Error(ErrorOrigin::OsLayer, ErrorKind::UnableToReadFile)
-                        .log_error("unable to read pdb from local cache")
+                        .log_error("unable to read file from local cache")
                 })?;
+
+                if is_valid(&buffer) {
+                    Some(buffer)
+                } else {
+                    warn!(
+                        "cached file {} does not match its index, downloading it again",
+                        cache_file.to_string_lossy()
+                    );
+                    None
+                }
+            } else {
+                None
+            };
+
+            let buffer = if let Some(buffer) = cached {
                 buffer
             } else {
                 let buffer = self.download(guid)?;
+                if !is_valid(&buffer) {
+                    return Err(Error(ErrorOrigin::OsLayer, ErrorKind::InvalidExeFile)
+                        .log_error("downloaded file does not match the requested index"));
+                }
 
                 if !cache_dir.exists() {
                     info!("creating cache directory {:?}", cache_dir.to_str());
                     fs::create_dir_all(&cache_dir).map_err(|_| {
                         Error(ErrorOrigin::OsLayer, ErrorKind::UnableToCreateDirectory)
-                            .log_error("unable to create folder in local pdb cache")
+                            .log_error("unable to create folder in local cache")
                     })?;
                 }
 
                 info!(
-                    "writing pdb to local cache: {}",
+                    "writing file to local cache: {}",
                     cache_file.to_string_lossy()
                 );
-                let mut file = File::create(cache_file).map_err(|_| {
+                let mut file = File::create(&cache_file).map_err(|_| {
                     Error(ErrorOrigin::OsLayer, ErrorKind::UnableToWriteFile)
-                        .log_error("unable to create file in local pdb cache")
+                        .log_error("unable to create file in local cache")
                 })?;
                 file.write_all(&buffer[..]).map_err(|_| {
                     Error(ErrorOrigin::OsLayer, ErrorKind::UnableToWriteFile)
-                        .log_error("unable to write pdb to local cache")
+                        .log_error("unable to write file to local cache")
                 })?;
 
+                if let Some(max_cache_size) = self.max_cache_size {
+                    evict_cache(cache_path, max_cache_size, &cache_file);
+                }
+
                 buffer
             };
 
+            #[cfg(feature = "tracing")]
+            tracing::Span::current().record("bytes", buffer.len());
+
             Ok(buffer)
         } else {
-            self.download(guid)
+            let buffer = self.download(guid)?;
+
+            #[cfg(feature = "tracing")]
+            tracing::Span::current().record("bytes", buffer.len());
+
+            Ok(buffer)
         }
     }
 
+    /// Loads the pdb with the given guid without blocking the async runtime.
+    ///
+    /// The download runs on the blocking thread pool of tokio, see [`SymbolStore::load`] for details.
+    #[cfg(feature = "async")]
+    pub async fn load_async(&self, guid: &Win32Guid) -> Result<Vec<u8>> {
+        let store = self.clone();
+        let guid = guid.clone();
+        tokio::task::spawn_blocking(move || store.load(&guid))
+            .await
+            .map_err(|err| {
+                Error(ErrorOrigin::OsLayer, ErrorKind::Http)
+                    .log_error(format!("pdb download task failed: {}", err))
+            })?
+    }
+
+    /// Tries all configured symbol servers in order.
     fn download(&self, guid: &Win32Guid) -> Result<Vec<u8>> {
-        let pdb_url = format!("{}/{}/{}", self.base_url, guid.file_name, guid.guid);
+        if self.servers.is_empty() {
+            return Err(Error(ErrorOrigin::OsLayer, ErrorKind::Configuration)
+                .log_error("no symbol server configured"));
+        }
+
+        let agent = self.agent()?;
 
-        self.download_file(&format!("{}/{}", pdb_url, guid.file_name))
-            .or_else(|_| self.download_file(&format!("{}/{}", pdb_url, "file.ptr")))
+        let mut result = Err(Error(ErrorOrigin::OsLayer, ErrorKind::Http));
+        for server in self.servers.iter() {
+            result = self.download_from(&agent, server, guid);
+            if result.is_ok() {
+                break;
+            }
+            info!("{} not found on {}", guid.file_name, server.url);
+        }
+        result
     }
 
-    fn download_file(&self, url: &str) -> Result<Vec<u8>> {
-        info!("downloading pdb from {}", url);
-        let resp = ureq::get(url).call().map_err(|_| {
-            Error(ErrorOrigin::OsLayer, ErrorKind::Http).log_error("unable to download pdb")
-        })?;
+    /// Creates the http agent, proxies are taken from the environment unless set explicitly.
+    fn agent(&self) -> Result<Agent> {
+        let mut builder = AgentBuilder::new().try_proxy_from_env(true);
 
-        assert!(resp.has("Content-Length"));
-        let len = resp
-            .header("Content-Length")
-            .and_then(|s| s.parse::<usize>().ok())
-            .unwrap();
+        if let Some(proxy) = &self.proxy {
+            let proxy = Proxy::new(proxy).map_err(|err| {
+                Error(ErrorOrigin::OsLayer, ErrorKind::Configuration)
+                    .log_error(format!("invalid proxy url: {}", err))
+            })?;
+            builder = builder.proxy(proxy);
+        }
+
+        if let Some(ca_file) = &self.ca_file {
+            builder = builder.tls_config(Arc::new(tls_config_with_ca(ca_file)?));
+        }
+
+        Ok(builder.build())
+    }
+
+    fn download_from(
+        &self,
+        agent: &Agent,
+        server: &SymbolServer,
+        guid: &Win32Guid,
+    ) -> Result<Vec<u8>> {
+        let timeout = server.timeout.or(self.timeout);
+
+        // compressed files replace the last character of the extension with an underscore
+        let compressed_name = format!(
+            "{}_",
+            &guid.file_name[..guid.file_name.len().saturating_sub(1)]
+        );
+
+        let fetch = |path: String| match server.local_path() {
+            Some(root) => read_local_file(&root.join(path)),
+            None => self.download_file(agent, &format!("{}/{}", server.url, path), timeout),
+        };
+
+        let dir = self.layout(agent, server).path(guid);
+        let buffer = fetch(format!("{}/{}", dir, guid.file_name))
+            .or_else(|_| fetch(format!("{}/{}", dir, compressed_name)))
+            .or_else(|_| fetch(format!("{}/{}", dir, "file.ptr")))?;
+        if buffer.starts_with(CAB_SIGNATURE) {
+            decompress_cab(buffer)
+        } else {
+            Ok(buffer)
+        }
+    }
 
-        let mut reader = resp.into_reader();
-        let buffer = read_to_end(&mut reader, len)?;
+    /// Returns the layout of the store, the `index2.txt` is only requested once per server.
+    ///
+    /// The flat layout is assumed if the server cannot be reached, the layout is detected again
+    /// on the next download in that case.
+    fn layout(&self, agent: &Agent, server: &SymbolServer) -> SymbolStoreLayout {
+        let mut layout = server.layout.lock().unwrap();
+        if let Some(layout) = *layout {
+            return layout;
+        }
 
-        assert_eq!(buffer.len(), len);
-        Ok(buffer)
+        let detected = match server.local_path() {
+            Some(root) => Some(root.join("index2.txt").is_file()),
+            None => {
+                let mut request = agent.head(&format!("{}/index2.txt", server.url));
+                if let Some(timeout) = server.timeout.or(self.timeout) {
+                    request = request.timeout(timeout);
+                }
+                match request.call() {
+                    Ok(_) => Some(true),
+                    Err(ureq::Error::Status(_, _)) => Some(false),
+                    Err(ureq::Error::Transport(_)) => None,
+                }
+            }
+        };
+
+        let detected_layout = match detected {
+            Some(true) => SymbolStoreLayout::TwoTier,
+            _ => SymbolStoreLayout::Flat,
+        };
+        if detected.is_some() {
+            info!("{} uses the {:?} layout", server.url, detected_layout);
+            *layout = Some(detected_layout);
+        }
+        detected_layout
+    }
+
+    fn download_file(
+        &self,
+        agent: &Agent,
+        url: &str,
+        timeout: Option<Duration>,
+    ) -> Result<Vec<u8>> {
+        info!("downloading {}", url);
+
+        let mut attempt = 0;
+        loop {
+            let mut request = agent.get(url);
+            if let Some(timeout) = timeout {
+                request = request.timeout(timeout);
+            }
+
+            // connection errors, rate limits, server errors and truncated bodies are retried
+            let retry_after = match request.call() {
+                Ok(resp) => {
+                    // chunked responses do not announce their length and are read until the end
+                    let len = resp
+                        .header("Content-Length")
+                        .and_then(|len| len.trim().parse::<usize>().ok());
+                    match self.read_body(&mut resp.into_reader(), len, url)? {
+                        Some(buffer) => return Ok(buffer),
+                        None if attempt < self.retries => None,
+                        None => {
+                            return Err(Error(ErrorOrigin::OsLayer, ErrorKind::Http)
+                                .log_error(format!("download of {} is incomplete", url)))
+                        }
+                    }
+                }
+                Err(ureq::Error::Transport(_)) if attempt < self.retries => None,
+                Err(ureq::Error::Status(429 | 500..=599, resp)) if attempt < self.retries => {
+                    resp.header("Retry-After").map(str::to_string)
+                }
+                Err(_) => {
+                    return Err(Error(ErrorOrigin::OsLayer, ErrorKind::Http)
+                        .log_error(format!("unable to download {}", url)))
+                }
+            };
+
+            attempt += 1;
+            let delay = retry_delay(attempt, retry_after.as_deref(), jitter_seed());
+            warn!(
+                "retrying download of {} in {:?} ({}/{})",
+                url, delay, attempt, self.retries
+            );
+            self.sleep(delay)?;
+        }
+    }
+
+    /// Reads the body of a response.
+    ///
+    /// `None` is returned if the connection broke off or the body does not match the announced length.
+    fn read_body<R: Read>(
+        &self,
+        reader: &mut R,
+        len: Option<usize>,
+        url: &str,
+    ) -> Result<Option<Vec<u8>>> {
+        match read_to_end(
+            reader,
+            len.unwrap_or_default(),
+            self.progress.as_ref(),
+            self.cancellation.as_ref(),
+        ) {
+            Ok(buffer) => match len {
+                Some(len) if buffer.len() != len => {
+                    warn!("received {} of {} bytes from {}", buffer.len(), len, url);
+                    Ok(None)
+                }
+                _ => Ok(Some(buffer)),
+            },
+            Err(Error(_, ErrorKind::Http)) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Waits for the given duration, the cancellation token is checked in between.
+    fn sleep(&self, delay: Duration) -> Result<()> {
+        let step = Duration::from_millis(100);
+        let mut remaining = delay;
+        while !remaining.is_zero() {
+            if let Some(cancellation) = &self.cancellation {
+                cancellation.check()?;
+            }
+            let current = remaining.min(step);
+            std::thread::sleep(current);
+            remaining -= current;
+        }
+        Ok(())
     }
 
     // symbol store configurations
+    /// Replaces all configured symbol servers with the given one.
+    ///
+    /// `file://` urls, unc paths and local directories are read directly from the file system.
     pub fn base_url(mut self, base_url: &str) -> Self {
-        self.base_url = base_url.to_string();
+        self.servers = vec![SymbolServer::new(base_url, None)];
+        self
+    }
+
+    /// Adds a symbol server that is tried if the pdb cannot be found on the previous ones.
+    pub fn fallback_url(mut self, url: &str) -> Self {
+        self.servers.push(SymbolServer::new(url, None));
+        self
+    }
+
+    /// Adds a fallback symbol server with its own request timeout.
+    pub fn fallback_url_with_timeout(mut self, url: &str, timeout: Duration) -> Self {
+        self.servers.push(SymbolServer::new(url, Some(timeout)));
+        self
+    }
+
+    /// Sets the request timeout for all symbol servers without an explicit timeout.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Sets the number of retries after connection errors, rate limits or server errors.
+    ///
+    /// Retries are delayed with an exponential backoff, a `Retry-After` sent by the server is honoured.
+    pub fn retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// Uses the given proxy (e.g. `http://proxy:8080`) instead of the `HTTPS_PROXY`/`HTTP_PROXY` environment variables.
+    pub fn proxy(mut self, proxy: &str) -> Self {
+        self.proxy = Some(proxy.to_string());
+        self
+    }
+
+    /// Trusts the certificates of the given pem file in addition to the default root certificates.
+    pub fn ca_file<P: AsRef<Path>>(mut self, ca_file: P) -> Self {
+        self.ca_file = Some(ca_file.as_ref().to_path_buf());
         self
     }
 
@@ -180,4 +745,150 @@ impl SymbolStore {
         self.cache_path = Some(cache_path.as_ref().to_path_buf());
         self
     }
+
+    /// Limits the size of the local cache, the least recently used pdbs are evicted first.
+    pub fn max_cache_size(mut self, max_cache_size: u64) -> Self {
+        self.max_cache_size = Some(max_cache_size);
+        self
+    }
+
+    /// Reports the progress of downloads to the given callback.
+    ///
+    /// With the `download_progress` feature a progress bar is printed to the terminal by default.
+    pub fn progress<P: ProgressCallback + 'static>(mut self, progress: P) -> Self {
+        self.progress = Some(Progress::new(progress));
+        self
+    }
+
+    /// Disables progress reporting.
+    pub fn no_progress(mut self) -> Self {
+        self.progress = None;
+        self
+    }
+
+    /// Aborts downloads once the token has been cancelled.
+    pub fn cancellation(mut self, cancellation: CancellationToken) -> Self {
+        self.cancellation = Some(cancellation);
+        self
+    }
+
+    /// Removes all pdbs from the local cache.
+    pub fn clear_cache(&self) -> Result<()> {
+        let cache_path = match &self.cache_path {
+            Some(cache_path) => cache_path,
+            None => return Ok(()),
+        };
+
+        for file in cached_pdbs(cache_path) {
+            fs::remove_file(&file).map_err(|_| {
+                Error(ErrorOrigin::OsLayer, ErrorKind::UnableToWriteFile)
+                    .log_error("unable to remove pdb from local cache")
+            })?;
+            if let Some(dir) = file.parent() {
+                fs::remove_dir(dir).ok();
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retry_delays() {
+        // exponential backoff within the jitter range
+        for (attempt, max) in [(1, 500), (2, 1000), (3, 2000), (20, 60_000)] {
+            for seed in [0, 1, 12345, u64::MAX] {
+                let delay = retry_delay(attempt, None, seed).as_millis();
+                assert!(delay >= max / 2 && delay <= max, "{} {}", attempt, delay);
+            }
+        }
+        assert_ne!(retry_delay(3, None, 1), retry_delay(3, None, 2));
+
+        // retry-after in seconds is honoured up to the maximum delay
+        assert_eq!(retry_delay(1, Some("7"), 0), Duration::from_secs(7));
+        assert_eq!(retry_delay(1, Some(" 3600 "), 0), RETRY_MAX_DELAY);
+        // http dates are not supported and fall back to the backoff
+        let delay = retry_delay(1, Some("Wed, 21 Oct 2015 07:28:00 GMT"), 0);
+        assert_eq!(delay, Duration::from_millis(250));
+    }
+
+    #[test]
+    fn read_body_lengths() {
+        let store = SymbolStore::new().no_cache().no_progress();
+        let body = [0x42u8; 0x100];
+
+        let buffer = store
+            .read_body(&mut Cursor::new(&body[..]), Some(body.len()), "test")
+            .unwrap();
+        assert_eq!(buffer.as_deref(), Some(&body[..]));
+
+        // responses without a content length are read until the end
+        let buffer = store
+            .read_body(&mut Cursor::new(&body[..]), None, "test")
+            .unwrap();
+        assert_eq!(buffer.as_deref(), Some(&body[..]));
+
+        // truncated and oversized bodies can be retried
+        let buffer = store
+            .read_body(&mut Cursor::new(&body[..0x80]), Some(body.len()), "test")
+            .unwrap();
+        assert_eq!(buffer, None);
+        let buffer = store
+            .read_body(&mut Cursor::new(&body[..]), Some(0x80), "test")
+            .unwrap();
+        assert_eq!(buffer, None);
+    }
+
+    #[test]
+    fn read_body_connection_reset() {
+        struct ResetReader;
+        impl Read for ResetReader {
+            fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+                Err(io::ErrorKind::ConnectionReset.into())
+            }
+        }
+
+        let store = SymbolStore::new().no_cache().no_progress();
+        assert_eq!(
+            store
+                .read_body(&mut ResetReader, Some(0x100), "test")
+                .unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn layout_path() {
+        let guid = Win32Guid::new("ntkrnlmp.pdb", "3844DBB920174967BE7AA4A2C20430FA2");
+        assert_eq!(
+            SymbolStoreLayout::Flat.path(&guid),
+            "ntkrnlmp.pdb/3844DBB920174967BE7AA4A2C20430FA2"
+        );
+        assert_eq!(
+            SymbolStoreLayout::TwoTier.path(&guid),
+            "nt/ntkrnlmp.pdb/3844DBB920174967BE7AA4A2C20430FA2"
+        );
+    }
+
+    #[test]
+    fn local_layout() {
+        let root = std::env::temp_dir().join(format!("memflow-symstore-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+
+        let store = SymbolStore::new().no_cache();
+        let agent = AgentBuilder::new().build();
+        let server = SymbolServer::new(root.to_str().unwrap(), None);
+        assert_eq!(store.layout(&agent, &server), SymbolStoreLayout::Flat);
+
+        // the detected layout is kept for the server
+        fs::write(root.join("index2.txt"), b"").unwrap();
+        assert_eq!(store.layout(&agent, &server), SymbolStoreLayout::Flat);
+        let server = SymbolServer::new(root.to_str().unwrap(), None);
+        assert_eq!(store.layout(&agent, &server), SymbolStoreLayout::TwoTier);
+
+        fs::remove_dir_all(&root).ok();
+    }
 }
@@ -0,0 +1,121 @@
+/*!
+Progress reporting and cancellation of long running operations.
+
+Pdb downloads and scans over all of physical memory can take a long time.
+A [`ProgressCallback`] is notified about the progress of such operations and a
+[`CancellationToken`] allows aborting them from another thread. The token is checked
+between chunks, the operation then fails with an error.
+*/
+use std::prelude::v1::*;
+
+use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use memflow::error::{Error, ErrorKind, ErrorOrigin, Result};
+
+/// Receives progress updates of a long running operation
+pub trait ProgressCallback: Send + Sync {
+    /// Called when an operation starts, `total` is 0 if the amount of work is unknown.
+    fn start(&self, _task: &str, _total: u64) {}
+
+    /// Called with the amount of work that has been done so far.
+    fn update(&self, _current: u64) {}
+
+    /// Called when the operation has finished or failed.
+    fn finish(&self) {}
+}
+
+/// Shareable handle to a [`ProgressCallback`]
+#[derive(Clone)]
+pub struct Progress(Arc<dyn ProgressCallback>);
+
+impl Progress {
+    pub fn new<P: ProgressCallback + 'static>(callback: P) -> Self {
+        Self(Arc::new(callback))
+    }
+
+    pub fn start(&self, task: &str, total: u64) {
+        self.0.start(task, total)
+    }
+
+    pub fn update(&self, current: u64) {
+        self.0.update(current)
+    }
+
+    pub fn finish(&self) {
+        self.0.finish()
+    }
+}
+
+impl fmt::Debug for Progress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Progress")
+    }
+}
+
+/// Token to cancel a long running operation from another thread
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Requests the cancellation of all operations using this token.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst)
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    /// Returns an error if the operation has been cancelled.
+    pub fn check(&self) -> Result<()> {
+        if self.is_cancelled() {
+            Err(Error(ErrorOrigin::OsLayer, ErrorKind::Unknown).log_info("operation cancelled"))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Progress bar printed to the terminal
+#[cfg(feature = "download_progress")]
+pub struct TerminalProgress {
+    bar: indicatif::ProgressBar,
+}
+
+#[cfg(feature = "download_progress")]
+impl Default for TerminalProgress {
+    fn default() -> Self {
+        Self {
+            bar: indicatif::ProgressBar::hidden(),
+        }
+    }
+}
+
+#[cfg(feature = "download_progress")]
+impl ProgressCallback for TerminalProgress {
+    fn start(&self, task: &str, total: u64) {
+        self.bar
+            .set_draw_target(indicatif::ProgressDrawTarget::stderr());
+        self.bar.set_length(total);
+        self.bar.set_position(0);
+        self.bar.set_style(indicatif::ProgressStyle::default_bar()
+            .template("{spinner:.green} {msg} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} ({eta})")
+            .unwrap()
+            .progress_chars("#>-"));
+        self.bar.set_message(task.to_string());
+    }
+
+    fn update(&self, current: u64) {
+        self.bar.set_position(current);
+    }
+
+    fn finish(&self) {
+        self.bar.finish();
+    }
+}
@@ -4,10 +4,12 @@ with memflow-win32. This example uses the `Inventory` feature of memflow
 but hard-wires the connector instance into the memflow-win32 OS layer.
 
 The example then dumps all the found offsets into the specified `output` file.
+With `--c-header` the offsets are written as a C header instead.
 
 # Usage:
 ```bash
 cargo run --release --example dump_offsets -- -vv -c kvm --output file.toml
+cargo run --release --example dump_offsets -- -vv -c kvm --c-header --output offsets.h
 ```
 */
 use std::fs::File;
@@ -22,6 +24,7 @@ use memflow_win32::prelude::v1::*;
 pub fn main() -> Result<()> {
     let matches = parse_args();
     let (chain, output) = extract_args(&matches)?;
+    let c_header = matches.get_flag("c-header");
 
     // create inventory + connector
     let inventory = Inventory::scan();
@@ -32,35 +35,23 @@ pub fn main() -> Result<()> {
         .build()
         .unwrap();
 
-    let winver = os.kernel_info.kernel_winver;
-
-    if winver != (0, 0).into() {
-        let guid = os.kernel_info.kernel_guid.unwrap_or_default();
-        let offsets = Win32OffsetFile {
-            header: Win32OffsetHeader {
-                pdb_file_name: guid.file_name.as_str().into(),
-                pdb_guid: guid.guid.as_str().into(),
-
-                arch: os.kernel_info.os_info.arch.into(),
-
-                nt_major_version: winver.major_version(),
-                nt_minor_version: winver.minor_version(),
-                nt_build_number: winver.build_number(),
-            },
-            offsets: os.offsets.into(),
-        };
-
-        // write offsets to file
-        let offsetstr = toml::to_string_pretty(&offsets).unwrap();
-        match output {
-            Some(output) => {
-                let mut file = File::create(output).unwrap();
-                file.write_all(offsetstr.as_bytes()).unwrap();
+    match os.offset_file() {
+        Ok(offsets) => {
+            // write offsets to file
+            let offsetstr = if c_header {
+                offsets.to_c_header(Win32CHeaderStyle::Struct)
+            } else {
+                toml::to_string_pretty(&offsets).unwrap()
+            };
+            match output {
+                Some(output) => {
+                    let mut file = File::create(output).unwrap();
+                    file.write_all(offsetstr.as_bytes()).unwrap();
+                }
+                None => println!("{offsetstr}"),
             }
-            None => println!("{offsetstr}"),
         }
-    } else {
-        error!("kernel version has to be valid in order to generate a offsets file");
+        Err(err) => error!("unable to generate offsets file: {}", err),
     }
 
     Ok(())
@@ -79,6 +70,12 @@ fn parse_args() -> ArgMatches {
         )
         .arg(Arg::new("os").short('o').action(ArgAction::Append))
         .arg(Arg::new("output").long("output").action(ArgAction::Set))
+        .arg(
+            Arg::new("c-header")
+                .long("c-header")
+                .help("write the offsets as a c header")
+                .action(ArgAction::SetTrue),
+        )
         .get_matches()
 }
 
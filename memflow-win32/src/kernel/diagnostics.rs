@@ -0,0 +1,210 @@
+/*!
+Diagnostics of the kernel scans.
+
+Every stage of the kernel scan (e.g. the low stub scan or the page map scan) is recorded
+in a [`ScanReport`] together with the amount of scanned candidates, the reasons candidates
+have been rejected and the error the stage failed with. This helps troubleshooting
+connectors that do not expose the memory of the target correctly.
+
+# Examples:
+
+```
+use memflow::prelude::v1::*;
+use memflow_win32::win32::Win32KernelInfo;
+
+fn test<T: PhysicalMemory>(connector: T) {
+    let (result, report) = Win32KernelInfo::scanner(connector).scan_with_report();
+    if result.is_err() {
+        println!("{}", report);
+    }
+}
+```
+*/
+use std::prelude::v1::*;
+
+use core::fmt;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use memflow::error::Result;
+use memflow::types::Address;
+
+/// Stage of the kernel scan
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize))]
+pub enum ScanStage {
+    /// Start block search in the low 1mb stub
+    LowStub,
+    /// Start block search in the low 16mb
+    LowStubFallback,
+    /// Start block search in all of physical memory
+    Exhaustive,
+    /// Probing for ntoskrnl.exe below the kernel hint
+    VaHint,
+    /// Scan of the mapped kernel address space for ntoskrnl.exe
+    PageMap,
+    /// Scan of the upper 2gb of 32 bit kernels for ntoskrnl.exe
+    HighMem,
+    /// Search for ntoskrnl.exe with wiped pe headers
+    Headerless,
+    /// Search for the debugger data block in physical memory
+    KdDebuggerData,
+    /// Search for the system process
+    SystemProcess,
+}
+
+impl fmt::Display for ScanStage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ScanStage::LowStub => "lowstub scan",
+            ScanStage::LowStubFallback => "lowstub fallback scan",
+            ScanStage::Exhaustive => "exhaustive dtb scan",
+            ScanStage::VaHint => "va hint probe",
+            ScanStage::PageMap => "page map scan",
+            ScanStage::HighMem => "high memory scan",
+            ScanStage::Headerless => "headerless scan",
+            ScanStage::KdDebuggerData => "debugger data block scan",
+            ScanStage::SystemProcess => "system process search",
+        })
+    }
+}
+
+const REJECT_REASON_COUNT: usize = 5;
+
+/// Reason a scanned candidate has been rejected
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize))]
+pub enum RejectReason {
+    /// The candidate could not be read
+    Unreadable,
+    /// No valid mz header was found
+    InvalidDosHeader,
+    /// The pe header could not be parsed
+    InvalidPeHeader,
+    /// The image is not ntoskrnl.exe
+    NameMismatch,
+    /// The candidate did not pass the validation of the stage
+    ValidationFailed,
+}
+
+const REJECT_REASONS: [RejectReason; REJECT_REASON_COUNT] = [
+    RejectReason::Unreadable,
+    RejectReason::InvalidDosHeader,
+    RejectReason::InvalidPeHeader,
+    RejectReason::NameMismatch,
+    RejectReason::ValidationFailed,
+];
+
+impl fmt::Display for RejectReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            RejectReason::Unreadable => "unreadable",
+            RejectReason::InvalidDosHeader => "invalid mz header",
+            RejectReason::InvalidPeHeader => "invalid pe header",
+            RejectReason::NameMismatch => "not ntoskrnl.exe",
+            RejectReason::ValidationFailed => "validation failed",
+        })
+    }
+}
+
+/// Counters of a running stage
+///
+/// The counters can be shared between the threads of a parallel scan.
+#[derive(Debug, Default)]
+pub struct ScanCounters {
+    candidates: AtomicUsize,
+    rejected: [AtomicUsize; REJECT_REASON_COUNT],
+}
+
+impl ScanCounters {
+    /// Counts a scanned candidate.
+    pub fn candidate(&self) {
+        self.candidates.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a rejected candidate.
+    pub fn reject(&self, reason: RejectReason) {
+        self.rejected[reason as usize].fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Outcome of a single stage
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize))]
+pub struct ScanStageReport {
+    pub stage: ScanStage,
+    /// Dtb the stage was running with
+    pub dtb: Option<Address>,
+    /// Number of scanned candidates, e.g. pages or chunks
+    pub candidates: usize,
+    /// Number of rejected candidates per reason
+    pub rejected: Vec<(RejectReason, usize)>,
+    /// Error the stage failed with
+    pub error: Option<String>,
+}
+
+impl ScanStageReport {
+    pub fn succeeded(&self) -> bool {
+        self.error.is_none()
+    }
+}
+
+/// Report of all stages of a kernel scan in the order they ran
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize))]
+pub struct ScanReport {
+    pub stages: Vec<ScanStageReport>,
+}
+
+impl ScanReport {
+    /// Records a stage without counters.
+    pub fn record<R>(&mut self, stage: ScanStage, dtb: Option<Address>, result: &Result<R>) {
+        self.record_counters(stage, dtb, &ScanCounters::default(), result)
+    }
+
+    /// Records a stage together with its counters.
+    pub fn record_counters<R>(
+        &mut self,
+        stage: ScanStage,
+        dtb: Option<Address>,
+        counters: &ScanCounters,
+        result: &Result<R>,
+    ) {
+        let rejected = REJECT_REASONS
+            .iter()
+            .zip(counters.rejected.iter())
+            .map(|(reason, count)| (*reason, count.load(Ordering::Relaxed)))
+            .filter(|(_, count)| *count > 0)
+            .collect();
+
+        self.stages.push(ScanStageReport {
+            stage,
+            dtb,
+            candidates: counters.candidates.load(Ordering::Relaxed),
+            rejected,
+            error: result.as_ref().err().map(|err| err.to_string()),
+        });
+    }
+}
+
+impl fmt::Display for ScanReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for stage in self.stages.iter() {
+            write!(f, "{}", stage.stage)?;
+            if let Some(dtb) = stage.dtb {
+                write!(f, " (dtb={:x})", dtb)?;
+            }
+            match &stage.error {
+                Some(err) => write!(f, ": failed: {}", err)?,
+                None => write!(f, ": ok")?,
+            }
+            if stage.candidates > 0 {
+                write!(f, ", {} candidates", stage.candidates)?;
+            }
+            for (reason, count) in stage.rejected.iter() {
+                write!(f, ", {} {}", count, reason)?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,314 @@
+/*!
+Heuristic offset scanner for kernels without a matching pdb or offset file.
+
+The offsets of the most important fields are derived from the code of small exported
+accessor functions in ntoskrnl.exe (e.g. `PsGetProcessId` is just a single load from the
+eprocess) and validated against the known layout of the System process.
+All other offsets are set to 0 which disables the features depending on them.
+*/
+use std::prelude::v1::*;
+
+use super::ntos::{self, pehelper};
+
+use std::convert::TryInto;
+
+use log::{debug, info, warn};
+
+use memflow::architecture::{ArchitectureIdent, ArchitectureObj};
+use memflow::error::{Error, ErrorKind, ErrorOrigin, Result};
+use memflow::mem::MemoryView;
+use memflow::types::{umem, Address};
+
+use pelite::PeView;
+
+use crate::offsets::{Win32OffsetTable, Win32Offsets};
+
+/// Number of bytes of an accessor function that are searched for the field access
+const CODE_SCAN_LEN: usize = 0x20;
+/// Number of bytes of an eprocess that are searched for known values on 64 bit kernels
+const EPROCESS_SCAN_LEN_X64: usize = 0x1000;
+/// Number of bytes of an eprocess that are searched for known values on 32 bit kernels,
+/// the x86 eprocess is far smaller than a page
+const EPROCESS_SCAN_LEN_X86: usize = 0x600;
+
+/// Instruction patterns followed by a 32 bit displacement
+///
+/// The patterns cover `mov rax, [rcx+disp32]` / `lea rax, [rcx+disp32]` on x64
+/// and `mov eax, [eax+disp32]` / `add eax, imm32` on x86 (stdcall, argument loaded into eax).
+/// The argument is loaded through ebp by msvc and through esp by gcc (e.g. ReactOS).
+const X64_LOAD: &[&[u8]] = &[&[0x48, 0x8b, 0x81]];
+const X64_LEA: &[&[u8]] = &[&[0x48, 0x8d, 0x81]];
+const X86_LOAD: &[&[u8]] = &[&[0x8b, 0x45, 0x08, 0x8b, 0x80], &[0x8b, 0x80]];
+const X86_LEA: &[&[u8]] = &[
+    &[0x8b, 0x45, 0x08, 0x05],
+    &[0x8b, 0x44, 0x24, 0x04, 0x05],
+    &[0x8d, 0x80],
+];
+
+/// Derives the critical offsets from the kernel image and the system eprocess.
+///
+/// The `dtb` is used to locate the directory table base in the system process.
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(name = "heuristics_find_offsets", skip_all)
+)]
+pub fn find_offsets<T: MemoryView>(
+    virt_mem: &mut T,
+    arch: ArchitectureIdent,
+    kernel_base: Address,
+    eprocess_base: Address,
+    dtb: Address,
+) -> Result<Win32Offsets> {
+    let arch_obj = ArchitectureObj::from(arch);
+    let ptr_size = arch_obj.size_addr();
+
+    let image = pehelper::try_get_pe_image(virt_mem, kernel_base)?;
+    let pe = PeView::from_bytes(&image)
+        .map_err(|err| Error(ErrorOrigin::OsLayer, ErrorKind::InvalidExeFile).log_info(err))?;
+
+    let mut eprocess = vec![0u8; eprocess_scan_len(ptr_size)];
+    virt_mem.read_raw_into(eprocess_base, &mut eprocess)?;
+
+    let eproc_pid = find_field_in_export(&pe, &image, arch, "PsGetProcessId", false)
+        .filter(|&offset| is_system_pid(virt_mem, &eprocess, eprocess_base, offset, ptr_size))
+        .or_else(|| {
+            debug!("PsGetProcessId could not be decoded, scanning system eprocess");
+            find_system_pid(virt_mem, &eprocess, eprocess_base, ptr_size)
+        })
+        .ok_or_else(|| {
+            Error(ErrorOrigin::OsLayer, ErrorKind::Offset)
+                .log_warn("unable to find UniqueProcessId in system eprocess")
+        })?;
+    let eproc_link = eproc_pid + ptr_size;
+    info!("eproc_pid={:x} eproc_link={:x}", eproc_pid, eproc_link);
+
+    let eproc_name = find_field_in_export(&pe, &image, arch, "PsGetProcessImageFileName", true)
+        .filter(|&offset| is_system_name(&eprocess, offset))
+        .or_else(|| {
+            debug!("PsGetProcessImageFileName could not be decoded, scanning system eprocess");
+            find_system_name(&eprocess, 0)
+        })
+        .ok_or_else(|| {
+            Error(ErrorOrigin::OsLayer, ErrorKind::Offset)
+                .log_warn("unable to find ImageFileName in system eprocess")
+        })?;
+    info!("eproc_name={:x}", eproc_name);
+
+    let kproc_dtb = find_dtb(&eprocess, dtb, arch).ok_or_else(|| {
+        Error(ErrorOrigin::OsLayer, ErrorKind::Offset)
+            .log_warn("unable to find DirectoryTableBase in system eprocess")
+    })?;
+    info!("kproc_dtb={:x}", kproc_dtb);
+
+    // the system process does not have a peb so the offset can only be derived from code
+    let eproc_peb = find_field_in_export(&pe, &image, arch, "PsGetProcessPeb", false)
+        .filter(|&offset| read_ptr(&eprocess, offset, ptr_size) == Some(0))
+        .ok_or_else(|| {
+            Error(ErrorOrigin::OsLayer, ErrorKind::Offset)
+                .log_warn("unable to derive the Peb offset from PsGetProcessPeb")
+        })?;
+    info!("eproc_peb={:x}", eproc_peb);
+
+    warn!("using heuristic offsets, only basic process enumeration is supported");
+
+    Ok(Win32Offsets(Win32OffsetTable {
+        list_blink: ptr_size as u32,
+        eproc_link: eproc_link as u32,
+        kproc_dtb: kproc_dtb as u32,
+        eproc_pid: eproc_pid as u32,
+        eproc_name: eproc_name as u32,
+        eproc_peb: eproc_peb as u32,
+        ..Default::default()
+    }))
+}
+
+/// Returns the number of bytes of an eprocess that are scanned for known values.
+pub(crate) fn eprocess_scan_len(ptr_size: usize) -> usize {
+    match ptr_size {
+        8 => EPROCESS_SCAN_LEN_X64,
+        _ => EPROCESS_SCAN_LEN_X86,
+    }
+}
+
+/// Reads a little endian pointer of the given size from the buffer.
+pub(crate) fn read_ptr(buf: &[u8], offset: usize, ptr_size: usize) -> Option<umem> {
+    let bytes = buf.get(offset..offset + ptr_size)?;
+    Some(match ptr_size {
+        8 => u64::from_le_bytes(bytes.try_into().unwrap()) as umem,
+        _ => u32::from_le_bytes(bytes.try_into().unwrap()) as umem,
+    })
+}
+
+/// Checks if `offset` is the `UniqueProcessId` of the system eprocess.
+///
+/// The system process always has the pid 4 and ActiveProcessLinks directly follows UniqueProcessId.
+fn is_system_pid<T: MemoryView>(
+    virt_mem: &mut T,
+    eprocess: &[u8],
+    eprocess_base: Address,
+    offset: usize,
+    ptr_size: usize,
+) -> bool {
+    read_ptr(eprocess, offset, ptr_size) == Some(4)
+        && is_valid_list_entry(virt_mem, ptr_size, eprocess_base + offset + ptr_size)
+}
+
+/// Scans the system eprocess for the offset of `UniqueProcessId`.
+pub(crate) fn find_system_pid<T: MemoryView>(
+    virt_mem: &mut T,
+    eprocess: &[u8],
+    eprocess_base: Address,
+    ptr_size: usize,
+) -> Option<usize> {
+    (0..eprocess.len().saturating_sub(2 * ptr_size))
+        .step_by(ptr_size)
+        .find(|&offset| is_system_pid(virt_mem, eprocess, eprocess_base, offset, ptr_size))
+}
+
+fn is_system_name(eprocess: &[u8], offset: usize) -> bool {
+    eprocess.get(offset..offset + 7) == Some(&b"System\0"[..])
+}
+
+/// Scans the system eprocess for the offset of `ImageFileName` starting at `start`.
+pub(crate) fn find_system_name(eprocess: &[u8], start: usize) -> Option<usize> {
+    (start..eprocess.len().saturating_sub(7)).find(|&offset| is_system_name(eprocess, offset))
+}
+
+/// Returns the mask of the page table address in a directory table base.
+pub(crate) fn dtb_mask(arch: ArchitectureIdent) -> umem {
+    match arch {
+        // pae tables are only 32 byte aligned
+        ArchitectureIdent::X86(32, true) => !0x1f,
+        _ => !0xfff,
+    }
+}
+
+/// Scans the start of the system eprocess for the offset of `DirectoryTableBase`.
+///
+/// DirectoryTableBase is located at the start of the embedded kprocess,
+/// the lower bits might contain the pcid or flags of the pae table.
+pub(crate) fn find_dtb(eprocess: &[u8], dtb: Address, arch: ArchitectureIdent) -> Option<usize> {
+    let ptr_size = ArchitectureObj::from(arch).size_addr();
+    let dtb_mask = dtb_mask(arch);
+    (ptr_size..0x100).step_by(ptr_size).find(|&offset| {
+        read_ptr(eprocess, offset, ptr_size).map_or(false, |v| {
+            v != 0 && v & dtb_mask == dtb.to_umem() & dtb_mask
+        })
+    })
+}
+
+/// Decodes the field offset accessed by a small exported accessor function.
+///
+/// If `lea` is set the function returns the address of the field instead of loading it.
+fn find_field_in_export(
+    pe: &PeView,
+    image: &[u8],
+    arch: ArchitectureIdent,
+    name: &str,
+    lea: bool,
+) -> Option<usize> {
+    let rva = ntos::get_export(pe, name).ok()? as usize;
+    let code = image.get(rva..(rva + CODE_SCAN_LEN).min(image.len()))?;
+
+    let offset = match arch {
+        ArchitectureIdent::X86(64, _) => {
+            find_displacement(code, if lea { X64_LEA } else { X64_LOAD })
+        }
+        ArchitectureIdent::X86(32, _) => {
+            find_displacement(code, if lea { X86_LEA } else { X86_LOAD })
+        }
+        ArchitectureIdent::AArch64(_) => find_aarch64_offset(code, lea),
+        _ => None,
+    }?;
+    debug!("{} accesses field at {:x}", name, offset);
+    Some(offset)
+}
+
+/// Returns the displacement following the first matching instruction pattern.
+fn find_displacement(code: &[u8], patterns: &[&[u8]]) -> Option<usize> {
+    patterns.iter().find_map(|pattern| {
+        let pos = code.windows(pattern.len()).position(|w| w == *pattern)? + pattern.len();
+        let disp = code.get(pos..pos + 4)?;
+        Some(u32::from_le_bytes(disp.try_into().unwrap()) as usize)
+    })
+}
+
+/// Decodes `ldr x0, [x0, #imm]` or `add x0, x0, #imm` in the given code.
+fn find_aarch64_offset(code: &[u8], lea: bool) -> Option<usize> {
+    let (opcode, scale) = if lea {
+        (0x9100_0000, 1)
+    } else {
+        (0xf940_0000, 8)
+    };
+    code.chunks_exact(4)
+        .map(|c| u32::from_le_bytes(c.try_into().unwrap()))
+        .find(|insn| insn & 0xffc0_03ff == opcode)
+        .map(|insn| ((insn >> 10) & 0xfff) as usize * scale)
+}
+
+/// Checks if the list entry at the given address is linked correctly to its successor.
+pub(crate) fn is_valid_list_entry<T: MemoryView>(
+    virt_mem: &mut T,
+    ptr_size: usize,
+    entry: Address,
+) -> bool {
+    let read = |virt_mem: &mut T, addr: Address| -> Option<Address> {
+        let mut buf = [0u8; 8];
+        virt_mem.read_raw_into(addr, &mut buf[..ptr_size]).ok()?;
+        Some(Address::from(u64::from_le_bytes(buf)))
+    };
+
+    match read(virt_mem, entry) {
+        Some(flink) if !flink.is_null() && flink != entry => {
+            read(virt_mem, flink + ptr_size) == Some(entry)
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scan_len_is_bounded_on_x86() {
+        assert_eq!(eprocess_scan_len(8), 0x1000);
+        assert!(eprocess_scan_len(4) < 0x1000);
+    }
+
+    #[test]
+    fn read_ptr_sizes() {
+        let buf = [1, 0, 0, 0, 2, 0, 0, 0];
+        assert_eq!(read_ptr(&buf, 0, 4), Some(1));
+        assert_eq!(read_ptr(&buf, 0, 8), Some(0x2_0000_0001));
+        assert_eq!(read_ptr(&buf, 4, 8), None);
+    }
+
+    #[test]
+    fn system_name() {
+        let mut buf = vec![0u8; 0x100];
+        buf[0x40..0x47].copy_from_slice(b"System\0");
+        assert_eq!(find_system_name(&buf, 0), Some(0x40));
+        assert_eq!(find_system_name(&buf, 0x41), None);
+        assert_eq!(find_system_name(&buf[..0x44], 0), None);
+    }
+
+    #[test]
+    fn dtb_with_flags() {
+        let mut buf = vec![0u8; 0x100];
+        buf[0x28..0x30].copy_from_slice(&0x1ad002u64.to_le_bytes());
+        let arch = ArchitectureIdent::X86(64, false);
+        assert_eq!(find_dtb(&buf, Address::from(0x1ad000u64), arch), Some(0x28));
+        assert_eq!(find_dtb(&buf, Address::from(0x1ae000u64), arch), None);
+    }
+
+    #[test]
+    fn dtb_pae() {
+        let mut buf = vec![0u8; 0x100];
+        buf[0x18..0x1c].copy_from_slice(&0x185020u32.to_le_bytes());
+        let arch = ArchitectureIdent::X86(32, true);
+        assert_eq!(dtb_mask(arch), !0x1f);
+        assert_eq!(find_dtb(&buf, Address::from(0x185020u64), arch), Some(0x18));
+        assert_eq!(find_dtb(&buf, Address::from(0x185000u64), arch), None);
+    }
+}
@@ -0,0 +1,355 @@
+/*!
+Discovery and decoding of the kernel debugger data block (`KDDEBUGGER_DATA64`).
+
+The block is located in the data section of ntoskrnl.exe and contains the addresses of
+important kernel globals like `PsActiveProcessHead` or `PsLoadedModuleList`.
+Since Windows 8 the block is encoded on x64 unless a kernel debugger is attached.
+The keys required for decoding are only available through the symbols of the kernel.
+*/
+use std::prelude::v1::*;
+
+use std::convert::TryInto;
+
+use crate::progress::{CancellationToken, Progress};
+
+use log::{debug, info};
+
+use memflow::error::{Error, ErrorKind, ErrorOrigin, Result};
+use memflow::mem::{MemoryView, PhysicalMemory};
+use memflow::types::{size, umem, Address, PhysicalAddress};
+
+/// Owner tag of the debugger data block (`KDBG`)
+pub const KDBG_TAG: u32 = 0x4742_444b;
+
+// offsets in KDDEBUGGER_DATA64, pointers are always stored as 64 bit values
+const OFFSET_TAG: usize = 0x10;
+const OFFSET_SIZE: usize = 0x14;
+const OFFSET_KERN_BASE: usize = 0x18;
+const OFFSET_PS_LOADED_MODULE_LIST: usize = 0x48;
+const OFFSET_PS_ACTIVE_PROCESS_HEAD: usize = 0x50;
+const OFFSET_PSP_CID_TABLE: usize = 0x58;
+const OFFSET_MM_PFN_DATABASE: usize = 0xc0;
+const OFFSET_MM_HIGHEST_USER_ADDRESS: usize = 0x1c8;
+const OFFSET_MM_SYSTEM_RANGE_START: usize = 0x1d0;
+const OFFSET_KI_PROCESSOR_BLOCK: usize = 0x218;
+const OFFSET_MM_PHYSICAL_MEMORY_BLOCK: usize = 0x270;
+
+// the size of the block grows with every windows release
+const MIN_SIZE: usize = 0x290;
+const MAX_SIZE: usize = 0x1000;
+
+/// Decoded contents of the kernel debugger data block
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize))]
+pub struct KdDebuggerData {
+    /// Size of the block in bytes
+    pub size: u32,
+    pub kern_base: Address,
+    pub ps_loaded_module_list: Address,
+    pub ps_active_process_head: Address,
+    pub psp_cid_table: Address,
+    pub mm_pfn_database: Address,
+    pub mm_highest_user_address: Address,
+    pub mm_system_range_start: Address,
+    pub ki_processor_block: Address,
+    pub mm_physical_memory_block: Address,
+}
+
+/// Keys used to encode the debugger data block on x64
+///
+/// The values are read from `KiWaitNever` and `KiWaitAlways`, `block_encoded` is
+/// the address of `KdpDataBlockEncoded`.
+#[derive(Debug, Clone, Copy)]
+pub struct KdDebuggerDataKeys {
+    pub wait_never: u64,
+    pub wait_always: u64,
+    pub block_encoded: Address,
+}
+
+impl KdDebuggerData {
+    /// Parses a decoded debugger data block.
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        if data.len() < MIN_SIZE || read_u32(data, OFFSET_TAG) != KDBG_TAG {
+            return Err(Error(ErrorOrigin::OsLayer, ErrorKind::InvalidExeFile)
+                .log_trace("invalid kernel debugger data block"));
+        }
+
+        // pointers of 32 bit kernels are sign extended
+        let is_32bit = read_u64(data, OFFSET_KERN_BASE) >> 32 == 0xffff_ffff;
+        let ptr = |offset: usize| {
+            let value = read_u64(data, offset);
+            Address::from(if is_32bit { value as u32 as u64 } else { value })
+        };
+        Ok(Self {
+            size: read_u32(data, OFFSET_SIZE),
+            kern_base: ptr(OFFSET_KERN_BASE),
+            ps_loaded_module_list: ptr(OFFSET_PS_LOADED_MODULE_LIST),
+            ps_active_process_head: ptr(OFFSET_PS_ACTIVE_PROCESS_HEAD),
+            psp_cid_table: ptr(OFFSET_PSP_CID_TABLE),
+            mm_pfn_database: ptr(OFFSET_MM_PFN_DATABASE),
+            mm_highest_user_address: ptr(OFFSET_MM_HIGHEST_USER_ADDRESS),
+            mm_system_range_start: ptr(OFFSET_MM_SYSTEM_RANGE_START),
+            ki_processor_block: ptr(OFFSET_KI_PROCESSOR_BLOCK),
+            mm_physical_memory_block: ptr(OFFSET_MM_PHYSICAL_MEMORY_BLOCK),
+        })
+    }
+}
+
+/// Decodes an encoded debugger data block in place.
+pub fn decode(data: &mut [u8], keys: &KdDebuggerDataKeys) {
+    for chunk in data.chunks_exact_mut(8) {
+        let mut value = u64::from_le_bytes(chunk.try_into().unwrap());
+        value ^= keys.wait_never;
+        value = value.rotate_left((keys.wait_never & 0xff) as u32);
+        value ^= keys.block_encoded.to_umem() as u64;
+        value = value.swap_bytes();
+        value ^= keys.wait_always;
+        chunk.copy_from_slice(&value.to_le_bytes());
+    }
+}
+
+/// Scans the kernel image for an unencoded debugger data block.
+#[cfg_attr(feature = "tracing", tracing::instrument(name = "kdbg_find", skip_all))]
+pub fn find<T: MemoryView>(
+    virt_mem: &mut T,
+    kernel_base: Address,
+    kernel_size: umem,
+) -> Result<KdDebuggerData> {
+    debug!("trying to find the kernel debugger data block");
+
+    let mut image = vec![0; (kernel_size as usize).min(size::mb(32))];
+    virt_mem.read_raw_into(kernel_base, &mut image)?;
+
+    let offset = (0..image.len().saturating_sub(MIN_SIZE))
+        .step_by(8)
+        .find(|&offset| is_block_header(&image[offset..], Some(kernel_base)))
+        .ok_or_else(|| {
+            Error(ErrorOrigin::OsLayer, ErrorKind::NotFound)
+                .log_info("unable to find an unencoded kernel debugger data block")
+        })?;
+    info!("KdDebuggerDataBlock found at {:x}", kernel_base + offset);
+
+    let size = read_u32(&image, offset + OFFSET_SIZE) as usize;
+    KdDebuggerData::parse(&image[offset..(offset + size).min(image.len())])
+}
+
+/// Reads the debugger data block at the given address and decodes it if necessary.
+///
+/// The address is usually resolved through the `KdDebuggerDataBlock` symbol of the kernel.
+pub fn read<T: MemoryView>(
+    virt_mem: &mut T,
+    address: Address,
+    keys: Option<&KdDebuggerDataKeys>,
+) -> Result<KdDebuggerData> {
+    let mut data = vec![0; MAX_SIZE];
+    virt_mem.read_raw_into(address, &mut data)?;
+
+    if read_u32(&data, OFFSET_TAG) != KDBG_TAG {
+        let keys = keys.ok_or_else(|| {
+            Error(ErrorOrigin::OsLayer, ErrorKind::InvalidExeFile)
+                .log_info("kernel debugger data block is encoded")
+        })?;
+        debug!("decoding the kernel debugger data block");
+        decode(&mut data, keys);
+    }
+
+    let size = (read_u32(&data, OFFSET_SIZE) as usize).min(MAX_SIZE);
+    KdDebuggerData::parse(&data[..size])
+}
+
+/// Scans all of physical memory for an unencoded debugger data block.
+///
+/// This is used to locate the kernel if the kernel image cannot be found in virtual memory.
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(name = "kdbg_find_phys", skip_all)
+)]
+pub fn find_phys<T: PhysicalMemory>(
+    mem: &mut T,
+    progress: Option<&Progress>,
+    cancellation: Option<&CancellationToken>,
+) -> Result<KdDebuggerData> {
+    let max_address = mem.metadata().max_address.to_umem();
+    if let Some(progress) = progress {
+        progress.start(
+            "scanning physical memory for the kernel debugger data block",
+            max_address as u64,
+        );
+    }
+
+    let mut result = Err(Error(ErrorOrigin::OsLayer, ErrorKind::NotFound)
+        .log_trace("unable to find the kernel debugger data block in physical memory"));
+    let mut chunk = vec![0; size::mb(2) + MAX_SIZE];
+    let mut base: umem = 0;
+    while base < max_address {
+        if let Some(cancellation) = cancellation {
+            if let Err(err) = cancellation.check() {
+                result = Err(err);
+                break;
+            }
+        }
+        if let Some(progress) = progress {
+            progress.update(base as u64);
+        }
+
+        // the chunks overlap so blocks crossing a chunk boundary are found as well
+        if mem
+            .phys_read_into(PhysicalAddress::from(base), chunk.as_mut_slice())
+            .is_ok()
+        {
+            if let Some(data) = (0..size::mb(2))
+                .step_by(8)
+                .filter(|&offset| is_block_header(&chunk[offset..], None))
+                .find_map(|offset| KdDebuggerData::parse(&chunk[offset..]).ok())
+            {
+                info!("KdDebuggerDataBlock found at physical address {:x}", base);
+                result = Ok(data);
+                break;
+            }
+        }
+
+        base += size::mb(2) as umem;
+    }
+
+    if let Some(progress) = progress {
+        progress.finish();
+    }
+    result
+}
+
+/// Checks the header of a debugger data block.
+///
+/// Without a known kernel base the kernel base in the block has to be a page aligned kernel address,
+/// kernel addresses of 32 bit kernels are sign extended as well.
+fn is_block_header(data: &[u8], kernel_base: Option<Address>) -> bool {
+    if data.len() < MIN_SIZE || read_u32(data, OFFSET_TAG) != KDBG_TAG {
+        return false;
+    }
+
+    let size = read_u32(data, OFFSET_SIZE) as usize;
+    if !(MIN_SIZE..=MAX_SIZE).contains(&size) {
+        return false;
+    }
+
+    let kern_base = read_u64(data, OFFSET_KERN_BASE);
+    match kernel_base {
+        Some(kernel_base) => {
+            let kernel_base = kernel_base.to_umem() as u64;
+            kern_base == kernel_base || kern_base == kernel_base as u32 as i32 as i64 as u64
+        }
+        None => kern_base & 0xfff == 0 && kern_base >> 63 == 1,
+    }
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use memflow::dummy::DummyMemory;
+
+    fn keys() -> KdDebuggerDataKeys {
+        KdDebuggerDataKeys {
+            wait_never: 0x1234_5678_9abc_de08,
+            wait_always: 0x0fed_cba9_8765_4321,
+            block_encoded: Address::from(0xfffff802_1d2fb6b0u64),
+        }
+    }
+
+    fn block(kern_base: u64) -> Vec<u8> {
+        let mut data = vec![0u8; MIN_SIZE];
+        data[OFFSET_TAG..OFFSET_TAG + 4].copy_from_slice(&KDBG_TAG.to_le_bytes());
+        data[OFFSET_SIZE..OFFSET_SIZE + 4].copy_from_slice(&(MIN_SIZE as u32).to_le_bytes());
+        data[OFFSET_KERN_BASE..OFFSET_KERN_BASE + 8].copy_from_slice(&kern_base.to_le_bytes());
+        data[OFFSET_PS_ACTIVE_PROCESS_HEAD..OFFSET_PS_ACTIVE_PROCESS_HEAD + 8]
+            .copy_from_slice(&(kern_base + 0xc1e6a0).to_le_bytes());
+        data
+    }
+
+    // inverse of `decode`
+    fn encode(data: &mut [u8], keys: &KdDebuggerDataKeys) {
+        for chunk in data.chunks_exact_mut(8) {
+            let mut value = u64::from_le_bytes(chunk.try_into().unwrap());
+            value ^= keys.wait_always;
+            value = value.swap_bytes();
+            value ^= keys.block_encoded.to_umem() as u64;
+            value = value.rotate_right((keys.wait_never & 0xff) as u32);
+            value ^= keys.wait_never;
+            chunk.copy_from_slice(&value.to_le_bytes());
+        }
+    }
+
+    #[test]
+    fn decode_steps() {
+        let keys = KdDebuggerDataKeys {
+            wait_never: 0,
+            wait_always: 0,
+            block_encoded: Address::from(0x0102_0304_0506_0708u64),
+        };
+        let mut data = [0u8; 8];
+        decode(&mut data, &keys);
+        assert_eq!(u64::from_le_bytes(data), 0x0807_0605_0403_0201);
+
+        let keys = KdDebuggerDataKeys {
+            wait_never: 0x08,
+            wait_always: 0x01,
+            block_encoded: Address::null(),
+        };
+        let mut data = 0x18u64.to_le_bytes();
+        decode(&mut data, &keys);
+        assert_eq!(u64::from_le_bytes(data), 0x0010_0000_0000_0001);
+    }
+
+    #[test]
+    fn decode_roundtrip() {
+        let plain = block(0xfffff802_1d000000);
+        let mut data = plain.clone();
+        encode(&mut data, &keys());
+        assert_ne!(read_u32(&data, OFFSET_TAG), KDBG_TAG);
+        decode(&mut data, &keys());
+        assert_eq!(data, plain);
+    }
+
+    #[test]
+    fn parse_sign_extended() {
+        let data = KdDebuggerData::parse(&block(0xffff_ffff_8280_0000)).unwrap();
+        assert_eq!(data.kern_base, Address::from(0x8280_0000u64));
+        assert_eq!(data.ps_active_process_head, Address::from(0x8341_e6a0u64));
+
+        let data = KdDebuggerData::parse(&block(0xfffff802_1d000000)).unwrap();
+        assert_eq!(data.kern_base, Address::from(0xfffff802_1d000000u64));
+        assert_eq!(data.size, MIN_SIZE as u32);
+
+        assert!(KdDebuggerData::parse(&block(0xfffff802_1d000000)[..MIN_SIZE - 8]).is_err());
+    }
+
+    #[test]
+    fn block_header() {
+        let data = block(0xffff_ffff_8280_0000);
+        assert!(is_block_header(&data, None));
+        assert!(is_block_header(&data, Some(Address::from(0x8280_0000u64))));
+        assert!(!is_block_header(&data, Some(Address::from(0x8290_0000u64))));
+        assert!(!is_block_header(&block(0xffff_ffff_8280_0100), None));
+        assert!(!is_block_header(&block(0x8280_0000), None));
+    }
+
+    #[test]
+    fn read_encoded() {
+        let mut mem = DummyMemory::new(size::mb(1));
+        let mut data = block(0xfffff802_1d000000);
+        encode(&mut data, &keys());
+        let mut view = mem.phys_view();
+        view.write_raw(Address::from(0x1000u64), &data).unwrap();
+
+        assert!(read(&mut view, Address::from(0x1000u64), None).is_err());
+        let kdbg = read(&mut view, Address::from(0x1000u64), Some(&keys())).unwrap();
+        assert_eq!(kdbg.kern_base, Address::from(0xfffff802_1d000000u64));
+    }
+}
@@ -1,6 +1,14 @@
+pub mod diagnostics;
+pub mod heuristics;
+pub mod kdbg;
 pub mod ntos;
+#[cfg(feature = "parallel")]
+pub mod parallel;
 pub mod start_block;
 pub mod sysproc;
+pub mod vbs;
 
 pub use memflow_win32_defs::kernel::*;
-pub use start_block::StartBlock;
+pub use ntos::NtosScanConfig;
+pub use start_block::{AArch64Layout, StartBlock};
+pub use vbs::Win32VbsInfo;
@@ -1,54 +1,244 @@
+pub(crate) mod headerless;
+pub(crate) mod lazy_pe;
 pub(crate) mod pehelper;
 
+mod aarch64;
 mod x64;
 mod x86;
 
+use super::diagnostics::{ScanCounters, ScanReport, ScanStage};
 use super::{StartBlock, Win32Guid, Win32Version};
+use crate::offsets::Win32ArchOffsets;
+use crate::progress::{CancellationToken, Progress};
+use crate::win32::{VirtualReadUnicodeString, Win32ListWalker, Win32VersionInfo};
+use lazy_pe::LazyPe;
 
 use std::convert::TryInto;
 use std::prelude::v1::*;
 
 use log::{info, warn};
 
-use memflow::architecture::ArchitectureObj;
+use memflow::architecture::{ArchitectureIdent, ArchitectureObj};
 use memflow::error::{Error, ErrorKind, ErrorOrigin, PartialResultExt, Result};
 use memflow::mem::{MemoryView, VirtualTranslate};
-use memflow::types::{umem, Address};
+use memflow::types::{mem, umem, Address};
 
 use pelite::{self, pe64::debug::CodeView, pe64::exports::Export, PeView};
 
+/// Upper bound for the number of loaded kernel modules
+const MAX_MODULES: usize = 0x1000;
+
+/// Granularity of the scans for ntoskrnl.exe in the kernel address space of x64 targets
+///
+/// Larger probes need fewer round trips on connectors with a high latency while
+/// smaller probes read less memory on connectors with a low bandwidth.
+#[derive(Debug, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize))]
+pub struct NtosScanConfig {
+    /// Size of the chunks that are read at once while probing for the kernel headers.
+    ///
+    /// This is rounded up to the page size.
+    pub probe_size: umem,
+    /// Range below the kernel hint that is probed before the page map is scanned
+    pub hint_window: umem,
+    /// Regions of the page map smaller than this are not probed
+    pub min_region_size: umem,
+    /// Scans the whole kernel address space for a kernel with wiped pe headers if all other scans fail.
+    ///
+    /// This is enabled by exhaustive scans.
+    pub headerless: bool,
+}
+
+impl Default for NtosScanConfig {
+    fn default() -> Self {
+        Self {
+            probe_size: mem::mb(2),
+            hint_window: mem::mb(16),
+            min_region_size: mem::kb(256),
+            headerless: false,
+        }
+    }
+}
+
 pub fn find<T: MemoryView + VirtualTranslate>(
     virt_mem: &mut T,
     start_block: &StartBlock,
 ) -> Result<(Address, umem)> {
+    find_with_progress(virt_mem, start_block, None, None)
+}
+
+/// Finds ntoskrnl.exe and reports the progress of the scan over the kernel address space.
+///
+/// The cancellation token is checked between the scanned regions.
+pub fn find_with_progress<T: MemoryView + VirtualTranslate>(
+    virt_mem: &mut T,
+    start_block: &StartBlock,
+    progress: Option<&Progress>,
+    cancellation: Option<&CancellationToken>,
+) -> Result<(Address, umem)> {
+    find_with_report(
+        virt_mem,
+        start_block,
+        &NtosScanConfig::default(),
+        progress,
+        cancellation,
+        &mut ScanReport::default(),
+    )
+}
+
+/// Finds ntoskrnl.exe and records every stage of the scan in the given report.
+///
+/// The granularity of the scans over the kernel address space is controlled by `config`.
+#[cfg_attr(feature = "tracing", tracing::instrument(name = "ntos_find", skip_all))]
+pub fn find_with_report<T: MemoryView + VirtualTranslate>(
+    virt_mem: &mut T,
+    start_block: &StartBlock,
+    config: &NtosScanConfig,
+    progress: Option<&Progress>,
+    cancellation: Option<&CancellationToken>,
+    report: &mut ScanReport,
+) -> Result<(Address, umem)> {
+    find_impl(
+        virt_mem,
+        start_block,
+        config,
+        progress,
+        cancellation,
+        report,
+        x64::find,
+        aarch64::find,
+    )
+}
+
+/// Finds ntoskrnl.exe and distributes the scan over the kernel address space over multiple threads.
+///
+/// Every thread reads through its own clone of `virt_mem`.
+#[cfg(feature = "parallel")]
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(name = "ntos_find_parallel", skip_all)
+)]
+pub fn find_parallel<T: MemoryView + VirtualTranslate + Clone + Send>(
+    virt_mem: &mut T,
+    start_block: &StartBlock,
+    config: &NtosScanConfig,
+    progress: Option<&Progress>,
+    cancellation: Option<&CancellationToken>,
+    report: &mut ScanReport,
+) -> Result<(Address, umem)> {
+    find_impl(
+        virt_mem,
+        start_block,
+        config,
+        progress,
+        cancellation,
+        report,
+        x64::find_parallel,
+        aarch64::find_parallel,
+    )
+}
+
+type FindFn<T> = fn(
+    &mut T,
+    &StartBlock,
+    &NtosScanConfig,
+    Option<&Progress>,
+    Option<&CancellationToken>,
+    &ScanCounters,
+) -> Result<(Address, umem)>;
+
+#[allow(clippy::too_many_arguments)]
+fn find_impl<T: MemoryView + VirtualTranslate>(
+    virt_mem: &mut T,
+    start_block: &StartBlock,
+    config: &NtosScanConfig,
+    progress: Option<&Progress>,
+    cancellation: Option<&CancellationToken>,
+    report: &mut ScanReport,
+    find_x64: FindFn<T>,
+    find_aarch64: FindFn<T>,
+) -> Result<(Address, umem)> {
+    let dtb = Some(start_block.dtb);
     let arch_obj = ArchitectureObj::from(start_block.arch);
-    if arch_obj.bits() == 64 {
+    if let ArchitectureIdent::AArch64(_) = start_block.arch {
+        let counters = ScanCounters::default();
+        let result = find_aarch64(
+            virt_mem,
+            start_block,
+            config,
+            progress,
+            cancellation,
+            &counters,
+        );
+        report.record_counters(ScanStage::PageMap, dtb, &counters, &result);
+        match result {
+            Ok(b) => return Ok(b),
+            Err(e) if cancellation.map_or(false, |c| c.is_cancelled()) => return Err(e),
+            Err(e) => warn!("aarch64::find() error: {}", e),
+        }
+    } else if arch_obj.bits() == 64 {
         if !start_block.kernel_hint.is_null() {
-            match x64::find_with_va_hint(virt_mem, start_block) {
+            let counters = ScanCounters::default();
+            let result = x64::find_with_va_hint(virt_mem, start_block, config, &counters);
+            report.record_counters(ScanStage::VaHint, dtb, &counters, &result);
+            match result {
                 Ok(b) => return Ok(b),
                 Err(e) => warn!("x64::find_with_va_hint() error: {}", e),
             }
         }
 
-        match x64::find(virt_mem, start_block) {
+        let counters = ScanCounters::default();
+        let result = find_x64(
+            virt_mem,
+            start_block,
+            config,
+            progress,
+            cancellation,
+            &counters,
+        );
+        report.record_counters(ScanStage::PageMap, dtb, &counters, &result);
+        match result {
             Ok(b) => return Ok(b),
+            Err(e) if cancellation.map_or(false, |c| c.is_cancelled()) => return Err(e),
             Err(e) => warn!("x64::find() error: {}", e),
         }
     } else if arch_obj.bits() == 32 {
-        match x86::find(virt_mem, start_block) {
+        let counters = ScanCounters::default();
+        let result = x86::find(virt_mem, start_block, progress, cancellation, &counters);
+        report.record_counters(ScanStage::HighMem, dtb, &counters, &result);
+        match result {
             Ok(b) => return Ok(b),
+            Err(e) if cancellation.map_or(false, |c| c.is_cancelled()) => return Err(e),
             Err(e) => warn!("x86::find() error: {}", e),
         }
     }
 
+    // the pe headers of the kernel might have been wiped
+    if config.headerless {
+        let counters = ScanCounters::default();
+        let result = headerless::find(virt_mem, start_block, progress, cancellation, &counters);
+        report.record_counters(ScanStage::Headerless, dtb, &counters, &result);
+        match result {
+            Ok(b) => return Ok(b),
+            Err(e) if cancellation.map_or(false, |c| c.is_cancelled()) => return Err(e),
+            Err(e) => warn!("headerless::find() error: {}", e),
+        }
+    }
+
     Err(Error(ErrorOrigin::OsLayer, ErrorKind::ProcessNotFound)
         .log_info("unable to find ntoskrnl.exe"))
 }
 
 // TODO: move to pe::...
 pub fn find_guid<T: MemoryView>(mem: &mut T, kernel_base: Address) -> Result<Win32Guid> {
-    let image = pehelper::try_get_pe_image(mem, kernel_base)?;
-    let pe = PeView::from_bytes(&image)
+    LazyPe::new(mem, kernel_base)?.guid()
+}
+
+/// Returns the pdb guid from the codeview debug entry of a mapped pe image.
+///
+/// This works for any module, not just the kernel.
+pub fn find_guid_in_image(image: &[u8]) -> Result<Win32Guid> {
+    let pe = PeView::from_bytes(image)
         .map_err(|err| Error(ErrorOrigin::OsLayer, ErrorKind::InvalidExeFile).log_info(err))?;
 
     let debug = match pe.debug() {
@@ -86,11 +276,16 @@ pub fn find_guid<T: MemoryView>(mem: &mut T, kernel_base: Address) -> Result<Win
         Error(ErrorOrigin::OsLayer, ErrorKind::Encoding)
             .log_info("unable to convert pdb file name to string")
     })?;
+    // some drivers are linked with the full path of the pdb, the symbol store only uses the file name
+    let file_name = file_name
+        .rsplit(|c| c == '\\' || c == '/')
+        .next()
+        .unwrap_or(file_name);
     let guid = format!("{:X}{:X}", signature, code_view.age());
     Ok(Win32Guid::new(file_name, &guid))
 }
 
-fn get_export(pe: &PeView, name: &str) -> Result<umem> {
+pub(crate) fn get_export(pe: &PeView, name: &str) -> Result<umem> {
     info!("trying to find {} export", name);
     let export = match pe
         .get_export_by_name(name)
@@ -106,14 +301,91 @@ fn get_export(pe: &PeView, name: &str) -> Result<umem> {
     Ok(export)
 }
 
-pub fn find_winver<T: MemoryView>(mem: &mut T, kernel_base: Address) -> Result<Win32Version> {
-    let image = pehelper::try_get_pe_image(mem, kernel_base)?;
-    let pe = PeView::from_bytes(&image)
-        .map_err(|err| Error(ErrorOrigin::OsLayer, ErrorKind::InvalidExeFile).log_info(err))?;
+/// Returns the names of all modules in the loaded module list of the kernel.
+///
+/// The list is walked through the exported `PsLoadedModuleList` and therefore does not require any offsets.
+pub fn find_module_names<T: MemoryView>(
+    mem: &mut T,
+    arch: ArchitectureIdent,
+    kernel_base: Address,
+) -> Result<Vec<String>> {
+    let list_head = kernel_base + LazyPe::new(mem, kernel_base)?.export("PsLoadedModuleList")?;
+
+    let arch_obj = ArchitectureObj::from(arch);
+    let offsets = Win32ArchOffsets::from(arch);
+
+    let mut names = vec![];
+    // a broken link ends the walk, the names found up to that point are still returned
+    Win32ListWalker::new(arch_obj, list_head)
+        .max_entries(MAX_MODULES)
+        .walk(mem, |mem, entry, _| {
+            if entry != list_head {
+                if let Ok(name) =
+                    mem.read_unicode_string(arch_obj, entry + offsets.ldr_data_base_name)
+                {
+                    names.push(name);
+                }
+            }
+            true
+        })
+        .ok();
+    Ok(names)
+}
+
+/// Returns true if the kernel at `kernel_base` belongs to ReactOS.
+///
+/// ReactOS mimics the layout of the Windows kernel but stores its own company name
+/// in the version resource of the kernel image. Only the version resource is read from the image.
+pub fn is_reactos<T: MemoryView>(mem: &mut T, kernel_base: Address) -> bool {
+    LazyPe::new(mem, kernel_base)
+        .and_then(|mut pe| pe.version_resource())
+        .and_then(|resource| Win32VersionInfo::parse(&resource))
+        .map_or(false, |info| {
+            info.company_name
+                .map_or(false, |name| name.contains("ReactOS"))
+        })
+}
+
+/// Kernel mode mapping of KUSER_SHARED_DATA on 32 bit kernels
+const KUSER_SHARED_DATA_X86: umem = 0xffdf0000;
+/// Kernel mode mapping of KUSER_SHARED_DATA on 64 bit kernels
+const KUSER_SHARED_DATA_X64: umem = 0xfffff780_00000000;
+/// User mode mapping of KUSER_SHARED_DATA in every process
+const KUSER_SHARED_DATA_USER: umem = 0x7ffe0000;
+
+/// Returns the address at which KUSER_SHARED_DATA is mapped in the given memory view.
+///
+/// The kernel mode mapping of the architecture is preferred. The user mode mapping is used
+/// as a fallback in case the kernel mode mapping cannot be read (e.g. on some nt 5.x kernels).
+pub(crate) fn kuser_shared_data<T: MemoryView>(
+    mem: &mut T,
+    arch: ArchitectureIdent,
+) -> Result<Address> {
+    let shared_data_kernel = match ArchitectureObj::from(arch).bits() {
+        64 => KUSER_SHARED_DATA_X64,
+        _ => KUSER_SHARED_DATA_X86,
+    };
+
+    [shared_data_kernel, KUSER_SHARED_DATA_USER]
+        .iter()
+        .map(|&shared_data| Address::from(shared_data))
+        .find(|&shared_data| read_shared_data_version(mem, shared_data).is_ok())
+        .ok_or_else(|| {
+            Error(ErrorOrigin::OsLayer, ErrorKind::InvalidMemory)
+                .log_debug("KUSER_SHARED_DATA is not mapped")
+        })
+}
+
+pub fn find_winver<T: MemoryView>(
+    mem: &mut T,
+    arch: ArchitectureIdent,
+    kernel_base: Address,
+) -> Result<Win32Version> {
+    let mut pe = LazyPe::new(mem, kernel_base)?;
 
     // NtBuildNumber
-    let nt_build_number_ref = get_export(&pe, "NtBuildNumber")?;
-    let rtl_get_version_ref = get_export(&pe, "RtlGetVersion");
+    let nt_build_number_ref = pe.export("NtBuildNumber")?;
+    let rtl_get_version_ref = pe.export("RtlGetVersion");
 
     let nt_build_number: u32 = mem.read(kernel_base + nt_build_number_ref)?;
     info!("nt_build_number: {}", nt_build_number);
@@ -122,42 +394,28 @@ pub fn find_winver<T: MemoryView>(mem: &mut T, kernel_base: Address) -> Result<W
             .log_info("unable to fetch nt build number"));
     }
 
-    // TODO: these reads should be optional
     // try to find major/minor version
     // read from KUSER_SHARED_DATA. these fields exist since nt 4.0 so they have to exist in case NtBuildNumber exists.
-    let mut nt_major_version: u32 = mem.read((0x7ffe0000 + 0x026C).into()).data_part()?;
-    let mut nt_minor_version: u32 = mem.read((0x7ffe0000 + 0x0270).into()).data_part()?;
-
-    // fallback on x64: try to parse RtlGetVersion assembly
-    if nt_major_version == 0 && rtl_get_version_ref.is_ok() {
-        let mut buf = [0u8; 0x100];
-        mem.read_into(kernel_base + rtl_get_version_ref.unwrap(), &mut buf)
-            .data_part()?;
-
-        nt_major_version = 0;
-        nt_minor_version = 0;
-
-        for i in 0..0xf0 {
-            if nt_major_version == 0
-                && nt_minor_version == 0
-                && u32::from_le_bytes(buf[i..i + 4].try_into().unwrap()) == 0x441c748
-            {
-                nt_major_version =
-                    u16::from_le_bytes(buf[i + 4..i + 4 + 2].try_into().unwrap()) as u32;
-                nt_minor_version = (buf[i + 5] & 0xF) as u32;
-            }
+    let (mut nt_major_version, mut nt_minor_version) = kuser_shared_data(mem, arch)
+        .and_then(|shared_data| read_shared_data_version(mem, shared_data))
+        .unwrap_or_else(|_| {
+            warn!("unable to read KUSER_SHARED_DATA, falling back to RtlGetVersion");
+            (0, 0)
+        });
 
-            if nt_major_version == 0
-                && u32::from_le_bytes(buf[i..i + 4].try_into().unwrap()) & 0xFFFFF == 0x441c7
-            {
-                nt_major_version = buf[i + 3] as u32;
-            }
+    // fallback: try to parse RtlGetVersion assembly
+    if nt_major_version == 0 {
+        if let Ok(rtl_get_version_ref) = rtl_get_version_ref {
+            let mut buf = [0u8; 0x100];
+            mem.read_into(kernel_base + rtl_get_version_ref, &mut buf)
+                .data_part()?;
 
-            if nt_minor_version == 0
-                && u32::from_le_bytes(buf[i..i + 4].try_into().unwrap()) & 0xFFFFF == 0x841c7
-            {
-                nt_major_version = buf[i + 3] as u32;
-            }
+            let (major, minor) = match arch {
+                ArchitectureIdent::AArch64(_) => parse_rtl_get_version_aarch64(&buf),
+                _ => parse_rtl_get_version_x86(&buf),
+            };
+            nt_major_version = major;
+            nt_minor_version = minor;
         }
     }
 
@@ -167,3 +425,163 @@ pub fn find_winver<T: MemoryView>(mem: &mut T, kernel_base: Address) -> Result<W
 
     Ok(version)
 }
+
+fn read_shared_data_version<T: MemoryView>(
+    mem: &mut T,
+    shared_data: Address,
+) -> Result<(u32, u32)> {
+    let nt_major_version: u32 = mem.read(shared_data + 0x026C).data_part()?;
+    let nt_minor_version: u32 = mem.read(shared_data + 0x0270).data_part()?;
+    if nt_major_version == 0 {
+        return Err(Error(ErrorOrigin::OsLayer, ErrorKind::InvalidMemory)
+            .log_trace("KUSER_SHARED_DATA is not mapped"));
+    }
+    Ok((nt_major_version, nt_minor_version))
+}
+
+/// Parses the stores of the version constants into the RTL_OSVERSIONINFOW structure on x86 and x64.
+fn parse_rtl_get_version_x86(buf: &[u8]) -> (u32, u32) {
+    let mut nt_major_version = 0;
+    let mut nt_minor_version = 0;
+
+    for i in 0..buf.len() - 0x10 {
+        let insn = u32::from_le_bytes(buf[i..i + 4].try_into().unwrap());
+
+        // mov qword ptr [rcx+4], imm32
+        if nt_major_version == 0 && nt_minor_version == 0 && insn == 0x441c748 {
+            nt_major_version = u16::from_le_bytes(buf[i + 4..i + 4 + 2].try_into().unwrap()) as u32;
+            nt_minor_version = (buf[i + 5] & 0xF) as u32;
+        }
+
+        // mov dword ptr [rcx+4], imm32
+        if nt_major_version == 0 && insn & 0xFFFFF == 0x441c7 {
+            nt_major_version = buf[i + 3] as u32;
+        }
+
+        // mov dword ptr [rcx+8], imm32
+        if nt_minor_version == 0 && insn & 0xFFFFF == 0x841c7 {
+            nt_minor_version = buf[i + 3] as u32;
+        }
+    }
+
+    (nt_major_version, nt_minor_version)
+}
+
+/// Parses the stores of the version constants into the RTL_OSVERSIONINFOW structure on aarch64.
+///
+/// The constants are loaded with `movz` and stored with `str` or `stp` relative to the first argument.
+fn parse_rtl_get_version_aarch64(buf: &[u8]) -> (u32, u32) {
+    let mut regs = [None; 32];
+    let mut nt_major_version = 0;
+    let mut nt_minor_version = 0;
+
+    for insn in buf
+        .chunks_exact(4)
+        .map(|c| u32::from_le_bytes(c.try_into().unwrap()))
+    {
+        let rt = (insn & 0x1f) as usize;
+        let rn = ((insn >> 5) & 0x1f) as usize;
+
+        if insn & 0x7f80_0000 == 0x5280_0000 {
+            // movz wd/xd, #imm16{, lsl #hw}
+            let shift = ((insn >> 21) & 0x3) * 16;
+            regs[rt] = Some(((insn >> 5) & 0xffff) << shift);
+        } else if insn & 0xffc0_0000 == 0xb900_0000 && rn == 0 {
+            // str wt, [x0, #imm12 * 4]
+            match ((insn >> 10) & 0xfff) * 4 {
+                4 => nt_major_version = regs[rt].unwrap_or(nt_major_version),
+                8 => nt_minor_version = regs[rt].unwrap_or(nt_minor_version),
+                _ => {}
+            }
+        } else if insn & 0xffc0_0000 == 0x2900_0000 && rn == 0 {
+            // stp wt, wt2, [x0, #imm7 * 4]
+            let rt2 = ((insn >> 10) & 0x1f) as usize;
+            if (insn >> 15) & 0x7f == 1 {
+                nt_major_version = regs[rt].unwrap_or(nt_major_version);
+                nt_minor_version = regs[rt2].unwrap_or(nt_minor_version);
+            }
+        } else if insn == 0xd65f_03c0 {
+            // ret
+            break;
+        }
+
+        if nt_major_version != 0 && nt_minor_version != 0 {
+            break;
+        }
+    }
+
+    (nt_major_version, nt_minor_version)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn code(insns: &[&[u8]]) -> Vec<u8> {
+        let mut buf = vec![0xccu8; 0x10];
+        insns.iter().for_each(|insn| buf.extend_from_slice(insn));
+        buf.resize(0x100, 0xcc);
+        buf
+    }
+
+    fn arm64(insns: &[u32]) -> Vec<u8> {
+        let mut buf = insns
+            .iter()
+            .flat_map(|insn| insn.to_le_bytes())
+            .collect::<Vec<_>>();
+        buf.resize(0x100, 0);
+        buf
+    }
+
+    #[test]
+    fn rtl_get_version_x64_dword() {
+        // mov dword ptr [rcx+4], 6 / mov dword ptr [rcx+8], 3
+        let buf = code(&[
+            &[0xc7, 0x41, 0x04, 0x06, 0, 0, 0],
+            &[0xc7, 0x41, 0x08, 0x03, 0, 0, 0],
+        ]);
+        assert_eq!(parse_rtl_get_version_x86(&buf), (6, 3));
+    }
+
+    #[test]
+    fn rtl_get_version_x64_qword() {
+        // mov qword ptr [rcx+4], 10
+        let buf = code(&[&[0x48, 0xc7, 0x41, 0x04, 0x0a, 0, 0, 0]]);
+        assert_eq!(parse_rtl_get_version_x86(&buf), (10, 0));
+    }
+
+    #[test]
+    fn rtl_get_version_minor_store() {
+        // the store to [rcx+8] is the minor version and never the major version
+        let buf = code(&[&[0xc7, 0x41, 0x08, 0x03, 0, 0, 0]]);
+        assert_eq!(parse_rtl_get_version_x86(&buf), (0, 3));
+    }
+
+    #[test]
+    fn rtl_get_version_aarch64_str() {
+        // movz w8, #6 / movz w9, #3 / str w8, [x0, #4] / str w9, [x0, #8] / ret
+        let buf = arm64(&[
+            0x5280_00c8,
+            0x5280_0069,
+            0xb900_0408,
+            0xb900_0809,
+            0xd65f_03c0,
+        ]);
+        assert_eq!(parse_rtl_get_version_aarch64(&buf), (6, 3));
+    }
+
+    #[test]
+    fn rtl_get_version_aarch64_stp() {
+        // movz w8, #10 / movz w9, #0 / stp w8, w9, [x0, #4] / ret
+        let buf = arm64(&[0x5280_0148, 0x5280_0009, 0x2900_a408, 0xd65f_03c0]);
+        assert_eq!(parse_rtl_get_version_aarch64(&buf), (10, 0));
+    }
+
+    #[test]
+    fn rtl_get_version_aarch64_ret() {
+        // stores after the end of the function are ignored
+        // movz w8, #10 / ret / str w8, [x0, #4]
+        let buf = arm64(&[0x5280_0148, 0xd65f_03c0, 0xb900_0408]);
+        assert_eq!(parse_rtl_get_version_aarch64(&buf), (0, 0));
+    }
+}
@@ -0,0 +1,143 @@
+use std::prelude::v1::*;
+
+use super::{pehelper, x64, NtosScanConfig};
+use crate::kernel::diagnostics::ScanCounters;
+use crate::kernel::StartBlock;
+use crate::progress::{CancellationToken, Progress};
+
+use log::debug;
+
+use memflow::architecture::ArchitectureObj;
+use memflow::error::{Error, ErrorKind, ErrorOrigin, Result};
+use memflow::mem::{MemoryView, VirtualTranslate};
+use memflow::types::{umem, Address};
+
+/// Start of the region arm64 kernels randomize the kernel image into
+const KERNEL_IMAGE_START: u64 = 0xfffff800_00000000;
+/// End of the region arm64 kernels randomize the kernel image into
+const KERNEL_IMAGE_END: u64 = 0xfffff808_00000000;
+
+// Windows on arm64 only uses 4kb granules so the probing of x64 can be reused
+// with the page map of the aarch64 translation.
+
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(name = "ntos_find_page_map_aarch64", skip_all, fields(regions))
+)]
+pub fn find<T: MemoryView + VirtualTranslate>(
+    virt_mem: &mut T,
+    start_block: &StartBlock,
+    config: &NtosScanConfig,
+    progress: Option<&Progress>,
+    cancellation: Option<&CancellationToken>,
+    counters: &ScanCounters,
+) -> Result<(Address, umem)> {
+    debug!("aarch64::find: trying to find ntoskrnl.exe with page map");
+
+    // the kernel image is usually mapped in the randomized image region, scanning it first
+    // avoids walking the page map of the entire kernel half of the address space.
+    let probe_size = x64::probe_size(config);
+    for image_region in [true, false] {
+        let chunks = kernel_chunks(virt_mem, start_block, config, image_region);
+        if let Some(progress) = progress {
+            progress.start(
+                "scanning kernel address space for ntoskrnl.exe",
+                chunks.len() as u64,
+            );
+        }
+        let result = x64::find_in_chunks(
+            virt_mem,
+            &chunks,
+            probe_size,
+            progress,
+            cancellation,
+            counters,
+        );
+        if let Some(progress) = progress {
+            progress.finish();
+        }
+
+        if let Some(a) = result? {
+            let addr = Address::from(a);
+            let size_of_image = pehelper::try_get_pe_size(virt_mem, addr)?;
+            return Ok((addr, size_of_image));
+        }
+    }
+
+    Err(Error(ErrorOrigin::OsLayer, ErrorKind::ProcessNotFound)
+        .log_trace("aarch64::find: unable to locate ntoskrnl.exe with a page map"))
+}
+
+/// Same as [`find`] but the chunks are distributed over the threads of the rayon thread pool.
+#[cfg(feature = "parallel")]
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(
+        name = "ntos_find_page_map_aarch64_parallel",
+        skip_all,
+        fields(regions)
+    )
+)]
+pub fn find_parallel<T: MemoryView + VirtualTranslate + Clone + Send>(
+    virt_mem: &mut T,
+    start_block: &StartBlock,
+    config: &NtosScanConfig,
+    progress: Option<&Progress>,
+    cancellation: Option<&CancellationToken>,
+    counters: &ScanCounters,
+) -> Result<(Address, umem)> {
+    debug!("aarch64::find_parallel: trying to find ntoskrnl.exe with page map");
+
+    let probe_size = x64::probe_size(config);
+    for image_region in [true, false] {
+        let chunks = kernel_chunks(virt_mem, start_block, config, image_region);
+        if let Some(progress) = progress {
+            progress.start(
+                "scanning kernel address space for ntoskrnl.exe",
+                chunks.len() as u64,
+            );
+        }
+        let result = crate::kernel::parallel::find_first(
+            virt_mem,
+            &chunks,
+            progress,
+            cancellation,
+            |virt_mem, buf, (va, _)| {
+                x64::find_with_va(virt_mem, va.to_umem(), probe_size, buf, counters).ok()
+            },
+        );
+        if let Some(progress) = progress {
+            progress.finish();
+        }
+
+        if let Some(a) = result? {
+            let addr = Address::from(a);
+            let size_of_image = pehelper::try_get_pe_size(virt_mem, addr)?;
+            return Ok((addr, size_of_image));
+        }
+    }
+
+    Err(Error(ErrorOrigin::OsLayer, ErrorKind::ProcessNotFound)
+        .log_trace("aarch64::find_parallel: unable to locate ntoskrnl.exe with a page map"))
+}
+
+/// Collects the chunks of either the kernel image region or the remaining kernel half of the address space.
+fn kernel_chunks<T: MemoryView + VirtualTranslate>(
+    virt_mem: &mut T,
+    start_block: &StartBlock,
+    config: &NtosScanConfig,
+    image_region: bool,
+) -> Vec<(Address, umem)> {
+    if image_region {
+        return x64::chunks_in_range(virt_mem, KERNEL_IMAGE_START, KERNEL_IMAGE_END, config);
+    }
+
+    let address_space_bits = ArchitectureObj::from(start_block.arch).address_space_bits();
+    let kernel_start = !0u64 - (1u64 << (address_space_bits - 1));
+    x64::chunks_in_range(virt_mem, kernel_start, !0u64, config)
+        .into_iter()
+        .filter(|(va, _)| {
+            *va < Address::from(KERNEL_IMAGE_START) || *va >= Address::from(KERNEL_IMAGE_END)
+        })
+        .collect()
+}
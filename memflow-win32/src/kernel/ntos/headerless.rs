@@ -0,0 +1,412 @@
+use std::prelude::v1::*;
+
+use crate::kernel::diagnostics::{RejectReason, ScanCounters};
+use crate::kernel::StartBlock;
+use crate::progress::{CancellationToken, Progress};
+
+use std::convert::TryInto;
+
+use log::{debug, info, trace};
+
+use memflow::architecture::ArchitectureObj;
+use memflow::cglue::tuple::*;
+use memflow::error::{Error, ErrorKind, ErrorOrigin, PartialResultExt, Result};
+use memflow::mem::{MemoryView, VirtualTranslate};
+use memflow::types::{mem, size, smem, umem, Address};
+
+/// Name of the kernel as stored in its export directory
+const NTOSKRNL_NAME: &[u8] = b"ntoskrnl.exe\0";
+/// Export that has to be present in the reconstructed export directory
+const VALIDATION_EXPORT: &str = "PsLookupProcessByProcessId";
+
+/// Maximum distance between the export directory and the name of the image
+const EXPORT_DIR_SCAN_LEN: usize = size::kb(256);
+/// Upper bound for the size of the reconstructed image
+pub(crate) const MAX_IMAGE_SIZE: umem = mem::mb(64);
+
+/// Parsed `IMAGE_EXPORT_DIRECTORY`
+#[derive(Debug, Clone, Copy)]
+struct ExportDirectory {
+    number_of_functions: u32,
+    number_of_names: u32,
+    address_of_functions: u32,
+    address_of_names: u32,
+    address_of_name_ordinals: u32,
+}
+
+/// Finds ntoskrnl.exe if its mz and pe headers have been wiped.
+///
+/// The kernel image is located through the name `ntoskrnl.exe` referenced by its export directory.
+/// The base is reconstructed from the rva of the name and the image is validated by resolving
+/// `PsLookupProcessByProcessId`. The size covers the mapped region following the base.
+///
+/// This reads the entire kernel half of the address space and is therefore only used for exhaustive scans.
+/// The cancellation token is checked between the scanned chunks.
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(name = "ntos_find_headerless", skip_all)
+)]
+pub fn find<T: MemoryView + VirtualTranslate>(
+    virt_mem: &mut T,
+    start_block: &StartBlock,
+    progress: Option<&Progress>,
+    cancellation: Option<&CancellationToken>,
+    counters: &ScanCounters,
+) -> Result<(Address, umem)> {
+    debug!("headerless::find: trying to find ntoskrnl.exe without pe headers");
+
+    let arch_obj = ArchitectureObj::from(start_block.arch);
+    let (start, end) = if arch_obj.bits() == 64 {
+        let address_space_bits = if start_block.la57 {
+            57
+        } else {
+            arch_obj.address_space_bits()
+        };
+        (!0u64 - (1u64 << (address_space_bits - 1)), !0u64)
+    } else {
+        (0x8000_0000, 0xffff_ffff)
+    };
+
+    if let Some(progress) = progress {
+        progress.start(
+            "scanning kernel address space for ntoskrnl.exe without pe headers",
+            end - start,
+        );
+    }
+    let result = find_in_range(virt_mem, start, end, progress, cancellation, counters);
+    if let Some(progress) = progress {
+        progress.finish();
+    }
+    result
+}
+
+fn find_in_range<T: MemoryView + VirtualTranslate>(
+    virt_mem: &mut T,
+    start: u64,
+    end: u64,
+    progress: Option<&Progress>,
+    cancellation: Option<&CancellationToken>,
+    counters: &ScanCounters,
+) -> Result<(Address, umem)> {
+    let page_map = virt_mem.virt_page_map_range_vec(smem::kb(4), start.into(), end.into());
+    for CTup3(region, region_size, _) in page_map.into_iter() {
+        let region_end = region + region_size;
+
+        let mut chunk_base = region;
+        while chunk_base < region_end {
+            if let Some(cancellation) = cancellation {
+                cancellation.check()?;
+            }
+            if let Some(progress) = progress {
+                progress.update(chunk_base.to_umem() as u64 - start);
+            }
+
+            let len = ((region_end - chunk_base) as usize).min(size::mb(2));
+            let mut chunk = vec![0; len];
+            if virt_mem
+                .read_raw_into(chunk_base, &mut chunk)
+                .data_part()
+                .is_ok()
+            {
+                for pos in find_all(&chunk, NTOSKRNL_NAME) {
+                    let name = chunk_base + pos;
+                    trace!("headerless::find: found kernel name at {:x}", name);
+                    counters.candidate();
+                    if let Some(base) = find_base_from_name(virt_mem, name) {
+                        let size = ((region_end - base) as umem).min(MAX_IMAGE_SIZE);
+                        info!("headerless::find: found ntoskrnl.exe at {:x}", base);
+                        return Ok((base, size));
+                    }
+                    counters.reject(RejectReason::ValidationFailed);
+                }
+            }
+            chunk_base += len;
+        }
+    }
+
+    Err(Error(ErrorOrigin::OsLayer, ErrorKind::ProcessNotFound)
+        .log_trace("headerless::find: unable to locate ntoskrnl.exe without pe headers"))
+}
+
+/// Resolves an export of an image without pe headers.
+///
+/// The export directory is located through the name of the image.
+/// `size` is the size of the image as returned by [`find`], unmapped pages inside of it are ignored.
+pub fn find_export<T: MemoryView>(
+    virt_mem: &mut T,
+    base: Address,
+    size: umem,
+    name: &str,
+) -> Result<Address> {
+    let mut image = vec![0; size.min(MAX_IMAGE_SIZE) as usize];
+    virt_mem.read_raw_into(base, &mut image).data_part()?;
+
+    find_all(&image, NTOSKRNL_NAME)
+        .find_map(|pos| {
+            let dir_start = pos.saturating_sub(EXPORT_DIR_SCAN_LEN);
+            let (_, dir) = (dir_start..pos).step_by(4).find_map(|offset| {
+                let dir = parse_export_directory(&image[offset..])?;
+                (read_u32(&image, offset + 0xc)? as usize == pos).then_some((offset, dir))
+            })?;
+            resolve_export(virt_mem, base, &dir, name)
+        })
+        .map(|rva| base + rva as umem)
+        .ok_or_else(|| {
+            Error(ErrorOrigin::OsLayer, ErrorKind::ExportNotFound)
+                .log_info(format!("unable to find export {} without pe headers", name))
+        })
+}
+
+/// Reconstructs the image base from the position of the image name.
+fn find_base_from_name<T: MemoryView>(virt_mem: &mut T, name: Address) -> Option<Address> {
+    let dir_start = name - EXPORT_DIR_SCAN_LEN;
+    let mut buf = vec![0; EXPORT_DIR_SCAN_LEN];
+    virt_mem
+        .read_raw_into(dir_start, &mut buf)
+        .data_part()
+        .ok()?;
+
+    (0..EXPORT_DIR_SCAN_LEN - 0x28)
+        .step_by(4)
+        .filter_map(|offset| {
+            let dir = parse_export_directory(&buf[offset..])?;
+            let name_rva = read_u32(&buf, offset + 0xc)? as umem;
+            let base = name.to_umem().checked_sub(name_rva)?;
+            // the directory and its tables have to be located inside the image before the name
+            let dir_rva = (dir_start + offset).to_umem().checked_sub(base)?;
+            let valid = base & 0xfff == 0
+                && name_rva < MAX_IMAGE_SIZE
+                && (dir.address_of_functions as umem) > dir_rva
+                && (dir.address_of_names as umem) < name_rva
+                && (dir.address_of_name_ordinals as umem) < name_rva;
+            valid.then_some((Address::from(base), dir))
+        })
+        .find(|(base, dir)| resolve_export(virt_mem, *base, dir, VALIDATION_EXPORT).is_some())
+        .map(|(base, _)| base)
+}
+
+fn parse_export_directory(buf: &[u8]) -> Option<ExportDirectory> {
+    // Characteristics is always 0
+    if read_u32(buf, 0)? != 0 {
+        return None;
+    }
+
+    let dir = ExportDirectory {
+        number_of_functions: read_u32(buf, 0x14)?,
+        number_of_names: read_u32(buf, 0x18)?,
+        address_of_functions: read_u32(buf, 0x1c)?,
+        address_of_names: read_u32(buf, 0x20)?,
+        address_of_name_ordinals: read_u32(buf, 0x24)?,
+    };
+
+    // ntoskrnl exports a few thousand functions
+    let plausible = dir.number_of_names > 100
+        && dir.number_of_names <= dir.number_of_functions
+        && dir.number_of_functions < 0x10000
+        && dir.address_of_functions != 0
+        && dir.address_of_names != 0
+        && dir.address_of_name_ordinals != 0;
+    plausible.then_some(dir)
+}
+
+/// Resolves the rva of an export by a binary search over the sorted name table.
+fn resolve_export<T: MemoryView>(
+    virt_mem: &mut T,
+    base: Address,
+    dir: &ExportDirectory,
+    name: &str,
+) -> Option<u32> {
+    let mut names = vec![0u8; dir.number_of_names as usize * 4];
+    virt_mem
+        .read_raw_into(base + dir.address_of_names as umem, &mut names)
+        .ok()?;
+
+    let (mut low, mut high) = (0usize, dir.number_of_names as usize);
+    while low < high {
+        let mid = (low + high) / 2;
+        let name_rva = read_u32(&names, mid * 4)?;
+
+        let mut buf = [0u8; 64];
+        virt_mem
+            .read_raw_into(base + name_rva as umem, &mut buf)
+            .data_part()
+            .ok()?;
+        let export_name = buf.split(|&c| c == 0).next()?;
+
+        match export_name.cmp(name.as_bytes()) {
+            core::cmp::Ordering::Less => low = mid + 1,
+            core::cmp::Ordering::Greater => high = mid,
+            core::cmp::Ordering::Equal => {
+                let mut ordinal = [0u8; 2];
+                virt_mem
+                    .read_raw_into(
+                        base + dir.address_of_name_ordinals as umem + mid as umem * 2,
+                        &mut ordinal,
+                    )
+                    .ok()?;
+                let ordinal = u16::from_le_bytes(ordinal) as umem;
+                return virt_mem
+                    .read::<u32>(base + dir.address_of_functions as umem + ordinal * 4)
+                    .ok();
+            }
+        }
+    }
+
+    None
+}
+
+fn find_all<'a>(haystack: &'a [u8], needle: &'a [u8]) -> impl Iterator<Item = usize> + 'a {
+    haystack
+        .windows(needle.len())
+        .enumerate()
+        .filter(move |(_, w)| *w == needle)
+        .map(|(i, _)| i)
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> Option<u32> {
+    buf.get(offset..offset + 4)
+        .map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use memflow::dummy::DummyMemory;
+    use memflow::mem::PhysicalMemory;
+
+    const BASE: u64 = 0x10_0000;
+    const IMAGE_SIZE: usize = 0x1_0000;
+    const EXPORT_DIR: usize = 0x2000;
+    const FUNCTIONS: usize = 0x2100;
+    const NAMES: usize = 0x3000;
+    const ORDINALS: usize = 0x4000;
+    const STRINGS: usize = 0x5000;
+    const NAME: usize = 0x8000;
+
+    fn put_u32(image: &mut [u8], offset: usize, value: u32) {
+        image[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    /// An image with wiped headers whose export directory references `ntoskrnl.exe`.
+    fn headerless_image(names: &[String]) -> Vec<u8> {
+        let mut image = vec![0u8; IMAGE_SIZE];
+
+        put_u32(&mut image, EXPORT_DIR + 0xc, NAME as u32);
+        put_u32(&mut image, EXPORT_DIR + 0x14, names.len() as u32);
+        put_u32(&mut image, EXPORT_DIR + 0x18, names.len() as u32);
+        put_u32(&mut image, EXPORT_DIR + 0x1c, FUNCTIONS as u32);
+        put_u32(&mut image, EXPORT_DIR + 0x20, NAMES as u32);
+        put_u32(&mut image, EXPORT_DIR + 0x24, ORDINALS as u32);
+
+        for (i, name) in names.iter().enumerate() {
+            let string = STRINGS + i * 0x20;
+            image[string..string + name.len()].copy_from_slice(name.as_bytes());
+            put_u32(&mut image, NAMES + i * 4, string as u32);
+            image[ORDINALS + i * 2..ORDINALS + i * 2 + 2]
+                .copy_from_slice(&(i as u16).to_le_bytes());
+            put_u32(&mut image, FUNCTIONS + i * 4, 0x1000 + i as u32 * 0x10);
+        }
+
+        image[NAME..NAME + NTOSKRNL_NAME.len()].copy_from_slice(NTOSKRNL_NAME);
+        image
+    }
+
+    /// Sorted export names, the validation export is the last one.
+    fn export_names(validation_export: &str) -> Vec<String> {
+        let mut names = (0..127)
+            .map(|i| format!("Export{:03}", i))
+            .collect::<Vec<_>>();
+        names.push(validation_export.to_string());
+        names
+    }
+
+    /// Deterministic pseudo random bytes (xorshift64).
+    fn random_bytes(len: usize) -> Vec<u8> {
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn find_image_without_headers() {
+        let mut mem = DummyMemory::new(size::mb(2));
+        let mut view = mem.phys_view();
+        view.write_raw(
+            Address::from(BASE),
+            &headerless_image(&export_names(VALIDATION_EXPORT)),
+        )
+        .unwrap();
+
+        let name = Address::from(BASE + NAME as u64);
+        assert_eq!(
+            find_base_from_name(&mut view, name),
+            Some(Address::from(BASE))
+        );
+
+        let export = find_export(
+            &mut view,
+            Address::from(BASE),
+            IMAGE_SIZE as umem,
+            VALIDATION_EXPORT,
+        )
+        .unwrap();
+        assert_eq!(export, Address::from(BASE + 0x1000 + 127 * 0x10));
+        let export = find_export(
+            &mut view,
+            Address::from(BASE),
+            IMAGE_SIZE as umem,
+            "Export042",
+        )
+        .unwrap();
+        assert_eq!(export, Address::from(BASE + 0x1000 + 42 * 0x10));
+
+        assert!(find_export(
+            &mut view,
+            Address::from(BASE),
+            IMAGE_SIZE as umem,
+            "Missing"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn reject_image_without_validation_export() {
+        let mut mem = DummyMemory::new(size::mb(2));
+        let mut view = mem.phys_view();
+        view.write_raw(
+            Address::from(BASE),
+            &headerless_image(&export_names("ZwClose")),
+        )
+        .unwrap();
+
+        let name = Address::from(BASE + NAME as u64);
+        assert_eq!(find_base_from_name(&mut view, name), None);
+    }
+
+    #[test]
+    fn reject_random_data() {
+        let mut mem = DummyMemory::new(size::mb(2));
+        let mut view = mem.phys_view();
+        let mut data = random_bytes(size::mb(1));
+        let pos = 0x8_0000;
+        data[pos..pos + NTOSKRNL_NAME.len()].copy_from_slice(NTOSKRNL_NAME);
+        view.write_raw(Address::from(BASE), &data).unwrap();
+
+        let name = Address::from(BASE + pos as u64);
+        assert_eq!(find_base_from_name(&mut view, name), None);
+        assert!(find_export(
+            &mut view,
+            Address::from(BASE),
+            data.len() as umem,
+            VALIDATION_EXPORT
+        )
+        .is_err());
+    }
+}
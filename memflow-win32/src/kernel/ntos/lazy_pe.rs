@@ -0,0 +1,870 @@
+use std::prelude::v1::*;
+
+use std::convert::TryInto;
+
+use log::trace;
+
+use memflow::error::{Error, ErrorKind, ErrorOrigin, PartialResultExt, Result};
+use memflow::mem::MemoryView;
+use memflow::types::{size, umem, Address};
+
+use crate::kernel::Win32Guid;
+
+const IMAGE_NT_OPTIONAL_HDR32_MAGIC: u16 = 0x10b;
+const IMAGE_NT_OPTIONAL_HDR64_MAGIC: u16 = 0x20b;
+
+const IMAGE_DIRECTORY_ENTRY_EXPORT: usize = 0;
+const IMAGE_DIRECTORY_ENTRY_IMPORT: usize = 1;
+const IMAGE_DIRECTORY_ENTRY_RESOURCE: usize = 2;
+const IMAGE_DIRECTORY_ENTRY_EXCEPTION: usize = 3;
+const IMAGE_DIRECTORY_ENTRY_SECURITY: usize = 4;
+const IMAGE_DIRECTORY_ENTRY_DEBUG: usize = 6;
+const IMAGE_DIRECTORY_ENTRY_COM_DESCRIPTOR: usize = 14;
+
+const RT_VERSION: u32 = 16;
+const IMAGE_RESOURCE_DATA_IS_DIRECTORY: u32 = 0x8000_0000;
+const IMAGE_RESOURCE_NAME_IS_STRING: u32 = 0x8000_0000;
+
+const IMAGE_SCN_MEM_EXECUTE: u32 = 0x2000_0000;
+const IMAGE_IMPORT_DESCRIPTOR_SIZE: usize = 0x14;
+
+const IMAGE_DEBUG_TYPE_CODEVIEW: u32 = 2;
+const IMAGE_DEBUG_DIRECTORY_SIZE: usize = 0x1c;
+const CV_SIGNATURE_RSDS: &[u8] = b"RSDS";
+
+/// Upper bound for the size of a data directory that is read at once
+const MAX_DIRECTORY_SIZE: usize = size::mb(16);
+/// Upper bound for the length of a string referenced by the image
+const MAX_STRING_LEN: usize = 0x200;
+/// Upper bound for the number of entries of a resource directory
+const MAX_RESOURCE_ENTRIES: usize = 0x1000;
+/// Upper bound for the number of functions imported from a single module
+const MAX_IMPORTS_PER_MODULE: usize = 0x4000;
+/// Upper bound for the size of the version resource
+const MAX_VERSION_RESOURCE_SIZE: usize = size::kb(64);
+
+/// Pe image in memory that is parsed without copying the whole image.
+///
+/// Only the headers are read up front. Lookups of exports and debug entries read
+/// the data directories they touch, this avoids reading several megabytes for the
+/// kernel image or a module when only a single export or the pdb guid is required.
+pub struct LazyPe<'a, T> {
+    mem: &'a mut T,
+    base: Address,
+    headers: Vec<u8>,
+    nt_headers: usize,
+    data_directories: usize,
+    number_of_data_directories: usize,
+}
+
+impl<'a, T: MemoryView> LazyPe<'a, T> {
+    /// Reads and validates the headers of the image at `base`.
+    pub fn new(mem: &'a mut T, base: Address) -> Result<Self> {
+        let mut headers = vec![0u8; size::kb(4)];
+        mem.read_raw_into(base, &mut headers).data_part()?;
+
+        if read_u16(&headers, 0) != Some(0x5a4d) {
+            return Err(Error(ErrorOrigin::OsLayer, ErrorKind::InvalidExeFile)
+                .log_trace("invalid mz signature"));
+        }
+
+        let nt_headers = read_u32(&headers, 0x3c)
+            .map(|e_lfanew| e_lfanew as usize)
+            .filter(|&e_lfanew| read_u32(&headers, e_lfanew) == Some(0x4550))
+            .ok_or_else(|| {
+                Error(ErrorOrigin::OsLayer, ErrorKind::InvalidExeFile)
+                    .log_trace("invalid pe signature")
+            })?;
+
+        // the optional header follows the signature and the file header
+        let optional_header = nt_headers + 0x18;
+        let (data_directories, number_of_data_directories) =
+            match read_u16(&headers, optional_header) {
+                Some(IMAGE_NT_OPTIONAL_HDR32_MAGIC) => (
+                    optional_header + 0x60,
+                    read_u32(&headers, optional_header + 0x5c),
+                ),
+                Some(IMAGE_NT_OPTIONAL_HDR64_MAGIC) => (
+                    optional_header + 0x70,
+                    read_u32(&headers, optional_header + 0x6c),
+                ),
+                _ => (0, None),
+            };
+        let number_of_data_directories = number_of_data_directories.ok_or_else(|| {
+            Error(ErrorOrigin::OsLayer, ErrorKind::InvalidExeFile)
+                .log_trace("invalid optional header")
+        })? as usize;
+
+        Ok(Self {
+            mem,
+            base,
+            headers,
+            nt_headers,
+            data_directories,
+            number_of_data_directories: number_of_data_directories.min(16),
+        })
+    }
+
+    /// Returns the rva and size of a data directory.
+    fn data_directory(&self, index: usize) -> Result<(u32, u32)> {
+        let entry = self.data_directories + index * 8;
+        match (
+            index < self.number_of_data_directories,
+            read_u32(&self.headers, entry),
+            read_u32(&self.headers, entry + 4),
+        ) {
+            (true, Some(rva), Some(size)) if rva != 0 && size != 0 => Ok((rva, size)),
+            _ => Err(Error(ErrorOrigin::OsLayer, ErrorKind::InvalidExeFile)
+                .log_trace(format!("data directory {} is not present", index))),
+        }
+    }
+
+    fn read_directory(&mut self, index: usize) -> Result<(u32, Vec<u8>)> {
+        let (rva, size) = self.data_directory(index)?;
+        let buf = self.read_rva(rva, (size as usize).min(MAX_DIRECTORY_SIZE))?;
+        Ok((rva, buf))
+    }
+
+    /// Reads the exception directory which contains the `RUNTIME_FUNCTION` entries of x64 and arm64 images.
+    pub fn exception_directory(&mut self) -> Result<Vec<u8>> {
+        self.read_directory(IMAGE_DIRECTORY_ENTRY_EXCEPTION)
+            .map(|(_, dir)| dir)
+    }
+
+    /// Returns the file offset and size of the certificate table.
+    ///
+    /// Unlike the other data directories the security directory references the file and not the image.
+    pub fn security_directory(&self) -> Result<(u32, u32)> {
+        self.data_directory(IMAGE_DIRECTORY_ENTRY_SECURITY)
+    }
+
+    /// Returns the link timestamp from the file header.
+    pub fn time_date_stamp(&self) -> Option<u32> {
+        read_u32(&self.headers, self.nt_headers + 0x8)
+    }
+
+    /// Returns the size of the image from the optional header.
+    pub fn size_of_image(&self) -> Option<u32> {
+        read_u32(&self.headers, self.nt_headers + 0x18 + 0x38)
+    }
+
+    /// Returns the machine type from the file header.
+    pub fn machine(&self) -> Option<u16> {
+        read_u16(&self.headers, self.nt_headers + 0x4)
+    }
+
+    /// Returns true if the image uses the 64 bit optional header.
+    pub fn is_64(&self) -> bool {
+        read_u16(&self.headers, self.nt_headers + 0x18) == Some(IMAGE_NT_OPTIONAL_HDR64_MAGIC)
+    }
+
+    /// Returns the rva, virtual size and characteristics of every section header.
+    pub fn sections(&self) -> impl Iterator<Item = (u32, u32, u32)> + '_ {
+        let number_of_sections = read_u16(&self.headers, self.nt_headers + 0x6).unwrap_or(0);
+        let size_of_optional_header =
+            read_u16(&self.headers, self.nt_headers + 0x14).unwrap_or(0) as usize;
+        let sections = self.nt_headers + 0x18 + size_of_optional_header;
+
+        (0..number_of_sections as usize).map_while(move |i| {
+            let section = sections + i * 0x28;
+            Some((
+                read_u32(&self.headers, section + 0xc)?,
+                read_u32(&self.headers, section + 0x8)?,
+                read_u32(&self.headers, section + 0x24)?,
+            ))
+        })
+    }
+
+    /// Returns true if the rva is part of an executable section.
+    pub fn is_executable(&self, rva: u32) -> bool {
+        self.sections()
+            .any(|(virtual_address, virtual_size, characteristics)| {
+                characteristics & IMAGE_SCN_MEM_EXECUTE != 0
+                    && rva >= virtual_address
+                    && rva - virtual_address < virtual_size
+            })
+    }
+
+    /// Converts a file offset into the rva of the section that maps it.
+    ///
+    /// Returns `None` if the offset is not part of the raw data of a section.
+    pub fn file_offset_to_rva(&self, offset: u32) -> Option<u32> {
+        let number_of_sections = read_u16(&self.headers, self.nt_headers + 0x6)? as usize;
+        let size_of_optional_header = read_u16(&self.headers, self.nt_headers + 0x14)? as usize;
+        let sections = self.nt_headers + 0x18 + size_of_optional_header;
+
+        (0..number_of_sections).find_map(|i| {
+            let section = sections + i * 0x28;
+            let virtual_address = read_u32(&self.headers, section + 0xc)?;
+            let size_of_raw_data = read_u32(&self.headers, section + 0x10)?;
+            let pointer_to_raw_data = read_u32(&self.headers, section + 0x14)?;
+            offset
+                .checked_sub(pointer_to_raw_data)
+                .filter(|&delta| delta < size_of_raw_data)
+                .map(|delta| virtual_address.wrapping_add(delta))
+        })
+    }
+
+    /// Reads `len` bytes at the given rva.
+    pub fn read_rva(&mut self, rva: u32, len: usize) -> Result<Vec<u8>> {
+        let mut buf = vec![0u8; len];
+        self.mem
+            .read_raw_into(self.base + rva as umem, &mut buf)
+            .data_part()?;
+        Ok(buf)
+    }
+
+    /// Reads a null terminated string at the given rva.
+    ///
+    /// The string is taken from `dir` if it is located inside of it.
+    fn read_string(&mut self, dir_rva: u32, dir: &[u8], rva: u32) -> Result<String> {
+        let bytes = match rva
+            .checked_sub(dir_rva)
+            .and_then(|offset| dir.get(offset as usize..))
+        {
+            Some(bytes) => bytes.to_vec(),
+            None => {
+                let mut buf = vec![0u8; MAX_STRING_LEN];
+                self.mem
+                    .read_raw_into(self.base + rva as umem, &mut buf)
+                    .data_part()?;
+                buf
+            }
+        };
+
+        let len = bytes.iter().position(|&c| c == 0).unwrap_or(bytes.len());
+        String::from_utf8(bytes[..len].to_vec()).map_err(|_| {
+            Error(ErrorOrigin::OsLayer, ErrorKind::Encoding).log_trace("invalid string encoding")
+        })
+    }
+
+    /// Returns the name of the image stored in its export directory.
+    pub fn dll_name(&mut self) -> Result<String> {
+        let (dir_rva, dir) = self.read_directory(IMAGE_DIRECTORY_ENTRY_EXPORT)?;
+        let name_rva = read_u32(&dir, 0xc).ok_or_else(|| {
+            Error(ErrorOrigin::OsLayer, ErrorKind::InvalidExeFile)
+                .log_trace("export directory is truncated")
+        })?;
+        self.read_string(dir_rva, &dir, name_rva)
+    }
+
+    /// Resolves the rva of an export by its name.
+    ///
+    /// Forwarded exports are not supported.
+    pub fn export(&mut self, name: &str) -> Result<umem> {
+        let (dir_rva, dir) = self.read_directory(IMAGE_DIRECTORY_ENTRY_EXPORT)?;
+        let not_found = || {
+            Error(ErrorOrigin::OsLayer, ErrorKind::ExportNotFound)
+                .log_info(format!("unable to find export {}", name))
+        };
+
+        let (number_of_names, address_of_functions, address_of_names, address_of_name_ordinals) =
+            match (
+                read_u32(&dir, 0x18),
+                read_u32(&dir, 0x1c),
+                read_u32(&dir, 0x20),
+                read_u32(&dir, 0x24),
+            ) {
+                (Some(n), Some(f), Some(na), Some(o)) => (n, f, na, o),
+                _ => return Err(not_found()),
+            };
+
+        let table = |rva: u32, index: usize, entry_size: usize| -> Option<usize> {
+            let offset = rva.checked_sub(dir_rva)? as usize + index * entry_size;
+            match entry_size {
+                2 => read_u16(&dir, offset).map(|v| v as usize),
+                _ => read_u32(&dir, offset).map(|v| v as usize),
+            }
+        };
+
+        // the name table is sorted which allows a binary search
+        let (mut low, mut high) = (0usize, number_of_names as usize);
+        while low < high {
+            let mid = (low + high) / 2;
+            let name_rva = table(address_of_names, mid, 4).ok_or_else(not_found)? as u32;
+            let export_name = self.read_string(dir_rva, &dir, name_rva)?;
+
+            match export_name.as_str().cmp(name) {
+                core::cmp::Ordering::Less => low = mid + 1,
+                core::cmp::Ordering::Greater => high = mid,
+                core::cmp::Ordering::Equal => {
+                    let ordinal = table(address_of_name_ordinals, mid, 2).ok_or_else(not_found)?;
+                    let rva = table(address_of_functions, ordinal, 4).ok_or_else(not_found)?;
+
+                    // forwarded exports point into the export directory
+                    if rva >= dir_rva as usize && rva < dir_rva as usize + dir.len() {
+                        return Err(Error(ErrorOrigin::OsLayer, ErrorKind::ExportNotFound)
+                            .log_info(format!("{} found but it was a forwarded export", name)));
+                    }
+
+                    trace!("export {} found at rva {:x}", name, rva);
+                    return Ok(rva as umem);
+                }
+            }
+        }
+
+        Err(not_found())
+    }
+
+    /// Returns the name, the rva and the rva of the address table entry of all named exports.
+    ///
+    /// Forwarded exports are skipped. The rvas are returned as they are stored in the
+    /// export address table, they may point outside of the image if the table was modified.
+    pub fn exports(&mut self) -> Result<Vec<(String, u32, u32)>> {
+        let (dir_rva, dir) = self.read_directory(IMAGE_DIRECTORY_ENTRY_EXPORT)?;
+        let (number_of_names, address_of_functions, address_of_names, address_of_name_ordinals) =
+            match (
+                read_u32(&dir, 0x18),
+                read_u32(&dir, 0x1c),
+                read_u32(&dir, 0x20),
+                read_u32(&dir, 0x24),
+            ) {
+                (Some(n), Some(f), Some(na), Some(o)) => (n, f, na, o),
+                _ => {
+                    return Err(Error(ErrorOrigin::OsLayer, ErrorKind::InvalidExeFile)
+                        .log_trace("export directory is truncated"))
+                }
+            };
+
+        let table = |rva: u32, index: usize, entry_size: usize| -> Option<u32> {
+            let offset = rva.checked_sub(dir_rva)? as usize + index * entry_size;
+            match entry_size {
+                2 => read_u16(&dir, offset).map(|v| v as u32),
+                _ => read_u32(&dir, offset),
+            }
+        };
+
+        let mut exports = vec![];
+        for i in 0..number_of_names as usize {
+            let (name_rva, ordinal) =
+                match table(address_of_names, i, 4).zip(table(address_of_name_ordinals, i, 2)) {
+                    Some(entry) => entry,
+                    None => break,
+                };
+            let rva = match table(address_of_functions, ordinal as usize, 4) {
+                Some(rva) => rva,
+                None => continue,
+            };
+
+            // forwarded exports point into the export directory
+            if rva >= dir_rva && ((rva - dir_rva) as usize) < dir.len() {
+                continue;
+            }
+
+            if let Ok(name) = self.read_string(dir_rva, &dir, name_rva) {
+                exports.push((name, rva, address_of_functions + ordinal * 4));
+            }
+        }
+
+        trace!("found {} exports", exports.len());
+        Ok(exports)
+    }
+
+    /// Returns the imported module name, the function name and the rva of the
+    /// import address table slot of all imports.
+    ///
+    /// Functions that are imported by ordinal do not have a name.
+    pub fn imports(&mut self) -> Result<Vec<(String, Option<String>, u32)>> {
+        let (dir_rva, dir) = self.read_directory(IMAGE_DIRECTORY_ENTRY_IMPORT)?;
+        let (thunk_size, ordinal_flag) = if self.is_64() {
+            (8, 1u64 << 63)
+        } else {
+            (4, 1u64 << 31)
+        };
+
+        let mut imports = vec![];
+        for descriptor in dir.chunks_exact(IMAGE_IMPORT_DESCRIPTOR_SIZE) {
+            let (original_first_thunk, name_rva, first_thunk) = match (
+                read_u32(descriptor, 0x0),
+                read_u32(descriptor, 0xc),
+                read_u32(descriptor, 0x10),
+            ) {
+                (Some(oft), Some(name), Some(ft)) if name != 0 && ft != 0 => (oft, name, ft),
+                _ => break,
+            };
+            let module_name = match self.read_string(dir_rva, &dir, name_rva) {
+                Ok(name) => name,
+                Err(_) => continue,
+            };
+
+            // bound images without a lookup table only provide the import address table
+            let lookup = if original_first_thunk != 0 {
+                original_first_thunk
+            } else {
+                first_thunk
+            };
+
+            'thunks: for chunk in (0..MAX_IMPORTS_PER_MODULE).step_by(0x100) {
+                let thunks =
+                    match self.read_rva(lookup + (chunk * thunk_size) as u32, 0x100 * thunk_size) {
+                        Ok(thunks) => thunks,
+                        Err(_) => break,
+                    };
+
+                for (i, thunk) in thunks.chunks_exact(thunk_size).enumerate() {
+                    let thunk = match thunk_size {
+                        8 => u64::from_le_bytes(thunk.try_into().unwrap()),
+                        _ => u32::from_le_bytes(thunk.try_into().unwrap()) as u64,
+                    };
+                    if thunk == 0 {
+                        break 'thunks;
+                    }
+
+                    let name = if original_first_thunk == 0 || thunk & ordinal_flag != 0 {
+                        None
+                    } else {
+                        // skip the hint of IMAGE_IMPORT_BY_NAME
+                        self.read_string(dir_rva, &dir, (thunk as u32).wrapping_add(2))
+                            .ok()
+                    };
+                    let slot = first_thunk + ((chunk + i) * thunk_size) as u32;
+                    imports.push((module_name.clone(), name, slot));
+                }
+            }
+        }
+
+        trace!("found {} imports", imports.len());
+        Ok(imports)
+    }
+
+    /// Reads the codeview debug entry and returns the guid of the pdb.
+    pub fn guid(&mut self) -> Result<Win32Guid> {
+        let (_, debug) = self.read_directory(IMAGE_DIRECTORY_ENTRY_DEBUG)?;
+
+        let (size_of_data, address_of_raw_data) = debug
+            .chunks_exact(IMAGE_DEBUG_DIRECTORY_SIZE)
+            .filter(|entry| read_u32(entry, 0xc) == Some(IMAGE_DEBUG_TYPE_CODEVIEW))
+            .find_map(|entry| Some((read_u32(entry, 0x10)?, read_u32(entry, 0x14)?)))
+            .ok_or_else(|| {
+                Error(ErrorOrigin::OsLayer, ErrorKind::InvalidExeFile)
+                    .log_info("unable to find codeview debug_data entry")
+            })?;
+
+        let mut code_view = vec![0u8; (size_of_data as usize).min(MAX_STRING_LEN + 0x18)];
+        self.mem
+            .read_raw_into(self.base + address_of_raw_data as umem, &mut code_view)
+            .data_part()?;
+
+        if code_view.get(0..4) != Some(CV_SIGNATURE_RSDS) || code_view.len() < 0x18 {
+            return Err(Error(ErrorOrigin::OsLayer, ErrorKind::InvalidExeFile)
+                .log_info("invalid code_view entry, expected version 7"));
+        }
+
+        // the guid is followed by the age and the pdb file name
+        let data1 = read_u32(&code_view, 4).unwrap();
+        let data2 = read_u16(&code_view, 8).unwrap();
+        let data3 = read_u16(&code_view, 10).unwrap();
+        let age = read_u32(&code_view, 0x14).unwrap();
+        let mut guid = format!("{:08X}{:04X}{:04X}", data1, data2, data3);
+        for b in code_view[12..20].iter() {
+            guid.push_str(&format!("{:02X}", b));
+        }
+        guid.push_str(&format!("{:X}", age));
+
+        let file_name = &code_view[0x18..];
+        let len = file_name
+            .iter()
+            .position(|&c| c == 0)
+            .unwrap_or(file_name.len());
+        let file_name = std::str::from_utf8(&file_name[..len]).map_err(|_| {
+            Error(ErrorOrigin::OsLayer, ErrorKind::Encoding)
+                .log_info("unable to convert pdb file name to string")
+        })?;
+        // some drivers are linked with the full path of the pdb, the symbol store only uses the file name
+        let file_name = file_name
+            .rsplit(|c| c == '\\' || c == '/')
+            .next()
+            .unwrap_or(file_name);
+
+        Ok(Win32Guid::new(file_name, &guid))
+    }
+
+    /// Reads the raw `VS_VERSIONINFO` resource of the image.
+    ///
+    /// Only the entries of the resource tree that lead to the first version resource are read,
+    /// the remaining resources (e.g. icons) are never touched.
+    pub fn version_resource(&mut self) -> Result<Vec<u8>> {
+        self.resource(RT_VERSION, None, MAX_VERSION_RESOURCE_SIZE)
+    }
+
+    /// Reads the first resource of the given type.
+    ///
+    /// If `name` is set only resources with this (case insensitive) name are considered,
+    /// resources that are identified by an id never match a name.
+    pub fn resource(
+        &mut self,
+        type_id: u32,
+        name: Option<&str>,
+        max_size: usize,
+    ) -> Result<Vec<u8>> {
+        let (dir_rva, _) = self.data_directory(IMAGE_DIRECTORY_ENTRY_RESOURCE)?;
+        let not_found = || {
+            Error(ErrorOrigin::OsLayer, ErrorKind::NotFound)
+                .log_trace(format!("unable to find resource of type {}", type_id))
+        };
+
+        // the tree is made of the type, the name and the language level
+        let mut offset = 0u32;
+        for level in 0..3 {
+            let header = self.read_rva(dir_rva.wrapping_add(offset), 0x10)?;
+            let count = (read_u16(&header, 0xc).unwrap() as usize
+                + read_u16(&header, 0xe).unwrap() as usize)
+                .min(MAX_RESOURCE_ENTRIES);
+            let entries = self.read_rva(dir_rva.wrapping_add(offset + 0x10), count * 8)?;
+
+            let mut data = None;
+            for entry in entries.chunks_exact(8) {
+                let id = read_u32(entry, 0).unwrap();
+                let matches = match (level, name) {
+                    (0, _) => id == type_id,
+                    (1, Some(name)) if id & IMAGE_RESOURCE_NAME_IS_STRING != 0 => {
+                        // names are stored as a length prefixed utf-16 string
+                        let name_rva = dir_rva.wrapping_add(id & !IMAGE_RESOURCE_NAME_IS_STRING);
+                        let len = read_u16(&self.read_rva(name_rva, 2)?, 0).unwrap() as usize;
+                        let buf = self.read_rva(name_rva + 2, len.min(MAX_STRING_LEN) * 2)?;
+                        let entry_name = buf
+                            .chunks_exact(2)
+                            .map(|c| u16::from_le_bytes([c[0], c[1]]))
+                            .collect::<Vec<_>>();
+                        String::from_utf16_lossy(&entry_name).eq_ignore_ascii_case(name)
+                    }
+                    (1, Some(_)) => false,
+                    _ => true,
+                };
+                if matches {
+                    data = read_u32(entry, 4);
+                    break;
+                }
+            }
+            let data = data.ok_or_else(not_found)?;
+
+            // only the last level points to the data entry
+            if (data & IMAGE_RESOURCE_DATA_IS_DIRECTORY != 0) != (level < 2) {
+                return Err(Error(ErrorOrigin::OsLayer, ErrorKind::InvalidExeFile)
+                    .log_trace("malformed resource directory"));
+            }
+            offset = data & !IMAGE_RESOURCE_DATA_IS_DIRECTORY;
+        }
+
+        let data_entry = self.read_rva(dir_rva.wrapping_add(offset), 0x10)?;
+        let rva = read_u32(&data_entry, 0).unwrap();
+        let size = read_u32(&data_entry, 4).unwrap() as usize;
+        self.read_rva(rva, size.min(max_size))
+    }
+
+    /// Returns the rva and size of the metadata and the flags from the clr header of managed images.
+    pub fn clr_header(&mut self) -> Result<(u32, u32, u32)> {
+        let (_, header) = self.read_directory(IMAGE_DIRECTORY_ENTRY_COM_DESCRIPTOR)?;
+        match (
+            read_u32(&header, 0x8),
+            read_u32(&header, 0xc),
+            read_u32(&header, 0x10),
+        ) {
+            (Some(rva), Some(size), Some(flags)) if rva != 0 => Ok((rva, size, flags)),
+            _ => Err(Error(ErrorOrigin::OsLayer, ErrorKind::InvalidExeFile)
+                .log_trace("clr header is truncated")),
+        }
+    }
+}
+
+fn read_u16(buf: &[u8], offset: usize) -> Option<u16> {
+    buf.get(offset..offset + 2)
+        .map(|b| u16::from_le_bytes(b.try_into().unwrap()))
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> Option<u32> {
+    buf.get(offset..offset + 4)
+        .map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use memflow::dummy::DummyMemory;
+    use memflow::mem::PhysicalMemory;
+
+    const BASE: u64 = 0x10000;
+    const EXPORT_DIR: usize = 0x2000;
+    const RESOURCE_DIR: usize = 0x2400;
+    const RT_RCDATA: u32 = 10;
+
+    fn put(image: &mut [u8], offset: usize, data: &[u8]) {
+        image[offset..offset + data.len()].copy_from_slice(data);
+    }
+
+    fn put_u16(image: &mut [u8], offset: usize, value: u16) {
+        put(image, offset, &value.to_le_bytes());
+    }
+
+    fn put_u32(image: &mut [u8], offset: usize, value: u32) {
+        put(image, offset, &value.to_le_bytes());
+    }
+
+    /// Writes a resource directory header with `named` string entries and `ids` id entries.
+    fn put_resource_dir(image: &mut [u8], offset: usize, named: u16, ids: u16) {
+        put_u16(image, offset + 0xc, named);
+        put_u16(image, offset + 0xe, ids);
+    }
+
+    fn put_resource_name(image: &mut [u8], offset: usize, name: &str) {
+        let name = name.encode_utf16().collect::<Vec<_>>();
+        put_u16(image, offset, name.len() as u16);
+        for (i, c) in name.iter().enumerate() {
+            put_u16(image, offset + 2 + i * 2, *c);
+        }
+    }
+
+    /// A pe64 image with a single code section, two exports and two named RT_RCDATA resources.
+    fn test_image() -> Vec<u8> {
+        let mut image = vec![0u8; 0x3000];
+        put(&mut image, 0, b"MZ");
+        put_u32(&mut image, 0x3c, 0x40);
+        put(&mut image, 0x40, b"PE\0\0");
+
+        // IMAGE_FILE_HEADER
+        put_u16(&mut image, 0x44, 0x8664);
+        put_u16(&mut image, 0x46, 1);
+        put_u32(&mut image, 0x48, 0x1234_5678);
+        put_u16(&mut image, 0x54, 0xf0);
+
+        // IMAGE_OPTIONAL_HEADER64
+        let opt = 0x58;
+        put_u16(&mut image, opt, IMAGE_NT_OPTIONAL_HDR64_MAGIC);
+        put_u32(&mut image, opt + 0x38, 0x3000);
+        put_u32(&mut image, opt + 0x6c, 16);
+        let dirs = opt + 0x70;
+        put_u32(&mut image, dirs, EXPORT_DIR as u32);
+        put_u32(&mut image, dirs + 4, 0x100);
+        put_u32(&mut image, dirs + 2 * 8, RESOURCE_DIR as u32);
+        put_u32(&mut image, dirs + 2 * 8 + 4, 0x200);
+
+        // .text section
+        let section = opt + 0xf0;
+        put(&mut image, section, b".text\0\0\0");
+        put_u32(&mut image, section + 0x8, 0x1000);
+        put_u32(&mut image, section + 0xc, 0x1000);
+        put_u32(&mut image, section + 0x10, 0x1000);
+        put_u32(&mut image, section + 0x14, 0x400);
+        put_u32(&mut image, section + 0x24, 0x6000_0020);
+
+        // export directory, the name table is sorted
+        let exp = EXPORT_DIR;
+        put_u32(&mut image, exp + 0xc, (exp + 0x80) as u32);
+        put_u32(&mut image, exp + 0x14, 2);
+        put_u32(&mut image, exp + 0x18, 2);
+        put_u32(&mut image, exp + 0x1c, (exp + 0x28) as u32);
+        put_u32(&mut image, exp + 0x20, (exp + 0x30) as u32);
+        put_u32(&mut image, exp + 0x24, (exp + 0x38) as u32);
+        put_u32(&mut image, exp + 0x28, 0x1100);
+        put_u32(&mut image, exp + 0x2c, 0x1200);
+        put_u32(&mut image, exp + 0x30, (exp + 0x90) as u32);
+        put_u32(&mut image, exp + 0x34, (exp + 0xa0) as u32);
+        put_u16(&mut image, exp + 0x38, 0);
+        put_u16(&mut image, exp + 0x3a, 1);
+        put(&mut image, exp + 0x80, b"test.sys\0");
+        put(&mut image, exp + 0x90, b"AlphaExport\0");
+        put(&mut image, exp + 0xa0, b"BetaExport\0");
+
+        // resource tree: type RT_RCDATA -> names OTHER and CLRDEBUGINFO -> language 0x409
+        let res = RESOURCE_DIR;
+        put_resource_dir(&mut image, res, 0, 1);
+        put_u32(&mut image, res + 0x10, RT_RCDATA);
+        put_u32(
+            &mut image,
+            res + 0x14,
+            IMAGE_RESOURCE_DATA_IS_DIRECTORY | 0x18,
+        );
+
+        put_resource_dir(&mut image, res + 0x18, 2, 0);
+        put_u32(
+            &mut image,
+            res + 0x28,
+            IMAGE_RESOURCE_NAME_IS_STRING | 0x100,
+        );
+        put_u32(
+            &mut image,
+            res + 0x2c,
+            IMAGE_RESOURCE_DATA_IS_DIRECTORY | 0x60,
+        );
+        put_u32(
+            &mut image,
+            res + 0x30,
+            IMAGE_RESOURCE_NAME_IS_STRING | 0x110,
+        );
+        put_u32(
+            &mut image,
+            res + 0x34,
+            IMAGE_RESOURCE_DATA_IS_DIRECTORY | 0x78,
+        );
+
+        put_resource_dir(&mut image, res + 0x60, 0, 1);
+        put_u32(&mut image, res + 0x70, 0x409);
+        put_u32(&mut image, res + 0x74, 0x90);
+        put_resource_dir(&mut image, res + 0x78, 0, 1);
+        put_u32(&mut image, res + 0x88, 0x409);
+        put_u32(&mut image, res + 0x8c, 0xa0);
+
+        // IMAGE_RESOURCE_DATA_ENTRY
+        put_u32(&mut image, res + 0x90, 0x2800);
+        put_u32(&mut image, res + 0x94, 4);
+        put_u32(&mut image, res + 0xa0, 0x2810);
+        put_u32(&mut image, res + 0xa4, 4);
+
+        put_resource_name(&mut image, res + 0x100, "OTHER");
+        put_resource_name(&mut image, res + 0x110, "CLRDEBUGINFO");
+        put(&mut image, 0x2800, b"othr");
+        put(&mut image, 0x2810, b"clr!");
+
+        image
+    }
+
+    fn write_image<M: MemoryView>(mem: &mut M, image: &[u8]) {
+        mem.write_raw(Address::from(BASE), image).unwrap();
+    }
+
+    #[test]
+    fn parse_headers() {
+        let mut mem = DummyMemory::new(size::mb(1));
+        let mut view = mem.phys_view();
+        write_image(&mut view, &test_image());
+
+        let pe = LazyPe::new(&mut view, Address::from(BASE)).unwrap();
+        assert_eq!(pe.machine(), Some(0x8664));
+        assert!(pe.is_64());
+        assert_eq!(pe.time_date_stamp(), Some(0x1234_5678));
+        assert_eq!(pe.size_of_image(), Some(0x3000));
+        assert_eq!(
+            pe.sections().collect::<Vec<_>>(),
+            vec![(0x1000, 0x1000, 0x6000_0020)]
+        );
+        assert!(pe.is_executable(0x1100));
+        assert!(!pe.is_executable(0x2000));
+        assert_eq!(pe.file_offset_to_rva(0x450), Some(0x1050));
+        assert_eq!(pe.file_offset_to_rva(0x100), None);
+        assert!(pe.security_directory().is_err());
+    }
+
+    #[test]
+    fn parse_exports() {
+        let mut mem = DummyMemory::new(size::mb(1));
+        let mut view = mem.phys_view();
+        write_image(&mut view, &test_image());
+
+        let mut pe = LazyPe::new(&mut view, Address::from(BASE)).unwrap();
+        assert_eq!(pe.dll_name().unwrap(), "test.sys");
+        assert_eq!(pe.export("AlphaExport").unwrap(), 0x1100);
+        assert_eq!(pe.export("BetaExport").unwrap(), 0x1200);
+        assert_eq!(
+            pe.export("GammaExport").unwrap_err().1,
+            ErrorKind::ExportNotFound
+        );
+        assert_eq!(
+            pe.exports().unwrap(),
+            vec![
+                (
+                    "AlphaExport".to_string(),
+                    0x1100,
+                    (EXPORT_DIR + 0x28) as u32
+                ),
+                ("BetaExport".to_string(), 0x1200, (EXPORT_DIR + 0x2c) as u32),
+            ]
+        );
+    }
+
+    #[test]
+    fn find_resource_by_name() {
+        let mut mem = DummyMemory::new(size::mb(1));
+        let mut view = mem.phys_view();
+        write_image(&mut view, &test_image());
+
+        let mut pe = LazyPe::new(&mut view, Address::from(BASE)).unwrap();
+        // names are compared case insensitive
+        assert_eq!(
+            pe.resource(RT_RCDATA, Some("clrdebuginfo"), 0x100).unwrap(),
+            b"clr!"
+        );
+        assert_eq!(
+            pe.resource(RT_RCDATA, Some("OTHER"), 0x100).unwrap(),
+            b"othr"
+        );
+        // without a name the first resource of the type is returned
+        assert_eq!(pe.resource(RT_RCDATA, None, 0x100).unwrap(), b"othr");
+        // the size of the data is capped
+        assert_eq!(pe.resource(RT_RCDATA, Some("OTHER"), 2).unwrap(), b"ot");
+
+        assert_eq!(
+            pe.resource(RT_RCDATA, Some("MISSING"), 0x100)
+                .unwrap_err()
+                .1,
+            ErrorKind::NotFound
+        );
+        assert_eq!(pe.version_resource().unwrap_err().1, ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn reject_truncated_headers() {
+        let mut mem = DummyMemory::new(size::mb(1));
+        let mut view = mem.phys_view();
+
+        let mut image = test_image();
+        put(&mut image, 0, b"ZM");
+        write_image(&mut view, &image);
+        assert!(LazyPe::new(&mut view, Address::from(BASE)).is_err());
+
+        // the pe signature is located outside of the headers
+        let mut image = test_image();
+        put_u32(&mut image, 0x3c, 0x2000);
+        write_image(&mut view, &image);
+        assert!(LazyPe::new(&mut view, Address::from(BASE)).is_err());
+
+        // the optional header is cut off by the end of the headers
+        let mut image = test_image();
+        put_u32(&mut image, 0x3c, 0xffc);
+        put(&mut image, 0xffc, b"PE\0\0");
+        write_image(&mut view, &image);
+        assert!(LazyPe::new(&mut view, Address::from(BASE)).is_err());
+
+        // the data directories beyond NumberOfRvaAndSizes are not present
+        let mut image = test_image();
+        put_u32(&mut image, 0x58 + 0x6c, 1);
+        write_image(&mut view, &image);
+        let mut pe = LazyPe::new(&mut view, Address::from(BASE)).unwrap();
+        assert_eq!(pe.export("AlphaExport").unwrap(), 0x1100);
+        assert_eq!(
+            pe.resource(RT_RCDATA, None, 0x100).unwrap_err().1,
+            ErrorKind::InvalidExeFile
+        );
+    }
+
+    #[test]
+    fn reject_out_of_range_rvas() {
+        let mut mem = DummyMemory::new(size::mb(1));
+        let mut view = mem.phys_view();
+
+        // export table entries pointing outside of the export directory and the memory
+        let mut image = test_image();
+        put_u32(&mut image, EXPORT_DIR + 0x20, 0xfff0_0000);
+        write_image(&mut view, &image);
+        let mut pe = LazyPe::new(&mut view, Address::from(BASE)).unwrap();
+        assert!(pe.export("AlphaExport").is_err());
+        assert!(pe.exports().unwrap().is_empty());
+
+        // an export name outside of the memory never matches
+        let mut image = test_image();
+        put_u32(&mut image, EXPORT_DIR + 0x30, 0xfff0_0000);
+        write_image(&mut view, &image);
+        let mut pe = LazyPe::new(&mut view, Address::from(BASE)).unwrap();
+        assert!(pe.export("AlphaExport").is_err());
+        assert_eq!(pe.export("BetaExport").unwrap(), 0x1200);
+
+        // data directories outside of the memory
+        let mut image = test_image();
+        put_u32(&mut image, 0x58 + 0x70, 0xfff0_0000);
+        put_u32(&mut image, 0x58 + 0x70 + 2 * 8, 0xfff0_0000);
+        write_image(&mut view, &image);
+        let mut pe = LazyPe::new(&mut view, Address::from(BASE)).unwrap();
+        assert!(pe.export("AlphaExport").is_err());
+        assert!(pe.resource(RT_RCDATA, None, 0x100).is_err());
+    }
+}
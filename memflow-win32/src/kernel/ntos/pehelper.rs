@@ -9,6 +9,26 @@ use memflow::types::{size, umem, Address};
 
 use pelite::{self, PeView};
 
+use super::lazy_pe::LazyPe;
+
+/// Names the kernel image stores in its export directory.
+///
+/// Kernels prior to Windows Vista keep the name of the build flavor (e.g. `ntkrnlpa.exe` for pae kernels)
+/// even though the image is loaded as `ntoskrnl.exe`.
+const KERNEL_IMAGE_NAMES: [&str; 4] = [
+    "ntoskrnl.exe",
+    "ntkrnlpa.exe",
+    "ntkrnlmp.exe",
+    "ntkrpamp.exe",
+];
+
+/// Returns true if the given export directory name belongs to a kernel image.
+pub fn is_kernel_name(name: &str) -> bool {
+    KERNEL_IMAGE_NAMES
+        .iter()
+        .any(|kernel_name| kernel_name.eq_ignore_ascii_case(name))
+}
+
 pub fn try_get_pe_size<T: MemoryView>(mem: &mut T, probe_addr: Address) -> Result<umem> {
     let mut probe_buf = vec![0; size::kb(4)];
     mem.read_raw_into(probe_addr, &mut probe_buf)?;
@@ -40,25 +60,7 @@ pub fn try_get_pe_image<T: MemoryView>(mem: &mut T, probe_addr: Address) -> Resu
 }
 
 pub fn try_get_pe_name<T: MemoryView>(mem: &mut T, probe_addr: Address) -> Result<String> {
-    let image = try_get_pe_image(mem, probe_addr)?;
-    let pe = PeView::from_bytes(&image)
-        .map_err(|err| Error(ErrorOrigin::OsLayer, ErrorKind::InvalidExeFile).log_trace(err))?;
-    let name = pe
-        .exports()
-        .map_err(|_| {
-            Error(ErrorOrigin::OsLayer, ErrorKind::InvalidExeFile)
-                .log_trace("unable to get exports")
-        })?
-        .dll_name()
-        .map_err(|_| {
-            Error(ErrorOrigin::OsLayer, ErrorKind::InvalidExeFile)
-                .log_trace("unable to get dll name")
-        })?
-        .to_str()
-        .map_err(|_| {
-            Error(ErrorOrigin::OsLayer, ErrorKind::Encoding)
-                .log_trace("unable to convert dll name string")
-        })?;
+    let name = LazyPe::new(mem, probe_addr)?.dll_name()?;
     debug!("try_get_pe_name: found pe header for {}", name);
-    Ok(name.to_string())
+    Ok(name)
 }
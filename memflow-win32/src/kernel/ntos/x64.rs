@@ -1,7 +1,10 @@
 use std::prelude::v1::*;
 
 use super::pehelper;
+use super::NtosScanConfig;
+use crate::kernel::diagnostics::{RejectReason, ScanCounters};
 use crate::kernel::StartBlock;
+use crate::progress::{CancellationToken, Progress};
 
 use log::{debug, trace};
 
@@ -11,25 +14,30 @@ use memflow::dataview::PodMethods;
 use memflow::error::{Error, ErrorKind, ErrorOrigin, PartialResultExt, Result};
 use memflow::iter::PageChunks;
 use memflow::mem::{MemoryView, VirtualTranslate};
-use memflow::types::{mem, size, smem, umem, Address};
+use memflow::types::{smem, umem, Address};
 
 use pelite::image::IMAGE_DOS_HEADER;
 
 pub fn find_with_va_hint<T: MemoryView + VirtualTranslate>(
     virt_mem: &mut T,
     start_block: &StartBlock,
+    config: &NtosScanConfig,
+    counters: &ScanCounters,
 ) -> Result<(Address, umem)> {
     debug!(
         "x64::find_with_va_hint: trying to find ntoskrnl.exe with va hint at {:x}",
         start_block.kernel_hint.to_umem()
     );
 
+    let probe_size = probe_size(config);
+
     // va was found previously
+    let mut buf = vec![];
     let mut va_base = start_block.kernel_hint.to_umem() & !0x0001_ffff;
-    while va_base + mem::mb(16) > start_block.kernel_hint.to_umem() {
+    while va_base + config.hint_window > start_block.kernel_hint.to_umem() {
         trace!("x64::find_with_va_hint: probing at {:x}", va_base);
 
-        match find_with_va(virt_mem, va_base) {
+        match find_with_va(virt_mem, va_base, probe_size, &mut buf, counters) {
             Ok(a) => {
                 let addr = Address::from(a);
                 let size_of_image = pehelper::try_get_pe_size(virt_mem, addr)?;
@@ -38,18 +46,41 @@ pub fn find_with_va_hint<T: MemoryView + VirtualTranslate>(
             Err(e) => trace!("x64::find_with_va_hint: probe error {:?}", e),
         }
 
-        va_base -= mem::mb(2);
+        va_base -= probe_size as umem;
     }
 
     Err(Error(ErrorOrigin::OsLayer, ErrorKind::ProcessNotFound)
         .log_trace("x64::find_with_va_hint: unable to locate ntoskrnl.exe via va hint"))
 }
 
-fn find_with_va<T: MemoryView + VirtualTranslate>(virt_mem: &mut T, va_base: umem) -> Result<umem> {
-    let mut buf = vec![0; size::mb(2)];
-    virt_mem
-        .read_raw_into(Address::from(va_base), &mut buf)
-        .data_part()?;
+/// Returns the probe size of the config rounded up to the page size.
+pub(super) fn probe_size(config: &NtosScanConfig) -> usize {
+    let page_size = x64::ARCH.page_size();
+    ((config.probe_size as usize).max(1) + page_size - 1) / page_size * page_size
+}
+
+/// Searches the chunk of `probe_size` bytes at `va_base` for the headers of ntoskrnl.exe.
+///
+/// `buf` is a scratch buffer that is reused between calls to avoid allocating a chunk for every probe.
+/// Pages that could not be read might contain stale data of a previous chunk, this is fine
+/// as every header candidate is validated against memory again.
+pub(super) fn find_with_va<T: MemoryView + VirtualTranslate>(
+    virt_mem: &mut T,
+    va_base: umem,
+    probe_size: usize,
+    buf: &mut Vec<u8>,
+    counters: &ScanCounters,
+) -> Result<umem> {
+    counters.candidate();
+
+    buf.resize(probe_size, 0);
+    if let Err(err) = virt_mem
+        .read_raw_into(Address::from(va_base), buf)
+        .data_part()
+    {
+        counters.reject(RejectReason::Unreadable);
+        return Err(err);
+    }
 
     buf.chunks_exact(x64::ARCH.page_size())
         .enumerate()
@@ -58,7 +89,13 @@ fn find_with_va<T: MemoryView + VirtualTranslate>(virt_mem: &mut T, va_base: ume
             (i, c, view.read::<IMAGE_DOS_HEADER>(0)) // TODO: potential endian mismatch
         })
         .filter(|(_, _, p)| p.e_magic == 0x5a4d) // MZ
-        .filter(|(_, _, p)| p.e_lfanew <= 0x800)
+        .filter(|(_, _, p)| {
+            let valid = p.e_lfanew <= 0x800;
+            if !valid {
+                counters.reject(RejectReason::InvalidDosHeader);
+            }
+            valid
+        })
         .inspect(|(i, _, _)| {
             trace!(
                 "x64::find_with_va: found potential header flags at offset {:x}",
@@ -67,8 +104,17 @@ fn find_with_va<T: MemoryView + VirtualTranslate>(virt_mem: &mut T, va_base: ume
         })
         .find(|(i, _, _)| {
             let probe_addr = Address::from(va_base + (*i as umem) * x64::ARCH.page_size() as umem);
-            let name = pehelper::try_get_pe_name(virt_mem, probe_addr).unwrap_or_default();
-            name == "ntoskrnl.exe"
+            match pehelper::try_get_pe_name(virt_mem, probe_addr) {
+                Ok(name) if pehelper::is_kernel_name(&name) => true,
+                Ok(_) => {
+                    counters.reject(RejectReason::NameMismatch);
+                    false
+                }
+                Err(_) => {
+                    counters.reject(RejectReason::InvalidPeHeader);
+                    false
+                }
+            }
         })
         .map(|(i, _, _)| va_base + i as umem * x64::ARCH.page_size() as umem)
         .ok_or_else(|| {
@@ -77,26 +123,42 @@ fn find_with_va<T: MemoryView + VirtualTranslate>(virt_mem: &mut T, va_base: ume
         })
 }
 
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(name = "ntos_find_page_map", skip_all, fields(regions))
+)]
 pub fn find<T: MemoryView + VirtualTranslate>(
     virt_mem: &mut T,
     start_block: &StartBlock,
+    config: &NtosScanConfig,
+    progress: Option<&Progress>,
+    cancellation: Option<&CancellationToken>,
+    counters: &ScanCounters,
 ) -> Result<(Address, umem)> {
     debug!("x64::find: trying to find ntoskrnl.exe with page map",);
 
-    let page_map = virt_mem.virt_page_map_range_vec(
-        smem::mb(2),
-        (!0u64 - (1u64 << (ArchitectureObj::from(start_block.arch).address_space_bits() - 1)))
-            .into(),
-        (!0u64).into(),
+    let chunks = kernel_chunks(virt_mem, start_block, config);
+
+    if let Some(progress) = progress {
+        progress.start(
+            "scanning kernel address space for ntoskrnl.exe",
+            chunks.len() as u64,
+        );
+    }
+    let probe_size = probe_size(config);
+    let result = find_in_chunks(
+        virt_mem,
+        &chunks,
+        probe_size,
+        progress,
+        cancellation,
+        counters,
     );
+    if let Some(progress) = progress {
+        progress.finish();
+    }
 
-    match page_map
-        .into_iter()
-        .flat_map(|CTup3(address, size, _)| size.page_chunks(address, size::mb(2)))
-        .filter(|(_, size)| *size > mem::kb(256))
-        .filter_map(|(va, _)| find_with_va(virt_mem, va.to_umem()).ok())
-        .next()
-    {
+    match result? {
         Some(a) => {
             let addr = Address::from(a);
             let size_of_image = pehelper::try_get_pe_size(virt_mem, addr)?;
@@ -106,3 +168,113 @@ pub fn find<T: MemoryView + VirtualTranslate>(
             .log_trace("x64::find: unable to locate ntoskrnl.exe with a page map")),
     }
 }
+
+/// Collects the chunks of the kernel address space that might contain ntoskrnl.exe.
+fn kernel_chunks<T: MemoryView + VirtualTranslate>(
+    virt_mem: &mut T,
+    start_block: &StartBlock,
+    config: &NtosScanConfig,
+) -> Vec<(Address, umem)> {
+    // the kernel half of the address space grows to 57 bits with 5-level paging
+    let address_space_bits = if start_block.la57 {
+        57
+    } else {
+        ArchitectureObj::from(start_block.arch).address_space_bits()
+    };
+    chunks_in_range(
+        virt_mem,
+        !0u64 - (1u64 << (address_space_bits - 1)),
+        !0u64,
+        config,
+    )
+}
+
+/// Collects the chunks of the mapped memory between `start` and `end` that might contain ntoskrnl.exe.
+pub(super) fn chunks_in_range<T: MemoryView + VirtualTranslate>(
+    virt_mem: &mut T,
+    start: u64,
+    end: u64,
+    config: &NtosScanConfig,
+) -> Vec<(Address, umem)> {
+    let page_map = virt_mem.virt_page_map_range_vec(smem::mb(2), start.into(), end.into());
+    #[cfg(feature = "tracing")]
+    tracing::Span::current().record("regions", page_map.len());
+
+    page_map
+        .into_iter()
+        .flat_map(|CTup3(address, size, _)| size.page_chunks(address, probe_size(config)))
+        .filter(|(_, size)| *size > config.min_region_size)
+        .collect::<Vec<_>>()
+}
+
+/// Same as [`find`] but the chunks are distributed over the threads of the rayon thread pool.
+#[cfg(feature = "parallel")]
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(name = "ntos_find_page_map_parallel", skip_all, fields(regions))
+)]
+pub fn find_parallel<T: MemoryView + VirtualTranslate + Clone + Send>(
+    virt_mem: &mut T,
+    start_block: &StartBlock,
+    config: &NtosScanConfig,
+    progress: Option<&Progress>,
+    cancellation: Option<&CancellationToken>,
+    counters: &ScanCounters,
+) -> Result<(Address, umem)> {
+    debug!("x64::find_parallel: trying to find ntoskrnl.exe with page map",);
+
+    let chunks = kernel_chunks(virt_mem, start_block, config);
+
+    if let Some(progress) = progress {
+        progress.start(
+            "scanning kernel address space for ntoskrnl.exe",
+            chunks.len() as u64,
+        );
+    }
+    let result = crate::kernel::parallel::find_first(
+        virt_mem,
+        &chunks,
+        progress,
+        cancellation,
+        |virt_mem, buf, (va, _)| {
+            find_with_va(virt_mem, va.to_umem(), probe_size(config), buf, counters).ok()
+        },
+    );
+    if let Some(progress) = progress {
+        progress.finish();
+    }
+
+    match result? {
+        Some(a) => {
+            let addr = Address::from(a);
+            let size_of_image = pehelper::try_get_pe_size(virt_mem, addr)?;
+            Ok((addr, size_of_image))
+        }
+        None => Err(Error(ErrorOrigin::OsLayer, ErrorKind::ProcessNotFound)
+            .log_trace("x64::find_parallel: unable to locate ntoskrnl.exe with a page map")),
+    }
+}
+
+pub(super) fn find_in_chunks<T: MemoryView + VirtualTranslate>(
+    virt_mem: &mut T,
+    chunks: &[(Address, umem)],
+    probe_size: usize,
+    progress: Option<&Progress>,
+    cancellation: Option<&CancellationToken>,
+    counters: &ScanCounters,
+) -> Result<Option<umem>> {
+    let mut buf = vec![];
+    for (i, (va, _)) in chunks.iter().enumerate() {
+        if let Some(cancellation) = cancellation {
+            cancellation.check()?;
+        }
+        if let Some(progress) = progress {
+            progress.update(i as u64);
+        }
+
+        if let Ok(a) = find_with_va(virt_mem, va.to_umem(), probe_size, &mut buf, counters) {
+            return Ok(Some(a));
+        }
+    }
+    Ok(None)
+}
@@ -1,7 +1,9 @@
 use std::prelude::v1::*;
 
 use super::pehelper;
+use crate::kernel::diagnostics::{RejectReason, ScanCounters};
 use crate::kernel::StartBlock;
+use crate::progress::{CancellationToken, Progress};
 
 use memflow::dataview::PodMethods;
 use memflow::error::{Error, ErrorKind, ErrorOrigin, PartialResultExt, Result};
@@ -17,16 +19,53 @@ const SIZE_8MB: usize = size::mb(8);
 const SIZE_4KB: usize = size::kb(4);
 
 // https://github.com/ufrisk/MemProcFS/blob/f2d15cf4fe4f19cfeea3dad52971fae2e491064b/vmm/vmmwininit.c#L410
-pub fn find<T: MemoryView>(virt_mem: &mut T, _start_block: &StartBlock) -> Result<(Address, umem)> {
+pub fn find<T: MemoryView>(
+    virt_mem: &mut T,
+    _start_block: &StartBlock,
+    progress: Option<&Progress>,
+    cancellation: Option<&CancellationToken>,
+    counters: &ScanCounters,
+) -> Result<(Address, umem)> {
     debug!("x86::find: trying to find ntoskrnl.exe");
 
+    if let Some(progress) = progress {
+        progress.start(
+            "scanning kernel address space for ntoskrnl.exe",
+            SIZE_256MB as u64,
+        );
+    }
+    let result = find_in_range(virt_mem, progress, cancellation, counters);
+    if let Some(progress) = progress {
+        progress.finish();
+    }
+    result
+}
+
+fn find_in_range<T: MemoryView>(
+    virt_mem: &mut T,
+    progress: Option<&Progress>,
+    cancellation: Option<&CancellationToken>,
+    counters: &ScanCounters,
+) -> Result<(Address, umem)> {
+    let mut buf = vec![0; SIZE_8MB];
     for base_addr in (0..SIZE_256MB).step_by(SIZE_8MB) {
+        if let Some(cancellation) = cancellation {
+            cancellation.check()?;
+        }
+        if let Some(progress) = progress {
+            progress.update(base_addr as u64);
+        }
+
         let base_addr = size::gb(2) + base_addr;
         // search in each page in the first 8mb chunks in the first 64mb of virtual memory
-        let mut buf = vec![0; SIZE_8MB];
-        virt_mem
+        counters.candidate();
+        if let Err(err) = virt_mem
             .read_raw_into(base_addr.into(), &mut buf)
-            .data_part()?;
+            .data_part()
+        {
+            counters.reject(RejectReason::Unreadable);
+            return Err(err);
+        }
 
         for addr in (0..SIZE_8MB).step_by(SIZE_4KB) {
             // TODO: potential endian mismatch in pod
@@ -38,18 +77,22 @@ pub fn find<T: MemoryView>(virt_mem: &mut T, _start_block: &StartBlock) -> Resul
             }
 
             if view.read::<IMAGE_DOS_HEADER>(0).e_lfanew > 0x800 {
+                counters.reject(RejectReason::InvalidDosHeader);
                 continue;
             }
 
             let image_base = Address::from(base_addr + addr);
-            if let Ok(name) = pehelper::try_get_pe_name(virt_mem, image_base) {
-                if name == "ntoskrnl.exe" {
+            match pehelper::try_get_pe_name(virt_mem, image_base) {
+                Ok(name) if pehelper::is_kernel_name(&name) => {
                     info!("ntoskrnl found");
                     // TODO: unify pe name + size
-                    if let Ok(size_of_image) = pehelper::try_get_pe_size(virt_mem, image_base) {
-                        return Ok((image_base, size_of_image));
+                    match pehelper::try_get_pe_size(virt_mem, image_base) {
+                        Ok(size_of_image) => return Ok((image_base, size_of_image)),
+                        Err(_) => counters.reject(RejectReason::InvalidPeHeader),
                     }
                 }
+                Ok(_) => counters.reject(RejectReason::NameMismatch),
+                Err(_) => counters.reject(RejectReason::InvalidPeHeader),
             }
         }
     }
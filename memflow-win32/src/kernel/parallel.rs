@@ -0,0 +1,131 @@
+/*!
+Distribution of scans over multiple threads.
+
+The candidates of a scan are partitioned into contiguous ranges, one per thread.
+Every thread reads through its own clone of the memory object, the connector therefore
+has to support concurrent access through clones.
+*/
+use std::prelude::v1::*;
+
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use crate::progress::{CancellationToken, Progress};
+
+use memflow::error::Result;
+
+use rayon::prelude::*;
+
+/// Scans the candidates on all threads of the rayon thread pool and returns the first match.
+///
+/// Matches are returned in the order of the candidates. Once a match has been found all
+/// partitions following it are aborted. The progress is reported as the number of scanned candidates.
+///
+/// Every partition owns a scratch buffer that is handed to all of its scans. It starts out empty,
+/// scans resize it to the length they need so the buffer is only allocated once per partition.
+pub fn find_first<M, C, R, F>(
+    mem: &M,
+    candidates: &[C],
+    progress: Option<&Progress>,
+    cancellation: Option<&CancellationToken>,
+    scan: F,
+) -> Result<Option<R>>
+where
+    M: Clone + Send,
+    C: Sync,
+    R: Send,
+    F: Fn(&mut M, &mut Vec<u8>, &C) -> Option<R> + Sync,
+{
+    let threads = rayon::current_num_threads().max(1);
+    let partition_len = ((candidates.len() + threads - 1) / threads).max(1);
+
+    // index of the first partition that found a match
+    let found = AtomicUsize::new(usize::MAX);
+    let scanned = AtomicU64::new(0);
+
+    let result = candidates
+        .chunks(partition_len)
+        .enumerate()
+        .map(|(i, partition)| (i, mem.clone(), partition))
+        .collect::<Vec<_>>()
+        .into_par_iter()
+        .find_map_first(|(i, mut mem, partition)| {
+            let mut scratch = vec![];
+            for candidate in partition.iter() {
+                if found.load(Ordering::Relaxed) < i
+                    || cancellation.map_or(false, |c| c.is_cancelled())
+                {
+                    return None;
+                }
+
+                let result = scan(&mut mem, &mut scratch, candidate);
+
+                let scanned = scanned.fetch_add(1, Ordering::Relaxed) + 1;
+                if let Some(progress) = progress {
+                    progress.update(scanned);
+                }
+
+                if result.is_some() {
+                    found.fetch_min(i, Ordering::Relaxed);
+                    return result;
+                }
+            }
+            None
+        });
+
+    if let Some(cancellation) = cancellation {
+        cancellation.check()?;
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::thread;
+    use std::time::Duration;
+
+    fn scan_in_pool(candidates: &[u64], matches: &[u64]) -> Option<u64> {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(4)
+            .build()
+            .unwrap();
+        pool.install(|| {
+            find_first(&(), candidates, None, None, |_, _, candidate| {
+                if !matches.contains(candidate) {
+                    return None;
+                }
+                // the lower match finishes last, it still has to win
+                if *candidate == matches[0] {
+                    thread::sleep(Duration::from_millis(50));
+                }
+                Some(*candidate)
+            })
+            .unwrap()
+        })
+    }
+
+    #[test]
+    fn lower_candidate_wins() {
+        let candidates = (0..64).collect::<Vec<u64>>();
+        // 10 and 50 are located in the first and the last of the four partitions
+        assert_eq!(scan_in_pool(&candidates, &[10, 50]), Some(10));
+    }
+
+    #[test]
+    fn no_match() {
+        let candidates = (0..64).collect::<Vec<u64>>();
+        assert_eq!(scan_in_pool(&candidates, &[]), None);
+    }
+
+    #[test]
+    fn cancelled_scan() {
+        let cancellation = CancellationToken::new();
+        cancellation.cancel();
+        let candidates = (0..64).collect::<Vec<u64>>();
+        let result = find_first(&(), &candidates, None, Some(&cancellation), |_, _, c| {
+            Some(*c)
+        });
+        assert!(result.is_err());
+    }
+}
@@ -10,7 +10,21 @@ use log::warn;
 use memflow::architecture::ArchitectureIdent;
 use memflow::error::{Error, ErrorKind, ErrorOrigin, Result};
 use memflow::mem::PhysicalMemory;
-use memflow::types::{size, Address, PhysicalAddress};
+use memflow::types::{mem, size, umem, Address, PhysicalAddress};
+
+use crate::progress::{CancellationToken, Progress};
+
+/// Upper bound of the extended scan for relocated pae page directories
+const PAE_EXTENDED_SCAN_END: umem = mem::mb(64);
+
+#[cfg(feature = "tracing")]
+fn record_bytes(bytes: usize) {
+    tracing::Span::current().record("bytes", bytes);
+}
+
+#[cfg(not(feature = "tracing"))]
+#[inline(always)]
+fn record_bytes(_bytes: usize) {}
 
 // PROCESSOR_START_BLOCK
 #[derive(Debug, Copy, Clone)]
@@ -19,17 +33,47 @@ pub struct StartBlock {
     pub arch: ArchitectureIdent,
     pub kernel_hint: Address,
     pub dtb: Address,
+    /// 5-level paging is enabled (CR4.LA57)
+    pub la57: bool,
+}
+
+/// Physical memory layout of aarch64 targets
+///
+/// The low stub of aarch64 targets is not located at physical address 0.
+/// Page tables referencing memory above `max_mem` are rejected while searching the dtb.
+#[derive(Debug, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize))]
+pub struct AArch64Layout {
+    /// Start of physical memory
+    pub phys_base: Address,
+    /// Upper bound of the physical memory of the target
+    pub max_mem: umem,
+}
+
+impl Default for AArch64Layout {
+    fn default() -> Self {
+        Self {
+            phys_base: Address::from(aarch64::PHYS_BASE),
+            max_mem: aarch64::MAX_MEM,
+        }
+    }
 }
 
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(name = "start_block_find_fallback", skip(mem), fields(bytes))
+)]
 pub fn find_fallback<T: PhysicalMemory>(
     mem: &mut T,
     arch: ArchitectureIdent,
+    layout: &AArch64Layout,
 ) -> Result<StartBlock> {
     match arch {
         ArchitectureIdent::X86(64, _) => {
             // read low 16mb stub
             let mut low16m = vec![0; size::mb(16)];
             mem.phys_read_into(PhysicalAddress::NULL, low16m.as_mut_slice())?;
+            record_bytes(low16m.len());
 
             x64::find(&low16m)
         }
@@ -37,24 +81,259 @@ pub fn find_fallback<T: PhysicalMemory>(
             // read low 16mb stub
             let mut low16m = vec![0; size::mb(16)];
 
-            //TODO: configure this, but so far arm null starts at this address
-            mem.phys_read_into(aarch64::PHYS_BASE.into(), low16m.as_mut_slice())?;
+            mem.phys_read_into(layout.phys_base.into(), low16m.as_mut_slice())?;
+            record_bytes(low16m.len());
 
-            aarch64::find(&low16m)
+            aarch64::find(&low16m, layout)
         }
         _ => Err(Error(ErrorOrigin::OsLayer, ErrorKind::NotImplemented)
             .log_error("start_block: fallback not implemented for given arch")),
     }
 }
 
+/// Scans all of physical memory for a self referencing page table.
+///
+/// This is significantly slower than [`find`] and [`find_fallback`] and is only meant
+/// as a last resort for targets with relocated low memory or unusual boot paths.
+/// Regions that cannot be read (e.g. holes in the memory map of the connector) are skipped.
+///
+/// The progress is reported in bytes of physical memory and the cancellation token is checked after every chunk.
+pub fn find_exhaustive<T: PhysicalMemory>(
+    mem: &mut T,
+    arch: ArchitectureIdent,
+    layout: &AArch64Layout,
+    progress: Option<&Progress>,
+    cancellation: Option<&CancellationToken>,
+) -> Result<StartBlock> {
+    find_exhaustive_from(mem, arch, layout, Address::null(), progress, cancellation)
+}
+
+/// Same as [`find_exhaustive`] but the scan starts at the physical address `start`.
+///
+/// This is used to continue the scan after the start block that was found
+/// has been rejected (e.g. because ntoskrnl.exe could not be located with its dtb).
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(
+        name = "start_block_find_exhaustive",
+        skip(mem, progress, cancellation),
+        fields(bytes)
+    )
+)]
+pub fn find_exhaustive_from<T: PhysicalMemory>(
+    mem: &mut T,
+    arch: ArchitectureIdent,
+    layout: &AArch64Layout,
+    start: Address,
+    progress: Option<&Progress>,
+    cancellation: Option<&CancellationToken>,
+) -> Result<StartBlock> {
+    let (base, max_address) = exhaustive_range(mem, arch, layout, start);
+
+    if let Some(progress) = progress {
+        progress.start("scanning physical memory for a dtb", max_address as u64);
+    }
+    let result = scan_exhaustive(mem, arch, layout, base, max_address, progress, cancellation);
+    if let Some(progress) = progress {
+        progress.finish();
+    }
+    result
+}
+
+fn scan_exhaustive<T: PhysicalMemory>(
+    mem: &mut T,
+    arch: ArchitectureIdent,
+    layout: &AArch64Layout,
+    mut base: umem,
+    max_address: umem,
+    progress: Option<&Progress>,
+    cancellation: Option<&CancellationToken>,
+) -> Result<StartBlock> {
+    let mut chunk = vec![0; size::mb(2)];
+    let mut bytes = 0;
+    while base < max_address {
+        if let Some(cancellation) = cancellation {
+            cancellation.check()?;
+        }
+        if let Some(progress) = progress {
+            progress.update(base as u64);
+        }
+
+        if mem
+            .phys_read_into(PhysicalAddress::from(base), chunk.as_mut_slice())
+            .is_ok()
+        {
+            bytes += chunk.len();
+
+            if let Ok(sb) = find_in_chunk(&chunk, Address::from(base), arch, layout)? {
+                record_bytes(bytes);
+                return Ok(sb);
+            }
+        }
+
+        base += chunk.len() as umem;
+    }
+
+    record_bytes(bytes);
+    Err(Error(ErrorOrigin::OsLayer, ErrorKind::NotFound)
+        .log_warn("start_block: unable to find dtb in physical memory"))
+}
+
+/// Same as [`find_exhaustive`] but the chunks are distributed over the threads of the rayon thread pool.
+///
+/// Every thread reads through its own clone of `mem`.
+/// The progress is reported in scanned chunks of 2mb.
+#[cfg(feature = "parallel")]
+pub fn find_exhaustive_parallel<T: PhysicalMemory + Clone + Send>(
+    mem: &mut T,
+    arch: ArchitectureIdent,
+    layout: &AArch64Layout,
+    progress: Option<&Progress>,
+    cancellation: Option<&CancellationToken>,
+) -> Result<StartBlock> {
+    find_exhaustive_parallel_from(mem, arch, layout, Address::null(), progress, cancellation)
+}
+
+/// Same as [`find_exhaustive_parallel`] but the scan starts at the physical address `start`.
+#[cfg(feature = "parallel")]
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(
+        name = "start_block_find_exhaustive_parallel",
+        skip(mem, progress, cancellation)
+    )
+)]
+pub fn find_exhaustive_parallel_from<T: PhysicalMemory + Clone + Send>(
+    mem: &mut T,
+    arch: ArchitectureIdent,
+    layout: &AArch64Layout,
+    start: Address,
+    progress: Option<&Progress>,
+    cancellation: Option<&CancellationToken>,
+) -> Result<StartBlock> {
+    let (base, max_address) = exhaustive_range(mem, arch, layout, start);
+
+    // fail early for unsupported architectures instead of in every thread
+    if !matches!(
+        arch,
+        ArchitectureIdent::X86(64, _)
+            | ArchitectureIdent::X86(32, _)
+            | ArchitectureIdent::AArch64(_)
+    ) {
+        return Err(Error(ErrorOrigin::OsLayer, ErrorKind::NotSupported)
+            .log_error("Unsupported architecture"));
+    }
+
+    let chunks = (base..max_address).step_by(size::mb(2)).collect::<Vec<_>>();
+
+    if let Some(progress) = progress {
+        progress.start("scanning physical memory for a dtb", chunks.len() as u64);
+    }
+    let result =
+        super::parallel::find_first(mem, &chunks, progress, cancellation, |mem, chunk, &base| {
+            chunk.resize(size::mb(2), 0);
+            mem.phys_read_into(PhysicalAddress::from(base), chunk.as_mut_slice())
+                .ok()?;
+            find_in_chunk(chunk, Address::from(base), arch, layout)
+                .ok()?
+                .ok()
+        });
+    if let Some(progress) = progress {
+        progress.finish();
+    }
+
+    result?.ok_or_else(|| {
+        Error(ErrorOrigin::OsLayer, ErrorKind::NotFound)
+            .log_warn("start_block: unable to find dtb in physical memory")
+    })
+}
+
+/// Returns the range of physical memory that is scanned by [`find_exhaustive_from`].
+///
+/// The start of the range is rounded up to the next page.
+fn exhaustive_range<T: PhysicalMemory>(
+    mem: &mut T,
+    arch: ArchitectureIdent,
+    layout: &AArch64Layout,
+    start: Address,
+) -> (umem, umem) {
+    let mut max_address = mem.metadata().max_address.to_umem();
+    let mut base = 0;
+    match arch {
+        ArchitectureIdent::X86(32, false) => max_address = max_address.min(mem::gb(4)),
+        ArchitectureIdent::AArch64(_) => base = layout.phys_base.to_umem(),
+        _ => {}
+    }
+    let start = (start.to_umem() + 0xfff) & !0xfff;
+    (base.max(start), max_address)
+}
+
+/// Searches a chunk of physical memory at the given address for a start block.
+///
+/// The outer result fails if the architecture is not supported.
+fn find_in_chunk(
+    chunk: &[u8],
+    addr: Address,
+    arch: ArchitectureIdent,
+    layout: &AArch64Layout,
+) -> Result<Result<StartBlock>> {
+    match arch {
+        ArchitectureIdent::X86(64, _) => Ok(x64::find_at(chunk, addr)),
+        ArchitectureIdent::X86(32, true) => Ok(x86pae::find_at(chunk, addr)),
+        ArchitectureIdent::X86(32, false) => Ok(x86::find_at(chunk, addr)),
+        ArchitectureIdent::AArch64(_) => Ok(aarch64::find_at(chunk, addr, layout)),
+        _ => Err(Error(ErrorOrigin::OsLayer, ErrorKind::NotSupported)
+            .log_error("Unsupported architecture")),
+    }
+}
+
+/// Searches the memory above the low 16mb for relocated pae page directories.
+fn find_pae_extended<T: PhysicalMemory>(mem: &mut T) -> Result<StartBlock> {
+    let mut chunk = vec![0; size::mb(16)];
+    let max_address = mem
+        .metadata()
+        .max_address
+        .to_umem()
+        .min(PAE_EXTENDED_SCAN_END);
+    let mut base = mem::mb(16);
+    // the low 16mb have already been read
+    let mut bytes = size::mb(16);
+    while base < max_address {
+        if mem
+            .phys_read_into(PhysicalAddress::from(base), chunk.as_mut_slice())
+            .is_ok()
+        {
+            bytes += chunk.len();
+            if let Ok(sb) = x86pae::find_at(&chunk, Address::from(base)) {
+                record_bytes(bytes);
+                return Ok(sb);
+            }
+        }
+        base += chunk.len() as umem;
+    }
+
+    record_bytes(bytes);
+    Err(Error(ErrorOrigin::OsLayer, ErrorKind::NotFound)
+        .log_warn("start_block: unable to find x86_pae dtb below 64mb"))
+}
+
 // bcdedit /set firstmegabytepolicyuseall
-pub fn find<T: PhysicalMemory>(mem: &mut T, arch: Option<ArchitectureIdent>) -> Result<StartBlock> {
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(name = "start_block_find", skip(mem), fields(bytes))
+)]
+pub fn find<T: PhysicalMemory>(
+    mem: &mut T,
+    arch: Option<ArchitectureIdent>,
+    layout: &AArch64Layout,
+) -> Result<StartBlock> {
     if let Some(arch) = arch {
         match arch {
             ArchitectureIdent::X86(64, _) => {
                 // read low 1mb stub
                 let mut low1m = vec![0; size::mb(1)];
                 mem.phys_read_into(PhysicalAddress::NULL, low1m.as_mut_slice())?;
+                record_bytes(low1m.len());
 
                 // find x64 dtb in low stub < 1M
                 match x64::find_lowstub(&low1m) {
@@ -66,27 +345,35 @@ pub fn find<T: PhysicalMemory>(mem: &mut T, arch: Option<ArchitectureIdent>) ->
                     Err(e) => warn!("x64::find_lowstub() error: {}", e),
                 }
 
-                find_fallback(mem, arch)
+                find_fallback(mem, arch, layout)
             }
             ArchitectureIdent::X86(32, true) => {
                 let mut low16m = vec![0; size::mb(16)];
                 mem.phys_read_into(PhysicalAddress::NULL, low16m.as_mut_slice())?;
-                x86pae::find(&low16m)
+                record_bytes(low16m.len());
+                match x86pae::find(&low16m) {
+                    Ok(sb) => Ok(sb),
+                    Err(e) => {
+                        warn!("x86pae::find() error: {}", e);
+                        find_pae_extended(mem)
+                    }
+                }
             }
             ArchitectureIdent::X86(32, false) => {
                 let mut low16m = vec![0; size::mb(16)];
                 mem.phys_read_into(PhysicalAddress::NULL, low16m.as_mut_slice())?;
+                record_bytes(low16m.len());
                 x86::find(&low16m)
             }
-            ArchitectureIdent::AArch64(_) => find_fallback(mem, arch),
+            ArchitectureIdent::AArch64(_) => find_fallback(mem, arch, layout),
             _ => Err(Error(ErrorOrigin::OsLayer, ErrorKind::NotSupported)
                 .log_error("Unsupported architecture")),
         }
     } else {
-        find(mem, Some(ArchitectureIdent::X86(64, false)))
-            .or_else(|_| find(mem, Some(ArchitectureIdent::X86(32, true))))
-            .or_else(|_| find(mem, Some(ArchitectureIdent::X86(32, false))))
-            .or_else(|_| find(mem, Some(ArchitectureIdent::AArch64(size::kb(4)))))
+        find(mem, Some(ArchitectureIdent::X86(64, false)), layout)
+            .or_else(|_| find(mem, Some(ArchitectureIdent::X86(32, true)), layout))
+            .or_else(|_| find(mem, Some(ArchitectureIdent::X86(32, false)), layout))
+            .or_else(|_| find(mem, Some(ArchitectureIdent::AArch64(size::kb(4))), layout))
             .map_err(|_| {
                 Error(ErrorOrigin::OsLayer, ErrorKind::NotFound).log_error("unable to find dtb")
             })
@@ -1,3 +1,4 @@
+use super::AArch64Layout;
 use crate::kernel::StartBlock;
 
 use std::convert::TryInto;
@@ -8,12 +9,12 @@ use memflow::types::{mem, umem, Address};
 
 #[allow(clippy::unnecessary_cast)]
 pub const PHYS_BASE: u64 = mem::gb(1) as u64;
+pub const MAX_MEM: umem = mem::gb(512);
 
 // mem here has to be a single page (4kb sized)
-fn find_pt(addr: Address, mem: &[u8]) -> Option<Address> {
-    // TODO: global define / config setting
+fn find_pt(addr: Address, mem: &[u8], max_mem: umem) -> Option<Address> {
     #[allow(clippy::unnecessary_cast)]
-    let max_mem = mem::gb(512) as u64;
+    let max_mem = max_mem as u64;
 
     let pte = u64::from_le_bytes(mem[0..8].try_into().unwrap());
 
@@ -40,19 +41,26 @@ fn find_pt(addr: Address, mem: &[u8]) -> Option<Address> {
     Some(addr)
 }
 
-pub fn find(mem: &[u8]) -> Result<StartBlock> {
+pub fn find(mem: &[u8], layout: &AArch64Layout) -> Result<StartBlock> {
+    find_at(mem, layout.phys_base, layout)
+}
+
+/// Searches the given memory which starts at the physical address `base`.
+pub fn find_at(mem: &[u8], base: Address, layout: &AArch64Layout) -> Result<StartBlock> {
     mem.chunks_exact(aarch64::ARCH.page_size())
         .enumerate()
         .filter_map(|(i, c)| {
             find_pt(
-                Address::from(PHYS_BASE) + (i as umem * aarch64::ARCH.page_size() as umem),
+                base + (i as umem * aarch64::ARCH.page_size() as umem),
                 c,
+                layout.max_mem,
             )
         })
         .map(|addr| StartBlock {
             arch: aarch64::ARCH.ident(),
             kernel_hint: Address::NULL,
             dtb: addr,
+            la57: false,
         })
         .next()
         .ok_or_else(|| {
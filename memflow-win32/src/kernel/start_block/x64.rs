@@ -6,6 +6,8 @@ use memflow::architecture::x86::x64;
 use memflow::error::{Error, ErrorKind, ErrorOrigin, Result};
 use memflow::types::{mem, umem, Address};
 
+const CR4_LA57: u64 = 1 << 12;
+
 // https://github.com/ufrisk/MemProcFS/blob/f2d15cf4fe4f19cfeea3dad52971fae2e491064b/vmm/vmmwininit.c#L560
 pub fn find_lowstub(stub: &[u8]) -> Result<StartBlock> {
     stub.chunks_exact(x64::ARCH.page_size())
@@ -15,8 +17,9 @@ pub fn find_lowstub(stub: &[u8]) -> Result<StartBlock> {
                 == 0x0000_0001_0006_00E9
         }) // start bytes
         .filter(|c| {
-            (0xffff_f800_0000_0003 & u64::from_le_bytes(c[0x70..0x70 + 8].try_into().unwrap()))
-                == 0xffff_f800_0000_0000
+            let entry = u64::from_le_bytes(c[0x70..0x70 + 8].try_into().unwrap());
+            (0xffff_f800_0000_0003 & entry) == 0xffff_f800_0000_0000
+                || (is_la57(c) && (0xff80_0000_0000_0003 & entry) == 0xff80_0000_0000_0000)
         }) // kernel entry
         .find(|c| {
             (0xffff_ff00_0000_0fff & u64::from_le_bytes(c[0xa0..0xa0 + 8].try_into().unwrap())) == 0
@@ -25,6 +28,7 @@ pub fn find_lowstub(stub: &[u8]) -> Result<StartBlock> {
             arch: x64::ARCH.ident(),
             kernel_hint: u64::from_le_bytes(c[0x70..0x70 + 8].try_into().unwrap()).into(),
             dtb: u64::from_le_bytes(c[0xa0..0xa0 + 8].try_into().unwrap()).into(),
+            la57: is_la57(c),
         })
         .ok_or_else(|| {
             Error(ErrorOrigin::OsLayer, ErrorKind::NotFound)
@@ -32,6 +36,11 @@ pub fn find_lowstub(stub: &[u8]) -> Result<StartBlock> {
         })
 }
 
+// cr4 is stored right after cr3 in the special registers of the processor state
+fn is_la57(stub: &[u8]) -> bool {
+    u64::from_le_bytes(stub[0xa8..0xa8 + 8].try_into().unwrap()) & CR4_LA57 != 0
+}
+
 fn find_pt(addr: Address, mem: &[u8]) -> Option<Address> {
     // TODO: global define / config setting
     #[allow(clippy::unnecessary_cast)]
@@ -63,13 +72,19 @@ fn find_pt(addr: Address, mem: &[u8]) -> Option<Address> {
 }
 
 pub fn find(mem: &[u8]) -> Result<StartBlock> {
+    find_at(mem, Address::NULL)
+}
+
+/// Searches the given memory which starts at the physical address `base`.
+pub fn find_at(mem: &[u8], base: Address) -> Result<StartBlock> {
     mem.chunks_exact(x64::ARCH.page_size())
         .enumerate()
-        .filter_map(|(i, c)| find_pt((i as umem * x64::ARCH.page_size() as umem).into(), c))
+        .filter_map(|(i, c)| find_pt(base + (i as umem * x64::ARCH.page_size() as umem), c))
         .map(|addr| StartBlock {
             arch: x64::ARCH.ident(),
             kernel_hint: Address::NULL,
             dtb: addr,
+            la57: false,
         })
         .next()
         .ok_or_else(|| {
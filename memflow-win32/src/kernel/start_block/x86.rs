@@ -26,12 +26,18 @@ fn check_page(base: Address, mem: &[u8]) -> bool {
 }
 
 pub fn find(mem: &[u8]) -> Result<StartBlock> {
-    mem.page_chunks(Address::NULL, x32::ARCH.page_size())
+    find_at(mem, Address::NULL)
+}
+
+/// Searches the given memory which starts at the physical address `base`.
+pub fn find_at(mem: &[u8], base: Address) -> Result<StartBlock> {
+    mem.page_chunks(base, x32::ARCH.page_size())
         .find(|(a, c)| check_page(*a, c))
         .map(|(a, _)| StartBlock {
             arch: x32::ARCH.ident(),
             kernel_hint: Address::NULL,
             dtb: a,
+            la57: false,
         })
         .ok_or_else(|| {
             Error(ErrorOrigin::OsLayer, ErrorKind::NotFound)
@@ -5,7 +5,11 @@ use std::convert::TryInto;
 use memflow::architecture::x86::x32_pae;
 use memflow::error::{Error, ErrorKind, ErrorOrigin, Result};
 use memflow::iter::PageChunks;
-use memflow::types::Address;
+use memflow::types::{umem, Address};
+
+// a pdpt contains 4 entries and is 32 byte aligned
+const PDPT_SIZE: usize = 0x20;
+const PDPTE_ADDRESS_MASK: u64 = 0x000f_ffff_ffff_f000;
 
 #[allow(clippy::unnecessary_cast)]
 fn check_page(addr: Address, mem: &[u8]) -> bool {
@@ -20,16 +24,63 @@ fn check_page(addr: Address, mem: &[u8]) -> bool {
     true
 }
 
+/// Checks a pdpt whose page directories are not located directly behind it.
+///
+/// The pdpt entries only have the present bit set and reference distinct page directories.
+/// The last page directory maps all page directories at 0xC0000000 which is validated
+/// if it is located inside of the scanned memory.
+fn check_pdpt(pdpt: &[u8], mem: &[u8], base: Address) -> bool {
+    let mut directories = [0u64; 4];
+    for (i, chunk) in pdpt.chunks_exact(8).enumerate() {
+        let entry = u64::from_le_bytes(chunk.try_into().unwrap());
+        if entry & !PDPTE_ADDRESS_MASK != 0x1
+            || directories[..i].contains(&(entry & PDPTE_ADDRESS_MASK))
+        {
+            return false;
+        }
+        directories[i] = entry & PDPTE_ADDRESS_MASK;
+    }
+
+    #[allow(clippy::unnecessary_cast)]
+    let last_directory = (directories[3] as umem)
+        .checked_sub(base.to_umem())
+        .and_then(|offset| mem.get(offset as usize..offset as usize + PDPT_SIZE));
+    let last_directory = match last_directory {
+        Some(last_directory) => last_directory,
+        None => return false,
+    };
+    last_directory
+        .chunks_exact(8)
+        .map(|c| u64::from_le_bytes(c.try_into().unwrap()))
+        .zip(directories.iter())
+        .all(|(pde, &directory)| pde & 0x1 != 0 && pde & PDPTE_ADDRESS_MASK == directory)
+}
+
 pub fn find(mem: &[u8]) -> Result<StartBlock> {
-    mem.page_chunks(Address::NULL, x32_pae::ARCH.page_size())
+    find_at(mem, Address::NULL)
+}
+
+/// Searches the given memory which starts at the physical address `base`.
+///
+/// Pdpts that are directly followed by their page directories are preferred. Otherwise every
+/// 32 byte aligned pdpt is checked for page directories that map themselves.
+pub fn find_at(mem: &[u8], base: Address) -> Result<StartBlock> {
+    mem.page_chunks(base, x32_pae::ARCH.page_size())
         .find(|(a, c)| check_page(*a, c))
-        .map(|(a, _)| StartBlock {
+        .map(|(a, _)| a)
+        .or_else(|| {
+            mem.chunks_exact(PDPT_SIZE)
+                .enumerate()
+                .find(|(_, c)| check_pdpt(c, mem, base))
+                .map(|(i, _)| base + i * PDPT_SIZE)
+        })
+        .map(|a| StartBlock {
             arch: x32_pae::ARCH.ident(),
             kernel_hint: Address::NULL,
             dtb: a,
+            la57: false,
         })
         .ok_or_else(|| {
-            Error(ErrorOrigin::OsLayer, ErrorKind::NotFound)
-                .log_warn("unable to find x86_pae dtb in lowstub < 16M")
+            Error(ErrorOrigin::OsLayer, ErrorKind::NotFound).log_warn("unable to find x86_pae dtb")
         })
 }
@@ -1,23 +1,22 @@
 use std::prelude::v1::*;
 
-use super::ntos::pehelper;
-use super::StartBlock;
+use super::ntos::{headerless, lazy_pe::LazyPe, pehelper};
+use super::{kdbg, StartBlock};
 
 use std::convert::TryInto;
 
 use log::{debug, info, warn};
 
 use memflow::architecture::ArchitectureObj;
-use memflow::error::{Error, ErrorKind, ErrorOrigin, Result};
+use memflow::error::{Error, ErrorKind, ErrorOrigin, PartialResultExt, Result};
 use memflow::mem::MemoryView;
 use memflow::types::{size, umem, Address};
 
-use pelite::{self, pe64::exports::Export, PeView};
-
 pub fn find<T: MemoryView>(
     virt_mem: &mut T,
     start_block: &StartBlock,
     ntos: Address,
+    ntos_size: umem,
 ) -> Result<Address> {
     debug!("trying to find system eprocess");
 
@@ -26,11 +25,21 @@ pub fn find<T: MemoryView>(
         Err(e) => warn!("{}", e),
     }
 
+    match find_exported_headerless(virt_mem, start_block, ntos, ntos_size) {
+        Ok(e) => return Ok(e),
+        Err(e) => warn!("{}", e),
+    }
+
     match find_in_section(virt_mem, start_block, ntos) {
         Ok(e) => return Ok(e),
         Err(e) => warn!("{}", e),
     }
 
+    match find_kdbg(virt_mem, start_block, ntos) {
+        Ok(e) => return Ok(e),
+        Err(e) => warn!("{}", e),
+    }
+
     Err(Error(ErrorOrigin::OsLayer, ErrorKind::NotFound).log_info("unable to find system eprocess"))
 }
 
@@ -41,22 +50,32 @@ pub fn find_exported<T: MemoryView>(
     kernel_base: Address,
 ) -> Result<Address> {
     // PsInitialSystemProcess -> PsActiveProcessHead
-    let image = pehelper::try_get_pe_image(virt_mem, kernel_base)?;
-    let pe = PeView::from_bytes(&image)
-        .map_err(|err| Error(ErrorOrigin::OsLayer, ErrorKind::InvalidExeFile).log_info(err))?;
-
-    let sys_proc = match pe
-        .get_export_by_name("PsInitialSystemProcess")
-        .map_err(|err| Error(ErrorOrigin::OsLayer, ErrorKind::ExportNotFound).log_info(err))?
-    {
-        Export::Symbol(s) => kernel_base + *s as umem,
-        Export::Forward(_) => {
-            return Err(Error(ErrorOrigin::OsLayer, ErrorKind::ExportNotFound)
-                .log_info("PsInitialSystemProcess found but it was a forwarded export"))
-        }
-    };
+    let sys_proc =
+        kernel_base + LazyPe::new(virt_mem, kernel_base)?.export("PsInitialSystemProcess")?;
+    info!("PsInitialSystemProcess found at 0x{:x}", sys_proc);
+
+    read_sys_proc(virt_mem, start_block, sys_proc)
+}
+
+// find from exported symbol if the pe headers of the kernel have been wiped
+pub fn find_exported_headerless<T: MemoryView>(
+    virt_mem: &mut T,
+    start_block: &StartBlock,
+    kernel_base: Address,
+    kernel_size: umem,
+) -> Result<Address> {
+    let sys_proc =
+        headerless::find_export(virt_mem, kernel_base, kernel_size, "PsInitialSystemProcess")?;
     info!("PsInitialSystemProcess found at 0x{:x}", sys_proc);
 
+    read_sys_proc(virt_mem, start_block, sys_proc)
+}
+
+fn read_sys_proc<T: MemoryView>(
+    virt_mem: &mut T,
+    start_block: &StartBlock,
+    sys_proc: Address,
+) -> Result<Address> {
     let arch_obj: ArchitectureObj = start_block.arch.into();
 
     // read containing value
@@ -75,6 +94,51 @@ pub fn find_exported<T: MemoryView>(
     Ok(sys_proc_addr)
 }
 
+// find from the process list head in the kernel debugger data block
+pub fn find_kdbg<T: MemoryView>(
+    virt_mem: &mut T,
+    start_block: &StartBlock,
+    ntos: Address,
+) -> Result<Address> {
+    let size = pehelper::try_get_pe_size(virt_mem, ntos)?;
+    let kdbg = kdbg::find(virt_mem, ntos, size)?;
+
+    let arch_obj: ArchitectureObj = start_block.arch.into();
+    let mut buf = vec![0u8; arch_obj.size_addr()];
+    virt_mem.read_raw_into(kdbg.ps_active_process_head, &mut buf)?;
+    let flink: Address = match arch_obj.bits() {
+        64 => u64::from_le_bytes(buf[0..8].try_into().unwrap()).into(),
+        32 => u32::from_le_bytes(buf[0..4].try_into().unwrap()).into(),
+        _ => return Err(Error(ErrorOrigin::OsLayer, ErrorKind::InvalidArchitecture)),
+    };
+
+    // the offset of ActiveProcessLinks is unknown at this point. The eprocess starts with the
+    // dispatcher header of the kprocess (type ProcessObject) followed by the DirectoryTableBase.
+    let dtb_offset = if arch_obj.bits() == 64 { 0x28 } else { 0x18 };
+    let scan_base = flink - size::kb(4);
+    let mut eprocess = vec![0u8; size::kb(4)];
+    virt_mem
+        .read_raw_into(scan_base, &mut eprocess)
+        .data_part()?;
+
+    let dtb = start_block.dtb.to_umem() & !0xfff;
+    (0..size::kb(4) - dtb_offset - arch_obj.size_addr())
+        .step_by(8)
+        .find(|&offset| {
+            let bytes = &eprocess[offset + dtb_offset..offset + dtb_offset + arch_obj.size_addr()];
+            let value = match arch_obj.bits() {
+                64 => u64::from_le_bytes(bytes.try_into().unwrap()) as umem,
+                _ => u32::from_le_bytes(bytes.try_into().unwrap()) as umem,
+            };
+            eprocess[offset] == 3 && value & !0xfff == dtb
+        })
+        .map(|offset| scan_base + offset)
+        .ok_or_else(|| {
+            Error(ErrorOrigin::OsLayer, ErrorKind::NotFound)
+                .log_info("unable to find system eprocess from the kernel debugger data block")
+        })
+}
+
 // TODO: scan in pdb
 
 // scan in section
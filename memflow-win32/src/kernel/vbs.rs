@@ -0,0 +1,120 @@
+/*!
+Detection of virtualization based security (VBS).
+
+If VBS is enabled the secure kernel (`securekernel.exe`) runs in the virtual trust level 1 (VTL1)
+next to the normal kernel. Memory that belongs to VTL1 or that is protected through the
+second level address translation (SLAT) of the hypervisor is not accessible from the normal
+kernel and usually reads as zeros through most connectors.
+
+The state is derived from two sources:
+- the `KeIsVbsEnabled` accessor which loads the global flag of the kernel
+- the loaded kernel modules which contain the secure kernel on some systems
+*/
+use std::prelude::v1::*;
+
+use super::ntos::lazy_pe::LazyPe;
+
+use std::convert::TryInto;
+
+use log::{debug, info};
+
+use memflow::architecture::ArchitectureIdent;
+use memflow::error::PartialResultExt;
+use memflow::mem::MemoryView;
+use memflow::types::{umem, Address};
+
+/// Number of bytes of `KeIsVbsEnabled` that are searched for the global flag
+const CODE_SCAN_LEN: usize = 0x20;
+
+const SECURE_KERNEL_NAME: &str = "securekernel.exe";
+
+/// Virtualization based security state of the kernel
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize))]
+pub struct Win32VbsInfo {
+    /// Value of the global vbs flag, `None` if it could not be resolved
+    pub vbs_enabled: Option<bool>,
+    /// The secure kernel is present in the loaded module list
+    pub secure_kernel_loaded: bool,
+}
+
+impl Win32VbsInfo {
+    /// Returns true if any source indicates that vbs is active.
+    ///
+    /// Reads of pages that are protected by the hypervisor might return zeros in this case.
+    pub fn is_active(&self) -> bool {
+        self.vbs_enabled.unwrap_or(false) || self.secure_kernel_loaded
+    }
+}
+
+/// Detects whether virtualization based security is enabled on the target.
+///
+/// `module_names` are the names of the loaded kernel modules, see [`super::ntos::find_module_names`].
+/// Errors while reading the kernel are not fatal and leave the corresponding fields unset.
+pub fn find<T: MemoryView>(
+    virt_mem: &mut T,
+    arch: ArchitectureIdent,
+    kernel_base: Address,
+    module_names: &[String],
+) -> Win32VbsInfo {
+    let secure_kernel_loaded = module_names
+        .iter()
+        .any(|name| name.eq_ignore_ascii_case(SECURE_KERNEL_NAME));
+
+    let vbs_enabled = find_vbs_flag(virt_mem, arch, kernel_base);
+
+    let info = Win32VbsInfo {
+        vbs_enabled,
+        secure_kernel_loaded,
+    };
+    info!("vbs={:?}", info);
+    info
+}
+
+/// Decodes the global flag loaded by `KeIsVbsEnabled` and reads it.
+///
+/// Only the export directory and the code of the accessor are read from the kernel image.
+fn find_vbs_flag<T: MemoryView>(
+    virt_mem: &mut T,
+    arch: ArchitectureIdent,
+    kernel_base: Address,
+) -> Option<bool> {
+    let mut pe = LazyPe::new(virt_mem, kernel_base).ok()?;
+    let rva = pe.export("KeIsVbsEnabled").ok()? as usize;
+    let code = pe.read_rva(rva as u32, CODE_SCAN_LEN).ok()?;
+
+    let flag = match arch {
+        // movzx eax, byte ptr [rip+disp32] / mov al, [rip+disp32] / mov eax, [rip+disp32]
+        ArchitectureIdent::X86(64, _) => [&[0x0f, 0xb6, 0x05][..], &[0x8a, 0x05], &[0x8b, 0x05]]
+            .iter()
+            .find_map(|pattern| {
+                let pos = code.windows(pattern.len()).position(|w| w == *pattern)?;
+                let disp_pos = pos + pattern.len();
+                let disp = i32::from_le_bytes(code.get(disp_pos..disp_pos + 4)?.try_into().ok()?);
+                // the displacement is relative to the end of the instruction
+                let next = (rva + disp_pos + 4) as i64;
+                Some(kernel_base + (next + disp as i64) as umem)
+            }),
+        // mov al, [abs32] / mov eax, [abs32]
+        ArchitectureIdent::X86(32, _) => code
+            .iter()
+            .position(|&b| b == 0xa0 || b == 0xa1)
+            .and_then(|pos| code.get(pos + 1..pos + 5))
+            .map(|abs| Address::from(u32::from_le_bytes(abs.try_into().unwrap()))),
+        _ => None,
+    };
+
+    let flag = match flag {
+        Some(flag) => flag,
+        None => {
+            debug!("KeIsVbsEnabled could not be decoded");
+            return None;
+        }
+    };
+
+    virt_mem
+        .read::<u8>(flag)
+        .data_part()
+        .ok()
+        .map(|enabled| enabled != 0)
+}
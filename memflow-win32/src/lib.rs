@@ -10,6 +10,8 @@ pub mod kernel;
 
 pub mod offsets;
 
+pub use memflow_win32_defs::progress;
+
 pub mod win32;
 
 pub mod prelude {
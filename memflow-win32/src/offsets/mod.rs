@@ -35,6 +35,11 @@ pub fn offset_builder<'a>() -> Win32OffsetBuilder<'a> {
 pub fn offset_builder_with_kernel_info<'a>(
     kernel_info: &Win32KernelInfo,
 ) -> Win32OffsetBuilder<'a> {
-    let builder = offset_builder();
+    // the embedded offsets only cover windows kernels
+    let builder = if kernel_info.reactos {
+        Win32Offsets::builder()
+    } else {
+        offset_builder()
+    };
     kernel_info.into_offset_builder(builder)
 }
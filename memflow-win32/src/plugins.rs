@@ -1,53 +1,249 @@
+use crate::kernel::AArch64Layout;
 use crate::offsets::SymbolStore;
-use crate::win32::{Win32Kernel, Win32KernelBuilder};
+use crate::win32::{
+    Win32EnvConfig, Win32Interaction, Win32InteractionArcBox, Win32Kernel, Win32KernelBuilder,
+};
 
 use memflow::cglue;
+use memflow::cglue::result::into_int_out_result;
 use memflow::plugins::{args, OsArgs};
 use memflow::prelude::v1::*;
 use memflow::types::cache::TimedCacheValidator;
 
+use std::mem::MaybeUninit;
 use std::time::Duration;
 
-#[os(name = "win32", accept_input = true, return_wrapped = true)]
+#[os(
+    name = "win32",
+    help_fn = "help",
+    accept_input = true,
+    return_wrapped = true
+)]
 pub fn create_os(
     args: &OsArgs,
     mem: Option<ConnectorInstanceArcBox<'static>>,
     lib: LibArc,
 ) -> Result<OsInstanceArcBox<'static>> {
+    create_kernel(args, mem, lib)
+}
+
+/// Creates the [`Win32Interaction`] of a target.
+///
+/// The same arguments as for the `win32` os plugin are accepted.
+pub fn create_interaction(
+    args: &OsArgs,
+    mem: ConnectorInstanceArcBox<'static>,
+    lib: LibArc,
+) -> Result<Win32InteractionArcBox<'static>> {
+    create_kernel(args, Some(mem), lib)
+}
+
+/// Entry point of [`create_interaction`] for plugin hosts.
+///
+/// `lib` has to reference the loaded library of this plugin. Returns 0 on success.
+#[no_mangle]
+pub extern "C" fn mf_win32_interaction(
+    args: &OsArgs,
+    mem: ConnectorInstanceArcBox<'static>,
+    lib: LibArc,
+    out: &mut MaybeUninit<Win32InteractionArcBox<'static>>,
+) -> i32 {
+    into_int_out_result(create_interaction(args, mem, lib), out)
+}
+
+/// Object that is handed out for the built kernel
+trait KernelInstance: Sized {
+    fn from_kernel<T: 'static + PhysicalMemory + Clone, V: 'static + VirtualTranslate2 + Clone>(
+        kernel: Win32Kernel<T, V>,
+        lib: LibArc,
+    ) -> Self;
+}
+
+impl KernelInstance for OsInstanceArcBox<'static> {
+    fn from_kernel<T: 'static + PhysicalMemory + Clone, V: 'static + VirtualTranslate2 + Clone>(
+        kernel: Win32Kernel<T, V>,
+        lib: LibArc,
+    ) -> Self {
+        // optional vtables (e.g. OsKeyboard) are populated from the cglue_impl_group!
+        // of Win32Kernel
+        group_obj!((kernel, lib) as OsInstance)
+    }
+}
+
+impl KernelInstance for Win32InteractionArcBox<'static> {
+    fn from_kernel<T: 'static + PhysicalMemory + Clone, V: 'static + VirtualTranslate2 + Clone>(
+        kernel: Win32Kernel<T, V>,
+        lib: LibArc,
+    ) -> Self {
+        group_obj!((kernel, lib) as Win32Interaction)
+    }
+}
+
+fn create_kernel<O: KernelInstance>(
+    args: &OsArgs,
+    mem: Option<ConnectorInstanceArcBox<'static>>,
+    lib: LibArc,
+) -> Result<O> {
     let mem = mem.ok_or_else(|| {
         Error(ErrorOrigin::OsLayer, ErrorKind::Configuration).log_error("Must provide memory!")
     })?;
 
+    // the plugin has its own instance of the log crate, this does not affect the host
+    if let Some(log_level) = Win32EnvConfig::from_env().log_level {
+        log::set_max_level(log_level);
+    }
+
     let builder = Win32Kernel::builder(mem);
-    build_dtb(builder, &args.extra_args, lib)
+    let builder = match args.extra_args.get("memmap") {
+        Some(path) => builder.mem_map_file(path)?,
+        None => builder,
+    };
+    let builder = match args.extra_args.get("offsets") {
+        Some(path) => builder.offset_file_path(path)?,
+        None => builder,
+    };
+    let builder = match args.extra_args.get("offset_db") {
+        Some(dir) => builder.offset_db_dir(dir),
+        None => builder,
+    };
+    let builder = match args.extra_args.get("calibrate") {
+        Some("true") | Some("1") => builder.calibrate(),
+        _ => builder,
+    };
+    #[cfg(feature = "parallel")]
+    let builder = match args.extra_args.get("parallel") {
+        Some("true") | Some("1") => builder.parallel(),
+        _ => builder,
+    };
+    let aarch64_phys_base = parse_hex_arg(&args.extra_args, "aarch64_base")?;
+    let aarch64_max_mem = parse_hex_arg(&args.extra_args, "aarch64_max_mem")?;
+    let builder = if aarch64_phys_base.is_some() || aarch64_max_mem.is_some() {
+        let default = AArch64Layout::default();
+        builder.aarch64_layout(AArch64Layout {
+            phys_base: aarch64_phys_base
+                .map(Address::from)
+                .unwrap_or(default.phys_base),
+            max_mem: aarch64_max_mem
+                .map(|max_mem| max_mem as umem)
+                .unwrap_or(default.max_mem),
+        })
+    } else {
+        builder
+    };
+    let builder = match args.extra_args.get("offset_overrides") {
+        Some(overrides) => parse_offset_overrides(overrides)?
+            .into_iter()
+            .fold(builder, |builder, (name, value)| {
+                builder.override_offset(name, value)
+            }),
+        None => builder,
+    };
+    match args.extra_args.get("profile") {
+        Some(profile) => build_dtb(builder.profile(profile.parse()?), &args.extra_args, lib),
+        None => build_dtb(builder, &args.extra_args, lib),
+    }
+}
+
+/// Parses an optional hex argument, an optional `0x` prefix is accepted.
+fn parse_hex_arg(args: &Args, name: &str) -> Result<Option<u64>> {
+    args.get(name)
+        .map(|value| {
+            let value = value.trim();
+            u64::from_str_radix(value.strip_prefix("0x").unwrap_or(value), 16).map_err(|_| {
+                Error(ErrorOrigin::OsLayer, ErrorKind::ArgValidation)
+                    .log_error(format!("{} must be a hex value", name))
+            })
+        })
+        .transpose()
+}
+
+/// Parses a list of `name:value` pairs separated by ';' or ','.
+///
+/// Values are parsed as hex if they are prefixed with `0x` and as decimal otherwise.
+fn parse_offset_overrides(overrides: &str) -> Result<Vec<(&str, u32)>> {
+    overrides
+        .split(|c| c == ',' || c == ';')
+        .filter(|o| !o.trim().is_empty())
+        .map(|o| {
+            let (name, value) = o.split_once(':').ok_or_else(|| {
+                Error(ErrorOrigin::OsLayer, ErrorKind::ArgValidation)
+                    .log_error(format!("invalid offset override: {}", o))
+            })?;
+            let value = value.trim();
+            let value = match value.strip_prefix("0x") {
+                Some(hex) => u32::from_str_radix(hex, 16),
+                None => value.parse::<u32>(),
+            }
+            .map_err(|_| {
+                Error(ErrorOrigin::OsLayer, ErrorKind::ArgValidation)
+                    .log_error(format!("invalid offset override value: {}", o))
+            })?;
+            Ok((name.trim(), value))
+        })
+        .collect()
+}
+
+/// Returns the usage of the plugin and all accepted arguments.
+pub fn help() -> String {
+    let description = [
+        "dtb              - the directory table base of the kernel, multiple candidates are separated by ';' (hex, default: scanned)",
+        "kernel_hint      - an address inside of the kernel image to speed up the scan (hex, default: none)",
+        "arch             - the architecture of the target: x64, x32, x32_pae, aarch64 (default: detected)",
+        "aarch64_base     - the start of physical memory on aarch64 targets (hex, default: 40000000)",
+        "aarch64_max_mem  - the upper bound of physical memory on aarch64 targets (hex, default: 8000000000)",
+        "symstore         - the symbol store mode: uncached, none (default: cached)",
+        "symstore_url     - the base url of the symbol store, fallback urls are separated by ';' (default: https://msdl.microsoft.com/download/symbols)",
+        "symstore_timeout - the request timeout per symbol server in seconds (default: none)",
+        "symstore_proxy   - the proxy used for pdb downloads (default: HTTPS_PROXY / HTTP_PROXY)",
+        "symstore_ca      - a pem file with additional trusted ca certificates (path, default: none)",
+        "symstore_cache   - the directory pdb files are cached in (default: user cache directory)",
+        "offsets          - a toml, json or yaml offset file used instead of the symbol store (path, default: none)",
+        "offset_db        - a directory of offset files matched by guid and version (path, default: none)",
+        "calibrate        - brute force the offsets on the system process if no offsets match: true, false (default: false)",
+        "parallel         - scan the kernel on multiple threads (requires the parallel feature): true, false (default: false)",
+        "offset_overrides - offsets patched after resolving, e.g. eproc_peb:0x550;kproc_dtb:0x28 (default: none)",
+        "memmap           - a memory map file in memflow's toml format (path, default: none)",
+        "profile          - the tuning profile: qemu-vm, pcileech-fpga, dumpfile, winpmem (default: none)",
+        "vatcache         - the vat cache mode: default, none (see vatcache_size and vatcache_time)",
+    ];
+
+    format!(
+        "The `win32` os plugin provides access to windows targets.
+
+Available arguments are:
+{}",
+        description.join("\n")
+    )
 }
 
 fn build_final<
     A: 'static + PhysicalMemory + Clone,
     B: 'static + PhysicalMemory + Clone,
     C: 'static + VirtualTranslate2 + Clone,
+    O: KernelInstance,
 >(
     kernel_builder: Win32KernelBuilder<A, B, C>,
     _: &Args,
     lib: LibArc,
-) -> Result<OsInstanceArcBox<'static>> {
+) -> Result<O> {
     log::info!(
         "Building kernel of type {}",
         std::any::type_name::<Win32KernelBuilder<A, B, C>>()
     );
     let kernel = kernel_builder.build()?;
-    Ok(group_obj!((kernel, lib) as OsInstance))
+    Ok(O::from_kernel(kernel, lib))
 }
 
 fn build_arch<
     A: 'static + PhysicalMemory + Clone,
     B: 'static + PhysicalMemory + Clone,
     C: 'static + VirtualTranslate2 + Clone,
+    O: KernelInstance,
 >(
     builder: Win32KernelBuilder<A, B, C>,
     args: &Args,
     lib: LibArc,
-) -> Result<OsInstanceArcBox<'static>> {
+) -> Result<O> {
     match args.get("arch").map(|a| a.to_lowercase()).as_deref() {
         Some("x64") => build_final(builder.arch(ArchitectureIdent::X86(64, false)), args, lib),
         Some("x32") => build_final(builder.arch(ArchitectureIdent::X86(32, false)), args, lib),
@@ -65,31 +261,74 @@ fn build_symstore<
     A: 'static + PhysicalMemory + Clone,
     B: 'static + PhysicalMemory + Clone,
     C: 'static + VirtualTranslate2 + Clone,
+    O: KernelInstance,
 >(
     builder: Win32KernelBuilder<A, B, C>,
     args: &Args,
     lib: LibArc,
-) -> Result<OsInstanceArcBox<'static>> {
-    match args.get("symstore") {
-        Some("uncached") => build_arch(
-            builder.symbol_store(SymbolStore::new().no_cache()),
-            args,
-            lib,
-        ),
-        Some("none") => build_arch(builder.no_symbol_store(), args, lib),
-        _ => build_arch(builder, args, lib),
+) -> Result<O> {
+    if args.get("symstore") == Some("none") {
+        return build_arch(builder.no_symbol_store(), args, lib);
+    }
+
+    let url = args.get("symstore_url");
+    let timeout = args.get("symstore_timeout");
+    let proxy = args.get("symstore_proxy");
+    let ca = args.get("symstore_ca");
+    let cache = args.get("symstore_cache");
+    let uncached = args.get("symstore") == Some("uncached");
+    if url.is_none()
+        && timeout.is_none()
+        && proxy.is_none()
+        && ca.is_none()
+        && cache.is_none()
+        && !uncached
+    {
+        return build_arch(builder, args, lib);
     }
+
+    let mut symbol_store = SymbolStore::new();
+    if let Some(url) = url {
+        let mut urls = url.split(';').map(str::trim).filter(|u| !u.is_empty());
+        if let Some(base_url) = urls.next() {
+            symbol_store = symbol_store.base_url(base_url);
+        }
+        for fallback_url in urls {
+            symbol_store = symbol_store.fallback_url(fallback_url);
+        }
+    }
+    if let Some(timeout) = timeout {
+        let secs = timeout.parse::<u64>().map_err(|_| {
+            Error(ErrorOrigin::OsLayer, ErrorKind::ArgValidation)
+                .log_error("symstore_timeout must be a number of seconds")
+        })?;
+        symbol_store = symbol_store.timeout(Duration::from_secs(secs));
+    }
+    if let Some(proxy) = proxy {
+        symbol_store = symbol_store.proxy(proxy);
+    }
+    if let Some(ca) = ca {
+        symbol_store = symbol_store.ca_file(ca);
+    }
+    if let Some(cache) = cache {
+        symbol_store = symbol_store.cache_path(cache);
+    }
+    if uncached {
+        symbol_store = symbol_store.no_cache();
+    }
+    build_arch(builder.symbol_store(symbol_store), args, lib)
 }
 
 fn build_kernel_hint<
     A: 'static + PhysicalMemory + Clone,
     B: 'static + PhysicalMemory + Clone,
     C: 'static + VirtualTranslate2 + Clone,
+    O: KernelInstance,
 >(
     builder: Win32KernelBuilder<A, B, C>,
     args: &Args,
     lib: LibArc,
-) -> Result<OsInstanceArcBox<'static>> {
+) -> Result<O> {
     match args
         .get("kernel_hint")
         .and_then(|d| u64::from_str_radix(d, 16).ok())
@@ -103,11 +342,12 @@ fn build_vat<
     A: 'static + PhysicalMemory + Clone,
     B: 'static + PhysicalMemory + Clone,
     C: 'static + VirtualTranslate2 + Clone,
+    O: KernelInstance,
 >(
     builder: Win32KernelBuilder<A, B, C>,
     args: &Args,
     lib: LibArc,
-) -> Result<OsInstanceArcBox<'static>> {
+) -> Result<O> {
     match args::parse_vatcache(args)? {
         Some((0, _)) => build_kernel_hint(
             builder.build_vat_cache(|v, a| {
@@ -140,16 +380,26 @@ fn build_dtb<
     A: 'static + PhysicalMemory + Clone,
     B: 'static + PhysicalMemory + Clone,
     C: 'static + VirtualTranslate2 + Clone,
+    O: KernelInstance,
 >(
     builder: Win32KernelBuilder<A, B, C>,
     args: &Args,
     lib: LibArc,
-) -> Result<OsInstanceArcBox<'static>> {
-    match args
+) -> Result<O> {
+    // multiple candidates can be separated by a comma or semicolon
+    let dtbs = args
         .get("dtb")
-        .and_then(|d| u64::from_str_radix(d, 16).ok())
-    {
-        Some(dtb) => build_vat(builder.dtb(Address::from(dtb)), args, lib),
-        _ => build_vat(builder, args, lib),
+        .map(|d| {
+            d.split(|c| c == ',' || c == ';')
+                .filter_map(|d| u64::from_str_radix(d.trim(), 16).ok())
+                .map(Address::from)
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+
+    match dtbs.as_slice() {
+        [] => build_vat(builder, args, lib),
+        [dtb] => build_vat(builder.dtb(*dtb), args, lib),
+        dtbs => build_vat(builder.dtb_candidates(dtbs), args, lib),
     }
 }
@@ -2,18 +2,140 @@ pub mod kernel;
 pub mod kernel_builder;
 pub mod kernel_info;
 
-pub use kernel::Win32Kernel;
+pub use kernel::{Win32AttachedView, Win32EprocessDefect, Win32Kernel};
 pub use kernel_builder::Win32KernelBuilder;
+#[cfg(feature = "parallel")]
+pub use kernel_info::ParallelScanner;
 pub use kernel_info::Win32KernelInfo;
 
+pub mod apc;
+#[cfg(feature = "authenticode")]
+pub mod authenticode;
+pub mod calibration;
+#[cfg(feature = "symstore")]
+pub mod clipboard;
+pub mod clr;
+pub mod cmdline;
+pub mod console;
+pub mod cpu_state;
+pub mod cpu_usage;
+pub mod crashdump;
+#[cfg(feature = "disasm")]
+pub mod disasm;
+#[cfg(feature = "std")]
+pub mod env_config;
+#[cfg(feature = "symstore")]
+pub mod etw;
+pub mod gadgets;
+pub mod gui_handles;
+pub mod hollowing;
+pub mod hooks;
+#[cfg(all(feature = "plugins", feature = "symstore"))]
+pub mod interaction;
+pub mod kdbg;
+#[cfg(feature = "symstore")]
+pub mod kernel_callbacks;
+pub mod kernel_stack;
+#[cfg(feature = "symstore")]
+pub mod kernel_types;
 pub mod keyboard;
+pub mod list_entry;
+pub mod mem_compression;
+#[cfg(feature = "symstore")]
+pub mod minifilter;
 pub mod module;
+#[cfg(feature = "module_hashes")]
+pub mod module_hash;
+#[cfg(feature = "symstore")]
+pub mod module_verify;
+#[cfg(feature = "symstore")]
+pub mod named_objects;
+#[cfg(feature = "symstore")]
+pub mod object_manager;
+#[cfg(feature = "symstore")]
+pub mod open_files;
+pub mod page_fault;
+#[cfg(feature = "std")]
+pub mod page_set;
+pub mod pagefile;
+pub mod platform;
 pub mod process;
+pub mod process_query;
+pub mod profile;
+pub mod pte;
+pub mod redact;
+pub mod section_views;
+pub mod service_table;
+pub mod session;
+pub mod smear;
+#[cfg(feature = "std")]
+pub mod snapshot;
+pub mod syscall_stubs;
 pub mod unicode_string;
 pub mod vat;
+pub mod version_info;
 
+pub use apc::*;
+#[cfg(feature = "authenticode")]
+pub use authenticode::*;
+#[cfg(feature = "symstore")]
+pub use clipboard::*;
+pub use clr::*;
+pub use cmdline::*;
+pub use console::*;
+pub use cpu_state::*;
+pub use cpu_usage::*;
+pub use crashdump::*;
+#[cfg(feature = "disasm")]
+pub use disasm::*;
+#[cfg(feature = "std")]
+pub use env_config::*;
+#[cfg(feature = "symstore")]
+pub use etw::*;
+pub use gadgets::*;
+pub use gui_handles::*;
+pub use hollowing::*;
+pub use hooks::*;
+#[cfg(all(feature = "plugins", feature = "symstore"))]
+pub use interaction::*;
+#[cfg(feature = "symstore")]
+pub use kernel_callbacks::*;
+pub use kernel_stack::*;
+#[cfg(feature = "symstore")]
+pub use kernel_types::*;
 pub use keyboard::*;
+pub use list_entry::*;
+pub use mem_compression::*;
+#[cfg(feature = "symstore")]
+pub use minifilter::*;
 pub use module::*;
+#[cfg(feature = "module_hashes")]
+pub use module_hash::*;
+#[cfg(feature = "symstore")]
+pub use module_verify::*;
+#[cfg(feature = "symstore")]
+pub use named_objects::*;
+#[cfg(feature = "symstore")]
+pub use object_manager::*;
+#[cfg(feature = "symstore")]
+pub use open_files::*;
+pub use page_fault::*;
+#[cfg(feature = "std")]
+pub use page_set::*;
+pub use pagefile::*;
+pub use platform::*;
 pub use process::*;
+pub use process_query::*;
+pub use profile::*;
+pub use pte::*;
+pub use redact::*;
+pub use section_views::*;
+pub use service_table::*;
+pub use session::*;
+pub use smear::*;
+#[cfg(feature = "std")]
+pub use snapshot::*;
+pub use syscall_stubs::*;
 pub use unicode_string::*;
 pub use vat::*;
+pub use version_info::*;
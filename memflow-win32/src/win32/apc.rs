@@ -0,0 +1,221 @@
+/*!
+Module for enumerating the pending asynchronous procedure calls (apcs) of threads.
+
+Every thread keeps two apc queues in `_KTHREAD::ApcState`, one for kernel and one for user apcs.
+Queued apcs that were not delivered yet stay in these queues, this is what apc injection
+(e.g. `QueueUserAPC`, `NtQueueApcThread` or drivers queueing user apcs) leaves behind.
+The routines of every apc are attributed to the module that contains them,
+routines that do not belong to any loaded module are a strong indicator for injected code.
+
+Apcs that are queued while a thread is attached to another process are kept in
+`_KTHREAD::SavedApcState` and are not enumerated.
+
+# Examples:
+
+```
+use memflow::prelude::v1::*;
+use memflow_win32::win32::Win32Kernel;
+
+fn test<T: 'static + PhysicalMemory + Clone, V: 'static + VirtualTranslate2 + Clone>(
+    kernel: &mut Win32Kernel<T, V>,
+) {
+    for process in kernel.process_info_list().unwrap() {
+        for apc in kernel.process_apc_list(process.clone()).unwrap_or_default() {
+            if apc.is_suspicious() {
+                println!("{}: {:?}", process.name, apc);
+            }
+        }
+    }
+}
+```
+*/
+use std::prelude::v1::*;
+
+use super::{Win32Kernel, Win32ListWalker, Win32ModuleOffset};
+
+use log::{debug, trace};
+
+use memflow::architecture::ArchitectureObj;
+use memflow::error::{Error, ErrorKind, ErrorOrigin, Result};
+use memflow::mem::{PhysicalMemory, VirtualTranslate2};
+use memflow::os::{ModuleInfo, Os, Process, ProcessInfo};
+use memflow::types::{umem, Address};
+
+/// Processor mode an apc is delivered in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize))]
+pub enum Win32ApcMode {
+    Kernel,
+    User,
+}
+
+/// A routine of an apc
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize))]
+pub struct Win32ApcRoutine {
+    pub address: Address,
+    /// Module that contains the routine, `None` if it does not belong to any loaded module
+    pub module: Option<Win32ModuleOffset>,
+}
+
+impl Win32ApcRoutine {
+    fn new(address: Address, modules: &[ModuleInfo]) -> Self {
+        Self {
+            address,
+            module: Win32ModuleOffset::find(modules.iter().cloned(), address),
+        }
+    }
+
+    /// Returns true if the routine is set but does not belong to any loaded module.
+    pub fn is_unbacked(&self) -> bool {
+        !self.address.is_null() && self.module.is_none()
+    }
+}
+
+/// A pending apc of a thread
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize))]
+pub struct Win32Apc {
+    /// Address of the `_KAPC`
+    pub address: Address,
+    /// Address of the `_ETHREAD` the apc is queued to
+    pub thread: Address,
+    pub mode: Win32ApcMode,
+    /// Runs in kernel mode before the normal routine
+    pub kernel_routine: Win32ApcRoutine,
+    /// Runs if the thread terminates before the apc is delivered
+    pub rundown_routine: Win32ApcRoutine,
+    /// Runs in the mode of the apc, null for special kernel apcs
+    pub normal_routine: Win32ApcRoutine,
+    /// First argument of the normal routine
+    pub normal_context: Address,
+}
+
+impl Win32Apc {
+    /// Returns true if any routine of the apc does not belong to a loaded module.
+    pub fn is_suspicious(&self) -> bool {
+        self.kernel_routine.is_unbacked()
+            || self.rundown_routine.is_unbacked()
+            || self.normal_routine.is_unbacked()
+    }
+}
+
+impl<T: 'static + PhysicalMemory + Clone, V: 'static + VirtualTranslate2 + Clone>
+    Win32Kernel<T, V>
+{
+    /// Returns the pending kernel and user apcs of a thread.
+    ///
+    /// Routines are only attributed to kernel modules,
+    /// use [`Win32Kernel::process_apc_list`] to attribute user mode routines as well.
+    pub fn thread_apc_list(&mut self, ethread: Address) -> Result<Vec<Win32Apc>> {
+        let modules = self.module_list()?;
+        self.thread_apc_list_with_modules(ethread, &modules)
+    }
+
+    /// Returns the pending apcs of all threads of a process.
+    ///
+    /// Routines are attributed to the kernel modules and the modules of the process.
+    /// If the module list of the process cannot be read only kernel modules are used.
+    pub fn process_apc_list(&mut self, info: ProcessInfo) -> Result<Vec<Win32Apc>> {
+        let eprocess = info.address;
+        // user apcs of wow64 threads carry an encoded normal routine
+        let is_wow64 = info.proc_arch != info.sys_arch;
+
+        let mut modules = self.module_list()?;
+        match self
+            .process_by_info(info)
+            .and_then(|mut process| process.module_list())
+        {
+            Ok(process_modules) => modules.extend(process_modules),
+            Err(err) => debug!("unable to read the module list of the process: {}", err),
+        }
+
+        let mut out = vec![];
+        for ethread in self.thread_address_list(eprocess)? {
+            match self.thread_apc_list_with_modules(ethread, &modules) {
+                Ok(apcs) => out.extend(apcs),
+                Err(err) => debug!("unable to read the apcs of thread {:x}: {}", ethread, err),
+            }
+        }
+
+        if is_wow64 {
+            for apc in out.iter_mut().filter(|apc| apc.mode == Win32ApcMode::User) {
+                let address = decode_wow64_routine(apc.normal_routine.address);
+                apc.normal_routine = Win32ApcRoutine::new(address, &modules);
+            }
+        }
+
+        Ok(out)
+    }
+
+    fn thread_apc_list_with_modules(
+        &mut self,
+        ethread: Address,
+        modules: &[ModuleInfo],
+    ) -> Result<Vec<Win32Apc>> {
+        if self.offsets.kthread_apc_state() == 0 || self.offsets.kapc_kernel_routine() == 0 {
+            return Err(Error(ErrorOrigin::OsLayer, ErrorKind::NotSupported)
+                .log_info("apc offsets are not available for this kernel"));
+        }
+
+        let arch: ArchitectureObj = self.kernel_info.os_info.arch.into();
+        // ApcListHead is an array of the kernel and the user queue
+        let list_heads =
+            ethread + self.offsets.kthread_apc_state() + self.offsets.kapc_state_apc_list_head();
+
+        let mut out = vec![];
+        for (idx, mode) in [Win32ApcMode::Kernel, Win32ApcMode::User]
+            .into_iter()
+            .enumerate()
+        {
+            let list_head = list_heads + (idx * 2 * arch.size_addr()) as umem;
+            let kapcs = Win32ListWalker::new(arch, list_head)
+                .entries(&mut self.virt_mem)?
+                .into_iter()
+                .filter(|&list_entry| list_entry != list_head)
+                .map(|list_entry| list_entry - self.offsets.kapc_apc_list_entry())
+                .collect::<Vec<_>>();
+
+            for kapc in kapcs.into_iter() {
+                let mut read = |offset: usize| -> Result<Address> {
+                    self.virt_mem.read_addr_arch(arch, kapc + offset)
+                };
+                let kernel_routine = read(self.offsets.kapc_kernel_routine())?;
+                let rundown_routine = read(self.offsets.kapc_rundown_routine())?;
+                let normal_routine = read(self.offsets.kapc_normal_routine())?;
+                let normal_context = read(self.offsets.kapc_normal_context())?;
+                trace!(
+                    "apc {:x}: mode={:?} kernel_routine={:x} normal_routine={:x}",
+                    kapc,
+                    mode,
+                    kernel_routine,
+                    normal_routine
+                );
+
+                out.push(Win32Apc {
+                    address: kapc,
+                    thread: ethread,
+                    mode,
+                    kernel_routine: Win32ApcRoutine::new(kernel_routine, modules),
+                    rundown_routine: Win32ApcRoutine::new(rundown_routine, modules),
+                    normal_routine: Win32ApcRoutine::new(normal_routine, modules),
+                    normal_context,
+                });
+            }
+        }
+
+        Ok(out)
+    }
+}
+
+/// Decodes the normal routine of a user apc that is queued to a wow64 thread.
+///
+/// `PsWrapApcWow64Thread` stores the routine as `-routine << 2`, routines that are not
+/// encoded (e.g. apcs that are delivered to the 64 bit ntdll) are returned unchanged.
+fn decode_wow64_routine(routine: Address) -> Address {
+    let encoded = routine.to_umem() as i64;
+    if encoded >= 0 {
+        return routine;
+    }
+    Address::from((-(encoded >> 2)) as u64)
+}
@@ -0,0 +1,320 @@
+/*!
+Module for reading the authenticode signature of loaded modules.
+
+The certificate table of a pe file is referenced by the security directory. It is usually appended
+to the end of the file and therefore not mapped by the loader, in that case only the location
+from the image header is reported. If the table is mapped (e.g. as part of a section or for images
+that were mapped as flat files) the pkcs#7 signature is parsed and the subject, issuer and sha1
+thumbprint of every signer are extracted.
+
+Note that most drivers and system dlls are catalog signed and do not contain a certificate table,
+a missing signature therefore does not imply that a module is unsigned.
+
+# Examples:
+
+```
+use memflow::prelude::v1::*;
+use memflow_win32::win32::{Win32Kernel, Win32ModuleInfo};
+
+fn test<T: 'static + PhysicalMemory + Clone, V: 'static + VirtualTranslate2 + Clone>(
+    kernel: &mut Win32Kernel<T, V>,
+) {
+    for module in kernel.module_list().unwrap() {
+        match module.authenticode(kernel) {
+            Ok(Some(signature)) => {
+                for signer in signature.signers.iter() {
+                    println!("{}: {} ({})", module.name, signer.subject, signer.thumbprint_hex());
+                }
+            }
+            Ok(None) => println!("{}: no embedded signature", module.name),
+            Err(err) => println!("{}: {}", module.name, err),
+        }
+    }
+}
+```
+*/
+use std::prelude::v1::*;
+
+use std::convert::TryInto;
+
+use crate::kernel::ntos::lazy_pe::LazyPe;
+
+use log::trace;
+
+use memflow::error::Result;
+use memflow::mem::MemoryView;
+use memflow::types::{size, Address};
+
+use sha1::{Digest, Sha1};
+
+const WIN_CERT_REVISION_2_0: u16 = 0x200;
+const WIN_CERT_TYPE_PKCS_SIGNED_DATA: u16 = 2;
+
+/// Upper bound for the size of the certificate table that is read from memory
+const MAX_CERTIFICATE_TABLE_SIZE: u32 = size::mb(1) as u32;
+
+const DER_INTEGER: u8 = 0x02;
+const DER_SEQUENCE: u8 = 0x30;
+const DER_SET: u8 = 0x31;
+const DER_CONTEXT_0: u8 = 0xa0;
+
+/// Authenticode signature of a module
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize))]
+pub struct Win32Authenticode {
+    /// File offset of the certificate table
+    pub file_offset: u32,
+    /// Size of the certificate table
+    pub size: u32,
+    /// The certificate table is mapped into memory and contains at least one signature
+    pub mapped: bool,
+    /// Signers of all pkcs#7 signatures in the certificate table
+    pub signers: Vec<Win32Signer>,
+}
+
+/// Certificate that signed a module
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize))]
+pub struct Win32Signer {
+    pub subject: String,
+    pub issuer: String,
+    /// Sha1 hash over the certificate
+    pub thumbprint: [u8; 20],
+}
+
+impl Win32Signer {
+    pub fn thumbprint_hex(&self) -> String {
+        self.thumbprint
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+}
+
+impl Win32Authenticode {
+    /// Reads the certificate table of the image at `base`.
+    ///
+    /// Returns `None` if the image does not contain a certificate table.
+    pub fn read<M: MemoryView>(mem: &mut M, base: Address) -> Result<Option<Self>> {
+        let mut pe = LazyPe::new(mem, base)?;
+        let (file_offset, size) = match pe.security_directory() {
+            Ok(dir) => dir,
+            Err(_) => return Ok(None),
+        };
+
+        // the table is either part of a section or the image is mapped with its file layout
+        let rva = pe.file_offset_to_rva(file_offset).or_else(|| {
+            pe.size_of_image()
+                .filter(|&size_of_image| file_offset.saturating_add(size) <= size_of_image)
+                .map(|_| file_offset)
+        });
+
+        let table = rva.and_then(|rva| {
+            pe.read_rva(rva, size.min(MAX_CERTIFICATE_TABLE_SIZE) as usize)
+                .ok()
+        });
+        let signers = table
+            .map(|table| parse_certificate_table(&table))
+            .unwrap_or_default();
+
+        trace!(
+            "certificate table at file offset {:x} with {} signers",
+            file_offset,
+            signers.len()
+        );
+        Ok(Some(Self {
+            file_offset,
+            size,
+            mapped: !signers.is_empty(),
+            signers,
+        }))
+    }
+}
+
+/// Parses the `WIN_CERTIFICATE` entries of the certificate table.
+fn parse_certificate_table(table: &[u8]) -> Vec<Win32Signer> {
+    let mut signers = vec![];
+
+    let mut offset = 0;
+    while let Some(header) = table.get(offset..offset + 8) {
+        let length = u32::from_le_bytes(header[0..4].try_into().unwrap()) as usize;
+        let revision = u16::from_le_bytes(header[4..6].try_into().unwrap());
+        let certificate_type = u16::from_le_bytes(header[6..8].try_into().unwrap());
+        if length < 8 {
+            break;
+        }
+
+        if revision == WIN_CERT_REVISION_2_0 && certificate_type == WIN_CERT_TYPE_PKCS_SIGNED_DATA {
+            if let Some(certificate) = table.get(offset + 8..offset + length) {
+                signers.extend(parse_signed_data(certificate).unwrap_or_default());
+            }
+        }
+
+        // entries are aligned to 8 bytes
+        offset += (length + 7) & !7;
+    }
+
+    signers
+}
+
+/// Extracts the signers of a pkcs#7 `SignedData` structure.
+fn parse_signed_data(buf: &[u8]) -> Option<Vec<Win32Signer>> {
+    // ContentInfo -> [0] -> SignedData
+    let content_info = Der::parse(buf)?.expect(DER_SEQUENCE)?;
+    let signed_data = content_info
+        .children()
+        .find(|c| c.tag == DER_CONTEXT_0)?
+        .children()
+        .next()?
+        .expect(DER_SEQUENCE)?;
+
+    let mut fields = signed_data.children();
+    fields.next()?.expect(DER_INTEGER)?; // version
+    fields.next()?.expect(DER_SET)?; // digestAlgorithms
+    fields.next()?.expect(DER_SEQUENCE)?; // encapContentInfo
+
+    let mut certificates = vec![];
+    let mut signer_infos = None;
+    for field in fields {
+        match field.tag {
+            DER_CONTEXT_0 => certificates = field.children().collect(),
+            DER_SET => signer_infos = Some(field),
+            _ => {}
+        }
+    }
+
+    let signers = signer_infos?
+        .children()
+        .filter_map(|signer_info| {
+            // only signers that are identified by issuer and serial number are supported
+            let sid = signer_info.children().nth(1)?.expect(DER_SEQUENCE)?;
+            let mut sid = sid.children();
+            let (issuer, serial) = (sid.next()?, sid.next()?);
+
+            let certificate = certificates.iter().find(|cert| {
+                tbs_fields(cert).map_or(false, |(cert_serial, cert_issuer, _)| {
+                    cert_serial.content == serial.content && cert_issuer.content == issuer.content
+                })
+            })?;
+            let (_, issuer, subject) = tbs_fields(certificate)?;
+
+            let mut thumbprint = [0u8; 20];
+            thumbprint.copy_from_slice(&Sha1::digest(certificate.raw));
+            Some(Win32Signer {
+                subject: format_name(&subject),
+                issuer: format_name(&issuer),
+                thumbprint,
+            })
+        })
+        .collect();
+
+    Some(signers)
+}
+
+/// Returns the serial number, issuer and subject of a certificate.
+fn tbs_fields<'a>(certificate: &Der<'a>) -> Option<(Der<'a>, Der<'a>, Der<'a>)> {
+    let tbs = certificate.children().next()?.expect(DER_SEQUENCE)?;
+    let mut fields = tbs.children().peekable();
+
+    // the version is optional
+    if fields.peek()?.tag == DER_CONTEXT_0 {
+        fields.next();
+    }
+
+    let serial = fields.next()?.expect(DER_INTEGER)?;
+    fields.next()?; // signature
+    let issuer = fields.next()?.expect(DER_SEQUENCE)?;
+    fields.next()?; // validity
+    let subject = fields.next()?.expect(DER_SEQUENCE)?;
+    Some((serial, issuer, subject))
+}
+
+/// Formats a distinguished name like `CN=name, O=organization, C=country`.
+fn format_name(name: &Der) -> String {
+    name.children()
+        .flat_map(|rdn| rdn.children())
+        .filter_map(|attribute| {
+            let mut attribute = attribute.children();
+            let oid = attribute.next()?;
+            let value = attribute.next()?;
+
+            let key = match oid.content {
+                [0x55, 0x04, 0x03] => "CN",
+                [0x55, 0x04, 0x06] => "C",
+                [0x55, 0x04, 0x07] => "L",
+                [0x55, 0x04, 0x08] => "ST",
+                [0x55, 0x04, 0x0a] => "O",
+                [0x55, 0x04, 0x0b] => "OU",
+                _ => return None,
+            };
+
+            let value = match value.tag {
+                // BMPString
+                0x1e => String::from_utf16_lossy(
+                    &value
+                        .content
+                        .chunks_exact(2)
+                        .map(|c| u16::from_be_bytes([c[0], c[1]]))
+                        .collect::<Vec<_>>(),
+                ),
+                _ => String::from_utf8_lossy(value.content).into_owned(),
+            };
+
+            Some(format!("{}={}", key, value))
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Minimal reader for der encoded values
+#[derive(Clone, Copy)]
+struct Der<'a> {
+    tag: u8,
+    content: &'a [u8],
+    /// The whole encoding including the tag and the length
+    raw: &'a [u8],
+}
+
+impl<'a> Der<'a> {
+    /// Parses the value at the start of `buf`.
+    fn parse(buf: &'a [u8]) -> Option<Self> {
+        let tag = *buf.first()?;
+        let first = *buf.get(1)? as usize;
+
+        let (length, header) = if first & 0x80 == 0 {
+            (first, 2)
+        } else {
+            // long form, indefinite lengths are not allowed in der
+            let count = first & 0x7f;
+            if count == 0 || count > 4 {
+                return None;
+            }
+            let length = buf
+                .get(2..2 + count)?
+                .iter()
+                .fold(0usize, |acc, &b| (acc << 8) | b as usize);
+            (length, 2 + count)
+        };
+
+        let raw = buf.get(..header.checked_add(length)?)?;
+        Some(Self {
+            tag,
+            content: &raw[header..],
+            raw,
+        })
+    }
+
+    fn expect(self, tag: u8) -> Option<Self> {
+        Some(self).filter(|der| der.tag == tag)
+    }
+
+    /// Iterates over the values of a constructed value.
+    fn children(&self) -> impl Iterator<Item = Der<'a>> {
+        let mut buf = self.content;
+        std::iter::from_fn(move || {
+            let der = Der::parse(buf)?;
+            buf = &buf[der.raw.len()..];
+            Some(der)
+        })
+    }
+}
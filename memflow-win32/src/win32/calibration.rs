@@ -0,0 +1,189 @@
+/*!
+Module for brute forcing the offsets of unknown kernels.
+
+The System process is used as an anchor: it always has the pid 4, the name `System`
+and the directory table base of the kernel. The eprocess of the System process is scanned
+for these values and the offsets are validated with the invariants of the process list.
+The peb and the section base are calibrated on the first user process in the process list
+since the `ImageBaseAddress` of its peb has to match the section base.
+
+All other offsets are set to 0 which disables the features depending on them.
+
+# Examples:
+
+```
+use memflow::prelude::v1::*;
+use memflow_win32::win32::Win32KernelInfo;
+
+fn test<T: PhysicalMemory>(mut connector: T) {
+    let kernel_info = Win32KernelInfo::scanner(connector.forward_mut())
+        .scan()
+        .unwrap();
+
+    let offsets = kernel_info.calibrate_offsets(connector.forward_mut()).unwrap();
+    println!("{:?}", offsets);
+}
+```
+*/
+use std::prelude::v1::*;
+
+use super::{Win32KernelInfo, Win32VirtualTranslate};
+use crate::kernel::heuristics::{
+    dtb_mask, eprocess_scan_len, find_dtb, find_system_name, find_system_pid, read_ptr,
+};
+use crate::offsets::Win32OffsetTable;
+
+use log::{debug, info, warn};
+
+use memflow::architecture::{ArchitectureIdent, ArchitectureObj};
+use memflow::cglue::forward::ForwardMut;
+use memflow::error::{Error, ErrorKind, ErrorOrigin, Result};
+use memflow::mem::{DirectTranslate, MemoryView, PhysicalMemory, VirtualDma};
+use memflow::types::{umem, Address};
+
+/// Maximum number of processes that are tried for the calibration of the peb
+const MAX_PROCESSES: usize = 32;
+
+impl Win32KernelInfo {
+    /// Brute forces the most important offsets by scanning the System process.
+    ///
+    /// This can be used if neither a pdb nor an offset file is available for the kernel.
+    pub fn calibrate_offsets<T: PhysicalMemory>(&self, mut mem: T) -> Result<Win32OffsetTable> {
+        let arch = self.os_info.arch;
+        let ptr_size = ArchitectureObj::from(arch).size_addr();
+
+        let mut kernel_mem = VirtualDma::with_vat(
+            mem.forward_mut(),
+            arch,
+            Win32VirtualTranslate::new(arch, self.kernel_dtb).with_la57(self.la57),
+            DirectTranslate::new(),
+        );
+
+        let scan_len = eprocess_scan_len(ptr_size);
+        let mut system = vec![0u8; scan_len];
+        kernel_mem.read_raw_into(self.eprocess_base, &mut system)?;
+
+        // ActiveProcessLinks directly follows UniqueProcessId
+        let eproc_pid = find_system_pid(&mut kernel_mem, &system, self.eprocess_base, ptr_size)
+            .ok_or_else(|| {
+                Error(ErrorOrigin::OsLayer, ErrorKind::Offset)
+                    .log_warn("unable to find UniqueProcessId in system eprocess")
+            })?;
+        let eproc_link = eproc_pid + ptr_size;
+
+        let eproc_name = find_system_name(&system, eproc_link).ok_or_else(|| {
+            Error(ErrorOrigin::OsLayer, ErrorKind::Offset)
+                .log_warn("unable to find ImageFileName in system eprocess")
+        })?;
+
+        let dtb_mask = dtb_mask(arch);
+        let kproc_dtb = find_dtb(&system, self.kernel_dtb, arch).ok_or_else(|| {
+            Error(ErrorOrigin::OsLayer, ErrorKind::Offset)
+                .log_warn("unable to find DirectoryTableBase in system eprocess")
+        })?;
+
+        info!(
+            "calibrated eproc_pid={:x} eproc_link={:x} eproc_name={:x} kproc_dtb={:x}",
+            eproc_pid, eproc_link, eproc_name, kproc_dtb
+        );
+
+        let mut table = Win32OffsetTable {
+            list_blink: ptr_size as u32,
+            eproc_link: eproc_link as u32,
+            kproc_dtb: kproc_dtb as u32,
+            eproc_pid: eproc_pid as u32,
+            eproc_name: eproc_name as u32,
+            ..Default::default()
+        };
+
+        // collect the eprocess of the first processes in the list
+        let mut processes = vec![];
+        let mut entry = read_ptr(&system, eproc_link, ptr_size).unwrap_or_default();
+        while entry != 0 && processes.len() < MAX_PROCESSES {
+            let eprocess = Address::from(entry) - eproc_link;
+            if eprocess == self.eprocess_base {
+                break;
+            }
+
+            let mut buf = vec![0u8; scan_len];
+            if kernel_mem.read_raw_into(eprocess, &mut buf).is_err() {
+                break;
+            }
+            entry = read_ptr(&buf, eproc_link, ptr_size).unwrap_or_default();
+            processes.push(buf);
+        }
+        drop(kernel_mem);
+
+        // the peb and section base are calibrated on the first user process
+        let calibrated = processes.iter().find_map(|eprocess| {
+            let dtb = read_ptr(eprocess, kproc_dtb, ptr_size)? & dtb_mask;
+            let mut proc_mem = VirtualDma::with_vat(
+                mem.forward_mut(),
+                arch,
+                Win32VirtualTranslate::new(arch, Address::from(dtb)).with_la57(self.la57),
+                DirectTranslate::new(),
+            );
+            calibrate_peb(&mut proc_mem, eprocess, eproc_name, ptr_size)
+        });
+
+        match calibrated {
+            Some((section_base, peb)) => {
+                info!(
+                    "calibrated eproc_section_base={:x} eproc_peb={:x}",
+                    section_base, peb
+                );
+                table.eproc_section_base = section_base as u32;
+                table.eproc_peb = peb as u32;
+            }
+            None => {
+                warn!("unable to calibrate the peb offset, process modules will be unavailable")
+            }
+        }
+
+        Ok(table)
+    }
+}
+
+/// Finds the offsets of the section base and the peb in the eprocess of a user process.
+///
+/// The `ImageBaseAddress` of the peb is located after `InheritedAddressSpace` and `Mutant`
+/// and has to match the section base of the process.
+fn calibrate_peb<T: MemoryView>(
+    proc_mem: &mut T,
+    eprocess: &[u8],
+    eproc_name: usize,
+    ptr_size: usize,
+) -> Option<(usize, usize)> {
+    let name = eprocess.get(eproc_name..eproc_name + 15)?;
+    debug!(
+        "calibrating peb on {:?}",
+        String::from_utf8_lossy(name.split(|&c| c == 0).next().unwrap_or_default())
+    );
+
+    let user_limit: umem = if ptr_size == 8 {
+        0x8000_0000_0000
+    } else {
+        0x8000_0000
+    };
+    let user_ptrs = (0..eprocess.len())
+        .step_by(ptr_size)
+        .filter_map(|offset| Some((offset, read_ptr(eprocess, offset, ptr_size)?)))
+        .filter(|(_, v)| *v != 0 && *v < user_limit && *v as usize % ptr_size == 0)
+        .collect::<Vec<_>>();
+
+    user_ptrs.iter().find_map(|&(peb_offset, peb)| {
+        let mut buf = [0u8; 8];
+        proc_mem
+            .read_raw_into(Address::from(peb) + 2 * ptr_size, &mut buf[..ptr_size])
+            .ok()?;
+        let image_base = u64::from_le_bytes(buf) as umem;
+        if image_base == 0 || image_base % 0x10000 != 0 {
+            return None;
+        }
+
+        user_ptrs
+            .iter()
+            .find(|&&(offset, v)| offset != peb_offset && v == image_base)
+            .map(|&(section_base_offset, _)| (section_base_offset, peb_offset))
+    })
+}
@@ -0,0 +1,400 @@
+/*!
+Module for reading the clipboard of a target.
+
+The clipboard is owned by the window station (`tagWINDOWSTATION`), the formats are kept in the
+`pClipBase` array of `tagCLIP` entries. The data of a format is a user object (`tagCLIPDATA`)
+that is referenced by a handle into the user handle table (`gSharedInfo.aheList`).
+
+All of these structures live in the session space of win32k. Just like the
+[`Win32Keyboard`](super::Win32Keyboard) a process of the interactive session (e.g. `explorer.exe`)
+is used as a proxy to read them.
+The symbols and types are resolved from the pdbs of the win32k modules which are loaded from the
+symbol store of the kernel (see [`Win32Kernel::set_symbol_store`](super::Win32Kernel::set_symbol_store)).
+Newer versions of nt 10.0 moved the window station list into a per session state
+and do not publish the window station type anymore, these are not supported.
+
+Formats whose data has not been rendered yet (delayed rendering) and formats that are backed
+by gdi objects (e.g. `CF_BITMAP`) are listed without data.
+
+This module is only available with the `symstore` feature enabled.
+
+# Examples:
+
+```
+use memflow::prelude::v1::*;
+use memflow_win32::win32::Win32Kernel;
+
+fn test<T: 'static + PhysicalMemory + Clone, V: 'static + VirtualTranslate2 + Clone>(
+    kernel: &mut Win32Kernel<T, V>,
+) {
+    let clipboard = kernel.clipboard().unwrap();
+    for format in clipboard.formats.iter() {
+        println!("{} {:?}", format.format, format.name());
+    }
+    if let Some(text) = clipboard.text() {
+        println!("{}", text);
+    }
+}
+```
+*/
+use std::prelude::v1::*;
+
+use std::convert::TryInto;
+
+use super::{Win32Kernel, Win32KernelSymbols, Win32KernelTypes, Win32ModuleInfo};
+use crate::offsets::SymbolStore;
+
+use log::{debug, trace};
+
+use memflow::architecture::ArchitectureObj;
+use memflow::error::{Error, ErrorKind, ErrorOrigin, Result};
+use memflow::mem::{MemoryView, PhysicalMemory, VirtualTranslate2};
+use memflow::os::{ModuleInfo, Os};
+use memflow::types::{umem, Address};
+
+pub const CF_TEXT: u32 = 1;
+pub const CF_BITMAP: u32 = 2;
+pub const CF_OEMTEXT: u32 = 7;
+pub const CF_DIB: u32 = 8;
+pub const CF_UNICODETEXT: u32 = 13;
+pub const CF_HDROP: u32 = 15;
+pub const CF_LOCALE: u32 = 16;
+pub const CF_DIBV5: u32 = 17;
+
+/// Names of the predefined clipboard formats
+const FORMAT_NAMES: &[(u32, &str)] = &[
+    (CF_TEXT, "CF_TEXT"),
+    (CF_BITMAP, "CF_BITMAP"),
+    (3, "CF_METAFILEPICT"),
+    (4, "CF_SYLK"),
+    (5, "CF_DIF"),
+    (6, "CF_TIFF"),
+    (CF_OEMTEXT, "CF_OEMTEXT"),
+    (CF_DIB, "CF_DIB"),
+    (9, "CF_PALETTE"),
+    (10, "CF_PENDATA"),
+    (11, "CF_RIFF"),
+    (12, "CF_WAVE"),
+    (CF_UNICODETEXT, "CF_UNICODETEXT"),
+    (14, "CF_ENHMETAFILE"),
+    (CF_HDROP, "CF_HDROP"),
+    (CF_LOCALE, "CF_LOCALE"),
+    (CF_DIBV5, "CF_DIBV5"),
+];
+
+const WIN32K_MODULES: &[&str] = &["win32kbase.sys", "win32kfull.sys", "win32k.sys"];
+
+/// `TYPE_CLIPDATA` in the user handle table
+const TYPE_CLIPDATA: u8 = 6;
+/// Handle of formats that use delayed rendering
+const DUMMY_HANDLE: umem = 1;
+/// Upper bound for the number of window stations in a session
+const MAX_WINDOW_STATIONS: usize = 64;
+/// Upper bound for the number of formats of a clipboard
+const MAX_CLIP_FORMATS: usize = 256;
+/// Upper bound for the size of the data of a format
+const MAX_CLIP_DATA_SIZE: usize = 0x100_0000;
+
+/// A format that is available on the clipboard
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize))]
+pub struct Win32ClipboardFormat {
+    /// Predefined (`CF_*`) or registered format id
+    pub format: u32,
+    /// User handle of the data
+    pub handle: Address,
+    /// `None` if the data is not rendered yet or is not backed by a `tagCLIPDATA`
+    pub data: Option<Vec<u8>>,
+}
+
+impl Win32ClipboardFormat {
+    /// Returns the name of a predefined format.
+    ///
+    /// Names of registered formats are kept in the atom table and are not resolved.
+    pub fn name(&self) -> Option<&'static str> {
+        FORMAT_NAMES
+            .iter()
+            .find(|(format, _)| *format == self.format)
+            .map(|(_, name)| *name)
+    }
+
+    /// Decodes the data of the text formats.
+    pub fn text(&self) -> Option<String> {
+        let data = self.data.as_ref()?;
+        match self.format {
+            CF_UNICODETEXT => {
+                let text = data
+                    .chunks_exact(2)
+                    .map(|c| u16::from_le_bytes(c.try_into().unwrap()))
+                    .take_while(|&c| c != 0)
+                    .collect::<Vec<_>>();
+                Some(String::from_utf16_lossy(&text))
+            }
+            CF_TEXT | CF_OEMTEXT => {
+                let len = data.iter().position(|&c| c == 0).unwrap_or(data.len());
+                Some(String::from_utf8_lossy(&data[..len]).into_owned())
+            }
+            _ => None,
+        }
+    }
+
+    /// Converts the data of the device independent bitmap formats into a bmp file.
+    pub fn bitmap(&self) -> Option<Vec<u8>> {
+        let data = self.data.as_ref()?;
+        if self.format != CF_DIB && self.format != CF_DIBV5 {
+            return None;
+        }
+
+        // BITMAPINFOHEADER
+        let header_size = u32::from_le_bytes(data.get(0..4)?.try_into().unwrap());
+        let bit_count = u16::from_le_bytes(data.get(14..16)?.try_into().unwrap());
+        let compression = u32::from_le_bytes(data.get(16..20)?.try_into().unwrap());
+        let colors_used = u32::from_le_bytes(data.get(32..36)?.try_into().unwrap());
+        // BI_BITFIELDS masks follow the BITMAPINFOHEADER
+        let masks = if compression == 3 && header_size == 40 {
+            12
+        } else {
+            0
+        };
+        let colors = match (colors_used, bit_count) {
+            (0, bits) if bits <= 8 => 1 << bits,
+            (colors, _) => colors as u64,
+        };
+        let bits_offset = (14 + header_size as u64 + masks + colors * 4) as u32;
+
+        // BITMAPFILEHEADER
+        let mut out = Vec::with_capacity(14 + data.len());
+        out.extend_from_slice(b"BM");
+        out.extend_from_slice(&(14 + data.len() as u32).to_le_bytes());
+        out.extend_from_slice(&[0u8; 4]);
+        out.extend_from_slice(&bits_offset.to_le_bytes());
+        out.extend_from_slice(data);
+        Some(out)
+    }
+}
+
+/// Clipboard of a window station
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize))]
+pub struct Win32Clipboard {
+    /// Address of the `tagWINDOWSTATION`
+    pub window_station: Address,
+    /// Incremented on every change of the clipboard, 0 if not available
+    pub sequence_number: u32,
+    pub formats: Vec<Win32ClipboardFormat>,
+}
+
+impl Win32Clipboard {
+    /// Returns the format with the given id.
+    pub fn format(&self, format: u32) -> Option<&Win32ClipboardFormat> {
+        self.formats.iter().find(|f| f.format == format)
+    }
+
+    /// Returns the text on the clipboard, unicode text is preferred.
+    pub fn text(&self) -> Option<String> {
+        [CF_UNICODETEXT, CF_TEXT, CF_OEMTEXT]
+            .iter()
+            .find_map(|&format| self.format(format)?.text())
+    }
+
+    /// Returns the bitmap on the clipboard as a bmp file.
+    pub fn bitmap(&self) -> Option<Vec<u8>> {
+        [CF_DIBV5, CF_DIB]
+            .iter()
+            .find_map(|&format| self.format(format)?.bitmap())
+    }
+}
+
+impl<T: 'static + PhysicalMemory + Clone, V: 'static + VirtualTranslate2 + Clone>
+    Win32Kernel<T, V>
+{
+    /// Reads the clipboard of the interactive session.
+    ///
+    /// The pdbs of the win32k modules are loaded from the symbol store of the kernel.
+    /// An error is returned if the kernel has no symbol store.
+    pub fn clipboard(&mut self) -> Result<Win32Clipboard> {
+        let store = self.configured_symbol_store()?;
+        self.clipboard_from_store(&store)
+    }
+
+    /// Reads the clipboard of the interactive session.
+    ///
+    /// The pdbs of the win32k modules are loaded from the given symbol store.
+    /// The first window station with a non empty clipboard is returned.
+    pub fn clipboard_from_store(&mut self, store: &SymbolStore) -> Result<Win32Clipboard> {
+        let modules = self
+            .module_list()?
+            .into_iter()
+            .filter(|m| {
+                WIN32K_MODULES
+                    .iter()
+                    .any(|name| m.name.as_ref().eq_ignore_ascii_case(name))
+            })
+            .collect::<Vec<_>>();
+        if modules.is_empty() {
+            return Err(Error(ErrorOrigin::OsLayer, ErrorKind::ModuleNotFound)
+                .log_info("no win32k module is loaded"));
+        }
+
+        let proxy = self.interactive_session_proxy()?;
+        debug!("reading clipboard through proxy process {}", proxy.name);
+        let arch: ArchitectureObj = self.kernel_info.os_info.arch.into();
+        let mut process = self.process_by_info(proxy)?;
+        let mem = &mut process.virt_mem;
+
+        let mut pdbs = modules
+            .iter()
+            .filter_map(|module| match Win32kPdb::new(mem, module, store) {
+                Ok(pdb) => Some(pdb),
+                Err(err) => {
+                    debug!("unable to load the pdb of {}: {}", module.name, err);
+                    None
+                }
+            })
+            .collect::<Vec<_>>();
+
+        let offsets = ClipOffsets::new(&mut pdbs, arch)?;
+        let win_sta_list = find_symbol(&pdbs, "grpWinStaList")?;
+        let shared_info = find_symbol(&pdbs, "gSharedInfo")?;
+
+        let handle_table = mem.read_addr_arch(arch, shared_info + arch.size_addr())?;
+        // HeEntrySize exists since nt 6.1
+        let handle_entry_size = match mem.read::<u32>(shared_info + 2 * arch.size_addr()) {
+            Ok(size) if (3 * arch.size_addr()..0x100).contains(&(size as usize)) => size as usize,
+            _ => 3 * arch.size_addr(),
+        };
+        trace!(
+            "handle_table={:x} handle_entry_size={:x}",
+            handle_table,
+            handle_entry_size
+        );
+
+        let mut window_station = mem.read_addr_arch(arch, win_sta_list)?;
+        for _ in 0..MAX_WINDOW_STATIONS {
+            if window_station.is_null() {
+                break;
+            }
+
+            let clip_base = mem.read_addr_arch(arch, window_station + offsets.clip_base)?;
+            let clip_count = mem.read::<u32>(window_station + offsets.num_clip_formats)? as usize;
+            trace!(
+                "window station {:x}: {} clipboard formats",
+                window_station,
+                clip_count
+            );
+
+            if !clip_base.is_null() && clip_count > 0 {
+                let mut clipboard = Win32Clipboard {
+                    window_station,
+                    sequence_number: offsets
+                        .clip_sequence_number
+                        .and_then(|offset| mem.read(window_station + offset).ok())
+                        .unwrap_or_default(),
+                    formats: vec![],
+                };
+                for idx in 0..clip_count.min(MAX_CLIP_FORMATS) {
+                    let clip = clip_base + (idx * offsets.clip_size) as umem;
+                    let format = mem.read::<u32>(clip)?;
+                    let handle = mem.read_addr_arch(arch, clip + arch.size_addr())?;
+                    let data = read_clip_data(mem, arch, handle_table, handle_entry_size, handle);
+                    clipboard.formats.push(Win32ClipboardFormat {
+                        format,
+                        handle,
+                        data,
+                    });
+                }
+                return Ok(clipboard);
+            }
+
+            window_station = mem.read_addr_arch(arch, window_station + offsets.next)?;
+        }
+
+        Ok(Win32Clipboard::default())
+    }
+}
+
+/// Symbols and types of a win32k module
+struct Win32kPdb {
+    symbols: Win32KernelSymbols,
+    types: Win32KernelTypes,
+}
+
+impl Win32kPdb {
+    fn new<M: MemoryView>(mem: &mut M, module: &ModuleInfo, store: &SymbolStore) -> Result<Self> {
+        let pdb = module.pdb(mem, store)?;
+        Ok(Self {
+            symbols: Win32KernelSymbols::new(&pdb, module.base)?,
+            types: Win32KernelTypes::new(pdb),
+        })
+    }
+}
+
+fn find_symbol(pdbs: &[Win32kPdb], name: &str) -> Result<Address> {
+    pdbs.iter()
+        .find_map(|pdb| pdb.symbols.address(name).ok())
+        .ok_or_else(|| {
+            Error(ErrorOrigin::OsLayer, ErrorKind::NotSupported)
+                .log_info(format!("{} not found in the win32k pdbs", name))
+        })
+}
+
+/// Offsets of `tagWINDOWSTATION` and the size of `tagCLIP`
+struct ClipOffsets {
+    next: usize,
+    clip_base: usize,
+    num_clip_formats: usize,
+    clip_sequence_number: Option<usize>,
+    clip_size: usize,
+}
+
+impl ClipOffsets {
+    fn new(pdbs: &mut [Win32kPdb], arch: ArchitectureObj) -> Result<Self> {
+        let types = pdbs
+            .iter_mut()
+            .map(|pdb| &mut pdb.types)
+            .find(|types| types.has_struct("tagWINDOWSTATION"))
+            .ok_or_else(|| {
+                Error(ErrorOrigin::OsLayer, ErrorKind::NotSupported)
+                    .log_info("tagWINDOWSTATION is not available in the win32k pdbs")
+            })?;
+
+        Ok(Self {
+            next: types.offset_of("tagWINDOWSTATION.rpwinstaNext")?,
+            clip_base: types.offset_of("tagWINDOWSTATION.pClipBase")?,
+            num_clip_formats: types.offset_of("tagWINDOWSTATION.cNumClipFormats")?,
+            clip_sequence_number: types.offset_of("tagWINDOWSTATION.iClipSequenceNumber").ok(),
+            // fmt, hData and fGlobalHandle
+            clip_size: types.struct_size("tagCLIP").unwrap_or(3 * arch.size_addr()),
+        })
+    }
+}
+
+/// Resolves the handle of a format and reads its `tagCLIPDATA`.
+fn read_clip_data<M: MemoryView>(
+    mem: &mut M,
+    arch: ArchitectureObj,
+    handle_table: Address,
+    handle_entry_size: usize,
+    handle: Address,
+) -> Option<Vec<u8>> {
+    if handle.is_null() || handle.to_umem() == DUMMY_HANDLE {
+        return None;
+    }
+
+    // the low word of a user handle is the index into the handle table
+    let index = (handle.to_umem() & 0xffff) as usize;
+    let entry = handle_table + (index * handle_entry_size) as umem;
+    let object_type = mem.read::<u8>(entry + 2 * arch.size_addr()).ok()?;
+    if object_type != TYPE_CLIPDATA {
+        trace!("handle {:x} is not a clipboard data object", handle);
+        return None;
+    }
+
+    // HEAD is followed by cbData and the data
+    let clip_data = mem.read_addr_arch(arch, entry).ok()?;
+    let size = mem.read::<u32>(clip_data + 2 * arch.size_addr()).ok()? as usize;
+    let mut data = vec![0u8; size.min(MAX_CLIP_DATA_SIZE)];
+    mem.read_raw_into(clip_data + 2 * arch.size_addr() + 4, &mut data)
+        .ok()?;
+    Some(data)
+}
@@ -0,0 +1,522 @@
+/*!
+Module for detecting the .NET runtime in a process.
+
+Managed processes load either the .NET Framework runtime (`clr.dll`, `mscorwks.dll` for 2.0)
+or the .NET Core runtime (`coreclr.dll`). Every runtime embeds a `CLRDEBUGINFO` resource
+which contains the timestamp and image size of the matching data access component (dac),
+this is how debuggers fetch the dac of a runtime from the symbol server. The dac is required
+to interpret most runtime structures, e.g. managed objects, which is not done by this module.
+
+App domains are enumerated through the static domain pointers of the runtime which are
+resolved from the public symbols of its pdb (see `Win32ClrRuntime::app_domains`, requires the
+`symstore` feature).
+The .NET Framework keeps the system domain, the shared domain and a list of app domains,
+.NET Core only has the system domain and a single app domain.
+The names of the domains are not part of the public symbols and are therefore not resolved.
+
+Managed assemblies are found by looking for the clr header in the loaded modules.
+Assemblies that are mapped without registering them in the loader list
+(e.g. by .NET Core or from a byte array) are not found.
+
+# Examples:
+
+```
+use memflow::prelude::v1::*;
+use memflow_win32::win32::{Win32Process, Win32VirtualTranslate};
+
+fn test<T: PhysicalMemory, V: VirtualTranslate2>(process: &mut Win32Process<T, V, Win32VirtualTranslate>) {
+    let clr = process.clr_info().unwrap();
+    for runtime in clr.runtimes.iter() {
+        println!("{:?} {}", runtime.flavor, runtime.version.as_deref().unwrap_or("?"));
+    }
+    for assembly in clr.assemblies.iter() {
+        println!("{:x} {} ({})", assembly.module.base, assembly.module.name, assembly.runtime_version);
+    }
+}
+```
+*/
+use std::prelude::v1::*;
+
+use std::convert::TryInto;
+
+use super::{Win32ModuleInfo, Win32Process, Win32VirtualTranslate};
+use crate::kernel::ntos::lazy_pe::LazyPe;
+#[cfg(feature = "symstore")]
+use crate::offsets::{PdbSymbols, SymbolStore};
+
+use log::{debug, trace};
+
+#[cfg(feature = "symstore")]
+use memflow::architecture::ArchitectureObj;
+use memflow::error::Result;
+#[cfg(feature = "symstore")]
+use memflow::error::{Error, ErrorKind, ErrorOrigin, PartialResultExt};
+use memflow::mem::{MemoryView, PhysicalMemory, VirtualTranslate2};
+use memflow::os::{ModuleInfo, Process};
+#[cfg(feature = "symstore")]
+use memflow::types::umem;
+use memflow::types::Address;
+
+const RT_RCDATA: u32 = 10;
+const CLR_DEBUG_RESOURCE_NAME: &str = "CLRDEBUGINFO";
+const CLR_DEBUG_RESOURCE_SIZE: usize = 0x24;
+
+const METADATA_SIGNATURE: u32 = 0x424a_5342;
+/// Upper bound for the length of the runtime version in the metadata root
+const MAX_METADATA_VERSION_LEN: usize = 0x100;
+
+const COMIMAGE_FLAGS_ILONLY: u32 = 0x1;
+
+// undecorated names of the static domain pointers
+const SYSTEM_DOMAIN_SYMBOL: &str = "SystemDomain::m_pSystemDomain";
+const SHARED_DOMAIN_SYMBOL: &str = "SharedDomain::m_pSharedDomain";
+const APP_DOMAIN_LIST_SYMBOL: &str = "SystemDomain::m_appDomainIdList";
+const THE_APP_DOMAIN_SYMBOL: &str = "AppDomain::m_pTheAppDomain";
+
+/// Upper bound for the number of app domains of a runtime
+const MAX_APP_DOMAINS: usize = 0x1000;
+
+/// Kind of a .NET runtime
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize))]
+pub enum Win32ClrFlavor {
+    /// .NET Framework (`clr.dll`, `mscorwks.dll`)
+    Framework,
+    /// .NET Core and .NET 5+ (`coreclr.dll`)
+    Core,
+}
+
+impl Win32ClrFlavor {
+    /// Returns the file name of the data access component of this runtime.
+    pub fn dac_file_name(&self) -> &'static str {
+        match self {
+            Self::Framework => "mscordacwks.dll",
+            Self::Core => "mscordaccore.dll",
+        }
+    }
+}
+
+/// Identification of the dac and dbi that match a runtime
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize))]
+pub struct Win32ClrDebugInfo {
+    pub dac_time_date_stamp: u32,
+    pub dac_size_of_image: u32,
+    pub dbi_time_date_stamp: u32,
+    pub dbi_size_of_image: u32,
+}
+
+/// A .NET runtime loaded in a process
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize))]
+pub struct Win32ClrRuntime {
+    pub module: ModuleInfo,
+    pub flavor: Win32ClrFlavor,
+    /// File version of the runtime module
+    pub version: Option<String>,
+    /// `None` if the `CLRDEBUGINFO` resource could not be read
+    pub debug_info: Option<Win32ClrDebugInfo>,
+}
+
+impl Win32ClrRuntime {
+    /// Loads the dac that matches this runtime from the given symbol store.
+    #[cfg(feature = "symstore")]
+    pub fn load_dac(&self, store: &SymbolStore) -> Result<Vec<u8>> {
+        let debug_info = self.debug_info.ok_or_else(|| {
+            Error(ErrorOrigin::OsLayer, ErrorKind::NotFound)
+                .log_info("the debug resource of the runtime is not available")
+        })?;
+        store.load_binary(
+            self.flavor.dac_file_name(),
+            debug_info.dac_time_date_stamp,
+            debug_info.dac_size_of_image,
+        )
+    }
+
+    /// Enumerates the app domains of this runtime.
+    ///
+    /// The static domain pointers are resolved through the pdb of the runtime which is loaded
+    /// from the given symbol store. Slots of unloaded app domains are skipped.
+    #[cfg(feature = "symstore")]
+    pub fn app_domains<M: MemoryView>(
+        &self,
+        mem: &mut M,
+        store: &SymbolStore,
+    ) -> Result<Vec<Win32ClrAppDomain>> {
+        let pdb = self.module.pdb(mem, store)?;
+        let symbols = PdbSymbols::new(&pdb).map_err(|_| {
+            Error(ErrorOrigin::OsLayer, ErrorKind::InvalidExeFile)
+                .log_info("unable to parse symbols of the runtime pdb")
+        })?;
+
+        read_app_domains(mem, self.module.arch.into(), self.module.base, |name| {
+            symbols.find_symbol_undecorated(name)
+        })
+    }
+}
+
+/// Kind of an app domain
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize))]
+pub enum Win32ClrAppDomainKind {
+    /// Domain of the runtime itself (`SystemDomain`)
+    System,
+    /// Domain of the domain neutral assemblies of the .NET Framework (`SharedDomain`)
+    Shared,
+    /// Domain that runs managed code (`AppDomain`)
+    App,
+}
+
+/// An app domain of a runtime
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize))]
+pub struct Win32ClrAppDomain {
+    pub kind: Win32ClrAppDomainKind,
+    /// Address of the domain object
+    pub address: Address,
+    /// Id of an app domain, the ids of app domains start at 1
+    pub id: Option<u32>,
+}
+
+/// A managed module in the loader list
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize))]
+pub struct Win32ManagedAssembly {
+    pub module: ModuleInfo,
+    /// Runtime version the assembly was built against, e.g. `v4.0.30319`
+    pub runtime_version: String,
+    /// The assembly only contains il code and no native code
+    pub il_only: bool,
+}
+
+/// Runtimes and managed assemblies of a process
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize))]
+pub struct Win32ClrInfo {
+    pub runtimes: Vec<Win32ClrRuntime>,
+    pub assemblies: Vec<Win32ManagedAssembly>,
+}
+
+impl Win32ClrInfo {
+    /// Returns true if a .NET runtime is loaded.
+    pub fn is_managed(&self) -> bool {
+        !self.runtimes.is_empty()
+    }
+}
+
+impl<T: PhysicalMemory, V: VirtualTranslate2> Win32Process<T, V, Win32VirtualTranslate> {
+    /// Detects the .NET runtimes and the managed assemblies that are loaded in the process.
+    pub fn clr_info(&mut self) -> Result<Win32ClrInfo> {
+        let modules = self.module_list()?;
+
+        let mut info = Win32ClrInfo::default();
+        for module in modules.into_iter() {
+            if let Some(flavor) = runtime_flavor(module.name.as_ref()) {
+                info.runtimes.push(Win32ClrRuntime {
+                    version: module
+                        .version_info(self)
+                        .ok()
+                        .and_then(|version| version.file_version),
+                    debug_info: read_debug_info(self, &module),
+                    flavor,
+                    module,
+                });
+            } else if let Some((runtime_version, il_only)) = read_clr_header(self, &module) {
+                trace!("found managed assembly {}", module.name);
+                info.assemblies.push(Win32ManagedAssembly {
+                    module,
+                    runtime_version,
+                    il_only,
+                });
+            }
+        }
+
+        debug!(
+            "found {} runtimes and {} managed assemblies",
+            info.runtimes.len(),
+            info.assemblies.len()
+        );
+        Ok(info)
+    }
+}
+
+fn runtime_flavor(name: &str) -> Option<Win32ClrFlavor> {
+    if name.eq_ignore_ascii_case("coreclr.dll") {
+        Some(Win32ClrFlavor::Core)
+    } else if name.eq_ignore_ascii_case("clr.dll") || name.eq_ignore_ascii_case("mscorwks.dll") {
+        Some(Win32ClrFlavor::Framework)
+    } else {
+        None
+    }
+}
+
+/// Reads the `CLR_DEBUG_RESOURCE` of a runtime module.
+fn read_debug_info<M: MemoryView>(mem: &mut M, module: &ModuleInfo) -> Option<Win32ClrDebugInfo> {
+    let resource = LazyPe::new(mem, module.base)
+        .ok()?
+        .resource(
+            RT_RCDATA,
+            Some(CLR_DEBUG_RESOURCE_NAME),
+            CLR_DEBUG_RESOURCE_SIZE,
+        )
+        .map_err(|err| {
+            debug!(
+                "unable to read the debug resource of {}: {}",
+                module.name, err
+            )
+        })
+        .ok()?;
+
+    // the version is followed by the signature guid
+    if read_u32(&resource, 0)? != 0 {
+        return None;
+    }
+    Some(Win32ClrDebugInfo {
+        dac_time_date_stamp: read_u32(&resource, 0x14)?,
+        dac_size_of_image: read_u32(&resource, 0x18)?,
+        dbi_time_date_stamp: read_u32(&resource, 0x1c)?,
+        dbi_size_of_image: read_u32(&resource, 0x20)?,
+    })
+}
+
+/// Reads the domains referenced by the static domain pointers of a runtime.
+///
+/// `symbol` resolves the rva of the symbol with the given undecorated name.
+#[cfg(feature = "symstore")]
+fn read_app_domains<M: MemoryView>(
+    mem: &mut M,
+    arch: ArchitectureObj,
+    base: Address,
+    symbol: impl Fn(&str) -> Option<u32>,
+) -> Result<Vec<Win32ClrAppDomain>> {
+    let mut read_ptr = |rva: u32| {
+        mem.read_addr_arch(arch, base + rva as umem)
+            .data_part()
+            .ok()
+            .filter(|address| !address.is_null())
+    };
+
+    let mut domains = vec![];
+    for (name, kind) in [
+        (SYSTEM_DOMAIN_SYMBOL, Win32ClrAppDomainKind::System),
+        (SHARED_DOMAIN_SYMBOL, Win32ClrAppDomainKind::Shared),
+    ] {
+        if let Some(address) = symbol(name).and_then(&mut read_ptr) {
+            domains.push(Win32ClrAppDomain {
+                kind,
+                address,
+                id: None,
+            });
+        }
+    }
+
+    if let Some(rva) = symbol(THE_APP_DOMAIN_SYMBOL) {
+        // .NET Core only has a single app domain
+        if let Some(address) = read_ptr(rva) {
+            domains.push(Win32ClrAppDomain {
+                kind: Win32ClrAppDomainKind::App,
+                address,
+                id: Some(1),
+            });
+        }
+    } else if let Some(rva) = symbol(APP_DOMAIN_LIST_SYMBOL) {
+        // the index in the list is the id of the app domain minus one
+        let entries = read_array_list(mem, arch, base + rva as umem)?;
+        domains.extend(entries.into_iter().enumerate().filter_map(|(i, address)| {
+            Some(Win32ClrAppDomain {
+                kind: Win32ClrAppDomainKind::App,
+                address: address?,
+                id: Some(i as u32 + 1),
+            })
+        }));
+    }
+
+    if domains.is_empty() {
+        return Err(Error(ErrorOrigin::OsLayer, ErrorKind::NotFound)
+            .log_info("unable to find the app domains of the runtime"));
+    }
+    Ok(domains)
+}
+
+/// Reads the entries of an `ArrayListStatic`.
+///
+/// The list consists of the number of entries followed by a chain of blocks.
+/// Every block holds the pointer to the next block, its size and the entries.
+#[cfg(feature = "symstore")]
+fn read_array_list<M: MemoryView>(
+    mem: &mut M,
+    arch: ArchitectureObj,
+    list: Address,
+) -> Result<Vec<Option<Address>>> {
+    let ptr_size = arch.size_addr();
+    let count = (mem.read::<u32>(list).data_part()? as usize).min(MAX_APP_DOMAINS);
+
+    let mut entries = Vec::with_capacity(count);
+    let mut block = list + ptr_size;
+    while entries.len() < count && !block.is_null() {
+        let next = mem.read_addr_arch(arch, block).data_part()?;
+        let block_size = mem.read::<u32>(block + ptr_size).data_part()? as usize;
+        let len = block_size.min(count - entries.len());
+        if len == 0 {
+            break;
+        }
+
+        // the entries are aligned to the pointer size
+        let mut buf = vec![0u8; len * ptr_size];
+        mem.read_raw_into(block + 2 * ptr_size, &mut buf)
+            .data_part()?;
+        entries.extend(buf.chunks_exact(ptr_size).map(|c| {
+            let address = match ptr_size {
+                8 => Address::from(u64::from_le_bytes(c.try_into().unwrap())),
+                _ => Address::from(u32::from_le_bytes(c.try_into().unwrap())),
+            };
+            (!address.is_null()).then_some(address)
+        }));
+
+        block = next;
+    }
+    Ok(entries)
+}
+
+/// Returns the runtime version of the metadata root and whether the image is il only.
+fn read_clr_header<M: MemoryView>(mem: &mut M, module: &ModuleInfo) -> Option<(String, bool)> {
+    let mut pe = LazyPe::new(mem, module.base).ok()?;
+    let (metadata_rva, metadata_size, flags) = pe.clr_header().ok()?;
+
+    let root = pe
+        .read_rva(
+            metadata_rva,
+            (metadata_size as usize).min(0x10 + MAX_METADATA_VERSION_LEN),
+        )
+        .ok()?;
+    if read_u32(&root, 0)? != METADATA_SIGNATURE {
+        return None;
+    }
+
+    let len = (read_u32(&root, 0xc)? as usize).min(MAX_METADATA_VERSION_LEN);
+    let version = root.get(0x10..0x10 + len)?;
+    let version = &version[..version.iter().position(|&c| c == 0).unwrap_or(len)];
+    Some((
+        String::from_utf8_lossy(version).into_owned(),
+        flags & COMIMAGE_FLAGS_ILONLY != 0,
+    ))
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> Option<u32> {
+    buf.get(offset..offset + 4)
+        .map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+}
+
+#[cfg(all(test, feature = "symstore"))]
+mod tests {
+    use super::*;
+
+    use memflow::architecture::ArchitectureIdent;
+    use memflow::dummy::DummyMemory;
+    use memflow::types::size;
+
+    fn x64() -> ArchitectureObj {
+        ArchitectureIdent::X86(64, false).into()
+    }
+
+    fn write_ptr<M: MemoryView>(mem: &mut M, address: u64, value: u64) {
+        mem.write(Address::from(address), &value).unwrap();
+    }
+
+    #[test]
+    fn core_domains() {
+        let mut mem = DummyMemory::new(size::mb(1));
+        let mut view = mem.phys_view();
+        write_ptr(&mut view, 0x10100, 0x2_0000);
+        write_ptr(&mut view, 0x10108, 0x3_0000);
+
+        let symbol = |name: &str| match name {
+            SYSTEM_DOMAIN_SYMBOL => Some(0x100),
+            THE_APP_DOMAIN_SYMBOL => Some(0x108),
+            _ => None,
+        };
+        assert_eq!(
+            read_app_domains(&mut view, x64(), Address::from(0x1_0000u64), symbol).unwrap(),
+            vec![
+                Win32ClrAppDomain {
+                    kind: Win32ClrAppDomainKind::System,
+                    address: Address::from(0x2_0000u64),
+                    id: None,
+                },
+                Win32ClrAppDomain {
+                    kind: Win32ClrAppDomainKind::App,
+                    address: Address::from(0x3_0000u64),
+                    id: Some(1),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn framework_domains() {
+        let mut mem = DummyMemory::new(size::mb(1));
+        let mut view = mem.phys_view();
+        write_ptr(&mut view, 0x10100, 0x2_0000);
+        write_ptr(&mut view, 0x10108, 0x2_1000);
+
+        // 3 app domains, the second one has been unloaded and the third one is in the second block
+        let list = 0x10200;
+        view.write(Address::from(list), &3u32).unwrap();
+        write_ptr(&mut view, list + 8, 0x4_0000);
+        view.write(Address::from(list + 0x10), &2u32).unwrap();
+        write_ptr(&mut view, list + 0x18, 0x3_0000);
+        write_ptr(&mut view, list + 0x20, 0);
+        write_ptr(&mut view, 0x4_0000, 0);
+        view.write(Address::from(0x4_0008u64), &4u32).unwrap();
+        write_ptr(&mut view, 0x4_0010, 0x3_2000);
+
+        let symbol = |name: &str| match name {
+            SYSTEM_DOMAIN_SYMBOL => Some(0x100),
+            SHARED_DOMAIN_SYMBOL => Some(0x108),
+            APP_DOMAIN_LIST_SYMBOL => Some(0x200),
+            _ => None,
+        };
+        let domains =
+            read_app_domains(&mut view, x64(), Address::from(0x1_0000u64), symbol).unwrap();
+        assert_eq!(
+            domains
+                .iter()
+                .map(|d| (d.kind, d.address.to_umem(), d.id))
+                .collect::<Vec<_>>(),
+            vec![
+                (Win32ClrAppDomainKind::System, 0x2_0000, None),
+                (Win32ClrAppDomainKind::Shared, 0x2_1000, None),
+                (Win32ClrAppDomainKind::App, 0x3_0000, Some(1)),
+                (Win32ClrAppDomainKind::App, 0x3_2000, Some(3)),
+            ]
+        );
+    }
+
+    #[test]
+    fn array_list_is_bounded() {
+        let mut mem = DummyMemory::new(size::mb(1));
+        let mut view = mem.phys_view();
+
+        // a block that links to itself
+        let list = 0x1000;
+        view.write(Address::from(list), &u32::MAX).unwrap();
+        write_ptr(&mut view, list + 8, list + 8);
+        view.write(Address::from(list + 0x10), &0x100u32).unwrap();
+
+        let entries = read_array_list(&mut view, x64(), Address::from(list)).unwrap();
+        assert_eq!(entries.len(), MAX_APP_DOMAINS);
+    }
+
+    #[test]
+    fn no_domains() {
+        let mut mem = DummyMemory::new(size::mb(1));
+        let mut view = mem.phys_view();
+        assert!(read_app_domains(&mut view, x64(), Address::from(0x1_0000u64), |_| None).is_err());
+        assert!(
+            read_app_domains(&mut view, x64(), Address::from(0x1_0000u64), |name| {
+                (name == THE_APP_DOMAIN_SYMBOL).then_some(0x100)
+            })
+            .is_err()
+        );
+    }
+}
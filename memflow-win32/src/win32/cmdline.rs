@@ -0,0 +1,440 @@
+/*!
+Module for recovering the command line of processes that have already exited.
+
+Once a process exits its address space is torn down and the command line can no longer be read
+from the process parameters in the PEB. As long as the `_EPROCESS` is still referenced a best
+effort recovery is attempted from the following sources (in order):
+- the process parameters that are still readable through stale or transitioning pages of the old address space
+- the image path recorded for auditing in `_EPROCESS::SeAuditProcessCreationInfo`
+- the truncated image file name stored in the `_EPROCESS`
+
+If the process parameters have been freed they might still be found in physical memory.
+[`Win32Kernel::carve_cmdline`] scans all of physical memory for normalized
+`RTL_USER_PROCESS_PARAMETERS` whose image path matches the image name of the process.
+Since this requires a full pass over physical memory it is not part of
+[`Win32Kernel::best_effort_cmdline`] and has to be invoked explicitly.
+The structures kept by CSRSS are not used as a source yet.
+
+Every result carries a confidence score between 0 and 1 reflecting how closely the source resembles
+the original command line.
+
+# Examples:
+
+```
+use memflow::prelude::v1::*;
+use memflow_win32::win32::Win32Kernel;
+
+fn test<T: 'static + PhysicalMemory + Clone, V: 'static + VirtualTranslate2 + Clone>(
+    kernel: &mut Win32Kernel<T, V>,
+) {
+    for info in kernel.process_info_list().unwrap() {
+        if let ProcessState::Dead(_) = info.state {
+            let info = kernel.process_info_from_base_info(info).unwrap();
+            let cmdline = kernel.best_effort_cmdline(&info).unwrap();
+            println!("{} ({:.2})", cmdline.command_line, cmdline.confidence);
+        }
+    }
+}
+```
+*/
+use std::prelude::v1::*;
+
+use std::convert::TryInto;
+
+use super::{
+    decode_utf16_lossy, VirtualReadUnicodeString, Win32Kernel, Win32Process, Win32ProcessInfo,
+    Win32UnicodeString,
+};
+
+use crate::offsets::Win32ArchOffsets;
+use crate::progress::{CancellationToken, Progress};
+
+use memflow::architecture::{ArchitectureIdent, ArchitectureObj};
+use memflow::error::{Error, ErrorKind, ErrorOrigin, Result};
+use memflow::mem::{MemoryView, PhysicalMemory, VirtualTranslate2};
+use memflow::os::Os;
+use memflow::types::{size, umem, Address, PhysicalAddress};
+
+use log::{debug, info};
+
+/// `RTL_USER_PROC_PARAMS_NORMALIZED`, the string buffers are pointers instead of offsets
+const PARAMS_NORMALIZED: u32 = 0x1;
+/// Upper bound for the size of carved process parameters
+const MAX_PARAMS_SIZE: usize = 0x40000;
+
+/// Source a command line has been recovered from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize))]
+pub enum Win32CommandLineSource {
+    /// Process parameters of a running process
+    ProcessParameters,
+    /// Process parameters read from stale pages of an exited process
+    StaleProcessParameters,
+    /// Process parameters carved from freed memory
+    CarvedProcessParameters,
+    /// Image path from `_EPROCESS::SeAuditProcessCreationInfo`
+    AuditImagePath,
+    /// Truncated image file name from `_EPROCESS::ImageFileName`
+    ImageFileName,
+}
+
+impl Win32CommandLineSource {
+    /// Confidence that a command line recovered from this source matches the original one.
+    pub fn confidence(&self) -> f32 {
+        match self {
+            Win32CommandLineSource::ProcessParameters => 1.0,
+            Win32CommandLineSource::StaleProcessParameters => 0.7,
+            Win32CommandLineSource::CarvedProcessParameters => 0.5,
+            Win32CommandLineSource::AuditImagePath => 0.4,
+            Win32CommandLineSource::ImageFileName => 0.1,
+        }
+    }
+}
+
+/// A recovered command line
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize))]
+pub struct Win32RecoveredCommandLine {
+    pub command_line: String,
+    pub source: Win32CommandLineSource,
+    /// Confidence between 0 and 1
+    pub confidence: f32,
+}
+
+impl Win32RecoveredCommandLine {
+    fn new(command_line: String, source: Win32CommandLineSource) -> Self {
+        Self {
+            command_line,
+            source,
+            confidence: source.confidence(),
+        }
+    }
+}
+
+impl<T: 'static + PhysicalMemory + Clone, V: 'static + VirtualTranslate2 + Clone>
+    Win32Kernel<T, V>
+{
+    /// Returns the command line of the process or the best approximation that can be recovered.
+    pub fn best_effort_cmdline(
+        &mut self,
+        info: &Win32ProcessInfo,
+    ) -> Result<Win32RecoveredCommandLine> {
+        if !info.base_info.command_line.is_empty() {
+            return Ok(Win32RecoveredCommandLine::new(
+                info.base_info.command_line.to_string(),
+                Win32CommandLineSource::ProcessParameters,
+            ));
+        }
+
+        match self.stale_cmdline(info) {
+            Ok(command_line) => {
+                return Ok(Win32RecoveredCommandLine::new(
+                    command_line,
+                    Win32CommandLineSource::StaleProcessParameters,
+                ))
+            }
+            Err(err) => debug!("unable to read stale process parameters: {}", err),
+        }
+
+        match self.audit_image_path(info) {
+            Ok(path) => {
+                return Ok(Win32RecoveredCommandLine::new(
+                    format!("\"{}\"", path),
+                    Win32CommandLineSource::AuditImagePath,
+                ))
+            }
+            Err(err) => debug!("unable to read audit image path: {}", err),
+        }
+
+        if info.base_info.name.is_empty() {
+            return Err(Error(ErrorOrigin::OsLayer, ErrorKind::NotFound)
+                .log_info("unable to recover the command line"));
+        }
+
+        Ok(Win32RecoveredCommandLine::new(
+            info.base_info.name.to_string(),
+            Win32CommandLineSource::ImageFileName,
+        ))
+    }
+
+    /// Carves the command line of the process from freed process parameters in physical memory.
+    ///
+    /// Candidates are matched by the image name of the process, if several instances of the same
+    /// image have been running the command line of any of them may be returned.
+    /// The progress is reported in bytes of physical memory and the cancellation token is checked after every chunk.
+    pub fn carve_cmdline(
+        &mut self,
+        info: &Win32ProcessInfo,
+        progress: Option<&Progress>,
+        cancellation: Option<&CancellationToken>,
+    ) -> Result<Win32RecoveredCommandLine> {
+        if info.base_info.name.is_empty() {
+            return Err(Error(ErrorOrigin::OsLayer, ErrorKind::NotFound)
+                .log_info("process has no image name to match carved process parameters"));
+        }
+
+        let arch = info.base_info.sys_arch;
+        let mem = self.virt_mem.phys_mem();
+        let max_address = mem.metadata().max_address.to_umem();
+        if let Some(progress) = progress {
+            progress.start(
+                "scanning physical memory for process parameters",
+                max_address as u64,
+            );
+        }
+
+        let mut result = Err(Error(ErrorOrigin::OsLayer, ErrorKind::NotFound)
+            .log_trace("unable to carve the process parameters from physical memory"));
+        // the chunks overlap so parameters crossing a chunk boundary are found as well
+        let mut chunk = vec![0; size::mb(2) + MAX_PARAMS_SIZE];
+        let mut base: umem = 0;
+        while base < max_address {
+            if let Some(cancellation) = cancellation {
+                if let Err(err) = cancellation.check() {
+                    result = Err(err);
+                    break;
+                }
+            }
+            if let Some(progress) = progress {
+                progress.update(base as u64);
+            }
+
+            if mem
+                .phys_read_into(PhysicalAddress::from(base), chunk.as_mut_slice())
+                .is_ok()
+            {
+                if let Some(command_line) =
+                    carve_in_chunk(&chunk, size::mb(2), arch, info.base_info.name.as_ref())
+                {
+                    info!("carved process parameters found at {:x}", base);
+                    result = Ok(Win32RecoveredCommandLine::new(
+                        command_line,
+                        Win32CommandLineSource::CarvedProcessParameters,
+                    ));
+                    break;
+                }
+            }
+
+            base += size::mb(2) as umem;
+        }
+
+        if let Some(progress) = progress {
+            progress.finish();
+        }
+        result
+    }
+
+    /// Reads the command line from the process parameters through the old address space.
+    fn stale_cmdline(&mut self, info: &Win32ProcessInfo) -> Result<String> {
+        let peb = info.peb().ok_or_else(|| {
+            Error(ErrorOrigin::OsLayer, ErrorKind::NotFound).log_trace("process has no peb")
+        })?;
+
+        let proc_arch = info.base_info.proc_arch;
+        let offsets = Win32ArchOffsets::from(proc_arch);
+
+        let mut process = Win32Process::with_kernel_ref(self, info.clone());
+        let process_params = process
+            .read_addr_arch(proc_arch.into(), peb + offsets.peb_process_params)?
+            .non_null()
+            .ok_or_else(|| {
+                Error(ErrorOrigin::OsLayer, ErrorKind::NotFound)
+                    .log_trace("process parameters have been freed")
+            })?;
+
+        let command_line = process
+            .read_unicode_string(proc_arch.into(), process_params + offsets.ppm_command_line)?;
+        if is_plausible(&command_line) {
+            Ok(command_line)
+        } else {
+            Err(Error(ErrorOrigin::OsLayer, ErrorKind::InvalidProcessInfo)
+                .log_trace("stale command line is not plausible"))
+        }
+    }
+
+    /// Reads the image path that is kept for auditing purposes.
+    fn audit_image_path(&mut self, info: &Win32ProcessInfo) -> Result<String> {
+        if self.offsets.eproc_audit_image_name() == 0 {
+            return Err(Error(ErrorOrigin::OsLayer, ErrorKind::NotSupported)
+                .log_trace("_EPROCESS::SeAuditProcessCreationInfo is not available"));
+        }
+
+        let arch = self.kernel_info.os_info.arch.into();
+        let name_info = self
+            .virt_mem
+            .read_addr_arch(
+                arch,
+                info.base_info.address + self.offsets.eproc_audit_image_name(),
+            )?
+            .non_null()
+            .ok_or_else(|| {
+                Error(ErrorOrigin::OsLayer, ErrorKind::NotFound)
+                    .log_trace("no audit image name recorded")
+            })?;
+
+        // OBJECT_NAME_INFORMATION only consists of a UNICODE_STRING
+        let path = self.virt_mem.read_unicode_string(arch, name_info)?;
+        if is_plausible(&path) {
+            Ok(path)
+        } else {
+            Err(Error(ErrorOrigin::OsLayer, ErrorKind::InvalidProcessInfo)
+                .log_trace("audit image path is not plausible"))
+        }
+    }
+}
+
+/// Stale memory might have been reused, reject strings with control characters.
+fn is_plausible(value: &str) -> bool {
+    !value.is_empty() && !value.chars().any(|c| c.is_control() && c != '\t')
+}
+
+/// Searches the first `len` bytes of a page aligned chunk for normalized process parameters
+/// of the given image and returns their command line.
+fn carve_in_chunk(chunk: &[u8], len: usize, arch: ArchitectureIdent, name: &str) -> Option<String> {
+    (0..len.min(chunk.len()))
+        .step_by(8)
+        .find_map(|offset| carve_params(chunk, offset, arch, name))
+}
+
+/// Parses normalized process parameters at the given offset of a page aligned chunk.
+///
+/// The string buffers are allocated together with the parameters, the virtual address
+/// of the parameters is derived from the lowest buffer and the page offset of the candidate.
+/// The pages of the allocation are assumed to be physically contiguous.
+fn carve_params(
+    chunk: &[u8],
+    offset: usize,
+    arch: ArchitectureIdent,
+    name: &str,
+) -> Option<String> {
+    let params = chunk.get(offset..)?;
+    let read_u32 = |o: usize| Some(u32::from_le_bytes(params.get(o..o + 4)?.try_into().ok()?));
+
+    let offsets = Win32ArchOffsets::from(arch);
+    let arch = ArchitectureObj::from(arch);
+    let header_size = offsets.ppm_command_line + Win32UnicodeString::size(arch);
+    let maximum_length = read_u32(0)? as usize;
+    let length = read_u32(4)? as usize;
+    if !(header_size..=MAX_PARAMS_SIZE).contains(&length)
+        || length > maximum_length
+        || maximum_length > MAX_PARAMS_SIZE
+        || read_u32(8)? & PARAMS_NORMALIZED == 0
+    {
+        return None;
+    }
+
+    let image_path =
+        Win32UnicodeString::from_bytes(arch, params.get(offsets.ppm_image_path_name..)?).ok()?;
+    let command_line =
+        Win32UnicodeString::from_bytes(arch, params.get(offsets.ppm_command_line..)?).ok()?;
+    if !is_plausible_string(&image_path) || !is_plausible_string(&command_line) {
+        return None;
+    }
+
+    // largest address below the buffers that shares the page offset of the candidate
+    let lowest = image_path
+        .buffer
+        .to_umem()
+        .min(command_line.buffer.to_umem());
+    let top = lowest.checked_sub(header_size as umem)?;
+    let params_va = top - (top.wrapping_sub(offset as umem) & 0xfff);
+
+    let read_string = |string: &Win32UnicodeString| {
+        let start = (string.buffer.to_umem() - params_va) as usize;
+        let end = start + string.length as usize;
+        if end > length {
+            return None;
+        }
+        Some(decode_utf16_lossy(
+            params.get(start..end)?,
+            arch.endianess(),
+        ))
+    };
+
+    let image_path = read_string(&image_path)?;
+    let file_name = image_path.rsplit('\\').next()?.to_lowercase();
+    if !file_name.starts_with(&name.to_lowercase()) {
+        return None;
+    }
+
+    let command_line = read_string(&command_line)?;
+    if is_plausible(&command_line) {
+        Some(command_line)
+    } else {
+        None
+    }
+}
+
+/// Checks the header of a carved string without logging rejected candidates.
+fn is_plausible_string(string: &Win32UnicodeString) -> bool {
+    string.length != 0
+        && string.length % 2 == 0
+        && string.length <= string.maximum_length
+        && !string.buffer.is_null()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_string(chunk: &mut [u8], params: usize, header: usize, offset: usize, value: &str) {
+        let buf = value
+            .encode_utf16()
+            .flat_map(u16::to_le_bytes)
+            .collect::<Vec<_>>();
+        let va = 0x1_0000_0230u64 + offset as u64;
+        chunk[params + header..params + header + 2]
+            .copy_from_slice(&(buf.len() as u16).to_le_bytes());
+        chunk[params + header + 2..params + header + 4]
+            .copy_from_slice(&(buf.len() as u16 + 2).to_le_bytes());
+        chunk[params + header + 8..params + header + 16].copy_from_slice(&va.to_le_bytes());
+        chunk[params + offset..params + offset + buf.len()].copy_from_slice(&buf);
+    }
+
+    fn params_chunk(flags: u32) -> Vec<u8> {
+        let arch = ArchitectureIdent::X86(64, false);
+        let offsets = Win32ArchOffsets::from(arch);
+
+        // the parameters are allocated at a virtual address with the same page offset
+        let mut chunk = vec![0; 0x2000];
+        let params = 0x230;
+        chunk[params..params + 4].copy_from_slice(&0x300u32.to_le_bytes());
+        chunk[params + 4..params + 8].copy_from_slice(&0x300u32.to_le_bytes());
+        chunk[params + 8..params + 12].copy_from_slice(&flags.to_le_bytes());
+        write_string(
+            &mut chunk,
+            params,
+            offsets.ppm_image_path_name,
+            0x100,
+            "C:\\Windows\\System32\\notepad.exe",
+        );
+        write_string(
+            &mut chunk,
+            params,
+            offsets.ppm_command_line,
+            0x180,
+            "notepad.exe C:\\a.txt",
+        );
+        chunk
+    }
+
+    #[test]
+    fn carve() {
+        let arch = ArchitectureIdent::X86(64, false);
+        let chunk = params_chunk(PARAMS_NORMALIZED);
+        assert_eq!(
+            carve_in_chunk(&chunk, 0x1000, arch, "notepad.exe").as_deref(),
+            Some("notepad.exe C:\\a.txt")
+        );
+        // the image file name in the eprocess is truncated
+        assert!(carve_in_chunk(&chunk, 0x1000, arch, "NOTEPAD.E").is_some());
+        assert!(carve_in_chunk(&chunk, 0x1000, arch, "calc.exe").is_none());
+    }
+
+    #[test]
+    fn carve_unnormalized() {
+        let chunk = params_chunk(0);
+        let arch = ArchitectureIdent::X86(64, false);
+        assert!(carve_in_chunk(&chunk, 0x1000, arch, "notepad.exe").is_none());
+    }
+}
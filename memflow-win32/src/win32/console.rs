@@ -0,0 +1,128 @@
+/*!
+Module for mapping console applications to their console host.
+
+Since Windows 7 every console application is attached to a `conhost.exe` instance.
+The kernel records the process id of this host in `_EPROCESS::ConsoleHostProcess`
+while the process parameters in the PEB contain the handle of the attached console.
+
+# Examples:
+
+```
+use memflow::prelude::v1::*;
+use memflow_win32::win32::Win32Kernel;
+
+fn test<T: 'static + PhysicalMemory + Clone, V: 'static + VirtualTranslate2 + Clone>(
+    kernel: &mut Win32Kernel<T, V>,
+) {
+    for group in kernel.console_groups().unwrap() {
+        println!("{} ({})", group.host.name, group.host.pid);
+        for client in group.clients.iter() {
+            println!("  {} ({})", client.name, client.pid);
+        }
+    }
+}
+```
+*/
+use std::prelude::v1::*;
+
+use super::{Win32Kernel, Win32Process};
+
+use crate::offsets::Win32ArchOffsets;
+
+use memflow::error::{Error, ErrorKind, ErrorOrigin, Result};
+use memflow::mem::{MemoryView, PhysicalMemory, VirtualTranslate2, VirtualTranslate3};
+use memflow::os::{Os, Pid, ProcessInfo, ProcessState};
+use memflow::types::Address;
+
+/// A console host together with all processes attached to it
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize))]
+pub struct Win32ConsoleGroup {
+    /// The `conhost.exe` process
+    pub host: ProcessInfo,
+    /// All console applications attached to the host
+    pub clients: Vec<ProcessInfo>,
+}
+
+impl<T: 'static + PhysicalMemory + Clone, V: 'static + VirtualTranslate2 + Clone>
+    Win32Kernel<T, V>
+{
+    /// Returns the process id of the console host the given process is attached to.
+    ///
+    /// Returns `None` if the process is not attached to a console.
+    pub fn console_host_pid(&mut self, info: &ProcessInfo) -> Result<Option<Pid>> {
+        if self.offsets.eproc_console_host_process() == 0 {
+            return Err(Error(ErrorOrigin::OsLayer, ErrorKind::NotSupported)
+                .log_info("_EPROCESS::ConsoleHostProcess is not available on this build"));
+        }
+
+        let value = self.virt_mem.read_addr_arch(
+            self.kernel_info.os_info.arch.into(),
+            info.address + self.offsets.eproc_console_host_process(),
+        )?;
+
+        // the lower bits are used as flags
+        let pid = (value.to_umem() & !3) as Pid;
+        Ok(if pid != 0 { Some(pid) } else { None })
+    }
+
+    /// Groups all console applications by the console host they are attached to.
+    pub fn console_groups(&mut self) -> Result<Vec<Win32ConsoleGroup>> {
+        let procs = self.process_info_list()?;
+
+        let mut groups: Vec<Win32ConsoleGroup> = vec![];
+        for proc in procs.iter() {
+            let host_pid = match self.console_host_pid(proc)? {
+                Some(pid) if pid != proc.pid => pid,
+                _ => continue,
+            };
+
+            let host = match procs
+                .iter()
+                .find(|p| p.pid == host_pid && p.state == ProcessState::Alive)
+            {
+                Some(host) => host,
+                None => continue,
+            };
+
+            match groups.iter_mut().find(|g| g.host.pid == host_pid) {
+                Some(group) => group.clients.push(proc.clone()),
+                None => groups.push(Win32ConsoleGroup {
+                    host: host.clone(),
+                    clients: vec![proc.clone()],
+                }),
+            }
+        }
+
+        Ok(groups)
+    }
+}
+
+impl<T: PhysicalMemory, V: VirtualTranslate2, D: VirtualTranslate3> Win32Process<T, V, D> {
+    /// Returns the console handle from the process parameters of this process.
+    ///
+    /// Returns `None` if the process is not attached to a console.
+    pub fn console_handle(&mut self) -> Result<Option<Address>> {
+        let peb = self.proc_info.peb().ok_or_else(|| {
+            Error(ErrorOrigin::OsLayer, ErrorKind::NotFound).log_info("process has no peb")
+        })?;
+
+        let proc_arch = self.proc_info.base_info.proc_arch;
+        let offsets = Win32ArchOffsets::from(proc_arch);
+
+        let process_params = self
+            .virt_mem
+            .read_addr_arch(proc_arch.into(), peb + offsets.peb_process_params)?;
+        if process_params.is_null() {
+            return Ok(None);
+        }
+
+        Ok(self
+            .virt_mem
+            .read_addr_arch(
+                proc_arch.into(),
+                process_params + offsets.ppm_console_handle,
+            )?
+            .non_null())
+    }
+}
@@ -0,0 +1,169 @@
+/*!
+Module for inspecting the state of every logical processor.
+
+`KiProcessorBlock` contains a pointer to the processor control block (`_KPRCB`) of every
+logical processor. The prcb is embedded in the processor control region (`_KPCR`) and
+references the thread that is currently running on the processor as well as the queue of
+pending deferred procedure calls (dpcs). The process that is running is the process whose
+address space the thread is currently attached to.
+
+On x64 the irql is kept in `cr8`, the value in the `_KPCR` is not maintained by the kernel
+and only reflects the irql at the time it was last written (e.g. when a crash dump was taken).
+
+The fields are only available when the offsets were generated from a pdb or an isf profile.
+
+# Examples:
+
+```
+use memflow::prelude::v1::*;
+use memflow_win32::win32::Win32Kernel;
+
+fn test<T: 'static + PhysicalMemory + Clone, V: 'static + VirtualTranslate2 + Clone>(
+    kernel: &mut Win32Kernel<T, V>,
+) {
+    for cpu in kernel.cpu_states().unwrap() {
+        println!(
+            "cpu {}: thread {:x} pid {:?} irql {:?} dpcs {:?}",
+            cpu.index, cpu.current_thread, cpu.pid, cpu.irql, cpu.dpc_queue_depth
+        );
+    }
+}
+```
+*/
+use std::prelude::v1::*;
+
+use super::Win32Kernel;
+
+use log::{debug, trace};
+
+use memflow::architecture::ArchitectureObj;
+use memflow::error::{Error, ErrorKind, ErrorOrigin, Result};
+use memflow::mem::{MemoryView, PhysicalMemory, VirtualTranslate2};
+use memflow::os::Pid;
+use memflow::types::Address;
+
+/// Upper bound for the number of entries of `KiProcessorBlock`
+const MAX_PROCESSORS: usize = 2048;
+
+/// State of a logical processor
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize))]
+pub struct Win32CpuState {
+    /// Index of the processor in `KiProcessorBlock`
+    pub index: usize,
+    /// Address of the `_KPRCB`
+    pub prcb: Address,
+    /// Address of the `_KPCR`, `None` if the offsets are not available
+    pub pcr: Option<Address>,
+    /// Irql stored in the `_KPCR`, may be stale on x64
+    pub irql: Option<u8>,
+    /// Address of the `_KTHREAD` that is currently running on the processor
+    pub current_thread: Address,
+    /// True if the processor is running its idle thread
+    pub idle: bool,
+    /// Address of the `_EPROCESS` whose address space is active on the processor
+    pub process: Option<Address>,
+    pub pid: Option<Pid>,
+    /// Number of dpcs that are queued on the processor
+    pub dpc_queue_depth: Option<u32>,
+}
+
+impl<T: 'static + PhysicalMemory + Clone, V: 'static + VirtualTranslate2 + Clone>
+    Win32Kernel<T, V>
+{
+    /// Returns the state of every logical processor.
+    ///
+    /// Fields that cannot be read (e.g. because the offsets are not available) are left empty.
+    pub fn cpu_states(&mut self) -> Result<Vec<Win32CpuState>> {
+        if self.offsets.ki_processor_block() == 0 || self.offsets.kprcb_current_thread() == 0 {
+            return Err(Error(ErrorOrigin::OsLayer, ErrorKind::NotSupported)
+                .log_info("KiProcessorBlock or _KPRCB offsets are not available"));
+        }
+
+        let arch: ArchitectureObj = self.kernel_info.os_info.arch.into();
+        let processor_block = self.kernel_info.os_info.base + self.offsets.ki_processor_block();
+
+        let mut out = vec![];
+        for index in 0..MAX_PROCESSORS {
+            let prcb = self
+                .virt_mem
+                .read_addr_arch(arch, processor_block + index * arch.size_addr())?;
+            if prcb.is_null() {
+                break;
+            }
+
+            match self.cpu_state(arch, index, prcb) {
+                Ok(state) => {
+                    trace!("cpu {}: {:?}", index, state);
+                    out.push(state)
+                }
+                Err(err) => debug!("unable to read the prcb of cpu {}: {}", index, err),
+            }
+        }
+
+        Ok(out)
+    }
+
+    fn cpu_state(
+        &mut self,
+        arch: ArchitectureObj,
+        index: usize,
+        prcb: Address,
+    ) -> Result<Win32CpuState> {
+        let current_thread = self
+            .virt_mem
+            .read_addr_arch(arch, prcb + self.offsets.kprcb_current_thread())?;
+        let idle = self.offsets.kprcb_idle_thread() != 0
+            && self
+                .virt_mem
+                .read_addr_arch(arch, prcb + self.offsets.kprcb_idle_thread())
+                .map_or(false, |idle_thread| idle_thread == current_thread);
+
+        let pcr = (self.offsets.kpcr_prcb() != 0).then_some(prcb - self.offsets.kpcr_prcb());
+        let irql = match pcr {
+            Some(pcr) if self.offsets.kpcr_irql() != 0 => {
+                self.virt_mem.read(pcr + self.offsets.kpcr_irql()).ok()
+            }
+            _ => None,
+        };
+
+        let process = if self.offsets.kthread_apc_state() != 0
+            && self.offsets.kapc_state_process() != 0
+            && !current_thread.is_null()
+        {
+            self.virt_mem
+                .read_addr_arch(
+                    arch,
+                    current_thread
+                        + self.offsets.kthread_apc_state()
+                        + self.offsets.kapc_state_process(),
+                )
+                .ok()
+                .filter(|process| !process.is_null())
+        } else {
+            None
+        };
+        let pid =
+            process.and_then(|process| self.virt_mem.read(process + self.offsets.eproc_pid()).ok());
+
+        let dpc_queue_depth = if self.offsets.kprcb_dpc_queue_depth() != 0 {
+            self.virt_mem
+                .read(prcb + self.offsets.kprcb_dpc_queue_depth())
+                .ok()
+        } else {
+            None
+        };
+
+        Ok(Win32CpuState {
+            index,
+            prcb,
+            pcr,
+            irql,
+            current_thread,
+            idle,
+            process,
+            pid,
+            dpc_queue_depth,
+        })
+    }
+}
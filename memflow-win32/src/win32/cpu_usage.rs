@@ -0,0 +1,290 @@
+/*!
+Module for computing the cpu usage of processes and threads.
+
+The kernel accounts the time a thread spends in kernel and in user mode in clock ticks in
+`_KTHREAD::KernelTime` and `_KTHREAD::UserTime`, the totals of all threads of a process are kept
+in `_KPROCESS`. Since nt 6.0 the number of cpu cycles is accounted in `CycleTime` as well.
+
+A [`Win32CpuSnapshot`] captures these counters together with the interrupt time of
+`KUSER_SHARED_DATA`. The cpu usage is computed from the difference of two snapshots, the
+percentages are relative to a single logical processor like in `top`.
+
+The counters are only available when the offsets were generated from a pdb or an isf profile.
+
+# Examples:
+
+```
+use memflow::prelude::v1::*;
+use memflow_win32::win32::Win32Kernel;
+
+fn test<T: 'static + PhysicalMemory + Clone, V: 'static + VirtualTranslate2 + Clone>(
+    kernel: &mut Win32Kernel<T, V>,
+) {
+    let before = kernel.cpu_snapshot().unwrap();
+    std::thread::sleep(std::time::Duration::from_secs(1));
+    let after = kernel.cpu_snapshot().unwrap();
+
+    for usage in after.process_usage(&before) {
+        println!("{}: {:.1}%", usage.pid, usage.total_percent());
+    }
+}
+```
+*/
+use std::prelude::v1::*;
+
+use super::Win32Kernel;
+use crate::kernel::ntos::kuser_shared_data;
+
+use log::trace;
+
+use memflow::error::{Error, ErrorKind, ErrorOrigin, PartialResultExt, Result};
+use memflow::mem::{MemoryView, PhysicalMemory, VirtualTranslate2};
+use memflow::os::{Os, Pid};
+use memflow::types::{umem, Address};
+
+/// KUSER_SHARED_DATA::TickCountMultiplier offset
+const KUSER_TICK_COUNT_MULTIPLIER: umem = 0x4;
+/// KUSER_SHARED_DATA::InterruptTime offset
+const KUSER_INTERRUPT_TIME: umem = 0x8;
+
+/// Cpu time counters of a process or thread
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize))]
+pub struct Win32CpuTimes {
+    /// Clock ticks spent in kernel mode
+    pub kernel_ticks: u32,
+    /// Clock ticks spent in user mode
+    pub user_ticks: u32,
+    /// Cpu cycles, `None` before nt 6.0
+    pub cycles: Option<u64>,
+}
+
+/// Cpu time counters of a thread
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize))]
+pub struct Win32ThreadCpuTimes {
+    pub ethread: Address,
+    pub times: Win32CpuTimes,
+}
+
+/// Cpu time counters of a process and its threads
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize))]
+pub struct Win32ProcessCpuTimes {
+    pub pid: Pid,
+    pub eprocess: Address,
+    pub times: Win32CpuTimes,
+    pub threads: Vec<Win32ThreadCpuTimes>,
+}
+
+/// Cpu time counters of all processes at a point in time
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize))]
+pub struct Win32CpuSnapshot {
+    /// Interrupt time in 100ns units at which the snapshot was taken
+    pub interrupt_time: u64,
+    /// Length of a clock tick in 100ns units
+    pub tick_increment: u64,
+    pub processes: Vec<Win32ProcessCpuTimes>,
+}
+
+/// Cpu usage of a process or thread between two snapshots
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize))]
+pub struct Win32CpuUsage {
+    pub pid: Pid,
+    /// Address of the eprocess or the ethread
+    pub address: Address,
+    /// Percentage of a single logical processor spent in kernel mode
+    pub kernel_percent: f32,
+    /// Percentage of a single logical processor spent in user mode
+    pub user_percent: f32,
+    /// Cpu cycles between the snapshots, `None` before nt 6.0
+    pub cycles: Option<u64>,
+}
+
+impl Win32CpuUsage {
+    /// Returns the combined kernel and user mode percentage.
+    pub fn total_percent(&self) -> f32 {
+        self.kernel_percent + self.user_percent
+    }
+}
+
+impl Win32CpuSnapshot {
+    /// Computes the cpu usage of all processes that exist in both snapshots.
+    pub fn process_usage(&self, previous: &Win32CpuSnapshot) -> Vec<Win32CpuUsage> {
+        self.processes
+            .iter()
+            .filter_map(|proc| {
+                let prev = previous.find_process(proc)?;
+                Some(self.usage(previous, proc.pid, proc.eprocess, proc.times, prev.times))
+            })
+            .collect()
+    }
+
+    /// Computes the cpu usage of all threads that exist in both snapshots.
+    pub fn thread_usage(&self, previous: &Win32CpuSnapshot) -> Vec<Win32CpuUsage> {
+        let mut out = vec![];
+        for proc in self.processes.iter() {
+            let prev = match previous.find_process(proc) {
+                Some(prev) => prev,
+                None => continue,
+            };
+            for thread in proc.threads.iter() {
+                if let Some(prev_thread) = prev.threads.iter().find(|t| t.ethread == thread.ethread)
+                {
+                    out.push(self.usage(
+                        previous,
+                        proc.pid,
+                        thread.ethread,
+                        thread.times,
+                        prev_thread.times,
+                    ));
+                }
+            }
+        }
+        out
+    }
+
+    /// Finds the same process in this snapshot, processes are matched by their pid and eprocess.
+    fn find_process(&self, proc: &Win32ProcessCpuTimes) -> Option<&Win32ProcessCpuTimes> {
+        self.processes
+            .iter()
+            .find(|p| p.pid == proc.pid && p.eprocess == proc.eprocess)
+    }
+
+    fn usage(
+        &self,
+        previous: &Win32CpuSnapshot,
+        pid: Pid,
+        address: Address,
+        times: Win32CpuTimes,
+        prev_times: Win32CpuTimes,
+    ) -> Win32CpuUsage {
+        let elapsed = self.interrupt_time.saturating_sub(previous.interrupt_time);
+        let percent = |ticks: u32, prev_ticks: u32| {
+            if elapsed == 0 {
+                return 0.0;
+            }
+            // the tick counters are 32 bits wide and wrap around
+            let delta = ticks.wrapping_sub(prev_ticks) as u64 * self.tick_increment;
+            (delta as f64 * 100.0 / elapsed as f64) as f32
+        };
+
+        Win32CpuUsage {
+            pid,
+            address,
+            kernel_percent: percent(times.kernel_ticks, prev_times.kernel_ticks),
+            user_percent: percent(times.user_ticks, prev_times.user_ticks),
+            cycles: times
+                .cycles
+                .zip(prev_times.cycles)
+                .map(|(cycles, prev_cycles)| cycles.saturating_sub(prev_cycles)),
+        }
+    }
+}
+
+impl<T: 'static + PhysicalMemory + Clone, V: 'static + VirtualTranslate2 + Clone>
+    Win32Kernel<T, V>
+{
+    /// Captures the cpu time counters of all processes and their threads.
+    ///
+    /// Processes whose threads cannot be read are captured without threads.
+    pub fn cpu_snapshot(&mut self) -> Result<Win32CpuSnapshot> {
+        let (interrupt_time, tick_increment) = self.read_shared_data_times()?;
+
+        let mut processes = vec![];
+        for proc in self.process_info_list()? {
+            let times = self.process_cpu_times(proc.address)?;
+            let threads = self
+                .thread_address_list(proc.address)
+                .unwrap_or_default()
+                .into_iter()
+                .filter_map(|ethread| {
+                    self.thread_cpu_times(ethread)
+                        .ok()
+                        .map(|times| Win32ThreadCpuTimes { ethread, times })
+                })
+                .collect();
+            processes.push(Win32ProcessCpuTimes {
+                pid: proc.pid,
+                eprocess: proc.address,
+                times,
+                threads,
+            });
+        }
+
+        Ok(Win32CpuSnapshot {
+            interrupt_time,
+            tick_increment,
+            processes,
+        })
+    }
+
+    /// Reads the cpu time counters of a process.
+    pub fn process_cpu_times(&mut self, eprocess: Address) -> Result<Win32CpuTimes> {
+        self.read_cpu_times(
+            eprocess,
+            self.offsets.kproc_kernel_time(),
+            self.offsets.kproc_user_time(),
+            self.offsets.kproc_cycle_time(),
+        )
+    }
+
+    /// Reads the cpu time counters of a thread.
+    pub fn thread_cpu_times(&mut self, ethread: Address) -> Result<Win32CpuTimes> {
+        self.read_cpu_times(
+            ethread,
+            self.offsets.kthread_kernel_time(),
+            self.offsets.kthread_user_time(),
+            self.offsets.kthread_cycle_time(),
+        )
+    }
+
+    fn read_cpu_times(
+        &mut self,
+        address: Address,
+        kernel_time: usize,
+        user_time: usize,
+        cycle_time: usize,
+    ) -> Result<Win32CpuTimes> {
+        if kernel_time == 0 || user_time == 0 {
+            return Err(Error(ErrorOrigin::OsLayer, ErrorKind::NotSupported)
+                .log_info("cpu time offsets are not available"));
+        }
+
+        Ok(Win32CpuTimes {
+            kernel_ticks: self.virt_mem.read(address + kernel_time)?,
+            user_ticks: self.virt_mem.read(address + user_time)?,
+            cycles: if cycle_time != 0 {
+                Some(self.virt_mem.read(address + cycle_time)?)
+            } else {
+                None
+            },
+        })
+    }
+
+    /// Reads the interrupt time and the length of a clock tick from `KUSER_SHARED_DATA`.
+    fn read_shared_data_times(&mut self) -> Result<(u64, u64)> {
+        let shared_data = kuser_shared_data(&mut self.virt_mem, self.kernel_info.os_info.arch)?;
+
+        // the high part of the interrupt time is duplicated, only the first 8 bytes are read
+        let interrupt_time: u64 = self
+            .virt_mem
+            .read(shared_data + KUSER_INTERRUPT_TIME)
+            .data_part()?;
+        // the multiplier is the length of a tick in milliseconds as a 8.24 fixed point number
+        let multiplier: u32 = self
+            .virt_mem
+            .read(shared_data + KUSER_TICK_COUNT_MULTIPLIER)
+            .data_part()?;
+        let tick_increment = (multiplier as u64 * 10_000) >> 24;
+        trace!(
+            "interrupt_time={} tick_increment={}",
+            interrupt_time,
+            tick_increment
+        );
+
+        Ok((interrupt_time, tick_increment))
+    }
+}
@@ -0,0 +1,332 @@
+/*!
+Module for parsing the header of raw Windows crash dumps.
+
+The header of a crash dump (`PAGEDU64` on 64 bit and `PAGEDUMP` on 32 bit targets) contains
+the directory table base, the addresses of important kernel globals and the physical memory runs
+of the dump. This allows bootstrapping the kernel without scanning for the start block.
+
+# Examples:
+
+```
+use memflow::prelude::v1::*;
+use memflow_win32::win32::Win32Kernel;
+
+fn test<T: 'static + PhysicalMemory + Clone>(mut connector: T) {
+    let mut header = vec![0; 0x2000];
+    connector
+        .phys_read_into(PhysicalAddress::NULL, header.as_mut_slice())
+        .unwrap();
+
+    let _kernel = Win32Kernel::builder(connector)
+        .from_crashdump_header(&header)
+        .unwrap()
+        .build()
+        .unwrap();
+}
+```
+*/
+use std::prelude::v1::*;
+
+use std::convert::TryInto;
+
+use memflow::architecture::ArchitectureIdent;
+use memflow::error::{Error, ErrorKind, ErrorOrigin, Result};
+use memflow::mem::MemoryMap;
+use memflow::types::{size, umem, Address};
+
+const IMAGE_FILE_MACHINE_I386: u32 = 0x14c;
+const IMAGE_FILE_MACHINE_AMD64: u32 = 0x8664;
+const IMAGE_FILE_MACHINE_ARM64: u32 = 0xaa64;
+
+/// Size of the `PhysicalMemoryBlockBuffer` on 64 bit, runs cannot exceed this buffer
+const PHYSICAL_MEMORY_BLOCK_SIZE_64: usize = 0x2c0;
+/// Size of the `PhysicalMemoryBlockBuffer` on 32 bit
+const PHYSICAL_MEMORY_BLOCK_SIZE_32: usize = 0x2bc;
+
+/// Full dumps store the physical memory runs consecutively after the header
+const DUMP_TYPE_FULL: u32 = 1;
+
+/// Parsed header of a raw crash dump
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize))]
+pub struct Win32CrashDumpHeader {
+    pub arch: ArchitectureIdent,
+    pub dtb: Address,
+    /// Build number of the kernel
+    pub build_number: u32,
+    pub pfn_database: Address,
+    pub ps_loaded_module_list: Address,
+    pub ps_active_process_head: Address,
+    pub kd_debugger_data_block: Address,
+    pub dump_type: u32,
+    /// Size of the header, the memory of full dumps starts directly after it
+    pub header_size: umem,
+    /// Physical memory runs as base address and size in bytes
+    pub runs: Vec<(Address, umem)>,
+}
+
+impl Win32CrashDumpHeader {
+    /// Parses the header of a crash dump.
+    ///
+    /// The buffer has to contain at least the first 0x2000 bytes (0x1000 bytes on 32 bit) of the dump.
+    pub fn parse(header: &[u8]) -> Result<Self> {
+        match header.get(0..8) {
+            Some(b"PAGEDU64") => Self::parse64(header),
+            Some(b"PAGEDUMP") => Self::parse32(header),
+            _ => Err(Error(ErrorOrigin::OsLayer, ErrorKind::InvalidMemorySlice)
+                .log_error("buffer does not contain a crash dump header")),
+        }
+    }
+
+    fn parse64(header: &[u8]) -> Result<Self> {
+        if header.len() < size::kb(8) {
+            return Err(Error(ErrorOrigin::OsLayer, ErrorKind::InvalidMemorySlice)
+                .log_error("64 bit crash dump header is truncated"));
+        }
+
+        let arch = match read_u32(header, 0x30) {
+            IMAGE_FILE_MACHINE_AMD64 => ArchitectureIdent::X86(64, false),
+            IMAGE_FILE_MACHINE_ARM64 => ArchitectureIdent::AArch64(size::kb(4)),
+            machine => {
+                return Err(
+                    Error(ErrorOrigin::OsLayer, ErrorKind::InvalidArchitecture).log_error(format!(
+                        "unsupported crash dump machine type: {:x}",
+                        machine
+                    )),
+                )
+            }
+        };
+
+        // the runs are limited by the size of the physical memory block at 0x88
+        let number_of_runs =
+            (read_u32(header, 0x88) as usize).min((PHYSICAL_MEMORY_BLOCK_SIZE_64 - 0x10) / 0x10);
+        let runs = (0..number_of_runs)
+            .map(|i| {
+                let offset = 0x98 + i * 0x10;
+                (
+                    Address::from(read_u64(header, offset) * 0x1000),
+                    read_u64(header, offset + 8) as umem * 0x1000,
+                )
+            })
+            .collect();
+
+        Ok(Self {
+            arch,
+            dtb: read_u64(header, 0x10).into(),
+            build_number: read_u32(header, 0x0c),
+            pfn_database: read_u64(header, 0x18).into(),
+            ps_loaded_module_list: read_u64(header, 0x20).into(),
+            ps_active_process_head: read_u64(header, 0x28).into(),
+            kd_debugger_data_block: read_u64(header, 0x80).into(),
+            dump_type: read_u32(header, 0xf98),
+            header_size: size::kb(8) as umem,
+            runs,
+        })
+    }
+
+    fn parse32(header: &[u8]) -> Result<Self> {
+        if header.len() < size::kb(4) {
+            return Err(Error(ErrorOrigin::OsLayer, ErrorKind::InvalidMemorySlice)
+                .log_error("32 bit crash dump header is truncated"));
+        }
+
+        if read_u32(header, 0x20) != IMAGE_FILE_MACHINE_I386 {
+            return Err(Error(ErrorOrigin::OsLayer, ErrorKind::InvalidArchitecture)
+                .log_error("unsupported crash dump machine type"));
+        }
+        let pae_enabled = header[0x5c] != 0;
+
+        // the runs are limited by the size of the physical memory block at 0x64
+        let number_of_runs =
+            (read_u32(header, 0x64) as usize).min((PHYSICAL_MEMORY_BLOCK_SIZE_32 - 8) / 8);
+        let runs = (0..number_of_runs)
+            .map(|i| {
+                let offset = 0x6c + i * 8;
+                (
+                    Address::from(read_u32(header, offset) as u64 * 0x1000),
+                    read_u32(header, offset + 4) as umem * 0x1000,
+                )
+            })
+            .collect();
+
+        Ok(Self {
+            arch: ArchitectureIdent::X86(32, pae_enabled),
+            dtb: Address::from(read_u32(header, 0x10)),
+            build_number: read_u32(header, 0x0c),
+            pfn_database: Address::from(read_u32(header, 0x14)),
+            ps_loaded_module_list: Address::from(read_u32(header, 0x18)),
+            ps_active_process_head: Address::from(read_u32(header, 0x1c)),
+            kd_debugger_data_block: Address::from(read_u32(header, 0x60)),
+            dump_type: read_u32(header, 0xf88),
+            header_size: size::kb(4) as umem,
+            runs,
+        })
+    }
+
+    /// Returns true if the memory of the dump is stored as consecutive physical memory runs.
+    pub fn is_full_dump(&self) -> bool {
+        self.dump_type == DUMP_TYPE_FULL
+    }
+
+    /// Returns the memory map of a full dump that maps the physical memory runs to their offset in the dump.
+    pub fn mem_map(&self) -> Option<MemoryMap<(Address, umem)>> {
+        if !self.is_full_dump() || self.runs.is_empty() {
+            return None;
+        }
+
+        let mut mem_map = MemoryMap::new();
+        let mut file_offset = self.header_size;
+        for &(base, size) in self.runs.iter() {
+            mem_map.push_remap(base, size, Address::from(file_offset));
+            file_offset += size;
+        }
+        Some(mem_map)
+    }
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(buf: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(buf[offset..offset + 8].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_u32(buf: &mut [u8], offset: usize, value: u32) {
+        buf[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn write_u64(buf: &mut [u8], offset: usize, value: u64) {
+        buf[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn header64(number_of_runs: u32) -> Vec<u8> {
+        let mut header = vec![0u8; size::kb(8)];
+        header[0..8].copy_from_slice(b"PAGEDU64");
+        write_u32(&mut header, 0x0c, 19041);
+        write_u64(&mut header, 0x10, 0x1ad000);
+        write_u64(&mut header, 0x28, 0xfffff802_1dc1e6a0);
+        write_u32(&mut header, 0x30, IMAGE_FILE_MACHINE_AMD64);
+        write_u64(&mut header, 0x80, 0xfffff802_1d2fb6b0);
+        write_u32(&mut header, 0x88, number_of_runs);
+        for i in 0..number_of_runs as usize {
+            write_u64(&mut header, 0x98 + i * 0x10, 1 + i as u64 * 0x100);
+            write_u64(&mut header, 0xa0 + i * 0x10, 0x10);
+        }
+        write_u32(&mut header, 0xf98, DUMP_TYPE_FULL);
+        header
+    }
+
+    fn header32(number_of_runs: u32) -> Vec<u8> {
+        let mut header = vec![0u8; size::kb(4)];
+        header[0..8].copy_from_slice(b"PAGEDUMP");
+        write_u32(&mut header, 0x0c, 7601);
+        write_u32(&mut header, 0x10, 0x185000);
+        write_u32(&mut header, 0x1c, 0x8299_5f18);
+        write_u32(&mut header, 0x20, IMAGE_FILE_MACHINE_I386);
+        header[0x5c] = 1;
+        write_u32(&mut header, 0x64, number_of_runs);
+        for i in 0..number_of_runs as usize {
+            write_u32(&mut header, 0x6c + i * 8, 1 + i as u32 * 0x100);
+            write_u32(&mut header, 0x70 + i * 8, 0x10);
+        }
+        write_u32(&mut header, 0xf88, DUMP_TYPE_FULL);
+        header
+    }
+
+    #[test]
+    fn parse64() {
+        let header = Win32CrashDumpHeader::parse(&header64(2)).unwrap();
+        assert_eq!(header.arch, ArchitectureIdent::X86(64, false));
+        assert_eq!(header.dtb, Address::from(0x1ad000u64));
+        assert_eq!(header.build_number, 19041);
+        assert_eq!(
+            header.ps_active_process_head,
+            Address::from(0xfffff802_1dc1e6a0u64)
+        );
+        assert_eq!(
+            header.kd_debugger_data_block,
+            Address::from(0xfffff802_1d2fb6b0u64)
+        );
+        assert!(header.is_full_dump());
+        assert_eq!(header.header_size, 0x2000);
+        assert_eq!(
+            header.runs,
+            vec![
+                (Address::from(0x1000u64), 0x10000),
+                (Address::from(0x101000u64), 0x10000)
+            ]
+        );
+    }
+
+    #[test]
+    fn parse32() {
+        let header = Win32CrashDumpHeader::parse(&header32(1)).unwrap();
+        assert_eq!(header.arch, ArchitectureIdent::X86(32, true));
+        assert_eq!(header.dtb, Address::from(0x185000u64));
+        assert_eq!(header.build_number, 7601);
+        assert_eq!(header.ps_active_process_head, Address::from(0x8299_5f18u64));
+        assert!(header.is_full_dump());
+        assert_eq!(header.header_size, 0x1000);
+        assert_eq!(header.runs, vec![(Address::from(0x1000u64), 0x10000)]);
+    }
+
+    #[test]
+    fn dump_type() {
+        let mut buf = header64(1);
+        write_u32(&mut buf, 0xf98, 2);
+        assert!(!Win32CrashDumpHeader::parse(&buf).unwrap().is_full_dump());
+
+        let mut buf = header32(1);
+        write_u32(&mut buf, 0xf88, 2);
+        assert!(!Win32CrashDumpHeader::parse(&buf).unwrap().is_full_dump());
+    }
+
+    #[test]
+    fn runs_are_capped() {
+        let mut buf = header64(0);
+        write_u32(&mut buf, 0x88, 0x1000);
+        let header = Win32CrashDumpHeader::parse(&buf).unwrap();
+        assert_eq!(
+            header.runs.len(),
+            (PHYSICAL_MEMORY_BLOCK_SIZE_64 - 0x10) / 0x10
+        );
+
+        let mut buf = header32(0);
+        write_u32(&mut buf, 0x64, 0x1000);
+        let header = Win32CrashDumpHeader::parse(&buf).unwrap();
+        assert_eq!(header.runs.len(), (PHYSICAL_MEMORY_BLOCK_SIZE_32 - 8) / 8);
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(Win32CrashDumpHeader::parse(b"PAGEDU64").is_err());
+        assert!(Win32CrashDumpHeader::parse(&header32(0)[..0x800]).is_err());
+        assert!(Win32CrashDumpHeader::parse(&[0u8; 0x2000]).is_err());
+
+        let mut buf = header64(0);
+        write_u32(&mut buf, 0x30, IMAGE_FILE_MACHINE_I386);
+        assert!(Win32CrashDumpHeader::parse(&buf).is_err());
+    }
+
+    #[test]
+    fn mem_map() {
+        let header = Win32CrashDumpHeader::parse(&header64(2)).unwrap();
+        assert!(header.mem_map().is_some());
+
+        let mut buf = header64(2);
+        write_u32(&mut buf, 0xf98, 2);
+        assert!(Win32CrashDumpHeader::parse(&buf)
+            .unwrap()
+            .mem_map()
+            .is_none());
+        assert!(Win32CrashDumpHeader::parse(&header64(0))
+            .unwrap()
+            .mem_map()
+            .is_none());
+    }
+}
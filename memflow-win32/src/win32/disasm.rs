@@ -0,0 +1,233 @@
+/*!
+Module for disassembling code in the kernel or in a process.
+
+Instructions are decoded with [iced-x86](https://github.com/icedland/iced) and formatted in intel syntax.
+Branch targets and memory operands that point into a loaded module are symbolized
+as `module!export+offset` or `module+offset` if no export precedes the address.
+
+This module is only available with the `disasm` feature enabled.
+
+# Examples:
+
+```
+use memflow::prelude::v1::*;
+use memflow_win32::win32::Win32Kernel;
+
+fn test<T: 'static + PhysicalMemory + Clone, V: 'static + VirtualTranslate2 + Clone>(
+    kernel: &mut Win32Kernel<T, V>,
+) {
+    let base = kernel.info().base;
+    for instr in kernel.disassemble(base + 0x1000, 0x40).unwrap() {
+        println!("{:x} {}", instr.address, instr.text);
+    }
+}
+```
+*/
+use std::prelude::v1::*;
+
+use super::{Win32Kernel, Win32Process, Win32VirtualTranslate};
+
+use memflow::architecture::ArchitectureIdent;
+use memflow::error::{Error, ErrorKind, ErrorOrigin, PartialResultExt, Result};
+use memflow::mem::{MemoryView, PhysicalMemory, VirtualTranslate2};
+use memflow::os::{ExportInfo, ModuleInfo, Os, Process};
+use memflow::types::{umem, Address};
+
+use std::collections::BTreeMap;
+
+use iced_x86::{
+    Decoder, DecoderOptions, Formatter, Instruction, IntelFormatter, OpKind, Register,
+    SymbolResolver, SymbolResult,
+};
+
+/// A decoded instruction
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize))]
+pub struct Win32Instruction {
+    pub address: Address,
+    pub bytes: Vec<u8>,
+    /// Instruction in intel syntax with symbolized operands
+    pub text: String,
+    /// Branch target or memory operand address of the instruction
+    pub target: Option<Address>,
+    /// Symbol of `target` if it points into a loaded module
+    pub symbol: Option<String>,
+}
+
+impl<T: 'static + PhysicalMemory + Clone, V: 'static + VirtualTranslate2 + Clone>
+    Win32Kernel<T, V>
+{
+    /// Disassembles `len` bytes of kernel memory starting at `addr`.
+    pub fn disassemble(&mut self, addr: Address, len: usize) -> Result<Vec<Win32Instruction>> {
+        let bytes = self.virt_mem.read_raw(addr, len).data_part()?;
+        let instructions = decode(self.kernel_info.os_info.arch, addr, &bytes)?;
+
+        let modules = self.module_list()?;
+        let symbols = resolve_symbols(&instructions, &modules, |m| self.module_export_list(m))?;
+
+        Ok(format(&instructions, addr, &bytes, symbols))
+    }
+}
+
+impl<T: PhysicalMemory, V: VirtualTranslate2> Win32Process<T, V, Win32VirtualTranslate> {
+    /// Disassembles `len` bytes of process memory starting at `addr`.
+    ///
+    /// The code is decoded with the architecture of the module containing `addr`,
+    /// the architecture of the process is used if no module contains it.
+    pub fn disassemble(&mut self, addr: Address, len: usize) -> Result<Vec<Win32Instruction>> {
+        let bytes = self.virt_mem.read_raw(addr, len).data_part()?;
+
+        let modules = self.module_list()?;
+        let arch = modules
+            .iter()
+            .find(|m| addr >= m.base && addr < m.base + m.size)
+            .map(|m| m.arch)
+            .unwrap_or(self.proc_info.base_info.proc_arch);
+        let instructions = decode(arch, addr, &bytes)?;
+
+        let symbols = resolve_symbols(&instructions, &modules, |m| self.module_export_list(m))?;
+
+        Ok(format(&instructions, addr, &bytes, symbols))
+    }
+}
+
+fn decode(arch: ArchitectureIdent, addr: Address, bytes: &[u8]) -> Result<Vec<Instruction>> {
+    let bitness = match arch {
+        ArchitectureIdent::X86(bits, _) => bits as u32,
+        _ => {
+            return Err(Error(ErrorOrigin::OsLayer, ErrorKind::NotSupported)
+                .log_info("disassembly is only supported on x86 targets"))
+        }
+    };
+
+    let mut decoder = Decoder::with_ip(bitness, bytes, addr.to_umem() as u64, DecoderOptions::NONE);
+    let mut instructions = vec![];
+    let mut instr = Instruction::default();
+    while decoder.can_decode() {
+        decoder.decode_out(&mut instr);
+        if instr.is_invalid() {
+            // the instruction is cut off at the end of the buffer
+            if !decoder.can_decode() {
+                break;
+            }
+        }
+        instructions.push(instr);
+    }
+    Ok(instructions)
+}
+
+/// Returns the address the instruction refers to.
+fn target(instr: &Instruction) -> Option<u64> {
+    if instr.near_branch_target() != 0 {
+        Some(instr.near_branch_target())
+    } else if instr.is_ip_rel_memory_operand() {
+        Some(instr.ip_rel_memory_address())
+    } else if (0..instr.op_count()).any(|i| instr.op_kind(i) == OpKind::Memory)
+        && instr.memory_base() == Register::None
+        && instr.memory_index() == Register::None
+    {
+        Some(instr.memory_displacement64())
+    } else {
+        None
+    }
+}
+
+/// Symbolizes all addresses referred to by the instructions.
+fn resolve_symbols<F: FnMut(&ModuleInfo) -> Result<Vec<ExportInfo>>>(
+    instructions: &[Instruction],
+    modules: &[ModuleInfo],
+    mut export_list: F,
+) -> Result<BTreeMap<u64, String>> {
+    let mut exports: BTreeMap<Address, Vec<ExportInfo>> = BTreeMap::new();
+    let mut symbols = BTreeMap::new();
+
+    for target in instructions.iter().filter_map(target) {
+        let addr = Address::from(target as umem);
+        let module = match modules
+            .iter()
+            .find(|m| addr >= m.base && addr < m.base + m.size)
+        {
+            Some(module) => module,
+            None => continue,
+        };
+
+        if !exports.contains_key(&module.base) {
+            // a module with a broken export directory is still symbolized by its name
+            let mut list = export_list(module).unwrap_or_default();
+            list.sort_by_key(|e| e.offset);
+            exports.insert(module.base, list);
+        }
+
+        let offset = addr.to_umem() - module.base.to_umem();
+        let symbol = match exports[&module.base]
+            .iter()
+            .rev()
+            .find(|e| e.offset <= offset)
+        {
+            Some(export) if export.offset == offset => format!("{}!{}", module.name, export.name),
+            Some(export) => format!(
+                "{}!{}+{:#x}",
+                module.name,
+                export.name,
+                offset - export.offset
+            ),
+            None => format!("{}+{:#x}", module.name, offset),
+        };
+        symbols.insert(target, symbol);
+    }
+
+    Ok(symbols)
+}
+
+fn format(
+    instructions: &[Instruction],
+    addr: Address,
+    bytes: &[u8],
+    symbols: BTreeMap<u64, String>,
+) -> Vec<Win32Instruction> {
+    let mut formatter = IntelFormatter::with_options(
+        Some(Box::new(Win32SymbolResolver {
+            symbols: symbols.clone(),
+        })),
+        None,
+    );
+
+    instructions
+        .iter()
+        .map(|instr| {
+            let start = (instr.ip() - addr.to_umem() as u64) as usize;
+            let end = (start + instr.len()).min(bytes.len());
+
+            let mut text = String::new();
+            formatter.format(instr, &mut text);
+
+            let target = target(instr);
+            Win32Instruction {
+                address: Address::from(instr.ip() as umem),
+                bytes: bytes[start..end].to_vec(),
+                text,
+                target: target.map(|t| Address::from(t as umem)),
+                symbol: target.and_then(|t| symbols.get(&t).cloned()),
+            }
+        })
+        .collect()
+}
+
+struct Win32SymbolResolver {
+    symbols: BTreeMap<u64, String>,
+}
+
+impl SymbolResolver for Win32SymbolResolver {
+    fn symbol(
+        &mut self,
+        _instruction: &Instruction,
+        _operand: u32,
+        _instruction_operand: Option<u32>,
+        address: u64,
+        _address_size: u32,
+    ) -> Option<SymbolResult<'_>> {
+        self.symbols
+            .get(&address)
+            .map(|s| SymbolResult::with_str(address, s.as_str()))
+    }
+}
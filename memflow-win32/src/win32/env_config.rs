@@ -0,0 +1,121 @@
+/*!
+Module for overriding the kernel configuration via environment variables.
+
+The following variables are read when a [`Win32KernelBuilder`](super::Win32KernelBuilder) is created:
+- `MEMFLOW_WIN32_SYMSTORE` - `default`, `uncached` or `none`
+- `MEMFLOW_WIN32_SYMSTORE_URL` - base url of the symbol store
+- `MEMFLOW_WIN32_SYMSTORE_CACHE_DIR` - directory used to cache downloaded pdbs
+- `MEMFLOW_WIN32_LOG_LEVEL` - maximum log level of the os plugin
+  (`off`, `error`, `warn`, `info`, `debug`, `trace`)
+
+The log level is only applied when this crate is loaded as a plugin, the plugin has its own
+instance of the `log` crate so the log level of the host application is not affected.
+When the crate is linked as a library the verbosity is controlled by the logger of the
+application (e.g. by filtering the `memflow_win32` target).
+
+Environment variables take precedence over the built-in defaults.
+Every setting that is explicitly configured on the builder (or passed as a plugin argument)
+takes precedence over the environment.
+*/
+use std::prelude::v1::*;
+
+use std::env;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+#[cfg(feature = "symstore")]
+use crate::offsets::SymbolStore;
+
+use log::{warn, LevelFilter};
+
+pub const ENV_SYMSTORE: &str = "MEMFLOW_WIN32_SYMSTORE";
+pub const ENV_SYMSTORE_URL: &str = "MEMFLOW_WIN32_SYMSTORE_URL";
+pub const ENV_SYMSTORE_CACHE_DIR: &str = "MEMFLOW_WIN32_SYMSTORE_CACHE_DIR";
+pub const ENV_LOG_LEVEL: &str = "MEMFLOW_WIN32_LOG_LEVEL";
+
+/// Symbol store mode set via `MEMFLOW_WIN32_SYMSTORE`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Win32EnvSymstoreMode {
+    Default,
+    Uncached,
+    None,
+}
+
+impl FromStr for Win32EnvSymstoreMode {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "default" => Ok(Self::Default),
+            "uncached" => Ok(Self::Uncached),
+            "none" => Ok(Self::None),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Configuration overrides read from the environment
+#[derive(Debug, Clone, Default)]
+pub struct Win32EnvConfig {
+    pub symstore: Option<Win32EnvSymstoreMode>,
+    pub symstore_url: Option<String>,
+    pub symstore_cache_dir: Option<PathBuf>,
+    pub log_level: Option<LevelFilter>,
+}
+
+impl Win32EnvConfig {
+    /// Reads all `MEMFLOW_WIN32_*` variables from the environment.
+    ///
+    /// Invalid values are ignored with a warning.
+    pub fn from_env() -> Self {
+        Self {
+            symstore: parse_var(ENV_SYMSTORE),
+            symstore_url: var(ENV_SYMSTORE_URL),
+            symstore_cache_dir: var(ENV_SYMSTORE_CACHE_DIR).map(PathBuf::from),
+            log_level: parse_var(ENV_LOG_LEVEL),
+        }
+    }
+
+    /// Returns the symbol store configured by the environment.
+    ///
+    /// `None` is returned if the environment does not override the symbol store,
+    /// `Some(None)` if the symbol store has been disabled.
+    #[cfg(feature = "symstore")]
+    pub fn symbol_store(&self) -> Option<Option<SymbolStore>> {
+        if self.symstore.is_none()
+            && self.symstore_url.is_none()
+            && self.symstore_cache_dir.is_none()
+        {
+            return None;
+        }
+
+        let mut store = SymbolStore::default();
+        if let Some(url) = &self.symstore_url {
+            store = store.base_url(url);
+        }
+        if let Some(cache_dir) = &self.symstore_cache_dir {
+            store = store.cache_path(cache_dir);
+        }
+
+        match self.symstore {
+            Some(Win32EnvSymstoreMode::None) => Some(None),
+            Some(Win32EnvSymstoreMode::Uncached) => Some(Some(store.no_cache())),
+            _ => Some(Some(store)),
+        }
+    }
+}
+
+fn var(name: &str) -> Option<String> {
+    env::var(name).ok().filter(|v| !v.is_empty())
+}
+
+fn parse_var<T: FromStr>(name: &str) -> Option<T> {
+    let value = var(name)?;
+    match value.parse() {
+        Ok(v) => Some(v),
+        Err(_) => {
+            warn!("ignoring invalid value for {}: {}", name, value);
+            None
+        }
+    }
+}
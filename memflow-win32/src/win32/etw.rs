@@ -0,0 +1,349 @@
+/*!
+Module for enumerating the event tracing for windows (etw) sessions of the kernel.
+
+Every active etw session is backed by a `_WMI_LOGGER_CONTEXT` in the logger table of the kernel.
+Since nt 10.0 the logger table and the table of registered providers are part of the etw state
+of the host silo (`PspHostSiloGlobals.EtwSiloState`), older versions keep them in global variables.
+Providers are stored in a hash table of `_ETW_GUID_ENTRY` structures, every entry keeps the
+enable information of up to 8 sessions the provider is enabled for.
+
+All structures are resolved from the kernel pdb which is loaded from the symbol store.
+Private loggers of processes are not part of the logger table and are not enumerated.
+
+This module is only available with the `symstore` feature enabled.
+
+# Examples:
+
+```
+use memflow::prelude::v1::*;
+use memflow_win32::offsets::SymbolStore;
+use memflow_win32::win32::Win32Kernel;
+
+fn test<T: 'static + PhysicalMemory + Clone, V: 'static + VirtualTranslate2 + Clone>(
+    kernel: &mut Win32Kernel<T, V>,
+) {
+    for session in kernel.etw_sessions(&SymbolStore::default()).unwrap() {
+        println!("{} ({})", session.name, session.log_file_name);
+        for provider in session.providers.iter() {
+            println!("  {} level={}", provider.guid, provider.level);
+        }
+    }
+}
+```
+*/
+use std::prelude::v1::*;
+
+use std::convert::TryInto;
+
+use super::{VirtualReadUnicodeString, Win32Kernel, Win32KernelTypes, Win32ListWalker};
+use crate::offsets::SymbolStore;
+
+use log::{debug, trace};
+
+use memflow::architecture::ArchitectureObj;
+use memflow::error::{Error, ErrorKind, ErrorOrigin, Result};
+use memflow::mem::{MemoryView, PhysicalMemory, VirtualTranslate2};
+use memflow::types::{umem, Address};
+
+/// Number of entries of the logger table
+const MAX_LOGGERS: usize = 64;
+/// Number of buckets of the provider hash table
+const GUID_HASH_BUCKETS: usize = 64;
+/// Number of sessions a provider can be enabled for
+const MAX_ENABLE_INFO: usize = 8;
+
+/// A provider that is enabled for an etw session
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize))]
+pub struct Win32EtwProvider {
+    /// Address of the `_ETW_GUID_ENTRY`
+    pub address: Address,
+    /// Guid of the provider, e.g. `{22fb2cd6-0e7b-422b-a0c7-2fad1fd0e716}`
+    pub guid: String,
+    pub level: u8,
+    pub match_any_keyword: u64,
+    pub match_all_keyword: u64,
+}
+
+/// An active etw session
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize))]
+pub struct Win32EtwSession {
+    /// Address of the `_WMI_LOGGER_CONTEXT`
+    pub address: Address,
+    pub logger_id: u32,
+    pub name: String,
+    /// Empty for real time sessions
+    pub log_file_name: String,
+    /// Combination of the `EVENT_TRACE_*_MODE` flags
+    pub logger_mode: u32,
+    /// Size of a buffer in bytes
+    pub buffer_size: u32,
+    pub minimum_buffers: u32,
+    pub maximum_buffers: u32,
+    pub number_of_buffers: u32,
+    pub events_lost: u32,
+    pub providers: Vec<Win32EtwProvider>,
+}
+
+impl<T: 'static + PhysicalMemory + Clone, V: 'static + VirtualTranslate2 + Clone>
+    Win32Kernel<T, V>
+{
+    /// Enumerates the active etw sessions and the providers that are enabled for them.
+    ///
+    /// The kernel pdb is loaded from the given symbol store.
+    pub fn etw_sessions(&mut self, store: &SymbolStore) -> Result<Vec<Win32EtwSession>> {
+        let symbols = self.kernel_symbols_from_store(store)?;
+        let mut types = self.kernel_types_from_store(store)?;
+        let arch: ArchitectureObj = self.kernel_info.os_info.arch.into();
+
+        // locate the logger table and the provider hash table
+        let silo_state = match (
+            symbols.address("PspHostSiloGlobals"),
+            types.offset_of("_ESERVERSILO_GLOBALS.EtwSiloState"),
+        ) {
+            (Ok(globals), Ok(offset)) => {
+                Some(self.virt_mem.read_addr_arch(arch, globals + offset)?)
+            }
+            _ => None,
+        };
+        let (logger_table, guid_table) = match silo_state {
+            Some(state) => (
+                state + types.offset_of("_ETW_SILODRIVERSTATE.EtwpLoggerContext")?,
+                types
+                    .offset_of("_ETW_SILODRIVERSTATE.EtwpGuidHashTable")
+                    .ok()
+                    .map(|offset| state + offset),
+            ),
+            None => (
+                symbols
+                    .address("EtwpLoggerContext")
+                    .or_else(|_| symbols.address("WmipLoggerContext"))?,
+                symbols.address("EtwpGuidHashTable").ok(),
+            ),
+        };
+        trace!(
+            "logger_table={:x} guid_table={:?}",
+            logger_table,
+            guid_table
+        );
+
+        let offsets = EtwOffsets::new(&mut types)?;
+        let mut sessions = vec![];
+        for logger in logger_contexts(&mut self.virt_mem, arch, logger_table)? {
+            match read_session(&mut self.virt_mem, arch, &offsets, logger) {
+                Ok(session) => sessions.push(session),
+                Err(err) => debug!("unable to read logger context {:x}: {}", logger, err),
+            }
+        }
+
+        match (guid_table, ProviderOffsets::new(&mut types)) {
+            (Some(guid_table), Ok(offsets)) => read_providers(
+                &mut self.virt_mem,
+                arch,
+                &offsets,
+                guid_table,
+                &mut sessions,
+            ),
+            _ => debug!("provider hash table is not available, skipping providers"),
+        }
+
+        Ok(sessions)
+    }
+}
+
+/// Offsets of `_WMI_LOGGER_CONTEXT`
+struct EtwOffsets {
+    logger_id: usize,
+    logger_name: usize,
+    log_file_name: usize,
+    logger_mode: usize,
+    buffer_size: usize,
+    minimum_buffers: usize,
+    maximum_buffers: usize,
+    number_of_buffers: usize,
+    events_lost: usize,
+}
+
+impl EtwOffsets {
+    fn new(types: &mut Win32KernelTypes) -> Result<Self> {
+        Ok(Self {
+            logger_id: types.offset_of("_WMI_LOGGER_CONTEXT.LoggerId")?,
+            logger_name: types.offset_of("_WMI_LOGGER_CONTEXT.LoggerName")?,
+            log_file_name: types.offset_of("_WMI_LOGGER_CONTEXT.LogFileName")?,
+            logger_mode: types.offset_of("_WMI_LOGGER_CONTEXT.LoggerMode")?,
+            buffer_size: types.offset_of("_WMI_LOGGER_CONTEXT.BufferSize")?,
+            minimum_buffers: types.offset_of("_WMI_LOGGER_CONTEXT.MinimumBuffers")?,
+            maximum_buffers: types.offset_of("_WMI_LOGGER_CONTEXT.MaximumBuffers")?,
+            number_of_buffers: types.offset_of("_WMI_LOGGER_CONTEXT.NumberOfBuffers")?,
+            events_lost: types.offset_of("_WMI_LOGGER_CONTEXT.EventsLost")?,
+        })
+    }
+}
+
+/// Offsets of `_ETW_HASH_BUCKET`, `_ETW_GUID_ENTRY` and `_TRACE_ENABLE_INFO`
+struct ProviderOffsets {
+    bucket_size: usize,
+    bucket_list_head: usize,
+    guid_list: usize,
+    guid: usize,
+    enable_info: usize,
+    enable_info_size: usize,
+    is_enabled: usize,
+    level: usize,
+    logger_id: usize,
+    match_any_keyword: usize,
+    match_all_keyword: usize,
+}
+
+impl ProviderOffsets {
+    fn new(types: &mut Win32KernelTypes) -> Result<Self> {
+        Ok(Self {
+            bucket_size: types.struct_size("_ETW_HASH_BUCKET")?,
+            // the first list of a bucket contains the trace guids
+            bucket_list_head: types.offset_of("_ETW_HASH_BUCKET.ListHead")?,
+            guid_list: types.offset_of("_ETW_GUID_ENTRY.GuidList")?,
+            guid: types.offset_of("_ETW_GUID_ENTRY.Guid")?,
+            enable_info: types.offset_of("_ETW_GUID_ENTRY.EnableInfo")?,
+            enable_info_size: types.struct_size("_TRACE_ENABLE_INFO")?,
+            is_enabled: types.offset_of("_TRACE_ENABLE_INFO.IsEnabled")?,
+            level: types.offset_of("_TRACE_ENABLE_INFO.Level")?,
+            logger_id: types.offset_of("_TRACE_ENABLE_INFO.LoggerId")?,
+            match_any_keyword: types.offset_of("_TRACE_ENABLE_INFO.MatchAnyKeyword")?,
+            match_all_keyword: types.offset_of("_TRACE_ENABLE_INFO.MatchAllKeyword")?,
+        })
+    }
+}
+
+/// Returns the logger contexts of all used slots of the logger table.
+fn logger_contexts<M: MemoryView>(
+    mem: &mut M,
+    arch: ArchitectureObj,
+    logger_table: Address,
+) -> Result<Vec<Address>> {
+    let ptr_size = arch.size_addr();
+    let mut table = vec![0u8; MAX_LOGGERS * ptr_size];
+    mem.read_raw_into(logger_table, &mut table)?;
+
+    // unused slots are either null or 1
+    Ok(table
+        .chunks_exact(ptr_size)
+        .map(|ptr| match ptr_size {
+            8 => Address::from(u64::from_le_bytes(ptr.try_into().unwrap())),
+            _ => Address::from(u32::from_le_bytes(ptr.try_into().unwrap())),
+        })
+        .filter(|logger| logger.to_umem() > 1)
+        .collect())
+}
+
+fn read_session<M: MemoryView>(
+    mem: &mut M,
+    arch: ArchitectureObj,
+    offsets: &EtwOffsets,
+    logger: Address,
+) -> Result<Win32EtwSession> {
+    let session = Win32EtwSession {
+        address: logger,
+        logger_id: mem.read(logger + offsets.logger_id)?,
+        name: mem
+            .read_unicode_string(arch, logger + offsets.logger_name)
+            .unwrap_or_default(),
+        log_file_name: mem
+            .read_unicode_string(arch, logger + offsets.log_file_name)
+            .unwrap_or_default(),
+        logger_mode: mem.read(logger + offsets.logger_mode)?,
+        buffer_size: mem.read(logger + offsets.buffer_size)?,
+        minimum_buffers: mem.read(logger + offsets.minimum_buffers)?,
+        maximum_buffers: mem.read(logger + offsets.maximum_buffers)?,
+        number_of_buffers: mem.read(logger + offsets.number_of_buffers)?,
+        events_lost: mem.read(logger + offsets.events_lost)?,
+        providers: vec![],
+    };
+    trace!("etw session {}: {}", session.logger_id, session.name);
+    Ok(session)
+}
+
+/// Walks the provider hash table and assigns every enabled provider to its sessions.
+fn read_providers<M: MemoryView>(
+    mem: &mut M,
+    arch: ArchitectureObj,
+    offsets: &ProviderOffsets,
+    guid_table: Address,
+    sessions: &mut [Win32EtwSession],
+) {
+    for bucket in 0..GUID_HASH_BUCKETS {
+        let list_head =
+            guid_table + (bucket * offsets.bucket_size + offsets.bucket_list_head) as umem;
+        let entries = match Win32ListWalker::new(arch, list_head).entries(mem) {
+            Ok(entries) => entries,
+            Err(err) => {
+                debug!("unable to walk guid hash bucket {}: {}", bucket, err);
+                continue;
+            }
+        };
+
+        for guid_entry in entries
+            .into_iter()
+            .filter(|&entry| entry != list_head)
+            .map(|entry| entry - offsets.guid_list)
+        {
+            let guid = match read_guid(mem, guid_entry + offsets.guid) {
+                Ok(guid) => guid,
+                Err(_) => continue,
+            };
+
+            for idx in 0..MAX_ENABLE_INFO {
+                let info = guid_entry + (offsets.enable_info + idx * offsets.enable_info_size);
+                let provider = read_enable_info(mem, offsets, info, guid_entry, &guid);
+                if let Ok(Some((logger_id, provider))) = provider {
+                    if let Some(session) = sessions
+                        .iter_mut()
+                        .find(|session| session.logger_id == logger_id as u32)
+                    {
+                        session.providers.push(provider);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Reads a `_TRACE_ENABLE_INFO` and returns the logger id and the provider if it is enabled.
+fn read_enable_info<M: MemoryView>(
+    mem: &mut M,
+    offsets: &ProviderOffsets,
+    info: Address,
+    guid_entry: Address,
+    guid: &str,
+) -> Result<Option<(u16, Win32EtwProvider)>> {
+    if mem.read::<u32>(info + offsets.is_enabled)? == 0 {
+        return Ok(None);
+    }
+    Ok(Some((
+        mem.read(info + offsets.logger_id)?,
+        Win32EtwProvider {
+            address: guid_entry,
+            guid: guid.to_string(),
+            level: mem.read(info + offsets.level)?,
+            match_any_keyword: mem.read(info + offsets.match_any_keyword)?,
+            match_all_keyword: mem.read(info + offsets.match_all_keyword)?,
+        },
+    )))
+}
+
+/// Reads a `_GUID` and formats it in registry format.
+fn read_guid<M: MemoryView>(mem: &mut M, address: Address) -> Result<String> {
+    let mut buf = [0u8; 16];
+    mem.read_raw_into(address, &mut buf)?;
+    let data1 = u32::from_le_bytes(buf[0..4].try_into().unwrap());
+    let data2 = u16::from_le_bytes(buf[4..6].try_into().unwrap());
+    let data3 = u16::from_le_bytes(buf[6..8].try_into().unwrap());
+    if data1 == 0 && data2 == 0 && data3 == 0 {
+        return Err(Error(ErrorOrigin::OsLayer, ErrorKind::InvalidArgument)
+            .log_trace("guid entry without a guid"));
+    }
+    Ok(format!(
+        "{{{:08x}-{:04x}-{:04x}-{:02x}{:02x}-{:02x}{:02x}{:02x}{:02x}{:02x}{:02x}}}",
+        data1, data2, data3, buf[8], buf[9], buf[10], buf[11], buf[12], buf[13], buf[14], buf[15]
+    ))
+}
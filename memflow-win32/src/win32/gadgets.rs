@@ -0,0 +1,436 @@
+/*!
+Module for locating code gadgets in the executable sections of loaded modules.
+
+A gadget is a short instruction sequence that is terminated by a return instruction.
+Gadgets are searched for by user supplied byte patterns that may contain wildcards.
+Every match is extended up to the next return instruction as long as the whole sequence
+does not exceed the configured maximum length.
+
+Return instructions are recognized per architecture:
+- x86: `ret` (`c3`) and `ret imm16` (`c2 iw`)
+- aarch64: `ret` (`d65f03c0`) on a 4 byte aligned boundary
+
+With the `disasm` feature enabled x86 gadgets are decoded with iced-x86 instead. A match is only
+reported if the instructions starting at the match decode up to a `ret` without passing an invalid
+instruction or another branch, so return opcodes inside of operands are not mistaken for the end
+of a gadget. Without the feature the gadget simply ends at the first return opcode.
+
+Modules whose headers cannot be parsed are skipped.
+
+# Examples:
+
+```
+use memflow::prelude::v1::*;
+use memflow_win32::win32::{Win32GadgetOptions, Win32GadgetPattern, Win32Kernel};
+
+fn test<T: 'static + PhysicalMemory + Clone, V: 'static + VirtualTranslate2 + Clone>(
+    kernel: &mut Win32Kernel<T, V>,
+) {
+    // mov rsp, ??; ...; ret
+    let options = Win32GadgetOptions::new()
+        .pattern(Win32GadgetPattern::parse("48 8b e?").unwrap())
+        .max_len(16);
+
+    for gadget in kernel.kernel_gadgets(&["ntoskrnl.exe"], &options).unwrap() {
+        println!("{} {:x}: {:02x?}", gadget.module, gadget.address, gadget.bytes);
+    }
+}
+```
+*/
+use std::prelude::v1::*;
+
+use super::{Win32Kernel, Win32Process, Win32VirtualTranslate};
+
+use memflow::architecture::ArchitectureIdent;
+use memflow::error::{Error, ErrorKind, ErrorOrigin, PartialResultExt, Result};
+use memflow::mem::{MemoryView, PhysicalMemory, VirtualTranslate2};
+use memflow::os::{ModuleInfo, Os, Process};
+use memflow::types::{umem, Address};
+
+use log::{debug, trace};
+use std::convert::TryInto;
+
+use pelite::{image::IMAGE_SCN_MEM_EXECUTE, PeView};
+
+#[cfg(feature = "disasm")]
+use iced_x86::{Decoder, DecoderOptions, FlowControl, Instruction, Mnemonic};
+
+const AARCH64_RET: [u8; 4] = [0xc0, 0x03, 0x5f, 0xd6];
+
+/// Byte pattern with optional wildcards
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize))]
+pub struct Win32GadgetPattern {
+    bytes: Vec<u8>,
+    mask: Vec<u8>,
+}
+
+impl Win32GadgetPattern {
+    /// Creates a pattern that matches the given bytes exactly.
+    pub fn new(bytes: &[u8]) -> Self {
+        Self {
+            bytes: bytes.to_vec(),
+            mask: vec![0xff; bytes.len()],
+        }
+    }
+
+    /// Parses a pattern of whitespace separated hex bytes.
+    ///
+    /// Each nibble can be replaced by a `?` to match any value (e.g. `48 8b e? ??`).
+    pub fn parse(pattern: &str) -> Result<Self> {
+        let mut bytes = vec![];
+        let mut mask = vec![];
+
+        for token in pattern.split_whitespace() {
+            let nibbles = token.as_bytes();
+            if nibbles.len() != 2 {
+                return Err(Error(ErrorOrigin::OsLayer, ErrorKind::InvalidArgument)
+                    .log_info(format!("invalid pattern byte: {}", token)));
+            }
+
+            let (mut byte, mut byte_mask) = (0u8, 0u8);
+            for &nibble in nibbles.iter() {
+                let (value, value_mask) = match nibble {
+                    b'?' => (0, 0),
+                    _ => match (nibble as char).to_digit(16) {
+                        Some(value) => (value as u8, 0xf),
+                        None => {
+                            return Err(Error(ErrorOrigin::OsLayer, ErrorKind::InvalidArgument)
+                                .log_info(format!("invalid pattern byte: {}", token)))
+                        }
+                    },
+                };
+                byte = (byte << 4) | value;
+                byte_mask = (byte_mask << 4) | value_mask;
+            }
+
+            bytes.push(byte);
+            mask.push(byte_mask);
+        }
+
+        if bytes.is_empty() {
+            return Err(Error(ErrorOrigin::OsLayer, ErrorKind::InvalidArgument)
+                .log_info("gadget pattern must not be empty"));
+        }
+
+        Ok(Self { bytes, mask })
+    }
+
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    /// Returns true if the pattern matches the start of the given buffer.
+    pub fn matches(&self, buf: &[u8]) -> bool {
+        buf.len() >= self.len()
+            && self
+                .bytes
+                .iter()
+                .zip(self.mask.iter())
+                .zip(buf.iter())
+                .all(|((b, m), v)| v & m == *b)
+    }
+}
+
+/// Options controlling the gadget search
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize))]
+pub struct Win32GadgetOptions {
+    pub patterns: Vec<Win32GadgetPattern>,
+    /// Maximum length of a gadget in bytes including the return instruction
+    pub max_len: usize,
+}
+
+impl Default for Win32GadgetOptions {
+    fn default() -> Self {
+        Self {
+            patterns: vec![],
+            max_len: 16,
+        }
+    }
+}
+
+impl Win32GadgetOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a pattern a gadget has to start with.
+    pub fn pattern(mut self, pattern: Win32GadgetPattern) -> Self {
+        self.patterns.push(pattern);
+        self
+    }
+
+    pub fn max_len(mut self, max_len: usize) -> Self {
+        self.max_len = max_len;
+        self
+    }
+}
+
+/// A gadget found in an executable section
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize))]
+pub struct Win32Gadget {
+    /// Name of the module the gadget resides in
+    pub module: String,
+    /// Virtual address of the first byte of the gadget
+    pub address: Address,
+    /// Index of the pattern that matched
+    pub pattern: usize,
+    /// Bytes of the gadget including the return instruction
+    pub bytes: Vec<u8>,
+}
+
+impl<T: 'static + PhysicalMemory + Clone, V: 'static + VirtualTranslate2 + Clone>
+    Win32Kernel<T, V>
+{
+    /// Searches the given kernel modules for gadgets.
+    ///
+    /// Module names are compared case insensitively. All modules are searched if `modules` is empty.
+    pub fn kernel_gadgets(
+        &mut self,
+        modules: &[&str],
+        options: &Win32GadgetOptions,
+    ) -> Result<Vec<Win32Gadget>> {
+        let modules = self
+            .module_list()?
+            .into_iter()
+            .filter(|m| {
+                modules.is_empty()
+                    || modules
+                        .iter()
+                        .any(|n| m.name.as_ref().eq_ignore_ascii_case(n))
+            })
+            .collect::<Vec<_>>();
+
+        let mut gadgets = vec![];
+        for module in modules.iter() {
+            match find_gadgets(&mut self.virt_mem, module, options) {
+                Ok(mut found) => gadgets.append(&mut found),
+                Err(err) => debug!("unable to search {} for gadgets: {}", module.name, err),
+            }
+        }
+        Ok(gadgets)
+    }
+}
+
+impl<T: PhysicalMemory, V: VirtualTranslate2> Win32Process<T, V, Win32VirtualTranslate> {
+    /// Searches the given modules of this process for gadgets.
+    ///
+    /// Module names are compared case insensitively. All modules are searched if `modules` is empty.
+    pub fn gadgets(
+        &mut self,
+        modules: &[&str],
+        options: &Win32GadgetOptions,
+    ) -> Result<Vec<Win32Gadget>> {
+        let modules = self
+            .module_list()?
+            .into_iter()
+            .filter(|m| {
+                modules.is_empty()
+                    || modules
+                        .iter()
+                        .any(|n| m.name.as_ref().eq_ignore_ascii_case(n))
+            })
+            .collect::<Vec<_>>();
+
+        let mut gadgets = vec![];
+        for module in modules.iter() {
+            match find_gadgets(&mut self.virt_mem, module, options) {
+                Ok(mut found) => gadgets.append(&mut found),
+                Err(err) => debug!("unable to search {} for gadgets: {}", module.name, err),
+            }
+        }
+        Ok(gadgets)
+    }
+}
+
+/// Searches all executable sections of a single module for gadgets.
+pub fn find_gadgets<M: MemoryView>(
+    mem: &mut M,
+    module: &ModuleInfo,
+    options: &Win32GadgetOptions,
+) -> Result<Vec<Win32Gadget>> {
+    match module.arch {
+        ArchitectureIdent::X86(_, _) | ArchitectureIdent::AArch64(_) => {}
+        _ => {
+            return Err(Error(ErrorOrigin::OsLayer, ErrorKind::NotSupported)
+                .log_info("gadget search is not supported on this architecture"))
+        }
+    }
+
+    let size = module.size.try_into().map_err(|_| {
+        Error(ErrorOrigin::OsLayer, ErrorKind::InvalidArgument)
+            .log_info("module size exceeds the address space")
+    })?;
+    let image = mem.read_raw(module.base, size).data_part()?;
+    let pe = PeView::from_bytes(&image)
+        .map_err(|err| Error(ErrorOrigin::OsLayer, ErrorKind::InvalidExeFile).log_info(err))?;
+
+    let mut gadgets = vec![];
+    for section in pe
+        .section_headers()
+        .iter()
+        .filter(|s| s.Characteristics & IMAGE_SCN_MEM_EXECUTE != 0)
+    {
+        let start = section.VirtualAddress as usize;
+        let end = (start + section.VirtualSize as usize).min(image.len());
+        if start >= end {
+            continue;
+        }
+        trace!(
+            "searching section {} of {} for gadgets",
+            section.name().unwrap_or("?"),
+            module.name
+        );
+
+        for (offset, pattern, len) in scan_section(&image[start..end], module.arch, options) {
+            gadgets.push(Win32Gadget {
+                module: module.name.to_string(),
+                address: module.base + (start + offset) as umem,
+                pattern,
+                bytes: image[start + offset..start + offset + len].to_vec(),
+            });
+        }
+    }
+
+    debug!("found {} gadgets in {}", gadgets.len(), module.name);
+    Ok(gadgets)
+}
+
+/// Returns the offset, pattern index and length of every gadget in the buffer.
+fn scan_section(
+    buf: &[u8],
+    arch: ArchitectureIdent,
+    options: &Win32GadgetOptions,
+) -> Vec<(usize, usize, usize)> {
+    let step = match arch {
+        ArchitectureIdent::AArch64(_) => 4,
+        _ => 1,
+    };
+
+    let mut gadgets = vec![];
+    for offset in (0..buf.len()).step_by(step) {
+        for (idx, pattern) in options.patterns.iter().enumerate() {
+            if !pattern.matches(&buf[offset..]) {
+                continue;
+            }
+
+            let window = &buf[offset..(offset + options.max_len).min(buf.len())];
+            if let Some(len) = gadget_len(window, pattern.len(), arch) {
+                gadgets.push((offset, idx, len));
+                break;
+            }
+        }
+    }
+    gadgets
+}
+
+/// Returns the length of the gadget up to and including the first return instruction
+/// that follows the matched pattern.
+fn gadget_len(window: &[u8], pattern_len: usize, arch: ArchitectureIdent) -> Option<usize> {
+    match arch {
+        ArchitectureIdent::AArch64(_) => {
+            let start = (pattern_len + 3) & !3;
+            (start..window.len())
+                .step_by(4)
+                .find(|&i| window[i..].starts_with(&AARCH64_RET))
+                .map(|i| i + AARCH64_RET.len())
+        }
+        #[cfg(feature = "disasm")]
+        ArchitectureIdent::X86(bitness, _) => {
+            decode_x86_gadget(window, pattern_len, bitness as u32)
+        }
+        _ => (pattern_len..window.len()).find_map(|i| match window[i] {
+            0xc3 => Some(i + 1),
+            0xc2 if i + 3 <= window.len() => Some(i + 3),
+            _ => None,
+        }),
+    }
+}
+
+/// Decodes the instructions of the window up to the first `ret` and returns its end.
+#[cfg(feature = "disasm")]
+fn decode_x86_gadget(window: &[u8], pattern_len: usize, bitness: u32) -> Option<usize> {
+    let mut decoder = Decoder::new(bitness, window, DecoderOptions::NONE);
+    let mut instr = Instruction::default();
+    while decoder.can_decode() {
+        decoder.decode_out(&mut instr);
+        if instr.is_invalid() {
+            return None;
+        }
+
+        match instr.flow_control() {
+            FlowControl::Next => {}
+            FlowControl::Return if instr.mnemonic() == Mnemonic::Ret => {
+                return Some(decoder.position()).filter(|&len| len > pattern_len);
+            }
+            _ => return None,
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_pattern() {
+        let pattern = Win32GadgetPattern::parse("48 8b e?").unwrap();
+        assert!(pattern.matches(&[0x48, 0x8b, 0xe4, 0xc3]));
+        assert!(!pattern.matches(&[0x48, 0x8b, 0xd4, 0xc3]));
+        assert!(!pattern.matches(&[0x48, 0x8b]));
+        assert!(Win32GadgetPattern::parse("48 8").is_err());
+        assert!(Win32GadgetPattern::parse("").is_err());
+    }
+
+    #[test]
+    fn scan_x64() {
+        let arch = ArchitectureIdent::X86(64, false);
+        let options = Win32GadgetOptions::new()
+            .pattern(Win32GadgetPattern::parse("48 8b e0").unwrap())
+            .max_len(8);
+
+        // mov rsp, rax; pop rbp; ret
+        let buf = [0x90, 0x48, 0x8b, 0xe0, 0x5d, 0xc3, 0x90];
+        assert_eq!(scan_section(&buf, arch, &options), vec![(1, 0, 5)]);
+
+        // the return is too far away
+        let buf = [0x48, 0x8b, 0xe0, 0x90, 0x90, 0x90, 0x90, 0x90, 0xc3];
+        assert!(scan_section(&buf, arch, &options).is_empty());
+    }
+
+    #[test]
+    fn scan_aarch64() {
+        let arch = ArchitectureIdent::AArch64(0x1000);
+        let options = Win32GadgetOptions::new()
+            .pattern(Win32GadgetPattern::parse("fd 7b c1 a8").unwrap())
+            .max_len(16);
+
+        // ldp x29, x30, [sp], #0x10; ret
+        let buf = [0xfd, 0x7b, 0xc1, 0xa8, 0xc0, 0x03, 0x5f, 0xd6];
+        assert_eq!(scan_section(&buf, arch, &options), vec![(0, 0, 8)]);
+    }
+
+    #[cfg(feature = "disasm")]
+    #[test]
+    fn scan_x64_decoded() {
+        let arch = ArchitectureIdent::X86(64, false);
+        let options = Win32GadgetOptions::new()
+            .pattern(Win32GadgetPattern::parse("b8").unwrap())
+            .max_len(8);
+
+        // mov eax, 0xc3; ret, the first c3 is part of the immediate
+        let buf = [0xb8, 0xc3, 0x00, 0x00, 0x00, 0xc3];
+        assert_eq!(scan_section(&buf, arch, &options), vec![(0, 0, 6)]);
+
+        // mov eax, 0xc3; jmp rax; ret
+        let buf = [0xb8, 0xc3, 0x00, 0x00, 0x00, 0xff, 0xe0, 0xc3];
+        assert!(scan_section(&buf, arch, &options).is_empty());
+    }
+}
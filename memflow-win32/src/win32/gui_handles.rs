@@ -0,0 +1,373 @@
+/*!
+Module for enumerating the gdi and user objects of the processes of the interactive session.
+
+Both handle tables are mapped read-only into every gui process of a session:
+- the gdi handle table is referenced by `GdiSharedHandleTable` in the peb,
+  every entry holds the process id of its owner and the type of the object
+- the user handle table is referenced by `gSharedInfo` which is exported by `user32.dll`,
+  every entry references its owning `W32THREAD` or `W32PROCESS` in the kernel
+
+The owners are attributed to the processes and threads of the process list. Gdi objects of
+pids and user objects of owners that are not part of the process list indicate hidden processes,
+this serves as another cross view besides the process list itself.
+
+A process of the interactive session (e.g. `explorer.exe`) is used as a proxy to read the tables,
+objects of other sessions are not enumerated. The gdi handle table only stores the low 16 bits
+of the process id.
+
+# Examples:
+
+```
+use memflow::prelude::v1::*;
+use memflow_win32::win32::Win32Kernel;
+
+fn test<T: 'static + PhysicalMemory + Clone, V: 'static + VirtualTranslate2 + Clone>(
+    kernel: &mut Win32Kernel<T, V>,
+) {
+    let handles = kernel.gui_handles().unwrap();
+    for process in handles.processes.iter() {
+        println!(
+            "{}: {} user objects, {} gdi objects",
+            process.pid,
+            process.user_object_count(),
+            process.gdi_object_count()
+        );
+    }
+    for process in handles.hidden_processes() {
+        println!("hidden process {}", process.pid);
+    }
+}
+```
+*/
+use std::prelude::v1::*;
+
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+
+use super::Win32Kernel;
+use crate::kernel::ntos::lazy_pe::LazyPe;
+
+use log::{debug, trace};
+
+use memflow::architecture::ArchitectureObj;
+use memflow::error::{Error, ErrorKind, ErrorOrigin, PartialResultExt, Result};
+use memflow::mem::{MemoryView, PhysicalMemory, VirtualTranslate2};
+use memflow::os::{Os, Pid, Process};
+use memflow::types::Address;
+
+/// Number of entries of the gdi handle table
+const GDI_HANDLE_COUNT: usize = 0x10000;
+/// Upper bound for the number of entries of the user handle table
+const MAX_USER_HANDLES: usize = 0x10000;
+
+/// Kind of a user object
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize))]
+pub enum Win32UserObjectType {
+    Window,
+    Menu,
+    Cursor,
+    SetWindowPos,
+    Hook,
+    ClipData,
+    CallProc,
+    AccelTable,
+    DdeAccess,
+    DdeConv,
+    DdeXact,
+    Monitor,
+    KbdLayout,
+    KbdFile,
+    WinEventHook,
+    Timer,
+    InputContext,
+    HidData,
+    DeviceInfo,
+    TouchInputInfo,
+    GestureInfo,
+    Other(u8),
+}
+
+impl From<u8> for Win32UserObjectType {
+    fn from(value: u8) -> Self {
+        match value {
+            1 => Self::Window,
+            2 => Self::Menu,
+            3 => Self::Cursor,
+            4 => Self::SetWindowPos,
+            5 => Self::Hook,
+            6 => Self::ClipData,
+            7 => Self::CallProc,
+            8 => Self::AccelTable,
+            9 => Self::DdeAccess,
+            10 => Self::DdeConv,
+            11 => Self::DdeXact,
+            12 => Self::Monitor,
+            13 => Self::KbdLayout,
+            14 => Self::KbdFile,
+            15 => Self::WinEventHook,
+            16 => Self::Timer,
+            17 => Self::InputContext,
+            18 => Self::HidData,
+            19 => Self::DeviceInfo,
+            20 => Self::TouchInputInfo,
+            21 => Self::GestureInfo,
+            other => Self::Other(other),
+        }
+    }
+}
+
+/// Kind of a gdi object
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize))]
+pub enum Win32GdiObjectType {
+    Dc,
+    Region,
+    Bitmap,
+    Path,
+    Palette,
+    ColorSpace,
+    Font,
+    Brush,
+    Other(u8),
+}
+
+impl From<u8> for Win32GdiObjectType {
+    fn from(value: u8) -> Self {
+        match value {
+            0x01 => Self::Dc,
+            0x04 => Self::Region,
+            0x05 => Self::Bitmap,
+            0x07 => Self::Path,
+            0x08 => Self::Palette,
+            0x09 => Self::ColorSpace,
+            0x0a => Self::Font,
+            0x10 => Self::Brush,
+            other => Self::Other(other),
+        }
+    }
+}
+
+/// Gdi and user objects of a single process
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize))]
+pub struct Win32ProcessGuiHandles {
+    pub pid: Pid,
+    /// Address of the eprocess, `None` if the process is not part of the process list
+    pub eprocess: Option<Address>,
+    /// Number of objects per kind
+    pub user_objects: Vec<(Win32UserObjectType, usize)>,
+    /// Number of objects per kind
+    pub gdi_objects: Vec<(Win32GdiObjectType, usize)>,
+}
+
+impl Win32ProcessGuiHandles {
+    pub fn user_object_count(&self) -> usize {
+        self.user_objects.iter().map(|(_, count)| count).sum()
+    }
+
+    pub fn gdi_object_count(&self) -> usize {
+        self.gdi_objects.iter().map(|(_, count)| count).sum()
+    }
+}
+
+/// Gdi and user objects of the interactive session
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize))]
+pub struct Win32GuiHandles {
+    pub processes: Vec<Win32ProcessGuiHandles>,
+    /// Owners of user objects that are neither a process nor a thread of the process list
+    pub unknown_user_owners: Vec<Address>,
+}
+
+impl Win32GuiHandles {
+    /// Returns the processes that own gdi objects but are not part of the process list.
+    pub fn hidden_processes(&self) -> impl Iterator<Item = &Win32ProcessGuiHandles> {
+        self.processes.iter().filter(|p| p.eprocess.is_none())
+    }
+}
+
+/// Counters of a process while the tables are walked
+#[derive(Default)]
+struct Counters {
+    user: BTreeMap<Win32UserObjectType, usize>,
+    gdi: BTreeMap<Win32GdiObjectType, usize>,
+}
+
+impl<T: 'static + PhysicalMemory + Clone, V: 'static + VirtualTranslate2 + Clone>
+    Win32Kernel<T, V>
+{
+    /// Enumerates the gdi and user objects of the processes of the interactive session.
+    pub fn gui_handles(&mut self) -> Result<Win32GuiHandles> {
+        // attribute eprocess and ethread addresses to the process list
+        let procs = self.process_info_list()?;
+        let mut owners = BTreeMap::new();
+        for proc in procs.iter() {
+            owners.insert(proc.address, proc.pid);
+            match self.thread_address_list(proc.address) {
+                Ok(threads) => owners.extend(threads.into_iter().map(|t| (t, proc.pid))),
+                Err(err) => debug!("unable to read the threads of {}: {}", proc.name, err),
+            }
+        }
+
+        let proxy = self.interactive_session_proxy()?;
+        debug!(
+            "reading gui handle tables through proxy process {}",
+            proxy.name
+        );
+        let mut process = self.process_by_info(proxy)?;
+        let arch: ArchitectureObj = process.proc_info.base_info.proc_arch.into();
+
+        let mut counters: BTreeMap<Pid, Counters> = BTreeMap::new();
+        let mut unknown_user_owners = vec![];
+
+        // gdi handle table
+        match gdi_handle_table(&mut process.virt_mem, arch, process.proc_info.peb_native()) {
+            Ok(entries) => {
+                for (pid, object_type) in entries.into_iter() {
+                    *counters
+                        .entry(pid)
+                        .or_default()
+                        .gdi
+                        .entry(object_type)
+                        .or_default() += 1;
+                }
+            }
+            Err(err) => debug!("unable to read the gdi handle table: {}", err),
+        }
+
+        // user handle table
+        let user32 = process
+            .module_list()?
+            .into_iter()
+            .find(|m| m.name.as_ref().eq_ignore_ascii_case("user32.dll"));
+        match user32.map(|user32| user_handle_table(&mut process.virt_mem, arch, user32.base)) {
+            Some(Ok(entries)) => {
+                // the first field of W32THREAD and W32PROCESS is the ethread or eprocess
+                let mut resolved: BTreeMap<Address, Option<Pid>> = BTreeMap::new();
+                for (owner, object_type) in entries.into_iter() {
+                    let pid = *resolved.entry(owner).or_insert_with(|| {
+                        let kernel_object = process.virt_mem.read_addr_arch(arch, owner).ok()?;
+                        owners.get(&kernel_object).copied()
+                    });
+                    match pid {
+                        Some(pid) => {
+                            *counters
+                                .entry(pid)
+                                .or_default()
+                                .user
+                                .entry(object_type)
+                                .or_default() += 1
+                        }
+                        None if !unknown_user_owners.contains(&owner) => {
+                            unknown_user_owners.push(owner)
+                        }
+                        None => (),
+                    }
+                }
+            }
+            Some(Err(err)) => debug!("unable to read the user handle table: {}", err),
+            None => debug!("user32.dll is not loaded in the proxy process"),
+        }
+
+        let processes = counters
+            .into_iter()
+            .map(|(pid, counters)| Win32ProcessGuiHandles {
+                pid,
+                // the gdi handle table only stores the low 16 bits of the pid
+                eprocess: procs
+                    .iter()
+                    .find(|p| p.pid == pid || p.pid & 0xffff == pid)
+                    .map(|p| p.address),
+                user_objects: counters.user.into_iter().collect(),
+                gdi_objects: counters.gdi.into_iter().collect(),
+            })
+            .collect();
+
+        Ok(Win32GuiHandles {
+            processes,
+            unknown_user_owners,
+        })
+    }
+}
+
+/// Reads the pid and type of all used entries of the gdi handle table.
+fn gdi_handle_table<M: MemoryView>(
+    mem: &mut M,
+    arch: ArchitectureObj,
+    peb: Option<Address>,
+) -> Result<Vec<(Pid, Win32GdiObjectType)>> {
+    let peb = peb.ok_or_else(|| {
+        Error(ErrorOrigin::OsLayer, ErrorKind::NotFound)
+            .log_info("peb of the proxy is not available")
+    })?;
+    let ptr_size = arch.size_addr();
+    let table_offset = if ptr_size == 8 { 0xf8 } else { 0x94 };
+    let table = mem.read_addr_arch(arch, peb + table_offset)?;
+    if table.is_null() {
+        return Err(Error(ErrorOrigin::OsLayer, ErrorKind::NotFound)
+            .log_info("the proxy process does not map the gdi handle table"));
+    }
+    trace!("gdi handle table: {:x}", table);
+
+    // pKernelAddress, wProcessId, wCount, wUpper, wType and pUserAddress
+    let entry_size = 2 * ptr_size + 8;
+    let mut buf = vec![0u8; GDI_HANDLE_COUNT * entry_size];
+    // pages of the table that are not committed yet are left empty
+    mem.read_raw_into(table, &mut buf).data_part()?;
+
+    Ok(buf
+        .chunks_exact(entry_size)
+        .filter_map(|entry| {
+            let pid = u16::from_le_bytes(entry[ptr_size..ptr_size + 2].try_into().unwrap());
+            let object_type = entry[ptr_size + 6] & 0x7f;
+            (object_type != 0).then_some((pid as Pid, object_type.into()))
+        })
+        .collect())
+}
+
+/// Reads the owner and type of all used entries of the user handle table.
+fn user_handle_table<M: MemoryView>(
+    mem: &mut M,
+    arch: ArchitectureObj,
+    user32: Address,
+) -> Result<Vec<(Address, Win32UserObjectType)>> {
+    let ptr_size = arch.size_addr();
+    let shared_info = user32 + LazyPe::new(mem, user32)?.export("gSharedInfo")?;
+
+    // psi, aheList and HeEntrySize
+    let server_info = mem.read_addr_arch(arch, shared_info)?;
+    let handle_table = mem.read_addr_arch(arch, shared_info + ptr_size)?;
+    let entry_size = match mem.read::<u32>(shared_info + 2 * ptr_size) {
+        Ok(size) if (3 * ptr_size..0x100).contains(&(size as usize)) => size as usize,
+        _ => 3 * ptr_size,
+    };
+    // cHandleEntries follows dwSRVIFlags
+    let count = (mem.read_addr_arch(arch, server_info + ptr_size)?.to_umem() as usize)
+        .min(MAX_USER_HANDLES);
+    trace!(
+        "user handle table: {:x} entries={} entry_size={:x}",
+        handle_table,
+        count,
+        entry_size
+    );
+
+    let mut buf = vec![0u8; count * entry_size];
+    mem.read_raw_into(handle_table, &mut buf).data_part()?;
+
+    // phead, pOwner, bType, bFlags and wUniq
+    let read_ptr = |buf: &[u8]| -> Address {
+        match ptr_size {
+            8 => Address::from(u64::from_le_bytes(buf[..8].try_into().unwrap())),
+            _ => Address::from(u32::from_le_bytes(buf[..4].try_into().unwrap())),
+        }
+    };
+    Ok(buf
+        .chunks_exact(entry_size)
+        .filter_map(|entry| {
+            let owner = read_ptr(&entry[ptr_size..]);
+            let object_type = entry[2 * ptr_size];
+            (object_type != 0 && !owner.is_null()).then_some((owner, object_type.into()))
+        })
+        .collect())
+}
@@ -0,0 +1,318 @@
+/*!
+Module for detecting hollowed processes.
+
+A hollowed process is created from a legitimate image whose code is replaced before the process
+starts running, a doppelgänged process maps an image from a file that differs from the one the
+process claims to run. Both techniques leave inconsistencies between the structures the kernel
+keeps about the process and the structures in user space:
+- the `ImageBaseAddress` of the peb does not match the `SectionBaseAddress` of the eprocess
+- the first entry of the loader list does not start at the section base
+- the vad at the section base is missing or is not an image mapping
+- the file mapped by the image vad does not match the name of the process
+
+Resolving the mapped file requires the `_MMVAD`, `_SUBSECTION`, `_CONTROL_AREA` and `_FILE_OBJECT`
+offsets which are only available when the offsets were generated from a pdb or an isf profile.
+
+# Examples:
+
+```
+use memflow::prelude::v1::*;
+use memflow_win32::win32::{Win32Process, Win32VirtualTranslate};
+
+fn test<T: PhysicalMemory, V: VirtualTranslate2>(process: &mut Win32Process<T, V, Win32VirtualTranslate>) {
+    let report = process.hollowing_report().unwrap();
+    for indicator in report.indicators.iter() {
+        println!("{}: {:?}", process.proc_info.base_info.name, indicator);
+    }
+}
+```
+*/
+use std::prelude::v1::*;
+
+use super::{VirtualReadUnicodeString, Win32Process, Win32VirtualTranslate};
+
+use crate::prelude::MmVadOffsetTable;
+
+use log::{debug, trace};
+
+use memflow::architecture::{ArchitectureIdent, ArchitectureObj};
+use memflow::error::{Error, ErrorKind, ErrorOrigin, Result};
+use memflow::mem::{MemoryView, PhysicalMemory, VirtualTranslate2};
+use memflow::os::{ModuleAddressInfo, Process};
+use memflow::types::{umem, Address};
+
+/// `_MI_VAD_TYPE::VadImageMap`
+pub(crate) const VAD_IMAGE_MAP: u32 = 2;
+
+/// Upper bound for the depth of the vad tree, the tree is balanced so this is never reached
+const MAX_VAD_DEPTH: usize = 64;
+
+/// Inconsistency that indicates a hollowed or doppelgänged process
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize))]
+pub enum Win32HollowingIndicator {
+    /// The image base in the peb differs from the section base of the process
+    PebImageBaseMismatch { peb_image_base: Address },
+    /// The first entry of the loader list does not start at the section base
+    LdrImageBaseMismatch { ldr_image_base: Address },
+    /// No vad covers the section base of the process
+    ImageVadMissing,
+    /// The vad at the section base is not an image mapping, e.g. private memory
+    NotAnImageVad { vad_type: u32 },
+    /// The file mapped at the section base does not match the name of the process
+    FileNameMismatch { file_name: String },
+}
+
+/// Result of the hollowing checks of a process
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize))]
+pub struct Win32HollowingReport {
+    /// `SectionBaseAddress` of the eprocess
+    pub section_base: Address,
+    /// `ImageBaseAddress` of the peb, `None` if the peb could not be read
+    pub peb_image_base: Option<Address>,
+    /// Base of the first entry of the loader list, `None` if the list could not be read
+    pub ldr_image_base: Option<Address>,
+    /// Name of the file mapped at the section base, `None` if the offsets are not available
+    pub mapped_file_name: Option<String>,
+    pub indicators: Vec<Win32HollowingIndicator>,
+}
+
+impl Win32HollowingReport {
+    /// Returns true if any indicator was found.
+    pub fn is_suspicious(&self) -> bool {
+        !self.indicators.is_empty()
+    }
+}
+
+impl<T: PhysicalMemory, V: VirtualTranslate2> Win32Process<T, V, Win32VirtualTranslate> {
+    /// Compares the peb, the loader list and the vad tree against the section base of the process.
+    ///
+    /// Checks that require structures that cannot be read (e.g. because they are paged out)
+    /// are skipped, the corresponding fields of the report are left empty.
+    pub fn hollowing_report(&mut self) -> Result<Win32HollowingReport> {
+        let section_base = self.proc_info.section_base;
+        if section_base.is_null() {
+            return Err(Error(ErrorOrigin::OsLayer, ErrorKind::NotSupported)
+                .log_info("section base of the process is not available"));
+        }
+
+        let mut report = Win32HollowingReport {
+            section_base,
+            ..Default::default()
+        };
+
+        // peb
+        let proc_arch = self.proc_info.base_info.proc_arch;
+        let proc_arch_obj = ArchitectureObj::from(proc_arch);
+        report.peb_image_base = self.proc_info.peb().and_then(|peb| {
+            self.virt_mem
+                .read_addr_arch(proc_arch_obj, peb + 2 * proc_arch_obj.size_addr())
+                .ok()
+        });
+        if let Some(peb_image_base) = report.peb_image_base {
+            if peb_image_base != section_base {
+                report
+                    .indicators
+                    .push(Win32HollowingIndicator::PebImageBaseMismatch { peb_image_base });
+            }
+        }
+
+        // loader list
+        report.ldr_image_base = self.first_ldr_module_base(proc_arch);
+        if let Some(ldr_image_base) = report.ldr_image_base {
+            if ldr_image_base != section_base {
+                report
+                    .indicators
+                    .push(Win32HollowingIndicator::LdrImageBaseMismatch { ldr_image_base });
+            }
+        }
+
+        // vad tree
+        let sys_arch: ArchitectureObj = self.proc_info.base_info.sys_arch.into();
+        let vad_root = self.proc_info.vad_root;
+        let mmvad = self.mmvad;
+        if vad_root.is_null() || mmvad.starting_vpn == mmvad.ending_vpn {
+            debug!("vad offsets are not available, skipping vad checks");
+            return Ok(report);
+        }
+
+        match find_vad(&mut self.virt_mem, vad_root, &mmvad, sys_arch, section_base) {
+            Ok(None) => report
+                .indicators
+                .push(Win32HollowingIndicator::ImageVadMissing),
+            Ok(Some(vad)) => {
+                // the flags are 64 bits wide on older x64 versions
+                let flags: u64 = self.virt_mem.read(vad + mmvad.u)?;
+                let vad_type = ((flags >> mmvad.vad_type_bit.min(61)) & 0b111) as u32;
+                trace!("vad at section base: {:x} vad_type={}", vad, vad_type);
+
+                if mmvad.vad_type_bit != 0 && vad_type != VAD_IMAGE_MAP {
+                    report
+                        .indicators
+                        .push(Win32HollowingIndicator::NotAnImageVad { vad_type });
+                } else {
+                    report.mapped_file_name =
+                        read_vad_file_name(&mut self.virt_mem, vad, &mmvad, sys_arch);
+                }
+            }
+            Err(err) => debug!("unable to walk the vad tree: {}", err),
+        }
+
+        if let Some(file_name) = report.mapped_file_name.as_ref() {
+            let process_name = self.proc_info.base_info.name.as_ref();
+            if !file_name_matches(file_name, process_name) {
+                report
+                    .indicators
+                    .push(Win32HollowingIndicator::FileNameMismatch {
+                        file_name: file_name.clone(),
+                    });
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// Returns the base of the first entry of the loader list.
+    fn first_ldr_module_base(&mut self, arch: ArchitectureIdent) -> Option<Address> {
+        let mut first = None;
+        let callback = &mut |info: ModuleAddressInfo| {
+            first = Some(info.address);
+            false
+        };
+        self.module_address_list_callback(Some(&arch), callback.into())
+            .ok()?;
+
+        self.module_by_address(first?, arch)
+            .ok()
+            .map(|module| module.base)
+    }
+}
+
+/// Searches the vad tree for the vad that contains `address`.
+fn find_vad<M: MemoryView>(
+    mem: &mut M,
+    root: Address,
+    offsets: &MmVadOffsetTable,
+    arch: ArchitectureObj,
+    address: Address,
+) -> Result<Option<Address>> {
+    let mut vad = root;
+    for _ in 0..MAX_VAD_DEPTH {
+        if vad.is_null() {
+            break;
+        }
+
+        let (start, end) = vad_range(mem, vad, offsets)?;
+        let child = if address < start {
+            0
+        } else if address >= end {
+            1
+        } else {
+            return Ok(Some(vad));
+        };
+        vad = mem.read_addr_arch(
+            arch,
+            vad + offsets.vad_node + child * arch.size_addr() as umem,
+        )?;
+    }
+
+    Ok(None)
+}
+
+/// Returns the start and the exclusive end of the memory range described by a vad.
+pub(crate) fn vad_range<M: MemoryView>(
+    mem: &mut M,
+    vad: Address,
+    offsets: &MmVadOffsetTable,
+) -> Result<(Address, Address)> {
+    // older versions store the vpns as addresses and do not have the high parts
+    let (page_size, has_high) = if offsets.starting_vpn_high == offsets.ending_vpn_high {
+        (1, false)
+    } else {
+        (0x1000, true)
+    };
+
+    let mut start = mem.read::<u32>(vad + offsets.starting_vpn)? as umem;
+    let mut end = mem.read::<u32>(vad + offsets.ending_vpn)? as umem;
+    if has_high {
+        start |= (mem.read::<u8>(vad + offsets.starting_vpn_high)? as umem) << 32;
+        end |= (mem.read::<u8>(vad + offsets.ending_vpn_high)? as umem) << 32;
+    }
+
+    Ok((
+        Address::from(start * page_size),
+        Address::from((end + 1) * page_size),
+    ))
+}
+
+/// Resolves the name of the file that is mapped by an image vad.
+fn read_vad_file_name<M: MemoryView>(
+    mem: &mut M,
+    vad: Address,
+    offsets: &MmVadOffsetTable,
+    arch: ArchitectureObj,
+) -> Option<String> {
+    let control_area = vad_control_area(mem, vad, offsets, arch)?;
+    control_area_file_name(mem, control_area, offsets, arch)
+}
+
+/// Resolves the control area of the section that is mapped by a vad.
+///
+/// The vad has to be a mapped vad, private vads do not have a subsection.
+pub(crate) fn vad_control_area<M: MemoryView>(
+    mem: &mut M,
+    vad: Address,
+    offsets: &MmVadOffsetTable,
+    arch: ArchitectureObj,
+) -> Option<Address> {
+    if offsets.subsection == 0 || offsets.subsection_control_area == 0 {
+        return None;
+    }
+
+    let subsection = mem.read_addr_arch(arch, vad + offsets.subsection).ok()?;
+    if subsection.is_null() {
+        return None;
+    }
+    mem.read_addr_arch(arch, subsection + offsets.subsection_control_area)
+        .ok()
+        .filter(|control_area| !control_area.is_null())
+}
+
+/// Resolves the name of the file that backs a control area.
+///
+/// Returns `None` for sections that are backed by the pagefile.
+pub(crate) fn control_area_file_name<M: MemoryView>(
+    mem: &mut M,
+    control_area: Address,
+    offsets: &MmVadOffsetTable,
+    arch: ArchitectureObj,
+) -> Option<String> {
+    if offsets.control_area_file_pointer == 0 {
+        return None;
+    }
+
+    // the file pointer is an EX_FAST_REF, the low bits contain the reference count
+    let ref_bits: umem = if arch.bits() == 64 { 0xf } else { 0x7 };
+    let file_pointer = mem
+        .read_addr_arch(arch, control_area + offsets.control_area_file_pointer)
+        .ok()?;
+    let file_object = Address::from(file_pointer.to_umem() & !ref_bits);
+    if file_object.is_null() {
+        return None;
+    }
+
+    mem.read_unicode_string(arch, file_object + offsets.file_object_file_name)
+        .ok()
+}
+
+/// Compares the file name of a path with the name of a process.
+///
+/// Process names taken from the eprocess are truncated to 14 characters.
+fn file_name_matches(path: &str, process_name: &str) -> bool {
+    let file_name = path.rsplit('\\').next().unwrap_or(path).to_lowercase();
+    let process_name = process_name.to_lowercase();
+    file_name == process_name
+        || (process_name.len() >= super::IMAGE_FILE_NAME_LENGTH - 1
+            && file_name.starts_with(&process_name))
+}
@@ -0,0 +1,361 @@
+/*!
+Module for detecting hooks in loaded modules.
+
+Every module is checked for three kinds of hooks:
+- export address table entries that point outside of the image of the module
+- import address table entries that point to memory that does not belong to any module
+- exported functions that start with a jump to a destination outside of the module
+
+Jumps through the import address table of the module itself are not reported as inline hooks
+since they are legitimate import thunks, their destination is covered by the import check instead.
+
+# Examples:
+
+```
+use memflow::prelude::v1::*;
+use memflow_win32::win32::Win32Kernel;
+
+fn test<T: 'static + PhysicalMemory + Clone, V: 'static + VirtualTranslate2 + Clone>(
+    kernel: &mut Win32Kernel<T, V>,
+) {
+    let report = kernel.kernel_hook_report().unwrap();
+    for hook in report.hooks.iter() {
+        println!(
+            "{:?} {}!{} -> {:x} ({})",
+            hook.hook_type,
+            hook.module,
+            hook.function.as_deref().unwrap_or("?"),
+            hook.destination,
+            hook.destination_module.as_deref().unwrap_or("unknown")
+        );
+    }
+}
+```
+*/
+use std::prelude::v1::*;
+
+use std::convert::TryInto;
+
+use super::{Win32Kernel, Win32Process, Win32VirtualTranslate};
+use crate::kernel::ntos::lazy_pe::LazyPe;
+
+use log::{debug, trace};
+
+use memflow::cglue::tuple::CTup2;
+use memflow::error::{PartialResultExt, Result};
+use memflow::mem::{MemoryView, PhysicalMemory, ReadData, VirtualTranslate2};
+use memflow::os::{ModuleInfo, Os, Process};
+use memflow::types::{umem, Address};
+
+const IMAGE_FILE_MACHINE_I386: u16 = 0x14c;
+const IMAGE_FILE_MACHINE_AMD64: u16 = 0x8664;
+const IMAGE_FILE_MACHINE_ARM64: u16 = 0xaa64;
+
+/// Number of bytes that are read from the start of every exported function
+const PROLOGUE_SIZE: usize = 16;
+
+/// Kind of a detected hook
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize))]
+pub enum Win32HookType {
+    /// The function starts with a jump outside of the module
+    Inline,
+    /// An import address table entry points outside of all loaded modules
+    Iat,
+    /// An export address table entry points outside of the module
+    Eat,
+}
+
+/// A single detected hook
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize))]
+pub struct Win32Hook {
+    pub hook_type: Win32HookType,
+    /// Address of the patched code or table entry
+    pub address: Address,
+    /// Name of the module that contains the patched code or table entry
+    pub module: String,
+    /// Name of the hooked function, `None` for functions that are imported by ordinal
+    pub function: Option<String>,
+    /// Address the hook redirects execution to
+    pub destination: Address,
+    /// Name of the module that contains the destination, `None` if it is not part of any module
+    pub destination_module: Option<String>,
+}
+
+/// Result of a hook scan over a set of modules
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize))]
+pub struct Win32HookReport {
+    pub hooks: Vec<Win32Hook>,
+    /// Number of modules that were scanned
+    pub scanned_modules: usize,
+    /// Number of modules whose headers could not be read, e.g. because they are paged out
+    pub failed_modules: usize,
+}
+
+impl<T: 'static + PhysicalMemory + Clone, V: 'static + VirtualTranslate2 + Clone>
+    Win32Kernel<T, V>
+{
+    /// Scans all kernel modules for hooks.
+    ///
+    /// Modules in session space (e.g. win32k.sys) are only readable from a process
+    /// that is attached to a session, they are counted as failed modules here.
+    pub fn kernel_hook_report(&mut self) -> Result<Win32HookReport> {
+        let modules = self.module_list()?;
+        Ok(scan_hooks(&mut self.virt_mem, &modules))
+    }
+}
+
+impl<T: PhysicalMemory, V: VirtualTranslate2> Win32Process<T, V, Win32VirtualTranslate> {
+    /// Scans all modules of the process for hooks.
+    ///
+    /// The native as well as the wow64 modules are scanned.
+    pub fn hook_report(&mut self) -> Result<Win32HookReport> {
+        let modules = self.module_list()?;
+        Ok(scan_hooks(self, &modules))
+    }
+}
+
+/// Scans the given modules for inline, import and export address table hooks.
+///
+/// Destinations are attributed to the modules in `modules`.
+pub fn scan_hooks<M: MemoryView>(mem: &mut M, modules: &[ModuleInfo]) -> Win32HookReport {
+    let mut report = Win32HookReport::default();
+    for module in modules.iter() {
+        match scan_module(mem, module, modules, &mut report.hooks) {
+            Ok(()) => report.scanned_modules += 1,
+            Err(err) => {
+                debug!("unable to scan {} for hooks: {}", module.name, err);
+                report.failed_modules += 1;
+            }
+        }
+    }
+
+    debug!(
+        "found {} hooks in {} modules ({} failed)",
+        report.hooks.len(),
+        report.scanned_modules,
+        report.failed_modules
+    );
+    report
+}
+
+fn scan_module<M: MemoryView>(
+    mem: &mut M,
+    module: &ModuleInfo,
+    modules: &[ModuleInfo],
+    hooks: &mut Vec<Win32Hook>,
+) -> Result<()> {
+    trace!("scanning {} for hooks", module.name);
+
+    let (machine, pointer_size, exports, imports) = {
+        let mut pe = LazyPe::new(mem, module.base)?;
+        let machine = pe.machine().unwrap_or_default();
+        let pointer_size = if pe.is_64() { 8 } else { 4 };
+        let exports = pe
+            .exports()
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(name, rva, slot)| {
+                let executable = pe.is_executable(rva);
+                (name, rva, slot, executable)
+            })
+            .collect::<Vec<_>>();
+        let imports = pe.imports().unwrap_or_default();
+        (machine, pointer_size, exports, imports)
+    };
+
+    let module_name = module.name.to_string();
+    let contains = |address: Address| address >= module.base && address < module.base + module.size;
+    let destination_module = |address: Address| {
+        modules
+            .iter()
+            .find(|m| address >= m.base && address < m.base + m.size)
+            .map(|m| m.name.to_string())
+    };
+
+    // export address table
+    for (name, rva, slot, _) in exports.iter() {
+        if (*rva as umem) < module.size {
+            continue;
+        }
+
+        let destination = module.base + *rva as umem;
+        hooks.push(Win32Hook {
+            hook_type: Win32HookType::Eat,
+            address: module.base + *slot as umem,
+            module: module_name.clone(),
+            function: Some(name.clone()),
+            destination,
+            destination_module: destination_module(destination),
+        });
+    }
+
+    // import address table
+    let mut slots = imports
+        .iter()
+        .map(|_| vec![0u8; pointer_size])
+        .collect::<Vec<_>>();
+    {
+        let mut data = imports
+            .iter()
+            .zip(slots.iter_mut())
+            .map(|((_, _, slot), buf)| {
+                CTup2(module.base + *slot as umem, buf.as_mut_slice().into())
+            })
+            .collect::<Vec<ReadData>>();
+        mem.read_raw_list(&mut data).data_part()?;
+    }
+
+    for ((_, name, slot), value) in imports.iter().zip(slots.iter()) {
+        let destination = read_pointer(value);
+        if destination.is_null() {
+            continue;
+        }
+
+        if destination_module(destination).is_none() {
+            hooks.push(Win32Hook {
+                hook_type: Win32HookType::Iat,
+                address: module.base + *slot as umem,
+                module: module_name.clone(),
+                function: name.clone(),
+                destination,
+                destination_module: None,
+            });
+        }
+    }
+
+    // function prologues
+    let functions = exports
+        .iter()
+        .filter(|(_, rva, _, executable)| *executable && (*rva as umem) < module.size)
+        .collect::<Vec<_>>();
+    let mut prologues = functions
+        .iter()
+        .map(|_| [0u8; PROLOGUE_SIZE])
+        .collect::<Vec<_>>();
+    {
+        let mut data = functions
+            .iter()
+            .zip(prologues.iter_mut())
+            .map(|((_, rva, _, _), buf)| {
+                CTup2(module.base + *rva as umem, buf.as_mut_slice().into())
+            })
+            .collect::<Vec<ReadData>>();
+        // paged out functions are left zeroed and never decode to a jump
+        mem.read_raw_list(&mut data).data_part().ok();
+    }
+
+    for ((name, rva, _, _), prologue) in functions.into_iter().zip(prologues.iter()) {
+        let address = module.base + *rva as umem;
+        let destination = match decode_jump(machine, address, prologue) {
+            Some(Jump::Direct(destination)) => destination,
+            Some(Jump::Indirect(pointer)) => {
+                // jumps through the own import address table are import thunks
+                if contains(pointer) {
+                    continue;
+                }
+
+                let mut value = vec![0u8; pointer_size];
+                if mem.read_raw_into(pointer, &mut value).data_part().is_err() {
+                    continue;
+                }
+                read_pointer(&value)
+            }
+            None => continue,
+        };
+
+        if !contains(destination) {
+            hooks.push(Win32Hook {
+                hook_type: Win32HookType::Inline,
+                address,
+                module: module_name.clone(),
+                function: Some(name.clone()),
+                destination,
+                destination_module: destination_module(destination),
+            });
+        }
+    }
+
+    Ok(())
+}
+
+/// Destination of a jump at the start of a function
+enum Jump {
+    Direct(Address),
+    /// The destination is read from the given address
+    Indirect(Address),
+}
+
+/// Decodes the most common trampolines that are written by hooking engines.
+fn decode_jump(machine: u16, address: Address, code: &[u8; PROLOGUE_SIZE]) -> Option<Jump> {
+    let u32_at = |offset: usize| u32::from_le_bytes(code[offset..offset + 4].try_into().unwrap());
+    let u64_at = |offset: usize| u64::from_le_bytes(code[offset..offset + 8].try_into().unwrap());
+    let relative = |offset: usize, len: usize| {
+        Address::from(
+            (address.to_umem() as i64
+                + (offset + len) as i64
+                + u32_at(offset + len - 4) as i32 as i64) as umem,
+        )
+    };
+
+    match machine {
+        IMAGE_FILE_MACHINE_I386 => {
+            // skip the hot patch prefix `mov edi, edi`
+            let start = if code[..2] == [0x8b, 0xff] { 2 } else { 0 };
+            match code[start..] {
+                // jmp rel32
+                [0xe9, ..] => Some(Jump::Direct(relative(start, 5))),
+                // jmp dword ptr [abs32]
+                [0xff, 0x25, ..] => Some(Jump::Indirect(Address::from(u32_at(start + 2)))),
+                // push imm32; ret
+                [0x68, _, _, _, _, 0xc3, ..] => {
+                    Some(Jump::Direct(Address::from(u32_at(start + 1))))
+                }
+                // mov eax, imm32; jmp eax
+                [0xb8, _, _, _, _, 0xff, 0xe0, ..] => {
+                    Some(Jump::Direct(Address::from(u32_at(start + 1))))
+                }
+                _ => None,
+            }
+        }
+        IMAGE_FILE_MACHINE_AMD64 => match code {
+            // jmp rel32
+            [0xe9, ..] => Some(Jump::Direct(relative(0, 5))),
+            // jmp qword ptr [rip+disp32]
+            [0xff, 0x25, ..] => Some(Jump::Indirect(relative(0, 6))),
+            // mov rax, imm64; jmp rax
+            [0x48, 0xb8, _, _, _, _, _, _, _, _, 0xff, 0xe0, ..] => {
+                Some(Jump::Direct(Address::from(u64_at(2))))
+            }
+            // mov r11, imm64; jmp r11
+            [0x49, 0xbb, _, _, _, _, _, _, _, _, 0x41, 0xff, 0xe3, ..] => {
+                Some(Jump::Direct(Address::from(u64_at(2))))
+            }
+            _ => None,
+        },
+        IMAGE_FILE_MACHINE_ARM64 => match (u32_at(0), u32_at(4)) {
+            // ldr x16, #8; br x16 (or x17) followed by the destination
+            (0x5800_0050, 0xd61f_0200) | (0x5800_0051, 0xd61f_0220) => {
+                Some(Jump::Direct(Address::from(u64_at(8))))
+            }
+            // b imm26
+            (instr, _) if instr & 0xfc00_0000 == 0x1400_0000 => {
+                let offset = (((instr & 0x03ff_ffff) << 6) as i32 >> 4) as i64;
+                Some(Jump::Direct(Address::from(
+                    (address.to_umem() as i64).wrapping_add(offset) as umem,
+                )))
+            }
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn read_pointer(buf: &[u8]) -> Address {
+    match buf.len() {
+        8 => Address::from(u64::from_le_bytes(buf.try_into().unwrap())),
+        _ => Address::from(u32::from_le_bytes(buf[..4].try_into().unwrap())),
+    }
+}
@@ -0,0 +1,124 @@
+/*!
+Module that bundles the interactive session of a target into a single trait group.
+
+The `Win32Interaction` group combines the keyboard ([`OsKeyboard`]), the clipboard ([`OsClipboard`])
+and the gui objects of the processes ([`OsGuiHandles`]) of the interactive session.
+Consumers that automate or record a target therefore only have to obtain one object
+through the plugin abi (see `mf_win32_interaction` in the plugins module).
+
+The traits only use ffi safe types, the full results are available through
+[`Win32Kernel::clipboard`] and [`Win32Kernel::gui_handles`].
+
+This module is only available with the `plugins` and `symstore` features enabled.
+
+# Examples:
+
+```
+use memflow::prelude::v1::*;
+use memflow_win32::win32::{OsClipboard, OsGuiHandles, Win32Kernel};
+
+fn test<T: 'static + PhysicalMemory + Clone, V: 'static + VirtualTranslate2 + Clone>(
+    kernel: &mut Win32Kernel<T, V>,
+) {
+    println!("{}", kernel.clipboard_text().unwrap());
+
+    kernel
+        .window_owner_list_callback(
+            (&mut |CTup2(pid, windows)| {
+                println!("{}: {} windows", pid, windows);
+                true
+            })
+                .into(),
+        )
+        .unwrap();
+}
+```
+*/
+use std::prelude::v1::*;
+
+use super::{Win32Kernel, Win32UserObjectType};
+
+use memflow::cglue;
+use memflow::os::keyboard::*;
+use memflow::prelude::v1::{Result, *};
+
+/// Callback that receives the pid, the number of user objects and the number of gdi objects
+/// of a process
+pub type GuiProcessCallback<'a> = OpaqueCallback<'a, CTup3<Pid, u64, u64>>;
+
+/// Callback that receives the pid and the number of windows of a process
+pub type WindowOwnerCallback<'a> = OpaqueCallback<'a, CTup2<Pid, u64>>;
+
+/// Access to the clipboard of the interactive session
+#[cglue_trait]
+#[int_result]
+pub trait OsClipboard: Send {
+    /// Returns the sequence number of the clipboard, it is incremented on every change.
+    fn clipboard_sequence_number(&mut self) -> Result<u32>;
+
+    /// Returns the text on the clipboard, unicode text is preferred.
+    fn clipboard_text(&mut self) -> Result<ReprCString>;
+}
+
+/// Enumeration of the gui objects of the interactive session
+#[cglue_trait]
+#[int_result]
+pub trait OsGuiHandles: Send {
+    /// Walks the processes that own gdi or user objects.
+    ///
+    /// Processes that are not part of the process list are included.
+    fn gui_process_list_callback(&mut self, callback: GuiProcessCallback) -> Result<()>;
+
+    /// Walks the processes that own windows.
+    fn window_owner_list_callback(&mut self, callback: WindowOwnerCallback) -> Result<()>;
+}
+
+cglue_trait_group!(Win32Interaction<'a>, { OsKeyboard, OsClipboard, OsGuiHandles }, {});
+
+impl<T: 'static + PhysicalMemory + Clone, V: 'static + VirtualTranslate2 + Clone> OsClipboard
+    for Win32Kernel<T, V>
+{
+    fn clipboard_sequence_number(&mut self) -> Result<u32> {
+        Ok(self.clipboard()?.sequence_number)
+    }
+
+    fn clipboard_text(&mut self) -> Result<ReprCString> {
+        self.clipboard()?
+            .text()
+            .map(ReprCString::from)
+            .ok_or_else(|| {
+                Error(ErrorOrigin::OsLayer, ErrorKind::NotFound)
+                    .log_debug("the clipboard does not contain text")
+            })
+    }
+}
+
+impl<T: 'static + PhysicalMemory + Clone, V: 'static + VirtualTranslate2 + Clone> OsGuiHandles
+    for Win32Kernel<T, V>
+{
+    fn gui_process_list_callback(&mut self, mut callback: GuiProcessCallback) -> Result<()> {
+        for process in self.gui_handles()?.processes.iter() {
+            let user_objects = process.user_object_count() as u64;
+            let gdi_objects = process.gdi_object_count() as u64;
+            if !callback.call(CTup3(process.pid, user_objects, gdi_objects)) {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn window_owner_list_callback(&mut self, mut callback: WindowOwnerCallback) -> Result<()> {
+        for process in self.gui_handles()?.processes.iter() {
+            let windows = process
+                .user_objects
+                .iter()
+                .find(|(object_type, _)| *object_type == Win32UserObjectType::Window);
+            if let Some((_, count)) = windows {
+                if !callback.call(CTup2(process.pid, *count as u64)) {
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,78 @@
+/*!
+Module for reading the kernel debugger data block of a running kernel.
+
+The unencoded block is found by scanning the kernel image. Blocks encoded by Windows 8+
+are decoded with the keys resolved from the kernel symbols (requires the `symstore` feature).
+
+# Examples:
+
+```
+use memflow::prelude::v1::*;
+use memflow_win32::win32::Win32Kernel;
+
+fn test<T: 'static + PhysicalMemory + Clone, V: 'static + VirtualTranslate2 + Clone>(
+    kernel: &mut Win32Kernel<T, V>,
+) {
+    let kdbg = kernel.kd_debugger_data().unwrap();
+    println!("PsActiveProcessHead: {:x}", kdbg.ps_active_process_head);
+    println!("PsLoadedModuleList: {:x}", kdbg.ps_loaded_module_list);
+}
+```
+*/
+use super::Win32Kernel;
+#[cfg(feature = "symstore")]
+use crate::kernel::kdbg::KdDebuggerDataKeys;
+use crate::kernel::kdbg::{self, KdDebuggerData};
+#[cfg(feature = "symstore")]
+use crate::offsets::SymbolStore;
+
+use memflow::error::Result;
+#[cfg(feature = "symstore")]
+use memflow::mem::MemoryView;
+use memflow::mem::{PhysicalMemory, VirtualTranslate2};
+
+impl<T: 'static + PhysicalMemory + Clone, V: 'static + VirtualTranslate2 + Clone>
+    Win32Kernel<T, V>
+{
+    /// Returns the kernel debugger data block.
+    ///
+    /// If the block cannot be found by scanning the kernel image it is resolved through the
+    /// kernel symbols which are loaded from the symbol store of the kernel.
+    pub fn kd_debugger_data(&mut self) -> Result<KdDebuggerData> {
+        let base = self.kernel_info.os_info.base;
+        let size = self.kernel_info.os_info.size;
+        match kdbg::find(&mut self.virt_mem, base, size) {
+            Ok(data) => Ok(data),
+            #[cfg(feature = "symstore")]
+            Err(err) => match self.symbol_store.clone() {
+                Some(store) => self.kd_debugger_data_from_store(&store),
+                None => Err(err),
+            },
+            #[cfg(not(feature = "symstore"))]
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Reads and decodes the kernel debugger data block with the symbols from the given store.
+    #[cfg(feature = "symstore")]
+    pub fn kd_debugger_data_from_store(&mut self, store: &SymbolStore) -> Result<KdDebuggerData> {
+        let symbols = self.kernel_symbols_from_store(store)?;
+        let address = symbols.address("KdDebuggerDataBlock")?;
+
+        // the block is only encoded on x64 kernels
+        let keys = match (
+            symbols.address("KiWaitNever"),
+            symbols.address("KiWaitAlways"),
+            symbols.address("KdpDataBlockEncoded"),
+        ) {
+            (Ok(wait_never), Ok(wait_always), Ok(block_encoded)) => Some(KdDebuggerDataKeys {
+                wait_never: self.virt_mem.read(wait_never)?,
+                wait_always: self.virt_mem.read(wait_always)?,
+                block_encoded,
+            }),
+            _ => None,
+        };
+
+        kdbg::read(&mut self.virt_mem, address, keys.as_ref())
+    }
+}
@@ -1,18 +1,32 @@
+mod attach;
+mod dtb;
+mod eprocess;
 mod mem_map;
+mod validate;
+
+pub use attach::Win32AttachedView;
+pub use validate::Win32EprocessDefect;
 
 use crate::{
-    offsets::{Win32ArchOffsets, Win32Offsets},
+    kernel::ntos::lazy_pe::LazyPe,
+    offsets::{Win32ArchOffsets, Win32OffsetFile, Win32OffsetHeader, Win32Offsets},
     prelude::{VirtualReadUnicodeString, Win32ExitStatus, EXIT_STATUS_STILL_ACTIVE},
 };
 
+#[cfg(feature = "symstore")]
+use crate::offsets::SymbolStore;
+
 use super::{
-    process::IMAGE_FILE_NAME_LENGTH, Win32KernelBuilder, Win32KernelInfo, Win32Keyboard,
-    Win32ModuleListInfo, Win32Process, Win32ProcessInfo, Win32VirtualTranslate,
+    process::IMAGE_FILE_NAME_LENGTH, ProcessListFilter, Win32KernelBuilder, Win32KernelInfo,
+    Win32Keyboard, Win32ListWalker, Win32ModuleListInfo, Win32ModuleOffset, Win32Process,
+    Win32ProcessInfo, Win32VirtualTranslate,
 };
 
 use memflow::mem::virt_translate::*;
 use memflow::prelude::v1::{Result, *};
 
+#[cfg(all(feature = "plugins", feature = "symstore"))]
+use super::interaction::*;
 #[cfg(feature = "plugins")]
 use memflow::cglue;
 #[cfg(feature = "plugins")]
@@ -20,17 +34,17 @@ use memflow::mem::{memory_view::*, phys_mem::*};
 #[cfg(feature = "plugins")]
 use memflow::os::keyboard::*;
 
-use log::{info, trace};
-use std::convert::TryInto;
+use log::{info, trace, warn};
 use std::fmt;
 use std::prelude::v1::*;
 
-use pelite::{self, pe64::exports::Export, PeView};
-
-const MAX_ITER_COUNT: usize = 65536;
+/// Number of processes whose eprocess fields are fetched in a single vectored read
+const PROCESS_BATCH_SIZE: usize = 64;
 
 #[cfg(feature = "plugins")]
 cglue_impl_group!(Win32Kernel<T, V>, OsInstance<'a>, { PhysicalMemory, MemoryView, VirtualTranslate, OsKeyboard });
+#[cfg(all(feature = "plugins", feature = "symstore"))]
+cglue_impl_group!(Win32Kernel<T, V>, Win32Interaction<'a>, {});
 
 #[derive(Clone)]
 pub struct Win32Kernel<T, V> {
@@ -41,16 +55,28 @@ pub struct Win32Kernel<T, V> {
     pub sysproc_dtb: Address,
 
     pub kernel_modules: Option<Win32ModuleListInfo>,
+    /// Processes that are enumerated by the process list
+    pub process_list_filter: ProcessListFilter,
+    /// Symbol store that pdbs of the kernel and its drivers are loaded from,
+    /// `None` if no pdbs should be loaded
+    #[cfg(feature = "symstore")]
+    pub symbol_store: Option<SymbolStore>,
 }
 
 impl<T: 'static + PhysicalMemory + Clone, V: 'static + VirtualTranslate2 + Clone>
     Win32Kernel<T, V>
 {
-    pub fn new(phys_mem: T, vat: V, offsets: Win32Offsets, kernel_info: Win32KernelInfo) -> Self {
+    pub fn new(
+        phys_mem: T,
+        vat: V,
+        offsets: Win32Offsets,
+        mut kernel_info: Win32KernelInfo,
+    ) -> Self {
         let mut virt_mem = VirtualDma::with_vat(
             phys_mem,
             kernel_info.os_info.arch,
-            Win32VirtualTranslate::new(kernel_info.os_info.arch, kernel_info.dtb),
+            Win32VirtualTranslate::new(kernel_info.os_info.arch, kernel_info.dtb)
+                .with_la57(kernel_info.la57),
             vat,
         );
 
@@ -68,7 +94,8 @@ impl<T: 'static + PhysicalMemory + Clone, V: 'static + VirtualTranslate2 + Clone
                         virt_mem = VirtualDma::with_vat(
                             phys_mem,
                             kernel_info.os_info.arch,
-                            Win32VirtualTranslate::new(kernel_info.os_info.arch, kernel_info.dtb),
+                            Win32VirtualTranslate::new(kernel_info.os_info.arch, kernel_info.dtb)
+                                .with_la57(kernel_info.la57),
                             vat,
                         );
                     }
@@ -85,7 +112,8 @@ impl<T: 'static + PhysicalMemory + Clone, V: 'static + VirtualTranslate2 + Clone
                         virt_mem = VirtualDma::with_vat(
                             phys_mem,
                             kernel_info.os_info.arch,
-                            Win32VirtualTranslate::new(kernel_info.os_info.arch, kernel_info.dtb),
+                            Win32VirtualTranslate::new(kernel_info.os_info.arch, kernel_info.dtb)
+                                .with_la57(kernel_info.la57),
                             vat,
                         );
                     }
@@ -97,27 +125,29 @@ impl<T: 'static + PhysicalMemory + Clone, V: 'static + VirtualTranslate2 + Clone
         // start_block only contains the winload's dtb which might
         // be different to the one used in the actual kernel.
         // In case of a failure this will fall back to the winload dtb.
-        // Read dtb of first process in eprocess list:
-        let sysproc_dtb = if let Some(Some(dtb)) = virt_mem
+        // Read dtb of first process in eprocess list and validate it:
+        let sysproc_dtb = virt_mem
             .read_addr_arch(
                 kernel_info.os_info.arch.into(),
                 kernel_info.eprocess_base + offsets.kproc_dtb(),
             )
             .ok()
-            .map(|a| a.as_page_aligned(4096).non_null())
-        {
-            info!("updating sysproc_dtb={:x}", dtb);
+            .and_then(|a| a.as_page_aligned(4096).non_null());
+        let kernel_dtb = dtb::select(virt_mem.phys_mem(), &kernel_info, sysproc_dtb);
+        kernel_info.kernel_dtb = kernel_dtb;
+
+        if kernel_dtb != kernel_info.dtb {
+            info!("updating sysproc_dtb={:x}", kernel_dtb);
             let (phys_mem, vat) = virt_mem.into_inner();
             virt_mem = VirtualDma::with_vat(
                 phys_mem,
                 kernel_info.os_info.arch,
-                Win32VirtualTranslate::new(kernel_info.os_info.arch, dtb),
+                Win32VirtualTranslate::new(kernel_info.os_info.arch, kernel_dtb)
+                    .with_la57(kernel_info.la57),
                 vat,
             );
-            dtb
-        } else {
-            kernel_info.dtb
-        };
+        }
+        let sysproc_dtb = kernel_dtb;
 
         Self {
             virt_mem,
@@ -126,29 +156,85 @@ impl<T: 'static + PhysicalMemory + Clone, V: 'static + VirtualTranslate2 + Clone
             kernel_info,
             sysproc_dtb,
             kernel_modules: None,
+            process_list_filter: ProcessListFilter::default(),
+            #[cfg(feature = "symstore")]
+            symbol_store: None,
         }
     }
 
+    /// Sets the processes that are enumerated by the process list.
+    ///
+    /// By default exited processes that are still referenced by the kernel are enumerated as well.
+    pub fn set_process_list_filter(&mut self, filter: ProcessListFilter) {
+        self.process_list_filter = filter;
+    }
+
+    /// Sets the symbol store that pdbs of the kernel and its drivers are loaded from.
+    ///
+    /// A kernel created by the [`Win32KernelBuilder`] uses the symbol store of the builder,
+    /// a kernel created through [`Win32Kernel::new`] does not load any pdbs by default.
+    #[cfg(feature = "symstore")]
+    pub fn set_symbol_store(&mut self, symbol_store: Option<SymbolStore>) {
+        self.symbol_store = symbol_store;
+    }
+
+    /// Returns the configured symbol store or an error if pdbs should not be loaded.
+    #[cfg(feature = "symstore")]
+    pub(crate) fn configured_symbol_store(&self) -> Result<SymbolStore> {
+        self.symbol_store.clone().ok_or_else(|| {
+            Error(ErrorOrigin::OsLayer, ErrorKind::Configuration)
+                .log_info("no symbol store has been configured for the kernel")
+        })
+    }
+
+    /// Enables decoding of software ptes for pages that cannot be translated.
+    ///
+    /// Processes created from this kernel afterwards inherit the setting.
+    /// See [`Win32VirtualTranslate::with_software_ptes`] for details.
+    pub fn set_software_ptes(&mut self, software_ptes: bool) {
+        let translator = self.virt_mem.translator().with_software_ptes(software_ptes);
+        self.virt_mem.set_translator(translator);
+    }
+
+    /// Returns true if the page at `addr` is mapped but reads as zeros while vbs is active.
+    ///
+    /// Such pages are likely protected by the second level address translation of the hypervisor
+    /// and their contents are not accessible. A warning is logged for every protected page.
+    pub fn is_slat_protected(&mut self, addr: Address) -> bool {
+        if !self.kernel_info.vbs.is_active() {
+            return false;
+        }
+
+        let page_size = self.kernel_info.os_info.arch.into_obj().page_size();
+        let page = addr.as_page_aligned(page_size);
+        if self.virt_mem.virt_to_phys(page).is_err() {
+            return false;
+        }
+
+        let mut buf = vec![0u8; page_size];
+        let protected = self
+            .virt_mem
+            .read_raw_into(page, &mut buf)
+            .data_part()
+            .is_ok()
+            && buf.iter().all(|&b| b == 0);
+        if protected {
+            warn!(
+                "page {:x} reads as zeros, it might be protected by virtualization based security",
+                page
+            );
+        }
+        protected
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
     pub fn kernel_modules(&mut self) -> Result<Win32ModuleListInfo> {
         if let Some(info) = self.kernel_modules {
             Ok(info)
         } else {
-            let image = self.virt_mem.read_raw(
-                self.kernel_info.os_info.base,
-                self.kernel_info.os_info.size.try_into().unwrap(),
-            )?;
-            let pe = PeView::from_bytes(&image).map_err(|err| {
-                Error(ErrorOrigin::OsLayer, ErrorKind::InvalidExeFile).log_info(err)
-            })?;
-            let addr = match pe.get_export_by_name("PsLoadedModuleList").map_err(|err| {
-                Error(ErrorOrigin::OsLayer, ErrorKind::ExportNotFound).log_info(err)
-            })? {
-                Export::Symbol(s) => self.kernel_info.os_info.base + *s as umem,
-                Export::Forward(_) => {
-                    return Err(Error(ErrorOrigin::OsLayer, ErrorKind::ExportNotFound)
-                        .log_info("PsLoadedModuleList found but it was a forwarded export"))
-                }
-            };
+            let base = self.kernel_info.os_info.base;
+            let addr =
+                base + LazyPe::new(&mut self.virt_mem, base)?.export("PsLoadedModuleList")?;
 
             let addr = self
                 .virt_mem
@@ -161,18 +247,88 @@ impl<T: 'static + PhysicalMemory + Clone, V: 'static + VirtualTranslate2 + Clone
         }
     }
 
+    /// Returns the kernel module that contains the given address and the offset into it.
+    pub fn kernel_module_containing(&mut self, address: Address) -> Result<Win32ModuleOffset> {
+        let modules = self.module_list()?;
+        Win32ModuleOffset::find(modules, address).ok_or_else(|| {
+            Error(ErrorOrigin::OsLayer, ErrorKind::ModuleNotFound)
+                .log_debug(format!("no kernel module contains address {:x}", address))
+        })
+    }
+
+    /// Walks the thread list of a process and calls a callback for each ethread structure address
+    pub fn thread_address_list_callback(
+        &mut self,
+        eprocess: Address,
+        mut callback: AddressCallback,
+    ) -> Result<()> {
+        // the thread list head is not available prior to nt 5.1
+        if self.offsets.eproc_thread_list() == 0 {
+            return Err(Error(ErrorOrigin::OsLayer, ErrorKind::NotSupported)
+                .log_info("eproc_thread_list is not available for this kernel"));
+        }
+
+        let list_head = eprocess + self.offsets.eproc_thread_list();
+        let ethread_list_entry = self.offsets.ethread_list_entry();
+        Win32ListWalker::new(self.kernel_info.os_info.arch, list_head).walk(
+            &mut self.virt_mem,
+            |_, list_entry, _| {
+                // the list head is part of the eprocess and not a thread
+                list_entry == list_head || callback.call(list_entry - ethread_list_entry)
+            },
+        )
+    }
+
+    /// Returns the addresses of all ethread structures of a process
+    pub fn thread_address_list(&mut self, eprocess: Address) -> Result<Vec<Address>> {
+        let mut out = vec![];
+        self.thread_address_list_callback(eprocess, (&mut out).into())?;
+        Ok(out)
+    }
+
     /// Consumes this kernel and return the underlying owned memory and vat objects
     pub fn into_inner(self) -> (T, V) {
         self.virt_mem.into_inner()
     }
 
+    /// Returns the offsets in use together with the guid and version of the kernel.
+    ///
+    /// The resulting offset file can be used to initialize the kernel without a symbol store.
+    pub fn offset_file(&self) -> Result<Win32OffsetFile> {
+        let winver = self.kernel_info.kernel_winver;
+        if winver == (0, 0).into() {
+            return Err(Error(ErrorOrigin::OsLayer, ErrorKind::NotFound)
+                .log_error("kernel version has to be valid in order to generate an offset file"));
+        }
+
+        let guid = self.kernel_info.kernel_guid.clone().unwrap_or_default();
+        Ok(Win32OffsetFile {
+            header: Win32OffsetHeader {
+                pdb_file_name: guid.file_name.as_str().into(),
+                pdb_guid: guid.guid.as_str().into(),
+
+                arch: self.kernel_info.os_info.arch.into(),
+
+                nt_major_version: winver.major_version(),
+                nt_minor_version: winver.minor_version(),
+                nt_build_number: winver.build_number(),
+            },
+            offsets: self.offsets.clone().into(),
+        })
+    }
+
+    /// Writes the offsets in use to an offset file.
+    ///
+    /// The format is derived from the file extension (toml, json or yaml).
+    #[cfg(feature = "offset_files")]
+    pub fn export_offsets<P: AsRef<std::path::Path>>(&self, path: P) -> Result<()> {
+        self.offset_file()?.to_file(path)
+    }
+
     pub fn kernel_process_info(&mut self) -> Result<Win32ProcessInfo> {
         let kernel_modules = self.kernel_modules()?;
 
-        let vad_root = self.read_addr_arch(
-            self.kernel_info.os_info.arch.into(),
-            self.kernel_info.os_info.base + self.offsets.eproc_vad_root(),
-        )?;
+        let vad_root = self.read_vad_root(self.kernel_info.os_info.base)?;
 
         Ok(Win32ProcessInfo {
             base_info: ProcessInfo {
@@ -201,6 +357,7 @@ impl<T: 'static + PhysicalMemory + Clone, V: 'static + VirtualTranslate2 + Clone
             module_info_wow64: None,
 
             vad_root,
+            la57: self.kernel_info.la57,
         })
     }
 
@@ -215,10 +372,24 @@ impl<T: 'static + PhysicalMemory + Clone, V: 'static + VirtualTranslate2 + Clone
         trace!("section_base={:x}", section_base);
 
         // find first ethread
-        let ethread = self.virt_mem.read_addr_arch(
-            self.kernel_info.os_info.arch.into(),
-            base_info.address + self.offsets.eproc_thread_list(),
-        )? - self.offsets.ethread_list_entry();
+        // the thread list head is not available prior to nt 5.1
+        let winver = self.kernel_info.kernel_winver;
+        let ethread = if self.offsets.eproc_thread_list() == 0
+            || (winver.major_version() != 0 && winver < (5, 1).into())
+        {
+            trace!("eproc_thread_list=null; skipping ethread lookup");
+            Address::null()
+        } else {
+            let thread_list = self.virt_mem.read_addr_arch(
+                self.kernel_info.os_info.arch.into(),
+                base_info.address + self.offsets.eproc_thread_list(),
+            )?;
+            if thread_list.is_null() {
+                Address::null()
+            } else {
+                thread_list - self.offsets.ethread_list_entry()
+            }
+        };
         trace!("ethread={:x}", ethread);
 
         let peb_native = self
@@ -248,34 +419,32 @@ impl<T: 'static + PhysicalMemory + Clone, V: 'static + VirtualTranslate2 + Clone
         let mut peb_wow64 = None;
 
         // TODO: does this need to be read with the process ctx?
-        let (teb, teb_wow64) = if self.kernel_info.kernel_winver >= (6, 2).into() {
-            let teb = self.virt_mem.read_addr_arch(
-                self.kernel_info.os_info.arch.into(),
-                ethread + self.offsets.kthread_teb(),
-            )?;
-
-            trace!("teb={:x}", teb);
-
-            if !teb.is_null() {
-                (
-                    Some(teb),
-                    if base_info.proc_arch == base_info.sys_arch {
-                        None
-                    } else {
-                        Some(teb + 0x2000)
-                    },
-                )
+        let (teb, teb_wow64) =
+            if self.kernel_info.kernel_winver >= (6, 2).into() && !ethread.is_null() {
+                let teb = self.virt_mem.read_addr_arch(
+                    self.kernel_info.os_info.arch.into(),
+                    ethread + self.offsets.kthread_teb(),
+                )?;
+
+                trace!("teb={:x}", teb);
+
+                if !teb.is_null() {
+                    (
+                        Some(teb),
+                        if base_info.proc_arch == base_info.sys_arch {
+                            None
+                        } else {
+                            Some(teb + 0x2000)
+                        },
+                    )
+                } else {
+                    (None, None)
+                }
             } else {
                 (None, None)
-            }
-        } else {
-            (None, None)
-        };
+            };
 
-        let vad_root = self.virt_mem.read_addr_arch(
-            self.kernel_info.os_info.arch.into(),
-            base_info.address + self.offsets.eproc_vad_root(),
-        )?;
+        let vad_root = self.read_vad_root(base_info.address)?;
 
         // construct reader with process dtb - win32 only uses/requires one dtb so we always store it in `dtb1`
         // TODO: can tlb be used here already?
@@ -283,7 +452,8 @@ impl<T: 'static + PhysicalMemory + Clone, V: 'static + VirtualTranslate2 + Clone
         let mut proc_reader = VirtualDma::with_vat(
             phys_mem.forward_mut(),
             base_info.proc_arch,
-            Win32VirtualTranslate::new(self.kernel_info.os_info.arch, base_info.dtb1),
+            Win32VirtualTranslate::new(self.kernel_info.os_info.arch, base_info.dtb1)
+                .with_la57(self.kernel_info.la57),
             vat,
         );
 
@@ -327,9 +497,22 @@ impl<T: 'static + PhysicalMemory + Clone, V: 'static + VirtualTranslate2 + Clone
             module_info_wow64,
 
             vad_root,
+            la57: self.kernel_info.la57,
         })
     }
 
+    pub(crate) fn read_vad_root(&mut self, eprocess: Address) -> Result<Address> {
+        if self.offsets.eproc_vad_root() == 0 {
+            trace!("eproc_vad_root=null; skipping vad root lookup");
+            return Ok(Address::null());
+        }
+
+        self.virt_mem.read_addr_arch(
+            self.kernel_info.os_info.arch.into(),
+            eprocess + self.offsets.eproc_vad_root(),
+        )
+    }
+
     fn process_info_fill(&mut self, info: Win32ProcessInfo) -> Result<Win32ProcessInfo> {
         // get full process name from module list
         let cloned_base = info.base_info.clone();
@@ -355,19 +538,18 @@ impl<T: 'static + PhysicalMemory + Clone, V: 'static + VirtualTranslate2 + Clone
             )
         }) {
             trace!("peb_process_params={:x}", peb_process_params);
-            let image_path_name = process
-                .read_unicode_string(
+            let mut strings = process
+                .read_unicode_string_list(
                     info.base_info.proc_arch.into(),
-                    peb_process_params + offsets.ppm_image_path_name,
+                    &[
+                        peb_process_params + offsets.ppm_image_path_name,
+                        peb_process_params + offsets.ppm_command_line,
+                    ],
                 )
-                .unwrap_or_default();
-
-            let command_line = process
-                .read_unicode_string(
-                    info.base_info.proc_arch.into(),
-                    peb_process_params + offsets.ppm_command_line,
-                )
-                .unwrap_or_default();
+                .into_iter()
+                .map(Option::unwrap_or_default);
+            let image_path_name = strings.next().unwrap();
+            let command_line = strings.next().unwrap();
 
             (image_path_name.into(), command_line.into())
         } else {
@@ -385,17 +567,103 @@ impl<T: 'static + PhysicalMemory + Clone, V: 'static + VirtualTranslate2 + Clone
         })
     }
 
+    /// Reads the base info of all processes in the process list.
+    ///
+    /// The eprocess fields of multiple processes are fetched in a single vectored read
+    /// which reduces the round trips on connectors with a high latency.
+    /// Processes that cannot be read are skipped.
+    pub fn process_info_base_list(&mut self) -> Result<Vec<ProcessInfo>> {
+        let addresses = self.process_address_list()?;
+
+        let mut out = Vec::with_capacity(addresses.len());
+        for chunk in addresses.chunks(PROCESS_BATCH_SIZE) {
+            let infos = match self.read_eprocess_fields(chunk) {
+                Some(fields) => chunk
+                    .iter()
+                    .zip(fields.iter())
+                    .filter_map(|(&address, fields)| {
+                        self.process_info_base_from_fields(address, fields).ok()
+                    })
+                    .collect::<Vec<_>>(),
+                // fall back to reading every process on its own
+                None => chunk
+                    .iter()
+                    .filter_map(|&address| self.process_info_base_by_address_fields(address).ok())
+                    .collect::<Vec<_>>(),
+            };
+            for info in infos {
+                if let Ok(info) = self.validate_eprocess(info) {
+                    out.push(info);
+                }
+            }
+        }
+        Ok(out)
+    }
+
+    /// Finds the process whose `DirectoryTableBase` matches the given cr3 value.
+    ///
+    /// Control bits of the cr3 value (e.g. the pcid on x64) are ignored. On kernels with
+    /// kva shadowing the user mode dtb of a process is matched as well.
+    pub fn process_by_dtb(&mut self, cr3: Address) -> Result<ProcessInfo> {
+        let mask: umem = match self.kernel_info.os_info.arch {
+            ArchitectureIdent::X86(32, true) => !0x1f,
+            // bit 63 of cr3 disables the tlb flush on x64
+            ArchitectureIdent::X86(64, _) => 0x000f_ffff_ffff_f000,
+            _ => !0xfff,
+        };
+        let dtb = Address::from(cr3.to_umem() & mask);
+
+        self.process_info_base_list()?
+            .into_iter()
+            .find(|info| {
+                Address::from(info.dtb1.to_umem() & mask) == dtb
+                    || (info.dtb2.is_valid() && Address::from(info.dtb2.to_umem() & mask) == dtb)
+            })
+            .ok_or_else(|| {
+                Error(ErrorOrigin::OsLayer, ErrorKind::ProcessNotFound)
+                    .log_info(format!("unable to find process with dtb {:x}", cr3))
+            })
+    }
+
     fn process_info_base_by_address(&mut self, address: Address) -> Result<ProcessInfo> {
+        let info = match self.read_eprocess_fields(&[address]) {
+            Some(fields) => self.process_info_base_from_fields(address, &fields[0])?,
+            None => self.process_info_base_by_address_fields(address)?,
+        };
+        self.validate_eprocess(info)
+    }
+
+    /// Reads the base info of a process field by field.
+    fn process_info_base_by_address_fields(&mut self, address: Address) -> Result<ProcessInfo> {
         let dtb = self.virt_mem.read_addr_arch(
             self.kernel_info.os_info.arch.into(),
             address + self.offsets.kproc_dtb(),
         )?;
         trace!("dtb={:x}", dtb);
 
+        // with kva shadowing user mode pages are mapped in a separate dtb,
+        // the lowest bit is set if kva shadowing is disabled for this process
+        let user_dtb = if self.offsets.kproc_user_dtb() != 0 {
+            self.virt_mem
+                .read_addr_arch(
+                    self.kernel_info.os_info.arch.into(),
+                    address + self.offsets.kproc_user_dtb(),
+                )
+                .ok()
+                .filter(|d| !d.is_null() && d.to_umem() & 1 == 0 && *d != dtb)
+                .unwrap_or_else(Address::invalid)
+        } else {
+            Address::invalid()
+        };
+        trace!("user_dtb={:x}", user_dtb);
+
         let pid: Pid = self.virt_mem.read(address + self.offsets.eproc_pid())?;
         trace!("pid={}", pid);
 
-        let state = if let Ok(exit_status) = self
+        // the exit status is not available prior to nt 5.1
+        let state = if self.offsets.eproc_exit_status() == 0 {
+            ProcessState::Unknown
+        } else if let Ok(exit_status) = self
             .virt_mem
             .read::<Win32ExitStatus>(address + self.offsets.eproc_exit_status())
         {
@@ -455,7 +723,7 @@ impl<T: 'static + PhysicalMemory + Clone, V: 'static + VirtualTranslate2 + Clone
             sys_arch,
             proc_arch,
             dtb1: dtb,
-            dtb2: Address::invalid(),
+            dtb2: user_dtb,
         })
     }
 }
@@ -532,47 +800,68 @@ impl<T: 'static + PhysicalMemory + Clone, V: 'static + VirtualTranslate2 + Clone
     /// Walks a process list and calls a callback for each process structure address
     ///
     /// The callback is fully opaque. We need this style so that C FFI can work seamlessly.
+    /// Processes are skipped according to the [`Win32Kernel::process_list_filter`].
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
     fn process_address_list_callback(
         &mut self,
         mut callback: AddressCallback,
     ) -> memflow::error::Result<()> {
         let list_start = self.kernel_info.eprocess_base + self.offsets.eproc_link();
-        let mut list_entry = list_start;
-
-        for _ in 0..MAX_ITER_COUNT {
-            let eprocess = list_entry - self.offsets.eproc_link();
-            trace!("eprocess={}", eprocess);
-
-            // test flink + blink before adding the process
-            let flink_entry = self
-                .virt_mem
-                .read_addr_arch(self.kernel_info.os_info.arch.into(), list_entry)?;
-            trace!("flink_entry={}", flink_entry);
-            let blink_entry = self.virt_mem.read_addr_arch(
-                self.kernel_info.os_info.arch.into(),
-                list_entry + self.offsets.list_blink(),
-            )?;
-            trace!("blink_entry={}", blink_entry);
-
-            if flink_entry.is_null()
-                || blink_entry.is_null()
-                || flink_entry == list_start
-                || flink_entry == list_entry
-            {
-                break;
-            }
-
-            trace!("found eprocess {:x}", eprocess);
-            if !callback.call(eprocess) {
-                break;
-            }
-            trace!("Continuing {:x} -> {:x}", list_entry, flink_entry);
+        let arch = self.kernel_info.os_info.arch;
+        let eproc_link = self.offsets.eproc_link();
+        let list_blink = self.offsets.list_blink();
+        let eproc_exit_status = self.offsets.eproc_exit_status();
+        let filter = self.process_list_filter;
+
+        let mut result = Ok(());
+        Win32ListWalker::new(arch, list_start).walk(
+            &mut self.virt_mem,
+            |virt_mem, list_entry, flink_entry| {
+                let eprocess = list_entry - eproc_link;
+                trace!("eprocess={}", eprocess);
+                trace!("flink_entry={}", flink_entry);
+
+                // test flink + blink before adding the process
+                let blink_entry =
+                    match virt_mem.read_addr_arch(arch.into(), list_entry + list_blink) {
+                        Ok(blink_entry) => blink_entry,
+                        Err(err) => {
+                            result = Err(err.into());
+                            return false;
+                        }
+                    };
+                trace!("blink_entry={}", blink_entry);
+
+                if flink_entry.is_null()
+                    || blink_entry.is_null()
+                    || flink_entry == list_start
+                    || flink_entry == list_entry
+                {
+                    return false;
+                }
 
-            // continue
-            list_entry = flink_entry;
-        }
+                trace!("found eprocess {:x}", eprocess);
+                if filter != ProcessListFilter::All {
+                    // the exit status is not available prior to nt 5.1
+                    let state = if eproc_exit_status == 0 {
+                        ProcessState::Unknown
+                    } else {
+                        match virt_mem.read::<Win32ExitStatus>(eprocess + eproc_exit_status) {
+                            Ok(EXIT_STATUS_STILL_ACTIVE) => ProcessState::Alive,
+                            Ok(exit_status) => ProcessState::Dead(exit_status),
+                            Err(_) => ProcessState::Unknown,
+                        }
+                    };
+                    if !filter.matches(&state) {
+                        trace!("skipping eprocess {:x} with state {:?}", eprocess, state);
+                        return true;
+                    }
+                }
+                callback.call(eprocess)
+            },
+        )?;
 
-        Ok(())
+        result
     }
 
     /// Find process information by its internal address
@@ -629,6 +918,23 @@ impl<T: 'static + PhysicalMemory + Clone, V: 'static + VirtualTranslate2 + Clone
             .map_err(From::from)
     }
 
+    /// Walks the kernel module list and calls the provided callback for each module info
+    ///
+    /// The module entries and their names are fetched with batched reads.
+    fn module_list_callback(&mut self, mut callback: ModuleInfoCallback) -> Result<()> {
+        let modules = self.kernel_modules()?.module_info_list(
+            &mut self.virt_mem,
+            self.kernel_info.eprocess_base,
+            self.kernel_info.os_info.arch,
+        )?;
+        for module in modules {
+            if !callback.call(module) {
+                break;
+            }
+        }
+        Ok(())
+    }
+
     /// Retrieves a module by its structure address
     ///
     /// # Arguments
@@ -0,0 +1,63 @@
+use std::prelude::v1::*;
+
+use memflow::cglue::forward::{ForwardMut, Fwd};
+use memflow::error::{Error, ErrorKind, ErrorOrigin, Result};
+use memflow::mem::{PhysicalMemory, VirtualDma, VirtualTranslate2};
+use memflow::os::{Os, ProcessState};
+use memflow::types::Address;
+
+use crate::win32::{Win32Kernel, Win32SessionId, Win32VirtualTranslate};
+
+/// Virtual memory view of the kernel that is translated with a different dtb
+///
+/// The view shares the physical memory and the translation caches of the kernel.
+pub type Win32AttachedView<'a, T, V> =
+    VirtualDma<Fwd<&'a mut T>, Fwd<&'a mut V>, Win32VirtualTranslate>;
+
+impl<T: 'static + PhysicalMemory + Clone, V: 'static + VirtualTranslate2 + Clone>
+    Win32Kernel<T, V>
+{
+    /// Returns a view of virtual memory that is translated with the given dtb.
+    ///
+    /// This can be used to inspect the address space of a process that is only known by its dtb.
+    /// Kernel pointers are read with the architecture of the kernel.
+    pub fn with_dtb(&mut self, dtb: Address) -> Win32AttachedView<'_, T, V> {
+        let arch = self.kernel_info.os_info.arch;
+        let translator = Win32VirtualTranslate::new(arch, dtb)
+            .with_la57(self.kernel_info.la57)
+            .with_pte_options_of(self.virt_mem.translator());
+
+        let (phys_mem, vat) = self.virt_mem.mem_vat_pair();
+        VirtualDma::with_vat(phys_mem.forward_mut(), arch, translator, vat.forward_mut())
+    }
+
+    /// Returns a view of virtual memory in which the session space of the given session is mapped.
+    ///
+    /// Session space (e.g. win32k and its global data) is only mapped in the address spaces
+    /// of the processes of a session, the view therefore uses the dtb of one of those processes.
+    /// Alive processes are preferred over processes that already exited.
+    pub fn attach_session(
+        &mut self,
+        session_id: Win32SessionId,
+    ) -> Result<Win32AttachedView<'_, T, V>> {
+        let procs = self.process_info_list()?;
+
+        let mut dtb = None;
+        for proc in procs.iter() {
+            if proc.dtb1.is_null() || self.process_session_id(proc)? != Some(session_id) {
+                continue;
+            }
+
+            dtb = Some(proc.dtb1);
+            if proc.state == ProcessState::Alive {
+                break;
+            }
+        }
+
+        match dtb {
+            Some(dtb) => Ok(self.with_dtb(dtb)),
+            None => Err(Error(ErrorOrigin::OsLayer, ErrorKind::NotFound)
+                .log_info(format!("no process found in session {}", session_id))),
+        }
+    }
+}
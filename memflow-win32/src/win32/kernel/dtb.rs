@@ -0,0 +1,93 @@
+use std::prelude::v1::*;
+
+use log::{debug, trace};
+
+use memflow::architecture::ArchitectureIdent;
+use memflow::mem::PhysicalMemory;
+use memflow::types::{Address, PhysicalAddress};
+
+use crate::win32::{Win32KernelInfo, Win32VirtualTranslate};
+
+const PAE_ADDRESS_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+/// Checks if the given dtb is usable as the kernel dtb.
+///
+/// The dtb has to map the kernel image and on x64 it has to contain a self referencing entry
+/// in the kernel half of the top level table.
+pub fn validate<T: PhysicalMemory>(
+    mem: &mut T,
+    kernel_info: &Win32KernelInfo,
+    dtb: Address,
+) -> bool {
+    if dtb.is_null() {
+        return false;
+    }
+
+    if let ArchitectureIdent::X86(64, _) = kernel_info.os_info.arch {
+        if !has_self_reference(mem, dtb) {
+            debug!("dtb={:x} does not contain a self referencing entry", dtb);
+            return false;
+        }
+    }
+
+    let translator =
+        Win32VirtualTranslate::new(kernel_info.os_info.arch, dtb).with_la57(kernel_info.la57);
+    match translator.pte_info(mem, kernel_info.os_info.base) {
+        Ok(info) if info.phys_addr.is_some() => true,
+        Ok(_) => {
+            debug!("dtb={:x} does not map the kernel image", dtb);
+            false
+        }
+        // translation checks are not supported on all architectures
+        Err(_) => true,
+    }
+}
+
+fn has_self_reference<T: PhysicalMemory>(mem: &mut T, dtb: Address) -> bool {
+    let mut table = [0u8; 0x1000];
+    if mem
+        .phys_read_into(
+            PhysicalAddress::from(dtb.as_page_aligned(0x1000)),
+            &mut table[..],
+        )
+        .is_err()
+    {
+        return false;
+    }
+
+    #[allow(clippy::unnecessary_cast)]
+    let dtb = dtb.to_umem() as u64 & PAE_ADDRESS_MASK;
+    match table[0x800..]
+        .chunks_exact(8)
+        .map(|c| u64::from_le_bytes(c.try_into().unwrap()))
+        .position(|e| e & 1 != 0 && e & PAE_ADDRESS_MASK == dtb)
+    {
+        Some(i) => {
+            trace!("found self referencing entry at index {:x}", 0x100 + i);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Selects the dtb used by the kernel.
+///
+/// The dtb of the system process is preferred, the dtb from the start block is used as a fallback.
+pub fn select<T: PhysicalMemory>(
+    mem: &mut T,
+    kernel_info: &Win32KernelInfo,
+    sysproc_dtb: Option<Address>,
+) -> Address {
+    match sysproc_dtb {
+        Some(dtb) if dtb == kernel_info.dtb => dtb,
+        Some(dtb) if validate(mem, kernel_info, dtb) => dtb,
+        Some(dtb) => {
+            debug!(
+                "sysproc_dtb={:x} failed validation, falling back to start_block.dtb={:x}",
+                dtb, kernel_info.dtb
+            );
+            kernel_info.dtb
+        }
+        None => kernel_info.dtb,
+    }
+}
@@ -0,0 +1,164 @@
+use std::prelude::v1::*;
+
+use log::trace;
+
+use memflow::architecture::{ArchitectureIdent, ArchitectureObj};
+use memflow::cglue::tuple::CTup2;
+use memflow::error::{Error, ErrorKind, ErrorOrigin, Result};
+use memflow::mem::{MemoryView, PhysicalMemory, ReadData, VirtualTranslate2};
+use memflow::os::process::{ProcessInfo, ProcessState};
+use memflow::types::Address;
+
+use crate::win32::process::{Win32ExitStatus, EXIT_STATUS_STILL_ACTIVE, IMAGE_FILE_NAME_LENGTH};
+use crate::win32::Win32Kernel;
+
+/// Raw eprocess fields that make up the base info of a process
+#[derive(Default)]
+pub(super) struct EprocessFields {
+    dtb: [u8; 8],
+    user_dtb: [u8; 8],
+    pid: [u8; 4],
+    exit_status: [u8; 4],
+    name: [u8; IMAGE_FILE_NAME_LENGTH],
+    wow64: [u8; 8],
+}
+
+impl<T: 'static + PhysicalMemory + Clone, V: 'static + VirtualTranslate2 + Clone>
+    Win32Kernel<T, V>
+{
+    /// Reads the base info fields of all given eprocess structures in a single vectored read.
+    ///
+    /// Returns `None` if any of the fields could not be read.
+    pub(super) fn read_eprocess_fields(
+        &mut self,
+        addresses: &[Address],
+    ) -> Option<Vec<EprocessFields>> {
+        let ptr_size = ArchitectureObj::from(self.kernel_info.os_info.arch).size_addr();
+
+        let mut fields = addresses
+            .iter()
+            .map(|_| EprocessFields::default())
+            .collect::<Vec<_>>();
+
+        {
+            let mut data: Vec<ReadData> = Vec::with_capacity(addresses.len() * 6);
+            for (&address, eprocess) in addresses.iter().zip(fields.iter_mut()) {
+                let EprocessFields {
+                    dtb,
+                    user_dtb,
+                    pid,
+                    exit_status,
+                    name,
+                    wow64,
+                } = eprocess;
+
+                data.push(CTup2(
+                    address + self.offsets.kproc_dtb(),
+                    (&mut dtb[..ptr_size]).into(),
+                ));
+                if self.offsets.kproc_user_dtb() != 0 {
+                    data.push(CTup2(
+                        address + self.offsets.kproc_user_dtb(),
+                        (&mut user_dtb[..ptr_size]).into(),
+                    ));
+                }
+                data.push(CTup2(
+                    address + self.offsets.eproc_pid(),
+                    (&mut pid[..]).into(),
+                ));
+                if self.offsets.eproc_exit_status() != 0 {
+                    data.push(CTup2(
+                        address + self.offsets.eproc_exit_status(),
+                        (&mut exit_status[..]).into(),
+                    ));
+                }
+                data.push(CTup2(
+                    address + self.offsets.eproc_name(),
+                    (&mut name[..]).into(),
+                ));
+                if self.offsets.eproc_wow64() != 0 {
+                    data.push(CTup2(
+                        address + self.offsets.eproc_wow64(),
+                        (&mut wow64[..ptr_size]).into(),
+                    ));
+                }
+            }
+
+            if let Err(err) = self.virt_mem.read_raw_list(&mut data) {
+                trace!("vectored eprocess read failed: {}", err);
+                return None;
+            }
+        }
+
+        Some(fields)
+    }
+
+    /// Constructs the base info of a process from its raw eprocess fields.
+    pub(super) fn process_info_base_from_fields(
+        &self,
+        address: Address,
+        fields: &EprocessFields,
+    ) -> Result<ProcessInfo> {
+        let dtb = Address::from(u64::from_le_bytes(fields.dtb));
+        trace!("dtb={:x}", dtb);
+
+        // with kva shadowing user mode pages are mapped in a separate dtb,
+        // the lowest bit is set if kva shadowing is disabled for this process
+        let user_dtb = Some(Address::from(u64::from_le_bytes(fields.user_dtb)))
+            .filter(|d| !d.is_null() && d.to_umem() & 1 == 0 && *d != dtb)
+            .unwrap_or_else(Address::invalid);
+        trace!("user_dtb={:x}", user_dtb);
+
+        let pid = u32::from_le_bytes(fields.pid);
+        trace!("pid={}", pid);
+
+        // the exit status is not available prior to nt 5.1
+        let state = if self.offsets.eproc_exit_status() == 0 {
+            ProcessState::Unknown
+        } else {
+            match Win32ExitStatus::from_le_bytes(fields.exit_status) {
+                EXIT_STATUS_STILL_ACTIVE => ProcessState::Alive,
+                exit_status => ProcessState::Dead(exit_status),
+            }
+        };
+
+        let name_len = fields
+            .name
+            .iter()
+            .position(|&c| c == 0)
+            .unwrap_or(IMAGE_FILE_NAME_LENGTH);
+        let name = String::from_utf8_lossy(&fields.name[..name_len]).to_string();
+        trace!("name={}", name);
+
+        let wow64 = Address::from(u64::from_le_bytes(fields.wow64));
+        trace!("wow64={:x}", wow64);
+
+        // determine process architecture
+        let sys_arch = self.kernel_info.os_info.arch;
+        let proc_arch = match ArchitectureObj::from(sys_arch).bits() {
+            64 => {
+                if wow64.is_null() {
+                    sys_arch
+                } else {
+                    ArchitectureIdent::X86(32, true)
+                }
+            }
+            32 => sys_arch,
+            _ => return Err(Error(ErrorOrigin::OsLayer, ErrorKind::InvalidArchitecture)),
+        };
+        trace!("proc_arch={:?}", proc_arch);
+
+        Ok(ProcessInfo {
+            address,
+            pid,
+            state,
+            name: name.into(),
+            path: "".into(),
+            command_line: "".into(),
+            sys_arch,
+            proc_arch,
+            dtb1: dtb,
+            dtb2: user_dtb,
+        })
+    }
+}
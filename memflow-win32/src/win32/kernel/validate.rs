@@ -0,0 +1,240 @@
+use std::prelude::v1::*;
+
+use log::debug;
+
+use memflow::architecture::{ArchitectureIdent, ArchitectureObj};
+use memflow::error::{Error, ErrorKind, ErrorOrigin, Result};
+use memflow::mem::{MemoryView, PhysicalMemory, VirtualTranslate2};
+use memflow::os::process::{Pid, ProcessInfo};
+use memflow::types::{umem, Address};
+
+use crate::kernel::heuristics::dtb_mask;
+use crate::win32::Win32Kernel;
+
+/// Process ids are handles in the PspCidTable which holds at most 2^24 entries
+const MAX_PID: Pid = 0x400_0000;
+
+/// Field of an eprocess structure that holds a value no valid process can have
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize))]
+pub enum Win32EprocessDefect {
+    /// The pid is not a valid handle value
+    InvalidPid(Pid),
+    /// The page table referenced by the dtb is null or outside of physical memory
+    InvalidDtb(Address),
+    /// The image file name is empty or contains control characters
+    InvalidName,
+    /// A pointer of the active process links does not point into kernel space
+    InvalidListEntry(Address),
+}
+
+impl<T: 'static + PhysicalMemory + Clone, V: 'static + VirtualTranslate2 + Clone>
+    Win32Kernel<T, V>
+{
+    /// Checks the fields of a process for values that cannot belong to a valid eprocess structure.
+    ///
+    /// This catches entries of partially overwritten memory and false positives of scans
+    /// before they are reported as processes. An empty list means no defects were found.
+    pub fn eprocess_defects(&mut self, info: &ProcessInfo) -> Vec<Win32EprocessDefect> {
+        let checks = Win32EprocessChecks {
+            arch: self.kernel_info.os_info.arch,
+            aligned_pids: self.kernel_info.kernel_winver.major_version() >= 5,
+            max_address: self.virt_mem.phys_mem_ref().metadata().max_address,
+            eproc_link: self.offsets.eproc_link(),
+            list_blink: self.offsets.list_blink(),
+        };
+        checks.defects(&mut self.virt_mem, info)
+    }
+
+    /// Rejects processes with defects, see [`Win32Kernel::eprocess_defects`].
+    pub(super) fn validate_eprocess(&mut self, info: ProcessInfo) -> Result<ProcessInfo> {
+        let defects = self.eprocess_defects(&info);
+        if defects.is_empty() {
+            Ok(info)
+        } else {
+            debug!(
+                "skipping eprocess {:x} with defects: {:?}",
+                info.address, defects
+            );
+            Err(Error(ErrorOrigin::OsLayer, ErrorKind::InvalidProcessInfo))
+        }
+    }
+}
+
+/// Kernel specific parameters of [`Win32Kernel::eprocess_defects`]
+struct Win32EprocessChecks {
+    arch: ArchitectureIdent,
+    /// Process ids are multiples of 4 since nt 5.0
+    aligned_pids: bool,
+    /// Highest physical address of the target, not checked if null
+    max_address: Address,
+    eproc_link: usize,
+    list_blink: usize,
+}
+
+impl Win32EprocessChecks {
+    fn defects<M: MemoryView>(&self, mem: &mut M, info: &ProcessInfo) -> Vec<Win32EprocessDefect> {
+        let mut defects = vec![];
+
+        if info.pid >= MAX_PID || (self.aligned_pids && info.pid % 4 != 0) {
+            defects.push(Win32EprocessDefect::InvalidPid(info.pid));
+        }
+
+        // the lower bits of the dtb might contain the pcid or flags of the pae table
+        let dtb = info.dtb1.to_umem() & dtb_mask(self.arch);
+        if dtb == 0 || (!self.max_address.is_null() && dtb > self.max_address.to_umem()) {
+            defects.push(Win32EprocessDefect::InvalidDtb(info.dtb1));
+        }
+
+        let name: &str = info.name.as_ref();
+        if name.is_empty() || name.chars().any(char::is_control) {
+            defects.push(Win32EprocessDefect::InvalidName);
+        }
+
+        let arch_obj = ArchitectureObj::from(self.arch);
+        let list_entry = info.address + self.eproc_link;
+        for link in [list_entry, list_entry + self.list_blink] {
+            match mem.read_addr_arch(arch_obj, link) {
+                Ok(ptr) if is_kernel_address(arch_obj, ptr) => {}
+                Ok(ptr) => defects.push(Win32EprocessDefect::InvalidListEntry(ptr)),
+                Err(_) => defects.push(Win32EprocessDefect::InvalidListEntry(Address::null())),
+            }
+        }
+
+        defects
+    }
+}
+
+/// Kernel space is located in the upper half of the address space on all supported architectures.
+fn is_kernel_address(arch: ArchitectureObj, address: Address) -> bool {
+    let bits = arch.bits() as umem;
+    (address.to_umem() >> (bits - 1)) & 1 == 1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use memflow::dummy::DummyMemory;
+    use memflow::os::process::ProcessState;
+    use memflow::types::size;
+
+    const EPROC_LINK: usize = 0x448;
+
+    fn checks() -> Win32EprocessChecks {
+        Win32EprocessChecks {
+            arch: ArchitectureIdent::X86(64, false),
+            aligned_pids: true,
+            max_address: Address::from(0xffff_ffffu64),
+            eproc_link: EPROC_LINK,
+            list_blink: 8,
+        }
+    }
+
+    fn process(pid: Pid, dtb: umem) -> ProcessInfo {
+        ProcessInfo {
+            address: Address::from(0x1000u64),
+            pid,
+            state: ProcessState::Alive,
+            name: "explorer.exe".into(),
+            path: "".into(),
+            command_line: "".into(),
+            sys_arch: ArchitectureIdent::X86(64, false),
+            proc_arch: ArchitectureIdent::X86(64, false),
+            dtb1: Address::from(dtb),
+            dtb2: Address::invalid(),
+        }
+    }
+
+    fn links<M: MemoryView>(mem: &mut M, flink: u64, blink: u64) {
+        let list_entry = Address::from(0x1000u64) + EPROC_LINK;
+        mem.write(list_entry, &flink).unwrap();
+        mem.write(list_entry + 8usize, &blink).unwrap();
+    }
+
+    #[test]
+    fn valid() {
+        let mut mem = DummyMemory::new(size::mb(1));
+        let mut view = mem.phys_view();
+        links(&mut view, 0xffff_c005_1f2e_3488, 0xffff_c005_1f2e_5488);
+
+        assert!(checks()
+            .defects(&mut view, &process(0x1f4, 0x1ad000))
+            .is_empty());
+    }
+
+    #[test]
+    fn pcid_tagged_dtb() {
+        let mut mem = DummyMemory::new(size::mb(1));
+        let mut view = mem.phys_view();
+        links(&mut view, 0xffff_c005_1f2e_3488, 0xffff_c005_1f2e_5488);
+
+        assert!(checks()
+            .defects(&mut view, &process(0x1f4, 0x1ad002))
+            .is_empty());
+    }
+
+    #[test]
+    fn invalid_dtb() {
+        let mut mem = DummyMemory::new(size::mb(1));
+        let mut view = mem.phys_view();
+        links(&mut view, 0xffff_c005_1f2e_3488, 0xffff_c005_1f2e_5488);
+
+        assert_eq!(
+            checks().defects(&mut view, &process(0x1f4, 0x2)),
+            vec![Win32EprocessDefect::InvalidDtb(Address::from(0x2u64))]
+        );
+        assert_eq!(
+            checks().defects(&mut view, &process(0x1f4, 0x1_0000_0000)),
+            vec![Win32EprocessDefect::InvalidDtb(Address::from(
+                0x1_0000_0000u64
+            ))]
+        );
+    }
+
+    #[test]
+    fn invalid_pid_and_name() {
+        let mut mem = DummyMemory::new(size::mb(1));
+        let mut view = mem.phys_view();
+        links(&mut view, 0xffff_c005_1f2e_3488, 0xffff_c005_1f2e_5488);
+
+        let mut info = process(0x1f5, 0x1ad000);
+        info.name = "exp\nlorer".into();
+        assert_eq!(
+            checks().defects(&mut view, &info),
+            vec![
+                Win32EprocessDefect::InvalidPid(0x1f5),
+                Win32EprocessDefect::InvalidName
+            ]
+        );
+        assert_eq!(
+            checks().defects(&mut view, &process(MAX_PID, 0x1ad000)),
+            vec![Win32EprocessDefect::InvalidPid(MAX_PID)]
+        );
+    }
+
+    #[test]
+    fn invalid_list_entry() {
+        let mut mem = DummyMemory::new(size::mb(1));
+        let mut view = mem.phys_view();
+        links(&mut view, 0x7ff6_1234_0000, 0xffff_c005_1f2e_5488);
+
+        assert_eq!(
+            checks().defects(&mut view, &process(0x1f4, 0x1ad000)),
+            vec![Win32EprocessDefect::InvalidListEntry(Address::from(
+                0x7ff6_1234_0000u64
+            ))]
+        );
+
+        // links outside of memory cannot be read
+        let mut info = process(0x1f4, 0x1ad000);
+        info.address = Address::from(size::mb(2) as umem);
+        assert_eq!(
+            checks().defects(&mut view, &info),
+            vec![
+                Win32EprocessDefect::InvalidListEntry(Address::null()),
+                Win32EprocessDefect::InvalidListEntry(Address::null())
+            ]
+        );
+    }
+}
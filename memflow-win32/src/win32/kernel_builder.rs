@@ -1,21 +1,32 @@
 use std::prelude::v1::*;
 
-use super::{Win32Kernel, Win32KernelInfo};
-use crate::offsets::Win32Offsets;
+#[cfg(feature = "parallel")]
+use super::kernel_info::ParallelScanner;
+use super::{ProcessListFilter, Win32CrashDumpHeader, Win32Kernel, Win32KernelInfo, Win32Profile};
+use crate::kernel::{AArch64Layout, NtosScanConfig};
+use crate::offsets::{Win32OffsetFile, Win32OffsetTable, Win32Offsets};
+use crate::progress::{CancellationToken, Progress, ProgressCallback};
 
 #[cfg(feature = "symstore")]
 use crate::offsets::SymbolStore;
 
-use crate::offsets::offset_builder_with_kernel_info;
+use crate::offsets::{offset_builder_with_kernel_info, Win32OffsetBuilder};
+
+#[cfg(feature = "std")]
+use super::Win32EnvConfig;
+
+use log::info;
 
 use memflow::architecture::ArchitectureIdent;
 use memflow::cglue::forward::ForwardMut;
 use memflow::error::Result;
+use memflow::error::{Error, ErrorKind, ErrorOrigin};
+use memflow::mem::MemoryMap;
 use memflow::mem::{
     phys_mem::CachedPhysicalMemory, virt_translate::CachedVirtualTranslate, DirectTranslate,
     PhysicalMemory, VirtualTranslate2,
 };
-use memflow::types::{Address, DefaultCacheValidator};
+use memflow::types::{umem, Address, DefaultCacheValidator};
 
 /// Builder for a Windows Kernel structure.
 ///
@@ -80,6 +91,12 @@ use memflow::types::{Address, DefaultCacheValidator};
 /// }
 /// ```
 ///
+/// # Environment
+///
+/// When built with the `std` feature the builder picks up `MEMFLOW_WIN32_*` environment variables
+/// on construction (see [`Win32EnvConfig`](super::Win32EnvConfig)).
+/// Settings that are configured on the builder afterwards take precedence over the environment.
+///
 /// # Remarks
 ///
 /// Manual initialization of the above examples would look like the following:
@@ -123,6 +140,22 @@ pub struct Win32KernelBuilder<T, TK, VK> {
     arch: Option<ArchitectureIdent>,
     kernel_hint: Option<Address>,
     dtb: Option<Address>,
+    dtb_candidates: Vec<Address>,
+    la57: Option<bool>,
+    aarch64_layout: Option<AArch64Layout>,
+    ntos_scan_config: Option<NtosScanConfig>,
+    exhaustive_scan: bool,
+    calibrate: bool,
+    progress: Option<Progress>,
+    cancellation: Option<CancellationToken>,
+    #[cfg(feature = "parallel")]
+    parallel: Option<fn(&T) -> ParallelScanner>,
+    mem_map: Option<MemoryMap<(Address, umem)>>,
+    offset_file: Option<Win32OffsetFile>,
+    #[cfg(feature = "offset_files")]
+    offset_db_dir: Option<std::path::PathBuf>,
+    offset_overrides: Vec<(String, u32)>,
+    process_list_filter: ProcessListFilter,
 
     #[cfg(feature = "symstore")]
     symbol_store: Option<SymbolStore>,
@@ -142,6 +175,22 @@ where
             arch: None,
             kernel_hint: None,
             dtb: None,
+            dtb_candidates: vec![],
+            la57: None,
+            aarch64_layout: None,
+            ntos_scan_config: None,
+            exhaustive_scan: false,
+            calibrate: false,
+            progress: None,
+            cancellation: None,
+            #[cfg(feature = "parallel")]
+            parallel: None,
+            mem_map: None,
+            offset_file: None,
+            #[cfg(feature = "offset_files")]
+            offset_db_dir: None,
+            offset_overrides: vec![],
+            process_list_filter: ProcessListFilter::default(),
 
             #[cfg(feature = "symstore")]
             symbol_store: Some(SymbolStore::default()),
@@ -149,6 +198,32 @@ where
             build_page_cache: Box::new(|connector, _| connector),
             build_vat_cache: Box::new(|vat, _| vat),
         }
+        .with_env_config()
+    }
+
+    #[cfg(feature = "std")]
+    fn with_env_config(self) -> Self {
+        self.env_config(&Win32EnvConfig::from_env())
+    }
+
+    #[cfg(not(feature = "std"))]
+    fn with_env_config(self) -> Self {
+        self
+    }
+}
+
+impl<T, TK, VK> Win32KernelBuilder<T, TK, VK> {
+    /// Applies the overrides of the given environment configuration.
+    ///
+    /// This is invoked automatically when the builder is created.
+    #[cfg(feature = "std")]
+    #[cfg_attr(not(feature = "symstore"), allow(unused_mut, unused_variables))]
+    pub fn env_config(mut self, config: &Win32EnvConfig) -> Self {
+        #[cfg(feature = "symstore")]
+        if let Some(symbol_store) = config.symbol_store() {
+            self.symbol_store = symbol_store;
+        }
+        self
     }
 }
 
@@ -158,24 +233,40 @@ where
     TK: 'static + PhysicalMemory + Clone,
     VK: 'static + VirtualTranslate2 + Clone,
 {
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(name = "win32_kernel_build", skip_all)
+    )]
     pub fn build(mut self) -> Result<Win32Kernel<TK, VK>> {
-        // find kernel_info
-        let mut kernel_scanner = Win32KernelInfo::scanner(self.connector.forward_mut());
-        if let Some(arch) = self.arch {
-            kernel_scanner = kernel_scanner.arch(arch);
-        }
-        if let Some(kernel_hint) = self.kernel_hint {
-            kernel_scanner = kernel_scanner.kernel_hint(kernel_hint);
-        }
-        if let Some(dtb) = self.dtb {
-            kernel_scanner = kernel_scanner.dtb(dtb);
+        // apply the memory map before scanning so reserved regions are never read
+        if let Some(mem_map) = &self.mem_map {
+            info!("applying user supplied mem_map={:?}", mem_map);
+            self.connector
+                .set_mem_map(mem_map.clone().into_vec().as_slice());
         }
-        let kernel_info = kernel_scanner.scan()?;
+
+        // find kernel_info, every dtb candidate is tried until the kernel could be found
+        let kernel_info = if self.dtb_candidates.is_empty() {
+            self.scan_kernel_info(self.dtb)?
+        } else {
+            let mut candidates = self.dtb.into_iter().chain(self.dtb_candidates.clone());
+            loop {
+                match candidates.next() {
+                    Some(dtb) => match self.scan_kernel_info(Some(dtb)) {
+                        Ok(kernel_info) => break kernel_info,
+                        Err(err) => info!("dtb candidate {:x} failed: {}", dtb, err),
+                    },
+                    None => {
+                        return Err(Error(ErrorOrigin::OsLayer, ErrorKind::NotFound)
+                            .log_error("none of the dtb candidates produced a valid kernel"))
+                    }
+                }
+            }
+        };
 
         // acquire offsets from the symbol store
         let offsets = self.build_offsets(&kernel_info)?;
-
-        // TODO: parse memory maps
+        let offsets = self.apply_offset_overrides(offsets)?;
 
         // create a vat object
         let vat = DirectTranslate::new();
@@ -185,18 +276,109 @@ where
         let kernel_vat = (self.build_vat_cache)(vat, kernel_info.os_info.arch);
 
         // create the final kernel object
-        Ok(Win32Kernel::new(
-            kernel_connector,
-            kernel_vat,
-            offsets,
-            kernel_info,
-        ))
+        let mut kernel = Win32Kernel::new(kernel_connector, kernel_vat, offsets, kernel_info);
+        kernel.set_process_list_filter(self.process_list_filter);
+        #[cfg(feature = "symstore")]
+        kernel.set_symbol_store(self.symbol_store);
+
+        // the kernel replaces the memory map with the one found in MmPhysicalMemoryBlock,
+        // a user supplied memory map always takes precedence
+        if let Some(mem_map) = self.mem_map {
+            kernel.set_mem_map(mem_map.into_vec().as_slice());
+        }
+
+        Ok(kernel)
+    }
+
+    fn apply_offset_overrides(&self, offsets: Win32Offsets) -> Result<Win32Offsets> {
+        if self.offset_overrides.is_empty() {
+            return Ok(offsets);
+        }
+
+        let mut table = Win32OffsetTable::from(offsets);
+        for (name, value) in self.offset_overrides.iter() {
+            let field = table.field_mut(name).ok_or_else(|| {
+                Error(ErrorOrigin::OsLayer, ErrorKind::InvalidArgument)
+                    .log_error(format!("unknown offset: {}", name))
+            })?;
+            info!("overriding offset {}: {:x} -> {:x}", name, field, value);
+            *field = *value;
+        }
+        Ok(Win32Offsets::from(table))
+    }
+
+    fn scan_kernel_info(&mut self, dtb: Option<Address>) -> Result<Win32KernelInfo> {
+        // the connector is cloned before scanning so the memory map has already been applied
+        #[cfg(feature = "parallel")]
+        let parallel = self.parallel.map(|parallel| parallel(&self.connector));
+
+        let mut kernel_scanner = Win32KernelInfo::scanner(self.connector.forward_mut());
+        if let Some(arch) = self.arch {
+            kernel_scanner = kernel_scanner.arch(arch);
+        }
+        if let Some(kernel_hint) = self.kernel_hint {
+            kernel_scanner = kernel_scanner.kernel_hint(kernel_hint);
+        }
+        if let Some(dtb) = dtb {
+            kernel_scanner = kernel_scanner.dtb(dtb);
+        }
+        if let Some(la57) = self.la57 {
+            kernel_scanner = kernel_scanner.la57(la57);
+        }
+        if let Some(aarch64_layout) = self.aarch64_layout {
+            kernel_scanner = kernel_scanner.aarch64_layout(aarch64_layout);
+        }
+        if let Some(ntos_scan_config) = self.ntos_scan_config {
+            kernel_scanner = kernel_scanner.ntos_scan_config(ntos_scan_config);
+        }
+        kernel_scanner = kernel_scanner.exhaustive_scan(self.exhaustive_scan);
+        if let Some(progress) = &self.progress {
+            kernel_scanner = kernel_scanner.progress(progress.clone());
+        }
+        if let Some(cancellation) = &self.cancellation {
+            kernel_scanner = kernel_scanner.cancellation(cancellation.clone());
+        }
+        #[cfg(feature = "parallel")]
+        if let Some(parallel) = parallel {
+            kernel_scanner = kernel_scanner.parallel(parallel);
+        }
+        kernel_scanner.scan()
     }
 
     #[cfg(feature = "symstore")]
-    fn build_offsets(&self, kernel_info: &Win32KernelInfo) -> Result<Win32Offsets> {
-        let mut builder = offset_builder_with_kernel_info(kernel_info);
-        if let Some(store) = &self.symbol_store {
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+    fn build_offsets(&mut self, kernel_info: &Win32KernelInfo) -> Result<Win32Offsets> {
+        if let Some(offset_file) = &self.offset_file {
+            return kernel_info
+                .into_offset_builder(Win32OffsetBuilder::new().no_symbol_store())
+                .offset_list(std::slice::from_ref(offset_file))
+                .build();
+        }
+
+        let connector = &mut self.connector;
+        let calibrate = self.calibrate;
+        let builder = offset_builder_with_kernel_info(kernel_info).heuristic(move || {
+            kernel_info
+                .heuristic_offsets(connector.forward_mut())
+                .or_else(|err| {
+                    if !calibrate {
+                        return Err(err);
+                    }
+                    info!("calibrating offsets on the system process");
+                    Ok(Win32Offsets::from(
+                        kernel_info.calibrate_offsets(connector.forward_mut())?,
+                    ))
+                })
+        });
+        #[cfg(feature = "offset_files")]
+        let builder = match &self.offset_db_dir {
+            Some(dir) => builder.offset_db_dir(dir),
+            None => builder,
+        };
+
+        // reactos kernels are not available on the microsoft symbol server
+        let mut builder = builder;
+        if let (Some(store), false) = (&self.symbol_store, kernel_info.reactos) {
             builder = builder.symbol_store(store.clone());
         } else {
             builder = builder.no_symbol_store();
@@ -205,8 +387,36 @@ where
     }
 
     #[cfg(not(feature = "symstore"))]
-    fn build_offsets(&self, kernel_info: &Win32KernelInfo) -> Result<Win32Offsets> {
-        offset_builder_with_kernel_info(&kernel_info).build()
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+    fn build_offsets(&mut self, kernel_info: &Win32KernelInfo) -> Result<Win32Offsets> {
+        if let Some(offset_file) = &self.offset_file {
+            return kernel_info
+                .into_offset_builder(Win32OffsetBuilder::new())
+                .offset_list(std::slice::from_ref(offset_file))
+                .build();
+        }
+
+        let connector = &mut self.connector;
+        let calibrate = self.calibrate;
+        let builder = offset_builder_with_kernel_info(kernel_info).heuristic(move || {
+            kernel_info
+                .heuristic_offsets(connector.forward_mut())
+                .or_else(|err| {
+                    if !calibrate {
+                        return Err(err);
+                    }
+                    info!("calibrating offsets on the system process");
+                    Ok(Win32Offsets::from(
+                        kernel_info.calibrate_offsets(connector.forward_mut())?,
+                    ))
+                })
+        });
+        #[cfg(feature = "offset_files")]
+        let builder = match &self.offset_db_dir {
+            Some(dir) => builder.offset_db_dir(dir),
+            None => builder,
+        };
+        builder.build()
     }
 
     pub fn arch(mut self, arch: ArchitectureIdent) -> Self {
@@ -224,6 +434,208 @@ where
         self
     }
 
+    /// Bootstraps the kernel from the header of a raw crash dump exposed by the connector.
+    ///
+    /// The architecture, dtb and a kernel hint are taken from the header so the start block
+    /// does not have to be scanned. The physical memory runs of full dumps are applied
+    /// as the memory map unless a memory map has been set already.
+    pub fn from_crashdump_header(mut self, header: &[u8]) -> Result<Self> {
+        let header = Win32CrashDumpHeader::parse(header)?;
+        info!(
+            "crash dump header: arch={:?} dtb={:x} build={}",
+            header.arch, header.dtb, header.build_number
+        );
+
+        self.arch = Some(header.arch);
+        self.dtb = Some(header.dtb);
+        // PsLoadedModuleList is located in the data section of ntoskrnl.exe
+        if !header.ps_loaded_module_list.is_null() {
+            self.kernel_hint = Some(header.ps_loaded_module_list);
+        }
+        if self.mem_map.is_none() {
+            self.mem_map = header.mem_map();
+        }
+        Ok(self)
+    }
+
+    /// Sets a list of dtbs that are tried in order until the kernel can be initialized with one of them.
+    ///
+    /// This is useful for memory dumps where the correct dtb is uncertain.
+    /// A dtb set via [`dtb`](Self::dtb) is tried first.
+    pub fn dtb_candidates(mut self, dtb_candidates: &[Address]) -> Self {
+        self.dtb_candidates = dtb_candidates.to_vec();
+        self
+    }
+
+    /// Forces 5-level paging (LA57) on or off.
+    ///
+    /// By default LA57 is detected from the CR4 value stored in the processor start block.
+    pub fn la57(mut self, la57: bool) -> Self {
+        self.la57 = Some(la57);
+        self
+    }
+
+    /// Sets the physical memory layout of aarch64 targets.
+    ///
+    /// By default physical memory is expected to start at 1gb with at most 512gb of memory.
+    /// Targets with a different physical layout or more memory require a custom layout.
+    pub fn aarch64_layout(mut self, layout: AArch64Layout) -> Self {
+        self.aarch64_layout = Some(layout);
+        self
+    }
+
+    /// Sets the granularity of the scans for ntoskrnl.exe in the kernel address space of x64 targets.
+    ///
+    /// By default the kernel is probed in chunks of 2mb within 16mb below the kernel hint.
+    /// Connectors with a high latency benefit from larger probes while connectors with
+    /// a low bandwidth benefit from smaller ones.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use memflow::mem::PhysicalMemory;
+    /// use memflow::types::mem;
+    /// use memflow_win32::kernel::NtosScanConfig;
+    /// use memflow_win32::win32::Win32KernelBuilder;
+    ///
+    /// fn test<T: 'static + PhysicalMemory + Clone>(connector: T) {
+    ///     let _kernel = Win32KernelBuilder::new(connector)
+    ///         .ntos_scan_config(NtosScanConfig {
+    ///             probe_size: mem::mb(8),
+    ///             ..Default::default()
+    ///         })
+    ///         .build()
+    ///         .unwrap();
+    /// }
+    /// ```
+    pub fn ntos_scan_config(mut self, config: NtosScanConfig) -> Self {
+        self.ntos_scan_config = Some(config);
+        self
+    }
+
+    /// Sets the memory map of the target.
+    ///
+    /// The memory map is applied to the connector before any scanning takes place
+    /// so that reserved regions (e.g. mmio holes) are never accessed.
+    /// It also takes precedence over the memory map found in the kernel.
+    pub fn mem_map(mut self, mem_map: MemoryMap<(Address, umem)>) -> Self {
+        self.mem_map = Some(mem_map);
+        self
+    }
+
+    /// Reads the memory map from the given file.
+    ///
+    /// See [`MemoryMap::open`] for the file format.
+    #[cfg(feature = "memmapfiles")]
+    pub fn mem_map_file<P: AsRef<std::path::Path>>(self, path: P) -> Result<Self> {
+        Ok(self.mem_map(MemoryMap::open(path)?))
+    }
+
+    /// Uses the offsets of the given offset file instead of the symbol store and the built-in offsets.
+    ///
+    /// The offset file still has to match the guid or the version of the target kernel.
+    pub fn offset_file(mut self, offset_file: Win32OffsetFile) -> Self {
+        self.offset_file = Some(offset_file);
+        self
+    }
+
+    /// Overrides a single offset after the offsets have been resolved.
+    ///
+    /// This allows fixing a wrong offset (e.g. on a new insider build) without rebuilding the crate.
+    /// The name is the name of the field in [`Win32OffsetTable`], building the kernel fails if it does not exist.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use memflow::mem::PhysicalMemory;
+    /// use memflow_win32::win32::Win32Kernel;
+    ///
+    /// fn test<T: 'static + PhysicalMemory + Clone>(connector: T) {
+    ///     let _kernel = Win32Kernel::builder(connector)
+    ///         .override_offset("eproc_peb", 0x550)
+    ///         .build()
+    ///         .unwrap();
+    /// }
+    /// ```
+    pub fn override_offset(mut self, name: &str, value: u32) -> Self {
+        self.offset_overrides.push((name.to_string(), value));
+        self
+    }
+
+    /// Reads the offset file from the given toml, json or yaml file.
+    ///
+    /// See [`offset_file`](Self::offset_file) for details.
+    #[cfg(feature = "offset_files")]
+    pub fn offset_file_path<P: AsRef<std::path::Path>>(self, path: P) -> Result<Self> {
+        Ok(self.offset_file(Win32OffsetFile::from_file(path)?))
+    }
+
+    /// Sets a directory of offset files that is searched when the offsets cannot be
+    /// retrieved from the symbol store.
+    ///
+    /// See [`Win32OffsetBuilder::offset_db_dir`] for details.
+    #[cfg(feature = "offset_files")]
+    pub fn offset_db_dir<P: AsRef<std::path::Path>>(mut self, offset_db_dir: P) -> Self {
+        self.offset_db_dir = Some(offset_db_dir.as_ref().to_path_buf());
+        self
+    }
+
+    /// Selects the processes that are enumerated by the process list of the kernel.
+    ///
+    /// See [`Win32Kernel::set_process_list_filter`].
+    pub fn process_list_filter(mut self, filter: ProcessListFilter) -> Self {
+        self.process_list_filter = filter;
+        self
+    }
+
+    /// Scans all of physical memory for the dtb if it cannot be found in the low stub.
+    ///
+    /// This is disabled by default since scanning can take a long time on large targets.
+    /// Exhaustive scans also search the whole kernel address space for a kernel with wiped pe headers.
+    pub fn exhaustive_scan(mut self) -> Self {
+        self.exhaustive_scan = true;
+        self
+    }
+
+    /// Brute forces the offsets on the System process if no other offset source matches the kernel.
+    ///
+    /// See [`Win32KernelInfo::calibrate_offsets`] for details.
+    pub fn calibrate(mut self) -> Self {
+        self.calibrate = true;
+        self
+    }
+
+    /// Reports the progress of the kernel scans to the given callback.
+    ///
+    /// This is mostly useful in combination with [`Win32KernelBuilder::exhaustive_scan`].
+    /// Pdb downloads report their progress through [`SymbolStore::progress`](crate::offsets::SymbolStore::progress).
+    pub fn progress<P: ProgressCallback + 'static>(mut self, progress: P) -> Self {
+        self.progress = Some(Progress::new(progress));
+        self
+    }
+
+    /// Distributes the kernel scans over the threads of the rayon thread pool.
+    ///
+    /// Every thread reads through its own clone of the connector, so the connector has to
+    /// support concurrent access through clones. This mostly speeds up the initialization
+    /// of large targets with fast connectors.
+    #[cfg(feature = "parallel")]
+    pub fn parallel(mut self) -> Self
+    where
+        T: 'static + PhysicalMemory + Clone + Send,
+    {
+        self.parallel = Some(|connector: &T| ParallelScanner::new(connector.clone()));
+        self
+    }
+
+    /// Aborts the kernel scans once the given token has been cancelled.
+    ///
+    /// The build then fails with an error.
+    pub fn cancellation(mut self, cancellation: CancellationToken) -> Self {
+        self.cancellation = Some(cancellation);
+        self
+    }
+
     /// Configures the symbol store to be used when constructing the Kernel.
     /// This will override the default symbol store that is being used if no other setting is configured.
     ///
@@ -303,6 +715,22 @@ where
             arch: self.arch,
             kernel_hint: self.kernel_hint,
             dtb: self.dtb,
+            dtb_candidates: self.dtb_candidates,
+            la57: self.la57,
+            aarch64_layout: self.aarch64_layout,
+            ntos_scan_config: self.ntos_scan_config,
+            exhaustive_scan: self.exhaustive_scan,
+            calibrate: self.calibrate,
+            progress: self.progress,
+            cancellation: self.cancellation,
+            #[cfg(feature = "parallel")]
+            parallel: self.parallel,
+            mem_map: self.mem_map,
+            offset_file: self.offset_file,
+            #[cfg(feature = "offset_files")]
+            offset_db_dir: self.offset_db_dir,
+            offset_overrides: self.offset_overrides,
+            process_list_filter: self.process_list_filter,
 
             #[cfg(feature = "symstore")]
             symbol_store: self.symbol_store,
@@ -322,6 +750,78 @@ where
         }
     }
 
+    /// Applies the settings of the given profile.
+    ///
+    /// This enables a page cache and a vat cache sized for the profile
+    /// and replaces any previously configured caches.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use memflow::mem::PhysicalMemory;
+    /// use memflow_win32::win32::{Win32Kernel, Win32Profile};
+    ///
+    /// fn test<T: 'static + PhysicalMemory + Clone>(connector: T) {
+    ///     let _kernel = Win32Kernel::builder(connector)
+    ///         .profile(Win32Profile::QemuVm)
+    ///         .build()
+    ///         .unwrap();
+    /// }
+    /// ```
+    pub fn profile(
+        self,
+        profile: Win32Profile,
+    ) -> Win32KernelBuilder<
+        T,
+        CachedPhysicalMemory<'a, T, DefaultCacheValidator>,
+        CachedVirtualTranslate<DirectTranslate, DefaultCacheValidator>,
+    > {
+        let settings = profile.settings();
+        info!("applying profile {}: {:?}", profile, settings);
+
+        Win32KernelBuilder {
+            connector: self.connector,
+
+            arch: self.arch,
+            kernel_hint: self.kernel_hint,
+            dtb: self.dtb,
+            dtb_candidates: self.dtb_candidates,
+            la57: self.la57,
+            aarch64_layout: self.aarch64_layout,
+            ntos_scan_config: self.ntos_scan_config,
+            exhaustive_scan: self.exhaustive_scan || settings.exhaustive_scan,
+            calibrate: self.calibrate,
+            progress: self.progress,
+            cancellation: self.cancellation,
+            #[cfg(feature = "parallel")]
+            parallel: self.parallel,
+            mem_map: self.mem_map,
+            offset_file: self.offset_file,
+            #[cfg(feature = "offset_files")]
+            offset_db_dir: self.offset_db_dir,
+            offset_overrides: self.offset_overrides,
+            process_list_filter: self.process_list_filter,
+
+            #[cfg(feature = "symstore")]
+            symbol_store: self.symbol_store,
+
+            build_page_cache: Box::new(move |connector, arch| {
+                CachedPhysicalMemory::builder(connector)
+                    .arch(arch)
+                    .cache_size(settings.page_cache_size)
+                    .build()
+                    .unwrap()
+            }),
+            build_vat_cache: Box::new(move |vat, arch| {
+                CachedVirtualTranslate::builder(vat)
+                    .arch(arch)
+                    .entries(settings.vat_cache_entries)
+                    .build()
+                    .unwrap()
+            }),
+        }
+    }
+
     /// Creates a Kernel structure by constructing the page cache from the given closure.
     ///
     /// This function accepts a `FnOnce` closure that is being evaluated
@@ -358,6 +858,22 @@ where
             arch: self.arch,
             kernel_hint: self.kernel_hint,
             dtb: self.dtb,
+            dtb_candidates: self.dtb_candidates,
+            la57: self.la57,
+            aarch64_layout: self.aarch64_layout,
+            ntos_scan_config: self.ntos_scan_config,
+            exhaustive_scan: self.exhaustive_scan,
+            calibrate: self.calibrate,
+            progress: self.progress,
+            cancellation: self.cancellation,
+            #[cfg(feature = "parallel")]
+            parallel: self.parallel,
+            mem_map: self.mem_map,
+            offset_file: self.offset_file,
+            #[cfg(feature = "offset_files")]
+            offset_db_dir: self.offset_db_dir,
+            offset_overrides: self.offset_overrides,
+            process_list_filter: self.process_list_filter,
 
             #[cfg(feature = "symstore")]
             symbol_store: self.symbol_store,
@@ -403,6 +919,22 @@ where
             arch: self.arch,
             kernel_hint: self.kernel_hint,
             dtb: self.dtb,
+            dtb_candidates: self.dtb_candidates,
+            la57: self.la57,
+            aarch64_layout: self.aarch64_layout,
+            ntos_scan_config: self.ntos_scan_config,
+            exhaustive_scan: self.exhaustive_scan,
+            calibrate: self.calibrate,
+            progress: self.progress,
+            cancellation: self.cancellation,
+            #[cfg(feature = "parallel")]
+            parallel: self.parallel,
+            mem_map: self.mem_map,
+            offset_file: self.offset_file,
+            #[cfg(feature = "offset_files")]
+            offset_db_dir: self.offset_db_dir,
+            offset_overrides: self.offset_overrides,
+            process_list_filter: self.process_list_filter,
 
             #[cfg(feature = "symstore")]
             symbol_store: self.symbol_store,
@@ -0,0 +1,277 @@
+/*!
+Module for enumerating registry and object callbacks of the kernel.
+
+Drivers register registry callbacks with `CmRegisterCallbackEx`, the kernel keeps them in the
+`CallbackListHead` list. Object callbacks registered with `ObRegisterCallbacks` are kept in the
+`CallbackList` of the object type they apply to (processes, threads and desktops).
+Both are commonly abused by rootkits and anti-cheats to hide or protect processes,
+registry keys and handles.
+
+The entries of both lists are undocumented and not part of the kernel pdb, their layout is
+stable since nt 6.0 and derived from the pointer width. The list heads are resolved from the
+kernel pdb which is loaded from the symbol store.
+
+Routines are attributed to the kernel module that contains them, routines that do not
+belong to any loaded module are suspicious.
+
+This module is only available with the `symstore` feature enabled.
+
+# Examples:
+
+```
+use memflow::prelude::v1::*;
+use memflow_win32::offsets::SymbolStore;
+use memflow_win32::win32::Win32Kernel;
+
+fn test<T: 'static + PhysicalMemory + Clone, V: 'static + VirtualTranslate2 + Clone>(
+    kernel: &mut Win32Kernel<T, V>,
+) {
+    let store = SymbolStore::default();
+    for callback in kernel.registry_callbacks(&store).unwrap() {
+        println!("{} {:x}", callback.altitude, callback.function.address);
+    }
+    for callback in kernel.object_callbacks(&store).unwrap() {
+        let routine = callback.pre_operation.address;
+        println!("{:?} {} {:x}", callback.object_type, callback.altitude, routine);
+    }
+}
+```
+*/
+use std::prelude::v1::*;
+
+use super::{VirtualReadUnicodeString, Win32Kernel, Win32ListWalker, Win32ModuleOffset};
+use crate::offsets::SymbolStore;
+
+use log::{debug, trace};
+
+use memflow::architecture::ArchitectureObj;
+use memflow::error::Result;
+use memflow::mem::{MemoryView, PhysicalMemory, VirtualTranslate2};
+use memflow::os::{ModuleInfo, Os};
+use memflow::types::Address;
+
+/// `OB_OPERATION_HANDLE_CREATE`
+pub const OB_OPERATION_HANDLE_CREATE: u32 = 0x1;
+/// `OB_OPERATION_HANDLE_DUPLICATE`
+pub const OB_OPERATION_HANDLE_DUPLICATE: u32 = 0x2;
+
+/// Object types that support object callbacks and the kernel variables that point to them
+const CALLBACK_OBJECT_TYPES: &[(Win32ObjectCallbackType, &str)] = &[
+    (Win32ObjectCallbackType::Process, "PsProcessType"),
+    (Win32ObjectCallbackType::Thread, "PsThreadType"),
+    (Win32ObjectCallbackType::Desktop, "ExDesktopObjectType"),
+];
+
+/// A routine of a kernel callback
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize))]
+pub struct Win32CallbackRoutine {
+    pub address: Address,
+    /// Module that contains the routine, `None` if it does not belong to any loaded module
+    pub module: Option<Win32ModuleOffset>,
+}
+
+impl Win32CallbackRoutine {
+    fn new(address: Address, modules: &[ModuleInfo]) -> Self {
+        Self {
+            address,
+            module: Win32ModuleOffset::find(modules.iter().cloned(), address),
+        }
+    }
+
+    /// Returns true if the routine is set but does not belong to any loaded module.
+    pub fn is_unbacked(&self) -> bool {
+        !self.address.is_null() && self.module.is_none()
+    }
+}
+
+/// A callback registered with `CmRegisterCallbackEx`
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize))]
+pub struct Win32RegistryCallback {
+    /// Address of the callback context block
+    pub address: Address,
+    /// Cookie returned by the registration, used to unregister the callback
+    pub cookie: u64,
+    pub altitude: String,
+    /// Context that is passed to the routine
+    pub context: Address,
+    pub function: Win32CallbackRoutine,
+}
+
+impl Win32RegistryCallback {
+    /// Returns true if the routine does not belong to a loaded module.
+    pub fn is_suspicious(&self) -> bool {
+        self.function.is_unbacked()
+    }
+}
+
+/// Object type an object callback is registered for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize))]
+pub enum Win32ObjectCallbackType {
+    Process,
+    Thread,
+    Desktop,
+}
+
+/// A callback registered with `ObRegisterCallbacks`
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize))]
+pub struct Win32ObjectCallback {
+    /// Address of the callback entry
+    pub address: Address,
+    pub object_type: Win32ObjectCallbackType,
+    /// Combination of `OB_OPERATION_HANDLE_CREATE` and `OB_OPERATION_HANDLE_DUPLICATE`
+    pub operations: u32,
+    pub enabled: bool,
+    /// Altitude of the registration, empty if it could not be read
+    pub altitude: String,
+    pub pre_operation: Win32CallbackRoutine,
+    pub post_operation: Win32CallbackRoutine,
+}
+
+impl Win32ObjectCallback {
+    /// Returns true if any routine does not belong to a loaded module.
+    pub fn is_suspicious(&self) -> bool {
+        self.pre_operation.is_unbacked() || self.post_operation.is_unbacked()
+    }
+}
+
+impl<T: 'static + PhysicalMemory + Clone, V: 'static + VirtualTranslate2 + Clone>
+    Win32Kernel<T, V>
+{
+    /// Enumerates the registry callbacks.
+    ///
+    /// The kernel pdb is loaded from the given symbol store.
+    pub fn registry_callbacks(
+        &mut self,
+        store: &SymbolStore,
+    ) -> Result<Vec<Win32RegistryCallback>> {
+        let list_head = self
+            .kernel_symbols_from_store(store)?
+            .address("CallbackListHead")?;
+        let modules = self.module_list()?;
+
+        let arch: ArchitectureObj = self.kernel_info.os_info.arch.into();
+        let ptr_size = arch.size_addr();
+        // the cookie is a LARGE_INTEGER that follows the list entry and a ULONG
+        let cookie_offset = 2 * ptr_size + 8;
+        let context_offset = cookie_offset + 8;
+        let function_offset = context_offset + ptr_size;
+        let altitude_offset = function_offset + ptr_size;
+
+        let mut out = vec![];
+        for entry in entries(&mut self.virt_mem, arch, list_head)? {
+            let mut read = || -> Result<Win32RegistryCallback> {
+                let function = self
+                    .virt_mem
+                    .read_addr_arch(arch, entry + function_offset)?;
+                Ok(Win32RegistryCallback {
+                    address: entry,
+                    cookie: self.virt_mem.read(entry + cookie_offset)?,
+                    altitude: self
+                        .virt_mem
+                        .read_unicode_string(arch, entry + altitude_offset)
+                        .unwrap_or_default(),
+                    context: self.virt_mem.read_addr_arch(arch, entry + context_offset)?,
+                    function: Win32CallbackRoutine::new(function, &modules),
+                })
+            };
+            match read() {
+                Ok(callback) => {
+                    trace!(
+                        "registry callback {:x} at altitude {}",
+                        callback.function.address,
+                        callback.altitude
+                    );
+                    out.push(callback)
+                }
+                Err(err) => debug!("unable to read registry callback {:x}: {}", entry, err),
+            }
+        }
+
+        Ok(out)
+    }
+
+    /// Enumerates the object callbacks of the process, thread and desktop object types.
+    ///
+    /// The kernel pdb is loaded from the given symbol store.
+    pub fn object_callbacks(&mut self, store: &SymbolStore) -> Result<Vec<Win32ObjectCallback>> {
+        let symbols = self.kernel_symbols_from_store(store)?;
+        let callback_list = self
+            .kernel_types_from_store(store)?
+            .offset_of("_OBJECT_TYPE.CallbackList")?;
+        let modules = self.module_list()?;
+
+        let arch: ArchitectureObj = self.kernel_info.os_info.arch.into();
+        let ptr_size = arch.size_addr();
+        let operations_offset = 2 * ptr_size;
+        let enabled_offset = operations_offset + 4;
+        let block_offset = operations_offset + 8;
+        let pre_operation_offset = block_offset + 2 * ptr_size;
+        let post_operation_offset = pre_operation_offset + ptr_size;
+        // the altitude of the registration block follows the version, count and context
+        let block_altitude_offset = 2 * ptr_size;
+
+        let mut out = vec![];
+        for &(object_type, symbol) in CALLBACK_OBJECT_TYPES.iter() {
+            // desktop objects support callbacks since nt 10.0
+            let type_ptr = match symbols.address(symbol) {
+                Ok(type_ptr) => type_ptr,
+                Err(_) => continue,
+            };
+            let list_head = self.virt_mem.read_addr_arch(arch, type_ptr)? + callback_list;
+
+            for entry in entries(&mut self.virt_mem, arch, list_head)? {
+                let mut read = || -> Result<Win32ObjectCallback> {
+                    let block = self.virt_mem.read_addr_arch(arch, entry + block_offset)?;
+                    let pre_operation = self
+                        .virt_mem
+                        .read_addr_arch(arch, entry + pre_operation_offset)?;
+                    let post_operation = self
+                        .virt_mem
+                        .read_addr_arch(arch, entry + post_operation_offset)?;
+                    Ok(Win32ObjectCallback {
+                        address: entry,
+                        object_type,
+                        operations: self.virt_mem.read(entry + operations_offset)?,
+                        enabled: self.virt_mem.read::<u32>(entry + enabled_offset)? != 0,
+                        altitude: self
+                            .virt_mem
+                            .read_unicode_string(arch, block + block_altitude_offset)
+                            .unwrap_or_default(),
+                        pre_operation: Win32CallbackRoutine::new(pre_operation, &modules),
+                        post_operation: Win32CallbackRoutine::new(post_operation, &modules),
+                    })
+                };
+                match read() {
+                    Ok(callback) => {
+                        trace!(
+                            "{:?} object callback at altitude {}",
+                            object_type,
+                            callback.altitude
+                        );
+                        out.push(callback)
+                    }
+                    Err(err) => debug!("unable to read object callback {:x}: {}", entry, err),
+                }
+            }
+        }
+
+        Ok(out)
+    }
+}
+
+/// Returns the entries of a list without its head.
+fn entries<M: MemoryView>(
+    mem: &mut M,
+    arch: ArchitectureObj,
+    list_head: Address,
+) -> Result<Vec<Address>> {
+    Ok(Win32ListWalker::new(arch, list_head)
+        .entries(mem)?
+        .into_iter()
+        .filter(|&entry| entry != list_head)
+        .collect())
+}
@@ -1,27 +1,51 @@
-use crate::kernel::{self, StartBlock};
+use std::prelude::v1::*;
+
+use crate::kernel::diagnostics::{ScanReport, ScanStage};
+use crate::kernel::{self, AArch64Layout, NtosScanConfig, StartBlock, Win32VbsInfo};
 use crate::kernel::{Win32Guid, Win32Version};
 
 use log::{info, warn};
 
 use memflow::architecture::ArchitectureIdent;
 use memflow::cglue::forward::ForwardMut;
-use memflow::error::Result;
+use memflow::error::{Error, ErrorKind, ErrorOrigin, Result};
 use memflow::mem::{DirectTranslate, PhysicalMemory, VirtualDma};
 use memflow::os::OsInfo;
-use memflow::types::Address;
+use memflow::types::{size, umem, Address};
+
+use super::{Win32Hypervisor, Win32VirtualTranslate};
 
-use super::Win32VirtualTranslate;
+use crate::offsets::{Win32OffsetBuilder, Win32Offsets};
+use crate::progress::{CancellationToken, Progress};
 
-use crate::offsets::Win32OffsetBuilder;
+#[cfg(feature = "parallel")]
+use std::sync::Arc;
 
 #[derive(Debug, Clone)]
 #[cfg_attr(feature = "serde", derive(::serde::Serialize))]
 pub struct Win32KernelInfo {
     pub os_info: OsInfo,
+    /// Dtb found in the start block (used by winload)
     pub dtb: Address,
+    /// Validated dtb used by the kernel at runtime.
+    ///
+    /// This is the dtb of the system process once the kernel has been initialized
+    /// and falls back to `dtb` if it could not be validated.
+    pub kernel_dtb: Address,
+    /// 5-level paging is enabled
+    pub la57: bool,
 
     pub kernel_guid: Option<Win32Guid>,
     pub kernel_winver: Win32Version,
+    /// The kernel belongs to ReactOS.
+    ///
+    /// ReactOS kernels are not available on the microsoft symbol server and
+    /// do not match the offsets of the windows version they report.
+    pub reactos: bool,
+    /// Virtualization based security state of the kernel
+    pub vbs: Win32VbsInfo,
+    /// Hypervisor the target is running under
+    pub hypervisor: Option<Win32Hypervisor>,
 
     pub eprocess_base: Address,
 }
@@ -49,6 +73,25 @@ impl Win32KernelInfo {
 
         offsets
     }
+
+    /// Derives the most important offsets from the kernel image and the system process.
+    ///
+    /// See [`kernel::heuristics`] for details.
+    pub fn heuristic_offsets<T: PhysicalMemory>(&self, mem: T) -> Result<Win32Offsets> {
+        let mut virt_mem = VirtualDma::with_vat(
+            mem,
+            self.os_info.arch,
+            Win32VirtualTranslate::new(self.os_info.arch, self.kernel_dtb).with_la57(self.la57),
+            DirectTranslate::new(),
+        );
+        kernel::heuristics::find_offsets(
+            &mut virt_mem,
+            self.os_info.arch,
+            self.os_info.base,
+            self.eprocess_base,
+            self.kernel_dtb,
+        )
+    }
 }
 
 pub struct KernelInfoScanner<T> {
@@ -56,6 +99,15 @@ pub struct KernelInfoScanner<T> {
     arch: Option<ArchitectureIdent>,
     kernel_hint: Option<Address>,
     dtb: Option<Address>,
+    la57: Option<bool>,
+    aarch64_layout: AArch64Layout,
+    ntos_scan_config: NtosScanConfig,
+    exhaustive_scan: bool,
+    progress: Option<Progress>,
+    cancellation: Option<CancellationToken>,
+    #[cfg(feature = "parallel")]
+    parallel: Option<ParallelScanner>,
+    report: ScanReport,
 }
 
 impl<T: PhysicalMemory> KernelInfoScanner<T> {
@@ -65,11 +117,43 @@ impl<T: PhysicalMemory> KernelInfoScanner<T> {
             arch: None,
             kernel_hint: None,
             dtb: None,
+            la57: None,
+            aarch64_layout: AArch64Layout::default(),
+            ntos_scan_config: NtosScanConfig::default(),
+            exhaustive_scan: false,
+            progress: None,
+            cancellation: None,
+            #[cfg(feature = "parallel")]
+            parallel: None,
+            report: ScanReport::default(),
+        }
+    }
+
+    /// Scans for the kernel.
+    ///
+    /// If the scan fails the [`ScanReport`] of all stages is logged, see [`KernelInfoScanner::scan_with_report`].
+    pub fn scan(self) -> Result<Win32KernelInfo> {
+        let (result, report) = self.scan_with_report();
+        if result.is_err() {
+            warn!("kernel scan failed:\n{}", report);
         }
+        result
+    }
+
+    /// Scans for the kernel and returns a report of all stages that ran during the scan.
+    ///
+    /// The report is returned regardless of the outcome of the scan.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(name = "kernel_info_scan", skip_all)
+    )]
+    pub fn scan_with_report(mut self) -> (Result<Win32KernelInfo>, ScanReport) {
+        let result = self.scan_stages();
+        (result, self.report)
     }
 
-    pub fn scan(mut self) -> Result<Win32KernelInfo> {
-        let start_block = if let (Some(arch), Some(dtb), Some(kernel_hint)) =
+    fn scan_stages(&mut self) -> Result<Win32KernelInfo> {
+        let mut start_block = if let (Some(arch), Some(dtb), Some(kernel_hint)) =
             (self.arch, self.dtb, self.kernel_hint)
         {
             // construct start block from user supplied hints
@@ -77,45 +161,132 @@ impl<T: PhysicalMemory> KernelInfoScanner<T> {
                 arch,
                 kernel_hint,
                 dtb,
+                la57: false,
             }
         } else {
-            let mut sb = kernel::start_block::find(&mut self.mem, self.arch)?;
+            let sb = kernel::start_block::find(&mut self.mem, self.arch, &self.aarch64_layout);
+            self.report.record(ScanStage::LowStub, None, &sb);
+            let mut sb = match sb {
+                Ok(sb) => sb,
+                Err(err) if self.exhaustive_scan => {
+                    warn!("unable to find start block, scanning all of physical memory");
+                    self.find_exhaustive().map_err(|_| err)?
+                }
+                Err(err) => return Err(err),
+            };
             if self.kernel_hint.is_some() && sb.kernel_hint.is_null() {
                 sb.kernel_hint = self.kernel_hint.unwrap()
             }
             // dtb is always set in start_block::find()
             sb
         };
+        if let Some(la57) = self.la57 {
+            start_block.la57 = la57;
+        }
 
-        self.scan_block(start_block).or_else(|_| {
-            let start_block = kernel::start_block::find_fallback(&mut self.mem, start_block.arch)?;
-            self.scan_block(start_block)
-        })
+        self.scan_block(start_block)
+            .or_else(|_| {
+                let fallback = kernel::start_block::find_fallback(
+                    &mut self.mem,
+                    start_block.arch,
+                    &self.aarch64_layout,
+                );
+                self.report
+                    .record(ScanStage::LowStubFallback, None, &fallback);
+                let mut fallback = fallback?;
+                fallback.la57 = self.la57.unwrap_or(start_block.la57);
+                self.scan_block(fallback)
+            })
+            .or_else(|err| {
+                if !self.exhaustive_scan {
+                    return Err(err);
+                }
+                warn!("unable to find ntoskrnl.exe, scanning all of physical memory for a dtb");
+                self.scan_exhaustive_blocks(start_block.arch, start_block.la57)
+            })
     }
 
+    /// Scans all of physical memory for dtbs until ntoskrnl.exe can be found with one of them.
+    ///
+    /// A rejected candidate does not end the scan, it continues after the rejected dtb.
+    fn scan_exhaustive_blocks(
+        &mut self,
+        arch: ArchitectureIdent,
+        la57: bool,
+    ) -> Result<Win32KernelInfo> {
+        let mut start = Address::null();
+        loop {
+            let mut exhaustive = self.find_exhaustive_arch(arch, start)?;
+            exhaustive.la57 = self.la57.unwrap_or(la57);
+            match self.scan_block(exhaustive) {
+                Ok(kernel_info) => return Ok(kernel_info),
+                Err(err) if self.is_cancelled() => return Err(err),
+                Err(_) => {
+                    info!("rejected dtb candidate {:x}", exhaustive.dtb);
+                    start = Address::from((exhaustive.dtb.to_umem() & !0xfff) + 0x1000);
+                }
+            }
+        }
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.cancellation
+            .as_ref()
+            .map_or(false, |c| c.is_cancelled())
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            skip_all,
+            fields(arch = ?start_block.arch, dtb = %start_block.dtb, kernel_hint = %start_block.kernel_hint)
+        )
+    )]
     fn scan_block(&mut self, start_block: StartBlock) -> Result<Win32KernelInfo> {
         info!(
             "arch={:?} kernel_hint={:x} dtb={:x}",
             start_block.arch, start_block.kernel_hint, start_block.dtb
         );
 
+        // find ntoskrnl.exe base
+        let ntos = self.find_ntos(&start_block);
+
         // construct virtual memory object for start_block
         let mut virt_mem = VirtualDma::with_vat(
             self.mem.forward_mut(),
             start_block.arch,
-            Win32VirtualTranslate::new(start_block.arch, start_block.dtb),
+            Win32VirtualTranslate::new(start_block.arch, start_block.dtb)
+                .with_la57(start_block.la57),
             DirectTranslate::new(),
         );
 
-        // find ntoskrnl.exe base
-        let (base, size) = kernel::ntos::find(&mut virt_mem, &start_block)?;
+        let (base, size) = match ntos {
+            Ok(ntos) => ntos,
+            Err(err) if self.exhaustive_scan => {
+                // the kernel debugger data block contains the kernel base if it is not encoded
+                warn!(
+                    "unable to find ntoskrnl.exe, scanning physical memory for KdDebuggerDataBlock"
+                );
+                let kdbg = kernel::kdbg::find_phys(
+                    virt_mem.phys_mem(),
+                    self.progress.as_ref(),
+                    self.cancellation.as_ref(),
+                );
+                self.report
+                    .record(ScanStage::KdDebuggerData, Some(start_block.dtb), &kdbg);
+                let kdbg = kdbg.map_err(|_| err)?;
+                let size = kernel::ntos::pehelper::try_get_pe_size(&mut virt_mem, kdbg.kern_base)?;
+                (kdbg.kern_base, size)
+            }
+            Err(err) => return Err(err),
+        };
         info!("base={} size={}", base, size);
 
         // get ntoskrnl.exe guid
         let kernel_guid = kernel::ntos::find_guid(&mut virt_mem, base).ok();
         info!("kernel_guid={:?}", kernel_guid);
 
-        let kernel_winver = kernel::ntos::find_winver(&mut virt_mem, base).ok();
+        let kernel_winver = kernel::ntos::find_winver(&mut virt_mem, start_block.arch, base).ok();
 
         if kernel_winver.is_none() {
             warn!("Failed to retrieve kernel version! Some features may be disabled.");
@@ -125,8 +296,33 @@ impl<T: PhysicalMemory> KernelInfoScanner<T> {
 
         info!("kernel_winver={:?}", kernel_winver);
 
+        let reactos = kernel::ntos::is_reactos(&mut virt_mem, base);
+        if reactos {
+            info!("reactos kernel detected, windows offsets and symbols are not used");
+        }
+
+        let module_names = kernel::ntos::find_module_names(&mut virt_mem, start_block.arch, base)
+            .unwrap_or_default();
+
+        let vbs = kernel::vbs::find(&mut virt_mem, start_block.arch, base, &module_names);
+        if vbs.is_active() {
+            warn!("vbs is enabled, pages protected by the hypervisor might read as zeros");
+        }
+
+        // vbs always runs on top of hyper-v
+        let hypervisor =
+            Win32Hypervisor::from_module_names(module_names.iter().map(String::as_str))
+                .or_else(|| vbs.is_active().then_some(Win32Hypervisor::HyperV));
+        info!("hypervisor={:?}", hypervisor);
+
         // find eprocess base
-        let eprocess_base = kernel::sysproc::find(&mut virt_mem, &start_block, base)?;
+        let eprocess_base = kernel::sysproc::find(&mut virt_mem, &start_block, base, size);
+        self.report.record(
+            ScanStage::SystemProcess,
+            Some(start_block.dtb),
+            &eprocess_base,
+        );
+        let eprocess_base = eprocess_base?;
         info!("eprocess_base={:x}", eprocess_base);
 
         // start_block only contains the winload's dtb which might
@@ -138,14 +334,20 @@ impl<T: PhysicalMemory> KernelInfoScanner<T> {
             arch,
             kernel_hint: _,
             dtb,
+            la57,
         } = start_block;
 
         Ok(Win32KernelInfo {
             os_info: OsInfo { base, size, arch },
             dtb,
+            kernel_dtb: dtb,
+            la57,
 
             kernel_guid,
             kernel_winver,
+            reactos,
+            vbs,
+            hypervisor,
 
             eprocess_base,
         })
@@ -165,4 +367,237 @@ impl<T: PhysicalMemory> KernelInfoScanner<T> {
         self.dtb = Some(dtb);
         self
     }
+
+    pub fn la57(mut self, la57: bool) -> Self {
+        self.la57 = Some(la57);
+        self
+    }
+
+    /// Sets the physical memory layout that is used to find the dtb of aarch64 targets.
+    pub fn aarch64_layout(mut self, layout: AArch64Layout) -> Self {
+        self.aarch64_layout = layout;
+        self
+    }
+
+    /// Sets the granularity of the scans for ntoskrnl.exe in the kernel address space.
+    pub fn ntos_scan_config(mut self, config: NtosScanConfig) -> Self {
+        self.ntos_scan_config = config;
+        self
+    }
+
+    /// Enables scanning all of physical memory for a dtb if it cannot be found in the low stub.
+    ///
+    /// This also enables the scan for a kernel with wiped pe headers, see [`NtosScanConfig::headerless`].
+    pub fn exhaustive_scan(mut self, exhaustive_scan: bool) -> Self {
+        self.exhaustive_scan = exhaustive_scan;
+        self
+    }
+
+    /// Reports the progress of the scans over physical memory and the kernel address space.
+    pub fn progress(mut self, progress: Progress) -> Self {
+        self.progress = Some(progress);
+        self
+    }
+
+    /// Aborts the scans once the token has been cancelled.
+    pub fn cancellation(mut self, cancellation: CancellationToken) -> Self {
+        self.cancellation = Some(cancellation);
+        self
+    }
+
+    /// Distributes the scans over the kernel address space and physical memory over multiple threads.
+    ///
+    /// See [`ParallelScanner`] for details.
+    #[cfg(feature = "parallel")]
+    pub fn parallel(mut self, parallel: ParallelScanner) -> Self {
+        self.parallel = Some(parallel);
+        self
+    }
+
+    fn find_ntos(&mut self, start_block: &StartBlock) -> Result<(Address, umem)> {
+        let config = NtosScanConfig {
+            headerless: self.ntos_scan_config.headerless || self.exhaustive_scan,
+            ..self.ntos_scan_config
+        };
+
+        #[cfg(feature = "parallel")]
+        if let Some(parallel) = &self.parallel {
+            return parallel.0.find_ntos(
+                start_block,
+                &config,
+                self.progress.as_ref(),
+                self.cancellation.as_ref(),
+                &mut self.report,
+            );
+        }
+
+        let mut virt_mem = VirtualDma::with_vat(
+            self.mem.forward_mut(),
+            start_block.arch,
+            Win32VirtualTranslate::new(start_block.arch, start_block.dtb)
+                .with_la57(start_block.la57),
+            DirectTranslate::new(),
+        );
+        kernel::ntos::find_with_report(
+            &mut virt_mem,
+            start_block,
+            &config,
+            self.progress.as_ref(),
+            self.cancellation.as_ref(),
+            &mut self.report,
+        )
+    }
+
+    fn find_exhaustive_arch(
+        &mut self,
+        arch: ArchitectureIdent,
+        start: Address,
+    ) -> Result<StartBlock> {
+        #[cfg(feature = "parallel")]
+        let result = match &self.parallel {
+            Some(parallel) => parallel.0.find_exhaustive(
+                arch,
+                &self.aarch64_layout,
+                start,
+                self.progress.as_ref(),
+                self.cancellation.as_ref(),
+            ),
+            None => kernel::start_block::find_exhaustive_from(
+                &mut self.mem,
+                arch,
+                &self.aarch64_layout,
+                start,
+                self.progress.as_ref(),
+                self.cancellation.as_ref(),
+            ),
+        };
+        #[cfg(not(feature = "parallel"))]
+        let result = kernel::start_block::find_exhaustive_from(
+            &mut self.mem,
+            arch,
+            &self.aarch64_layout,
+            start,
+            self.progress.as_ref(),
+            self.cancellation.as_ref(),
+        );
+
+        self.report.record(ScanStage::Exhaustive, None, &result);
+        result
+    }
+
+    fn find_exhaustive(&mut self) -> Result<StartBlock> {
+        let archs = match self.arch {
+            Some(arch) => vec![arch],
+            None => vec![
+                ArchitectureIdent::X86(64, false),
+                ArchitectureIdent::X86(32, true),
+                ArchitectureIdent::X86(32, false),
+                ArchitectureIdent::AArch64(size::kb(4)),
+            ],
+        };
+
+        for arch in archs {
+            match self.find_exhaustive_arch(arch, Address::null()) {
+                Ok(sb) => return Ok(sb),
+                Err(err) if self.is_cancelled() => return Err(err),
+                Err(_) => {}
+            }
+        }
+
+        Err(Error(ErrorOrigin::OsLayer, ErrorKind::NotFound)
+            .log_error("unable to find dtb in physical memory"))
+    }
+}
+
+/// Clone of a connector that is used to distribute scans over the threads of the rayon thread pool.
+///
+/// Every thread reads through its own clone of the connector, so the connector has to
+/// support concurrent access through clones.
+/// The connector has to be a clone of the one that is passed to the scanner.
+#[cfg(feature = "parallel")]
+#[derive(Clone)]
+pub struct ParallelScanner(Arc<dyn ParallelScan>);
+
+#[cfg(feature = "parallel")]
+impl ParallelScanner {
+    pub fn new<T: 'static + PhysicalMemory + Clone + Send>(mem: T) -> Self {
+        Self(Arc::new(ParallelMem(mem)))
+    }
+}
+
+#[cfg(feature = "parallel")]
+impl core::fmt::Debug for ParallelScanner {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str("ParallelScanner")
+    }
+}
+
+#[cfg(feature = "parallel")]
+trait ParallelScan {
+    fn find_ntos(
+        &self,
+        start_block: &StartBlock,
+        config: &NtosScanConfig,
+        progress: Option<&Progress>,
+        cancellation: Option<&CancellationToken>,
+        report: &mut ScanReport,
+    ) -> Result<(Address, umem)>;
+
+    fn find_exhaustive(
+        &self,
+        arch: ArchitectureIdent,
+        layout: &AArch64Layout,
+        start: Address,
+        progress: Option<&Progress>,
+        cancellation: Option<&CancellationToken>,
+    ) -> Result<StartBlock>;
+}
+
+#[cfg(feature = "parallel")]
+struct ParallelMem<T>(T);
+
+#[cfg(feature = "parallel")]
+impl<T: PhysicalMemory + Clone + Send> ParallelScan for ParallelMem<T> {
+    fn find_ntos(
+        &self,
+        start_block: &StartBlock,
+        config: &NtosScanConfig,
+        progress: Option<&Progress>,
+        cancellation: Option<&CancellationToken>,
+        report: &mut ScanReport,
+    ) -> Result<(Address, umem)> {
+        let mut virt_mem = VirtualDma::with_vat(
+            self.0.clone(),
+            start_block.arch,
+            Win32VirtualTranslate::new(start_block.arch, start_block.dtb)
+                .with_la57(start_block.la57),
+            DirectTranslate::new(),
+        );
+        kernel::ntos::find_parallel(
+            &mut virt_mem,
+            start_block,
+            config,
+            progress,
+            cancellation,
+            report,
+        )
+    }
+
+    fn find_exhaustive(
+        &self,
+        arch: ArchitectureIdent,
+        layout: &AArch64Layout,
+        start: Address,
+        progress: Option<&Progress>,
+        cancellation: Option<&CancellationToken>,
+    ) -> Result<StartBlock> {
+        kernel::start_block::find_exhaustive_parallel_from(
+            &mut self.0.clone(),
+            arch,
+            layout,
+            start,
+            progress,
+            cancellation,
+        )
+    }
 }
@@ -0,0 +1,482 @@
+/*!
+Module for reading and walking the kernel stacks of threads.
+
+The bounds of a kernel stack are taken from the `_KTHREAD` of the thread. The walk starts at the
+saved stack pointer (`KernelStack`) and is conservative: every stack slot that points into an
+executable section of a kernel module right behind a call instruction is treated as a return
+address. On x64 the unwind information of the function containing a return address is used to
+skip directly to the next frame, this avoids most of the stale return addresses that a plain scan
+reports. Functions that use a frame pointer or machine frames fall back to scanning.
+
+The saved stack pointer is only valid for threads that are not running,
+the stacks of running threads are stale.
+
+Frames are symbolized by the exports of the modules. If a symbol store is set the pdbs of the
+kernel and the drivers are used instead, this resolves internal functions as well.
+
+# Examples:
+
+```
+use memflow::prelude::v1::*;
+use memflow_win32::win32::{Win32Kernel, Win32StackWalker};
+
+fn test<T: 'static + PhysicalMemory + Clone, V: 'static + VirtualTranslate2 + Clone>(
+    kernel: &mut Win32Kernel<T, V>,
+) {
+    let system = kernel.kernel_process_info().unwrap();
+    let mut walker = kernel.kernel_stack_walker().unwrap();
+    for ethread in kernel.thread_address_list(system.base_info.address).unwrap() {
+        let stack = kernel.kernel_stack(ethread).unwrap();
+        println!("thread {:x}:", ethread);
+        for frame in walker.walk(kernel, &stack).unwrap() {
+            println!("  {:x} {}", frame.return_address, frame.symbol.unwrap_or_default());
+        }
+    }
+}
+```
+*/
+use std::prelude::v1::*;
+
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+
+use super::Win32Kernel;
+#[cfg(feature = "symstore")]
+use super::Win32ModuleInfo;
+use crate::kernel::ntos::lazy_pe::LazyPe;
+#[cfg(feature = "symstore")]
+use crate::offsets::{PdbSymbols, SymbolStore};
+
+use log::{debug, trace};
+
+use memflow::architecture::ArchitectureObj;
+use memflow::error::{Error, ErrorKind, ErrorOrigin, PartialResultExt, Result};
+use memflow::mem::{MemoryView, PhysicalMemory, VirtualTranslate2};
+use memflow::os::{ModuleInfo, Os};
+use memflow::types::{umem, Address};
+
+use pelite::image::IMAGE_SCN_MEM_EXECUTE;
+
+/// Upper bound for the size of a kernel stack that is walked
+const MAX_KERNEL_STACK_SIZE: umem = 0x40000;
+/// Upper bound for the number of chained unwind infos
+const MAX_UNWIND_CHAIN: usize = 8;
+
+const UNW_FLAG_CHAININFO: u8 = 0x4;
+
+const UWOP_PUSH_NONVOL: u8 = 0;
+const UWOP_ALLOC_LARGE: u8 = 1;
+const UWOP_ALLOC_SMALL: u8 = 2;
+const UWOP_SET_FPREG: u8 = 3;
+const UWOP_SAVE_NONVOL: u8 = 4;
+const UWOP_SAVE_NONVOL_FAR: u8 = 5;
+const UWOP_SAVE_XMM128: u8 = 8;
+const UWOP_SAVE_XMM128_FAR: u8 = 9;
+
+/// Bounds of the kernel stack of a thread
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize))]
+pub struct Win32KernelStack {
+    /// Top of the stack without the initial trap frame and floating point save area
+    pub initial_stack: Address,
+    /// Lowest address of the stack
+    pub stack_limit: Address,
+    /// Highest address of the stack
+    pub stack_base: Address,
+    /// Stack pointer saved on the last context switch
+    pub kernel_stack: Address,
+}
+
+impl Win32KernelStack {
+    /// Returns true if the address is part of the stack.
+    pub fn contains(&self, address: Address) -> bool {
+        address >= self.stack_limit && address < self.stack_base
+    }
+}
+
+/// A single frame of a call stack
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize))]
+pub struct Win32StackFrame {
+    /// Address of the stack slot that holds the return address
+    pub stack_address: Address,
+    pub return_address: Address,
+    /// Name of the module that contains the return address
+    pub module: String,
+    /// Offset of the return address from the base of the module
+    pub offset: umem,
+    /// Symbol and offset, e.g. `KeWaitForSingleObject+0x1a2`
+    pub symbol: Option<String>,
+    /// The frame was found by scanning and not through unwind information
+    pub scanned: bool,
+}
+
+impl<T: 'static + PhysicalMemory + Clone, V: 'static + VirtualTranslate2 + Clone>
+    Win32Kernel<T, V>
+{
+    /// Reads the kernel stack bounds of a thread.
+    pub fn kernel_stack(&mut self, ethread: Address) -> Result<Win32KernelStack> {
+        if self.offsets.kthread_stack_limit() == 0 || self.offsets.kthread_kernel_stack() == 0 {
+            return Err(Error(ErrorOrigin::OsLayer, ErrorKind::NotSupported)
+                .log_info("kthread stack offsets are not available for this kernel"));
+        }
+
+        let arch = self.kernel_info.os_info.arch.into();
+        let mut read = |offset: usize| -> Result<Address> {
+            if offset == 0 {
+                return Ok(Address::null());
+            }
+            self.virt_mem.read_addr_arch(arch, ethread + offset)
+        };
+
+        let initial_stack = read(self.offsets.kthread_initial_stack())?;
+        let stack_limit = read(self.offsets.kthread_stack_limit())?;
+        let kernel_stack = read(self.offsets.kthread_kernel_stack())?;
+        // StackBase does not exist prior to nt 5.1, the initial stack is used instead
+        let stack_base = match read(self.offsets.kthread_stack_base())? {
+            base if base.is_null() => initial_stack,
+            base => base,
+        };
+
+        Ok(Win32KernelStack {
+            initial_stack,
+            stack_limit,
+            stack_base,
+            kernel_stack,
+        })
+    }
+
+    /// Creates a stack walker for the currently loaded kernel modules.
+    pub fn kernel_stack_walker(&mut self) -> Result<Win32StackWalker> {
+        let modules = self.module_list()?;
+        Ok(Win32StackWalker::new(
+            self.kernel_info.os_info.arch.into(),
+            modules,
+        ))
+    }
+
+    /// Walks the kernel stack of a thread and symbolizes it with the exports of the kernel modules.
+    ///
+    /// Use [`Win32Kernel::kernel_stack_walker`] to walk the stacks of multiple threads.
+    pub fn kernel_call_stack(&mut self, ethread: Address) -> Result<Vec<Win32StackFrame>> {
+        let stack = self.kernel_stack(ethread)?;
+        self.kernel_stack_walker()?.walk(&mut self.virt_mem, &stack)
+    }
+}
+
+/// Information about a module that is cached between walks
+#[derive(Default)]
+struct ModuleData {
+    is_64: bool,
+    /// Rva and size of all executable sections
+    code: Vec<(u32, u32)>,
+    /// Raw `RUNTIME_FUNCTION` entries
+    runtime_functions: Vec<u8>,
+    /// Symbols sorted by their rva
+    symbols: Option<Vec<(u32, String)>>,
+}
+
+/// Walks kernel stacks and caches the module information that is required to do so.
+pub struct Win32StackWalker {
+    arch: ArchitectureObj,
+    modules: Vec<ModuleInfo>,
+    data: BTreeMap<Address, ModuleData>,
+    #[cfg(feature = "symstore")]
+    symbol_store: Option<SymbolStore>,
+}
+
+impl Win32StackWalker {
+    /// Creates a stack walker for the given kernel modules.
+    pub fn new(arch: ArchitectureObj, modules: Vec<ModuleInfo>) -> Self {
+        Self {
+            arch,
+            modules,
+            data: BTreeMap::new(),
+            #[cfg(feature = "symstore")]
+            symbol_store: None,
+        }
+    }
+
+    /// Symbolizes frames with the pdbs from the given symbol store instead of the exports.
+    #[cfg(feature = "symstore")]
+    pub fn symbol_store(mut self, symbol_store: SymbolStore) -> Self {
+        self.symbol_store = Some(symbol_store);
+        self
+    }
+
+    /// Walks the stack from the saved stack pointer up to the stack base.
+    pub fn walk<M: MemoryView>(
+        &mut self,
+        mem: &mut M,
+        stack: &Win32KernelStack,
+    ) -> Result<Vec<Win32StackFrame>> {
+        if !stack.contains(stack.kernel_stack) {
+            return Err(Error(ErrorOrigin::OsLayer, ErrorKind::InvalidMemory)
+                .log_debug("saved stack pointer is outside of the stack bounds"));
+        }
+
+        let start = stack.kernel_stack;
+        let size = (stack.stack_base - start).min(MAX_KERNEL_STACK_SIZE) as usize;
+        let mut buf = vec![0u8; size];
+        mem.read_raw_into(start, &mut buf).data_part()?;
+
+        let ptr_size = self.arch.size_addr();
+        let read_slot = |pos: usize| -> Option<Address> {
+            let slot = buf.get(pos..pos + ptr_size)?;
+            Some(match ptr_size {
+                8 => Address::from(u64::from_le_bytes(slot.try_into().unwrap())),
+                _ => Address::from(u32::from_le_bytes(slot.try_into().unwrap())),
+            })
+        };
+
+        let mut frames = vec![];
+        let mut pos = 0;
+        let mut unwound = false;
+        while let Some(value) = read_slot(pos) {
+            let module = match self.return_address_module(mem, value) {
+                Some(module) => module,
+                None => {
+                    unwound = false;
+                    pos += ptr_size;
+                    continue;
+                }
+            };
+
+            let offset = value - module.base;
+            frames.push(Win32StackFrame {
+                stack_address: start + pos as umem,
+                return_address: value,
+                module: module.name.to_string(),
+                offset,
+                symbol: self.symbolize(mem, &module, offset as u32),
+                scanned: !unwound,
+            });
+
+            // continue with the frame of the caller if the frame size is known
+            match self.frame_size(mem, &module, offset as u32) {
+                Some(frame_size) => {
+                    trace!("frame of {:x} has a size of {:x}", value, frame_size);
+                    pos += ptr_size + frame_size as usize;
+                    unwound = true;
+                }
+                None => {
+                    pos += ptr_size;
+                    unwound = false;
+                }
+            }
+        }
+
+        debug!("found {} frames on stack {:x}", frames.len(), start);
+        Ok(frames)
+    }
+
+    /// Returns the module if `address` is a plausible return address.
+    fn return_address_module<M: MemoryView>(
+        &mut self,
+        mem: &mut M,
+        address: Address,
+    ) -> Option<ModuleInfo> {
+        let module = self
+            .modules
+            .iter()
+            .find(|m| address >= m.base && address < m.base + m.size)?
+            .clone();
+
+        let rva = (address - module.base) as u32;
+        let data = self.module_data(mem, &module);
+        if !data
+            .code
+            .iter()
+            .any(|&(start, size)| rva >= start && rva - start < size)
+        {
+            return None;
+        }
+
+        let mut code = [0u8; 8];
+        mem.read_raw_into(address - code.len(), &mut code)
+            .data_part()
+            .ok()?;
+        if follows_call(&code) {
+            Some(module)
+        } else {
+            None
+        }
+    }
+
+    fn module_data<M: MemoryView>(&mut self, mem: &mut M, module: &ModuleInfo) -> &mut ModuleData {
+        self.data.entry(module.base).or_insert_with(|| {
+            let mut pe = match LazyPe::new(mem, module.base) {
+                Ok(pe) => pe,
+                Err(_) => return ModuleData::default(),
+            };
+
+            let code = pe
+                .sections()
+                .filter(|(_, _, characteristics)| characteristics & IMAGE_SCN_MEM_EXECUTE != 0)
+                .map(|(rva, size, _)| (rva, size))
+                .collect();
+            let is_64 = pe.is_64();
+            let runtime_functions = if is_64 {
+                pe.exception_directory().unwrap_or_default()
+            } else {
+                vec![]
+            };
+
+            ModuleData {
+                is_64,
+                code,
+                runtime_functions,
+                symbols: None,
+            }
+        })
+    }
+
+    /// Returns the size of the stack frame of the function containing `rva`.
+    ///
+    /// The size does not include the return address of the function.
+    fn frame_size<M: MemoryView>(
+        &mut self,
+        mem: &mut M,
+        module: &ModuleInfo,
+        rva: u32,
+    ) -> Option<u32> {
+        let data = self.module_data(mem, module);
+        if !data.is_64 {
+            return None;
+        }
+
+        // the runtime functions are sorted by their start address
+        let functions = &data.runtime_functions;
+        let count = functions.len() / 12;
+        let entry = |i: usize| {
+            (
+                read_u32(functions, i * 12).unwrap(),
+                read_u32(functions, i * 12 + 4).unwrap(),
+                read_u32(functions, i * 12 + 8).unwrap(),
+            )
+        };
+        let (mut low, mut high) = (0, count);
+        while low < high {
+            let mid = (low + high) / 2;
+            if entry(mid).0 <= rva {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+        // return addresses may point right behind the last instruction of a function
+        let (begin, end, mut unwind_info) = entry(low.checked_sub(1)?);
+        if rva <= begin || rva > end {
+            return None;
+        }
+
+        let mut size = 0;
+        for _ in 0..MAX_UNWIND_CHAIN {
+            let mut pe = LazyPe::new(mem, module.base).ok()?;
+            let header = pe.read_rva(unwind_info, 4).ok()?;
+            let (flags, count) = (header[0] >> 3, header[2] as usize);
+            let codes = pe.read_rva(unwind_info + 4, count * 2).ok()?;
+
+            let mut i = 0;
+            while i < count {
+                let (op, op_info) = (codes[i * 2 + 1] & 0xf, codes[i * 2 + 1] >> 4);
+                let slot = |n: usize| read_u16(&codes, (i + n) * 2).map(|v| v as u32);
+                match op {
+                    UWOP_PUSH_NONVOL => size += 8,
+                    UWOP_ALLOC_LARGE if op_info == 0 => {
+                        size += slot(1)? * 8;
+                        i += 1;
+                    }
+                    UWOP_ALLOC_LARGE => {
+                        size += slot(1)? | (slot(2)? << 16);
+                        i += 2;
+                    }
+                    UWOP_ALLOC_SMALL => size += op_info as u32 * 8 + 8,
+                    UWOP_SAVE_NONVOL | UWOP_SAVE_XMM128 => i += 1,
+                    UWOP_SAVE_NONVOL_FAR | UWOP_SAVE_XMM128_FAR => i += 2,
+                    // frame pointers and machine frames cannot be unwound without the registers
+                    UWOP_SET_FPREG => return None,
+                    _ => return None,
+                }
+                i += 1;
+            }
+
+            if flags & UNW_FLAG_CHAININFO == 0 {
+                return Some(size);
+            }
+
+            // the chained runtime function follows the aligned unwind codes
+            let chained = unwind_info + 4 + ((count + 1) & !1) as u32 * 2;
+            unwind_info = read_u32(&pe.read_rva(chained, 12).ok()?, 8)?;
+        }
+
+        None
+    }
+
+    /// Returns the symbol and offset of an rva in a module.
+    fn symbolize<M: MemoryView>(
+        &mut self,
+        mem: &mut M,
+        module: &ModuleInfo,
+        rva: u32,
+    ) -> Option<String> {
+        if self.module_data(mem, module).symbols.is_none() {
+            #[cfg(feature = "symstore")]
+            let pdb_symbols = self.symbol_store.as_ref().and_then(|store| {
+                let pdb = module.pdb(mem, store).ok()?;
+                let symbols = PdbSymbols::new(&pdb).ok()?;
+                Some(
+                    symbols
+                        .symbols()
+                        .map(|(name, rva)| (rva, name.to_string()))
+                        .collect::<Vec<_>>(),
+                )
+            });
+            #[cfg(not(feature = "symstore"))]
+            let pdb_symbols = None;
+
+            let mut symbols = pdb_symbols.unwrap_or_else(|| {
+                LazyPe::new(mem, module.base)
+                    .and_then(|mut pe| pe.exports())
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|(name, rva, _)| (rva, name))
+                    .collect()
+            });
+            symbols.sort_unstable();
+            self.module_data(mem, module).symbols = Some(symbols);
+        }
+
+        let symbols = self.module_data(mem, module).symbols.as_ref()?;
+        let (symbol_rva, name) =
+            &symbols[symbols.partition_point(|(s, _)| *s <= rva).checked_sub(1)?];
+        Some(format!("{}+{:#x}", name, rva - symbol_rva))
+    }
+}
+
+/// Returns true if the code right before a return address ends with a call instruction.
+fn follows_call(code: &[u8; 8]) -> bool {
+    let b = |back: usize| code[code.len() - back];
+    // call rel32
+    b(5) == 0xe8
+        // call [rip+disp32] / call [abs32]
+        || (b(6) == 0xff && b(5) == 0x15)
+        // call reg / call [reg]
+        || (b(2) == 0xff
+            && b(1) & 0x38 == 0x10
+            && (b(1) >= 0xc0 || (b(1) < 0x40 && b(1) & 0x7 != 4 && b(1) & 0x7 != 5)))
+        // call [reg+disp8]
+        || (b(3) == 0xff && b(2) & 0xf8 == 0x50)
+        // call [reg+disp32]
+        || (b(6) == 0xff && b(5) & 0xf8 == 0x90)
+}
+
+fn read_u16(buf: &[u8], offset: usize) -> Option<u16> {
+    buf.get(offset..offset + 2)
+        .map(|b| u16::from_le_bytes(b.try_into().unwrap()))
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> Option<u32> {
+    buf.get(offset..offset + 4)
+        .map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+}
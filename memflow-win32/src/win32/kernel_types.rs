@@ -0,0 +1,263 @@
+/*!
+Module for querying the type information and symbols of the running kernel at runtime.
+
+The pdb of the kernel is loaded from the symbol store of the kernel and arbitrary structures and fields
+can be resolved by name. Nested fields are separated by dots (e.g. `_EPROCESS.Vm.WorkingSetSize`).
+This allows reading fields that are not part of the [`Win32Offsets`](crate::offsets::Win32Offsets).
+
+Public and global symbols of the kernel are resolved to virtual addresses by adding the kernel base.
+
+Only fields of embedded structures can be resolved, pointers have to be dereferenced
+by the caller and the lookup has to continue at the type of the pointee.
+
+This module is only available with the `symstore` feature enabled.
+
+# Examples:
+
+```
+use memflow::prelude::v1::*;
+use memflow_win32::win32::Win32Kernel;
+
+fn test<T: 'static + PhysicalMemory + Clone, V: 'static + VirtualTranslate2 + Clone>(
+    kernel: &mut Win32Kernel<T, V>,
+) {
+    let mut types = kernel.kernel_types().unwrap();
+    let field = types.field("_EPROCESS.Vm.WorkingSetSize").unwrap();
+    println!("{} at {:#x}", field.type_name, field.offset);
+
+    let eprocess = kernel.kernel_info.eprocess_base;
+    let working_set_size: u64 = kernel.virt_mem.read(eprocess + field.offset).unwrap();
+    println!("working set size: {}", working_set_size);
+
+    let addr = kernel.kernel_symbol("PsActiveProcessHead").unwrap();
+    println!("PsActiveProcessHead: {:x}", addr);
+}
+```
+*/
+use std::prelude::v1::*;
+
+use super::Win32Kernel;
+use crate::offsets::{PdbStruct, PdbSymbols, SymbolStore};
+
+use memflow::error::{Error, ErrorKind, ErrorOrigin, Result};
+use memflow::mem::{PhysicalMemory, VirtualTranslate2};
+use memflow::types::{umem, Address};
+
+use log::debug;
+use std::collections::HashMap;
+
+/// A field resolved by [`Win32KernelTypes::field`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize))]
+pub struct Win32TypeField {
+    /// Name of the type of the field as emitted by the pdb parser
+    pub type_name: String,
+    /// Offset of the field relative to the start of the outermost structure
+    pub offset: usize,
+    pub bit_offset: usize,
+    /// Length of the bitfield, 0 if the field is not a bitfield
+    pub bit_length: usize,
+}
+
+/// Queryable type information of a kernel
+pub struct Win32KernelTypes {
+    pdb: Vec<u8>,
+    structs: HashMap<String, PdbStruct>,
+}
+
+impl Win32KernelTypes {
+    /// Creates the type information from the contents of a pdb file.
+    pub fn new(pdb: Vec<u8>) -> Self {
+        Self {
+            pdb,
+            structs: HashMap::new(),
+        }
+    }
+
+    /// Returns true if the pdb contains the given structure.
+    pub fn has_struct(&mut self, name: &str) -> bool {
+        self.find_struct(name).is_ok()
+    }
+
+    /// Returns the size of a structure in bytes.
+    pub fn struct_size(&mut self, name: &str) -> Result<usize> {
+        Ok(self.find_struct(name)?.size())
+    }
+
+    /// Returns all fields declared directly in a structure.
+    pub fn fields(&mut self, name: &str) -> Result<Vec<(String, Win32TypeField)>> {
+        Ok(self
+            .find_struct(name)?
+            .fields()
+            .map(|(field_name, f)| {
+                (
+                    field_name.to_string(),
+                    Win32TypeField {
+                        type_name: f.type_name.clone(),
+                        offset: f.offset,
+                        bit_offset: f.bit_offset,
+                        bit_length: f.bit_length,
+                    },
+                )
+            })
+            .collect())
+    }
+
+    /// Resolves a dot separated field path starting at a structure (e.g. `_EPROCESS.Vm.WorkingSetSize`).
+    pub fn field(&mut self, path: &str) -> Result<Win32TypeField> {
+        let mut parts = path.split('.');
+        let mut type_name = parts.next().unwrap_or_default().to_string();
+
+        let mut field: Option<Win32TypeField> = None;
+        for field_name in parts {
+            if type_name.ends_with('*') {
+                return Err(Error(ErrorOrigin::OsLayer, ErrorKind::InvalidArgument)
+                    .log_info(format!("{} is a pointer and cannot be followed", type_name)));
+            }
+
+            let offset = field.as_ref().map_or(0, |f| f.offset);
+            let pdb_field = self
+                .find_struct(&type_name)?
+                .fields()
+                .find(|(name, _)| *name == field_name)
+                .map(|(_, f)| f.clone())
+                .ok_or_else(|| {
+                    Error(ErrorOrigin::OsLayer, ErrorKind::NotFound)
+                        .log_info(format!("{}::{} not found", type_name, field_name))
+                })?;
+
+            type_name = pdb_field.type_name.clone();
+            field = Some(Win32TypeField {
+                type_name: pdb_field.type_name,
+                offset: offset + pdb_field.offset,
+                bit_offset: pdb_field.bit_offset,
+                bit_length: pdb_field.bit_length,
+            });
+        }
+
+        field.ok_or_else(|| {
+            Error(ErrorOrigin::OsLayer, ErrorKind::InvalidArgument)
+                .log_info(format!("{} does not contain a field", path))
+        })
+    }
+
+    /// Resolves the offset of a dot separated field path.
+    ///
+    /// See [`Win32KernelTypes::field`] for details.
+    pub fn offset_of(&mut self, path: &str) -> Result<usize> {
+        Ok(self.field(path)?.offset)
+    }
+
+    fn find_struct(&mut self, name: &str) -> Result<&PdbStruct> {
+        if !self.structs.contains_key(name) {
+            debug!("parsing {} from kernel pdb", name);
+            let ty = PdbStruct::new(&self.pdb, name).map_err(|_| {
+                Error(ErrorOrigin::OsLayer, ErrorKind::InvalidExeFile)
+                    .log_warn("unable to parse kernel pdb")
+            })?;
+            if ty.size() == 0 {
+                return Err(Error(ErrorOrigin::OsLayer, ErrorKind::NotFound)
+                    .log_info(format!("{} not found", name)));
+            }
+            self.structs.insert(name.to_string(), ty);
+        }
+        Ok(&self.structs[name])
+    }
+}
+
+/// Symbols of a kernel resolved to virtual addresses
+pub struct Win32KernelSymbols {
+    symbols: PdbSymbols,
+    base: Address,
+}
+
+impl Win32KernelSymbols {
+    /// Creates the symbol table from the contents of a pdb file and the base of the kernel image.
+    pub fn new(pdb: &[u8], base: Address) -> Result<Self> {
+        let symbols = PdbSymbols::new(pdb).map_err(|_| {
+            Error(ErrorOrigin::OsLayer, ErrorKind::InvalidExeFile)
+                .log_warn("unable to parse symbols of kernel pdb")
+        })?;
+        Ok(Self { symbols, base })
+    }
+
+    /// Resolves a symbol to its virtual address.
+    ///
+    /// Decorated names of 32 bit kernels are matched by their undecorated name as well.
+    pub fn address(&self, name: &str) -> Result<Address> {
+        self.symbols
+            .find_symbol_undecorated(name)
+            .map(|rva| self.base + rva as umem)
+            .ok_or_else(|| {
+                Error(ErrorOrigin::OsLayer, ErrorKind::NotFound)
+                    .log_info(format!("symbol {} not found", name))
+            })
+    }
+
+    /// Returns the names and addresses of all symbols.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, Address)> {
+        let base = self.base;
+        self.symbols
+            .symbols()
+            .map(move |(name, rva)| (name, base + rva as umem))
+    }
+
+    /// Returns the names and addresses of all symbols starting with the given prefix.
+    pub fn with_prefix<'a>(
+        &'a self,
+        prefix: &'a str,
+    ) -> impl Iterator<Item = (&'a str, Address)> + 'a {
+        let base = self.base;
+        self.symbols
+            .find_symbols_with_prefix(prefix)
+            .map(move |(name, rva)| (name, base + rva as umem))
+    }
+
+    /// Returns the symbol preceding the given address and the offset of the address to it.
+    pub fn nearest(&self, addr: Address) -> Option<(&str, umem)> {
+        self.iter()
+            .filter(|(_, a)| *a <= addr)
+            .max_by_key(|(_, a)| *a)
+            .map(|(name, a)| (name, addr.to_umem() - a.to_umem()))
+    }
+}
+
+impl<T: 'static + PhysicalMemory + Clone, V: 'static + VirtualTranslate2 + Clone>
+    Win32Kernel<T, V>
+{
+    /// Loads the type information of the kernel from the symbol store of the kernel.
+    pub fn kernel_types(&self) -> Result<Win32KernelTypes> {
+        self.kernel_types_from_store(&self.configured_symbol_store()?)
+    }
+
+    /// Loads the type information of the kernel from the given symbol store.
+    pub fn kernel_types_from_store(&self, store: &SymbolStore) -> Result<Win32KernelTypes> {
+        let guid = self.kernel_info.kernel_guid.as_ref().ok_or_else(|| {
+            Error(ErrorOrigin::OsLayer, ErrorKind::NotFound)
+                .log_error("kernel guid is required to load the kernel pdb")
+        })?;
+        Ok(Win32KernelTypes::new(store.load(guid)?))
+    }
+
+    /// Loads the symbols of the kernel from the symbol store of the kernel.
+    pub fn kernel_symbols(&self) -> Result<Win32KernelSymbols> {
+        self.kernel_symbols_from_store(&self.configured_symbol_store()?)
+    }
+
+    /// Loads the symbols of the kernel from the given symbol store.
+    pub fn kernel_symbols_from_store(&self, store: &SymbolStore) -> Result<Win32KernelSymbols> {
+        let guid = self.kernel_info.kernel_guid.as_ref().ok_or_else(|| {
+            Error(ErrorOrigin::OsLayer, ErrorKind::NotFound)
+                .log_error("kernel guid is required to load the kernel pdb")
+        })?;
+        Win32KernelSymbols::new(&store.load(guid)?, self.kernel_info.os_info.base)
+    }
+
+    /// Resolves a kernel symbol to its virtual address.
+    ///
+    /// The symbols are loaded from the symbol store of the kernel on every call,
+    /// use [`Win32Kernel::kernel_symbols`] to resolve multiple symbols.
+    pub fn kernel_symbol(&self, name: &str) -> Result<Address> {
+        self.kernel_symbols()?.address(name)
+    }
+}
@@ -0,0 +1,226 @@
+/*!
+Bounded walking of `_LIST_ENTRY` based lists.
+
+Kernel lists can be corrupted while the target modifies them or be manipulated on purpose
+(e.g. by a rootkit unlinking its process). Every walk is bounded by a maximum number of entries
+and detects cycles that do not pass through the start of the list, so a broken list can neither
+hang the caller nor exhaust its memory.
+*/
+use std::prelude::v1::*;
+
+use log::warn;
+
+use memflow::architecture::ArchitectureObj;
+use memflow::error::Result;
+use memflow::mem::MemoryView;
+use memflow::types::{umem, Address};
+
+/// Default upper bound for the number of entries of a list
+pub const DEFAULT_MAX_LIST_ENTRIES: usize = 65536;
+
+/// Walker over the `Flink` pointers of a `_LIST_ENTRY` based list
+#[derive(Clone, Copy)]
+pub struct Win32ListWalker {
+    arch: ArchitectureObj,
+    start: Address,
+    max_entries: usize,
+    alignment: umem,
+}
+
+impl Win32ListWalker {
+    /// Creates a walker that starts at the given list entry.
+    pub fn new(arch: impl Into<ArchitectureObj>, start: Address) -> Self {
+        Self {
+            arch: arch.into(),
+            start,
+            max_entries: DEFAULT_MAX_LIST_ENTRIES,
+            alignment: 1,
+        }
+    }
+
+    /// Sets the maximum number of entries that are walked.
+    pub fn max_entries(mut self, max_entries: usize) -> Self {
+        self.max_entries = max_entries;
+        self
+    }
+
+    /// Ends the walk at `Flink` pointers that are not aligned to the given number of bytes.
+    pub fn alignment(mut self, alignment: umem) -> Self {
+        self.alignment = alignment.max(1);
+        self
+    }
+
+    /// Calls `callback` with every entry of the list and the `Flink` that was read from it.
+    ///
+    /// The start entry is passed to the callback first. The walk ends once the callback returns false,
+    /// the `Flink` is null, misaligned or leads back to the start, a cycle has been detected
+    /// or the maximum number of entries has been reached.
+    ///
+    /// Cycles are detected with Floyd's algorithm, entries of a cycle might therefore
+    /// be passed to the callback more than once before the walk ends.
+    pub fn walk<M: MemoryView>(
+        &self,
+        mem: &mut M,
+        mut callback: impl FnMut(&mut M, Address, Address) -> bool,
+    ) -> Result<()> {
+        // the slow pointer is taken from the visited entries so no entry has to be read twice
+        let mut visited = vec![];
+        let mut entry = self.start;
+        loop {
+            if visited.len() >= self.max_entries {
+                warn!(
+                    "list at {:x} exceeds {} entries, stopping walk",
+                    self.start, self.max_entries
+                );
+                break;
+            }
+
+            let flink = mem.read_addr_arch(self.arch, entry)?;
+            visited.push(entry);
+            if !callback(mem, entry, flink) {
+                break;
+            }
+
+            if flink.is_null() || flink.to_umem() % self.alignment != 0 || flink == self.start {
+                break;
+            }
+
+            // the fast pointer advances by one entry per step and the slow pointer by one every second step
+            if flink == entry || flink == visited[visited.len() / 2] {
+                warn!(
+                    "list at {:x} contains a cycle at {:x}, stopping walk",
+                    self.start, flink
+                );
+                break;
+            }
+
+            entry = flink;
+        }
+        Ok(())
+    }
+
+    /// Returns the addresses of all entries of the list including the start entry.
+    pub fn entries<M: MemoryView>(&self, mem: &mut M) -> Result<Vec<Address>> {
+        let mut entries = vec![];
+        self.walk(mem, |_, entry, _| {
+            entries.push(entry);
+            true
+        })?;
+        Ok(entries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use memflow::architecture::ArchitectureIdent;
+    use memflow::dummy::DummyMemory;
+    use memflow::mem::PhysicalMemory;
+    use memflow::types::size;
+
+    fn walker(start: u64) -> Win32ListWalker {
+        Win32ListWalker::new(ArchitectureIdent::X86(64, false), Address::from(start))
+    }
+
+    fn link<M: MemoryView>(mem: &mut M, entry: u64, flink: u64) {
+        mem.write(Address::from(entry), &flink).unwrap();
+    }
+
+    fn addrs(entries: &[u64]) -> Vec<Address> {
+        entries.iter().map(|&e| Address::from(e)).collect()
+    }
+
+    #[test]
+    fn walk_circular_list() {
+        let mut mem = DummyMemory::new(size::mb(1));
+        let mut view = mem.phys_view();
+        link(&mut view, 0x1000, 0x2000);
+        link(&mut view, 0x2000, 0x3000);
+        link(&mut view, 0x3000, 0x1000);
+
+        let entries = walker(0x1000).entries(&mut view).unwrap();
+        assert_eq!(entries, addrs(&[0x1000, 0x2000, 0x3000]));
+    }
+
+    #[test]
+    fn walk_ends_at_null_and_misaligned() {
+        let mut mem = DummyMemory::new(size::mb(1));
+        let mut view = mem.phys_view();
+        link(&mut view, 0x1000, 0x2000);
+        link(&mut view, 0x2000, 0);
+        assert_eq!(
+            walker(0x1000).entries(&mut view).unwrap(),
+            addrs(&[0x1000, 0x2000])
+        );
+
+        link(&mut view, 0x2000, 0x3004);
+        assert_eq!(
+            walker(0x1000).alignment(8).entries(&mut view).unwrap(),
+            addrs(&[0x1000, 0x2000])
+        );
+    }
+
+    #[test]
+    fn walk_detects_cycles() {
+        let mut mem = DummyMemory::new(size::mb(1));
+        let mut view = mem.phys_view();
+        // the list never returns to its start
+        link(&mut view, 0x1000, 0x2000);
+        link(&mut view, 0x2000, 0x3000);
+        link(&mut view, 0x3000, 0x4000);
+        link(&mut view, 0x4000, 0x5000);
+        link(&mut view, 0x5000, 0x2000);
+
+        let entries = walker(0x1000).entries(&mut view).unwrap();
+        assert!(entries.len() < 16);
+        assert_eq!(
+            entries[..5],
+            addrs(&[0x1000, 0x2000, 0x3000, 0x4000, 0x5000])[..]
+        );
+
+        // an entry pointing to itself
+        link(&mut view, 0x2000, 0x2000);
+        assert_eq!(
+            walker(0x1000).entries(&mut view).unwrap(),
+            addrs(&[0x1000, 0x2000])
+        );
+    }
+
+    #[test]
+    fn walk_max_entries() {
+        let mut mem = DummyMemory::new(size::mb(1));
+        let mut view = mem.phys_view();
+        for entry in (0x1000..0x9000).step_by(0x1000) {
+            link(&mut view, entry, entry + 0x1000);
+        }
+        link(&mut view, 0x9000, 0x1000);
+
+        let entries = walker(0x1000).max_entries(4).entries(&mut view).unwrap();
+        assert_eq!(entries, addrs(&[0x1000, 0x2000, 0x3000, 0x4000]));
+    }
+
+    #[test]
+    fn walk_stops_on_callback() {
+        let mut mem = DummyMemory::new(size::mb(1));
+        let mut view = mem.phys_view();
+        link(&mut view, 0x1000, 0x2000);
+        link(&mut view, 0x2000, 0x3000);
+        link(&mut view, 0x3000, 0x1000);
+
+        let mut visited = vec![];
+        walker(0x1000)
+            .walk(&mut view, |_, entry, flink| {
+                visited.push((entry, flink));
+                entry != Address::from(0x2000u64)
+            })
+            .unwrap();
+        assert_eq!(
+            visited,
+            vec![
+                (Address::from(0x1000u64), Address::from(0x2000u64)),
+                (Address::from(0x2000u64), Address::from(0x3000u64))
+            ]
+        );
+    }
+}
@@ -0,0 +1,546 @@
+/*!
+Module for reading pages from the memory compression store.
+
+Starting with Windows 10 the memory manager compresses pages instead of
+writing them to the pagefile directly. The compressed data is held in the user-mode
+address space of the `MemCompression` process and is referenced by the store manager
+(`nt!SmGlobals`). Pages that reside in the store have a software pte that points
+into the virtual store pagefile.
+
+The store manager structures are undocumented and vary between builds,
+so the offsets used to walk them can be overridden via [`Win32MemCompressionOffsets`].
+`SmGlobals` is not exported, with the `symstore` feature enabled it is resolved from the kernel pdb
+together with the invalid pte mask. Without a pdb both have to be supplied by the caller
+through [`Win32Kernel::mem_compression_with_offsets`].
+
+# Examples:
+
+```
+use memflow::prelude::v1::*;
+use memflow_win32::win32::{Win32Kernel, Win32Process, Win32VirtualTranslate};
+
+fn test<T: 'static + PhysicalMemory + Clone, V: 'static + VirtualTranslate2 + Clone>(
+    kernel: &mut Win32Kernel<T, V>,
+    process: &mut Win32Process<T, V, Win32VirtualTranslate>,
+) {
+    let mut store = kernel.mem_compression().unwrap();
+
+    let mut buf = vec![0u8; 0x2000];
+    process
+        .read_raw_into_decompressed(&mut store, Address::from(0x10000), &mut buf)
+        .unwrap();
+}
+```
+*/
+use std::prelude::v1::*;
+
+use super::{
+    Win32Kernel, Win32PagefileLocation, Win32Process, Win32SoftwarePteFormat, Win32VirtualTranslate,
+};
+#[cfg(feature = "symstore")]
+use crate::offsets::SymbolStore;
+
+use memflow::error::{Error, ErrorKind, ErrorOrigin, Result};
+use memflow::mem::{MemoryView, PhysicalMemory, VirtualTranslate2};
+use memflow::os::Os;
+use memflow::types::{umem, Address};
+
+use log::{debug, trace};
+
+const PAGE_SIZE: usize = 0x1000;
+
+/// Name of the process that holds the compressed pages
+const MEM_COMPRESSION_PROCESS: &str = "MemCompression";
+
+/// Upper bound for the depth of a store manager b+tree
+const MAX_TREE_DEPTH: usize = 16;
+
+const COMPRESSION_FORMAT_XPRESS: u32 = 3;
+
+/// Offsets of the undocumented store manager structures.
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize))]
+pub struct Win32MemCompressionOffsets {
+    /// `SMKM_STORE_MGR.sGlobalTree`, b+tree mapping page keys to store indices
+    pub store_mgr_key_to_store_tree: usize,
+    /// Size of a single `SMKM_STORE_METADATA` entry
+    pub store_metadata_size: usize,
+    /// `SMKM_STORE.StStore.StDataMgr.sLocalTree`, b+tree mapping page keys to page records
+    pub store_pages_tree: usize,
+    /// `SMKM_STORE.StStore.StDataMgr.ChunkMetaData`
+    pub store_chunk_metadata: usize,
+    /// `SMKM_STORE.StStore.StDataMgr.dwRegionSizeMask`
+    pub store_region_size_mask: usize,
+    /// `SMKM_STORE.StStore.StDataMgr.dwRegionIndexMask`
+    pub store_region_index_mask: usize,
+    /// `SMKM_STORE.StStore.StDataMgr.CompressionAlgorithm`
+    pub store_compression_algorithm: usize,
+    /// `SMKM_STORE.StStore.StDataMgr.CompressedRegionPtrArray`
+    pub store_compressed_region_ptr_array: usize,
+    /// `SMHP_CHUNK_METADATA.dwBitValue`
+    pub chunk_metadata_bit_value: usize,
+    /// `SMHP_CHUNK_METADATA.dwPageRecordSize`
+    pub chunk_metadata_page_record_size: usize,
+    /// `MiState.Hardware.InvalidPteMask`, used to unswizzle software ptes.
+    ///
+    /// This is filled in from the kernel pdb when the reader is created by
+    /// [`Win32Kernel::mem_compression`] or [`Win32Kernel::mem_compression_from_store`].
+    /// It is non-zero on Windows 10 1803 and newer x64 kernels.
+    pub invalid_pte_mask: u64,
+}
+
+impl Default for Win32MemCompressionOffsets {
+    fn default() -> Self {
+        Self::win10_1903_x64()
+    }
+}
+
+impl Win32MemCompressionOffsets {
+    /// Structure layout of Windows 10 1903 and newer x64 builds.
+    pub fn win10_1903_x64() -> Self {
+        Self {
+            store_mgr_key_to_store_tree: 0x1c0,
+            store_metadata_size: 0x28,
+            store_pages_tree: 0x50,
+            store_chunk_metadata: 0xc0,
+            store_region_size_mask: 0x328,
+            store_region_index_mask: 0x32c,
+            store_compression_algorithm: 0x3e0,
+            store_compressed_region_ptr_array: 0x1848,
+            chunk_metadata_bit_value: 0x108,
+            chunk_metadata_page_record_size: 0x110,
+            invalid_pte_mask: 0,
+        }
+    }
+
+    pub fn invalid_pte_mask(mut self, invalid_pte_mask: u64) -> Self {
+        self.invalid_pte_mask = invalid_pte_mask;
+        self
+    }
+}
+
+/// Key of a page in the store manager
+///
+/// The key is composed of the pagefile number and the page offset inside of the pagefile.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize))]
+pub struct Win32StorePageKey(pub u32);
+
+impl Win32StorePageKey {
+    /// Decodes the page key from a software pte that references a pagefile.
+    ///
+    /// Returns `None` for demand zero, prototype and transition ptes.
+    pub fn from_software_pte(pte: u64, invalid_pte_mask: u64) -> Option<Self> {
+        Win32PagefileLocation::from_software_pte(
+            pte,
+            Win32SoftwarePteFormat::X64 { invalid_pte_mask },
+        )
+        .map(Self::from)
+    }
+
+    pub fn page_file_number(&self) -> u32 {
+        self.0 >> 28
+    }
+
+    pub fn page_file_offset(&self) -> u32 {
+        self.0 & 0x0fff_ffff
+    }
+}
+
+impl From<Win32PagefileLocation> for Win32StorePageKey {
+    fn from(location: Win32PagefileLocation) -> Self {
+        Self((location.page_file_number << 28) | (location.page_offset as u32 & 0x0fff_ffff))
+    }
+}
+
+/// Reader for the memory compression store.
+///
+/// Holds the `MemCompression` process that owns the compressed regions.
+pub struct Win32MemCompression<T, V> {
+    process: Win32Process<T, V, Win32VirtualTranslate>,
+    sm_globals: Address,
+    offsets: Win32MemCompressionOffsets,
+}
+
+impl<T: 'static + PhysicalMemory + Clone, V: 'static + VirtualTranslate2 + Clone>
+    Win32Kernel<T, V>
+{
+    /// Creates a reader for the memory compression store with the default offsets.
+    ///
+    /// `nt!SmGlobals` and the invalid pte mask are resolved from the kernel pdb which is loaded
+    /// from the symbol store of the kernel (see [`Win32Kernel::set_symbol_store`]).
+    /// An error is returned if the kernel has no symbol store or the pdb cannot be loaded.
+    #[cfg(feature = "symstore")]
+    pub fn mem_compression(&mut self) -> Result<Win32MemCompression<T, V>> {
+        let store = self.configured_symbol_store()?;
+        self.mem_compression_from_store(&store)
+    }
+
+    /// Creates a reader for the memory compression store with the default offsets.
+    ///
+    /// `nt!SmGlobals` and the invalid pte mask are resolved from the kernel pdb
+    /// which is loaded from the given symbol store.
+    #[cfg(feature = "symstore")]
+    pub fn mem_compression_from_store(
+        &mut self,
+        store: &SymbolStore,
+    ) -> Result<Win32MemCompression<T, V>> {
+        let sm_globals = self
+            .kernel_symbols_from_store(store)?
+            .address("SmGlobals")?;
+        debug!("SmGlobals={:x}", sm_globals);
+        // kernels older than Windows 10 1803 do not xor their software ptes
+        let invalid_pte_mask = match self.software_pte_format_from_store(store)? {
+            Win32SoftwarePteFormat::X64 { invalid_pte_mask } => invalid_pte_mask,
+            _ => 0,
+        };
+        self.mem_compression_with_offsets(
+            sm_globals,
+            Win32MemCompressionOffsets::default().invalid_pte_mask(invalid_pte_mask),
+        )
+    }
+
+    /// Creates a reader for the memory compression store with custom structure offsets.
+    ///
+    /// `sm_globals` is the virtual address of `nt!SmGlobals`. The invalid pte mask in `offsets`
+    /// has to match the kernel, otherwise the page keys of Windows 10 1803 and newer x64
+    /// kernels are decoded incorrectly.
+    pub fn mem_compression_with_offsets(
+        &mut self,
+        sm_globals: Address,
+        offsets: Win32MemCompressionOffsets,
+    ) -> Result<Win32MemCompression<T, V>> {
+        let info = self.process_info_by_name(MEM_COMPRESSION_PROCESS)?;
+        debug!("found {} process: {:?}", MEM_COMPRESSION_PROCESS, info);
+
+        let process = self.clone().into_process_by_info(info)?;
+        Ok(Win32MemCompression {
+            process,
+            sm_globals,
+            offsets,
+        })
+    }
+}
+
+impl<T: PhysicalMemory, V: VirtualTranslate2> Win32MemCompression<T, V> {
+    pub fn offsets(&self) -> &Win32MemCompressionOffsets {
+        &self.offsets
+    }
+
+    /// Reads and decompresses the page referenced by the given software pte.
+    pub fn read_page(&mut self, pte: u64, out: &mut [u8; PAGE_SIZE]) -> Result<()> {
+        let key = Win32StorePageKey::from_software_pte(pte, self.offsets.invalid_pte_mask)
+            .ok_or_else(|| {
+                Error(ErrorOrigin::OsLayer, ErrorKind::InvalidArgument)
+                    .log_trace("pte does not reference a pagefile")
+            })?;
+        self.read_page_by_key(key, out)
+    }
+
+    /// Reads and decompresses the page with the given store manager key.
+    pub fn read_page_by_key(
+        &mut self,
+        key: Win32StorePageKey,
+        out: &mut [u8; PAGE_SIZE],
+    ) -> Result<()> {
+        let offsets = self.offsets;
+        let mem = &mut self.process.virt_mem;
+
+        // 1. find the store that holds the page
+        let store_index = btree_lookup(
+            mem,
+            self.sm_globals + offsets.store_mgr_key_to_store_tree,
+            key.0,
+        )? & 0x3ff;
+        let metadata_array = mem.read_addr64(self.sm_globals + (store_index as umem >> 5) * 8)?;
+        let store = mem.read_addr64(
+            metadata_array + (store_index as umem & 0x1f) * offsets.store_metadata_size as umem,
+        )?;
+        trace!(
+            "page key {:x} is held by store {} at {:x}",
+            key.0,
+            store_index,
+            store
+        );
+        if store.is_null() {
+            return Err(Error(ErrorOrigin::OsLayer, ErrorKind::NotFound)
+                .log_trace("store for page key is not initialized"));
+        }
+
+        // 2. find the page record inside of the store
+        let record_index = btree_lookup(mem, store + offsets.store_pages_tree, key.0)?;
+        let chunk_metadata = store + offsets.store_chunk_metadata;
+        let bit_value: u32 = mem.read(chunk_metadata + offsets.chunk_metadata_bit_value)?;
+        let record_size: u32 =
+            mem.read(chunk_metadata + offsets.chunk_metadata_page_record_size)?;
+
+        // chunks double in size, the first chunk holds `1 << bit_value` records
+        let chunk = 31 - ((record_index >> bit_value) + 1).leading_zeros();
+        let chunk_start = ((1u32 << chunk) - 1) << bit_value;
+        let chunk_ptr = mem.read_addr64(chunk_metadata + chunk as umem * 8)?;
+        let record = chunk_ptr + (record_index - chunk_start) as umem * record_size as umem;
+
+        let region_key: u32 = mem.read(record)?;
+        let compressed_size = mem.read::<u16>(record + 4usize)? as usize;
+
+        // 3. locate the compressed data inside of the MemCompression process
+        let region_size_mask: u32 = mem.read(store + offsets.store_region_size_mask)?;
+        let region_index_mask: u32 = mem.read(store + offsets.store_region_index_mask)?;
+        let region_index =
+            (region_key >> (32 - region_size_mask.leading_zeros())) & region_index_mask;
+        let region_offset = (region_key & region_size_mask) << 4;
+
+        let region_ptr_array =
+            mem.read_addr64(store + offsets.store_compressed_region_ptr_array)?;
+        let region = mem.read_addr64(region_ptr_array + region_index as umem * 8)?;
+        let data_addr = region + region_offset;
+        trace!(
+            "compressed page for key {:x} at {:x} (size={:x})",
+            key.0,
+            data_addr,
+            compressed_size
+        );
+
+        if compressed_size == 0 || compressed_size > PAGE_SIZE {
+            return Err(Error(ErrorOrigin::OsLayer, ErrorKind::InvalidMemory)
+                .log_trace("invalid compressed page size"));
+        }
+
+        if compressed_size == PAGE_SIZE {
+            // incompressible pages are stored as is
+            return mem.read_raw_into(data_addr, out).map_err(From::from);
+        }
+
+        let algorithm: u32 = mem.read(store + offsets.store_compression_algorithm)?;
+        if algorithm & 0xff != COMPRESSION_FORMAT_XPRESS {
+            return Err(Error(ErrorOrigin::OsLayer, ErrorKind::NotSupported)
+                .log_info("unsupported store compression algorithm"));
+        }
+
+        let compressed = mem.read_raw(data_addr, (compressed_size + 0xf) & !0xf)?;
+        xpress_decompress(&compressed[..compressed_size], out)
+    }
+}
+
+impl<T: PhysicalMemory, V: VirtualTranslate2> Win32Process<T, V, Win32VirtualTranslate> {
+    /// Reads memory and falls back to the memory compression store for pages that
+    /// are not resident but are held in compressed form.
+    pub fn read_raw_into_decompressed<U: PhysicalMemory, W: VirtualTranslate2>(
+        &mut self,
+        store: &mut Win32MemCompression<U, W>,
+        addr: Address,
+        out: &mut [u8],
+    ) -> Result<()> {
+        self.read_raw_into_with_fallback(addr, out, |pte, page| store.read_page(pte, page))
+    }
+}
+
+/// Looks up a key in a store manager b+tree and returns the associated value.
+fn btree_lookup<M: MemoryView>(mem: &mut M, tree: Address, key: u32) -> Result<u32> {
+    let mut node = mem.read_addr64(tree)?;
+
+    for _ in 0..MAX_TREE_DEPTH {
+        if node.is_null() {
+            break;
+        }
+
+        // B_TREE { u16 cEntries; u8 cLevel; u8 fLeaf; u32 pad; PVOID LeftChild; entries[] }
+        let entries = mem.read::<u16>(node)? as usize;
+        let leaf = mem.read::<u8>(node + 3usize)? != 0;
+        let entries_addr = node + 0x10usize;
+
+        if leaf {
+            // leaf entries: { u32 key; u32 value; }
+            let raw = mem.read_raw(entries_addr, entries * 8)?;
+            return raw
+                .chunks_exact(8)
+                .map(|c| {
+                    (
+                        u32::from_le_bytes([c[0], c[1], c[2], c[3]]),
+                        u32::from_le_bytes([c[4], c[5], c[6], c[7]]),
+                    )
+                })
+                .find(|(k, _)| *k == key)
+                .map(|(_, v)| v)
+                .ok_or_else(|| {
+                    Error(ErrorOrigin::OsLayer, ErrorKind::NotFound)
+                        .log_trace("key not found in store b+tree")
+                });
+        }
+
+        // node entries: { u32 key; u32 pad; PVOID child; }, keys smaller than the first entry
+        // are stored in the left child
+        let raw = mem.read_raw(entries_addr, entries * 0x10)?;
+        let child = raw
+            .chunks_exact(0x10)
+            .take_while(|c| u32::from_le_bytes([c[0], c[1], c[2], c[3]]) <= key)
+            .last()
+            .map(|c| {
+                Address::from(u64::from_le_bytes([
+                    c[8], c[9], c[10], c[11], c[12], c[13], c[14], c[15],
+                ]))
+            });
+        node = match child {
+            Some(child) => child,
+            None => mem.read_addr64(node + 8usize)?,
+        };
+    }
+
+    Err(Error(ErrorOrigin::OsLayer, ErrorKind::NotFound).log_trace("key not found in store b+tree"))
+}
+
+/// Decompresses a buffer with the plain LZ77 variant of the Xpress algorithm (MS-XCA 2.4).
+///
+/// The whole output buffer has to be filled, input that ends early is reported as an error.
+fn xpress_decompress(input: &[u8], out: &mut [u8]) -> Result<()> {
+    let invalid =
+        || Error(ErrorOrigin::OsLayer, ErrorKind::Encoding).log_trace("invalid xpress data");
+
+    let read_u16 = |pos: usize| -> Option<usize> {
+        Some(u16::from_le_bytes([*input.get(pos)?, *input.get(pos + 1)?]) as usize)
+    };
+    let read_u32 = |pos: usize| -> Option<usize> {
+        Some(u32::from_le_bytes([
+            *input.get(pos)?,
+            *input.get(pos + 1)?,
+            *input.get(pos + 2)?,
+            *input.get(pos + 3)?,
+        ]) as usize)
+    };
+
+    let mut flags = 0;
+    let mut flag_count = 0;
+    let mut in_pos = 0;
+    let mut out_pos = 0;
+    let mut last_length_half_byte = 0;
+
+    while out_pos < out.len() {
+        if flag_count == 0 {
+            flags = match read_u32(in_pos) {
+                Some(flags) => flags,
+                None => break,
+            };
+            in_pos += 4;
+            flag_count = 32;
+        }
+        flag_count -= 1;
+
+        if flags & (1 << flag_count) == 0 {
+            // literal
+            match input.get(in_pos) {
+                Some(b) => out[out_pos] = *b,
+                None => break,
+            }
+            in_pos += 1;
+            out_pos += 1;
+            continue;
+        }
+
+        if in_pos >= input.len() {
+            break;
+        }
+
+        let match_bytes = read_u16(in_pos).ok_or_else(invalid)?;
+        in_pos += 2;
+        let mut match_len = match_bytes % 8;
+        let match_offset = (match_bytes / 8) + 1;
+
+        if match_len == 7 {
+            if last_length_half_byte == 0 {
+                match_len = (*input.get(in_pos).ok_or_else(invalid)? % 16) as usize;
+                last_length_half_byte = in_pos;
+                in_pos += 1;
+            } else {
+                match_len = (input[last_length_half_byte] / 16) as usize;
+                last_length_half_byte = 0;
+            }
+
+            if match_len == 15 {
+                match_len = *input.get(in_pos).ok_or_else(invalid)? as usize;
+                in_pos += 1;
+
+                if match_len == 255 {
+                    match_len = read_u16(in_pos).ok_or_else(invalid)?;
+                    in_pos += 2;
+                    if match_len == 0 {
+                        match_len = read_u32(in_pos).ok_or_else(invalid)?;
+                        in_pos += 4;
+                    }
+                    match_len = match_len.checked_sub(15 + 7).ok_or_else(invalid)?;
+                }
+                match_len += 15;
+            }
+            match_len += 7;
+        }
+        match_len += 3;
+
+        if match_offset > out_pos {
+            return Err(invalid());
+        }
+        for _ in 0..match_len.min(out.len() - out_pos) {
+            out[out_pos] = out[out_pos - match_offset];
+            out_pos += 1;
+        }
+    }
+
+    if out_pos < out.len() {
+        return Err(
+            Error(ErrorOrigin::OsLayer, ErrorKind::Encoding).log_trace("xpress data is truncated")
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // test vectors from MS-XCA 3.1 and 3.2
+    const ALPHABET: &[u8] = b"abcdefghijklmnopqrstuvwxyz";
+    const ALPHABET_COMPRESSED: &[u8] = &[
+        0x3f, 0x00, 0x00, 0x00, 0x61, 0x62, 0x63, 0x64, 0x65, 0x66, 0x67, 0x68, 0x69, 0x6a, 0x6b,
+        0x6c, 0x6d, 0x6e, 0x6f, 0x70, 0x71, 0x72, 0x73, 0x74, 0x75, 0x76, 0x77, 0x78, 0x79, 0x7a,
+    ];
+    const REPEATED_COMPRESSED: &[u8] = &[
+        0xff, 0xff, 0xff, 0x1f, 0x61, 0x62, 0x63, 0x17, 0x00, 0x0f, 0xff, 0x26, 0x01,
+    ];
+
+    #[test]
+    fn xpress_literals() {
+        let mut out = [0u8; 26];
+        xpress_decompress(ALPHABET_COMPRESSED, &mut out).unwrap();
+        assert_eq!(&out[..], ALPHABET);
+    }
+
+    #[test]
+    fn xpress_long_match() {
+        let mut out = [0u8; 300];
+        xpress_decompress(REPEATED_COMPRESSED, &mut out).unwrap();
+        assert_eq!(out.to_vec(), b"abc".repeat(100));
+    }
+
+    #[test]
+    fn xpress_truncated_literals() {
+        let mut out = [0u8; 26];
+        let input = &ALPHABET_COMPRESSED[..ALPHABET_COMPRESSED.len() - 1];
+        let err = xpress_decompress(input, &mut out).unwrap_err();
+        assert_eq!(err.1, ErrorKind::Encoding);
+    }
+
+    #[test]
+    fn xpress_truncated_match() {
+        let mut out = [0u8; 300];
+        let input = &REPEATED_COMPRESSED[..REPEATED_COMPRESSED.len() - 1];
+        let err = xpress_decompress(input, &mut out).unwrap_err();
+        assert_eq!(err.1, ErrorKind::Encoding);
+    }
+
+    #[test]
+    fn xpress_invalid_offset() {
+        // a match before any literal references data outside of the output
+        let mut out = [0u8; 16];
+        let err = xpress_decompress(&[0x00, 0x00, 0x00, 0x80, 0x00, 0x00], &mut out).unwrap_err();
+        assert_eq!(err.1, ErrorKind::Encoding);
+    }
+}
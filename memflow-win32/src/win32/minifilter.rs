@@ -0,0 +1,542 @@
+/*!
+Module for enumerating file system minifilters and legacy file system filters.
+
+Minifilters are registered with the filter manager (`fltmgr.sys`) which keeps them in frames.
+Every frame covers a range of altitudes and contains the registered filters and the volumes
+the frame is attached to. The structures of the filter manager are not part of the kernel pdb,
+the pdb of `fltmgr.sys` is loaded from the symbol store to resolve them.
+
+Legacy filters attach their own device objects to the device stack of a volume.
+The stack of every volume is walked from the file system device at the bottom to the top,
+devices of drivers other than the file system and the filter manager are legacy filters.
+
+Routines are attributed to the kernel module that contains them, routines that do not
+belong to any loaded module are suspicious.
+
+This module is only available with the `symstore` feature enabled.
+
+# Examples:
+
+```
+use memflow::prelude::v1::*;
+use memflow_win32::offsets::SymbolStore;
+use memflow_win32::win32::Win32Kernel;
+
+fn test<T: 'static + PhysicalMemory + Clone, V: 'static + VirtualTranslate2 + Clone>(
+    kernel: &mut Win32Kernel<T, V>,
+) {
+    let filters = kernel.filter_manager(&SymbolStore::default()).unwrap();
+    for frame in filters.frames.iter() {
+        for filter in frame.filters.iter() {
+            println!("{} {}", filter.altitude, filter.name);
+        }
+        for volume in frame.volumes.iter() {
+            for device in volume.legacy_filters() {
+                println!("{}: {}", volume.device_name, device.driver_name);
+            }
+        }
+    }
+}
+```
+*/
+use std::prelude::v1::*;
+
+use super::{
+    VirtualReadUnicodeString, Win32Kernel, Win32KernelSymbols, Win32KernelTypes, Win32ListWalker,
+    Win32ModuleInfo, Win32ModuleOffset,
+};
+use crate::offsets::SymbolStore;
+
+use log::{debug, trace};
+
+use memflow::architecture::ArchitectureObj;
+use memflow::error::{Error, ErrorKind, ErrorOrigin, Result};
+use memflow::mem::{MemoryView, PhysicalMemory, VirtualTranslate2};
+use memflow::os::{ModuleInfo, Os};
+use memflow::types::{umem, Address};
+
+const FLTMGR_DRIVER_NAME: &str = "\\FileSystem\\FltMgr";
+
+/// `IRP_MJ_OPERATION_END`, terminates the operation registrations of a filter
+const IRP_MJ_OPERATION_END: u8 = 0x80;
+/// Upper bound for the number of operation registrations of a filter
+const MAX_OPERATIONS: usize = 64;
+/// Upper bound for the number of devices in a device stack
+const MAX_DEVICE_STACK_DEPTH: usize = 32;
+
+/// A routine registered with the filter manager
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize))]
+pub struct Win32FilterRoutine {
+    pub address: Address,
+    /// Name of the module that contains the routine
+    pub module: Option<String>,
+}
+
+impl Win32FilterRoutine {
+    fn new(address: Address, modules: &[ModuleInfo]) -> Self {
+        Self {
+            address,
+            module: Win32ModuleOffset::find(modules.iter().cloned(), address)
+                .map(|m| m.module.name.to_string()),
+        }
+    }
+
+    /// Returns true if the routine is set but does not belong to any loaded module.
+    pub fn is_unbacked(&self) -> bool {
+        !self.address.is_null() && self.module.is_none()
+    }
+}
+
+/// Callbacks of a minifilter for a single irp major function
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize))]
+pub struct Win32MinifilterOperation {
+    /// Irp major function, negative values (e.g. `IRP_MJ_ACQUIRE_FOR_SECTION_SYNCHRONIZATION`)
+    /// are stored as their unsigned representation
+    pub major_function: u8,
+    pub flags: u32,
+    pub pre_operation: Win32FilterRoutine,
+    pub post_operation: Win32FilterRoutine,
+}
+
+/// An instance of a minifilter that is attached to a volume
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize))]
+pub struct Win32MinifilterInstance {
+    /// Address of the `_FLT_INSTANCE`
+    pub address: Address,
+    pub name: String,
+    pub altitude: String,
+    /// Address of the `_FLT_VOLUME` the instance is attached to
+    pub volume: Address,
+}
+
+/// A minifilter registered with the filter manager
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize))]
+pub struct Win32Minifilter {
+    /// Address of the `_FLT_FILTER`
+    pub address: Address,
+    pub name: String,
+    pub altitude: String,
+    pub flags: u32,
+    pub driver_object: Address,
+    pub unload: Win32FilterRoutine,
+    pub instance_setup: Win32FilterRoutine,
+    pub operations: Vec<Win32MinifilterOperation>,
+    pub instances: Vec<Win32MinifilterInstance>,
+}
+
+impl Win32Minifilter {
+    /// Returns true if any routine of the filter does not belong to a loaded module.
+    pub fn is_suspicious(&self) -> bool {
+        self.unload.is_unbacked()
+            || self.instance_setup.is_unbacked()
+            || self
+                .operations
+                .iter()
+                .any(|op| op.pre_operation.is_unbacked() || op.post_operation.is_unbacked())
+    }
+}
+
+/// A device object in the device stack of a volume
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize))]
+pub struct Win32StackDevice {
+    /// Address of the `_DEVICE_OBJECT`
+    pub address: Address,
+    pub driver_object: Address,
+    /// Name of the driver object, e.g. `\FileSystem\Ntfs`
+    pub driver_name: String,
+}
+
+/// A volume the filter manager is attached to
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize))]
+pub struct Win32FilterVolume {
+    /// Address of the `_FLT_VOLUME`
+    pub address: Address,
+    /// Name of the volume device, e.g. `\Device\HarddiskVolume2`
+    pub device_name: String,
+    /// Device object of the filter manager in the device stack of the volume
+    pub device_object: Address,
+    /// Device stack of the volume from the bottom to the top
+    pub device_stack: Vec<Win32StackDevice>,
+}
+
+impl Win32FilterVolume {
+    /// Returns the devices of legacy filters in the device stack.
+    ///
+    /// The file system at the bottom of the stack and the filter manager are skipped.
+    pub fn legacy_filters(&self) -> impl Iterator<Item = &Win32StackDevice> {
+        self.device_stack
+            .iter()
+            .skip(1)
+            .filter(|device| !device.driver_name.eq_ignore_ascii_case(FLTMGR_DRIVER_NAME))
+    }
+}
+
+/// A frame of the filter manager
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize))]
+pub struct Win32FilterFrame {
+    /// Address of the `_FLTP_FRAME`
+    pub address: Address,
+    pub id: u32,
+    pub altitude_low: String,
+    pub altitude_high: String,
+    pub filters: Vec<Win32Minifilter>,
+    pub volumes: Vec<Win32FilterVolume>,
+}
+
+/// Frames, minifilters and volumes of the filter manager
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize))]
+pub struct Win32FilterManager {
+    pub frames: Vec<Win32FilterFrame>,
+}
+
+impl Win32FilterManager {
+    /// Returns all minifilters of all frames.
+    pub fn filters(&self) -> impl Iterator<Item = &Win32Minifilter> {
+        self.frames.iter().flat_map(|frame| frame.filters.iter())
+    }
+}
+
+impl<T: 'static + PhysicalMemory + Clone, V: 'static + VirtualTranslate2 + Clone>
+    Win32Kernel<T, V>
+{
+    /// Enumerates the frames, minifilters and volumes of the filter manager.
+    ///
+    /// The pdbs of the kernel and of `fltmgr.sys` are loaded from the given symbol store.
+    pub fn filter_manager(&mut self, store: &SymbolStore) -> Result<Win32FilterManager> {
+        let modules = self.module_list()?;
+        let fltmgr = modules
+            .iter()
+            .find(|m| m.name.as_ref().eq_ignore_ascii_case("fltmgr.sys"))
+            .cloned()
+            .ok_or_else(|| {
+                Error(ErrorOrigin::OsLayer, ErrorKind::ModuleNotFound)
+                    .log_info("fltmgr.sys is not loaded")
+            })?;
+
+        let fltmgr_pdb = fltmgr.pdb(&mut self.virt_mem, store)?;
+        let globals = Win32KernelSymbols::new(&fltmgr_pdb, fltmgr.base)?.address("FltGlobals")?;
+        let mut fltmgr_types = Win32KernelTypes::new(fltmgr_pdb);
+        let mut kernel_types = self.kernel_types_from_store(store)?;
+
+        let arch: ArchitectureObj = self.kernel_info.os_info.arch.into();
+        let offsets = FltOffsets::new(&mut fltmgr_types, &mut kernel_types, arch)?;
+        let mut reader = FltReader {
+            mem: &mut self.virt_mem,
+            arch,
+            offsets,
+            modules: &modules,
+        };
+
+        let frame_list = globals + reader.offsets.globals_frame_list;
+        let mut out = Win32FilterManager::default();
+        for frame in reader.list(frame_list, reader.offsets.frame_links)? {
+            match reader.frame(frame) {
+                Ok(frame) => out.frames.push(frame),
+                Err(err) => debug!("unable to read filter frame {:x}: {}", frame, err),
+            }
+        }
+
+        Ok(out)
+    }
+}
+
+/// Offsets of the filter manager and kernel structures
+#[derive(Clone, Copy)]
+struct FltOffsets {
+    /// `_GLOBALS.FrameList.rList`
+    globals_frame_list: usize,
+
+    frame_links: usize,
+    frame_id: usize,
+    frame_altitude_low: usize,
+    frame_altitude_high: usize,
+    /// `_FLTP_FRAME.RegisteredFilters.rList`
+    frame_filters: usize,
+    /// `_FLTP_FRAME.AttachedVolumes.rList`
+    frame_volumes: usize,
+
+    /// `_FLT_FILTER.Base.PrimaryLink`
+    filter_link: usize,
+    filter_name: usize,
+    filter_altitude: usize,
+    filter_flags: usize,
+    filter_driver_object: usize,
+    filter_unload: usize,
+    filter_instance_setup: usize,
+    filter_operations: usize,
+    /// `_FLT_FILTER.InstanceList.rList`
+    filter_instances: usize,
+
+    instance_filter_link: usize,
+    instance_name: usize,
+    instance_altitude: usize,
+    instance_volume: usize,
+
+    /// `_FLT_VOLUME.Base.PrimaryLink`
+    volume_link: usize,
+    volume_device_name: usize,
+    volume_device_object: usize,
+
+    /// Size and field offsets of `_FLT_OPERATION_REGISTRATION`
+    operation_size: usize,
+    operation_flags: usize,
+    operation_pre: usize,
+    operation_post: usize,
+
+    device_driver_object: usize,
+    device_attached_device: usize,
+    device_extension: usize,
+    devobj_extension_attached_to: usize,
+    driver_name: usize,
+}
+
+impl FltOffsets {
+    fn new(
+        fltmgr: &mut Win32KernelTypes,
+        kernel: &mut Win32KernelTypes,
+        arch: ArchitectureObj,
+    ) -> Result<Self> {
+        // the registration structure is public and not always part of the pdb
+        let ptr_size = arch.size_addr();
+        let (operation_size, operation_flags, operation_pre, operation_post) =
+            match fltmgr.struct_size("_FLT_OPERATION_REGISTRATION") {
+                Ok(size) => (
+                    size,
+                    fltmgr.offset_of("_FLT_OPERATION_REGISTRATION.Flags")?,
+                    fltmgr.offset_of("_FLT_OPERATION_REGISTRATION.PreOperation")?,
+                    fltmgr.offset_of("_FLT_OPERATION_REGISTRATION.PostOperation")?,
+                ),
+                Err(_) => (8 + 3 * ptr_size, 4, 8, 8 + ptr_size),
+            };
+
+        Ok(Self {
+            globals_frame_list: fltmgr.offset_of("_GLOBALS.FrameList.rList")?,
+
+            frame_links: fltmgr.offset_of("_FLTP_FRAME.Links")?,
+            frame_id: fltmgr.offset_of("_FLTP_FRAME.FrameID")?,
+            frame_altitude_low: fltmgr.offset_of("_FLTP_FRAME.AltitudeIntervalLow")?,
+            frame_altitude_high: fltmgr.offset_of("_FLTP_FRAME.AltitudeIntervalHigh")?,
+            frame_filters: fltmgr.offset_of("_FLTP_FRAME.RegisteredFilters.rList")?,
+            frame_volumes: fltmgr.offset_of("_FLTP_FRAME.AttachedVolumes.rList")?,
+
+            filter_link: fltmgr.offset_of("_FLT_FILTER.Base.PrimaryLink")?,
+            filter_name: fltmgr.offset_of("_FLT_FILTER.Name")?,
+            filter_altitude: fltmgr.offset_of("_FLT_FILTER.DefaultAltitude")?,
+            filter_flags: fltmgr.offset_of("_FLT_FILTER.Flags")?,
+            filter_driver_object: fltmgr.offset_of("_FLT_FILTER.DriverObject")?,
+            filter_unload: fltmgr.offset_of("_FLT_FILTER.FilterUnload")?,
+            filter_instance_setup: fltmgr.offset_of("_FLT_FILTER.InstanceSetup")?,
+            filter_operations: fltmgr.offset_of("_FLT_FILTER.Operations")?,
+            filter_instances: fltmgr.offset_of("_FLT_FILTER.InstanceList.rList")?,
+
+            instance_filter_link: fltmgr.offset_of("_FLT_INSTANCE.FilterLink")?,
+            instance_name: fltmgr.offset_of("_FLT_INSTANCE.Name")?,
+            instance_altitude: fltmgr.offset_of("_FLT_INSTANCE.Altitude")?,
+            instance_volume: fltmgr.offset_of("_FLT_INSTANCE.Volume")?,
+
+            volume_link: fltmgr.offset_of("_FLT_VOLUME.Base.PrimaryLink")?,
+            volume_device_name: fltmgr.offset_of("_FLT_VOLUME.DeviceName")?,
+            volume_device_object: fltmgr.offset_of("_FLT_VOLUME.DeviceObject")?,
+
+            operation_size,
+            operation_flags,
+            operation_pre,
+            operation_post,
+
+            device_driver_object: kernel.offset_of("_DEVICE_OBJECT.DriverObject")?,
+            device_attached_device: kernel.offset_of("_DEVICE_OBJECT.AttachedDevice")?,
+            device_extension: kernel.offset_of("_DEVICE_OBJECT.DeviceObjectExtension")?,
+            devobj_extension_attached_to: kernel.offset_of("_DEVOBJ_EXTENSION.AttachedTo")?,
+            driver_name: kernel.offset_of("_DRIVER_OBJECT.DriverName")?,
+        })
+    }
+}
+
+struct FltReader<'a, M> {
+    mem: &'a mut M,
+    arch: ArchitectureObj,
+    offsets: FltOffsets,
+    modules: &'a [ModuleInfo],
+}
+
+impl<'a, M: MemoryView> FltReader<'a, M> {
+    fn frame(&mut self, frame: Address) -> Result<Win32FilterFrame> {
+        let o = self.offsets;
+
+        let mut out = Win32FilterFrame {
+            address: frame,
+            id: self.mem.read(frame + o.frame_id)?,
+            altitude_low: self.string(frame + o.frame_altitude_low),
+            altitude_high: self.string(frame + o.frame_altitude_high),
+            filters: vec![],
+            volumes: vec![],
+        };
+        trace!(
+            "frame {}: altitudes {}-{}",
+            out.id,
+            out.altitude_low,
+            out.altitude_high
+        );
+
+        for filter in self.list(frame + o.frame_filters, o.filter_link)? {
+            match self.filter(filter) {
+                Ok(filter) => out.filters.push(filter),
+                Err(err) => debug!("unable to read minifilter {:x}: {}", filter, err),
+            }
+        }
+        for volume in self.list(frame + o.frame_volumes, o.volume_link)? {
+            match self.volume(volume) {
+                Ok(volume) => out.volumes.push(volume),
+                Err(err) => debug!("unable to read filter volume {:x}: {}", volume, err),
+            }
+        }
+
+        Ok(out)
+    }
+
+    fn filter(&mut self, filter: Address) -> Result<Win32Minifilter> {
+        let o = self.offsets;
+        let (unload, instance_setup, operations) = (
+            self.addr(filter + o.filter_unload)?,
+            self.addr(filter + o.filter_instance_setup)?,
+            self.addr(filter + o.filter_operations)?,
+        );
+
+        let mut out = Win32Minifilter {
+            address: filter,
+            name: self.string(filter + o.filter_name),
+            altitude: self.string(filter + o.filter_altitude),
+            flags: self.mem.read(filter + o.filter_flags)?,
+            driver_object: self.addr(filter + o.filter_driver_object)?,
+            unload: Win32FilterRoutine::new(unload, self.modules),
+            instance_setup: Win32FilterRoutine::new(instance_setup, self.modules),
+            operations: self.operations(operations),
+            instances: vec![],
+        };
+        trace!("minifilter {} at altitude {}", out.name, out.altitude);
+
+        for instance in self.list(filter + o.filter_instances, o.instance_filter_link)? {
+            out.instances.push(Win32MinifilterInstance {
+                address: instance,
+                name: self.string(instance + o.instance_name),
+                altitude: self.string(instance + o.instance_altitude),
+                volume: self.addr(instance + o.instance_volume)?,
+            });
+        }
+
+        Ok(out)
+    }
+
+    /// Reads the operation registrations of a filter up to `IRP_MJ_OPERATION_END`.
+    fn operations(&mut self, operations: Address) -> Vec<Win32MinifilterOperation> {
+        let o = self.offsets;
+        let mut out = vec![];
+        if operations.is_null() {
+            return out;
+        }
+
+        for idx in 0..MAX_OPERATIONS {
+            let entry = operations + (idx * o.operation_size) as umem;
+            let major_function = match self.mem.read::<u8>(entry) {
+                Ok(IRP_MJ_OPERATION_END) | Err(_) => break,
+                Ok(major_function) => major_function,
+            };
+            let (flags, pre, post) = match (
+                self.mem.read::<u32>(entry + o.operation_flags),
+                self.addr(entry + o.operation_pre),
+                self.addr(entry + o.operation_post),
+            ) {
+                (Ok(flags), Ok(pre), Ok(post)) => (flags, pre, post),
+                _ => break,
+            };
+            out.push(Win32MinifilterOperation {
+                major_function,
+                flags,
+                pre_operation: Win32FilterRoutine::new(pre, self.modules),
+                post_operation: Win32FilterRoutine::new(post, self.modules),
+            });
+        }
+
+        out
+    }
+
+    fn volume(&mut self, volume: Address) -> Result<Win32FilterVolume> {
+        let o = self.offsets;
+        let device_object = self.addr(volume + o.volume_device_object)?;
+        Ok(Win32FilterVolume {
+            address: volume,
+            device_name: self.string(volume + o.volume_device_name),
+            device_object,
+            device_stack: self.device_stack(device_object),
+        })
+    }
+
+    /// Walks the device stack that contains `device` from the bottom to the top.
+    fn device_stack(&mut self, device: Address) -> Vec<Win32StackDevice> {
+        let o = self.offsets;
+
+        // find the bottom of the stack
+        let mut bottom = device;
+        for _ in 0..MAX_DEVICE_STACK_DEPTH {
+            let lower = self
+                .addr(bottom + o.device_extension)
+                .and_then(|extension| self.addr(extension + o.devobj_extension_attached_to));
+            match lower {
+                Ok(lower) if !lower.is_null() => bottom = lower,
+                _ => break,
+            }
+        }
+
+        let mut out = vec![];
+        let mut device = bottom;
+        while !device.is_null() && out.len() < MAX_DEVICE_STACK_DEPTH {
+            let driver_object = self
+                .addr(device + o.device_driver_object)
+                .unwrap_or_default();
+            let driver_name = if driver_object.is_null() {
+                String::new()
+            } else {
+                self.string(driver_object + o.driver_name)
+            };
+            out.push(Win32StackDevice {
+                address: device,
+                driver_object,
+                driver_name,
+            });
+            device = self
+                .addr(device + o.device_attached_device)
+                .unwrap_or_default();
+        }
+
+        out
+    }
+
+    /// Returns the entries of a list, `link` is the offset of the list entry in the element.
+    fn list(&mut self, list_head: Address, link: usize) -> Result<Vec<Address>> {
+        Ok(Win32ListWalker::new(self.arch, list_head)
+            .entries(self.mem)?
+            .into_iter()
+            .filter(|&list_entry| list_entry != list_head)
+            .map(|list_entry| list_entry - link)
+            .collect())
+    }
+
+    fn addr(&mut self, address: Address) -> Result<Address> {
+        self.mem.read_addr_arch(self.arch, address)
+    }
+
+    /// Reads a unicode string, strings that cannot be read are returned empty.
+    fn string(&mut self, address: Address) -> String {
+        self.mem
+            .read_unicode_string(self.arch, address)
+            .unwrap_or_default()
+    }
+}
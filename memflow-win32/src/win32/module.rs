@@ -1,17 +1,105 @@
 use std::prelude::v1::*;
 
+use crate::kernel::{ntos, ntos::lazy_pe::LazyPe, Win32Guid};
+#[cfg(feature = "symstore")]
+use crate::offsets::SymbolStore;
 use crate::offsets::Win32ArchOffsets;
-use crate::win32::VirtualReadUnicodeString;
+#[cfg(feature = "authenticode")]
+use crate::win32::Win32Authenticode;
+#[cfg(feature = "symstore")]
+use crate::win32::{verify_module, Win32ModuleVerification};
+use crate::win32::{
+    VirtualReadUnicodeString, Win32ListWalker, Win32UnicodeString, Win32VersionInfo,
+};
+
+use std::convert::TryInto;
 
 use log::trace;
 
-use memflow::architecture::ArchitectureIdent;
-use memflow::error::Result;
-use memflow::mem::MemoryView;
+use memflow::architecture::{ArchitectureIdent, ArchitectureObj};
+use memflow::cglue::tuple::CTup2;
+use memflow::error::{PartialResultExt, Result};
+use memflow::mem::{MemoryView, ReadData};
 use memflow::os::{AddressCallback, ModuleInfo};
-use memflow::types::Address;
+use memflow::types::{umem, Address};
+
+/// Module that contains a virtual address
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize))]
+pub struct Win32ModuleOffset {
+    pub module: ModuleInfo,
+    /// Offset of the address from the base of the module
+    pub offset: umem,
+}
+
+impl Win32ModuleOffset {
+    /// Finds the module whose image contains the given address.
+    pub fn find(modules: impl IntoIterator<Item = ModuleInfo>, address: Address) -> Option<Self> {
+        modules
+            .into_iter()
+            .find(|m| address >= m.base && address < m.base + m.size)
+            .map(|module| Self {
+                offset: address - module.base,
+                module,
+            })
+    }
+}
+
+/// Windows specific extensions of [`ModuleInfo`]
+pub trait Win32ModuleInfo {
+    /// Reads the codeview debug entry of the module and returns the guid of its pdb.
+    fn codeview<M: MemoryView>(&self, mem: &mut M) -> Result<Win32Guid>;
+
+    /// Reads the version resource of the module.
+    fn version_info<M: MemoryView>(&self, mem: &mut M) -> Result<Win32VersionInfo>;
+
+    /// Reads the authenticode signature embedded in the module.
+    ///
+    /// Returns `None` if the module does not contain a certificate table.
+    #[cfg(feature = "authenticode")]
+    fn authenticode<M: MemoryView>(&self, mem: &mut M) -> Result<Option<Win32Authenticode>> {
+        Win32Authenticode::read(mem, self.base)
+    }
+
+    /// Loads the pdb of the module from the given symbol store.
+    ///
+    /// Pdbs are cached per module and guid, this works for drivers (e.g. `win32k.sys`, `tcpip.sys`)
+    /// as well as user mode modules (e.g. `ntdll.dll`).
+    #[cfg(feature = "symstore")]
+    fn pdb<M: MemoryView>(&self, mem: &mut M, store: &SymbolStore) -> Result<Vec<u8>> {
+        store.load(&self.codeview(mem)?)
+    }
+
+    /// Compares the code of the module against its original binary from the given symbol store.
+    #[cfg(feature = "symstore")]
+    fn verify<M: MemoryView>(
+        &self,
+        mem: &mut M,
+        store: &SymbolStore,
+    ) -> Result<Win32ModuleVerification>;
+}
+
+impl Win32ModuleInfo for ModuleInfo {
+    fn codeview<M: MemoryView>(&self, mem: &mut M) -> Result<Win32Guid> {
+        trace!("reading codeview entry of {}", self.name);
+        ntos::find_guid(mem, self.base)
+    }
+
+    #[cfg(feature = "symstore")]
+    fn verify<M: MemoryView>(
+        &self,
+        mem: &mut M,
+        store: &SymbolStore,
+    ) -> Result<Win32ModuleVerification> {
+        verify_module(mem, self, store)
+    }
 
-const MAX_ITER_COUNT: usize = 65536;
+    fn version_info<M: MemoryView>(&self, mem: &mut M) -> Result<Win32VersionInfo> {
+        trace!("reading version resource of {}", self.name);
+        let resource = LazyPe::new(mem, self.base)?.version_resource()?;
+        Win32VersionInfo::parse(&resource)
+    }
+}
 
 #[derive(Debug, Clone, Copy)]
 #[repr(C)]
@@ -73,24 +161,10 @@ impl Win32ModuleListInfo {
         arch: ArchitectureIdent,
         mut callback: AddressCallback,
     ) -> Result<()> {
-        let list_start = self.module_base;
-        let mut list_entry = list_start;
-        let arch_obj = arch.into();
-        for _ in 0..MAX_ITER_COUNT {
-            if !callback.call(list_entry) {
-                break;
-            }
-            list_entry = mem.as_mut().read_addr_arch(arch_obj, list_entry)?;
-            // Break on misaligned entry. On NT 4.0 list end is misaligned, maybe it's a flag?
-            if list_entry.is_null()
-                || (list_entry.to_umem() & 0b111) != 0
-                || list_entry == self.module_base
-            {
-                break;
-            }
-        }
-
-        Ok(())
+        // Break on misaligned entry. On NT 4.0 list end is misaligned, maybe it's a flag?
+        Win32ListWalker::new(arch, self.module_base)
+            .alignment(8)
+            .walk(mem.as_mut(), |_, list_entry, _| callback.call(list_entry))
     }
 
     pub fn module_base_from_entry(
@@ -149,4 +223,94 @@ impl Win32ModuleListInfo {
             arch,
         })
     }
+
+    /// Reads the info of all modules in the list.
+    ///
+    /// Every `_LDR_DATA_TABLE_ENTRY` is fetched with a single read while walking the list.
+    /// The name buffers of all modules are resolved afterwards in a single vectored read.
+    pub fn module_info_list(
+        &self,
+        mem: &mut impl MemoryView,
+        parent_eprocess: Address,
+        arch: ArchitectureIdent,
+    ) -> Result<Vec<ModuleInfo>> {
+        let arch_obj = ArchitectureObj::from(arch);
+        let ptr_size = arch_obj.size_addr();
+        // the base name is the last field that is required
+        let entry_size = self.offsets.ldr_data_base_name + 2 * ptr_size;
+
+        let read_ptr = |buf: &[u8], offset: usize| -> Address {
+            match ptr_size {
+                8 => Address::from(u64::from_le_bytes(
+                    buf[offset..offset + 8].try_into().unwrap(),
+                )),
+                _ => Address::from(u32::from_le_bytes(
+                    buf[offset..offset + 4].try_into().unwrap(),
+                )),
+            }
+        };
+
+        // prefetch all entries in a single vectored read
+        let mut entries = Win32ListWalker::new(arch, self.module_base)
+            .alignment(8)
+            .entries(mem)?
+            .into_iter()
+            .map(|list_entry| (list_entry, vec![0u8; entry_size]))
+            .collect::<Vec<_>>();
+        {
+            let mut data = entries
+                .iter_mut()
+                .map(|(list_entry, entry)| CTup2(*list_entry, entry.as_mut_slice().into()))
+                .collect::<Vec<ReadData>>();
+            mem.read_raw_list(&mut data).data_part()?;
+        }
+
+        // resolve the full and base name of all entries
+        let strings = entries
+            .iter()
+            .flat_map(|(_, entry)| {
+                [
+                    self.offsets.ldr_data_full_name,
+                    self.offsets.ldr_data_base_name,
+                ]
+                .into_iter()
+                .map(move |offset| {
+                    // invalid headers are left empty and skipped by the vectored read
+                    Win32UnicodeString::from_bytes(arch_obj, &entry[offset..]).unwrap_or_default()
+                })
+            })
+            .collect::<Vec<_>>();
+        // names that could not be read are left empty
+        let mut names = mem
+            .read_unicode_string_buffers(arch_obj, &strings)
+            .into_iter()
+            .map(Option::unwrap_or_default);
+
+        let mut out = Vec::with_capacity(entries.len());
+        for (address, entry) in entries.iter() {
+            let (path, name) = (names.next().unwrap(), names.next().unwrap());
+            let base = read_ptr(entry, self.offsets.ldr_data_base);
+            let mut size = read_ptr(entry, self.offsets.ldr_data_size).to_umem();
+
+            // If size here is messed up, try to parse it from the module pe file
+            if size < 0x1000 {
+                if let Ok(new_size) = crate::kernel::ntos::pehelper::try_get_pe_size(mem, base) {
+                    size = new_size;
+                }
+            }
+
+            trace!("module base={:x} size={:x} name={}", base, size, name);
+            out.push(ModuleInfo {
+                address: *address,
+                parent_process: parent_eprocess,
+                base,
+                size,
+                path: path.into(),
+                name: name.into(),
+                arch,
+            });
+        }
+
+        Ok(out)
+    }
 }
@@ -0,0 +1,344 @@
+/*!
+Module for computing content hashes of loaded modules.
+
+The image of a module is reconstructed from the target's memory and hashed
+in a way that makes the result comparable with threat-intel databases.
+Besides plain MD5/SHA256 content hashes this also computes the
+[import hash](https://www.mandiant.com/resources/blog/tracking-malware-import-hashing)
+and the hash of the decoded rich header.
+
+# Examples:
+
+```
+use memflow::prelude::v1::*;
+use memflow_win32::win32::{Win32Process, Win32VirtualTranslate, Win32ModuleHashOptions};
+
+fn test<T: PhysicalMemory, V: VirtualTranslate2>(process: &mut Win32Process<T, V, Win32VirtualTranslate>) {
+    let module = process.primary_module().unwrap();
+    let hashes = process
+        .module_hashes_with_options(&module, Win32ModuleHashOptions::file_layout())
+        .unwrap();
+    println!("sha256: {}", hashes.sha256_hex());
+}
+```
+*/
+use std::prelude::v1::*;
+
+use super::Win32Process;
+
+use memflow::error::{Error, ErrorKind, ErrorOrigin, PartialResultExt, Result};
+use memflow::mem::{MemoryView, PhysicalMemory, VirtualTranslate2, VirtualTranslate3};
+use memflow::os::ModuleInfo;
+
+use log::trace;
+use std::convert::TryInto;
+
+use md5::{Digest, Md5};
+use pelite::{image::IMAGE_SCN_MEM_WRITE, pe64::imports::Import, PeView};
+use sha2::Sha256;
+
+/// Layout of the image that is used for hashing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize))]
+pub enum Win32ImageLayout {
+    /// Hash the image exactly as it is mapped in memory.
+    Memory,
+    /// Re-align all sections to their on-disk file offsets before hashing.
+    ///
+    /// This makes the hashes comparable to samples that were taken from disk
+    /// as long as the sections have not been modified after loading.
+    File,
+}
+
+/// Options controlling how a module image is normalized before it is hashed.
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize))]
+pub struct Win32ModuleHashOptions {
+    pub layout: Win32ImageLayout,
+    /// Zero out the contents of writable sections (e.g. `.data`) which are
+    /// usually modified at runtime.
+    pub zero_writable_sections: bool,
+}
+
+impl Default for Win32ModuleHashOptions {
+    fn default() -> Self {
+        Self {
+            layout: Win32ImageLayout::Memory,
+            zero_writable_sections: false,
+        }
+    }
+}
+
+impl Win32ModuleHashOptions {
+    /// Options that reconstruct the on-disk layout of the image.
+    pub fn file_layout() -> Self {
+        Self {
+            layout: Win32ImageLayout::File,
+            ..Default::default()
+        }
+    }
+
+    pub fn layout(mut self, layout: Win32ImageLayout) -> Self {
+        self.layout = layout;
+        self
+    }
+
+    pub fn zero_writable_sections(mut self, zero_writable_sections: bool) -> Self {
+        self.zero_writable_sections = zero_writable_sections;
+        self
+    }
+}
+
+/// Hashes of a single module.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize))]
+pub struct Win32ModuleHashes {
+    /// MD5 hash over the normalized image
+    pub md5: [u8; 16],
+    /// SHA256 hash over the normalized image
+    pub sha256: [u8; 32],
+    /// Import hash (imphash), `None` if the module does not have any imports
+    pub imphash: Option<[u8; 16]>,
+    /// MD5 hash over the decoded rich header, `None` if the module does not contain a rich header
+    pub rich_header_hash: Option<[u8; 16]>,
+}
+
+impl Win32ModuleHashes {
+    pub fn md5_hex(&self) -> String {
+        to_hex(&self.md5)
+    }
+
+    pub fn sha256_hex(&self) -> String {
+        to_hex(&self.sha256)
+    }
+
+    pub fn imphash_hex(&self) -> Option<String> {
+        self.imphash.as_ref().map(|h| to_hex(h))
+    }
+
+    pub fn rich_header_hash_hex(&self) -> Option<String> {
+        self.rich_header_hash.as_ref().map(|h| to_hex(h))
+    }
+}
+
+impl<T: PhysicalMemory, V: VirtualTranslate2, D: VirtualTranslate3> Win32Process<T, V, D> {
+    /// Computes the content-, import- and rich header hashes for the given module
+    /// with default normalization options.
+    pub fn module_hashes(&mut self, info: &ModuleInfo) -> Result<Win32ModuleHashes> {
+        self.module_hashes_with_options(info, Win32ModuleHashOptions::default())
+    }
+
+    /// Computes the content-, import- and rich header hashes for the given module.
+    pub fn module_hashes_with_options(
+        &mut self,
+        info: &ModuleInfo,
+        options: Win32ModuleHashOptions,
+    ) -> Result<Win32ModuleHashes> {
+        let size = info.size.try_into().map_err(|_| {
+            Error(ErrorOrigin::OsLayer, ErrorKind::InvalidArgument)
+                .log_info("module size exceeds the address space")
+        })?;
+        let image = self.virt_mem.read_raw(info.base, size).data_part()?;
+        module_hashes_from_image(&image, options)
+    }
+}
+
+/// Computes the module hashes from an image that was read in its memory layout.
+pub fn module_hashes_from_image(
+    image: &[u8],
+    options: Win32ModuleHashOptions,
+) -> Result<Win32ModuleHashes> {
+    let pe = PeView::from_bytes(image)
+        .map_err(|err| Error(ErrorOrigin::OsLayer, ErrorKind::InvalidExeFile).log_info(err))?;
+
+    let normalized = normalize_image(&pe, image, options);
+    trace!("normalized image size={:x}", normalized.len());
+
+    let mut md5 = [0u8; 16];
+    md5.copy_from_slice(&Md5::digest(&normalized));
+    let mut sha256 = [0u8; 32];
+    sha256.copy_from_slice(&Sha256::digest(&normalized));
+
+    Ok(Win32ModuleHashes {
+        md5,
+        sha256,
+        imphash: imphash(&pe),
+        rich_header_hash: rich_header_hash(&pe),
+    })
+}
+
+fn normalize_image(pe: &PeView, image: &[u8], options: Win32ModuleHashOptions) -> Vec<u8> {
+    let size_of_headers = match pe.optional_header() {
+        pelite::Wrap::T32(opt32) => opt32.SizeOfHeaders,
+        pelite::Wrap::T64(opt64) => opt64.SizeOfHeaders,
+    } as usize;
+
+    let mut out = match options.layout {
+        Win32ImageLayout::Memory => image.to_vec(),
+        Win32ImageLayout::File => {
+            let file_size = pe
+                .section_headers()
+                .iter()
+                .map(|s| s.file_range().end as usize)
+                .max()
+                .unwrap_or(0)
+                .max(size_of_headers);
+
+            let mut out = vec![0u8; file_size];
+            let headers = size_of_headers.min(image.len());
+            out[..headers].copy_from_slice(&image[..headers]);
+            out
+        }
+    };
+
+    for section in pe.section_headers().iter() {
+        let (dst_start, len) = match options.layout {
+            Win32ImageLayout::Memory => (
+                section.VirtualAddress as usize,
+                section.VirtualSize.max(section.SizeOfRawData) as usize,
+            ),
+            Win32ImageLayout::File => (
+                section.PointerToRawData as usize,
+                section.VirtualSize.min(section.SizeOfRawData) as usize,
+            ),
+        };
+
+        if options.zero_writable_sections && section.Characteristics & IMAGE_SCN_MEM_WRITE != 0 {
+            let end = (dst_start + len).min(out.len());
+            if dst_start < end {
+                out[dst_start..end].iter_mut().for_each(|b| *b = 0);
+            }
+        } else if options.layout == Win32ImageLayout::File {
+            let src_start = section.VirtualAddress as usize;
+            let len = len
+                .min(image.len().saturating_sub(src_start))
+                .min(out.len().saturating_sub(dst_start));
+            if len > 0 {
+                out[dst_start..dst_start + len].copy_from_slice(&image[src_start..src_start + len]);
+            }
+        }
+    }
+
+    out
+}
+
+fn imphash(pe: &PeView) -> Option<[u8; 16]> {
+    let imports = pe.imports().ok()?;
+
+    let mut entries = vec![];
+    for desc in imports {
+        let dll_name = match desc.dll_name().ok().and_then(|n| n.to_str().ok()) {
+            Some(n) => n.to_lowercase(),
+            None => continue,
+        };
+
+        // extensions are stripped the same way pefile does it
+        let lib = match dll_name.rsplit_once('.') {
+            Some((lib, "dll" | "ocx" | "sys")) => lib.to_string(),
+            _ => dll_name,
+        };
+
+        if let Ok(int) = desc.int() {
+            for import in int.filter_map(std::result::Result::ok) {
+                match import {
+                    Import::ByName { name, .. } => {
+                        if let Ok(name) = name.to_str() {
+                            entries.push(format!("{}.{}", lib, name.to_lowercase()));
+                        }
+                    }
+                    Import::ByOrdinal { ord } => entries.push(format!("{}.ord{}", lib, ord)),
+                }
+            }
+        }
+    }
+
+    if entries.is_empty() {
+        return None;
+    }
+
+    let mut out = [0u8; 16];
+    out.copy_from_slice(&Md5::digest(entries.join(",").as_bytes()));
+    Some(out)
+}
+
+fn rich_header_hash(pe: &PeView) -> Option<[u8; 16]> {
+    let rich = pe.rich_structure().ok()?;
+    let key = rich.xor_key();
+
+    // the encoded image ends with the 'Rich' marker followed by the xor key
+    let encoded = rich.image();
+    let decoded = encoded[..encoded.len().saturating_sub(2)]
+        .iter()
+        .flat_map(|d| (d ^ key).to_le_bytes())
+        .collect::<Vec<u8>>();
+
+    let mut out = [0u8; 16];
+    out.copy_from_slice(&Md5::digest(&decoded));
+    Some(out)
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A pe64 image without sections, imports or rich header.
+    fn minimal_image() -> Vec<u8> {
+        let mut image = vec![0u8; 0x200];
+        image[0..2].copy_from_slice(b"MZ");
+        image[0x3c..0x40].copy_from_slice(&0x40u32.to_le_bytes());
+        image[0x40..0x44].copy_from_slice(b"PE\0\0");
+
+        // IMAGE_FILE_HEADER
+        image[0x44..0x46].copy_from_slice(&0x8664u16.to_le_bytes());
+        image[0x54..0x56].copy_from_slice(&0xf0u16.to_le_bytes());
+        image[0x56..0x58].copy_from_slice(&0x22u16.to_le_bytes());
+
+        // IMAGE_OPTIONAL_HEADER64
+        let opt = 0x58;
+        image[opt..opt + 2].copy_from_slice(&0x20bu16.to_le_bytes());
+        image[opt + 0x20..opt + 0x24].copy_from_slice(&0x200u32.to_le_bytes());
+        image[opt + 0x24..opt + 0x28].copy_from_slice(&0x200u32.to_le_bytes());
+        image[opt + 0x38..opt + 0x3c].copy_from_slice(&0x200u32.to_le_bytes());
+        image[opt + 0x3c..opt + 0x40].copy_from_slice(&0x200u32.to_le_bytes());
+        image[opt + 0x6c..opt + 0x70].copy_from_slice(&16u32.to_le_bytes());
+
+        image
+    }
+
+    #[test]
+    fn hash_minimal_image() {
+        let hashes =
+            module_hashes_from_image(&minimal_image(), Win32ModuleHashOptions::default()).unwrap();
+
+        assert_eq!(hashes.md5_hex(), "0592850f5885ba5273277dec6a653656");
+        assert_eq!(
+            hashes.sha256_hex(),
+            "85b07fb2a826e6b3eb759f5a7e62f582bf10c7593dc44f8700e48cf12d08efa5"
+        );
+        assert_eq!(hashes.imphash, None);
+        assert_eq!(hashes.rich_header_hash, None);
+    }
+
+    #[test]
+    fn hash_file_layout_without_sections() {
+        let image = minimal_image();
+        let memory = module_hashes_from_image(&image, Win32ModuleHashOptions::default()).unwrap();
+        let file = module_hashes_from_image(&image, Win32ModuleHashOptions::file_layout()).unwrap();
+
+        // without sections the file layout only consists of the headers
+        assert_eq!(memory.sha256, file.sha256);
+    }
+
+    #[test]
+    fn hash_invalid_image() {
+        let mut image = minimal_image();
+        image[0..2].copy_from_slice(b"ZM");
+
+        let err = module_hashes_from_image(&image, Win32ModuleHashOptions::default()).unwrap_err();
+        assert_eq!(err.1, ErrorKind::InvalidExeFile);
+    }
+}
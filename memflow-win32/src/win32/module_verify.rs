@@ -0,0 +1,334 @@
+/*!
+Module for verifying the code of loaded modules against their original binaries.
+
+The binary of a module is fetched from the symbol store by the timestamp and the image size
+of the loaded module. It is mapped into its memory layout and relocated to the base address
+of the module. Afterwards the executable sections are compared to the target's memory,
+every difference is reported as a patch, e.g. an inline hook.
+
+Import address tables are excluded from the comparison. Note that the kernel legitimately
+modifies some of its own code at runtime (e.g. retpoline and import optimization),
+those changes are reported as patches as well.
+
+# Examples:
+
+```
+use memflow::prelude::v1::*;
+use memflow_win32::offsets::SymbolStore;
+use memflow_win32::win32::{Win32Kernel, Win32ModuleInfo};
+
+fn test<T: 'static + PhysicalMemory + Clone, V: 'static + VirtualTranslate2 + Clone>(
+    kernel: &mut Win32Kernel<T, V>,
+) {
+    let store = SymbolStore::default();
+    for module in kernel.module_list().unwrap() {
+        if let Ok(verification) = module.verify(kernel, &store) {
+            for patch in verification.patches.iter() {
+                println!("{} {}+{:x}", module.name, patch.section, patch.rva);
+            }
+        }
+    }
+}
+```
+*/
+use std::prelude::v1::*;
+
+use std::convert::TryInto;
+
+use crate::kernel::ntos::lazy_pe::LazyPe;
+use crate::offsets::SymbolStore;
+
+use log::{debug, trace};
+
+use memflow::error::{Error, ErrorKind, ErrorOrigin, PartialResultExt, Result};
+use memflow::mem::MemoryView;
+use memflow::os::ModuleInfo;
+use memflow::types::{umem, Address};
+
+use pelite::image::{IMAGE_SCN_MEM_DISCARDABLE, IMAGE_SCN_MEM_EXECUTE};
+use pelite::PeFile;
+
+const IMAGE_DIRECTORY_ENTRY_BASERELOC: usize = 5;
+const IMAGE_DIRECTORY_ENTRY_IAT: usize = 12;
+
+const IMAGE_REL_BASED_ABSOLUTE: u16 = 0;
+const IMAGE_REL_BASED_HIGHLOW: u16 = 3;
+const IMAGE_REL_BASED_DIR64: u16 = 10;
+
+/// Differences that are at most this many bytes apart are reported as a single patch
+const MERGE_DISTANCE: usize = 8;
+
+const PAGE_SIZE: usize = 0x1000;
+
+/// Range of code that differs from the original binary
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize))]
+pub struct Win32ModulePatch {
+    /// Name of the section that contains the patch
+    pub section: String,
+    pub rva: u32,
+    /// Bytes of the relocated binary
+    pub original: Vec<u8>,
+    /// Bytes in memory
+    pub current: Vec<u8>,
+}
+
+/// Result of the comparison of a loaded module with its original binary
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize))]
+pub struct Win32ModuleVerification {
+    pub patches: Vec<Win32ModulePatch>,
+    /// Number of bytes that were compared
+    pub compared_bytes: usize,
+    /// Number of pages that could not be read, e.g. because they are paged out
+    pub unreadable_pages: usize,
+}
+
+impl Win32ModuleVerification {
+    /// Returns true if the executable sections match the original binary.
+    pub fn is_intact(&self) -> bool {
+        self.patches.is_empty()
+    }
+}
+
+/// Fetches the binary of the module from the symbol store and compares its code against memory.
+pub fn verify_module<M: MemoryView>(
+    mem: &mut M,
+    module: &ModuleInfo,
+    store: &SymbolStore,
+) -> Result<Win32ModuleVerification> {
+    let headers = {
+        let pe = LazyPe::new(mem, module.base)?;
+        (pe.time_date_stamp(), pe.size_of_image())
+    };
+    let (time_date_stamp, size_of_image) = match headers {
+        (Some(time_date_stamp), Some(size_of_image)) => (time_date_stamp, size_of_image),
+        _ => {
+            return Err(Error(ErrorOrigin::OsLayer, ErrorKind::InvalidExeFile)
+                .log_info("unable to read the file header of the module"))
+        }
+    };
+
+    let file = store.load_binary(module.name.as_ref(), time_date_stamp, size_of_image)?;
+    verify_module_with_file(mem, module, &file)
+}
+
+/// Compares the code of a loaded module against the given binary.
+pub fn verify_module_with_file<M: MemoryView>(
+    mem: &mut M,
+    module: &ModuleInfo,
+    file: &[u8],
+) -> Result<Win32ModuleVerification> {
+    let pe = PeFile::from_bytes(file)
+        .map_err(|err| Error(ErrorOrigin::OsLayer, ErrorKind::InvalidExeFile).log_info(err))?;
+
+    let mut image = map_image(&pe, file);
+    let image_base = match pe.optional_header() {
+        pelite::Wrap::T32(opt32) => opt32.ImageBase as u64,
+        pelite::Wrap::T64(opt64) => opt64.ImageBase,
+    };
+    #[allow(clippy::unnecessary_cast)]
+    let base = module.base.to_umem() as u64;
+    relocate(&mut image, image_base, base);
+
+    let iat = data_directory(&image, IMAGE_DIRECTORY_ENTRY_IAT)
+        .map(|(rva, size)| rva as usize..rva as usize + size as usize);
+
+    let mut verification = Win32ModuleVerification::default();
+    let mut page = vec![0u8; PAGE_SIZE];
+    for section in pe.section_headers().iter().filter(|s| {
+        s.Characteristics & IMAGE_SCN_MEM_EXECUTE != 0
+            && s.Characteristics & IMAGE_SCN_MEM_DISCARDABLE == 0
+    }) {
+        let name = section.name().unwrap_or_default().to_string();
+        let start = section.VirtualAddress as usize;
+        let end =
+            (start + section.VirtualSize.min(section.SizeOfRawData) as usize).min(image.len());
+        trace!("comparing section {} ({:x}-{:x})", name, start, end);
+
+        let mut pending: Option<(usize, usize)> = None;
+        for page_start in (start..end).step_by(PAGE_SIZE) {
+            let page_end = (page_start + PAGE_SIZE).min(end);
+            let current = &mut page[..page_end - page_start];
+            if mem
+                .read_raw_into(module.base + page_start as umem, current)
+                .data_part()
+                .is_err()
+            {
+                verification.unreadable_pages += 1;
+                continue;
+            }
+            verification.compared_bytes += current.len();
+
+            for (i, (&a, &b)) in image[page_start..page_end]
+                .iter()
+                .zip(current.iter())
+                .enumerate()
+            {
+                let rva = page_start + i;
+                if a == b || iat.as_ref().map_or(false, |iat| iat.contains(&rva)) {
+                    continue;
+                }
+
+                pending = match pending {
+                    Some((patch_start, patch_end)) if rva - patch_end <= MERGE_DISTANCE => {
+                        Some((patch_start, rva + 1))
+                    }
+                    Some(patch) => {
+                        verification.patches.push(read_patch(
+                            mem,
+                            module.base,
+                            &image,
+                            &name,
+                            patch,
+                        ));
+                        Some((rva, rva + 1))
+                    }
+                    None => Some((rva, rva + 1)),
+                };
+            }
+        }
+
+        if let Some(patch) = pending {
+            verification
+                .patches
+                .push(read_patch(mem, module.base, &image, &name, patch));
+        }
+    }
+
+    debug!(
+        "found {} patches in {} ({} bytes compared, {} pages unreadable)",
+        verification.patches.len(),
+        module.name,
+        verification.compared_bytes,
+        verification.unreadable_pages
+    );
+    Ok(verification)
+}
+
+fn read_patch<M: MemoryView>(
+    mem: &mut M,
+    base: Address,
+    image: &[u8],
+    section: &str,
+    (start, end): (usize, usize),
+) -> Win32ModulePatch {
+    let mut current = vec![0u8; end - start];
+    mem.read_raw_into(base + start as umem, &mut current)
+        .data_part()
+        .ok();
+
+    Win32ModulePatch {
+        section: section.to_string(),
+        rva: start as u32,
+        original: image[start..end].to_vec(),
+        current,
+    }
+}
+
+/// Copies the headers and sections of the binary to their virtual addresses.
+fn map_image(pe: &PeFile, file: &[u8]) -> Vec<u8> {
+    let (size_of_image, size_of_headers) = match pe.optional_header() {
+        pelite::Wrap::T32(opt32) => (opt32.SizeOfImage, opt32.SizeOfHeaders),
+        pelite::Wrap::T64(opt64) => (opt64.SizeOfImage, opt64.SizeOfHeaders),
+    };
+
+    let mut image = vec![0u8; size_of_image as usize];
+    let headers = (size_of_headers as usize).min(file.len()).min(image.len());
+    image[..headers].copy_from_slice(&file[..headers]);
+
+    for section in pe.section_headers().iter() {
+        let src = section.PointerToRawData as usize;
+        let dst = section.VirtualAddress as usize;
+        let len = (section.SizeOfRawData as usize)
+            .min(file.len().saturating_sub(src))
+            .min(image.len().saturating_sub(dst));
+        if len > 0 {
+            image[dst..dst + len].copy_from_slice(&file[src..src + len]);
+        }
+    }
+
+    image
+}
+
+/// Applies the base relocations of the mapped image for the given load address.
+fn relocate(image: &mut [u8], image_base: u64, base: u64) {
+    let delta = base.wrapping_sub(image_base);
+    let (rva, size) = match data_directory(image, IMAGE_DIRECTORY_ENTRY_BASERELOC) {
+        Some(dir) if delta != 0 => dir,
+        _ => return,
+    };
+
+    let relocs = match image.get(rva as usize..rva as usize + size as usize) {
+        Some(relocs) => relocs.to_vec(),
+        None => return,
+    };
+
+    let mut offset = 0;
+    while let (Some(page), Some(block_size)) =
+        (read_u32(&relocs, offset), read_u32(&relocs, offset + 4))
+    {
+        let block_size = block_size as usize;
+        if block_size < 8 {
+            break;
+        }
+
+        for entry in relocs
+            .get(offset + 8..offset + block_size)
+            .unwrap_or_default()
+            .chunks_exact(2)
+        {
+            let entry = u16::from_le_bytes([entry[0], entry[1]]);
+            let target = page as usize + (entry & 0xfff) as usize;
+            match entry >> 12 {
+                IMAGE_REL_BASED_ABSOLUTE => {}
+                IMAGE_REL_BASED_HIGHLOW => {
+                    if let Some(value) = image.get_mut(target..target + 4) {
+                        let relocated = u32::from_le_bytes(value.try_into().unwrap())
+                            .wrapping_add(delta as u32);
+                        value.copy_from_slice(&relocated.to_le_bytes());
+                    }
+                }
+                IMAGE_REL_BASED_DIR64 => {
+                    if let Some(value) = image.get_mut(target..target + 8) {
+                        let relocated =
+                            u64::from_le_bytes(value.try_into().unwrap()).wrapping_add(delta);
+                        value.copy_from_slice(&relocated.to_le_bytes());
+                    }
+                }
+                ty => trace!("unsupported relocation type {}", ty),
+            }
+        }
+
+        offset += block_size;
+    }
+}
+
+/// Returns the rva and size of a data directory of the mapped image.
+fn data_directory(image: &[u8], index: usize) -> Option<(u32, u32)> {
+    let nt_headers = read_u32(image, 0x3c)? as usize;
+    let optional_header = nt_headers + 0x18;
+    let (data_directories, count) = match image.get(optional_header..optional_header + 2)? {
+        [0x0b, 0x01] => (
+            optional_header + 0x60,
+            read_u32(image, optional_header + 0x5c)?,
+        ),
+        [0x0b, 0x02] => (
+            optional_header + 0x70,
+            read_u32(image, optional_header + 0x6c)?,
+        ),
+        _ => return None,
+    };
+    if index >= count as usize {
+        return None;
+    }
+
+    let rva = read_u32(image, data_directories + index * 8)?;
+    let size = read_u32(image, data_directories + index * 8 + 4)?;
+    Some((rva, size)).filter(|&(rva, size)| rva != 0 && size != 0)
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> Option<u32> {
+    buf.get(offset..offset + 4)
+        .map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+}
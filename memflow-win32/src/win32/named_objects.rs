@@ -0,0 +1,241 @@
+/*!
+Module for enumerating named synchronization objects and sections.
+
+Named mutants, events, semaphores and sections are found by walking the object namespace
+starting at `ObpRootDirectoryObject`, subdirectories (e.g. `\Sessions\1\BaseNamedObjects`)
+are walked recursively. The type of an object is resolved through the `TypeIndex` of its
+`_OBJECT_HEADER`, which is obfuscated with `ObHeaderCookie` since nt 10.0.
+
+The processes that opened an object are found by walking the handle tables of all processes
+in the process list. For mutants the process of the owning thread is resolved as well.
+Unnamed objects and objects that are not linked into the namespace are not enumerated.
+
+Malware commonly creates mutexes with well known names to prevent running twice,
+which makes the names useful as indicators of compromise.
+
+All structures are resolved from the kernel pdb which is loaded from the symbol store.
+This module is only available with the `symstore` feature enabled.
+
+# Examples:
+
+```
+use memflow::prelude::v1::*;
+use memflow_win32::offsets::SymbolStore;
+use memflow_win32::win32::{Win32Kernel, Win32NamedObjectType};
+
+fn test<T: 'static + PhysicalMemory + Clone, V: 'static + VirtualTranslate2 + Clone>(
+    kernel: &mut Win32Kernel<T, V>,
+) {
+    for object in kernel.named_objects(&SymbolStore::default()).unwrap() {
+        if object.object_type == Win32NamedObjectType::Mutant {
+            let pids = object.handles.iter().map(|h| h.pid).collect::<Vec<_>>();
+            println!("{} opened by {:?}", object.name, pids);
+        }
+    }
+}
+```
+*/
+use std::prelude::v1::*;
+
+use std::collections::BTreeMap;
+
+use super::object_manager::MAX_DIRECTORY_DEPTH;
+use super::{Win32Kernel, Win32ObjectManager};
+use crate::offsets::SymbolStore;
+
+use log::{debug, trace};
+
+use memflow::error::Result;
+use memflow::mem::{MemoryView, PhysicalMemory, VirtualTranslate2};
+use memflow::os::{Os, Pid};
+use memflow::types::{umem, Address};
+
+/// Number of hash buckets of an `_OBJECT_DIRECTORY`
+const DIRECTORY_HASH_BUCKETS: usize = 37;
+/// Upper bound for the number of entries in a hash bucket
+const MAX_BUCKET_ENTRIES: usize = 0x1000;
+
+/// Type of a named object
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize))]
+pub enum Win32NamedObjectType {
+    Mutant,
+    Event,
+    Semaphore,
+    Section,
+}
+
+impl Win32NamedObjectType {
+    fn from_type_name(name: &str) -> Option<Self> {
+        match name {
+            "Mutant" => Some(Self::Mutant),
+            "Event" => Some(Self::Event),
+            "Semaphore" => Some(Self::Semaphore),
+            "Section" => Some(Self::Section),
+            _ => None,
+        }
+    }
+}
+
+/// A handle of a process to a named object
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize))]
+pub struct Win32ObjectHandle {
+    pub pid: Pid,
+    pub handle: u32,
+}
+
+/// A named object in the object namespace
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize))]
+pub struct Win32NamedObject {
+    /// Address of the object body
+    pub address: Address,
+    pub object_type: Win32NamedObjectType,
+    /// Full path in the object namespace, e.g. `\Sessions\1\BaseNamedObjects\Foo`
+    pub name: String,
+    /// Handles of the processes that opened the object
+    pub handles: Vec<Win32ObjectHandle>,
+    /// Process of the thread that owns a mutant, `None` for unowned mutants and other types
+    pub owner_pid: Option<Pid>,
+}
+
+impl<T: 'static + PhysicalMemory + Clone, V: 'static + VirtualTranslate2 + Clone>
+    Win32Kernel<T, V>
+{
+    /// Enumerates the named mutants, events, semaphores and sections of the object namespace
+    /// and the processes that have opened them.
+    ///
+    /// The kernel pdb is loaded from the given symbol store.
+    pub fn named_objects(&mut self, store: &SymbolStore) -> Result<Vec<Win32NamedObject>> {
+        let mut objects = self.object_manager(store)?;
+        let mutant_owner_thread = self
+            .kernel_types_from_store(store)?
+            .offset_of("_KMUTANT.OwnerThread")?;
+        let arch = objects.arch();
+
+        // object namespace
+        let mut out = vec![];
+        let root = objects.root_directory;
+        walk_directory(&mut objects, &mut self.virt_mem, root, "", 0, &mut out);
+        debug!("found {} named objects", out.len());
+
+        // owners of mutants
+        let procs = self.process_info_list()?;
+        let mut thread_pids = BTreeMap::new();
+        for proc in procs.iter() {
+            if let Ok(threads) = self.thread_address_list(proc.address) {
+                thread_pids.extend(threads.into_iter().map(|t| (t, proc.pid)));
+            }
+        }
+        for object in out
+            .iter_mut()
+            .filter(|o| o.object_type == Win32NamedObjectType::Mutant)
+        {
+            object.owner_pid = self
+                .virt_mem
+                .read_addr_arch(arch, object.address + mutant_owner_thread)
+                .ok()
+                .and_then(|thread| thread_pids.get(&thread).copied());
+        }
+
+        // handles
+        let index = out
+            .iter()
+            .enumerate()
+            .map(|(idx, object)| (object.address, idx))
+            .collect::<BTreeMap<_, _>>();
+        for proc in procs.iter() {
+            let handles = match objects.handles(&mut self.virt_mem, proc.address) {
+                Ok(handles) => handles,
+                Err(err) => {
+                    debug!("unable to read the handle table of {}: {}", proc.name, err);
+                    continue;
+                }
+            };
+            for entry in handles.into_iter() {
+                if let Some(&idx) = index.get(&entry.object) {
+                    out[idx].handles.push(Win32ObjectHandle {
+                        pid: proc.pid,
+                        handle: entry.handle,
+                    });
+                }
+            }
+        }
+
+        Ok(out)
+    }
+}
+
+/// Collects the named objects of a directory and its subdirectories.
+fn walk_directory<M: MemoryView>(
+    objects: &mut Win32ObjectManager,
+    mem: &mut M,
+    directory: Address,
+    path: &str,
+    depth: usize,
+    out: &mut Vec<Win32NamedObject>,
+) {
+    if depth >= MAX_DIRECTORY_DEPTH {
+        debug!("object directory {} is nested too deep", path);
+        return;
+    }
+
+    let arch = objects.arch();
+    let o = objects.offsets;
+    for bucket in 0..DIRECTORY_HASH_BUCKETS {
+        let mut entry = match mem.read_addr_arch(
+            arch,
+            directory + (o.directory_buckets + bucket * arch.size_addr()) as umem,
+        ) {
+            Ok(entry) => entry,
+            Err(_) => continue,
+        };
+
+        for _ in 0..MAX_BUCKET_ENTRIES {
+            if entry.is_null() {
+                break;
+            }
+
+            if let Ok(object) = mem.read_addr_arch(arch, entry + o.directory_entry_object) {
+                visit_object(objects, mem, object, path, depth, out);
+            }
+
+            entry = match mem.read_addr_arch(arch, entry + o.directory_entry_chain_link) {
+                Ok(next) => next,
+                Err(_) => break,
+            };
+        }
+    }
+}
+
+fn visit_object<M: MemoryView>(
+    objects: &mut Win32ObjectManager,
+    mem: &mut M,
+    object: Address,
+    path: &str,
+    depth: usize,
+    out: &mut Vec<Win32NamedObject>,
+) {
+    let (type_name, name) = match (
+        objects.object_type_name(mem, object),
+        objects.object_name(mem, object),
+    ) {
+        (Some(type_name), Some(name)) => (type_name, name),
+        _ => return,
+    };
+    let name = format!("{}\\{}", path, name);
+
+    if type_name == "Directory" {
+        walk_directory(objects, mem, object, &name, depth + 1, out);
+    } else if let Some(object_type) = Win32NamedObjectType::from_type_name(&type_name) {
+        trace!("{} {}", type_name, name);
+        out.push(Win32NamedObject {
+            address: object,
+            object_type,
+            name,
+            handles: vec![],
+            owner_pid: None,
+        });
+    }
+}
@@ -0,0 +1,406 @@
+/*!
+Module for inspecting kernel objects and the handle tables of processes.
+
+Every kernel object is preceded by an `_OBJECT_HEADER`. The type of an object is resolved
+through the `TypeIndex` of its header, which is obfuscated with `ObHeaderCookie` since nt 10.0.
+The name of an object is stored in the optional `_OBJECT_HEADER_NAME_INFO` that precedes the
+header, it also references the object directory that contains the object.
+
+The handle table of a process is referenced by `_EPROCESS.ObjectTable` and consists of up to
+three levels of pages. Each entry contains the object header and the granted access mask,
+the encoding of both changed with nt 6.2.
+
+All structures are resolved from the kernel pdb which is loaded from the symbol store.
+This module is only available with the `symstore` feature enabled.
+
+# Examples:
+
+```
+use memflow::prelude::v1::*;
+use memflow_win32::offsets::SymbolStore;
+use memflow_win32::win32::Win32Kernel;
+
+fn test<T: 'static + PhysicalMemory + Clone, V: 'static + VirtualTranslate2 + Clone>(
+    kernel: &mut Win32Kernel<T, V>,
+) {
+    let mut objects = kernel.object_manager(&SymbolStore::default()).unwrap();
+    let eprocess = kernel.kernel_info.eprocess_base;
+    for handle in objects.handles(&mut kernel.virt_mem, eprocess).unwrap() {
+        let name = objects.object_path(&mut kernel.virt_mem, handle.object);
+        println!("{:x} {} {:?}", handle.handle, handle.type_name, name);
+    }
+}
+```
+*/
+use std::prelude::v1::*;
+
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+
+use super::{VirtualReadUnicodeString, Win32Kernel, Win32KernelTypes};
+use crate::offsets::SymbolStore;
+
+use memflow::architecture::ArchitectureObj;
+use memflow::error::{PartialResultExt, Result};
+use memflow::mem::{MemoryView, PhysicalMemory, VirtualTranslate2};
+use memflow::types::Address;
+
+/// Upper bound for the number of level 0 pages of a handle table
+const MAX_HANDLE_TABLE_PAGES: usize = 0x1000;
+const HANDLE_TABLE_PAGE_SIZE: usize = 0x1000;
+/// Upper bound for the depth of the object namespace
+pub(crate) const MAX_DIRECTORY_DEPTH: usize = 16;
+
+/// `OB_INFOMASK_CREATOR_INFO`
+const INFOMASK_CREATOR_INFO: u8 = 0x1;
+/// `OB_INFOMASK_NAME`
+const INFOMASK_NAME: u8 = 0x2;
+
+/// An entry of the handle table of a process
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize))]
+pub struct Win32HandleEntry {
+    pub handle: u32,
+    /// Address of the object body
+    pub object: Address,
+    pub granted_access: u32,
+    /// Name of the object type, e.g. `File` or `Process`, empty if it could not be resolved
+    pub type_name: String,
+}
+
+/// Offsets of the object manager structures
+#[derive(Clone, Copy)]
+pub(crate) struct ObjectOffsets {
+    pub header_body: usize,
+    header_type_index: usize,
+    header_info_mask: usize,
+    name_info_size: usize,
+    name_info_directory: usize,
+    name_info_name: usize,
+    creator_info_size: usize,
+    type_name: usize,
+    pub directory_buckets: usize,
+    pub directory_entry_chain_link: usize,
+    pub directory_entry_object: usize,
+    eproc_object_table: usize,
+    pub file_object_device_object: usize,
+    pub file_object_file_name: usize,
+    pub file_object_current_byte_offset: usize,
+}
+
+impl ObjectOffsets {
+    fn new(types: &mut Win32KernelTypes) -> Result<Self> {
+        Ok(Self {
+            header_body: types.offset_of("_OBJECT_HEADER.Body")?,
+            header_type_index: types.offset_of("_OBJECT_HEADER.TypeIndex")?,
+            header_info_mask: types.offset_of("_OBJECT_HEADER.InfoMask")?,
+            name_info_size: types.struct_size("_OBJECT_HEADER_NAME_INFO")?,
+            name_info_directory: types.offset_of("_OBJECT_HEADER_NAME_INFO.Directory")?,
+            name_info_name: types.offset_of("_OBJECT_HEADER_NAME_INFO.Name")?,
+            creator_info_size: types.struct_size("_OBJECT_HEADER_CREATOR_INFO")?,
+            type_name: types.offset_of("_OBJECT_TYPE.Name")?,
+            directory_buckets: types.offset_of("_OBJECT_DIRECTORY.HashBuckets")?,
+            directory_entry_chain_link: types.offset_of("_OBJECT_DIRECTORY_ENTRY.ChainLink")?,
+            directory_entry_object: types.offset_of("_OBJECT_DIRECTORY_ENTRY.Object")?,
+            eproc_object_table: types.offset_of("_EPROCESS.ObjectTable")?,
+            file_object_device_object: types.offset_of("_FILE_OBJECT.DeviceObject")?,
+            file_object_file_name: types.offset_of("_FILE_OBJECT.FileName")?,
+            file_object_current_byte_offset: types.offset_of("_FILE_OBJECT.CurrentByteOffset")?,
+        })
+    }
+}
+
+/// Layout of the handle table entries
+#[derive(Clone, Copy)]
+struct HandleTableOffsets {
+    table_code: usize,
+    entry_size: usize,
+    /// Bit offset and length of the object pointer in an entry since nt 6.2
+    object_pointer_bits: Option<(usize, usize)>,
+    /// Offset, bit offset and bit length of the granted access mask in an entry
+    granted_access: (usize, usize, usize),
+}
+
+impl HandleTableOffsets {
+    fn new(types: &mut Win32KernelTypes) -> Result<Self> {
+        let granted_access = match types.field("_HANDLE_TABLE_ENTRY.GrantedAccessBits") {
+            Ok(field) => (field.offset, field.bit_offset, field.bit_length),
+            Err(_) => (types.offset_of("_HANDLE_TABLE_ENTRY.GrantedAccess")?, 0, 32),
+        };
+
+        Ok(Self {
+            table_code: types.offset_of("_HANDLE_TABLE.TableCode")?,
+            entry_size: types.struct_size("_HANDLE_TABLE_ENTRY")?,
+            object_pointer_bits: types
+                .field("_HANDLE_TABLE_ENTRY.ObjectPointerBits")
+                .ok()
+                .filter(|field| field.bit_length > 0)
+                .map(|field| (field.bit_offset, field.bit_length)),
+            granted_access,
+        })
+    }
+}
+
+/// Resolves the types and names of kernel objects and walks handle tables.
+///
+/// The resolved object types are cached, the object manager should therefore be reused
+/// for multiple lookups.
+pub struct Win32ObjectManager {
+    arch: ArchitectureObj,
+    type_table: Address,
+    header_cookie: Option<u8>,
+    type_names: BTreeMap<u8, String>,
+    pub(crate) root_directory: Address,
+    pub(crate) offsets: ObjectOffsets,
+    handle_table: HandleTableOffsets,
+}
+
+impl<T: 'static + PhysicalMemory + Clone, V: 'static + VirtualTranslate2 + Clone>
+    Win32Kernel<T, V>
+{
+    /// Creates an object manager for the running kernel.
+    ///
+    /// The kernel pdb is loaded from the given symbol store.
+    pub fn object_manager(&mut self, store: &SymbolStore) -> Result<Win32ObjectManager> {
+        let symbols = self.kernel_symbols_from_store(store)?;
+        let mut types = self.kernel_types_from_store(store)?;
+        let arch: ArchitectureObj = self.kernel_info.os_info.arch.into();
+
+        Ok(Win32ObjectManager {
+            arch,
+            type_table: symbols.address("ObTypeIndexTable")?,
+            // the type index is obfuscated since nt 10.0
+            header_cookie: match symbols.address("ObHeaderCookie") {
+                Ok(cookie) => Some(self.virt_mem.read(cookie)?),
+                Err(_) => None,
+            },
+            type_names: BTreeMap::new(),
+            root_directory: self
+                .virt_mem
+                .read_addr_arch(arch, symbols.address("ObpRootDirectoryObject")?)?,
+            offsets: ObjectOffsets::new(&mut types)?,
+            handle_table: HandleTableOffsets::new(&mut types)?,
+        })
+    }
+}
+
+impl Win32ObjectManager {
+    pub(crate) fn arch(&self) -> ArchitectureObj {
+        self.arch
+    }
+
+    /// Resolves the name of the type of an object.
+    pub fn object_type_name<M: MemoryView>(
+        &mut self,
+        mem: &mut M,
+        object: Address,
+    ) -> Option<String> {
+        let header = object - self.offsets.header_body;
+        let mut type_index = mem
+            .read::<u8>(header + self.offsets.header_type_index)
+            .ok()?;
+        if let Some(cookie) = self.header_cookie {
+            type_index ^= cookie ^ (header.to_umem() >> 8) as u8;
+        }
+
+        if !self.type_names.contains_key(&type_index) {
+            let object_type = mem
+                .read_addr_arch(
+                    self.arch,
+                    self.type_table + type_index as usize * self.arch.size_addr(),
+                )
+                .ok()?;
+            let name = mem
+                .read_unicode_string(self.arch, object_type + self.offsets.type_name)
+                .ok()?;
+            self.type_names.insert(type_index, name);
+        }
+        self.type_names.get(&type_index).cloned()
+    }
+
+    /// Reads the name of an object, `None` if the object is unnamed.
+    pub fn object_name<M: MemoryView>(&self, mem: &mut M, object: Address) -> Option<String> {
+        let name_info = self.name_info(mem, object)?;
+        mem.read_unicode_string(self.arch, name_info + self.offsets.name_info_name)
+            .ok()
+    }
+
+    /// Returns the full path of an object in the object namespace, e.g. `\Device\HarddiskVolume3`.
+    ///
+    /// Objects that are not linked into the namespace only return their name.
+    pub fn object_path<M: MemoryView>(&self, mem: &mut M, object: Address) -> Option<String> {
+        let mut path = self.object_name(mem, object)?;
+
+        let mut object = object;
+        for _ in 0..MAX_DIRECTORY_DEPTH {
+            let directory = match self.name_info(mem, object).and_then(|name_info| {
+                mem.read_addr_arch(self.arch, name_info + self.offsets.name_info_directory)
+                    .ok()
+            }) {
+                Some(directory) if !directory.is_null() => directory,
+                _ => break,
+            };
+            // the root directory is named `\`
+            if directory == self.root_directory {
+                break;
+            }
+            path = format!("{}\\{}", self.object_name(mem, directory)?, path);
+            object = directory;
+        }
+
+        Some(format!("\\{}", path))
+    }
+
+    /// Returns the address of the name info header of an object.
+    fn name_info<M: MemoryView>(&self, mem: &mut M, object: Address) -> Option<Address> {
+        let header = object - self.offsets.header_body;
+        let info_mask = mem
+            .read::<u8>(header + self.offsets.header_info_mask)
+            .ok()?;
+        if info_mask & INFOMASK_NAME == 0 {
+            return None;
+        }
+
+        // the optional headers precede the object header in the order of their mask bits
+        let mut name_info = header - self.offsets.name_info_size;
+        if info_mask & INFOMASK_CREATOR_INFO != 0 {
+            name_info = name_info - self.offsets.creator_info_size;
+        }
+        Some(name_info)
+    }
+
+    /// Returns all used entries of the handle table of a process.
+    ///
+    /// Since the handle table is located in kernel space the memory of the kernel
+    /// as well as the memory of any process can be used.
+    pub fn handles<M: MemoryView>(
+        &mut self,
+        mem: &mut M,
+        eprocess: Address,
+    ) -> Result<Vec<Win32HandleEntry>> {
+        let handle_table =
+            mem.read_addr_arch(self.arch, eprocess + self.offsets.eproc_object_table)?;
+        if handle_table.is_null() {
+            return Ok(vec![]);
+        }
+
+        let mut out = vec![];
+        for (handle, entry) in self.handle_table_entries(mem, handle_table)? {
+            let object = self.decode_object(&entry);
+            if object.is_null() {
+                continue;
+            }
+            let object = object + self.offsets.header_body;
+            out.push(Win32HandleEntry {
+                handle,
+                object,
+                granted_access: self.decode_granted_access(&entry),
+                type_name: self.object_type_name(mem, object).unwrap_or_default(),
+            });
+        }
+
+        Ok(out)
+    }
+
+    /// Returns the handle values and the raw entries of a handle table.
+    fn handle_table_entries<M: MemoryView>(
+        &self,
+        mem: &mut M,
+        handle_table: Address,
+    ) -> Result<Vec<(u32, Vec<u8>)>> {
+        let table_code = mem
+            .read_addr_arch(self.arch, handle_table + self.handle_table.table_code)?
+            .to_umem();
+        let levels = (table_code & 0x3) as usize;
+        let table = Address::from(table_code & !0x3);
+
+        // resolve the level 0 pages
+        let mut pages = vec![table];
+        for _ in 0..levels {
+            let mut next = vec![];
+            for page in pages.into_iter() {
+                next.extend(
+                    self.read_page(mem, page)?
+                        .into_iter()
+                        .take_while(|ptr| !ptr.is_null()),
+                );
+                if next.len() > MAX_HANDLE_TABLE_PAGES {
+                    break;
+                }
+            }
+            pages = next;
+        }
+        pages.truncate(MAX_HANDLE_TABLE_PAGES);
+
+        let entry_size = self.handle_table.entry_size;
+        let entries_per_page = HANDLE_TABLE_PAGE_SIZE / entry_size;
+        let mut out = vec![];
+        let mut buf = vec![0u8; HANDLE_TABLE_PAGE_SIZE];
+        for (page_idx, page) in pages.into_iter().enumerate() {
+            if mem.read_raw_into(page, &mut buf).data_part().is_err() {
+                continue;
+            }
+            // the first entry of every page is reserved
+            for (idx, entry) in buf.chunks_exact(entry_size).enumerate().skip(1) {
+                if entry.iter().any(|&b| b != 0) {
+                    let handle = ((page_idx * entries_per_page + idx) * 4) as u32;
+                    out.push((handle, entry.to_vec()));
+                }
+            }
+        }
+
+        Ok(out)
+    }
+
+    /// Reads a page of pointers of an upper level of a handle table.
+    fn read_page<M: MemoryView>(&self, mem: &mut M, page: Address) -> Result<Vec<Address>> {
+        let mut buf = vec![0u8; HANDLE_TABLE_PAGE_SIZE];
+        mem.read_raw_into(page, &mut buf).data_part()?;
+        Ok(buf
+            .chunks_exact(self.arch.size_addr())
+            .map(|ptr| Address::from(self.read_ptr(ptr)))
+            .collect())
+    }
+
+    /// Returns the object header an entry points to.
+    fn decode_object(&self, entry: &[u8]) -> Address {
+        let value = self.read_ptr(entry);
+        if value == 0 {
+            return Address::null();
+        }
+
+        match self.handle_table.object_pointer_bits {
+            Some((offset, len)) => {
+                let va_bits = if self.arch.size_addr() == 8 { 48 } else { 32 };
+                let ptr = ((value >> offset) & ((1u64 << len) - 1)) << (va_bits - len);
+                if self.arch.size_addr() == 8 {
+                    Address::from(ptr | 0xffff_0000_0000_0000)
+                } else {
+                    Address::from(ptr)
+                }
+            }
+            // the low bits contain the lock and the audit flags
+            None => Address::from(value & !0x7),
+        }
+    }
+
+    fn decode_granted_access(&self, entry: &[u8]) -> u32 {
+        let (offset, bit_offset, bit_length) = self.handle_table.granted_access;
+        let value = match entry.get(offset..offset + 4) {
+            Some(buf) => u32::from_le_bytes(buf.try_into().unwrap()),
+            None => return 0,
+        };
+        if bit_length >= 32 {
+            value
+        } else {
+            (value >> bit_offset) & ((1 << bit_length) - 1)
+        }
+    }
+
+    fn read_ptr(&self, buf: &[u8]) -> u64 {
+        match self.arch.size_addr() {
+            8 => u64::from_le_bytes(buf[..8].try_into().unwrap()),
+            _ => u32::from_le_bytes(buf[..4].try_into().unwrap()) as u64,
+        }
+    }
+}
@@ -0,0 +1,113 @@
+/*!
+Module for listing the files a process has opened.
+
+The handle table of the process is walked with the [`Win32ObjectManager`] and every handle
+to a `File` object is resolved to its `_FILE_OBJECT`. The full path of a file is the name of
+the device the file object belongs to (e.g. `\Device\HarddiskVolume3`) followed by the
+`FileName` of the file object.
+
+Handles to devices, pipes and sockets are listed as well, their file name is usually empty
+or relative to the device.
+
+This module is only available with the `symstore` feature enabled.
+
+# Examples:
+
+```
+use memflow::prelude::v1::*;
+use memflow_win32::offsets::SymbolStore;
+use memflow_win32::win32::Win32Kernel;
+
+fn test<T: 'static + PhysicalMemory + Clone, V: 'static + VirtualTranslate2 + Clone>(
+    kernel: &mut Win32Kernel<T, V>,
+) {
+    let mut objects = kernel.object_manager(&SymbolStore::default()).unwrap();
+    let mut process = kernel.process_by_name("explorer.exe").unwrap();
+    for file in process.open_files(&mut objects).unwrap() {
+        println!("{:x} {:x} {}", file.handle, file.granted_access, file.path);
+    }
+}
+```
+*/
+use std::prelude::v1::*;
+
+use super::{VirtualReadUnicodeString, Win32ObjectManager, Win32Process};
+
+use log::trace;
+
+use memflow::error::Result;
+use memflow::mem::{MemoryView, PhysicalMemory, VirtualTranslate2, VirtualTranslate3};
+use memflow::types::Address;
+
+/// A file opened by a process
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize))]
+pub struct Win32OpenFile {
+    pub handle: u32,
+    /// Address of the `_FILE_OBJECT`
+    pub object: Address,
+    pub granted_access: u32,
+    /// Path of the device in the object namespace, `None` if the device is unnamed
+    pub device_name: Option<String>,
+    /// Path of the file relative to the device
+    pub file_name: String,
+    /// Full path consisting of the device name and the file name
+    pub path: String,
+    /// Current position of the file pointer
+    pub current_byte_offset: u64,
+}
+
+impl<T: PhysicalMemory, V: VirtualTranslate2, D: VirtualTranslate3> Win32Process<T, V, D> {
+    /// Lists the files this process has opened.
+    ///
+    /// The object manager has to be created by the kernel this process belongs to.
+    /// File objects that cannot be read are skipped.
+    pub fn open_files(&mut self, objects: &mut Win32ObjectManager) -> Result<Vec<Win32OpenFile>> {
+        let arch = objects.arch();
+        let offsets = objects.offsets;
+        let eprocess = self.proc_info.base_info.address;
+
+        let mut out = vec![];
+        for entry in objects.handles(&mut self.virt_mem, eprocess)? {
+            if entry.type_name != "File" {
+                continue;
+            }
+
+            let file_name = match self
+                .virt_mem
+                .read_unicode_string(arch, entry.object + offsets.file_object_file_name)
+            {
+                Ok(file_name) => file_name,
+                Err(_) => continue,
+            };
+            let device_name = self
+                .virt_mem
+                .read_addr_arch(arch, entry.object + offsets.file_object_device_object)
+                .ok()
+                .filter(|device| !device.is_null())
+                .and_then(|device| objects.object_path(&mut self.virt_mem, device));
+            let current_byte_offset = self
+                .virt_mem
+                .read(entry.object + offsets.file_object_current_byte_offset)
+                .unwrap_or_default();
+
+            let path = match device_name.as_ref() {
+                Some(device_name) => format!("{}{}", device_name, file_name),
+                None => file_name.clone(),
+            };
+            trace!("{:x} {}", entry.handle, path);
+
+            out.push(Win32OpenFile {
+                handle: entry.handle,
+                object: entry.object,
+                granted_access: entry.granted_access,
+                device_name,
+                file_name,
+                path,
+                current_byte_offset,
+            });
+        }
+
+        Ok(out)
+    }
+}
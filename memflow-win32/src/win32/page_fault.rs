@@ -0,0 +1,174 @@
+/*!
+Module for diagnosing why a virtual address cannot be read.
+
+When a read fails the paging structures of the address are walked and the
+software pte of the page is decoded to figure out where the page currently resides.
+Pagefile and compressed pages are only classified correctly on Windows 10 1803 and newer x64
+kernels if the format carries the invalid pte mask of the kernel,
+see [`Win32Kernel::software_pte_format`](super::Win32Kernel::software_pte_format).
+
+# Examples:
+
+```
+use memflow::prelude::v1::*;
+use memflow_win32::win32::{Win32Kernel, Win32Process, Win32VirtualTranslate};
+
+fn test<T: 'static + PhysicalMemory + Clone, V: 'static + VirtualTranslate2 + Clone>(
+    kernel: &mut Win32Kernel<T, V>,
+    process: &mut Win32Process<T, V, Win32VirtualTranslate>,
+) {
+    let format = kernel.software_pte_format().unwrap();
+    let addr = Address::from(0x10000);
+    if process.read::<u64>(addr).is_err() {
+        let fault = process.page_fault_info(addr, format).unwrap();
+        println!("{:x}: {:?}", addr, fault.kind);
+    }
+}
+```
+*/
+use std::prelude::v1::*;
+
+use super::{
+    Win32PageTableEntry, Win32PageTableLevel, Win32PagefileLocation, Win32Process,
+    Win32SoftwarePteFormat, Win32VirtualTranslate,
+};
+
+use memflow::error::Result;
+use memflow::mem::{PhysicalMemory, VirtualTranslate2};
+use memflow::types::Address;
+
+// protection values of the memory manager (_MMPTE_SOFTWARE::Protection)
+const MM_PROTECTION_MASK: u64 = 0x1f;
+const MM_GUARD_PAGE: u64 = 0x10;
+// decommitted pages are marked as guard pages without any access rights
+const MM_DECOMMIT: u64 = MM_GUARD_PAGE;
+const MM_NOACCESS: u64 = 0x18;
+
+/// Describes where a page that is not accessible currently resides
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize))]
+pub enum Win32PageFaultKind {
+    /// The page is valid and can be read
+    Valid,
+    /// One of the paging structures above the pte is not present
+    TableNotPresent(Win32PageTableLevel),
+    /// The page has never been committed or has been decommitted
+    NotCommitted,
+    /// The page has been committed but not accessed yet and will be zeroed on first access
+    DemandZero,
+    /// The page is a guard page
+    Guard,
+    /// The page is committed with `PAGE_NOACCESS`
+    NoAccess,
+    /// The page is still resident but has been removed from the working set
+    Transition(Address),
+    /// The pte points to a prototype pte, `None` if the prototype pte is located via the vad
+    Prototype(Option<Address>),
+    /// The page has been written to a pagefile
+    Pagefile(Win32PagefileLocation),
+    /// The page is held in the memory compression store
+    Compressed(Win32PagefileLocation),
+}
+
+/// Result of a page fault diagnosis
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize))]
+pub struct Win32PageFault {
+    pub virt_addr: Address,
+    /// The last paging structure entry that was walked
+    pub entry: Option<Win32PageTableEntry>,
+    pub kind: Win32PageFaultKind,
+}
+
+impl Win32VirtualTranslate {
+    /// Diagnoses why the given virtual address cannot be accessed.
+    ///
+    /// `virtual_store` is the number of the virtual store pagefile that backs the
+    /// memory compression store (if known). Pagefile entries referencing it are reported as compressed.
+    pub fn page_fault_info<T: PhysicalMemory + ?Sized>(
+        &self,
+        mem: &mut T,
+        virt_addr: Address,
+        format: Win32SoftwarePteFormat,
+        virtual_store: Option<u32>,
+    ) -> Result<Win32PageFault> {
+        let info = self.pte_info(mem, virt_addr)?;
+        let entry = info.last_entry().copied();
+
+        let kind = match entry {
+            _ if info.phys_addr.is_some() => Win32PageFaultKind::Valid,
+            None => Win32PageFaultKind::TableNotPresent(Win32PageTableLevel::Pml4e),
+            Some(e) if e.level != Win32PageTableLevel::Pte => {
+                if e.value == 0 {
+                    Win32PageFaultKind::NotCommitted
+                } else {
+                    Win32PageFaultKind::TableNotPresent(e.level)
+                }
+            }
+            Some(e) => classify_software_pte(self, &e, format, virtual_store),
+        };
+
+        Ok(Win32PageFault {
+            virt_addr,
+            entry,
+            kind,
+        })
+    }
+}
+
+fn classify_software_pte(
+    vat: &Win32VirtualTranslate,
+    entry: &Win32PageTableEntry,
+    format: Win32SoftwarePteFormat,
+    virtual_store: Option<u32>,
+) -> Win32PageFaultKind {
+    if entry.value == 0 {
+        return Win32PageFaultKind::NotCommitted;
+    }
+
+    if entry.flags.transition {
+        return Win32PageFaultKind::Transition(entry.pfn_address());
+    }
+
+    if entry.flags.prototype {
+        return Win32PageFaultKind::Prototype(vat.prototype_pte_address(entry.value));
+    }
+
+    if let Some(location) = Win32PagefileLocation::from_software_pte(entry.value, format) {
+        return if Some(location.page_file_number) == virtual_store {
+            Win32PageFaultKind::Compressed(location)
+        } else {
+            Win32PageFaultKind::Pagefile(location)
+        };
+    }
+
+    match (entry.value >> 5) & MM_PROTECTION_MASK {
+        MM_DECOMMIT => Win32PageFaultKind::NotCommitted,
+        MM_NOACCESS => Win32PageFaultKind::NoAccess,
+        protection if protection & MM_NOACCESS == MM_GUARD_PAGE => Win32PageFaultKind::Guard,
+        _ => Win32PageFaultKind::DemandZero,
+    }
+}
+
+impl<T: PhysicalMemory, V: VirtualTranslate2> Win32Process<T, V, Win32VirtualTranslate> {
+    /// Diagnoses why the given virtual address cannot be read.
+    pub fn page_fault_info(
+        &mut self,
+        virt_addr: Address,
+        format: Win32SoftwarePteFormat,
+    ) -> Result<Win32PageFault> {
+        self.page_fault_info_with_virtual_store(virt_addr, format, None)
+    }
+
+    /// Diagnoses why the given virtual address cannot be read and reports pages
+    /// in the given virtual store pagefile as compressed.
+    pub fn page_fault_info_with_virtual_store(
+        &mut self,
+        virt_addr: Address,
+        format: Win32SoftwarePteFormat,
+        virtual_store: Option<u32>,
+    ) -> Result<Win32PageFault> {
+        let translator = *self.virt_mem.translator();
+        translator.page_fault_info(self.virt_mem.phys_mem(), virt_addr, format, virtual_store)
+    }
+}
@@ -0,0 +1,186 @@
+/*!
+Module for recording the physical pages touched during an analysis.
+
+Wrapping the connector in a [`Win32PageSetRecorder`] records every physical page that is read
+while the kernel is initialized and while processes, modules and other structures are enumerated.
+The resulting page set can be written out as a manifest and the very same pages can be acquired
+from the target. Since the results only depend on the recorded pages they can later be
+re-verified independently from the captured evidence.
+
+# Examples:
+
+```
+use memflow::prelude::v1::*;
+use memflow_win32::win32::{Win32KernelBuilder, Win32PageSetRecorder};
+
+fn test<T: 'static + PhysicalMemory + Clone>(connector: T) {
+    let recorder = Win32PageSetRecorder::new(connector);
+    let page_set = recorder.page_set();
+
+    let mut kernel = Win32KernelBuilder::new(recorder).build().unwrap();
+    let _processes = kernel.process_info_list().unwrap();
+
+    let mut manifest = vec![];
+    page_set.write_manifest(&mut manifest).unwrap();
+}
+```
+*/
+use std::prelude::v1::*;
+
+use std::collections::BTreeSet;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+
+use memflow::cglue::tuple::*;
+use memflow::error::{Error, ErrorKind, ErrorOrigin, Result};
+use memflow::mem::mem_data::MemOps;
+use memflow::mem::{
+    PhysicalMemory, PhysicalMemoryMapping, PhysicalMemoryMetadata, PhysicalReadMemOps,
+    PhysicalWriteMemOps,
+};
+use memflow::types::{umem, Address, PhysicalAddress};
+
+const PAGE_SIZE: umem = 0x1000;
+
+/// Set of physical pages shared between a recorder and its clones
+#[derive(Debug, Clone, Default)]
+pub struct Win32PageSet {
+    pages: Arc<Mutex<BTreeSet<umem>>>,
+}
+
+impl Win32PageSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn record(&self, addr: PhysicalAddress, len: usize) {
+        if len == 0 {
+            return;
+        }
+
+        let start = addr.address().to_umem() & !(PAGE_SIZE - 1);
+        let end = addr.address().to_umem() + len as umem;
+
+        let mut pages = self.pages.lock().unwrap();
+        let mut page = start;
+        while page < end {
+            pages.insert(page);
+            page += PAGE_SIZE;
+        }
+    }
+
+    /// Returns the base addresses of all recorded pages in ascending order.
+    pub fn pages(&self) -> Vec<Address> {
+        self.pages
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|&p| Address::from(p))
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.pages.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn contains(&self, addr: Address) -> bool {
+        self.pages
+            .lock()
+            .unwrap()
+            .contains(&(addr.to_umem() & !(PAGE_SIZE - 1)))
+    }
+
+    /// Removes all recorded pages.
+    pub fn clear(&self) {
+        self.pages.lock().unwrap().clear();
+    }
+
+    /// Writes the manifest of all recorded pages.
+    ///
+    /// The manifest contains the page size followed by the base address of every page, one per line.
+    pub fn write_manifest<W: Write>(&self, mut writer: W) -> Result<()> {
+        let pages = self.pages.lock().unwrap();
+        let to_err = |_| {
+            Error(ErrorOrigin::OsLayer, ErrorKind::UnableToWriteFile)
+                .log_error("unable to write page set manifest")
+        };
+
+        writeln!(writer, "# page_size={:#x} pages={}", PAGE_SIZE, pages.len()).map_err(to_err)?;
+        for page in pages.iter() {
+            writeln!(writer, "{:#x}", page).map_err(to_err)?;
+        }
+        Ok(())
+    }
+
+    /// Reads all recorded pages from the given memory and hands them to `out`.
+    ///
+    /// This can be used to acquire exactly the pages that were touched during the analysis.
+    pub fn acquire<T: PhysicalMemory, F: FnMut(Address, &[u8]) -> Result<()>>(
+        &self,
+        mem: &mut T,
+        mut out: F,
+    ) -> Result<()> {
+        let mut buf = vec![0u8; PAGE_SIZE as usize];
+        for page in self.pages() {
+            mem.phys_read_into(PhysicalAddress::from(page), buf.as_mut_slice())?;
+            out(page, &buf)?;
+        }
+        Ok(())
+    }
+}
+
+/// Physical memory wrapper that records all pages that are read
+#[derive(Clone)]
+pub struct Win32PageSetRecorder<T> {
+    mem: T,
+    page_set: Win32PageSet,
+}
+
+impl<T: PhysicalMemory> Win32PageSetRecorder<T> {
+    pub fn new(mem: T) -> Self {
+        Self::with_page_set(mem, Win32PageSet::new())
+    }
+
+    /// Creates a recorder that records into an existing page set.
+    pub fn with_page_set(mem: T, page_set: Win32PageSet) -> Self {
+        Self { mem, page_set }
+    }
+
+    /// Returns a handle to the recorded page set.
+    ///
+    /// The handle is shared with all clones of this recorder.
+    pub fn page_set(&self) -> Win32PageSet {
+        self.page_set.clone()
+    }
+
+    pub fn into_inner(self) -> T {
+        self.mem
+    }
+}
+
+impl<T: PhysicalMemory> PhysicalMemory for Win32PageSetRecorder<T> {
+    fn phys_read_raw_iter(
+        &mut self,
+        MemOps { inp, out, out_fail }: PhysicalReadMemOps,
+    ) -> Result<()> {
+        let page_set = &self.page_set;
+        let inp = inp.inspect(|CTup3(addr, _, buf)| page_set.record(*addr, buf.len()));
+        MemOps::with_raw(inp, out, out_fail, |data| self.mem.phys_read_raw_iter(data))
+    }
+
+    fn phys_write_raw_iter(&mut self, data: PhysicalWriteMemOps) -> Result<()> {
+        self.mem.phys_write_raw_iter(data)
+    }
+
+    fn metadata(&self) -> PhysicalMemoryMetadata {
+        self.mem.metadata()
+    }
+
+    fn set_mem_map(&mut self, mem_map: &[PhysicalMemoryMapping]) {
+        self.mem.set_mem_map(mem_map)
+    }
+}
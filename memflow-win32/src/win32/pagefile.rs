@@ -0,0 +1,585 @@
+/*!
+Module for resolving paged out memory with the help of pagefiles.
+
+When a page has been written to a pagefile its pte is replaced by a software pte
+that contains the number of the pagefile and the page offset inside of it.
+Since memflow has no access to the pagefiles of the target by itself the data has
+to be supplied by a [`PagefileProvider`] (e.g. the pagefile that was acquired alongside a memory dump).
+
+On Windows 10 1803 and newer x64 kernels software ptes are xored with the invalid pte mask
+(`MiState.Hardware.InvalidPteMask`). With the `symstore` feature enabled the mask is read
+through the kernel pdb from the symbol store of the kernel. If the mask cannot be determined
+[`Win32Kernel::software_pte_format`] returns an error and the format has to be constructed
+by the caller.
+
+Paged out pages can either be read explicitly with [`Win32Process::read_raw_into_with_pagefile`]
+or transparently by wrapping the connector in a [`Win32PagefileMemory`] and enabling the pagefile
+format on the translator with [`Win32Kernel::set_pagefile_format`]. The translator then resolves
+software ptes into a physical address window that is served by the pagefile provider.
+
+# Examples:
+
+```
+use memflow::prelude::v1::*;
+use memflow_win32::win32::{Win32Kernel, Win32PagefileFiles, Win32Process, Win32VirtualTranslate};
+
+fn test<T: 'static + PhysicalMemory + Clone, V: 'static + VirtualTranslate2 + Clone>(
+    kernel: &mut Win32Kernel<T, V>,
+    process: &mut Win32Process<T, V, Win32VirtualTranslate>,
+) {
+    let pagefile = std::fs::File::open("pagefile.sys").unwrap();
+    let mut reader = kernel
+        .pagefile_reader(Win32PagefileFiles::new().pagefile(0, pagefile))
+        .unwrap();
+
+    let mut buf = vec![0u8; 0x2000];
+    process
+        .read_raw_into_with_pagefile(&mut reader, Address::from(0x10000), &mut buf)
+        .unwrap();
+}
+```
+
+Reading paged out memory transparently:
+
+```
+use memflow::prelude::v1::*;
+use memflow_win32::win32::{Win32KernelBuilder, Win32PagefileFiles, Win32PagefileMemory};
+
+fn test<T: 'static + PhysicalMemory + Clone>(connector: T) {
+    let pagefile = std::fs::File::open("pagefile.sys").unwrap();
+    let mem = Win32PagefileMemory::new(connector, Win32PagefileFiles::new().pagefile(0, pagefile));
+
+    let mut kernel = Win32KernelBuilder::new(mem).build().unwrap();
+    let format = kernel.software_pte_format().unwrap();
+    kernel.set_pagefile_format(Some(format));
+
+    let mut process = kernel.process_by_name("explorer.exe").unwrap();
+    let _modules = process.module_list().unwrap();
+}
+```
+*/
+use std::prelude::v1::*;
+
+use super::{Win32Kernel, Win32Process, Win32VirtualTranslate};
+
+use crate::kernel::Win32Version;
+#[cfg(feature = "symstore")]
+use crate::offsets::SymbolStore;
+
+#[cfg(feature = "symstore")]
+use log::debug;
+
+use memflow::architecture::ArchitectureIdent;
+use memflow::error::{Error, ErrorKind, ErrorOrigin, Result};
+#[cfg(feature = "symstore")]
+use memflow::mem::MemoryView;
+use memflow::mem::{PhysicalMemory, VirtualTranslate2};
+use memflow::types::{umem, Address};
+
+#[cfg(feature = "std")]
+use std::io::{Read, Seek, SeekFrom};
+#[cfg(feature = "std")]
+use std::sync::{Arc, Mutex};
+
+#[cfg(feature = "std")]
+use memflow::cglue::tuple::*;
+#[cfg(feature = "std")]
+use memflow::mem::mem_data::MemOps;
+#[cfg(feature = "std")]
+use memflow::mem::{
+    PhysicalMemoryMapping, PhysicalMemoryMetadata, PhysicalReadMemOps, PhysicalWriteMemOps,
+};
+
+const PAGE_SIZE: usize = 0x1000;
+
+/// Base of the physical address window into which paged out pages are translated.
+///
+/// Bits 44 to 47 hold the pagefile number, bits 12 to 43 the page offset inside of the pagefile.
+const PAGEFILE_WINDOW: umem = 0xfff0_0000_0000_0000;
+const PAGEFILE_WINDOW_MASK: umem = 0xffff_0000_0000_0000;
+
+const PTE_VALID: u64 = 1 << 0;
+const PTE_SWIZZLE_BIT: u64 = 1 << 4;
+const PTE_PROTOTYPE: u64 = 1 << 10;
+const PTE_TRANSITION: u64 = 1 << 11;
+
+/// Supplies the contents of pagefiles
+pub trait PagefileProvider {
+    /// Reads the page at `page_offset` (in pages) from the pagefile with the given number.
+    fn read_page(
+        &mut self,
+        page_file_number: u32,
+        page_offset: u64,
+        out: &mut [u8; PAGE_SIZE],
+    ) -> Result<()>;
+}
+
+impl<F: FnMut(u32, u64, &mut [u8; PAGE_SIZE]) -> Result<()>> PagefileProvider for F {
+    fn read_page(
+        &mut self,
+        page_file_number: u32,
+        page_offset: u64,
+        out: &mut [u8; PAGE_SIZE],
+    ) -> Result<()> {
+        (self)(page_file_number, page_offset, out)
+    }
+}
+
+/// A [`PagefileProvider`] backed by a set of readers (e.g. files)
+#[cfg(feature = "std")]
+pub struct Win32PagefileFiles<R> {
+    pagefiles: Vec<(u32, R)>,
+}
+
+#[cfg(feature = "std")]
+impl<R> Default for Win32PagefileFiles<R> {
+    fn default() -> Self {
+        Self { pagefiles: vec![] }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<R: Read + Seek> Win32PagefileFiles<R> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds the pagefile with the given number. The first pagefile (`pagefile.sys`) has the number 0.
+    pub fn pagefile(mut self, page_file_number: u32, reader: R) -> Self {
+        self.pagefiles.push((page_file_number, reader));
+        self
+    }
+}
+
+#[cfg(feature = "std")]
+impl<R: Read + Seek> PagefileProvider for Win32PagefileFiles<R> {
+    fn read_page(
+        &mut self,
+        page_file_number: u32,
+        page_offset: u64,
+        out: &mut [u8; PAGE_SIZE],
+    ) -> Result<()> {
+        let (_, reader) = self
+            .pagefiles
+            .iter_mut()
+            .find(|(n, _)| *n == page_file_number)
+            .ok_or_else(|| {
+                Error(ErrorOrigin::OsLayer, ErrorKind::NotFound)
+                    .log_trace("pagefile is not available")
+            })?;
+
+        reader
+            .seek(SeekFrom::Start(page_offset * PAGE_SIZE as u64))
+            .and_then(|_| reader.read_exact(out))
+            .map_err(|_| {
+                Error(ErrorOrigin::OsLayer, ErrorKind::UnableToReadFile)
+                    .log_trace("unable to read page from pagefile")
+            })
+    }
+}
+
+/// Layout of software ptes that reference a pagefile
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize))]
+pub enum Win32SoftwarePteFormat {
+    /// 32 bit ptes, `PageFileLow` at bit 1 and `PageFileHigh` at bit 12
+    X86,
+    /// 64 bit ptes, `PageFileLow` at bit 1 and `PageFileHigh` at bit 32
+    Pae,
+    /// 64 bit ptes on Windows 10 1803 and newer, `PageFileLow` moved to bit 12.
+    ///
+    /// Ptes that do not have the swizzle bit set are xored with the invalid pte mask.
+    X64 { invalid_pte_mask: u64 },
+}
+
+impl Win32SoftwarePteFormat {
+    /// Returns the software pte format for the given architecture and kernel version.
+    pub fn new(arch: ArchitectureIdent, winver: Win32Version) -> Option<Self> {
+        match arch {
+            ArchitectureIdent::X86(64, _) if winver >= Win32Version::new(10, 0, 17134) => {
+                Some(Self::X64 {
+                    invalid_pte_mask: 0,
+                })
+            }
+            ArchitectureIdent::X86(64, _) | ArchitectureIdent::X86(32, true) => Some(Self::Pae),
+            ArchitectureIdent::X86(32, false) => Some(Self::X86),
+            _ => None,
+        }
+    }
+}
+
+/// Location of a paged out page
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize))]
+pub struct Win32PagefileLocation {
+    pub page_file_number: u32,
+    /// Offset inside of the pagefile in pages
+    pub page_offset: u64,
+}
+
+impl Win32PagefileLocation {
+    /// Decodes the pagefile location from a software pte.
+    ///
+    /// Returns `None` if the pte does not reference a pagefile
+    /// (e.g. for valid, demand zero, prototype and transition ptes).
+    pub fn from_software_pte(pte: u64, format: Win32SoftwarePteFormat) -> Option<Self> {
+        if pte & PTE_VALID != 0 || pte & (PTE_PROTOTYPE | PTE_TRANSITION) != 0 {
+            return None;
+        }
+
+        let (page_file_number, page_offset) = match format {
+            Win32SoftwarePteFormat::X86 => ((pte >> 1) & 0xf, (pte >> 12) & 0xf_ffff),
+            Win32SoftwarePteFormat::Pae => ((pte >> 1) & 0xf, pte >> 32),
+            Win32SoftwarePteFormat::X64 { invalid_pte_mask } => {
+                let pte = if pte & PTE_SWIZZLE_BIT == 0 {
+                    pte ^ invalid_pte_mask
+                } else {
+                    pte
+                };
+                ((pte >> 12) & 0xf, pte >> 32)
+            }
+        };
+
+        if page_offset == 0 {
+            return None;
+        }
+
+        Some(Self {
+            page_file_number: page_file_number as u32,
+            page_offset,
+        })
+    }
+
+    /// Returns the address of the page in the physical pagefile window
+    /// of a [`Win32PagefileMemory`].
+    pub fn window_address(&self) -> Address {
+        Address::from(
+            PAGEFILE_WINDOW
+                | ((self.page_file_number as umem & 0xf) << 44)
+                | ((self.page_offset & 0xffff_ffff) << 12),
+        )
+    }
+
+    /// Decodes the pagefile location from an address inside of the physical pagefile window.
+    pub fn from_window_address(addr: Address) -> Option<Self> {
+        let addr = addr.to_umem();
+        if addr & PAGEFILE_WINDOW_MASK != PAGEFILE_WINDOW {
+            return None;
+        }
+
+        Some(Self {
+            page_file_number: ((addr >> 44) & 0xf) as u32,
+            page_offset: (addr >> 12) & 0xffff_ffff,
+        })
+    }
+}
+
+/// Resolves software ptes with the help of a [`PagefileProvider`]
+pub struct Win32PagefileReader<P> {
+    provider: P,
+    format: Win32SoftwarePteFormat,
+}
+
+impl<P: PagefileProvider> Win32PagefileReader<P> {
+    pub fn new(provider: P, format: Win32SoftwarePteFormat) -> Self {
+        Self { provider, format }
+    }
+
+    pub fn format(&self) -> Win32SoftwarePteFormat {
+        self.format
+    }
+
+    pub fn provider(&mut self) -> &mut P {
+        &mut self.provider
+    }
+
+    pub fn into_inner(self) -> P {
+        self.provider
+    }
+
+    /// Reads the page referenced by the given software pte.
+    pub fn read_page(&mut self, pte: u64, out: &mut [u8; PAGE_SIZE]) -> Result<()> {
+        let location =
+            Win32PagefileLocation::from_software_pte(pte, self.format).ok_or_else(|| {
+                Error(ErrorOrigin::OsLayer, ErrorKind::InvalidArgument)
+                    .log_trace("pte does not reference a pagefile")
+            })?;
+
+        self.provider
+            .read_page(location.page_file_number, location.page_offset, out)
+    }
+}
+
+/// Physical memory wrapper that serves the physical pagefile window from a [`PagefileProvider`]
+///
+/// All other reads and all writes are forwarded to the wrapped memory.
+/// The provider is shared with all clones of the wrapper.
+#[cfg(feature = "std")]
+pub struct Win32PagefileMemory<T, P> {
+    mem: T,
+    provider: Arc<Mutex<P>>,
+}
+
+#[cfg(feature = "std")]
+impl<T: Clone, P> Clone for Win32PagefileMemory<T, P> {
+    fn clone(&self) -> Self {
+        Self {
+            mem: self.mem.clone(),
+            provider: self.provider.clone(),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<T: PhysicalMemory, P: PagefileProvider> Win32PagefileMemory<T, P> {
+    pub fn new(mem: T, provider: P) -> Self {
+        Self {
+            mem,
+            provider: Arc::new(Mutex::new(provider)),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.mem
+    }
+}
+
+#[cfg(feature = "std")]
+impl<T: PhysicalMemory, P: PagefileProvider> PhysicalMemory for Win32PagefileMemory<T, P> {
+    fn phys_read_raw_iter(
+        &mut self,
+        MemOps {
+            inp,
+            mut out,
+            mut out_fail,
+        }: PhysicalReadMemOps,
+    ) -> Result<()> {
+        let mut page = [0u8; PAGE_SIZE];
+        let mut provider = self.provider.lock().unwrap();
+
+        let mut forward = vec![];
+        for CTup3(addr, meta_addr, mut buf) in inp {
+            let location = match Win32PagefileLocation::from_window_address(addr.address()) {
+                Some(location) => location,
+                None => {
+                    forward.push(CTup3(addr, meta_addr, buf));
+                    continue;
+                }
+            };
+
+            // the translator never hands out ranges that cross a page boundary
+            let offset = addr.address().to_umem() as usize & (PAGE_SIZE - 1);
+            let read = offset + buf.len() <= PAGE_SIZE
+                && provider
+                    .read_page(location.page_file_number, location.page_offset, &mut page)
+                    .is_ok();
+
+            if read {
+                buf.copy_from_slice(&page[offset..offset + buf.len()]);
+                if let Some(out) = out.as_deref_mut() {
+                    out.call(CTup2(meta_addr, buf));
+                }
+            } else if let Some(out_fail) = out_fail.as_deref_mut() {
+                out_fail.call(CTup2(meta_addr, buf));
+            }
+        }
+        std::mem::drop(provider);
+
+        MemOps::with_raw(forward.into_iter(), out, out_fail, |data| {
+            self.mem.phys_read_raw_iter(data)
+        })
+    }
+
+    fn phys_write_raw_iter(&mut self, data: PhysicalWriteMemOps) -> Result<()> {
+        self.mem.phys_write_raw_iter(data)
+    }
+
+    fn metadata(&self) -> PhysicalMemoryMetadata {
+        self.mem.metadata()
+    }
+
+    fn set_mem_map(&mut self, mem_map: &[PhysicalMemoryMapping]) {
+        self.mem.set_mem_map(mem_map)
+    }
+}
+
+impl<T: 'static + PhysicalMemory + Clone, V: 'static + VirtualTranslate2 + Clone>
+    Win32Kernel<T, V>
+{
+    /// Returns the software pte format used by this kernel.
+    ///
+    /// On Windows 10 1803 and newer x64 kernels the invalid pte mask is read through the kernel pdb
+    /// which is loaded from the symbol store of the kernel (see [`Win32Kernel::set_symbol_store`]).
+    /// An error is returned if the mask cannot be determined.
+    pub fn software_pte_format(&mut self) -> Result<Win32SoftwarePteFormat> {
+        match self.default_software_pte_format() {
+            #[cfg(feature = "symstore")]
+            Win32SoftwarePteFormat::X64 { .. } => {
+                let store = self.configured_symbol_store()?;
+                self.software_pte_format_from_store(&store)
+            }
+            #[cfg(not(feature = "symstore"))]
+            Win32SoftwarePteFormat::X64 { .. } => Err(Error(
+                ErrorOrigin::OsLayer,
+                ErrorKind::UnsupportedOptionalFeature,
+            )
+            .log_info("the invalid pte mask can only be read with the symstore feature enabled")),
+            format => Ok(format),
+        }
+    }
+
+    /// Returns the software pte format used by this kernel.
+    ///
+    /// The invalid pte mask is read through the kernel pdb which is loaded
+    /// from the given symbol store.
+    #[cfg(feature = "symstore")]
+    pub fn software_pte_format_from_store(
+        &mut self,
+        store: &SymbolStore,
+    ) -> Result<Win32SoftwarePteFormat> {
+        match self.default_software_pte_format() {
+            Win32SoftwarePteFormat::X64 { .. } => Ok(Win32SoftwarePteFormat::X64 {
+                invalid_pte_mask: self.invalid_pte_mask_from_store(store)?,
+            }),
+            format => Ok(format),
+        }
+    }
+
+    /// Reads `MiState.Hardware.InvalidPteMask` through the kernel pdb which is loaded from
+    /// the given symbol store.
+    #[cfg(feature = "symstore")]
+    pub fn invalid_pte_mask_from_store(&mut self, store: &SymbolStore) -> Result<u64> {
+        let mi_state = self.kernel_symbols_from_store(store)?.address("MiState")?;
+        let offset = self
+            .kernel_types_from_store(store)?
+            .offset_of("_MI_SYSTEM_INFORMATION.Hardware.InvalidPteMask")?;
+        let invalid_pte_mask = self.virt_mem.read(mi_state + offset)?;
+        debug!("InvalidPteMask={:x}", invalid_pte_mask);
+        Ok(invalid_pte_mask)
+    }
+
+    fn default_software_pte_format(&self) -> Win32SoftwarePteFormat {
+        Win32SoftwarePteFormat::new(
+            self.kernel_info.os_info.arch,
+            self.kernel_info.kernel_winver,
+        )
+        .unwrap_or(Win32SoftwarePteFormat::Pae)
+    }
+
+    /// Creates a pagefile reader that decodes software ptes in the format of this kernel.
+    ///
+    /// See [`Win32Kernel::software_pte_format`] for how the format is determined,
+    /// [`Win32PagefileReader::new`] accepts an explicit format instead.
+    pub fn pagefile_reader<P: PagefileProvider>(
+        &mut self,
+        provider: P,
+    ) -> Result<Win32PagefileReader<P>> {
+        Ok(Win32PagefileReader::new(
+            provider,
+            self.software_pte_format()?,
+        ))
+    }
+
+    /// Enables translating paged out pages into the physical pagefile window.
+    ///
+    /// The connector has to be wrapped in a [`Win32PagefileMemory`] that serves the window.
+    /// Processes created from this kernel afterwards inherit the setting.
+    /// See [`Win32VirtualTranslate::with_pagefile_format`] for details.
+    pub fn set_pagefile_format(&mut self, format: Option<Win32SoftwarePteFormat>) {
+        let translator = self.virt_mem.translator().with_pagefile_format(format);
+        self.virt_mem.set_translator(translator);
+    }
+}
+
+impl<T: PhysicalMemory, V: VirtualTranslate2> Win32Process<T, V, Win32VirtualTranslate> {
+    /// Reads memory and falls back to the given pagefiles for pages that have been paged out.
+    pub fn read_raw_into_with_pagefile<P: PagefileProvider>(
+        &mut self,
+        reader: &mut Win32PagefileReader<P>,
+        addr: Address,
+        out: &mut [u8],
+    ) -> Result<()> {
+        self.read_raw_into_with_fallback(addr, out, |pte, page| reader.read_page(pte, page))
+    }
+
+    /// Enables translating paged out pages into the physical pagefile window.
+    ///
+    /// See [`Win32VirtualTranslate::with_pagefile_format`] for details.
+    pub fn set_pagefile_format(&mut self, format: Option<Win32SoftwarePteFormat>) {
+        let translator = self.virt_mem.translator().with_pagefile_format(format);
+        self.virt_mem.set_translator(translator);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INVALID_PTE_MASK: u64 = 0x0000_0008_0000_0000;
+
+    #[test]
+    fn software_pte_x64_unswizzled() {
+        // page offset 0x1234 in pagefile 0 with PAGE_READWRITE, xored with the invalid pte mask
+        let pte = 0x0000_1234_0000_0080 ^ INVALID_PTE_MASK;
+        let format = Win32SoftwarePteFormat::X64 {
+            invalid_pte_mask: INVALID_PTE_MASK,
+        };
+        assert_eq!(
+            Win32PagefileLocation::from_software_pte(pte, format),
+            Some(Win32PagefileLocation {
+                page_file_number: 0,
+                page_offset: 0x1234,
+            })
+        );
+
+        let format = Win32SoftwarePteFormat::X64 {
+            invalid_pte_mask: 0,
+        };
+        assert_eq!(
+            Win32PagefileLocation::from_software_pte(pte, format).map(|l| l.page_offset),
+            Some(0x123c)
+        );
+    }
+
+    #[test]
+    fn software_pte_x64_swizzled() {
+        let pte = 0x0000_1234_0000_2090;
+        let format = Win32SoftwarePteFormat::X64 {
+            invalid_pte_mask: INVALID_PTE_MASK,
+        };
+        assert_eq!(
+            Win32PagefileLocation::from_software_pte(pte, format),
+            Some(Win32PagefileLocation {
+                page_file_number: 2,
+                page_offset: 0x1234,
+            })
+        );
+    }
+
+    #[test]
+    fn software_pte_not_in_pagefile() {
+        let format = Win32SoftwarePteFormat::Pae;
+        // valid, transition, prototype and demand zero ptes
+        for pte in [0x1234_5867, 0x1234_5880, 0x1234_5480, 0x80] {
+            assert_eq!(Win32PagefileLocation::from_software_pte(pte, format), None);
+        }
+    }
+
+    #[test]
+    fn window_address_roundtrip() {
+        let location = Win32PagefileLocation {
+            page_file_number: 2,
+            page_offset: 0x1234,
+        };
+        let addr = location.window_address();
+        assert_eq!(addr, Address::from(0xfff0_2000_0123_4000u64));
+        assert_eq!(
+            Win32PagefileLocation::from_window_address(addr + 0x10usize),
+            Some(location)
+        );
+        assert_eq!(
+            Win32PagefileLocation::from_window_address(Address::from(0x1234_5000u64)),
+            None
+        );
+    }
+}
@@ -0,0 +1,233 @@
+/*!
+Module for reporting the processor and virtualization platform of the target.
+
+The information is combined from several sources:
+- the processor features in `KUSER_SHARED_DATA`
+- the processor control block (`_KPRCB`) of the boot processor which caches the cpuid vendor and signature
+- the loaded kernel modules which reveal the guest drivers of known hypervisors
+
+# Examples:
+
+```
+use memflow::prelude::v1::*;
+use memflow_win32::win32::{Win32Kernel, PF_AVX2_INSTRUCTIONS_AVAILABLE};
+
+fn test<T: 'static + PhysicalMemory + Clone, V: 'static + VirtualTranslate2 + Clone>(
+    kernel: &mut Win32Kernel<T, V>,
+) {
+    let info = kernel.platform_info().unwrap();
+    println!("{:?} ({} processors)", info.cpu_vendor, info.active_processor_count);
+    println!("avx2: {}", info.processor_features.has(PF_AVX2_INSTRUCTIONS_AVAILABLE));
+    println!("hypervisor: {:?}", info.hypervisor);
+}
+```
+*/
+use std::prelude::v1::*;
+
+use super::Win32Kernel;
+use crate::kernel::ntos::kuser_shared_data;
+
+use memflow::error::{PartialResultExt, Result};
+use memflow::mem::{MemoryView, PhysicalMemory, VirtualTranslate2};
+use memflow::os::Os;
+use memflow::types::{umem, Address};
+
+use log::debug;
+
+const KUSER_PROCESSOR_FEATURES: umem = 0x274;
+const KUSER_ACTIVE_PROCESSOR_COUNT: umem = 0x3c0;
+
+const PROCESSOR_FEATURE_MAX: usize = 64;
+
+pub const PF_MMX_INSTRUCTIONS_AVAILABLE: u32 = 3;
+pub const PF_XMMI_INSTRUCTIONS_AVAILABLE: u32 = 6;
+pub const PF_XMMI64_INSTRUCTIONS_AVAILABLE: u32 = 10;
+pub const PF_NX_ENABLED: u32 = 12;
+pub const PF_SSE3_INSTRUCTIONS_AVAILABLE: u32 = 13;
+pub const PF_XSAVE_ENABLED: u32 = 17;
+pub const PF_SECOND_LEVEL_ADDRESS_TRANSLATION: u32 = 20;
+pub const PF_VIRT_FIRMWARE_ENABLED: u32 = 21;
+pub const PF_RDWRFSGSBASE_AVAILABLE: u32 = 22;
+pub const PF_FASTFAIL_AVAILABLE: u32 = 23;
+pub const PF_RDRAND_INSTRUCTION_AVAILABLE: u32 = 28;
+pub const PF_RDTSCP_INSTRUCTION_AVAILABLE: u32 = 32;
+pub const PF_SSSE3_INSTRUCTIONS_AVAILABLE: u32 = 36;
+pub const PF_SSE4_1_INSTRUCTIONS_AVAILABLE: u32 = 37;
+pub const PF_SSE4_2_INSTRUCTIONS_AVAILABLE: u32 = 38;
+pub const PF_AVX_INSTRUCTIONS_AVAILABLE: u32 = 39;
+pub const PF_AVX2_INSTRUCTIONS_AVAILABLE: u32 = 40;
+pub const PF_AVX512F_INSTRUCTIONS_AVAILABLE: u32 = 41;
+
+/// Guest drivers that identify a hypervisor
+const HYPERVISOR_DRIVERS: [(&str, Win32Hypervisor); 11] = [
+    ("vmbus.sys", Win32Hypervisor::HyperV),
+    ("winhv.sys", Win32Hypervisor::HyperV),
+    ("vmci.sys", Win32Hypervisor::VMware),
+    ("vmmouse.sys", Win32Hypervisor::VMware),
+    ("vm3dmp.sys", Win32Hypervisor::VMware),
+    ("vboxguest.sys", Win32Hypervisor::VirtualBox),
+    ("vboxsf.sys", Win32Hypervisor::VirtualBox),
+    ("viostor.sys", Win32Hypervisor::Kvm),
+    ("vioscsi.sys", Win32Hypervisor::Kvm),
+    ("netkvm.sys", Win32Hypervisor::Kvm),
+    ("xenbus.sys", Win32Hypervisor::Xen),
+];
+
+/// Processor features reported in `KUSER_SHARED_DATA::ProcessorFeatures`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize))]
+pub struct Win32ProcessorFeatures(pub u64);
+
+impl Win32ProcessorFeatures {
+    /// Returns true if the feature with the given `PF_*` index is present.
+    pub fn has(&self, feature: u32) -> bool {
+        feature < PROCESSOR_FEATURE_MAX as u32 && self.0 & (1 << feature) != 0
+    }
+}
+
+/// Hypervisor the target is running under
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize))]
+pub enum Win32Hypervisor {
+    HyperV,
+    VMware,
+    VirtualBox,
+    Kvm,
+    Xen,
+}
+
+impl Win32Hypervisor {
+    /// Identifies the hypervisor by the guest drivers in the given kernel module names.
+    pub fn from_module_names<'a, I: IntoIterator<Item = &'a str>>(names: I) -> Option<Self> {
+        names.into_iter().find_map(|module| {
+            HYPERVISOR_DRIVERS
+                .iter()
+                .find(|(name, _)| module.eq_ignore_ascii_case(name))
+                .map(|(_, hv)| *hv)
+        })
+    }
+}
+
+/// Processor and virtualization information of the target
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize))]
+pub struct Win32PlatformInfo {
+    /// Cpuid vendor string (e.g. `GenuineIntel`)
+    pub cpu_vendor: Option<String>,
+    pub cpu_family: Option<u8>,
+    pub cpu_model: Option<u8>,
+    pub cpu_stepping: Option<u8>,
+    pub active_processor_count: u32,
+    pub processor_features: Win32ProcessorFeatures,
+    /// Hardware virtualization is enabled in the firmware
+    pub virt_firmware_enabled: bool,
+    /// Hypervisor detected by its guest drivers
+    pub hypervisor: Option<Win32Hypervisor>,
+}
+
+impl<T: 'static + PhysicalMemory + Clone, V: 'static + VirtualTranslate2 + Clone>
+    Win32Kernel<T, V>
+{
+    /// Collects processor and hypervisor information of the target.
+    pub fn platform_info(&mut self) -> Result<Win32PlatformInfo> {
+        let shared_data = kuser_shared_data(&mut self.virt_mem, self.kernel_info.os_info.arch)?;
+
+        let mut features = [0u8; PROCESSOR_FEATURE_MAX];
+        self.virt_mem
+            .read_into(shared_data + KUSER_PROCESSOR_FEATURES, &mut features)
+            .data_part()?;
+        let processor_features = Win32ProcessorFeatures(
+            features
+                .iter()
+                .enumerate()
+                .filter(|(_, f)| **f != 0)
+                .fold(0, |acc, (i, _)| acc | (1 << i)),
+        );
+
+        let active_processor_count: u32 = self
+            .virt_mem
+            .read(shared_data + KUSER_ACTIVE_PROCESSOR_COUNT)
+            .data_part()?;
+
+        let (cpu_vendor, cpu_family, cpu_model, cpu_stepping) = match self.boot_prcb() {
+            Some(prcb) => self.read_cpu_info(prcb),
+            None => (None, None, None, None),
+        };
+
+        let hypervisor =
+            Win32Hypervisor::from_module_names(self.module_list()?.iter().map(|m| m.name.as_ref()))
+                .or(self.kernel_info.hypervisor);
+
+        Ok(Win32PlatformInfo {
+            cpu_vendor,
+            cpu_family,
+            cpu_model,
+            cpu_stepping,
+            active_processor_count,
+            processor_features,
+            virt_firmware_enabled: processor_features.has(PF_VIRT_FIRMWARE_ENABLED),
+            hypervisor,
+        })
+    }
+
+    /// Returns the address of the `_KPRCB` of the boot processor.
+    fn boot_prcb(&mut self) -> Option<Address> {
+        if self.offsets.ki_processor_block() == 0 {
+            debug!("KiProcessorBlock is not available");
+            return None;
+        }
+
+        self.virt_mem
+            .read_addr_arch(
+                self.kernel_info.os_info.arch.into(),
+                self.kernel_info.os_info.base + self.offsets.ki_processor_block(),
+            )
+            .ok()?
+            .non_null()
+    }
+
+    fn read_cpu_info(
+        &mut self,
+        prcb: Address,
+    ) -> (Option<String>, Option<u8>, Option<u8>, Option<u8>) {
+        let vendor = if self.offsets.kprcb_vendor_string() != 0 {
+            let mut vendor = [0u8; 13];
+            self.virt_mem
+                .read_into(prcb + self.offsets.kprcb_vendor_string(), &mut vendor)
+                .data_part()
+                .ok()
+                .and_then(|_| {
+                    let len = vendor.iter().position(|&c| c == 0).unwrap_or(vendor.len());
+                    std::str::from_utf8(&vendor[..len]).ok().map(String::from)
+                })
+                .filter(|v| !v.is_empty())
+        } else {
+            None
+        };
+
+        let family = if self.offsets.kprcb_cpu_type() != 0 {
+            self.virt_mem
+                .read::<u8>(prcb + self.offsets.kprcb_cpu_type())
+                .data_part()
+                .ok()
+        } else {
+            None
+        };
+
+        // CpuStep contains the model in the high and the stepping in the low byte
+        let (model, stepping) = if self.offsets.kprcb_cpu_step() != 0 {
+            match self
+                .virt_mem
+                .read::<u16>(prcb + self.offsets.kprcb_cpu_step())
+                .data_part()
+            {
+                Ok(step) => (Some((step >> 8) as u8), Some(step as u8)),
+                Err(_) => (None, None),
+            }
+        } else {
+            (None, None)
+        };
+
+        (vendor, family, model, stepping)
+    }
+}
@@ -1,6 +1,6 @@
 use std::prelude::v1::*;
 
-use super::{Win32Kernel, Win32ModuleListInfo};
+use super::{Win32Kernel, Win32ModuleListInfo, Win32ModuleOffset};
 
 use crate::prelude::MmVadOffsetTable;
 
@@ -21,6 +21,37 @@ pub type Win32ExitStatus = i32;
 /// Process has not exited yet
 pub const EXIT_STATUS_STILL_ACTIVE: i32 = 259;
 
+/// Selects the processes of the process list by their exit status
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize))]
+pub enum ProcessListFilter {
+    /// All processes including the ones that have already exited
+    All,
+    /// Processes that have not exited yet.
+    ///
+    /// This includes processes whose state is unknown because the exit status is not available.
+    Alive,
+    /// Processes that have exited but are still referenced by the kernel
+    Exited,
+}
+
+impl Default for ProcessListFilter {
+    fn default() -> Self {
+        Self::All
+    }
+}
+
+impl ProcessListFilter {
+    /// Returns true if a process in the given state passes the filter.
+    pub fn matches(&self, state: &ProcessState) -> bool {
+        match self {
+            Self::All => true,
+            Self::Alive => !matches!(state, ProcessState::Dead(_)),
+            Self::Exited => matches!(state, ProcessState::Dead(_)),
+        }
+    }
+}
+
 /// EPROCESS ImageFileName byte length
 pub const IMAGE_FILE_NAME_LENGTH: usize = 15;
 
@@ -48,6 +79,8 @@ pub struct Win32ProcessInfo {
 
     // memory
     pub vad_root: Address,
+    /// 5-level paging is enabled
+    pub la57: bool,
 }
 
 impl Win32ProcessInfo {
@@ -92,7 +125,13 @@ impl Win32ProcessInfo {
     }
 
     pub fn translator(&self) -> Win32VirtualTranslate {
-        Win32VirtualTranslate::new(self.base_info.sys_arch, self.base_info.dtb1)
+        let translator = Win32VirtualTranslate::new(self.base_info.sys_arch, self.base_info.dtb1)
+            .with_la57(self.la57);
+        if self.base_info.dtb2.is_valid() {
+            translator.with_user_dtb(self.base_info.dtb2)
+        } else {
+            translator
+        }
     }
 }
 
@@ -107,7 +146,7 @@ pub struct Win32Process<T, V, D> {
 
     sysproc_dtb: D,
     offset_eproc_exit_status: usize,
-    mmvad: MmVadOffsetTable,
+    pub(crate) mmvad: MmVadOffsetTable,
 }
 
 // TODO: can be removed i think
@@ -169,7 +208,9 @@ impl<T: PhysicalMemory, V: VirtualTranslate2> Process
 
     /// Retrieves the state of the process
     fn state(&mut self) -> ProcessState {
-        if let Ok(exit_status) = self.virt_mem.read::<Win32ExitStatus>(
+        if self.offset_eproc_exit_status == 0 {
+            ProcessState::Unknown
+        } else if let Ok(exit_status) = self.virt_mem.read::<Win32ExitStatus>(
             self.proc_info.base_info.address + self.offset_eproc_exit_status,
         ) {
             if exit_status == EXIT_STATUS_STILL_ACTIVE {
@@ -186,11 +227,16 @@ impl<T: PhysicalMemory, V: VirtualTranslate2> Process
     ///
     /// # Remarks
     ///
-    /// For memflow-win32 the second parameter should be set to `Address::invalid()`.
-    fn set_dtb(&mut self, dtb1: Address, _dtb2: Address) -> Result<()> {
+    /// For memflow-win32 the second parameter is the user mode dtb used with kva shadowing.
+    /// It should be set to `Address::invalid()` if kva shadowing is not used.
+    fn set_dtb(&mut self, dtb1: Address, dtb2: Address) -> Result<()> {
         self.proc_info.base_info.dtb1 = dtb1;
-        self.proc_info.base_info.dtb2 = Address::invalid();
-        self.virt_mem.set_translator(self.proc_info.translator());
+        self.proc_info.base_info.dtb2 = dtb2;
+        let translator = self
+            .proc_info
+            .translator()
+            .with_pte_options_of(self.virt_mem.translator());
+        self.virt_mem.set_translator(translator);
         Ok(())
     }
 
@@ -228,6 +274,42 @@ impl<T: PhysicalMemory, V: VirtualTranslate2> Process
             .map_err(From::from)
     }
 
+    /// Walks the process' module list and calls the provided callback for each module info
+    ///
+    /// The module entries and their names are fetched with batched reads.
+    fn module_list_callback(
+        &mut self,
+        target_arch: Option<&ArchitectureIdent>,
+        mut callback: ModuleInfoCallback,
+    ) -> memflow::error::Result<()> {
+        let infos = [
+            (
+                self.proc_info.module_info_native,
+                self.proc_info.base_info.sys_arch,
+            ),
+            (
+                self.proc_info.module_info_wow64,
+                self.proc_info.base_info.proc_arch,
+            ),
+        ];
+
+        for (info, arch) in infos
+            .iter()
+            .filter(|(_, a)| target_arch.map_or(true, |ta| a == ta))
+            .filter_map(|(info, arch)| info.zip(Some(*arch)))
+        {
+            let modules =
+                info.module_info_list(&mut self.virt_mem, self.proc_info.base_info.address, arch)?;
+            for module in modules {
+                if !callback.call(module) {
+                    return Ok(());
+                }
+            }
+        }
+
+        Ok(())
+    }
+
     /// Retrieves a module by its structure address and architecture
     ///
     /// # Arguments
@@ -430,7 +512,10 @@ impl<T: PhysicalMemory, V: VirtualTranslate2> Win32Process<T, V, Win32VirtualTra
     pub fn with_kernel(kernel: Win32Kernel<T, V>, proc_info: Win32ProcessInfo) -> Self {
         let mut virt_mem = kernel.virt_mem;
         virt_mem.set_proc_arch(proc_info.base_info.proc_arch.into());
-        let sysproc_dtb = virt_mem.set_translator(proc_info.translator());
+        let translator = proc_info
+            .translator()
+            .with_pte_options_of(virt_mem.translator());
+        let sysproc_dtb = virt_mem.set_translator(translator);
 
         Self {
             virt_mem,
@@ -441,10 +526,29 @@ impl<T: PhysicalMemory, V: VirtualTranslate2> Win32Process<T, V, Win32VirtualTra
         }
     }
 
+    /// Enables decoding of software ptes for pages that cannot be translated.
+    ///
+    /// See [`Win32VirtualTranslate::with_software_ptes`] for details.
+    pub fn set_software_ptes(&mut self, software_ptes: bool) {
+        let translator = self.virt_mem.translator().with_software_ptes(software_ptes);
+        self.virt_mem.set_translator(translator);
+    }
+
     /// Consumes this process, returning the underlying memory and vat objects
     pub fn into_inner(self) -> (T, V) {
         self.virt_mem.into_inner()
     }
+
+    /// Returns the module that contains the given address and the offset into it.
+    ///
+    /// The native as well as the wow64 module list are searched.
+    pub fn module_containing(&mut self, address: Address) -> Result<Win32ModuleOffset> {
+        let modules = self.module_list()?;
+        Win32ModuleOffset::find(modules, address).ok_or_else(|| {
+            Error(ErrorOrigin::OsLayer, ErrorKind::ModuleNotFound)
+                .log_debug(format!("no module contains address {:x}", address))
+        })
+    }
 }
 
 impl<'a, T: PhysicalMemory, V: VirtualTranslate2>
@@ -466,7 +570,7 @@ impl<'a, T: PhysicalMemory, V: VirtualTranslate2>
         let virt_mem = VirtualDma::with_vat(
             phys_mem.forward_mut(),
             proc_info.base_info.proc_arch,
-            proc_info.translator(),
+            proc_info.translator().with_pte_options_of(&sysproc_dtb),
             vat.forward_mut(),
         );
 
@@ -481,6 +585,41 @@ impl<'a, T: PhysicalMemory, V: VirtualTranslate2>
 }
 
 impl<T: PhysicalMemory, V: VirtualTranslate2, D: VirtualTranslate3> Win32Process<T, V, D> {
+    /// Finds all virtual addresses in this process that map to the given physical address.
+    ///
+    /// This walks the entire page table hierarchy of the process and can therefore be slow.
+    /// Use `virt_addr_of_phys_range` to restrict the search to a specific virtual range.
+    pub fn virt_addr_of_phys(&mut self, phys_addr: Address) -> Vec<Address> {
+        let translations = self.virt_mem.virt_translation_map_vec();
+        Self::virt_addrs_in_translations(translations, phys_addr)
+    }
+
+    /// Finds all virtual addresses between `start` and `end` that map to the given physical address.
+    pub fn virt_addr_of_phys_range(
+        &mut self,
+        phys_addr: Address,
+        start: Address,
+        end: Address,
+    ) -> Vec<Address> {
+        let translations = self.virt_mem.virt_translation_map_range_vec(start, end);
+        Self::virt_addrs_in_translations(translations, phys_addr)
+    }
+
+    fn virt_addrs_in_translations(
+        translations: Vec<VirtualTranslation>,
+        phys_addr: Address,
+    ) -> Vec<Address> {
+        translations
+            .into_iter()
+            .filter(|t| {
+                let phys_start = t.out_physical.address();
+                phys_addr >= phys_start && phys_addr < phys_start + t.size
+            })
+            .map(|t| t.in_virtual + (phys_addr - t.out_physical.address()))
+            .inspect(|virt_addr| log::trace!("found mapping {:x} -> {:x}", virt_addr, phys_addr))
+            .collect()
+    }
+
     fn module_address_list_with_infos_callback(
         &mut self,
         module_infos: impl Iterator<Item = (Win32ModuleListInfo, ArchitectureIdent)>,
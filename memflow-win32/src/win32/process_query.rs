@@ -0,0 +1,235 @@
+/*!
+Module for looking up processes by name patterns.
+
+The lookup of the `Os` trait only compares the `ImageFileName` of a process which is truncated to 15 characters.
+A [`Win32ProcessQuery`] matches wildcard patterns against the untruncated file name from the image path
+in the peb or against the full image path and returns all matching processes.
+
+Patterns support `*` (any number of characters) and `?` (a single character).
+Matching is case-insensitive by default, like file names on Windows.
+
+# Examples:
+
+```
+use memflow::prelude::v1::*;
+use memflow_win32::win32::{Win32Kernel, Win32ProcessQuery};
+
+fn test<T: 'static + PhysicalMemory + Clone, V: 'static + VirtualTranslate2 + Clone>(
+    kernel: &mut Win32Kernel<T, V>,
+) {
+    for proc in kernel.processes_by_query(&Win32ProcessQuery::new("svc*.exe")).unwrap() {
+        println!("{} ({})", proc.name, proc.pid);
+    }
+
+    let query = Win32ProcessQuery::new(r"C:\Windows\System32\*").full_path(true);
+    println!("{} system processes", kernel.processes_by_query(&query).unwrap().len());
+}
+```
+*/
+use std::prelude::v1::*;
+
+use super::Win32Kernel;
+
+use memflow::error::Result;
+use memflow::mem::{PhysicalMemory, VirtualTranslate2};
+use memflow::os::{Os, ProcessInfo};
+
+/// Wildcard pattern that is matched against the names or image paths of processes
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize))]
+pub struct Win32ProcessQuery {
+    pattern: String,
+    case_sensitive: bool,
+    full_path: bool,
+}
+
+impl Win32ProcessQuery {
+    /// Creates a case-insensitive query that matches the file names of processes.
+    pub fn new(pattern: &str) -> Self {
+        Self {
+            pattern: pattern.to_string(),
+            case_sensitive: false,
+            full_path: false,
+        }
+    }
+
+    /// Compares the characters case-sensitively.
+    pub fn case_sensitive(mut self, case_sensitive: bool) -> Self {
+        self.case_sensitive = case_sensitive;
+        self
+    }
+
+    /// Matches the pattern against the full image path instead of the file name.
+    ///
+    /// Processes without an image path (e.g. the System process) never match.
+    pub fn full_path(mut self, full_path: bool) -> Self {
+        self.full_path = full_path;
+        self
+    }
+
+    /// Returns true if the given process matches the query.
+    ///
+    /// Without `full_path` both the truncated `ImageFileName` and the file name of the image path are tried.
+    pub fn matches(&self, info: &ProcessInfo) -> bool {
+        let path: &str = info.path.as_ref();
+        if self.full_path {
+            return !path.is_empty() && self.matches_str(path);
+        }
+
+        let file_name = path.rsplit(['\\', '/']).next().unwrap_or_default();
+        (!file_name.is_empty() && self.matches_str(file_name))
+            || self.matches_str(info.name.as_ref())
+    }
+
+    fn matches_str(&self, text: &str) -> bool {
+        if self.case_sensitive {
+            glob_match(
+                &self.pattern.chars().collect::<Vec<_>>(),
+                &text.chars().collect::<Vec<_>>(),
+            )
+        } else {
+            glob_match(
+                &self.pattern.to_lowercase().chars().collect::<Vec<_>>(),
+                &text.to_lowercase().chars().collect::<Vec<_>>(),
+            )
+        }
+    }
+}
+
+/// Matches `text` against a pattern with `*` and `?` wildcards.
+///
+/// On a mismatch the matching resumes after the last `*`, the pattern is therefore matched in linear time
+/// for every position of that wildcard.
+fn glob_match(pattern: &[char], text: &[char]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    let mut backtrack = None;
+
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                // let the last wildcard consume one more character
+                Some((bp, bt)) => {
+                    backtrack = Some((bp, bt + 1));
+                    p = bp + 1;
+                    t = bt + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+impl<T: 'static + PhysicalMemory + Clone, V: 'static + VirtualTranslate2 + Clone>
+    Win32Kernel<T, V>
+{
+    /// Returns all processes that match the given query.
+    pub fn processes_by_query(&mut self, query: &Win32ProcessQuery) -> Result<Vec<ProcessInfo>> {
+        Ok(self
+            .process_info_list()?
+            .into_iter()
+            .filter(|info| query.matches(info))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use memflow::architecture::ArchitectureIdent;
+    use memflow::os::process::ProcessState;
+    use memflow::types::Address;
+
+    fn glob(pattern: &str, text: &str) -> bool {
+        glob_match(
+            &pattern.chars().collect::<Vec<_>>(),
+            &text.chars().collect::<Vec<_>>(),
+        )
+    }
+
+    fn process(name: &str, path: &str) -> ProcessInfo {
+        ProcessInfo {
+            address: Address::from(0x1000u64),
+            pid: 0x1f4,
+            state: ProcessState::Alive,
+            name: name.into(),
+            path: path.into(),
+            command_line: "".into(),
+            sys_arch: ArchitectureIdent::X86(64, false),
+            proc_arch: ArchitectureIdent::X86(64, false),
+            dtb1: Address::from(0x1ad000u64),
+            dtb2: Address::invalid(),
+        }
+    }
+
+    #[test]
+    fn glob_literal() {
+        assert!(glob("svchost.exe", "svchost.exe"));
+        assert!(!glob("svchost.exe", "svchost.ex"));
+        assert!(!glob("svchost.ex", "svchost.exe"));
+        assert!(glob("", ""));
+        assert!(!glob("", "a"));
+    }
+
+    #[test]
+    fn glob_wildcards() {
+        assert!(glob("svc*.exe", "svchost.exe"));
+        assert!(glob("*", ""));
+        assert!(glob("**", "abc"));
+        assert!(glob("*.exe", ".exe"));
+        assert!(glob("?sass.exe", "lsass.exe"));
+        assert!(!glob("?sass.exe", "sass.exe"));
+        assert!(!glob("svc*.dll", "svchost.exe"));
+    }
+
+    #[test]
+    fn glob_backtracking() {
+        // the wildcard has to consume a prefix that matches the rest of the pattern
+        assert!(glob("*ab", "aab"));
+        assert!(glob("*a*b", "xaxxab"));
+        assert!(glob("a*b*c", "abbbc"));
+        assert!(!glob("a*b*c", "abbbd"));
+        assert!(glob("*.exe", "a.exe.exe"));
+        assert!(!glob("*a?", "ba"));
+    }
+
+    #[test]
+    fn query_case() {
+        let info = process("SearchHost.exe", "");
+        assert!(Win32ProcessQuery::new("searchhost.exe").matches(&info));
+        assert!(!Win32ProcessQuery::new("searchhost.exe")
+            .case_sensitive(true)
+            .matches(&info));
+    }
+
+    #[test]
+    fn query_truncated_name() {
+        let info = process(
+            "MicrosoftEdgeUp",
+            r"C:\Program Files (x86)\Microsoft\EdgeUpdate\MicrosoftEdgeUpdate.exe",
+        );
+        assert!(Win32ProcessQuery::new("*edgeupdate.exe").matches(&info));
+        assert!(Win32ProcessQuery::new("microsoftedgeup").matches(&info));
+        assert!(!Win32ProcessQuery::new(r"c:\program files*").matches(&info));
+        assert!(Win32ProcessQuery::new(r"c:\program files*")
+            .full_path(true)
+            .matches(&info));
+    }
+
+    #[test]
+    fn query_without_path() {
+        let info = process("System", "");
+        assert!(Win32ProcessQuery::new("sys*").matches(&info));
+        assert!(!Win32ProcessQuery::new("*").full_path(true).matches(&info));
+    }
+}
@@ -0,0 +1,115 @@
+/*!
+Module containing tuned builder settings for common classes of connectors.
+
+Connectors differ a lot in their access characteristics. Reading from a dump file is cheap
+while every read over a pcileech fpga device has a high latency. A [`Win32Profile`] bundles
+cache sizes and scan settings that work well for a given class of connectors.
+
+# Examples:
+
+```
+use memflow::prelude::v1::*;
+use memflow_win32::win32::{Win32Kernel, Win32Profile};
+
+fn test<T: 'static + PhysicalMemory + Clone>(connector: T) {
+    let _kernel = Win32Kernel::builder(connector)
+        .profile(Win32Profile::PcileechFpga)
+        .build()
+        .unwrap();
+}
+```
+*/
+use std::prelude::v1::*;
+
+use core::fmt;
+use core::str::FromStr;
+
+use memflow::error::{Error, ErrorKind, ErrorOrigin};
+use memflow::types::size;
+
+/// Builder profile for a class of connectors
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize, ::serde::Deserialize))]
+pub enum Win32Profile {
+    /// Virtual machines accessed through shared memory (e.g. the qemu connector)
+    QemuVm,
+    /// Dma devices with a high latency per read
+    PcileechFpga,
+    /// Static memory dumps
+    Dumpfile,
+    /// Local live memory acquired through a driver such as winpmem
+    Winpmem,
+}
+
+/// Settings bundled by a [`Win32Profile`]
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize))]
+pub struct Win32ProfileSettings {
+    /// Size of the page cache in bytes
+    pub page_cache_size: usize,
+    /// Number of entries in the vat cache
+    pub vat_cache_entries: usize,
+    /// Scan all of physical memory for the dtb if it cannot be found in the low stub
+    pub exhaustive_scan: bool,
+}
+
+impl Win32Profile {
+    /// Returns the name of the profile as it is accepted by the plugin.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Win32Profile::QemuVm => "qemu-vm",
+            Win32Profile::PcileechFpga => "pcileech-fpga",
+            Win32Profile::Dumpfile => "dumpfile",
+            Win32Profile::Winpmem => "winpmem",
+        }
+    }
+
+    pub fn settings(&self) -> Win32ProfileSettings {
+        match self {
+            // reads are cheap but the memory changes constantly
+            Win32Profile::QemuVm => Win32ProfileSettings {
+                page_cache_size: size::mb(2),
+                vat_cache_entries: 2048,
+                exhaustive_scan: false,
+            },
+            // every read has a high latency, scanning all of memory is not feasible
+            Win32Profile::PcileechFpga => Win32ProfileSettings {
+                page_cache_size: size::mb(16),
+                vat_cache_entries: 4096,
+                exhaustive_scan: false,
+            },
+            // the file cache of the os already covers most reads and dumps might lack the low stub
+            Win32Profile::Dumpfile => Win32ProfileSettings {
+                page_cache_size: size::mb(1),
+                vat_cache_entries: 1024,
+                exhaustive_scan: true,
+            },
+            Win32Profile::Winpmem => Win32ProfileSettings {
+                page_cache_size: size::mb(4),
+                vat_cache_entries: 2048,
+                exhaustive_scan: false,
+            },
+        }
+    }
+}
+
+impl FromStr for Win32Profile {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "qemu-vm" | "qemu" => Ok(Win32Profile::QemuVm),
+            "pcileech-fpga" | "pcileech" => Ok(Win32Profile::PcileechFpga),
+            "dumpfile" => Ok(Win32Profile::Dumpfile),
+            "winpmem" => Ok(Win32Profile::Winpmem),
+            _ => Err(Error(ErrorOrigin::OsLayer, ErrorKind::InvalidArgument)
+                .log_error(format!("unknown profile: {}", s))),
+        }
+    }
+}
+
+impl fmt::Display for Win32Profile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
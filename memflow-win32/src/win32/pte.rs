@@ -0,0 +1,502 @@
+use std::prelude::v1::*;
+
+use super::{Win32PagefileLocation, Win32Process, Win32VirtualTranslate};
+
+use std::convert::TryInto;
+use std::fmt;
+
+use memflow::architecture::ArchitectureIdent;
+use memflow::error::{Error, ErrorKind, ErrorOrigin, Result};
+use memflow::mem::{MemoryView, PhysicalMemory, VirtualTranslate2};
+use memflow::types::{umem, Address, PhysicalAddress};
+
+const PTE_PRESENT: u64 = 1 << 0;
+const PTE_WRITABLE: u64 = 1 << 1;
+const PTE_USER: u64 = 1 << 2;
+const PTE_WRITE_THROUGH: u64 = 1 << 3;
+const PTE_CACHE_DISABLED: u64 = 1 << 4;
+const PTE_ACCESSED: u64 = 1 << 5;
+const PTE_DIRTY: u64 = 1 << 6;
+const PTE_LARGE_PAGE: u64 = 1 << 7;
+const PTE_GLOBAL: u64 = 1 << 8;
+// software bits used by the windows memory manager for invalid ptes
+const PTE_PROTOTYPE: u64 = 1 << 10;
+const PTE_TRANSITION: u64 = 1 << 11;
+const PTE_NX: u64 = 1 << 63;
+
+const PAE_ADDRESS_MASK: u64 = 0x000f_ffff_ffff_f000;
+const X86_ADDRESS_MASK: u64 = 0xffff_f000;
+
+/// Paging structure level of a page table entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize))]
+pub enum Win32PageTableLevel {
+    /// Page map level 5 entry (x64 with 5-level paging only)
+    Pml5e,
+    /// Page map level 4 entry (x64 only)
+    Pml4e,
+    /// Page directory pointer table entry (x64 and x86 pae)
+    Pdpte,
+    /// Page directory entry
+    Pde,
+    /// Page table entry
+    Pte,
+}
+
+/// Decoded flags of a single page table entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize))]
+pub struct Win32PteFlags {
+    pub present: bool,
+    pub writable: bool,
+    pub user: bool,
+    pub write_through: bool,
+    pub cache_disabled: bool,
+    pub accessed: bool,
+    pub dirty: bool,
+    pub large_page: bool,
+    pub global: bool,
+    pub nx: bool,
+    /// The page is in transition (only valid if `present` is not set)
+    pub transition: bool,
+    /// The entry is a prototype pte (only valid if `present` is not set)
+    pub prototype: bool,
+}
+
+impl Win32PteFlags {
+    fn new(value: u64, nx_supported: bool) -> Self {
+        let present = value & PTE_PRESENT != 0;
+        Self {
+            present,
+            writable: value & PTE_WRITABLE != 0,
+            user: value & PTE_USER != 0,
+            write_through: value & PTE_WRITE_THROUGH != 0,
+            cache_disabled: value & PTE_CACHE_DISABLED != 0,
+            accessed: value & PTE_ACCESSED != 0,
+            dirty: value & PTE_DIRTY != 0,
+            large_page: present && value & PTE_LARGE_PAGE != 0,
+            global: value & PTE_GLOBAL != 0,
+            nx: nx_supported && value & PTE_NX != 0,
+            transition: !present && value & PTE_TRANSITION != 0 && value & PTE_PROTOTYPE == 0,
+            prototype: !present && value & PTE_PROTOTYPE != 0,
+        }
+    }
+}
+
+impl fmt::Display for Win32PteFlags {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // same ordering as WinDbg's !pte command, write-through is printed as 'H'
+        // so it can not be confused with the transition and writable flags
+        let flags = [
+            (self.prototype, 'P'),
+            (self.transition, 'T'),
+            (self.large_page, 'L'),
+            (self.global, 'G'),
+            (self.dirty, 'D'),
+            (self.accessed, 'A'),
+            (self.cache_disabled, 'N'),
+            (self.write_through, 'H'),
+            (self.user, 'U'),
+            (self.writable, 'W'),
+            (!self.nx, 'E'),
+            (self.present, 'V'),
+        ];
+        for (set, c) in flags.iter() {
+            write!(f, "{}", if *set { *c } else { '-' })?;
+        }
+        Ok(())
+    }
+}
+
+/// A single entry in the paging structure hierarchy
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize))]
+pub struct Win32PageTableEntry {
+    pub level: Win32PageTableLevel,
+    /// Physical address of the entry itself
+    pub address: Address,
+    /// Raw value of the entry
+    pub value: u64,
+    pub flags: Win32PteFlags,
+}
+
+impl Win32PageTableEntry {
+    /// Physical address of the next level table or the mapped page
+    pub fn pfn_address(&self) -> Address {
+        Address::from(self.value & PAE_ADDRESS_MASK)
+    }
+
+    /// Physical address of the page if it is still resident in memory.
+    ///
+    /// This is the case for valid entries as well as for entries in transition.
+    pub fn resident_address(&self) -> Option<Address> {
+        if self.flags.present || self.flags.transition {
+            Some(self.pfn_address())
+        } else {
+            None
+        }
+    }
+}
+
+/// Full paging structure chain for a virtual address
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize))]
+pub struct Win32PteInfo {
+    pub virt_addr: Address,
+    /// All entries that were walked, starting with the top level entry
+    pub entries: Vec<Win32PageTableEntry>,
+    /// Resulting physical address, `None` if the address is not mapped
+    pub phys_addr: Option<Address>,
+}
+
+impl Win32PteInfo {
+    /// Returns the entry for the given level (if it was walked)
+    pub fn entry(&self, level: Win32PageTableLevel) -> Option<&Win32PageTableEntry> {
+        self.entries.iter().find(|e| e.level == level)
+    }
+
+    /// Returns the last entry that was walked
+    pub fn last_entry(&self) -> Option<&Win32PageTableEntry> {
+        self.entries.last()
+    }
+}
+
+/// Paging structure entries translating a range of virtual addresses
+pub(crate) enum Win32PteRun {
+    /// The address is mapped by a large page or an upper level entry is not present.
+    ///
+    /// The run covers the remainder of the `1 << shift` bytes aligned region,
+    /// `phys_addr` is the translation of the address itself.
+    Region {
+        phys_addr: Option<Address>,
+        shift: u32,
+    },
+    /// Raw values of the ptes of consecutive pages starting at the page of the address
+    Ptes(Vec<u64>),
+}
+
+/// Returns the physical address of `virt_addr` if the given pte is present.
+pub(crate) fn present_pte_address(value: u64, virt_addr: Address) -> Option<Address> {
+    if value & PTE_PRESENT != 0 {
+        Some(Address::from(value & PAE_ADDRESS_MASK) + (virt_addr.to_umem() & 0xfff))
+    } else {
+        None
+    }
+}
+
+impl Win32VirtualTranslate {
+    /// Walks the paging structures for the given virtual address and returns all visited entries.
+    #[allow(clippy::unnecessary_cast)]
+    pub fn pte_info<T: PhysicalMemory + ?Sized>(
+        &self,
+        mem: &mut T,
+        virt_addr: Address,
+    ) -> Result<Win32PteInfo> {
+        use Win32PageTableLevel::*;
+
+        let (levels, entry_size, mut table, nx_supported): (&[(_, u32, u32)], _, _, _) =
+            match self.sys_arch.ident() {
+                ArchitectureIdent::X86(64, _) if self.la57 => (
+                    &[
+                        (Pml5e, 48, 9),
+                        (Pml4e, 39, 9),
+                        (Pdpte, 30, 9),
+                        (Pde, 21, 9),
+                        (Pte, 12, 9),
+                    ],
+                    8,
+                    self.dtb.to_umem() as u64 & PAE_ADDRESS_MASK,
+                    true,
+                ),
+                ArchitectureIdent::X86(64, _) => (
+                    &[(Pml4e, 39, 9), (Pdpte, 30, 9), (Pde, 21, 9), (Pte, 12, 9)],
+                    8,
+                    self.dtb.to_umem() as u64 & PAE_ADDRESS_MASK,
+                    true,
+                ),
+                ArchitectureIdent::X86(32, true) => (
+                    &[(Pdpte, 30, 2), (Pde, 21, 9), (Pte, 12, 9)],
+                    8,
+                    self.dtb.to_umem() as u64 & 0xffff_ffe0,
+                    true,
+                ),
+                ArchitectureIdent::X86(32, false) => (
+                    &[(Pde, 22, 10), (Pte, 12, 10)],
+                    4,
+                    self.dtb.to_umem() as u64 & X86_ADDRESS_MASK,
+                    false,
+                ),
+                _ => {
+                    return Err(Error(ErrorOrigin::OsLayer, ErrorKind::NotSupported)
+                        .log_info("pte_info is not supported on this architecture"))
+                }
+            };
+
+        let va = virt_addr.to_umem() as u64;
+        let address_mask = if entry_size == 8 {
+            PAE_ADDRESS_MASK
+        } else {
+            X86_ADDRESS_MASK
+        };
+
+        let mut entries = vec![];
+        for (i, &(level, shift, bits)) in levels.iter().enumerate() {
+            let index = (va >> shift) & ((1 << bits) - 1);
+            let address = Address::from(table + index * entry_size);
+
+            let value = if entry_size == 8 {
+                let mut value = 0u64;
+                mem.phys_read_into(PhysicalAddress::from(address), &mut value)?;
+                value
+            } else {
+                let mut value = 0u32;
+                mem.phys_read_into(PhysicalAddress::from(address), &mut value)?;
+                value as u64
+            };
+
+            let flags = Win32PteFlags::new(value, nx_supported);
+            entries.push(Win32PageTableEntry {
+                level,
+                address,
+                value,
+                flags,
+            });
+
+            if !flags.present {
+                break;
+            }
+
+            // large pages can only be mapped by pdes or x64 pdptes
+            let is_leaf = i == levels.len() - 1
+                || (flags.large_page && (level == Pde || (level == Pdpte && levels.len() >= 4)));
+            if is_leaf {
+                let page_mask = (1u64 << shift) - 1;
+                let phys_addr = (value & address_mask & !page_mask) | (va & page_mask);
+                return Ok(Win32PteInfo {
+                    virt_addr,
+                    entries,
+                    phys_addr: Some(Address::from(phys_addr as umem)),
+                });
+            }
+
+            table = value & address_mask;
+        }
+
+        Ok(Win32PteInfo {
+            virt_addr,
+            entries,
+            phys_addr: None,
+        })
+    }
+}
+
+impl Win32VirtualTranslate {
+    /// Tries to resolve a virtual address whose pte is invalid but still references a resident page.
+    ///
+    /// This covers ptes in transition state as well as prototype ptes that point to
+    /// a valid or transitioning prototype pte.
+    pub fn resolve_software_pte<T: PhysicalMemory + ?Sized>(
+        &self,
+        mem: &mut T,
+        virt_addr: Address,
+    ) -> Option<Address> {
+        let info = self.pte_info(mem, virt_addr).ok()?;
+        let entry = info.last_entry()?;
+        if entry.flags.present || entry.level != Win32PageTableLevel::Pte {
+            return None;
+        }
+
+        self.resolve_invalid_pte(mem, entry.value, virt_addr)
+    }
+
+    /// Resolves the physical address of a page whose pte is not present.
+    ///
+    /// Paged out pages are resolved into the physical pagefile window
+    /// if a pagefile format is set on the translator.
+    pub(crate) fn resolve_invalid_pte<T: PhysicalMemory + ?Sized>(
+        &self,
+        mem: &mut T,
+        value: u64,
+        virt_addr: Address,
+    ) -> Option<Address> {
+        let flags = Win32PteFlags::new(value, true);
+        let page_offset = virt_addr.to_umem() & 0xfff;
+        if flags.transition {
+            return Some(Address::from(value & PAE_ADDRESS_MASK) + page_offset);
+        }
+
+        if flags.prototype {
+            let proto_pte = self.prototype_pte_address(value)?;
+
+            // the prototype pte itself resides in paged pool
+            let proto_info = self.pte_info(mem, proto_pte).ok()?;
+            let proto_phys = match proto_info.phys_addr {
+                Some(phys) => phys,
+                None => {
+                    proto_info.last_entry()?.resident_address()? + (proto_pte.to_umem() & 0xfff)
+                }
+            };
+
+            let mut value = 0u64;
+            mem.phys_read_into(PhysicalAddress::from(proto_phys), &mut value)
+                .ok()?;
+
+            let flags = Win32PteFlags::new(value, true);
+            let proto_entry = Win32PageTableEntry {
+                level: Win32PageTableLevel::Pte,
+                address: proto_phys,
+                value,
+                flags,
+            };
+            return proto_entry
+                .resident_address()
+                .or_else(|| self.pagefile_window_address(value))
+                .map(|phys| phys + page_offset);
+        }
+
+        self.pagefile_window_address(value)
+            .map(|phys| phys + page_offset)
+    }
+
+    /// Returns the page in the physical pagefile window a software pte references.
+    fn pagefile_window_address(&self, value: u64) -> Option<Address> {
+        let format = self.pagefile_format?;
+        Win32PagefileLocation::from_software_pte(value, format)
+            .map(|location| location.window_address())
+    }
+
+    /// Walks the paging structures for a range of `len` bytes starting at `virt_addr`.
+    ///
+    /// The ptes of all pages of the range that are located in the same page table
+    /// are read in a single batch.
+    pub(crate) fn pte_run<T: PhysicalMemory + ?Sized>(
+        &self,
+        mem: &mut T,
+        virt_addr: Address,
+        len: umem,
+    ) -> Result<Win32PteRun> {
+        let info = self.pte_info(mem, virt_addr)?;
+        let entry = match info.last_entry() {
+            Some(entry) if entry.level == Win32PageTableLevel::Pte => entry,
+            Some(entry) => {
+                return Ok(Win32PteRun::Region {
+                    phys_addr: info.phys_addr,
+                    shift: self.level_shift(entry.level),
+                })
+            }
+            None => {
+                return Ok(Win32PteRun::Region {
+                    phys_addr: None,
+                    shift: 12,
+                })
+            }
+        };
+
+        let entry_size = match self.sys_arch.ident() {
+            ArchitectureIdent::X86(32, false) => 4,
+            _ => 8,
+        };
+        let index = (entry.address.to_umem() as usize & 0xfff) / entry_size;
+        let pages = (((virt_addr.to_umem() & 0xfff) + len + 0xfff) >> 12) as usize;
+        let count = pages.min(0x1000 / entry_size - index).max(1);
+        if count == 1 {
+            return Ok(Win32PteRun::Ptes(vec![entry.value]));
+        }
+
+        let mut buf = vec![0u8; count * entry_size];
+        mem.phys_read_into(PhysicalAddress::from(entry.address), buf.as_mut_slice())?;
+        Ok(Win32PteRun::Ptes(
+            buf.chunks_exact(entry_size)
+                .map(|c| match entry_size {
+                    8 => u64::from_le_bytes(c.try_into().unwrap()),
+                    _ => u32::from_le_bytes(c.try_into().unwrap()) as u64,
+                })
+                .collect(),
+        ))
+    }
+
+    /// Number of address bits covered by a single entry of the given paging structure level
+    pub(crate) fn level_shift(&self, level: Win32PageTableLevel) -> u32 {
+        match level {
+            Win32PageTableLevel::Pml5e => 48,
+            Win32PageTableLevel::Pml4e => 39,
+            Win32PageTableLevel::Pdpte => 30,
+            Win32PageTableLevel::Pde => match self.sys_arch.ident() {
+                ArchitectureIdent::X86(32, false) => 22,
+                _ => 21,
+            },
+            Win32PageTableLevel::Pte => 12,
+        }
+    }
+
+    /// Decodes the virtual address of the prototype pte a software pte points to.
+    pub(crate) fn prototype_pte_address(&self, value: u64) -> Option<Address> {
+        match self.sys_arch.ident() {
+            ArchitectureIdent::X86(64, _) => {
+                let proto_address = value >> 16;
+                // this special value indicates that the prototype pte has to be looked up in the vad
+                if proto_address == 0xffff_ffff_0000 {
+                    return None;
+                }
+                // sign extend the 48 bit address
+                Some(Address::from(((proto_address << 16) as i64 >> 16) as u64))
+            }
+            ArchitectureIdent::X86(32, true) => {
+                let proto_address = value >> 32;
+                if proto_address == 0xffff_ffff {
+                    return None;
+                }
+                Some(Address::from(proto_address))
+            }
+            _ => None,
+        }
+    }
+}
+
+impl<T: PhysicalMemory, V: VirtualTranslate2> Win32Process<T, V, Win32VirtualTranslate> {
+    /// Returns the full paging structure chain for the given virtual address.
+    ///
+    /// This is the equivalent of WinDbg's `!pte` command.
+    pub fn pte_info(&mut self, virt_addr: Address) -> Result<Win32PteInfo> {
+        let translator = *self.virt_mem.translator();
+        translator.pte_info(self.virt_mem.phys_mem(), virt_addr)
+    }
+
+    /// Reads memory page by page and invokes `fallback` with the software pte
+    /// of every page that could not be read.
+    pub(crate) fn read_raw_into_with_fallback<F: FnMut(u64, &mut [u8; 0x1000]) -> Result<()>>(
+        &mut self,
+        addr: Address,
+        out: &mut [u8],
+        mut fallback: F,
+    ) -> Result<()> {
+        let mut page = [0u8; 0x1000];
+
+        let mut offset = 0;
+        while offset < out.len() {
+            let cur = addr + offset;
+            let page_offset = (cur.to_umem() as usize) & 0xfff;
+            let len = (0x1000 - page_offset).min(out.len() - offset);
+            let buf = &mut out[offset..offset + len];
+
+            if self.virt_mem.read_raw_into(cur, buf).is_err() {
+                let pte = self
+                    .pte_info(cur)?
+                    .last_entry()
+                    .filter(|e| e.level == Win32PageTableLevel::Pte && !e.flags.present)
+                    .map(|e| e.value)
+                    .ok_or_else(|| {
+                        Error(ErrorOrigin::OsLayer, ErrorKind::OutOfBounds)
+                            .log_trace("page is not backed by a software pte")
+                    })?;
+
+                fallback(pte, &mut page)?;
+                buf.copy_from_slice(&page[page_offset..page_offset + len]);
+            }
+
+            offset += len;
+        }
+
+        Ok(())
+    }
+}
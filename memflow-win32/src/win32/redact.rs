@@ -0,0 +1,445 @@
+/*!
+Module for redacting privacy sensitive information before it is reported or serialized.
+
+A [`Win32RedactionPolicy`] describes how user names, command lines, file paths and clipboard
+contents are treated.
+Each category can be kept as is, masked or replaced by a keyed hash. Hashed values are stable for a
+given key so that entries can still be correlated across reports without revealing the original value.
+
+The policy is applied through the [`Win32Redact`] trait which is implemented for the process and
+module infos as well as for recovered command lines, version infos, clipboards and open files.
+
+Note that the hash is meant for pseudonymization and is not a cryptographic hash function.
+Short values such as user names should always be hashed with a secret key.
+
+# Examples:
+
+```
+use memflow::prelude::v1::*;
+use memflow_win32::win32::{Win32Kernel, Win32Redact, Win32RedactionMode, Win32RedactionPolicy};
+
+fn test<T: 'static + PhysicalMemory + Clone, V: 'static + VirtualTranslate2 + Clone>(
+    kernel: &mut Win32Kernel<T, V>,
+) {
+    let policy = Win32RedactionPolicy::new()
+        .user_names(Win32RedactionMode::Hash)
+        .command_lines(Win32RedactionMode::Mask)
+        .key(0x1234_5678);
+
+    for info in kernel.process_info_list().unwrap() {
+        let info = info.redacted(&policy);
+        println!("{} {} {}", info.pid, info.path, info.command_line);
+    }
+}
+```
+*/
+use std::prelude::v1::*;
+
+#[cfg(feature = "symstore")]
+use super::{Win32Clipboard, Win32ClipboardFormat, Win32OpenFile, CF_UNICODETEXT};
+use super::{Win32ProcessInfo, Win32RecoveredCommandLine, Win32VersionInfo};
+
+use memflow::os::{ModuleInfo, ProcessInfo};
+
+/// Placeholder for masked values
+pub const REDACTED: &str = "<redacted>";
+
+/// Path prefixes whose next component is a user name
+const USER_PROFILE_DIRS: [&str; 2] = ["\\users\\", "\\documents and settings\\"];
+
+/// How a category of information is redacted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize, ::serde::Deserialize))]
+pub enum Win32RedactionMode {
+    /// The value is kept as is
+    Keep,
+    /// The value is replaced by a placeholder
+    Mask,
+    /// The value is replaced by a keyed hash
+    Hash,
+}
+
+/// Policy describing which information is redacted
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize, ::serde::Deserialize))]
+pub struct Win32RedactionPolicy {
+    user_names: Win32RedactionMode,
+    command_lines: Win32RedactionMode,
+    paths: Win32RedactionMode,
+    clipboard: Win32RedactionMode,
+    key: u64,
+}
+
+impl Default for Win32RedactionPolicy {
+    fn default() -> Self {
+        Self {
+            user_names: Win32RedactionMode::Mask,
+            command_lines: Win32RedactionMode::Keep,
+            paths: Win32RedactionMode::Keep,
+            clipboard: Win32RedactionMode::Mask,
+            key: 0,
+        }
+    }
+}
+
+impl Win32RedactionPolicy {
+    /// Creates a policy that masks user names and clipboard contents and keeps everything else.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a policy that keeps all information.
+    pub fn keep_all() -> Self {
+        Self::new()
+            .user_names(Win32RedactionMode::Keep)
+            .clipboard(Win32RedactionMode::Keep)
+    }
+
+    /// Sets how user names are redacted.
+    ///
+    /// User names are also redacted inside of paths and command lines that are otherwise kept.
+    pub fn user_names(mut self, mode: Win32RedactionMode) -> Self {
+        self.user_names = mode;
+        self
+    }
+
+    /// Sets how command lines are redacted.
+    pub fn command_lines(mut self, mode: Win32RedactionMode) -> Self {
+        self.command_lines = mode;
+        self
+    }
+
+    /// Sets how file paths are redacted.
+    pub fn paths(mut self, mode: Win32RedactionMode) -> Self {
+        self.paths = mode;
+        self
+    }
+
+    /// Sets how the contents of the clipboard are redacted.
+    ///
+    /// Text formats are replaced by the placeholder or the hash of the text,
+    /// the data of all other formats is dropped unless it is kept.
+    pub fn clipboard(mut self, mode: Win32RedactionMode) -> Self {
+        self.clipboard = mode;
+        self
+    }
+
+    /// Sets the key that is mixed into all hashes.
+    pub fn key(mut self, key: u64) -> Self {
+        self.key = key;
+        self
+    }
+
+    /// Redacts a user name.
+    pub fn redact_user_name(&self, user_name: &str) -> String {
+        self.apply(self.user_names, user_name)
+    }
+
+    /// Redacts a file path.
+    pub fn redact_path(&self, path: &str) -> String {
+        match self.paths {
+            Win32RedactionMode::Keep => self.redact_user_profiles(path),
+            mode => self.apply(mode, path),
+        }
+    }
+
+    /// Redacts a command line.
+    pub fn redact_command_line(&self, command_line: &str) -> String {
+        match self.command_lines {
+            Win32RedactionMode::Keep => self.redact_user_profiles(command_line),
+            mode => self.apply(mode, command_line),
+        }
+    }
+
+    /// Redacts the text of a clipboard format.
+    pub fn redact_clipboard_text(&self, text: &str) -> String {
+        self.apply(self.clipboard, text)
+    }
+
+    fn apply(&self, mode: Win32RedactionMode, value: &str) -> String {
+        if value.is_empty() {
+            return String::new();
+        }
+
+        match mode {
+            Win32RedactionMode::Keep => value.to_string(),
+            Win32RedactionMode::Mask => REDACTED.to_string(),
+            Win32RedactionMode::Hash => format!("<{:016x}>", self.hash(value)),
+        }
+    }
+
+    /// Replaces the user name component of all user profile paths in the given string.
+    fn redact_user_profiles(&self, value: &str) -> String {
+        if self.user_names == Win32RedactionMode::Keep {
+            return value.to_string();
+        }
+
+        // paths are matched case insensitively, ascii lowercase keeps all byte offsets intact
+        let lower = value.to_ascii_lowercase();
+
+        let mut out = String::with_capacity(value.len());
+        let mut pos = 0;
+        while let Some((start, prefix)) = USER_PROFILE_DIRS
+            .iter()
+            .filter_map(|p| lower[pos..].find(p).map(|i| (pos + i, p.len())))
+            .min_by_key(|(i, _)| *i)
+        {
+            let name_start = start + prefix;
+            let name_end = value[name_start..]
+                .find(|c: char| c == '\\' || c == '"' || c == ' ')
+                .map(|i| name_start + i)
+                .unwrap_or(value.len());
+
+            out.push_str(&value[pos..name_start]);
+            out.push_str(&self.redact_user_name(&value[name_start..name_end]));
+            pos = name_end;
+        }
+        out.push_str(&value[pos..]);
+
+        out
+    }
+
+    // keyed fnv-1a, case insensitive so the same path always yields the same hash
+    fn hash(&self, value: &str) -> u64 {
+        value
+            .bytes()
+            .map(|b| b.to_ascii_lowercase())
+            .fold(0xcbf2_9ce4_8422_2325 ^ self.key, |hash, b| {
+                (hash ^ u64::from(b)).wrapping_mul(0x0100_0000_01b3)
+            })
+    }
+}
+
+/// Types that contain privacy sensitive information
+pub trait Win32Redact {
+    /// Returns a copy with all sensitive information redacted according to the policy.
+    fn redacted(&self, policy: &Win32RedactionPolicy) -> Self;
+}
+
+impl Win32Redact for ProcessInfo {
+    fn redacted(&self, policy: &Win32RedactionPolicy) -> Self {
+        Self {
+            path: policy.redact_path(self.path.as_ref()).into(),
+            command_line: policy
+                .redact_command_line(self.command_line.as_ref())
+                .into(),
+            ..self.clone()
+        }
+    }
+}
+
+impl Win32Redact for Win32ProcessInfo {
+    fn redacted(&self, policy: &Win32RedactionPolicy) -> Self {
+        Self {
+            base_info: self.base_info.redacted(policy),
+            ..self.clone()
+        }
+    }
+}
+
+impl Win32Redact for ModuleInfo {
+    fn redacted(&self, policy: &Win32RedactionPolicy) -> Self {
+        Self {
+            path: policy.redact_path(self.path.as_ref()).into(),
+            ..self.clone()
+        }
+    }
+}
+
+impl<R: Win32Redact> Win32Redact for Vec<R> {
+    fn redacted(&self, policy: &Win32RedactionPolicy) -> Self {
+        self.iter().map(|r| r.redacted(policy)).collect()
+    }
+}
+
+impl Win32Redact for Win32RecoveredCommandLine {
+    fn redacted(&self, policy: &Win32RedactionPolicy) -> Self {
+        Self {
+            command_line: policy.redact_command_line(&self.command_line),
+            ..self.clone()
+        }
+    }
+}
+
+impl Win32Redact for Win32VersionInfo {
+    fn redacted(&self, policy: &Win32RedactionPolicy) -> Self {
+        Self {
+            original_filename: self
+                .original_filename
+                .as_ref()
+                .map(|name| policy.redact_path(name)),
+            ..self.clone()
+        }
+    }
+}
+
+#[cfg(feature = "symstore")]
+impl Win32Redact for Win32ClipboardFormat {
+    fn redacted(&self, policy: &Win32RedactionPolicy) -> Self {
+        if policy.clipboard == Win32RedactionMode::Keep {
+            return self.clone();
+        }
+
+        // text is replaced in the encoding of its format so it can still be decoded
+        let data = self.text().map(|text| {
+            let text = policy.redact_clipboard_text(&text);
+            match self.format {
+                CF_UNICODETEXT => text
+                    .encode_utf16()
+                    .chain(Some(0))
+                    .flat_map(u16::to_le_bytes)
+                    .collect(),
+                _ => text.bytes().chain(Some(0)).collect(),
+            }
+        });
+
+        Self {
+            data,
+            ..self.clone()
+        }
+    }
+}
+
+#[cfg(feature = "symstore")]
+impl Win32Redact for Win32Clipboard {
+    fn redacted(&self, policy: &Win32RedactionPolicy) -> Self {
+        Self {
+            formats: self.formats.redacted(policy),
+            ..self.clone()
+        }
+    }
+}
+
+#[cfg(feature = "symstore")]
+impl Win32Redact for Win32OpenFile {
+    fn redacted(&self, policy: &Win32RedactionPolicy) -> Self {
+        Self {
+            file_name: policy.redact_path(&self.file_name),
+            path: policy.redact_path(&self.path),
+            ..self.clone()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::win32::Win32CommandLineSource;
+
+    #[test]
+    fn user_profiles() {
+        let policy = Win32RedactionPolicy::new();
+        assert_eq!(
+            policy.redact_path("C:\\Users\\alice\\Desktop\\a.exe"),
+            "C:\\Users\\<redacted>\\Desktop\\a.exe"
+        );
+        assert_eq!(
+            policy.redact_command_line("\"c:\\users\\bob\" /x C:\\Documents and Settings\\eve"),
+            "\"c:\\users\\<redacted>\" /x C:\\Documents and Settings\\<redacted>"
+        );
+        assert_eq!(
+            Win32RedactionPolicy::keep_all().redact_path("C:\\Users\\alice"),
+            "C:\\Users\\alice"
+        );
+    }
+
+    #[test]
+    fn modes() {
+        let policy = Win32RedactionPolicy::new()
+            .paths(Win32RedactionMode::Hash)
+            .command_lines(Win32RedactionMode::Mask)
+            .key(1);
+        assert_eq!(policy.redact_command_line("a.exe /x"), REDACTED);
+        assert_eq!(policy.redact_command_line(""), "");
+
+        // hashes are case insensitive and depend on the key
+        let hash = policy.redact_path("C:\\Windows\\a.exe");
+        assert_eq!(hash.len(), 18);
+        assert_eq!(hash, policy.redact_path("c:\\windows\\A.EXE"));
+        assert_ne!(
+            hash,
+            policy.clone().key(2).redact_path("C:\\Windows\\a.exe")
+        );
+    }
+
+    #[test]
+    fn command_line() {
+        let cmdline = Win32RecoveredCommandLine {
+            command_line: "C:\\Users\\alice\\a.exe".to_string(),
+            source: Win32CommandLineSource::AuditImagePath,
+            confidence: 0.4,
+        };
+        let redacted = cmdline.redacted(&Win32RedactionPolicy::new());
+        assert_eq!(redacted.command_line, "C:\\Users\\<redacted>\\a.exe");
+        assert_eq!(redacted.source, Win32CommandLineSource::AuditImagePath);
+    }
+
+    #[test]
+    fn version_info() {
+        let info = Win32VersionInfo {
+            company_name: Some("Microsoft Corporation".to_string()),
+            original_filename: Some("a.exe".to_string()),
+            ..Default::default()
+        };
+        let redacted = info.redacted(&Win32RedactionPolicy::new().paths(Win32RedactionMode::Mask));
+        assert_eq!(redacted.company_name, info.company_name);
+        assert_eq!(redacted.original_filename.as_deref(), Some(REDACTED));
+    }
+
+    #[cfg(feature = "symstore")]
+    #[test]
+    fn clipboard() {
+        use crate::win32::{CF_DIB, CF_TEXT};
+        use memflow::types::Address;
+
+        let format = |format, data: &[u8]| Win32ClipboardFormat {
+            format,
+            handle: Address::null(),
+            data: Some(data.to_vec()),
+        };
+        let clipboard = Win32Clipboard {
+            formats: vec![
+                format(CF_UNICODETEXT, &[b's', 0, b'e', 0, 0, 0]),
+                format(CF_TEXT, b"se\0"),
+                format(CF_DIB, &[0; 40]),
+            ],
+            ..Default::default()
+        };
+
+        let redacted = clipboard.redacted(&Win32RedactionPolicy::new());
+        assert_eq!(
+            redacted.format(CF_UNICODETEXT).unwrap().text().unwrap(),
+            REDACTED
+        );
+        assert_eq!(redacted.format(CF_TEXT).unwrap().text().unwrap(), REDACTED);
+        assert!(redacted.format(CF_DIB).unwrap().data.is_none());
+
+        let kept = clipboard.redacted(&Win32RedactionPolicy::keep_all());
+        assert_eq!(kept.text().unwrap(), "se");
+        assert!(kept.bitmap().is_some());
+    }
+
+    #[cfg(feature = "symstore")]
+    #[test]
+    fn open_file() {
+        use memflow::types::Address;
+
+        let file = Win32OpenFile {
+            handle: 4,
+            object: Address::null(),
+            granted_access: 0,
+            device_name: Some("\\Device\\HarddiskVolume3".to_string()),
+            file_name: "\\Users\\alice\\a.txt".to_string(),
+            path: "\\Device\\HarddiskVolume3\\Users\\alice\\a.txt".to_string(),
+            current_byte_offset: 0,
+        };
+        let redacted = file.redacted(&Win32RedactionPolicy::new());
+        assert_eq!(redacted.file_name, "\\Users\\<redacted>\\a.txt");
+        assert_eq!(
+            redacted.path,
+            "\\Device\\HarddiskVolume3\\Users\\<redacted>\\a.txt"
+        );
+        assert_eq!(redacted.device_name, file.device_name);
+    }
+}
@@ -0,0 +1,273 @@
+/*!
+Module for cross-referencing the views of shared sections.
+
+Every view of a section that is mapped into a process is described by a mapped vad whose
+subsection points to the `_CONTROL_AREA` of the section. Grouping the mapped vads of all
+processes by their control area yields the processes that share a section and the addresses
+at which they map it. This can be used to trace shared memory ipc channels between processes
+or to find all processes that map a given file.
+
+Walking the vads requires the `_MMVAD_FLAGS::PrivateMemory`, `_MMVAD::Subsection` and
+`_SUBSECTION::ControlArea` offsets which are only available when the offsets were generated
+from a pdb or an isf profile.
+
+# Examples:
+
+```
+use memflow::prelude::v1::*;
+use memflow_win32::win32::Win32Kernel;
+
+fn test<T: 'static + PhysicalMemory + Clone, V: 'static + VirtualTranslate2 + Clone>(
+    kernel: &mut Win32Kernel<T, V>,
+) {
+    for section in kernel.shared_sections().unwrap() {
+        println!("{:x} {:?}", section.control_area, section.file_name);
+        for view in section.views.iter() {
+            println!("  {} ({}) {:x}", view.process_name, view.pid, view.base);
+        }
+    }
+}
+```
+*/
+use std::prelude::v1::*;
+
+use std::collections::BTreeMap;
+
+use super::hollowing::{control_area_file_name, vad_control_area, vad_range, VAD_IMAGE_MAP};
+use super::Win32Kernel;
+use crate::prelude::MmVadOffsetTable;
+
+use log::{debug, trace};
+
+use memflow::architecture::ArchitectureObj;
+use memflow::error::{Error, ErrorKind, ErrorOrigin, Result};
+use memflow::mem::{MemoryView, PhysicalMemory, VirtualTranslate2};
+use memflow::os::{Os, Pid};
+use memflow::types::{umem, Address};
+
+#[cfg(feature = "symstore")]
+use crate::offsets::SymbolStore;
+
+/// Upper bound for the number of vads of a single process
+const MAX_VAD_COUNT: usize = 0x10000;
+
+/// A view of a section that is mapped into a process
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize))]
+pub struct Win32SectionView {
+    pub pid: Pid,
+    pub process_name: String,
+    pub eprocess: Address,
+    /// Address of the vad that describes the view
+    pub vad: Address,
+    pub base: Address,
+    pub size: umem,
+    /// True if the section is mapped as an executable image
+    pub image: bool,
+}
+
+/// A section and all of its views
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize))]
+pub struct Win32MappedSection {
+    /// Address of the `_CONTROL_AREA` that identifies the section
+    pub control_area: Address,
+    /// Name of the backing file, `None` for sections that are backed by the pagefile
+    pub file_name: Option<String>,
+    pub views: Vec<Win32SectionView>,
+}
+
+impl Win32MappedSection {
+    /// Returns the ids of the processes that map the section.
+    pub fn pids(&self) -> Vec<Pid> {
+        let mut pids = self.views.iter().map(|view| view.pid).collect::<Vec<_>>();
+        pids.sort_unstable();
+        pids.dedup();
+        pids
+    }
+
+    /// Returns true if the section is mapped by more than one process.
+    pub fn is_shared(&self) -> bool {
+        self.pids().len() > 1
+    }
+}
+
+impl<T: 'static + PhysicalMemory + Clone, V: 'static + VirtualTranslate2 + Clone>
+    Win32Kernel<T, V>
+{
+    /// Enumerates all sections that are mapped into any process together with their views.
+    ///
+    /// Processes whose vad tree cannot be read are skipped.
+    pub fn mapped_sections(&mut self) -> Result<Vec<Win32MappedSection>> {
+        let offsets = self.offsets.mm_vad();
+        if offsets.private_memory_bit == 0
+            || offsets.subsection == 0
+            || offsets.subsection_control_area == 0
+        {
+            return Err(
+                Error(ErrorOrigin::OsLayer, ErrorKind::NotSupported).log_info(
+                    "the vad offsets required to resolve mapped sections are not available",
+                ),
+            );
+        }
+
+        let arch: ArchitectureObj = self.kernel_info.os_info.arch.into();
+        let mut sections = BTreeMap::new();
+        for proc in self.process_info_list()? {
+            let vad_root = match self.read_vad_root(proc.address) {
+                Ok(vad_root) if !vad_root.is_null() => vad_root,
+                _ => continue,
+            };
+
+            let vads = mapped_vads(&mut self.virt_mem, vad_root, &offsets, arch);
+            trace!("{} maps {} sections", proc.name, vads.len());
+            for (vad, control_area) in vads.into_iter() {
+                let (start, end) = match vad_range(&mut self.virt_mem, vad, &offsets) {
+                    Ok(range) => range,
+                    Err(_) => continue,
+                };
+                let flags = self
+                    .virt_mem
+                    .read::<u64>(vad + offsets.u)
+                    .unwrap_or_default();
+                let vad_type = ((flags >> offsets.vad_type_bit.min(61)) & 0b111) as u32;
+
+                let view = Win32SectionView {
+                    pid: proc.pid,
+                    process_name: proc.name.to_string(),
+                    eprocess: proc.address,
+                    vad,
+                    base: start,
+                    size: end.to_umem() - start.to_umem(),
+                    image: offsets.vad_type_bit != 0 && vad_type == VAD_IMAGE_MAP,
+                };
+
+                let virt_mem = &mut self.virt_mem;
+                sections
+                    .entry(control_area)
+                    .or_insert_with(|| Win32MappedSection {
+                        control_area,
+                        file_name: control_area_file_name(virt_mem, control_area, &offsets, arch),
+                        views: vec![],
+                    })
+                    .views
+                    .push(view);
+            }
+        }
+
+        debug!("found {} mapped sections", sections.len());
+        Ok(sections.into_values().collect())
+    }
+
+    /// Enumerates the sections that are mapped by more than one process.
+    pub fn shared_sections(&mut self) -> Result<Vec<Win32MappedSection>> {
+        Ok(self
+            .mapped_sections()?
+            .into_iter()
+            .filter(Win32MappedSection::is_shared)
+            .collect())
+    }
+
+    /// Returns all views of the section with the given control area.
+    pub fn section_views(&mut self, control_area: Address) -> Result<Win32MappedSection> {
+        self.mapped_sections()?
+            .into_iter()
+            .find(|section| section.control_area == control_area)
+            .ok_or_else(|| {
+                Error(ErrorOrigin::OsLayer, ErrorKind::NotFound)
+                    .log_info("the section is not mapped by any process")
+            })
+    }
+
+    /// Returns the sections that are backed by the given file and their views.
+    ///
+    /// The name is compared case insensitively against the end of the path of the backing file,
+    /// e.g. `ntdll.dll` matches `\Windows\System32\ntdll.dll`.
+    pub fn file_section_views(&mut self, file_name: &str) -> Result<Vec<Win32MappedSection>> {
+        let file_name = file_name.to_lowercase();
+        Ok(self
+            .mapped_sections()?
+            .into_iter()
+            .filter(|section| {
+                section
+                    .file_name
+                    .as_ref()
+                    .map_or(false, |name| name.to_lowercase().ends_with(&file_name))
+            })
+            .collect())
+    }
+
+    /// Returns all views of a section object, e.g. a named section found by
+    /// [`Win32Kernel::named_objects`].
+    ///
+    /// The layout of the section object is resolved from the kernel pdb which is loaded from
+    /// the given symbol store.
+    #[cfg(feature = "symstore")]
+    pub fn section_object_views(
+        &mut self,
+        store: &SymbolStore,
+        section: Address,
+    ) -> Result<Win32MappedSection> {
+        let mut types = self.kernel_types_from_store(store)?;
+        let arch: ArchitectureObj = self.kernel_info.os_info.arch.into();
+
+        let control_area = if let Ok(offset) = types.offset_of("_SECTION.u1.ControlArea") {
+            // the low bits of the pointer are used as flags since nt 10.0
+            let control_area = self.virt_mem.read_addr_arch(arch, section + offset)?;
+            Address::from(control_area.to_umem() & !0x3)
+        } else {
+            // older versions reference the control area through the segment
+            let segment = self
+                .virt_mem
+                .read_addr_arch(arch, section + types.offset_of("_SECTION_OBJECT.Segment")?)?;
+            self.virt_mem
+                .read_addr_arch(arch, segment + types.offset_of("_SEGMENT.ControlArea")?)?
+        };
+
+        self.section_views(control_area)
+    }
+}
+
+/// Returns the mapped vads of a vad tree and the control areas of their sections.
+fn mapped_vads<M: MemoryView>(
+    mem: &mut M,
+    root: Address,
+    offsets: &MmVadOffsetTable,
+    arch: ArchitectureObj,
+) -> Vec<(Address, Address)> {
+    let mut out = vec![];
+    let mut stack = vec![root];
+    let mut count = 0;
+    while let Some(vad) = stack.pop() {
+        if vad.is_null() {
+            continue;
+        }
+        count += 1;
+        if count > MAX_VAD_COUNT {
+            debug!("vad tree at {:x} is too large", root);
+            break;
+        }
+
+        for child in 0..2 {
+            if let Ok(node) = mem.read_addr_arch(
+                arch,
+                vad + offsets.vad_node + child * arch.size_addr() as umem,
+            ) {
+                stack.push(node);
+            }
+        }
+
+        // the flags are 64 bits wide on older x64 versions
+        let flags = match mem.read::<u64>(vad + offsets.u) {
+            Ok(flags) => flags,
+            Err(_) => continue,
+        };
+        if (flags >> offsets.private_memory_bit.min(63)) & 1 != 0 {
+            continue;
+        }
+        if let Some(control_area) = vad_control_area(mem, vad, offsets, arch) {
+            out.push((vad, control_area));
+        }
+    }
+    out
+}
@@ -0,0 +1,260 @@
+/*!
+Module for resolving the win32k shadow system service table.
+
+GUI threads use a second service table (`W32pServiceTable`) which is located in win32k.sys.
+Since win32k.sys is mapped in session space it can only be read from within the context
+of a process that is attached to a session. Similar to the keyboard module a
+proxy process is used to read the table.
+
+Every entry is resolved to its target routine and attributed to the kernel module
+containing it so that hooked entries pointing outside of the win32k drivers become visible.
+
+With the `symstore` feature enabled the table is located through the pdb of win32k.sys
+which is loaded from the symbol store of the kernel. The exports of win32k.sys are used
+as a fallback, recent builds of win32k.sys do not export the table anymore though.
+*/
+use std::prelude::v1::*;
+
+#[cfg(feature = "symstore")]
+use super::Win32ModuleInfo;
+use super::{Win32Kernel, Win32Process};
+#[cfg(feature = "symstore")]
+use crate::offsets::{PdbSymbols, SymbolStore};
+
+use memflow::architecture::ArchitectureObj;
+use memflow::error::{Error, ErrorKind, ErrorOrigin, Result};
+use memflow::mem::{MemoryView, PhysicalMemory, VirtualTranslate2, VirtualTranslate3};
+use memflow::os::{ExportInfo, ModuleInfo, Os, Process};
+use memflow::types::{umem, Address};
+
+use log::debug;
+#[cfg(feature = "symstore")]
+use log::warn;
+
+/// Names of processes that are used to read session space memory.
+const SESSION_PROXY_PROCESSES: [&str; 4] = ["winlogon.exe", "csrss.exe", "explorer.exe", "dwm.exe"];
+
+/// Upper bound for the amount of entries in a service table
+const MAX_SERVICE_LIMIT: u32 = 0x2000;
+
+/// A single resolved service table entry
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize))]
+pub struct Win32ServiceTableEntry {
+    /// Index of the service in the table (without the table index bits)
+    pub index: u32,
+    /// Address of the service routine
+    pub target: Address,
+    /// Kernel module containing the service routine
+    pub module: Option<ModuleInfo>,
+}
+
+impl Win32ServiceTableEntry {
+    /// Returns true if the entry points outside of the expected module.
+    pub fn is_hooked(&self, expected_modules: &[&str]) -> bool {
+        match &self.module {
+            Some(m) => !expected_modules
+                .iter()
+                .any(|e| m.name.as_ref().eq_ignore_ascii_case(e)),
+            None => true,
+        }
+    }
+}
+
+/// A resolved service table
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize))]
+pub struct Win32ServiceTable {
+    pub base: Address,
+    pub limit: u32,
+    pub entries: Vec<Win32ServiceTableEntry>,
+}
+
+impl Win32ServiceTable {
+    /// Returns all entries pointing outside of the win32k drivers.
+    pub fn hooked_entries(&self) -> impl Iterator<Item = &Win32ServiceTableEntry> {
+        self.entries
+            .iter()
+            .filter(|e| e.is_hooked(&["win32k.sys", "win32kbase.sys", "win32kfull.sys"]))
+    }
+}
+
+impl<T: 'static + PhysicalMemory + Clone, V: 'static + VirtualTranslate2 + Clone>
+    Win32Kernel<T, V>
+{
+    /// Resolves the shadow service table (`W32pServiceTable`) used by GUI threads.
+    ///
+    /// With the `symstore` feature enabled the pdb of win32k.sys is loaded from the symbol store
+    /// of the kernel (see [`Win32Kernel::set_symbol_store`]). Without a symbol store only the
+    /// exports of win32k.sys are used.
+    pub fn shadow_service_table(&mut self) -> Result<Win32ServiceTable> {
+        #[cfg(feature = "symstore")]
+        if let Some(store) = self.symbol_store.clone() {
+            return self.shadow_service_table_from_store(&store);
+        }
+        self.shadow_service_table_with(ExportLocator)
+    }
+
+    /// Resolves the shadow service table (`W32pServiceTable`) used by GUI threads.
+    ///
+    /// The pdb of win32k.sys is loaded from the given symbol store.
+    #[cfg(feature = "symstore")]
+    pub fn shadow_service_table_from_store(
+        &mut self,
+        store: &SymbolStore,
+    ) -> Result<Win32ServiceTable> {
+        self.shadow_service_table_with(PdbLocator(store))
+    }
+
+    fn shadow_service_table_with<L: ServiceTableLocator>(
+        &mut self,
+        locator: L,
+    ) -> Result<Win32ServiceTable> {
+        let win32k = self.module_by_name("win32k.sys")?;
+        debug!("found win32k.sys: {:?}", win32k);
+
+        let modules = self.module_list()?;
+        let arch = self.kernel_info.os_info.arch.into();
+
+        let procs = self.process_info_list()?;
+        procs
+            .into_iter()
+            .filter(|p| {
+                SESSION_PROXY_PROCESSES
+                    .iter()
+                    .any(|n| p.name.as_ref().eq_ignore_ascii_case(n))
+            })
+            .find_map(|p| {
+                let mut process = self.process_by_info(p).ok()?;
+                read_shadow_service_table(&mut process, &win32k, &modules, arch, &locator)
+                    .map_err(|err| debug!("unable to read shadow service table: {}", err))
+                    .ok()
+            })
+            .ok_or_else(|| {
+                Error(ErrorOrigin::OsLayer, ErrorKind::NotFound)
+                    .log_info("unable to find any proxy process that maps W32pServiceTable")
+            })
+    }
+}
+
+/// Locates `W32pServiceTable` and `W32pServiceLimit` relative to the base of win32k.sys.
+trait ServiceTableLocator {
+    fn locate<M: MemoryView>(&self, mem: &mut M, win32k: &ModuleInfo) -> Result<(umem, umem)>;
+}
+
+/// Locates the service table through the exports of win32k.sys
+struct ExportLocator;
+
+impl ServiceTableLocator for ExportLocator {
+    fn locate<M: MemoryView>(&self, mem: &mut M, win32k: &ModuleInfo) -> Result<(umem, umem)> {
+        let mut table = None;
+        let mut limit = None;
+        let callback = &mut |export: ExportInfo| {
+            match export.name.as_ref() {
+                "W32pServiceTable" => table = Some(export.offset),
+                "W32pServiceLimit" => limit = Some(export.offset),
+                _ => {}
+            }
+            table.is_none() || limit.is_none()
+        };
+        memflow::os::util::module_export_list_callback(mem, win32k, callback.into())?;
+
+        table.zip(limit).ok_or_else(|| {
+            Error(ErrorOrigin::OsLayer, ErrorKind::ExportNotFound)
+                .log_info("unable to find W32pServiceTable or W32pServiceLimit")
+        })
+    }
+}
+
+/// Locates the service table through the pdb of win32k.sys and falls back to its exports
+#[cfg(feature = "symstore")]
+struct PdbLocator<'a>(&'a SymbolStore);
+
+#[cfg(feature = "symstore")]
+impl ServiceTableLocator for PdbLocator<'_> {
+    fn locate<M: MemoryView>(&self, mem: &mut M, win32k: &ModuleInfo) -> Result<(umem, umem)> {
+        let symbols = win32k.pdb(mem, self.0).and_then(|pdb| {
+            PdbSymbols::new(&pdb).map_err(|_| {
+                Error(ErrorOrigin::OsLayer, ErrorKind::InvalidExeFile)
+                    .log_info("unable to parse symbols of win32k pdb")
+            })
+        });
+
+        let err = match symbols {
+            Ok(symbols) => {
+                let table = symbols.find_symbol_undecorated("W32pServiceTable");
+                let limit = symbols.find_symbol_undecorated("W32pServiceLimit");
+                if let Some((table, limit)) = table.zip(limit) {
+                    return Ok((table as umem, limit as umem));
+                }
+                Error(ErrorOrigin::OsLayer, ErrorKind::NotFound)
+                    .log_info("W32pServiceTable or W32pServiceLimit not found in win32k pdb")
+            }
+            Err(err) => err,
+        };
+
+        // recent builds of win32k.sys do not export the table, report the pdb error in that case
+        warn!(
+            "unable to locate W32pServiceTable through the win32k pdb ({}), falling back to the exports of win32k.sys",
+            err
+        );
+        ExportLocator.locate(mem, win32k).map_err(|_| err)
+    }
+}
+
+fn read_shadow_service_table<
+    T: PhysicalMemory,
+    V: VirtualTranslate2,
+    D: VirtualTranslate3,
+    L: ServiceTableLocator,
+>(
+    process: &mut Win32Process<T, V, D>,
+    win32k: &ModuleInfo,
+    modules: &[ModuleInfo],
+    arch: ArchitectureObj,
+    locator: &L,
+) -> Result<Win32ServiceTable> {
+    let (table, limit) = locator.locate(&mut process.virt_mem, win32k)?;
+    let base = win32k.base + table;
+
+    let limit: u32 = process.virt_mem.read(win32k.base + limit)?;
+    if limit == 0 || limit > MAX_SERVICE_LIMIT {
+        return Err(Error(ErrorOrigin::OsLayer, ErrorKind::InvalidMemory)
+            .log_info("W32pServiceLimit is out of range"));
+    }
+    debug!("W32pServiceTable={:x} W32pServiceLimit={}", base, limit);
+
+    let raw = process
+        .virt_mem
+        .read_raw(base, limit as usize * 4)?
+        .chunks_exact(4)
+        .map(|c| u32::from_le_bytes([c[0], c[1], c[2], c[3]]))
+        .collect::<Vec<_>>();
+
+    let entries = raw
+        .into_iter()
+        .enumerate()
+        .map(|(index, value)| {
+            let target = match arch.bits() {
+                // x64 entries are relative offsets, the low 4 bits encode the argument count
+                64 => Address::from((base.to_umem() as i64 + ((value as i32) >> 4) as i64) as umem),
+                _ => Address::from(value as umem),
+            };
+
+            Win32ServiceTableEntry {
+                index: index as u32,
+                target,
+                module: modules
+                    .iter()
+                    .find(|m| target >= m.base && target < m.base + m.size)
+                    .cloned(),
+            }
+        })
+        .collect();
+
+    Ok(Win32ServiceTable {
+        base,
+        limit,
+        entries,
+    })
+}
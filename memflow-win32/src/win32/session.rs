@@ -0,0 +1,176 @@
+/*!
+Module for attributing processes to terminal services sessions.
+
+Every process that is not part of session 0 isolation (e.g. the System process)
+references the `_MM_SESSION_SPACE` of its session in `_EPROCESS::Session`.
+On multi-session systems (e.g. Windows Server with RDP) this can be used
+to slice the process list by session.
+
+# Examples:
+
+```
+use memflow::prelude::v1::*;
+use memflow_win32::win32::Win32Kernel;
+
+fn test<T: 'static + PhysicalMemory + Clone, V: 'static + VirtualTranslate2 + Clone>(
+    kernel: &mut Win32Kernel<T, V>,
+) {
+    for summary in kernel.session_summaries().unwrap() {
+        println!(
+            "session {}: {} processes ({} alive)",
+            summary.session_id, summary.process_count, summary.alive_count
+        );
+    }
+
+    for proc in kernel.processes_by_session(1).unwrap() {
+        println!("{} ({})", proc.name, proc.pid);
+    }
+}
+```
+*/
+use std::prelude::v1::*;
+
+use super::Win32Kernel;
+
+use memflow::error::{Error, ErrorKind, ErrorOrigin, PartialResultExt, Result};
+use memflow::mem::{MemoryView, PhysicalMemory, VirtualTranslate2};
+use memflow::os::{Os, Pid, ProcessInfo, ProcessState};
+use memflow::types::Address;
+
+/// Processes that are used to access the session space of the interactive session
+const PROXY_PROCESSES: &[&str] = &["explorer.exe", "winlogon.exe", "dwm.exe"];
+
+/// Terminal services session id
+pub type Win32SessionId = u32;
+
+/// Summary of all processes running in a session
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize))]
+pub struct Win32SessionSummary {
+    pub session_id: Win32SessionId,
+    /// Address of the `_MM_SESSION_SPACE` of this session
+    pub session_space: Address,
+    pub process_count: usize,
+    pub alive_count: usize,
+    pub pids: Vec<Pid>,
+}
+
+impl<T: 'static + PhysicalMemory + Clone, V: 'static + VirtualTranslate2 + Clone>
+    Win32Kernel<T, V>
+{
+    /// Returns the address of the `_MM_SESSION_SPACE` the given process belongs to.
+    ///
+    /// Returns `None` if the process is not attached to a session.
+    pub fn process_session_space(&mut self, info: &ProcessInfo) -> Result<Option<Address>> {
+        if self.offsets.eproc_session() == 0 {
+            return Err(Error(ErrorOrigin::OsLayer, ErrorKind::NotSupported)
+                .log_info("_EPROCESS::Session is not available on this build"));
+        }
+
+        Ok(self
+            .virt_mem
+            .read_addr_arch(
+                self.kernel_info.os_info.arch.into(),
+                info.address + self.offsets.eproc_session(),
+            )?
+            .non_null())
+    }
+
+    /// Returns the session id of the given process.
+    ///
+    /// Returns `None` if the process is not attached to a session.
+    pub fn process_session_id(&mut self, info: &ProcessInfo) -> Result<Option<Win32SessionId>> {
+        match self.process_session_space(info)? {
+            Some(session) => Ok(Some(self.read_session_id(session)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns all processes that belong to the session with the given id.
+    pub fn processes_by_session(&mut self, session_id: Win32SessionId) -> Result<Vec<ProcessInfo>> {
+        let procs = self.process_info_list()?;
+
+        let mut out = vec![];
+        for proc in procs.into_iter() {
+            if self.process_session_id(&proc)? == Some(session_id) {
+                out.push(proc);
+            }
+        }
+
+        Ok(out)
+    }
+
+    /// Groups all processes by their session and returns a summary for each session.
+    ///
+    /// Processes that are not attached to a session are skipped.
+    pub fn session_summaries(&mut self) -> Result<Vec<Win32SessionSummary>> {
+        let procs = self.process_info_list()?;
+
+        let mut summaries: Vec<Win32SessionSummary> = vec![];
+        for proc in procs.iter() {
+            let session_space = match self.process_session_space(proc)? {
+                Some(session) => session,
+                None => continue,
+            };
+
+            let idx = match summaries
+                .iter()
+                .position(|s| s.session_space == session_space)
+            {
+                Some(idx) => idx,
+                None => {
+                    let session_id = self.read_session_id(session_space)?;
+                    summaries.push(Win32SessionSummary {
+                        session_id,
+                        session_space,
+                        process_count: 0,
+                        alive_count: 0,
+                        pids: vec![],
+                    });
+                    summaries.len() - 1
+                }
+            };
+
+            let summary = &mut summaries[idx];
+            summary.process_count += 1;
+            if proc.state == ProcessState::Alive {
+                summary.alive_count += 1;
+            }
+            summary.pids.push(proc.pid);
+        }
+
+        summaries.sort_by_key(|s| s.session_id);
+        Ok(summaries)
+    }
+
+    /// Returns a process of the interactive session.
+    ///
+    /// Session space (e.g. win32k) is only mapped in the processes of a session,
+    /// such a process is used as a proxy to read it.
+    pub(crate) fn interactive_session_proxy(&mut self) -> Result<ProcessInfo> {
+        let procs = self.process_info_list()?;
+        PROXY_PROCESSES
+            .iter()
+            .find_map(|name| {
+                procs
+                    .iter()
+                    .find(|p| p.name.as_ref().eq_ignore_ascii_case(name))
+                    .cloned()
+            })
+            .ok_or_else(|| {
+                Error(ErrorOrigin::OsLayer, ErrorKind::ProcessNotFound)
+                    .log_info("unable to find a proxy process of the interactive session")
+            })
+    }
+
+    fn read_session_id(&mut self, session_space: Address) -> Result<Win32SessionId> {
+        if self.offsets.mm_session_space_id() == 0 {
+            return Err(Error(ErrorOrigin::OsLayer, ErrorKind::NotSupported)
+                .log_info("_MM_SESSION_SPACE::SessionId is not available on this build"));
+        }
+
+        self.virt_mem
+            .read(session_space + self.offsets.mm_session_space_id())
+            .data_part()
+    }
+}
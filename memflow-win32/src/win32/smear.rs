@@ -0,0 +1,473 @@
+/*!
+Module for detecting memory acquisition smear.
+
+When memory is acquired from a running system the kernel keeps modifying its structures
+while they are being read. The resulting snapshot can contain list entries that were unlinked
+or freed in the meantime. This module walks kernel lists and checks every entry for such inconsistencies:
+- the back link of the next entry does not point back to the current entry
+- the entry resides on a page that has been zeroed
+- the process has a dtb outside of physical memory or a creation time that lies in the future
+- the entry cannot be read at all
+
+The current system time is read from `KUSER_SHARED_DATA`, if it is not mapped the creation times are not checked.
+
+The findings are summarized in a smear score between 0 (consistent) and 1 (every entry is affected).
+
+# Examples:
+
+```
+use memflow::prelude::v1::*;
+use memflow_win32::win32::Win32Kernel;
+
+fn test<T: 'static + PhysicalMemory + Clone, V: 'static + VirtualTranslate2 + Clone>(
+    kernel: &mut Win32Kernel<T, V>,
+) {
+    let report = kernel.process_list_smear().unwrap();
+    if !report.is_consistent() {
+        println!("process list smear score: {:.2}", report.score);
+        for finding in report.findings.iter() {
+            println!("{:x}: {:?}", finding.address, finding.kind);
+        }
+    }
+}
+```
+*/
+use std::prelude::v1::*;
+
+use super::Win32Kernel;
+use crate::kernel::ntos::kuser_shared_data;
+
+use memflow::architecture::ArchitectureObj;
+use memflow::error::{PartialResultExt, Result};
+use memflow::mem::{MemoryView, PhysicalMemory, VirtualTranslate2};
+use memflow::types::{umem, Address};
+
+use log::debug;
+
+const MAX_ITER_COUNT: usize = 65536;
+
+/// KUSER_SHARED_DATA::SystemTime offset
+const KUSER_SYSTEM_TIME: umem = 0x14;
+
+/// Number of bytes at the start of each entry that are checked for zeroing
+const ZERO_CHECK_SIZE: usize = 0x40;
+
+/// Kind of inconsistency found in a list entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize))]
+pub enum Win32SmearKind {
+    /// The back link of the next entry points to a different entry
+    LinkMismatch { next: Address, blink: Address },
+    /// The entry resides on a zeroed or freed page
+    ZeroPage,
+    /// The process has an invalid dtb
+    InvalidDtb(Address),
+    /// The process creation time lies after the current system time
+    FutureCreateTime(u64),
+    /// The list could not be followed back to its head
+    Truncated,
+    /// The entry could not be read
+    Unreadable,
+}
+
+/// An inconsistency found in a list entry
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize))]
+pub struct Win32SmearFinding {
+    /// Address of the structure containing the list entry
+    pub address: Address,
+    pub kind: Win32SmearKind,
+}
+
+/// Result of a smear check over a single list
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize))]
+pub struct Win32SmearReport {
+    /// Number of entries that were checked
+    pub entries: usize,
+    pub findings: Vec<Win32SmearFinding>,
+    /// Fraction of entries with at least one finding
+    pub score: f32,
+}
+
+impl Win32SmearReport {
+    /// Returns true if no inconsistencies have been found.
+    pub fn is_consistent(&self) -> bool {
+        self.findings.is_empty()
+    }
+
+    /// Returns all findings for the structure at the given address.
+    pub fn findings_for(&self, address: Address) -> impl Iterator<Item = &Win32SmearFinding> {
+        self.findings.iter().filter(move |f| f.address == address)
+    }
+
+    fn update_score(&mut self) {
+        let mut affected = self.findings.iter().map(|f| f.address).collect::<Vec<_>>();
+        affected.sort_unstable();
+        affected.dedup();
+
+        self.score = if self.entries > 0 {
+            (affected.len() as f32 / self.entries as f32).min(1.0)
+        } else {
+            0.0
+        };
+    }
+}
+
+impl<T: 'static + PhysicalMemory + Clone, V: 'static + VirtualTranslate2 + Clone>
+    Win32Kernel<T, V>
+{
+    /// Checks the active process list for smear.
+    pub fn process_list_smear(&mut self) -> Result<Win32SmearReport> {
+        let list_head = self.kernel_info.eprocess_base + self.offsets.eproc_link();
+        let mut report = self.list_smear(list_head, self.offsets.eproc_link())?;
+
+        let arch = self.kernel_info.os_info.arch;
+        let system_time =
+            match kuser_shared_data(&mut self.virt_mem, arch).and_then(|shared_data| {
+                self.virt_mem
+                    .read::<u64>(shared_data + KUSER_SYSTEM_TIME)
+                    .data_part()
+            }) {
+                Ok(system_time) => Some(system_time),
+                Err(err) => {
+                    debug!(
+                        "unable to read the system time, skipping creation times: {}",
+                        err
+                    );
+                    None
+                }
+            };
+
+        let checks = Win32ProcessSmearChecks {
+            dtb: self.offsets.kproc_dtb(),
+            create_time: self.offsets.eproc_create_time(),
+            max_address: PhysicalMemory::metadata(self).max_address,
+            system_time,
+        };
+        let entries = list_entries(
+            &mut self.virt_mem,
+            arch.into(),
+            list_head,
+            self.offsets.eproc_link(),
+        );
+        for eprocess in entries {
+            checks.check(&mut self.virt_mem, arch.into(), eprocess, &mut report);
+        }
+
+        report.update_score();
+        Ok(report)
+    }
+
+    /// Checks a kernel `LIST_ENTRY` list for smear.
+    ///
+    /// `entry_offset` is the offset of the list entry inside of the structures linked by the list.
+    /// The findings are reported with the address of the containing structure.
+    pub fn list_smear(
+        &mut self,
+        list_head: Address,
+        entry_offset: usize,
+    ) -> Result<Win32SmearReport> {
+        Ok(list_smear(
+            &mut self.virt_mem,
+            self.kernel_info.os_info.arch.into(),
+            list_head,
+            entry_offset,
+            self.offsets.list_blink(),
+        ))
+    }
+}
+
+/// Checks a `LIST_ENTRY` list for smear.
+///
+/// Entries that cannot be read are reported as findings, the walk stops at the first one.
+fn list_smear<M: MemoryView>(
+    mem: &mut M,
+    arch: ArchitectureObj,
+    list_head: Address,
+    entry_offset: usize,
+    blink_offset: usize,
+) -> Win32SmearReport {
+    let mut report = Win32SmearReport::default();
+    let unreadable = |report: &mut Win32SmearReport, address| {
+        report.findings.push(Win32SmearFinding {
+            address,
+            kind: Win32SmearKind::Unreadable,
+        })
+    };
+
+    let mut list_entry = list_head;
+    let mut terminated = false;
+    for _ in 0..MAX_ITER_COUNT {
+        let flink = match mem.read_addr_arch(arch, list_entry) {
+            Ok(flink) => flink,
+            Err(_) => {
+                unreadable(&mut report, list_entry - entry_offset);
+                break;
+            }
+        };
+        if flink.is_null() || flink == list_entry {
+            break;
+        }
+
+        let blink = match mem.read_addr_arch(arch, flink + blink_offset) {
+            Ok(blink) => blink,
+            Err(_) => {
+                unreadable(&mut report, flink - entry_offset);
+                break;
+            }
+        };
+        let address = list_entry - entry_offset;
+        if blink != list_entry {
+            report.findings.push(Win32SmearFinding {
+                address,
+                kind: Win32SmearKind::LinkMismatch { next: flink, blink },
+            });
+        }
+
+        if flink == list_head {
+            terminated = true;
+            break;
+        }
+
+        report.entries += 1;
+        let address = flink - entry_offset;
+        let mut buf = [0u8; ZERO_CHECK_SIZE];
+        if mem.read_raw_into(address, &mut buf).data_part().is_err() {
+            unreadable(&mut report, address);
+            break;
+        }
+        if buf.iter().all(|&b| b == 0) {
+            report.findings.push(Win32SmearFinding {
+                address,
+                kind: Win32SmearKind::ZeroPage,
+            });
+            // the links of a zeroed entry cannot be followed any further
+            break;
+        }
+
+        list_entry = flink;
+    }
+
+    if !terminated {
+        report.findings.push(Win32SmearFinding {
+            address: list_entry - entry_offset,
+            kind: Win32SmearKind::Truncated,
+        });
+    }
+
+    report.update_score();
+    debug!(
+        "list_head={:x} entries={} findings={} score={}",
+        list_head,
+        report.entries,
+        report.findings.len(),
+        report.score
+    );
+    report
+}
+
+/// Returns the addresses of the structures linked by the list up to the first unreadable entry.
+fn list_entries<M: MemoryView>(
+    mem: &mut M,
+    arch: ArchitectureObj,
+    list_head: Address,
+    entry_offset: usize,
+) -> Vec<Address> {
+    let mut entries = vec![];
+    let mut list_entry = mem.read_addr_arch(arch, list_head).unwrap_or_default();
+    while !list_entry.is_null() && list_entry != list_head && entries.len() < MAX_ITER_COUNT {
+        entries.push(list_entry - entry_offset);
+        list_entry = mem.read_addr_arch(arch, list_entry).unwrap_or_default();
+    }
+    entries
+}
+
+/// Consistency checks of a single `_EPROCESS`
+struct Win32ProcessSmearChecks {
+    dtb: usize,
+    create_time: usize,
+    max_address: Address,
+    system_time: Option<u64>,
+}
+
+impl Win32ProcessSmearChecks {
+    fn check<M: MemoryView>(
+        &self,
+        mem: &mut M,
+        arch: ArchitectureObj,
+        eprocess: Address,
+        report: &mut Win32SmearReport,
+    ) {
+        let mut push = |kind| {
+            report.findings.push(Win32SmearFinding {
+                address: eprocess,
+                kind,
+            })
+        };
+
+        match mem.read_addr_arch(arch, eprocess + self.dtb) {
+            Ok(dtb) => {
+                // the low bits of the dtb are used for pcid tagging
+                let dtb = Address::from(dtb.to_umem() & !0xfff);
+                if dtb.is_null() || (!self.max_address.is_null() && dtb > self.max_address) {
+                    push(Win32SmearKind::InvalidDtb(dtb));
+                }
+            }
+            Err(_) => push(Win32SmearKind::Unreadable),
+        }
+
+        if let Some(system_time) = self.system_time.filter(|_| self.create_time != 0) {
+            match mem.read::<u64>(eprocess + self.create_time).data_part() {
+                Ok(create_time) if create_time > system_time => {
+                    push(Win32SmearKind::FutureCreateTime(create_time))
+                }
+                Ok(_) => {}
+                Err(_) => push(Win32SmearKind::Unreadable),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use memflow::architecture::ArchitectureIdent;
+    use memflow::dummy::DummyMemory;
+    use memflow::types::size;
+
+    const ENTRY_OFFSET: usize = 0x10;
+    const BLINK_OFFSET: usize = 8;
+
+    fn arch() -> ArchitectureObj {
+        ArchitectureIdent::X86(64, false).into()
+    }
+
+    fn link<M: MemoryView>(mem: &mut M, entry: u64, flink: u64, blink: u64) {
+        mem.write(Address::from(entry), &flink).unwrap();
+        mem.write(Address::from(entry + BLINK_OFFSET as u64), &blink)
+            .unwrap();
+    }
+
+    #[test]
+    fn consistent_list() {
+        let mut mem = DummyMemory::new(size::mb(1));
+        let mut view = mem.phys_view();
+        link(&mut view, 0x1000, 0x2000, 0x3000);
+        link(&mut view, 0x2000, 0x3000, 0x1000);
+        link(&mut view, 0x3000, 0x1000, 0x2000);
+
+        let report = list_smear(
+            &mut view,
+            arch(),
+            Address::from(0x1000u64),
+            ENTRY_OFFSET,
+            BLINK_OFFSET,
+        );
+        assert_eq!(report.entries, 2);
+        assert!(report.is_consistent());
+        assert_eq!(report.score, 0.0);
+        assert_eq!(
+            list_entries(&mut view, arch(), Address::from(0x1000u64), ENTRY_OFFSET),
+            vec![Address::from(0x1ff0u64), Address::from(0x2ff0u64)]
+        );
+    }
+
+    #[test]
+    fn link_mismatch() {
+        let mut mem = DummyMemory::new(size::mb(1));
+        let mut view = mem.phys_view();
+        link(&mut view, 0x1000, 0x2000, 0x3000);
+        link(&mut view, 0x2000, 0x3000, 0x1000);
+        link(&mut view, 0x3000, 0x1000, 0x4000);
+
+        let report = list_smear(
+            &mut view,
+            arch(),
+            Address::from(0x1000u64),
+            ENTRY_OFFSET,
+            BLINK_OFFSET,
+        );
+        assert_eq!(report.findings.len(), 1);
+        assert_eq!(report.findings[0].address, Address::from(0x1ff0u64));
+        assert_eq!(
+            report.findings[0].kind,
+            Win32SmearKind::LinkMismatch {
+                next: Address::from(0x3000u64),
+                blink: Address::from(0x4000u64)
+            }
+        );
+        assert_eq!(report.score, 0.5);
+    }
+
+    #[test]
+    fn unreadable_entry() {
+        let mut mem = DummyMemory::new(size::mb(1));
+        let mut view = mem.phys_view();
+        link(&mut view, 0x1000, 0x2000, 0x2000);
+        link(&mut view, 0x2000, size::gb(16) as umem, 0x1000);
+
+        let report = list_smear(
+            &mut view,
+            arch(),
+            Address::from(0x1000u64),
+            ENTRY_OFFSET,
+            BLINK_OFFSET,
+        );
+        let kinds = report.findings.iter().map(|f| f.kind).collect::<Vec<_>>();
+        assert_eq!(
+            kinds,
+            vec![Win32SmearKind::Unreadable, Win32SmearKind::Truncated]
+        );
+        assert_eq!(
+            list_entries(&mut view, arch(), Address::from(0x1000u64), ENTRY_OFFSET).len(),
+            2
+        );
+    }
+
+    #[test]
+    fn process_checks() {
+        let mut mem = DummyMemory::new(size::mb(1));
+        let mut view = mem.phys_view();
+        let checks = Win32ProcessSmearChecks {
+            dtb: 0x28,
+            create_time: 0x30,
+            max_address: Address::from(size::mb(4) as umem),
+            system_time: Some(1000),
+        };
+
+        // pcid tagged dtb
+        view.write(Address::from(0x1028u64), &0x1ad002u64).unwrap();
+        view.write(Address::from(0x1030u64), &999u64).unwrap();
+        let mut report = Win32SmearReport::default();
+        checks.check(&mut view, arch(), Address::from(0x1000u64), &mut report);
+        assert!(report.is_consistent());
+
+        view.write(Address::from(0x2028u64), &0x1_0000_0000u64)
+            .unwrap();
+        view.write(Address::from(0x2030u64), &1001u64).unwrap();
+        checks.check(&mut view, arch(), Address::from(0x2000u64), &mut report);
+        let kinds = report.findings.iter().map(|f| f.kind).collect::<Vec<_>>();
+        assert_eq!(
+            kinds,
+            vec![
+                Win32SmearKind::InvalidDtb(Address::from(0x1_0000_0000u64)),
+                Win32SmearKind::FutureCreateTime(1001)
+            ]
+        );
+
+        let mut report = Win32SmearReport::default();
+        checks.check(
+            &mut view,
+            arch(),
+            Address::from(size::gb(16) as umem),
+            &mut report,
+        );
+        let kinds = report.findings.iter().map(|f| f.kind).collect::<Vec<_>>();
+        assert_eq!(
+            kinds,
+            vec![Win32SmearKind::Unreadable, Win32SmearKind::Unreadable]
+        );
+    }
+}
@@ -0,0 +1,121 @@
+/*!
+Module for caching the process list and the module lists of processes.
+
+Tools that poll the process and module lists periodically (e.g. a user interface refreshing
+every frame) would otherwise walk the kernel lists on every call. A [`Win32Snapshot`] keeps
+the results until its cache validator invalidates them, either after a timeout or manually
+through [`Win32Snapshot::invalidate`].
+
+# Examples:
+
+```
+use std::time::Duration;
+
+use memflow::prelude::v1::*;
+use memflow::types::cache::TimedCacheValidator;
+use memflow_win32::win32::{Win32Kernel, Win32Snapshot};
+
+fn test<T: 'static + PhysicalMemory + Clone, V: 'static + VirtualTranslate2 + Clone>(
+    kernel: &mut Win32Kernel<T, V>,
+) {
+    let mut snapshot =
+        Win32Snapshot::new(TimedCacheValidator::new(Duration::from_millis(500).into()));
+
+    for process in snapshot.process_list(kernel).unwrap().to_vec() {
+        let modules = snapshot.module_list(kernel, &process).unwrap();
+        println!("{} ({} modules)", process.name, modules.len());
+    }
+}
+```
+*/
+use std::prelude::v1::*;
+
+use std::collections::HashMap;
+
+use super::Win32Kernel;
+
+use memflow::error::Result;
+use memflow::mem::{PhysicalMemory, VirtualTranslate2};
+use memflow::os::{ModuleInfo, Os, Pid, Process, ProcessInfo};
+use memflow::types::{Address, CacheValidator, DefaultCacheValidator};
+
+const PROCESS_SLOT: usize = 0;
+const MODULE_SLOT: usize = 1;
+const SLOT_COUNT: usize = 2;
+
+/// Cache of the process list and the module lists of processes
+pub struct Win32Snapshot<Vat> {
+    validator: Vat,
+    processes: Vec<ProcessInfo>,
+    /// Module lists by the address of the process
+    modules: HashMap<Address, (Pid, Vec<ModuleInfo>)>,
+}
+
+impl Default for Win32Snapshot<DefaultCacheValidator> {
+    fn default() -> Self {
+        Self::new(DefaultCacheValidator::default())
+    }
+}
+
+impl<Vat: CacheValidator> Win32Snapshot<Vat> {
+    /// Creates a new snapshot whose lists are kept as long as the validator considers them valid.
+    pub fn new(mut validator: Vat) -> Self {
+        validator.allocate_slots(SLOT_COUNT);
+        Self {
+            validator,
+            processes: vec![],
+            modules: HashMap::new(),
+        }
+    }
+
+    /// Returns the cached process list and walks the process list of the kernel if it is outdated.
+    pub fn process_list<T, V>(&mut self, kernel: &mut Win32Kernel<T, V>) -> Result<&[ProcessInfo]>
+    where
+        T: 'static + PhysicalMemory + Clone,
+        V: 'static + VirtualTranslate2 + Clone,
+    {
+        self.validator.update_validity();
+        if !self.validator.is_slot_valid(PROCESS_SLOT) {
+            self.processes = kernel.process_info_list()?;
+            self.validator.validate_slot(PROCESS_SLOT);
+        }
+        Ok(&self.processes)
+    }
+
+    /// Returns the cached module list of the process and walks it if it is outdated.
+    pub fn module_list<T, V>(
+        &mut self,
+        kernel: &mut Win32Kernel<T, V>,
+        process: &ProcessInfo,
+    ) -> Result<&[ModuleInfo]>
+    where
+        T: 'static + PhysicalMemory + Clone,
+        V: 'static + VirtualTranslate2 + Clone,
+    {
+        self.validator.update_validity();
+        if !self.validator.is_slot_valid(MODULE_SLOT) {
+            self.modules.clear();
+            self.validator.validate_slot(MODULE_SLOT);
+        }
+
+        // the memory of an exited process might be reused for a new one
+        let outdated = self
+            .modules
+            .get(&process.address)
+            .map_or(true, |(pid, _)| *pid != process.pid);
+        if outdated {
+            let modules = kernel.process_by_info(process.clone())?.module_list()?;
+            self.modules.insert(process.address, (process.pid, modules));
+        }
+
+        Ok(&self.modules[&process.address].1)
+    }
+
+    /// Invalidates all cached lists.
+    pub fn invalidate(&mut self) {
+        self.validator.invalidate_slot(PROCESS_SLOT);
+        self.validator.invalidate_slot(MODULE_SLOT);
+        self.processes.clear();
+        self.modules.clear();
+    }
+}
@@ -0,0 +1,265 @@
+/*!
+Module for verifying the integrity of the syscall stubs in ntdll.
+
+Every `Nt*`/`Zw*` export of ntdll is a small stub that loads the syscall number into `eax`
+and transfers control into the kernel. User-mode hooking engines usually overwrite the
+first bytes of these stubs with a jump into their own module.
+
+The expected syscall number of each stub is derived from the build itself:
+syscall numbers are assigned in the order the `Zw*` stubs appear in the ntdll image.
+*/
+use std::prelude::v1::*;
+
+use super::{Win32Process, Win32VirtualTranslate};
+
+use memflow::architecture::ArchitectureIdent;
+use memflow::error::{Error, ErrorKind, ErrorOrigin, Result};
+use memflow::mem::{MemoryView, PhysicalMemory, VirtualTranslate2};
+use memflow::os::{ExportInfo, ModuleInfo, Process};
+use memflow::types::{umem, Address};
+
+use log::{debug, trace};
+use std::convert::TryInto;
+
+/// Number of bytes that are read and compared for each stub
+pub const SYSCALL_STUB_SIZE: usize = 0x20;
+
+/// Describes why a syscall stub was flagged
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize))]
+pub enum Win32SyscallStubState {
+    /// The stub starts with a jump to the contained address
+    Jump(Address),
+    /// The stub loads a different syscall number than expected
+    SyscallNumberMismatch(u32),
+    /// The stub does not match the expected instruction pattern
+    Modified,
+}
+
+/// A syscall stub that does not match the expected pattern
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize))]
+pub struct Win32SyscallStubHook {
+    /// Name of the export (e.g. `NtOpenProcess`)
+    pub name: String,
+    /// Virtual address of the stub
+    pub address: Address,
+    /// Architecture of the ntdll the stub resides in
+    pub arch: ArchitectureIdent,
+    /// Syscall number the stub is expected to load
+    pub expected_syscall: u32,
+    pub state: Win32SyscallStubState,
+    /// Module that contains the hook target, if any
+    pub hook_module: Option<ModuleInfo>,
+    /// The first bytes of the stub
+    pub bytes: [u8; SYSCALL_STUB_SIZE],
+}
+
+impl<T: PhysicalMemory, V: VirtualTranslate2> Win32Process<T, V, Win32VirtualTranslate> {
+    /// Compares all syscall stubs in the native and wow64 ntdll of this process
+    /// against the expected pattern and returns every stub that deviates from it.
+    pub fn syscall_stub_hooks(&mut self) -> Result<Vec<Win32SyscallStubHook>> {
+        let mut archs = vec![self.proc_info.base_info.sys_arch];
+        if self.proc_info.base_info.proc_arch != self.proc_info.base_info.sys_arch {
+            archs.push(self.proc_info.base_info.proc_arch);
+        }
+
+        let modules = self.module_list()?;
+
+        let mut hooks = vec![];
+        for arch in archs.into_iter() {
+            let ntdll = modules
+                .iter()
+                .find(|m| m.arch == arch && m.name.as_ref().eq_ignore_ascii_case("ntdll.dll"))
+                .ok_or_else(|| {
+                    Error(ErrorOrigin::OsLayer, ErrorKind::ModuleNotFound)
+                        .log_info("unable to find ntdll.dll")
+                })?
+                .clone();
+            hooks.append(&mut self.ntdll_syscall_stub_hooks(&ntdll, &modules)?);
+        }
+
+        Ok(hooks)
+    }
+
+    fn ntdll_syscall_stub_hooks(
+        &mut self,
+        ntdll: &ModuleInfo,
+        modules: &[ModuleInfo],
+    ) -> Result<Vec<Win32SyscallStubHook>> {
+        match ntdll.arch {
+            ArchitectureIdent::X86(_, _) => {}
+            _ => {
+                return Err(Error(ErrorOrigin::OsLayer, ErrorKind::NotSupported)
+                    .log_info("syscall stub verification is only supported on x86 targets"))
+            }
+        }
+
+        let exports = self.module_export_list(ntdll)?;
+        let stubs = syscall_stubs(&exports);
+        debug!("found {} syscall stubs in {}", stubs.len(), ntdll.path);
+
+        let mut hooks = vec![];
+        for (expected_syscall, (offset, names)) in stubs.iter().enumerate() {
+            let address = ntdll.base + *offset;
+
+            let mut bytes = [0u8; SYSCALL_STUB_SIZE];
+            if self.virt_mem.read_raw_into(address, &mut bytes).is_err() {
+                trace!("unable to read syscall stub at {:x}", address);
+                continue;
+            }
+
+            let state = match decode_indirect_jump(&bytes, address, ntdll.arch) {
+                Some(ptr) => Some(Win32SyscallStubState::Jump(
+                    self.virt_mem
+                        .read_addr_arch(ntdll.arch.into(), ptr)
+                        .unwrap_or(Address::NULL),
+                )),
+                None => verify_stub(&bytes, address, ntdll.arch, expected_syscall as u32),
+            };
+
+            if let Some(state) = state {
+                let hook_module = match state {
+                    Win32SyscallStubState::Jump(target) => modules
+                        .iter()
+                        .find(|m| target >= m.base && target < m.base + m.size)
+                        .cloned(),
+                    _ => None,
+                };
+
+                for name in names.iter() {
+                    hooks.push(Win32SyscallStubHook {
+                        name: name.clone(),
+                        address,
+                        arch: ntdll.arch,
+                        expected_syscall: expected_syscall as u32,
+                        state,
+                        hook_module: hook_module.clone(),
+                        bytes,
+                    });
+                }
+            }
+        }
+
+        Ok(hooks)
+    }
+}
+
+/// Returns all syscall stubs sorted by their address together with the exports pointing to them.
+///
+/// The index of a stub in the returned list is its syscall number.
+fn syscall_stubs(exports: &[ExportInfo]) -> Vec<(umem, Vec<String>)> {
+    let mut stubs: Vec<(umem, Vec<String>)> = exports
+        .iter()
+        .filter(|e| e.name.as_ref().starts_with("Zw"))
+        .map(|e| (e.offset, vec![]))
+        .collect();
+    stubs.sort_by_key(|(offset, _)| *offset);
+    stubs.dedup_by_key(|(offset, _)| *offset);
+
+    for export in exports.iter().filter(|e| {
+        let name = e.name.as_ref();
+        name.starts_with("Zw") || name.starts_with("Nt")
+    }) {
+        if let Ok(idx) = stubs.binary_search_by_key(&export.offset, |(offset, _)| *offset) {
+            stubs[idx].1.push(export.name.to_string());
+        }
+    }
+
+    stubs
+}
+
+fn verify_stub(
+    bytes: &[u8; SYSCALL_STUB_SIZE],
+    address: Address,
+    arch: ArchitectureIdent,
+    expected_syscall: u32,
+) -> Option<Win32SyscallStubState> {
+    if let Some(target) = decode_jump(&bytes[..], address, arch) {
+        return Some(Win32SyscallStubState::Jump(target));
+    }
+
+    let (syscall, rest_offset) = match arch {
+        // mov r10, rcx; mov eax, imm32
+        ArchitectureIdent::X86(64, _) if bytes[0..4] == [0x4c, 0x8b, 0xd1, 0xb8] => {
+            (u32::from_le_bytes(bytes[4..8].try_into().unwrap()), 8)
+        }
+        // mov eax, imm32
+        ArchitectureIdent::X86(32, _) if bytes[0] == 0xb8 => {
+            (u32::from_le_bytes(bytes[1..5].try_into().unwrap()), 5)
+        }
+        _ => return Some(Win32SyscallStubState::Modified),
+    };
+
+    // some hooks keep the syscall number intact and place the jump right after it
+    if let Some(target) = decode_jump(&bytes[rest_offset..], address + rest_offset, arch) {
+        return Some(Win32SyscallStubState::Jump(target));
+    }
+
+    // the upper bits contain the service table index (and argument info on older wow64 builds)
+    if syscall & 0xfff != expected_syscall {
+        return Some(Win32SyscallStubState::SyscallNumberMismatch(syscall));
+    }
+
+    if let ArchitectureIdent::X86(64, _) = arch {
+        // the stub has to contain a `syscall` instruction
+        if !bytes.windows(2).any(|w| w == [0x0f, 0x05]) {
+            return Some(Win32SyscallStubState::Modified);
+        }
+    }
+
+    None
+}
+
+/// Location of the pointer that is dereferenced by an indirect `jmp [mem]` instruction
+fn decode_indirect_jump(
+    bytes: &[u8],
+    address: Address,
+    arch: ArchitectureIdent,
+) -> Option<Address> {
+    match (bytes, arch) {
+        // jmp [rip+disp32]
+        ([0xff, 0x25, disp @ ..], ArchitectureIdent::X86(64, _)) if disp.len() >= 4 => {
+            let disp = i32::from_le_bytes(disp[..4].try_into().unwrap());
+            Some(Address::from(
+                (address.to_umem() as i64 + 6 + disp as i64) as umem,
+            ))
+        }
+        // jmp [abs32]
+        ([0xff, 0x25, abs @ ..], _) if abs.len() >= 4 => Some(Address::from(u32::from_le_bytes(
+            abs[..4].try_into().unwrap(),
+        ) as umem)),
+        _ => None,
+    }
+}
+
+fn decode_jump(bytes: &[u8], address: Address, arch: ArchitectureIdent) -> Option<Address> {
+    match bytes {
+        // jmp rel32
+        [0xe9, rel @ ..] if rel.len() >= 4 => {
+            let rel = i32::from_le_bytes(rel[..4].try_into().unwrap());
+            Some(Address::from(
+                (address.to_umem() as i64 + 5 + rel as i64) as umem,
+            ))
+        }
+        // jmp rel8
+        [0xeb, rel, ..] => Some(Address::from(
+            (address.to_umem() as i64 + 2 + *rel as i8 as i64) as umem,
+        )),
+        // push imm32; ret
+        [0x68, imm @ ..] if imm.len() >= 5 && imm[4] == 0xc3 => Some(Address::from(
+            u32::from_le_bytes(imm[..4].try_into().unwrap()) as umem,
+        )),
+        // mov rax, imm64; jmp rax
+        [0x48, 0xb8, imm @ ..]
+            if matches!(arch, ArchitectureIdent::X86(64, _))
+                && imm.len() >= 10
+                && imm[8..10] == [0xff, 0xe0] =>
+        {
+            Some(Address::from(u64::from_le_bytes(
+                imm[..8].try_into().unwrap(),
+            )))
+        }
+        _ => None,
+    }
+}
@@ -3,81 +3,407 @@ use std::prelude::v1::*;
 use std::convert::TryInto;
 
 use memflow::architecture::{ArchitectureObj, Endianess};
+use memflow::cglue::tuple::CTup2;
 use memflow::error::{Error, ErrorKind, ErrorOrigin, Result};
-use memflow::mem::MemoryView;
+use memflow::mem::{MemoryView, ReadData};
 use memflow::types::Address;
 
-use widestring::U16CString;
-
-pub trait VirtualReadUnicodeString {
-    fn read_unicode_string(&mut self, proc_arch: ArchitectureObj, addr: Address) -> Result<String>;
+/// Header of a `UNICODE_STRING` that references the string buffer
+///
+/// ```text
+/// typedef struct _windows_unicode_string32 {
+///     uint16_t length;
+///     uint16_t maximum_length;
+///     uint32_t pBuffer; // pointer to string contents
+/// } __attribute__((packed)) win32_unicode_string_t;
+///
+/// typedef struct _windows_unicode_string64 {
+///     uint16_t length;
+///     uint16_t maximum_length;
+///     uint32_t padding; // align pBuffer
+///     uint64_t pBuffer; // pointer to string contents
+/// } __attribute__((packed)) win64_unicode_string_t;
+/// ```
+///
+/// The 32 bit layout is used by wow64 processes and is selected by passing the architecture of the process.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize))]
+pub struct Win32UnicodeString {
+    /// Length of the string in bytes without the null terminator
+    pub length: u16,
+    /// Size of the buffer in bytes
+    pub maximum_length: u16,
+    pub buffer: Address,
 }
 
-// TODO: split up cpu and proc arch in read_helper.rs
-impl<T: MemoryView> VirtualReadUnicodeString for T {
-    fn read_unicode_string(&mut self, proc_arch: ArchitectureObj, addr: Address) -> Result<String> {
-        /*
-        typedef struct _windows_unicode_string32 {
-            uint16_t length;
-            uint16_t maximum_length;
-            uint32_t pBuffer; // pointer to string contents
-        } __attribute__((packed)) win32_unicode_string_t;
-
-        typedef struct _windows_unicode_string64 {
-            uint16_t length;
-            uint16_t maximum_length;
-            uint32_t padding; // align pBuffer
-            uint64_t pBuffer; // pointer to string contents
-        } __attribute__((packed)) win64_unicode_string_t;
-        */
-
-        // length is always the first entry
-        let mut length = 0u16;
-        self.read_into(addr, &mut length)?;
-        if length == 0 {
-            return Err(Error(ErrorOrigin::OsLayer, ErrorKind::Encoding)
-                .log_debug("unable to read unicode string length (length is zero)"));
+impl Default for Win32UnicodeString {
+    fn default() -> Self {
+        Self {
+            length: 0,
+            maximum_length: 0,
+            buffer: Address::null(),
         }
+    }
+}
+
+impl Win32UnicodeString {
+    /// Returns the size of the structure for the given architecture.
+    pub fn size(proc_arch: ArchitectureObj) -> usize {
+        2 * proc_arch.size_addr()
+    }
+
+    /// Parses the header from the raw bytes of the structure.
+    pub fn from_bytes(proc_arch: ArchitectureObj, buf: &[u8]) -> Result<Self> {
+        let buf = buf.get(..Self::size(proc_arch)).ok_or_else(|| {
+            Error(ErrorOrigin::OsLayer, ErrorKind::Encoding)
+                .log_debug("unicode string header is truncated")
+        })?;
+
+        let endianess = proc_arch.endianess();
+        let read_u16 = |b: &[u8]| match endianess {
+            Endianess::LittleEndian => u16::from_le_bytes(b.try_into().unwrap()),
+            Endianess::BigEndian => u16::from_be_bytes(b.try_into().unwrap()),
+        };
 
-        // TODO: chek if length exceeds limit
         // buffer is either aligned at 4 or 8
-        let buffer = match proc_arch.bits() {
-            64 => self.read_addr64(addr + 8)?,
-            32 => self.read_addr32(addr + 4)?,
-            _ => {
-                return Err(Error(ErrorOrigin::OsLayer, ErrorKind::InvalidArchitecture));
+        let buffer = match (proc_arch.bits(), endianess) {
+            (64, Endianess::LittleEndian) => u64::from_le_bytes(buf[8..16].try_into().unwrap()),
+            (64, Endianess::BigEndian) => u64::from_be_bytes(buf[8..16].try_into().unwrap()),
+            (32, Endianess::LittleEndian) => {
+                u32::from_le_bytes(buf[4..8].try_into().unwrap()) as u64
             }
+            (32, Endianess::BigEndian) => u32::from_be_bytes(buf[4..8].try_into().unwrap()) as u64,
+            _ => return Err(Error(ErrorOrigin::OsLayer, ErrorKind::InvalidArchitecture)),
         };
-        if buffer.is_null() {
+
+        Ok(Self {
+            length: read_u16(&buf[0..2]),
+            maximum_length: read_u16(&buf[2..4]),
+            buffer: Address::from(buffer),
+        })
+    }
+
+    /// Checks that the header references a non-empty buffer that is able to hold the string.
+    pub fn validate(&self) -> Result<()> {
+        if self.length == 0 {
+            return Err(Error(ErrorOrigin::OsLayer, ErrorKind::Encoding)
+                .log_debug("unable to read unicode string length (length is zero)"));
+        }
+
+        if self.buffer.is_null() {
             return Err(Error(ErrorOrigin::OsLayer, ErrorKind::Encoding)
                 .log_debug("unable to read unicode string buffer"));
         }
 
         // check if buffer length is mod 2 (utf-16)
-        if length % 2 != 0 {
+        if self.length % 2 != 0 {
             return Err(Error(ErrorOrigin::OsLayer, ErrorKind::Encoding)
                 .log_debug("unicode string length is not a multiple of two"));
         }
 
+        // the length of partially overwritten strings usually exceeds their buffer
+        if self.maximum_length != 0 && self.length > self.maximum_length {
+            return Err(Error(ErrorOrigin::OsLayer, ErrorKind::Encoding)
+                .log_debug("unicode string length exceeds its maximum length"));
+        }
+
+        Ok(())
+    }
+}
+
+/// Decodes an utf-16 buffer up to the first null terminator.
+///
+/// Invalid surrogates are replaced with the unicode replacement character.
+pub fn decode_utf16_lossy(buf: &[u8], endianess: Endianess) -> String {
+    let content16 = buf
+        .chunks_exact(2)
+        .map(|b| match endianess {
+            Endianess::LittleEndian => u16::from_le_bytes([b[0], b[1]]),
+            Endianess::BigEndian => u16::from_be_bytes([b[0], b[1]]),
+        })
+        .take_while(|&c| c != 0)
+        .collect::<Vec<u16>>();
+    String::from_utf16_lossy(&content16)
+}
+
+pub trait VirtualReadUnicodeString {
+    fn read_unicode_string(&mut self, proc_arch: ArchitectureObj, addr: Address) -> Result<String>;
+
+    /// Reads the unicode strings at all given addresses.
+    ///
+    /// The headers and the buffers are each fetched in a single vectored read.
+    /// Strings that are invalid or that cannot be read are returned as `None`.
+    fn read_unicode_string_list(
+        &mut self,
+        proc_arch: ArchitectureObj,
+        addrs: &[Address],
+    ) -> Vec<Option<String>>;
+
+    /// Reads the buffers of the given unicode string headers.
+    ///
+    /// The buffers are fetched in a single vectored read. If that read fails every buffer
+    /// is read on its own so a single paged out buffer does not affect the other strings.
+    fn read_unicode_string_buffers(
+        &mut self,
+        proc_arch: ArchitectureObj,
+        strings: &[Win32UnicodeString],
+    ) -> Vec<Option<String>>;
+}
+
+// TODO: split up cpu and proc arch in read_helper.rs
+impl<T: MemoryView> VirtualReadUnicodeString for T {
+    fn read_unicode_string(&mut self, proc_arch: ArchitectureObj, addr: Address) -> Result<String> {
+        let mut header = vec![0; Win32UnicodeString::size(proc_arch)];
+        self.read_raw_into(addr, &mut header)?;
+        let string = Win32UnicodeString::from_bytes(proc_arch, &header)?;
+        string.validate()?;
+
         // read buffer
-        let mut content = vec![0; length as usize + 2];
-        self.read_raw_into(buffer, &mut content)?;
-        content[length as usize] = 0;
-        content[length as usize + 1] = 0;
-
-        let content16 = content
-            .chunks_exact(2)
-            .map(|b| {
-                b[0..2]
-                    .try_into()
-                    .map_err(|_| Error(ErrorOrigin::OsLayer, ErrorKind::Encoding))
+        let mut content = vec![0; string.length as usize];
+        self.read_raw_into(string.buffer, &mut content)?;
+        Ok(decode_utf16_lossy(&content, proc_arch.endianess()))
+    }
+
+    fn read_unicode_string_list(
+        &mut self,
+        proc_arch: ArchitectureObj,
+        addrs: &[Address],
+    ) -> Vec<Option<String>> {
+        let size = Win32UnicodeString::size(proc_arch);
+        let mut headers = addrs.iter().map(|_| vec![0; size]).collect::<Vec<_>>();
+        let headers_read = {
+            let mut data = addrs
+                .iter()
+                .zip(headers.iter_mut())
+                .map(|(&addr, header)| CTup2(addr, header.as_mut_slice().into()))
+                .collect::<Vec<ReadData>>();
+            self.read_raw_list(&mut data).is_ok()
+        };
+
+        // fall back to reading every header on its own
+        let strings = addrs
+            .iter()
+            .zip(headers.iter_mut())
+            .map(|(&addr, header)| {
+                if !headers_read {
+                    self.read_raw_into(addr, header).ok()?;
+                }
+                Win32UnicodeString::from_bytes(proc_arch, header)
+                    .ok()
+                    .filter(|s| s.validate().is_ok())
             })
-            .filter_map(Result::ok)
-            .map(|b| match proc_arch.endianess() {
-                Endianess::LittleEndian => u16::from_le_bytes(b),
-                Endianess::BigEndian => u16::from_be_bytes(b),
+            .collect::<Vec<_>>();
+
+        // strings with an invalid header are skipped without reading their buffer
+        let valid = strings.iter().flatten().copied().collect::<Vec<_>>();
+        let mut buffers = self
+            .read_unicode_string_buffers(proc_arch, &valid)
+            .into_iter();
+
+        strings
+            .iter()
+            .map(|s| s.and_then(|_| buffers.next().flatten()))
+            .collect()
+    }
+
+    fn read_unicode_string_buffers(
+        &mut self,
+        proc_arch: ArchitectureObj,
+        strings: &[Win32UnicodeString],
+    ) -> Vec<Option<String>> {
+        let endianess = proc_arch.endianess();
+        let mut contents = strings
+            .iter()
+            .map(|s| s.validate().ok().map(|_| vec![0; s.length as usize]))
+            .collect::<Vec<_>>();
+
+        let contents_read = {
+            let mut data = strings
+                .iter()
+                .zip(contents.iter_mut())
+                .filter_map(|(s, content)| {
+                    Some(CTup2(s.buffer, content.as_mut()?.as_mut_slice().into()))
+                })
+                .collect::<Vec<ReadData>>();
+            self.read_raw_list(&mut data).is_ok()
+        };
+
+        strings
+            .iter()
+            .zip(contents.iter_mut())
+            .map(|(s, content)| {
+                let content = content.as_mut()?;
+                // fall back to reading every buffer on its own
+                if !contents_read {
+                    self.read_raw_into(s.buffer, content).ok()?;
+                }
+                Some(decode_utf16_lossy(content, endianess))
             })
-            .collect::<Vec<u16>>();
-        Ok(U16CString::from_vec_truncate(content16).to_string_lossy())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use memflow::architecture::ArchitectureIdent;
+    use memflow::dummy::DummyMemory;
+    use memflow::mem::PhysicalMemory;
+    use memflow::types::size;
+
+    fn x64() -> ArchitectureObj {
+        ArchitectureIdent::X86(64, false).into()
+    }
+
+    fn x86() -> ArchitectureObj {
+        ArchitectureIdent::X86(32, false).into()
+    }
+
+    fn utf16(s: &str) -> Vec<u8> {
+        s.encode_utf16().flat_map(|c| c.to_le_bytes()).collect()
+    }
+
+    #[test]
+    fn from_bytes_x64() {
+        let mut buf = vec![0x1a, 0, 0x1c, 0, 0xff, 0xff, 0xff, 0xff];
+        buf.extend_from_slice(&0xffff_c005_1f2e_3488u64.to_le_bytes());
+        assert_eq!(
+            Win32UnicodeString::from_bytes(x64(), &buf).unwrap(),
+            Win32UnicodeString {
+                length: 0x1a,
+                maximum_length: 0x1c,
+                buffer: Address::from(0xffff_c005_1f2e_3488u64),
+            }
+        );
+        assert!(Win32UnicodeString::from_bytes(x64(), &buf[..15]).is_err());
+    }
+
+    #[test]
+    fn from_bytes_x86() {
+        let buf = [0x08, 0, 0x0a, 0, 0x88, 0x34, 0x2e, 0x1f, 0xaa];
+        assert_eq!(
+            Win32UnicodeString::from_bytes(x86(), &buf).unwrap(),
+            Win32UnicodeString {
+                length: 8,
+                maximum_length: 10,
+                buffer: Address::from(0x1f2e_3488u64),
+            }
+        );
+        assert!(Win32UnicodeString::from_bytes(x86(), &buf[..7]).is_err());
+    }
+
+    #[test]
+    fn validate() {
+        let string = Win32UnicodeString {
+            length: 8,
+            maximum_length: 10,
+            buffer: Address::from(0x1000u64),
+        };
+        assert!(string.validate().is_ok());
+        // the maximum length is not always set
+        assert!(Win32UnicodeString {
+            maximum_length: 0,
+            ..string
+        }
+        .validate()
+        .is_ok());
+
+        assert!(Win32UnicodeString {
+            length: 0,
+            ..string
+        }
+        .validate()
+        .is_err());
+        assert!(Win32UnicodeString {
+            length: 7,
+            ..string
+        }
+        .validate()
+        .is_err());
+        assert!(Win32UnicodeString {
+            length: 12,
+            ..string
+        }
+        .validate()
+        .is_err());
+        assert!(Win32UnicodeString {
+            buffer: Address::null(),
+            ..string
+        }
+        .validate()
+        .is_err());
+    }
+
+    #[test]
+    fn decode_utf16() {
+        let buf = utf16("ntoskrnl.exe");
+        assert_eq!(
+            decode_utf16_lossy(&buf, Endianess::LittleEndian),
+            "ntoskrnl.exe"
+        );
+
+        let be = "ab"
+            .encode_utf16()
+            .flat_map(|c| c.to_be_bytes())
+            .collect::<Vec<_>>();
+        assert_eq!(decode_utf16_lossy(&be, Endianess::BigEndian), "ab");
+    }
+
+    #[test]
+    fn decode_utf16_null_and_surrogates() {
+        let mut buf = utf16("abc");
+        buf.extend_from_slice(&[0, 0]);
+        buf.extend_from_slice(&utf16("def"));
+        assert_eq!(decode_utf16_lossy(&buf, Endianess::LittleEndian), "abc");
+
+        // an unpaired surrogate and a trailing odd byte
+        let mut buf = utf16("a");
+        buf.extend_from_slice(&0xd800u16.to_le_bytes());
+        buf.extend_from_slice(&utf16("b"));
+        buf.push(0x41);
+        assert_eq!(
+            decode_utf16_lossy(&buf, Endianess::LittleEndian),
+            "a\u{fffd}b"
+        );
+    }
+
+    #[test]
+    fn read_strings() {
+        let mut mem = DummyMemory::new(size::mb(1));
+        let mut view = mem.phys_view();
+
+        let content = utf16("System32");
+        view.write_raw(Address::from(0x2000u64), &content).unwrap();
+
+        let mut header = vec![
+            content.len() as u8,
+            0,
+            content.len() as u8 + 2,
+            0,
+            0,
+            0,
+            0,
+            0,
+        ];
+        header.extend_from_slice(&0x2000u64.to_le_bytes());
+        view.write_raw(Address::from(0x1000u64), &header).unwrap();
+
+        assert_eq!(
+            view.read_unicode_string(x64(), Address::from(0x1000u64))
+                .unwrap(),
+            "System32"
+        );
+        // an empty header is invalid
+        assert!(view
+            .read_unicode_string(x64(), Address::from(0x3000u64))
+            .is_err());
+        assert_eq!(
+            view.read_unicode_string_list(
+                x64(),
+                &[Address::from(0x1000u64), Address::from(0x3000u64)]
+            ),
+            vec![Some("System32".to_string()), None]
+        );
     }
 }
@@ -1,18 +1,31 @@
 use memflow::{
     architecture::{arm, x86, ArchitectureIdent, ArchitectureObj},
     cglue::tuple::*,
+    error::{Error, ErrorKind, ErrorOrigin},
     iter::SplitAtIndex,
     mem::{
         MemoryView, PhysicalMemory, VirtualDma, VirtualTranslate2, VirtualTranslate3,
         VtopFailureCallback, VtopOutputCallback,
     },
-    types::{umem, Address},
+    types::{umem, Address, PhysicalAddress},
 };
 
+use super::pagefile::Win32SoftwarePteFormat;
+use super::pte::{present_pte_address, Win32PteRun};
+
 #[derive(Debug, Clone, Copy)]
 pub struct Win32VirtualTranslate {
     pub sys_arch: ArchitectureObj,
     pub dtb: Address,
+    /// Separate user mode dtb of processes on kernels with kva shadowing enabled
+    pub user_dtb: Option<Address>,
+    /// 5-level paging is enabled
+    pub la57: bool,
+    /// Failed translations are retried by decoding the software ptes of the pages
+    pub software_ptes: bool,
+    /// Format of software ptes that reference a pagefile, pages that were paged out
+    /// are translated into the physical pagefile window if set
+    pub pagefile_format: Option<Win32SoftwarePteFormat>,
 }
 
 impl Win32VirtualTranslate {
@@ -20,9 +33,66 @@ impl Win32VirtualTranslate {
         Self {
             sys_arch: arch.into(),
             dtb,
+            user_dtb: None,
+            la57: false,
+            software_ptes: false,
+            pagefile_format: None,
         }
     }
 
+    /// Enables 5-level paging (LA57) for x64 targets.
+    ///
+    /// The memflow x86 translator only supports 4-level paging,
+    /// so translations are done by walking the paging structures manually.
+    pub fn with_la57(mut self, la57: bool) -> Self {
+        self.la57 = la57;
+        self
+    }
+
+    /// Enables decoding of software ptes for pages that cannot be translated.
+    ///
+    /// Pages in transition and resident prototype ptes are still readable with this enabled.
+    /// This is disabled by default since every page that fails to translate is walked again.
+    pub fn with_software_ptes(mut self, software_ptes: bool) -> Self {
+        self.software_ptes = software_ptes;
+        self
+    }
+
+    /// Translates pages that were paged out into the physical pagefile window.
+    ///
+    /// Software ptes that reference a pagefile are decoded with the given format and translated
+    /// into the window served by a [`Win32PagefileMemory`](super::Win32PagefileMemory), which
+    /// has to wrap the physical memory. Like [`Self::with_software_ptes`] this retries every page
+    /// that fails to translate.
+    pub fn with_pagefile_format(mut self, pagefile_format: Option<Win32SoftwarePteFormat>) -> Self {
+        self.pagefile_format = pagefile_format;
+        self
+    }
+
+    /// Copies the settings for resolving invalid ptes from another translator.
+    pub(crate) fn with_pte_options_of(self, other: &Self) -> Self {
+        self.with_software_ptes(other.software_ptes)
+            .with_pagefile_format(other.pagefile_format)
+    }
+
+    /// Sets the user mode dtb (`_KPROCESS::UserDirectoryTableBase`).
+    ///
+    /// User mode addresses that cannot be translated with the kernel dtb
+    /// are retried with the user mode dtb.
+    pub fn with_user_dtb(mut self, user_dtb: Address) -> Self {
+        self.user_dtb = Some(user_dtb);
+        self
+    }
+
+    fn is_user_address(&self, addr: Address) -> bool {
+        let bits = if self.la57 {
+            57
+        } else {
+            self.sys_arch.address_space_bits()
+        };
+        addr.to_umem() >> (bits - 1) == 0
+    }
+
     pub fn virt_mem<T: PhysicalMemory, V: VirtualTranslate2>(
         self,
         mem: T,
@@ -33,6 +103,138 @@ impl Win32VirtualTranslate {
     }
 }
 
+impl Win32VirtualTranslate {
+    /// Translates the given ranges by walking the 5-level paging structures.
+    ///
+    /// The paging structures are walked once per page table and the ptes of consecutive pages
+    /// are read in a single batch. Large pages and non-present paging structures are handled
+    /// as a whole so sparse address ranges can be skipped quickly.
+    fn virt_to_phys_iter_la57<
+        T: PhysicalMemory + ?Sized,
+        B: SplitAtIndex,
+        VI: Iterator<Item = CTup3<Address, Address, B>>,
+    >(
+        &self,
+        mem: &mut T,
+        dtb: Address,
+        addrs: VI,
+        out: &mut VtopOutputCallback<B>,
+        out_fail: &mut VtopFailureCallback<B>,
+    ) {
+        let vat = Self { dtb, ..*self };
+
+        for data in addrs {
+            vat.translate_range(
+                mem,
+                Error(ErrorOrigin::VirtualTranslate, ErrorKind::OutOfBounds),
+                data,
+                out,
+                out_fail,
+                |_, pte, addr| present_pte_address(pte, addr),
+            );
+        }
+    }
+
+    /// Retries a failed translation by decoding the software pte of each page in the range.
+    ///
+    /// Pages in transition, resident prototype ptes and (if enabled) paged out pages
+    /// are handed to `out`, everything else is forwarded to `out_fail`.
+    fn resolve_failed<T: PhysicalMemory + ?Sized, B: SplitAtIndex>(
+        &self,
+        mem: &mut T,
+        err: Error,
+        data: CTup3<Address, Address, B>,
+        out: &mut VtopOutputCallback<B>,
+        out_fail: &mut VtopFailureCallback<B>,
+    ) {
+        self.translate_range(mem, err, data, out, out_fail, |mem, pte, addr| {
+            present_pte_address(pte, addr).or_else(|| self.resolve_invalid_pte(mem, pte, addr))
+        });
+    }
+
+    /// Translates a range by walking the paging structures once per page table.
+    ///
+    /// The ptes of consecutive pages are read in a single batch and handed to `resolve`.
+    /// Ranges whose upper level entry is not present are failed as a whole with `err`.
+    fn translate_range<
+        T: PhysicalMemory + ?Sized,
+        B: SplitAtIndex,
+        F: FnMut(&mut T, u64, Address) -> Option<Address>,
+    >(
+        &self,
+        mem: &mut T,
+        err: Error,
+        CTup3(mut addr, mut meta_addr, buf): CTup3<Address, Address, B>,
+        out: &mut VtopOutputCallback<B>,
+        out_fail: &mut VtopFailureCallback<B>,
+        mut resolve: F,
+    ) {
+        let mut rest = Some(buf);
+        while let Some(buf) = rest.take() {
+            let (run, err) = match self.pte_run(mem, addr, buf.length()) {
+                Ok(run) => (run, err),
+                Err(walk_err) => (
+                    Win32PteRun::Region {
+                        phys_addr: None,
+                        shift: 12,
+                    },
+                    walk_err,
+                ),
+            };
+
+            match run {
+                Win32PteRun::Ptes(ptes) => {
+                    rest = Some(buf);
+                    for pte in ptes {
+                        let buf = match rest.take() {
+                            Some(buf) => buf,
+                            None => break,
+                        };
+                        let page_remaining = 0x1000 - (addr.to_umem() & 0xfff);
+                        let (chunk, next) = buf.split_at(page_remaining);
+                        rest = next;
+
+                        if let Some(chunk) = chunk {
+                            match resolve(mem, pte, addr) {
+                                Some(phys) => {
+                                    out.call(CTup3(PhysicalAddress::from(phys), meta_addr, chunk))
+                                }
+                                None => out_fail.call((err, CTup3(addr, meta_addr, chunk))),
+                            };
+                        }
+
+                        addr += page_remaining;
+                        meta_addr += page_remaining;
+                    }
+                }
+                Win32PteRun::Region { phys_addr, shift } => {
+                    let region_size: umem = 1 << shift;
+                    let region_remaining = region_size - (addr.to_umem() & (region_size - 1));
+                    let (chunk, next) = buf.split_at(region_remaining);
+                    rest = next;
+
+                    if let Some(chunk) = chunk {
+                        match phys_addr {
+                            Some(phys) => {
+                                out.call(CTup3(PhysicalAddress::from(phys), meta_addr, chunk))
+                            }
+                            None => out_fail.call((err, CTup3(addr, meta_addr, chunk))),
+                        };
+                    }
+
+                    addr += region_remaining;
+                    meta_addr += region_remaining;
+                }
+            }
+        }
+    }
+
+    /// Returns true if software ptes of this architecture can be decoded.
+    fn supports_software_ptes(&self) -> bool {
+        matches!(self.sys_arch.ident(), ArchitectureIdent::X86(..))
+    }
+}
+
 impl VirtualTranslate3 for Win32VirtualTranslate {
     fn virt_to_phys_iter<
         T: PhysicalMemory + ?Sized,
@@ -46,13 +248,69 @@ impl VirtualTranslate3 for Win32VirtualTranslate {
         out_fail: &mut VtopFailureCallback<B>,
         tmp_buf: &mut [std::mem::MaybeUninit<u8>],
     ) {
-        if let Ok(translator) = x86::new_translator(self.dtb, self.sys_arch) {
-            translator.virt_to_phys_iter(mem, addrs, out, out_fail, tmp_buf)
+        // collect failed translations so we can try to resolve software ptes afterwards
+        let mut failed = vec![];
+        let fail_callback = &mut |(err, data): (Error, CTup3<Address, Address, B>)| {
+            failed.push((err, data));
+            true
+        };
+        let mut fail_callback: VtopFailureCallback<B> = fail_callback.into();
+
+        if self.la57 {
+            self.virt_to_phys_iter_la57(mem, self.dtb, addrs, out, &mut fail_callback)
+        } else if let Ok(translator) = x86::new_translator(self.dtb, self.sys_arch) {
+            translator.virt_to_phys_iter(mem, addrs, out, &mut fail_callback, tmp_buf)
         } else if let Ok(translator) = arm::new_translator_nonsplit(self.dtb, self.sys_arch) {
-            translator.virt_to_phys_iter(mem, addrs, out, out_fail, tmp_buf)
+            translator.virt_to_phys_iter(mem, addrs, out, &mut fail_callback, tmp_buf)
         } else {
             panic!("Invalid architecture");
         }
+
+        std::mem::drop(fail_callback);
+
+        // user pages might only be mapped in the shadowed user mode dtb
+        if let Some(user_dtb) = self.user_dtb {
+            let (user, kernel): (Vec<_>, Vec<_>) = failed
+                .into_iter()
+                .partition(|(_, CTup3(addr, _, _))| self.is_user_address(*addr));
+
+            failed = kernel;
+            if !user.is_empty() {
+                let fail_callback = &mut |(err, data): (Error, CTup3<Address, Address, B>)| {
+                    failed.push((err, data));
+                    true
+                };
+                let mut fail_callback: VtopFailureCallback<B> = fail_callback.into();
+                let user = user.into_iter().map(|(_, data)| data);
+
+                if self.la57 {
+                    self.virt_to_phys_iter_la57(mem, user_dtb, user, out, &mut fail_callback);
+                } else if let Ok(translator) = x86::new_translator(user_dtb, self.sys_arch) {
+                    translator.virt_to_phys_iter(mem, user, out, &mut fail_callback, tmp_buf);
+                } else {
+                    for data in user {
+                        fail_callback.call((
+                            Error(
+                                ErrorOrigin::VirtualTranslate,
+                                ErrorKind::InvalidArchitecture,
+                            ),
+                            data,
+                        ));
+                    }
+                }
+            }
+        }
+
+        // software ptes are only decoded on request since every failed page has to be walked again
+        if (self.software_ptes || self.pagefile_format.is_some()) && self.supports_software_ptes() {
+            for (err, data) in failed.into_iter() {
+                self.resolve_failed(mem, err, data, out, out_fail);
+            }
+        } else {
+            for data in failed.into_iter() {
+                out_fail.call(data);
+            }
+        }
     }
 
     fn translation_table_id(&self, _address: Address) -> umem {
@@ -0,0 +1,170 @@
+/*!
+Module for reading the version resource of modules in memory.
+
+The `VS_VERSIONINFO` resource is read from the mapped image, this allows reporting
+the exact versions of drivers and dlls without access to the disk of the target.
+
+# Examples:
+
+```
+use memflow::prelude::v1::*;
+use memflow_win32::win32::{Win32Kernel, Win32ModuleInfo};
+
+fn test<T: 'static + PhysicalMemory + Clone, V: 'static + VirtualTranslate2 + Clone>(
+    kernel: &mut Win32Kernel<T, V>,
+) {
+    for module in kernel.module_list().unwrap() {
+        if let Ok(version) = module.version_info(kernel) {
+            println!(
+                "{}: {} ({})",
+                module.name,
+                version.file_version.unwrap_or_default(),
+                version.company_name.unwrap_or_default()
+            );
+        }
+    }
+}
+```
+*/
+use std::prelude::v1::*;
+
+use std::convert::TryInto;
+
+use super::decode_utf16_lossy;
+
+use memflow::architecture::Endianess;
+use memflow::error::{Error, ErrorKind, ErrorOrigin, Result};
+
+const VS_FFI_SIGNATURE: u32 = 0xfeef04bd;
+
+/// Version information of a module
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize))]
+pub struct Win32VersionInfo {
+    /// Binary file version of the fixed file info, falls back to the `FileVersion` string
+    pub file_version: Option<String>,
+    /// Binary product version of the fixed file info, falls back to the `ProductVersion` string
+    pub product_version: Option<String>,
+    pub company_name: Option<String>,
+    pub original_filename: Option<String>,
+}
+
+impl Win32VersionInfo {
+    /// Parses a raw `VS_VERSIONINFO` resource.
+    pub fn parse(buf: &[u8]) -> Result<Self> {
+        let root = Block::parse(buf)
+            .filter(|(b, _)| b.key == "VS_VERSION_INFO")
+            .map(|(b, _)| b)
+            .ok_or_else(|| {
+                Error(ErrorOrigin::OsLayer, ErrorKind::InvalidExeFile)
+                    .log_debug("invalid version resource")
+            })?;
+
+        let mut info = Self::default();
+
+        // VS_FIXEDFILEINFO
+        if read_u32(root.value, 0) == Some(VS_FFI_SIGNATURE) {
+            info.file_version = format_version(read_u32(root.value, 8), read_u32(root.value, 12));
+            info.product_version =
+                format_version(read_u32(root.value, 16), read_u32(root.value, 20));
+        }
+
+        // StringFileInfo -> StringTable -> String
+        let strings = Block::children(root.children)
+            .filter(|b| b.key == "StringFileInfo")
+            .flat_map(|b| Block::children(b.children))
+            .flat_map(|table| Block::children(table.children))
+            .map(|s| (s.key, decode_utf16_lossy(s.value, Endianess::LittleEndian)))
+            .filter(|(_, value)| !value.is_empty())
+            .collect::<Vec<_>>();
+        let string = |key: &str| {
+            strings
+                .iter()
+                .find(|(k, _)| k == key)
+                .map(|(_, value)| value.clone())
+        };
+
+        info.file_version = info.file_version.or_else(|| string("FileVersion"));
+        info.product_version = info.product_version.or_else(|| string("ProductVersion"));
+        info.company_name = string("CompanyName");
+        info.original_filename = string("OriginalFilename");
+
+        Ok(info)
+    }
+}
+
+fn format_version(ms: Option<u32>, ls: Option<u32>) -> Option<String> {
+    let (ms, ls) = (ms?, ls?);
+    Some(format!(
+        "{}.{}.{}.{}",
+        ms >> 16,
+        ms & 0xffff,
+        ls >> 16,
+        ls & 0xffff
+    ))
+}
+
+/// Node of the version resource tree
+///
+/// Every node starts with its length, the length of its value, its type and a null terminated key.
+/// The value and the children are aligned to 4 bytes.
+struct Block<'a> {
+    key: String,
+    value: &'a [u8],
+    children: &'a [u8],
+}
+
+impl<'a> Block<'a> {
+    /// Parses the block at the start of `buf` and returns it with the aligned length of the block.
+    fn parse(buf: &'a [u8]) -> Option<(Self, usize)> {
+        let length = read_u16(buf, 0)? as usize;
+        let value_length = read_u16(buf, 2)? as usize;
+        let is_text = read_u16(buf, 4)? == 1;
+        let block = buf.get(..length)?;
+
+        let key_len = block.get(6..)?.chunks_exact(2).position(|c| c == [0, 0])?;
+        let key = decode_utf16_lossy(&block[6..6 + key_len * 2], Endianess::LittleEndian);
+
+        // the length of text values is given in characters
+        let value_size = if is_text {
+            value_length * 2
+        } else {
+            value_length
+        };
+        let value_start = align4(6 + (key_len + 1) * 2).min(length);
+        let value_end = (value_start + value_size).min(length);
+        let children_start = align4(value_end).min(length);
+
+        Some((
+            Self {
+                key,
+                value: &block[value_start..value_end],
+                children: &block[children_start..],
+            },
+            align4(length),
+        ))
+    }
+
+    /// Iterates over the blocks that are stored back to back in `buf`.
+    fn children(mut buf: &'a [u8]) -> impl Iterator<Item = Block<'a>> {
+        std::iter::from_fn(move || {
+            let (block, length) = Block::parse(buf)?;
+            buf = buf.get(length..).unwrap_or_default();
+            Some(block)
+        })
+    }
+}
+
+fn align4(offset: usize) -> usize {
+    (offset + 3) & !3
+}
+
+fn read_u16(buf: &[u8], offset: usize) -> Option<u16> {
+    buf.get(offset..offset + 2)
+        .map(|b| u16::from_le_bytes(b.try_into().unwrap()))
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> Option<u32> {
+    buf.get(offset..offset + 4)
+        .map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+}